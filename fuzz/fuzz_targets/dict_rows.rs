@@ -0,0 +1,35 @@
+//! A dictionary of arbitrary rows, validated, built and searched.
+#![no_main]
+
+use std::fs;
+
+use libfuzzer_sys::fuzz_target;
+use liushu_core::dict::{self, BuildOptions};
+use liushu_core::engine::{EngineWithRedb, InputMethodEngine};
+use liushu_core::progress::NoProgress;
+
+fuzz_target!(|data: &[u8]| {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("words.tsv");
+    fs::write(&input, data).unwrap();
+    let inputs = [input];
+    let Ok(report) = dict::validate(&inputs) else {
+        return;
+    };
+    let built = dict::build(
+        &inputs,
+        dir.path(),
+        "fuzz",
+        BuildOptions::default(),
+        &NoProgress,
+    );
+    // the build stops at the first row validation found wrong
+    assert_eq!(built.is_ok(), report.errors.is_empty());
+    if built.is_ok() {
+        let engine = EngineWithRedb::with_formula(dir.path(), "fuzz").unwrap();
+        let codes: Vec<String> = engine.codes().take(8).collect();
+        for code in codes {
+            assert!(!engine.search(&code).unwrap().is_empty());
+        }
+    }
+});
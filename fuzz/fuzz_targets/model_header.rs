@@ -0,0 +1,19 @@
+//! A model dump of arbitrary bytes, gzipped when it starts like gzip.
+#![no_main]
+
+use std::fs;
+
+use libfuzzer_sys::fuzz_target;
+use liushu_core::hmm::import_model;
+
+fuzz_target!(|data: &[u8]| {
+    let dir = tempfile::tempdir().unwrap();
+    let name = if data.starts_with(&[0x1f, 0x8b]) {
+        "model.tsv.gz"
+    } else {
+        "model.tsv"
+    };
+    let input = dir.path().join(name);
+    fs::write(&input, data).unwrap();
+    let _ = import_model(&input, &dir.path().join("model.redb"));
+});
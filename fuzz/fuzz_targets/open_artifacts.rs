@@ -0,0 +1,36 @@
+//! Artifacts of arbitrary bytes, opened and searched: the first two bytes tell how many of
+//! the rest are the trie, the others are the redb dictionary.
+#![no_main]
+
+use std::fs;
+
+use libfuzzer_sys::fuzz_target;
+use liushu_core::engine::{EngineWithRedb, InputMethodEngine, MemoryEngine};
+
+fuzz_target!(
+    // liushu catches the panics of redb reading a corrupt file, so they mustn't abort
+    init: drop(std::panic::take_hook()),
+    |data: &[u8]| open(data)
+);
+
+fn open(data: &[u8]) {
+    let Some((len, rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let (trie, redb) = rest.split_at(usize::from(u16::from_le_bytes(*len)).min(rest.len()));
+
+    if let Ok(engine) = MemoryEngine::from_bytes(trie, redb) {
+        let _ = engine.search("a");
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("fuzz.trie"), trie).unwrap();
+    fs::write(dir.path().join("fuzz.redb"), redb).unwrap();
+    if let Ok(engine) = EngineWithRedb::with_formula(dir.path(), "fuzz") {
+        let codes: Vec<String> = engine.codes().take(8).collect();
+        for code in codes {
+            let _ = engine.search(&code);
+        }
+        let _ = engine.reverse_lookup("a");
+    }
+}
@@ -0,0 +1,139 @@
+//! Dictionaries generated from a fixed seed, so that every machine benchmarks the same
+//! entries and their numbers compare in relative terms.
+
+// each benchmark uses part of it
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+
+use liushu_core::config::{Config, Formula};
+use liushu_core::engine::EngineWithRedb;
+use tempfile::TempDir;
+
+pub const SEED: u64 = 0x6c69_7573_6875;
+
+/// Bytes of every generated code, the longest of the searches benchmarked.
+pub const CODE_LEN: usize = 6;
+
+/// Entries of the dictionaries benchmarked. `cargo test` runs each benchmark once without
+/// `--bench`, only to tell that it runs, so the dictionaries are small then.
+pub fn sizes() -> Vec<usize> {
+    if std::env::args().any(|arg| arg == "--bench") {
+        vec![10_000, 100_000, 500_000]
+    } else {
+        vec![1_000]
+    }
+}
+
+/// splitmix64, which needs no dependency and gives the same numbers everywhere.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Self {
+        Self(SEED)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    pub fn code(&mut self) -> String {
+        (0..CODE_LEN)
+            .map(|_| char::from(b'a' + self.below(26) as u8))
+            .collect()
+    }
+}
+
+/// A text per index, none twice, of CJK ideographs like those of a dictionary.
+fn text(mut index: usize) -> String {
+    const IDEOGRAPHS: usize = 0x9fa5 - 0x4e00;
+    let mut text = String::new();
+    loop {
+        text.extend(char::from_u32(0x4e00 + (index % IDEOGRAPHS) as u32));
+        index /= IDEOGRAPHS;
+        if index == 0 {
+            return text;
+        }
+    }
+}
+
+/// The TSV of a dictionary of `entries` entries.
+pub fn dictionary(entries: usize) -> String {
+    let mut rng = Rng::new();
+    let mut tsv = String::from("text\tcode\tweight\n");
+    for index in 0..entries {
+        let weight = rng.below(10_000);
+        tsv.push_str(&format!("{}\t{}\t{}\n", text(index), rng.code(), weight));
+    }
+    tsv
+}
+
+/// `n` codes searched, the first bytes of codes of the dictionary.
+pub fn queries(len: usize, n: usize) -> Vec<String> {
+    let mut rng = Rng::new();
+    (0..n)
+        .map(|_| {
+            rng.below(10_000);
+            rng.code()[..len].to_string()
+        })
+        .collect()
+}
+
+/// A config dir with the formula `fixture` of a generated dictionary, and a target dir to
+/// deploy it to.
+pub struct Fixture {
+    _dir: TempDir,
+    pub config_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub formula: Formula,
+}
+
+impl Fixture {
+    pub fn new(entries: usize) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("config");
+        let target_dir = dir.path().join("target");
+        fs::create_dir_all(config_dir.join("fixture")).unwrap();
+        fs::create_dir_all(&target_dir).unwrap();
+        fs::write(
+            config_dir.join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        fs::write(config_dir.join("fixture/words.tsv"), dictionary(entries)).unwrap();
+        let config = Config::load_from_path(config_dir.join("main.dhall")).unwrap();
+        let formula = config.formulas.into_iter().next().unwrap();
+        Self {
+            _dir: dir,
+            config_dir,
+            target_dir,
+            formula,
+        }
+    }
+
+    /// With the artifacts of the formula built.
+    pub fn deployed(entries: usize) -> Self {
+        let fixture = Self::new(entries);
+        fixture.build();
+        fixture
+    }
+
+    pub fn build(&self) {
+        self.formula
+            .compile2(&self.config_dir, &self.target_dir)
+            .unwrap();
+    }
+
+    pub fn engine(&self) -> EngineWithRedb {
+        EngineWithRedb::with_formula(&self.target_dir, "fixture").unwrap()
+    }
+}
@@ -0,0 +1,56 @@
+//! Building the artifacts of a formula.
+
+mod bench_support;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use liushu_core::dict::{self, BuildOptions};
+use liushu_core::engine::InputMethodEngine;
+use liushu_core::progress::NoProgress;
+
+use bench_support::{queries, sizes, Fixture};
+
+fn compile2(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile2");
+    group.sample_size(10);
+    for entries in sizes() {
+        let fixture = Fixture::new(entries);
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_function(BenchmarkId::from_parameter(entries), |b| {
+            b.iter(|| fixture.build())
+        });
+    }
+    group.finish();
+}
+
+/// From the dictionaries to the first search of the artifacts.
+fn build_and_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build_and_search");
+    group.sample_size(10);
+    for entries in sizes() {
+        let fixture = Fixture::new(entries);
+        let inputs = fixture.formula.dictionaries(&fixture.config_dir);
+        let code = &queries(3, 1)[0];
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_function(BenchmarkId::from_parameter(entries), |b| {
+            b.iter(|| {
+                let options = BuildOptions {
+                    force: true,
+                    ..Default::default()
+                };
+                dict::build(
+                    &inputs,
+                    &fixture.target_dir,
+                    "fixture",
+                    options,
+                    &NoProgress,
+                )
+                .unwrap();
+                assert!(!fixture.engine().search(code).unwrap().is_empty());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compile2, build_and_search);
+criterion_main!(benches);
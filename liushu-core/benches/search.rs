@@ -0,0 +1,89 @@
+//! Searches of a deployed formula, the first one of an engine, and opening its trie.
+
+mod bench_support;
+
+use std::fs;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use liushu_core::engine::{InputMethodEngine, MemoryEngine};
+
+use bench_support::{queries, sizes, Fixture};
+
+fn search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("search");
+    for entries in sizes() {
+        let fixture = Fixture::deployed(entries);
+        let engine = fixture.engine();
+        for len in [1, 3, 6] {
+            let codes = queries(len, 64);
+            let id = BenchmarkId::new(format!("{}-char", len), entries);
+            group.bench_with_input(id, &codes, |b, codes| {
+                let mut codes = codes.iter().cycle();
+                b.iter(|| engine.search(codes.next().unwrap()).unwrap())
+            });
+            let id = BenchmarkId::new(format!("{}-char-into", len), entries);
+            group.bench_with_input(id, &codes, |b, codes| {
+                let mut codes = codes.iter().cycle();
+                let mut items = Vec::new();
+                b.iter(|| {
+                    items.clear();
+                    engine
+                        .search_into(codes.next().unwrap(), &mut items)
+                        .unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The first search of an engine just opened, with and without warming it up first.
+fn first_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("first_search");
+    for entries in sizes() {
+        let fixture = Fixture::deployed(entries);
+        let code = &queries(3, 1)[0];
+        for warm_up in [false, true] {
+            let id = BenchmarkId::new(if warm_up { "warm" } else { "cold" }, entries);
+            group.bench_function(id, |b| {
+                b.iter_batched(
+                    || {
+                        let engine = fixture.engine();
+                        if warm_up {
+                            engine.warm_up(Duration::from_millis(200)).unwrap();
+                        }
+                        engine
+                    },
+                    // dropped once timed, with the engine
+                    |engine| {
+                        let items = engine.search(code).unwrap();
+                        (engine, items)
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decode_trie(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_trie");
+    // the trie alone, the definitions are those of no entry
+    let (_, definitions) = std::iter::empty()
+        .collect::<MemoryEngine>()
+        .to_bytes()
+        .unwrap();
+    for entries in sizes() {
+        let fixture = Fixture::deployed(entries);
+        let trie = fs::read(fixture.target_dir.join("fixture.trie")).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(entries), &trie, |b, trie| {
+            b.iter(|| MemoryEngine::from_bytes(trie, &definitions).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, search, first_search, decode_trie);
+criterion_main!(benches);
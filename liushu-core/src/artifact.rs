@@ -0,0 +1,469 @@
+//! What the engines need of the redb files liushu writes, apart from how they are built.
+
+use std::any::Any;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::{IntErrorKind, ParseIntError};
+use std::panic::{self, AssertUnwindSafe, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use redb::{Database, ReadTransaction, TableDefinition, WriteTransaction};
+#[cfg(feature = "sqlite-engine")]
+use rusqlite::{Connection, OpenFlags};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{IoResultExt, LiushuError};
+
+/// The weight and comment of each text of a formula, in its `.redb` artifact.
+pub const DICTIONARY: TableDefinition<&str, (u64, Option<&str>)> =
+    TableDefinition::new("dictionary");
+
+/// The dictionaries of a build that tracked provenance, by their index in [`PROVENANCE`].
+pub const SOURCES: TableDefinition<u32, &str> = TableDefinition::new("sources");
+
+/// The dictionary and line of the row of each `(text, code)`, the last row when there are
+/// several, in the `.redb` artifact of a build that tracked provenance.
+pub const PROVENANCE: TableDefinition<(&str, &str), (u32, u64)> =
+    TableDefinition::new("provenance");
+
+/// What each character of the codes of a formula stands for, such as the radical of a key,
+/// in the `.redb` artifact of a build given an annotation table.
+pub const ANNOTATIONS: TableDefinition<&str, &str> = TableDefinition::new("annotations");
+
+/// The forms the texts and codes of a formula were normalized to, `text` and `code` each to
+/// one such as `NFC`, in the `.redb` artifact of a build that normalized them, see
+/// [`crate::normalize`]. Engines of the artifact normalize what they search alike.
+pub const NORMALIZATION: TableDefinition<&str, &str> = TableDefinition::new("normalization");
+
+/// The texts with a character out of the charset of their formula, in the `.redb` artifact
+/// of a build that tagged them, see [`crate::charset`].
+pub const OUT_OF_CHARSET: TableDefinition<&str, ()> = TableDefinition::new("out_of_charset");
+
+/// The entries of [`NORMALIZATION`], and the rows of the table of the same name in the
+/// `.db3` artifact.
+pub const NORMALIZATION_FORMS: [(&str, &str); 2] = [("text", "NFC"), ("code", "NFKC lowercase")];
+
+/// The heaviest weight of an entry, the greatest an `INTEGER` of sqlite holds, so that
+/// the artifacts of either engine have the same weights.
+pub const MAX_WEIGHT: u64 = i64::MAX as u64;
+
+/// A row of a dictionary, and an entry of the artifacts built from them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DictItem {
+    pub text: String,
+    pub code: String,
+    /// Clamped into `0..=MAX_WEIGHT` as it is read, see [`parse_weight`].
+    #[serde(deserialize_with = "deserialize_weight")]
+    pub weight: u64,
+    pub comment: Option<String>,
+}
+
+/// Parses the weight of a row, answering it and whether it was clamped: a negative one to
+/// 0, and one heavier than [`MAX_WEIGHT`] to it.
+pub fn parse_weight(given: &str) -> Result<(u64, bool), ParseIntError> {
+    let error = match given.parse::<u64>() {
+        Ok(weight) => return Ok((weight.min(MAX_WEIGHT), weight > MAX_WEIGHT)),
+        Err(error) => error,
+    };
+    match given.parse::<i128>() {
+        Ok(weight) if weight <= 0 => Ok((0, weight < 0)),
+        Ok(_) => Ok((MAX_WEIGHT, true)),
+        Err(e) if *e.kind() == IntErrorKind::PosOverflow => Ok((MAX_WEIGHT, true)),
+        Err(e) if *e.kind() == IntErrorKind::NegOverflow => Ok((0, true)),
+        Err(_) => Err(error),
+    }
+}
+
+fn deserialize_weight<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    struct WeightVisitor;
+
+    impl Visitor<'_> for WeightVisitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an integer weight")
+        }
+
+        fn visit_str<E: de::Error>(self, given: &str) -> Result<u64, E> {
+            parse_weight(given)
+                .map(|(weight, _)| weight)
+                .map_err(|e| E::custom(format!("invalid weight {:?}: {}", given, e)))
+        }
+    }
+
+    deserializer.deserialize_str(WeightVisitor)
+}
+
+/// Where a candidate comes from, shown as `phrases.tsv:1042`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    /// The dictionary as the build was given it.
+    pub source: String,
+    /// From 1.
+    pub line: u64,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Path::new(&self.source)
+            .file_name()
+            .map_or(self.source.as_str().into(), |name| name.to_string_lossy());
+        write!(f, "{}:{}", name, self.line)
+    }
+}
+
+/// How [`ArtifactOpenOptions`] open a database.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// One that exists, only read: a missing one is [`LiushuError::ArtifactMissing`] rather
+    /// than an empty one created, and a write to it a [`LiushuError::ReadOnly`].
+    #[default]
+    ReadOnly,
+    /// One that exists, or an empty one created when there is none.
+    ReadWrite,
+    /// An empty one, failing when there is a file already.
+    CreateNew,
+}
+
+/// How a database is opened: the artifacts by the engines read-only and by the builds
+/// created new, and the user and patch dictionaries read-write, as they are the only
+/// databases written once open, each in a file of its own.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArtifactOpenOptions {
+    pub mode: OpenMode,
+    /// A database of its own in memory, for tests, new whatever the path, which only names
+    /// it in errors, so it can't be opened [`OpenMode::ReadOnly`]. redb has no memory
+    /// backend, its databases are in a temporary file removed once they are closed.
+    pub in_memory: bool,
+}
+
+impl ArtifactOpenOptions {
+    pub const fn new(mode: OpenMode) -> Self {
+        Self {
+            mode,
+            in_memory: false,
+        }
+    }
+
+    /// Opens the redb database at `path`, catching a panic of redb like [`open_redb`].
+    /// `operation` is what errors tell was being done, such as `open dictionary`.
+    pub fn open_redb(&self, path: &Path, operation: &str) -> Result<ArtifactDb, LiushuError> {
+        if self.in_memory {
+            self.check_in_memory()?;
+            let temp = TempFile::new(path);
+            let db = open_redb(path, operation, || Database::create(&temp.0))?;
+            return Ok(ArtifactDb {
+                db,
+                path: path.to_path_buf(),
+                mode: self.mode,
+                _temp: Some(temp),
+            });
+        }
+        self.check_path(path)?;
+        let db = match self.mode {
+            OpenMode::ReadOnly => open_redb(path, operation, || Database::open(path))?,
+            OpenMode::ReadWrite | OpenMode::CreateNew => {
+                open_redb(path, operation, || Database::create(path))?
+            }
+        };
+        Ok(ArtifactDb {
+            db,
+            path: path.to_path_buf(),
+            mode: self.mode,
+            _temp: None,
+        })
+    }
+
+    /// Opens the sqlite database at `path` with the flags of the mode, sqlite failing
+    /// writes to one opened [`OpenMode::ReadOnly`] itself.
+    #[cfg(feature = "sqlite-engine")]
+    pub fn open_sqlite(&self, path: &Path) -> Result<Connection, LiushuError> {
+        let flags = OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let flags = match self.mode {
+            OpenMode::ReadOnly => flags | OpenFlags::SQLITE_OPEN_READ_ONLY,
+            OpenMode::ReadWrite | OpenMode::CreateNew => {
+                flags | OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+            }
+        };
+        if self.in_memory {
+            self.check_in_memory()?;
+            return Ok(Connection::open_in_memory_with_flags(flags)?);
+        }
+        self.check_path(path)?;
+        Ok(Connection::open_with_flags(path, flags)?)
+    }
+
+    fn check_in_memory(&self) -> Result<(), LiushuError> {
+        match self.mode {
+            OpenMode::ReadOnly => Err(LiushuError::InvalidInput(
+                "a database in memory is new, it can't be opened read-only".to_string(),
+            )),
+            OpenMode::ReadWrite | OpenMode::CreateNew => Ok(()),
+        }
+    }
+
+    fn check_path(&self, path: &Path) -> Result<(), LiushuError> {
+        match self.mode {
+            // neither redb nor sqlite would tell, they create an empty database
+            OpenMode::ReadOnly if !path.exists() => {
+                Err(LiushuError::ArtifactMissing(path.to_path_buf()))
+            }
+            OpenMode::CreateNew if path.exists() => Err(LiushuError::io_at(
+                "create",
+                path,
+                io::Error::from(io::ErrorKind::AlreadyExists),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A redb database opened by [`ArtifactOpenOptions::open_redb`], written to only when it
+/// wasn't opened [`OpenMode::ReadOnly`].
+pub struct ArtifactDb {
+    db: Database,
+    path: PathBuf,
+    mode: OpenMode,
+    /// Of a database in memory, removed once `db`, dropped first, is closed.
+    _temp: Option<TempFile>,
+}
+
+impl ArtifactDb {
+    pub fn begin_read(&self) -> Result<ReadTransaction<'_>, redb::Error> {
+        self.db.begin_read()
+    }
+
+    pub fn begin_write(&self) -> Result<WriteTransaction<'_>, LiushuError> {
+        if self.mode == OpenMode::ReadOnly {
+            return Err(LiushuError::ReadOnly(self.path.clone()));
+        }
+        Ok(self.db.begin_write()?)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> OpenMode {
+        self.mode
+    }
+}
+
+/// A file of the temp dir removed as it is dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Named after `path`, and unique in the process.
+    fn new(path: &Path) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        Self(std::env::temp_dir().join(format!(
+            "liushu-{}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            name
+        )))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Opens the redb database at `path` with `open`, which calls `Database::open` or
+/// `Database::create`. redb panics on some malformed files instead of failing, the panic is
+/// caught so that a corrupt artifact can't take down a frontend.
+pub(crate) fn open_redb(
+    path: &Path,
+    operation: &str,
+    open: impl FnOnce() -> Result<Database, redb::Error> + UnwindSafe,
+) -> Result<Database, LiushuError> {
+    match panic::catch_unwind(open) {
+        Ok(db) => db.with_path(operation, path),
+        Err(panic) => Err(panicked(path, "opening", panic)),
+    }
+}
+
+/// Runs `read` on the redb database at `path`, catching a panic of redb on a malformed
+/// file like [`open_redb`]. Only for reads, which leave nothing half changed when they panic.
+pub(crate) fn read_redb<T>(
+    path: &Path,
+    read: impl FnOnce() -> Result<T, LiushuError>,
+) -> Result<T, LiushuError> {
+    panic::catch_unwind(AssertUnwindSafe(read))
+        .unwrap_or_else(|panic| Err(panicked(path, "reading", panic)))
+}
+
+fn panicked(path: &Path, doing: &str, panic: Box<dyn Any + Send>) -> LiushuError {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    LiushuError::ArtifactCorrupt {
+        path: path.to_path_buf(),
+        source: format!("redb panicked {} it: {}", doing, message).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::ReadableTable;
+
+    use super::*;
+
+    const TABLE: TableDefinition<&str, u64> = TableDefinition::new("table");
+
+    #[test]
+    fn test_parse_weight() {
+        for (given, parsed) in [
+            ("5", (5, false)),
+            ("+5", (5, false)),
+            ("4294967296", (4294967296, false)),
+            ("9223372036854775807", (MAX_WEIGHT, false)),
+            ("9223372036854775808", (MAX_WEIGHT, true)),
+            (
+                "99999999999999999999999999999999999999999",
+                (MAX_WEIGHT, true),
+            ),
+            ("-0", (0, false)),
+            ("-5", (0, true)),
+            ("-99999999999999999999999999999999999999999", (0, true)),
+        ] {
+            assert_eq!(parse_weight(given), Ok(parsed), "{}", given);
+        }
+        for given in ["many", "1.5", ""] {
+            assert!(parse_weight(given).is_err(), "{}", given);
+        }
+        assert_eq!(
+            parse_weight("many").unwrap_err().to_string(),
+            "invalid digit found in string"
+        );
+    }
+
+    fn write(db: &ArtifactDb) -> Result<(), LiushuError> {
+        let tx = db.begin_write()?;
+        tx.open_table(TABLE)?.insert("key", 1)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn read(db: &ArtifactDb) -> Option<u64> {
+        let tx = db.begin_read().unwrap();
+        let table = match tx.open_table(TABLE) {
+            Err(redb::Error::TableDoesNotExist(_)) => return None,
+            table => table.unwrap(),
+        };
+        let value = table.get("key").unwrap().map(|v| v.value());
+        value
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.redb");
+        let options = |mode| ArtifactOpenOptions::new(mode);
+
+        // not an empty database created
+        assert!(matches!(
+            options(OpenMode::ReadOnly).open_redb(&path, "open"),
+            Err(LiushuError::ArtifactMissing(p)) if p == path
+        ));
+        assert!(!path.exists());
+
+        let db = options(OpenMode::CreateNew)
+            .open_redb(&path, "create")
+            .unwrap();
+        write(&db).unwrap();
+        drop(db);
+        let error = options(OpenMode::CreateNew)
+            .open_redb(&path, "create")
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), "E_IO");
+
+        let db = options(OpenMode::ReadOnly)
+            .open_redb(&path, "open")
+            .unwrap();
+        assert_eq!(read(&db), Some(1));
+        let error = write(&db).unwrap_err();
+        assert!(matches!(&error, LiushuError::ReadOnly(p) if *p == path));
+        assert_eq!(error.code(), "E_READ_ONLY");
+        drop(db);
+
+        let db = options(OpenMode::ReadWrite)
+            .open_redb(&path, "open")
+            .unwrap();
+        write(&db).unwrap();
+        let other = dir.path().join("other.redb");
+        options(OpenMode::ReadWrite)
+            .open_redb(&other, "open")
+            .unwrap();
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.redb");
+        let options = ArtifactOpenOptions {
+            mode: OpenMode::CreateNew,
+            in_memory: true,
+        };
+        let db = options.open_redb(&path, "create").unwrap();
+        let other = options.open_redb(&path, "create").unwrap();
+        write(&db).unwrap();
+        assert_eq!((read(&db), read(&other)), (Some(1), None));
+        assert!(!path.exists());
+        let temp = db._temp.as_ref().unwrap().0.clone();
+        drop(db);
+        assert!(!temp.exists());
+
+        let read_only = ArtifactOpenOptions {
+            mode: OpenMode::ReadOnly,
+            in_memory: true,
+        };
+        assert!(matches!(
+            read_only.open_redb(&path, "open"),
+            Err(LiushuError::InvalidInput(_))
+        ));
+    }
+
+    #[cfg(feature = "sqlite-engine")]
+    #[test]
+    fn test_open_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.db3");
+        let options = |mode| ArtifactOpenOptions::new(mode);
+        assert!(matches!(
+            options(OpenMode::ReadOnly).open_sqlite(&path),
+            Err(LiushuError::ArtifactMissing(_))
+        ));
+        assert!(!path.exists());
+
+        let conn = options(OpenMode::CreateNew).open_sqlite(&path).unwrap();
+        conn.execute("CREATE TABLE t (x INTEGER)", ()).unwrap();
+        drop(conn);
+        assert!(options(OpenMode::CreateNew).open_sqlite(&path).is_err());
+
+        let conn = options(OpenMode::ReadOnly).open_sqlite(&path).unwrap();
+        let error = conn.execute("INSERT INTO t VALUES (1)", ()).unwrap_err();
+        assert_eq!(
+            error.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::ReadOnly)
+        );
+
+        let conn = ArtifactOpenOptions {
+            mode: OpenMode::CreateNew,
+            in_memory: true,
+        }
+        .open_sqlite(&path)
+        .unwrap();
+        assert!(conn.execute("SELECT * FROM t", ()).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    config::Config,
+    deploy::{deploy, DeploySummary},
+    dirs::MyProjectDirs,
+    error::{IoResultExt, LiushuError},
+};
+
+/// A minimal config bundled with the binary, a slice of the most frequent entries of the
+/// sunman prelude, with paths relative to the config dir.
+pub const STARTER: [(&str, &str); 2] = [
+    ("main.dhall", include_str!("../assets/starter/main.dhall")),
+    (
+        "sunman/starter.dict.tsv",
+        include_str!("../assets/starter/sunman/starter.dict.tsv"),
+    ),
+];
+
+#[derive(Debug, Serialize)]
+pub struct BootstrapReport {
+    pub written: Vec<PathBuf>,
+    /// Files of the starter config already in the config dir, which are left alone.
+    pub skipped: Vec<PathBuf>,
+    pub deploy: DeploySummary,
+}
+
+/// Whether there is no config to load yet.
+pub fn needs_bootstrap(dirs: &MyProjectDirs) -> bool {
+    !dirs.config_dir.join("main.dhall").exists()
+}
+
+/// Writes the starter config into the config dir and deploys it.
+///
+/// Files already there are never overwritten, so a partial config keeps what it has.
+pub fn bootstrap(dirs: &MyProjectDirs) -> Result<BootstrapReport, LiushuError> {
+    let mut written = Vec::new();
+    let mut skipped = Vec::new();
+    for (name, content) in STARTER {
+        let path = dirs.config_dir.join(name);
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent).with_path("create config dir", parent)?;
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes())
+                    .with_path("write starter config", &path)?;
+                written.push(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => skipped.push(path),
+            Err(e) => return Err(LiushuError::io_at("create starter config", &path, e)),
+        }
+    }
+    info!(
+        written = written.len(),
+        skipped = skipped.len(),
+        "wrote the starter config"
+    );
+
+    let config = Config::load_from_path(dirs.config_dir.join("main.dhall"))?;
+    let deploy = deploy(&config, dirs)?;
+    Ok(BootstrapReport {
+        written,
+        skipped,
+        deploy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deploy::FormulaStatus;
+    use crate::engine::{EngineWithRedb, InputMethodEngine};
+
+    #[test]
+    fn test_bootstrap() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = MyProjectDirs::from_root(root.path());
+        // a dictionary the user already started on
+        fs::create_dir_all(dirs.config_dir.join("sunman")).unwrap();
+        let dictionary = dirs.config_dir.join("sunman/starter.dict.tsv");
+        fs::write(&dictionary, "text\tcode\tweight\n你好\tnihao\t2\n").unwrap();
+        assert!(needs_bootstrap(&dirs));
+
+        let report = bootstrap(&dirs).unwrap();
+        assert_eq!(report.written, [dirs.config_dir.join("main.dhall")]);
+        assert_eq!(report.skipped, [dictionary]);
+        assert_eq!(report.deploy.formulas[0].status, FormulaStatus::Deployed);
+        assert!(!needs_bootstrap(&dirs));
+        let engine = EngineWithRedb::with_formula(&dirs.target_dir, "sunman").unwrap();
+        assert_eq!(engine.search("nihao").unwrap()[0].text, "你好");
+    }
+}
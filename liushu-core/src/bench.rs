@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::{
+    engine::{CacheStats, EngineWithRedb, InputMethodEngine},
+    error::LiushuError,
+};
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub queries: usize,
+    pub iterations: usize,
+    pub searches: usize,
+    /// Candidates returned over all searches.
+    pub candidates: usize,
+    pub p50_us: f64,
+    pub p95_us: f64,
+    pub p99_us: f64,
+    /// Filled in by callers that can count allocations, e.g. with dhat.
+    pub allocations_per_search: Option<f64>,
+    /// Filled in by callers searching through a [`SearchCache`](crate::engine::SearchCache).
+    pub cache: Option<CacheStats>,
+}
+
+/// Replays every query `iterations` times against an already loaded engine.
+pub fn run(
+    engine: &dyn InputMethodEngine,
+    queries: &[String],
+    iterations: usize,
+) -> Result<BenchReport, LiushuError> {
+    let mut latencies = Vec::with_capacity(queries.len() * iterations);
+    let mut candidates = 0;
+
+    for _ in 0..iterations {
+        for query in queries {
+            let start = Instant::now();
+            let result = engine.search(query)?;
+            latencies.push(start.elapsed());
+            candidates += result.len();
+        }
+    }
+    latencies.sort();
+
+    Ok(BenchReport {
+        queries: queries.len(),
+        iterations,
+        searches: latencies.len(),
+        candidates,
+        p50_us: percentile(&latencies, 50.0),
+        p95_us: percentile(&latencies, 95.0),
+        p99_us: percentile(&latencies, 99.0),
+        allocations_per_search: None,
+        cache: None,
+    })
+}
+
+/// Nearest-rank percentile of sorted latencies, in microseconds.
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1_000_000.0
+}
+
+/// Picks `n` code prefixes spread evenly over the trie of a deployed formula.
+pub fn sample_queries(engine: &EngineWithRedb, n: usize) -> Vec<String> {
+    let codes: Vec<String> = engine.codes().collect();
+    if codes.is_empty() || n == 0 {
+        return Vec::new();
+    }
+
+    let step = (codes.len() / n).max(1);
+    codes
+        .iter()
+        .step_by(step)
+        .take(n)
+        .enumerate()
+        .map(|(i, code)| {
+            let len = i % code.chars().count() + 1;
+            code.chars().take(len).collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, engine::SearchResultItem};
+
+    struct EchoEngine;
+
+    impl InputMethodEngine for EchoEngine {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            Ok(code
+                .chars()
+                .map(|c| SearchResultItem {
+                    text: c.to_string(),
+                    code: code.to_string(),
+                    weight: 0,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let queries = vec!["a".to_string(), "abc".to_string()];
+        let report = run(&EchoEngine, &queries, 10).unwrap();
+
+        assert_eq!(report.queries, 2);
+        assert_eq!(report.searches, 20);
+        assert_eq!(report.candidates, 40);
+        assert!(report.p50_us <= report.p95_us);
+        assert!(report.p95_us <= report.p99_us);
+        assert!(report.allocations_per_search.is_none());
+    }
+
+    #[test]
+    fn test_run_cached() {
+        use crate::engine::SearchCache;
+
+        struct SlowEngine;
+
+        impl InputMethodEngine for SlowEngine {
+            fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+                std::thread::sleep(Duration::from_millis(2));
+                EchoEngine.search(code)
+            }
+        }
+
+        // the prefixes of a code typed over and over
+        let queries = vec!["a".to_string(), "ab".to_string(), "abc".to_string()];
+        let uncached = run(&SlowEngine, &queries, 5).unwrap();
+        let engine = SearchCache::new(SlowEngine, "fixture", 8);
+        let cached = run(&engine, &queries, 5).unwrap();
+
+        assert!(uncached.p50_us >= 2000.0);
+        assert!(cached.p50_us < 1000.0);
+        assert_eq!(cached.candidates, uncached.candidates);
+        let stats = engine.stats();
+        assert_eq!((stats.hits, stats.misses), (12, 3));
+    }
+
+    #[test]
+    fn test_sample_queries() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(config_dir.path().join("fixture")).unwrap();
+        std::fs::write(
+            config_dir.path().join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            config_dir.path().join("fixture/words.tsv"),
+            "text\tcode\tweight\n你\tni\t1\n好\thao\t1\n我\two\t1\n",
+        )
+        .unwrap();
+        let config = Config::load_from_path(config_dir.path().join("main.dhall")).unwrap();
+        config.formulas[0]
+            .compile2(&config_dir, &target_dir)
+            .unwrap();
+        let engine = EngineWithRedb::with_formula(&target_dir, "fixture").unwrap();
+
+        assert_eq!(sample_queries(&engine, 3), ["h", "ni", "w"]);
+        assert_eq!(sample_queries(&engine, 1), ["h"]);
+        assert!(sample_queries(&engine, 0).is_empty());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_micros).collect();
+
+        assert_eq!(percentile(&latencies, 50.0), 50.0);
+        assert_eq!(percentile(&latencies, 95.0), 95.0);
+        assert_eq!(percentile(&latencies, 99.0), 99.0);
+        assert_eq!(percentile(&latencies[..1], 99.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+}
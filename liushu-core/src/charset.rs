@@ -0,0 +1,174 @@
+//! The characters a formula offers candidates of, for deployments that keep to a set such
+//! as GB2312, like teaching or fonts that lack the others. A formula names its `charset` in
+//! the config, one of [`BUILTIN`] or a file of its config dir whose characters are those of
+//! the set, and the entries with a character outside of it are dropped or tagged as they
+//! are built, see [`CharsetMode`]. Engines of either backend leave the tagged ones out while
+//! strict, see [`EngineWithRedb::strict_charset`](crate::engine::EngineWithRedb::strict_charset).
+//!
+//! Only the CJK ideographs of a text are looked up, its punctuation, letters and digits
+//! are in every set.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{IoResultExt, LiushuError};
+
+mod gb2312;
+
+/// The names of the sets that ship with liushu.
+pub const BUILTIN: [&str; 1] = ["gb2312"];
+
+/// The first code point of the bitsets of the built-in sets, that of CJK Unified
+/// Ideographs.
+const IDEOGRAPHS_START: u32 = 0x4E00;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Charset {
+    /// A built-in set, a bit of each code point from U+4E00.
+    Bits(&'static [u64]),
+    Chars(HashSet<char>),
+}
+
+impl Charset {
+    /// The set of [`BUILTIN`] named `name`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "gb2312" => Some(Self::Bits(&gb2312::GB2312)),
+            _ => None,
+        }
+    }
+
+    /// The built-in set `name`, or the characters of the file of that path from `base_dir`.
+    pub fn named(name: &str, base_dir: &Path) -> Result<Self, LiushuError> {
+        match Self::builtin(name) {
+            Some(charset) => Ok(charset),
+            None => Self::from_file(&base_dir.join(name)),
+        }
+    }
+
+    /// The characters of the file at `path` but whitespace.
+    pub fn from_file(path: &Path) -> Result<Self, LiushuError> {
+        if !path.exists() {
+            return Err(LiushuError::Missing(path.to_path_buf()));
+        }
+        let chars = fs::read_to_string(path).with_path("read charset", path)?;
+        Ok(Self::Chars(
+            chars.chars().filter(|c| !c.is_whitespace()).collect(),
+        ))
+    }
+
+    /// Whether every ideograph of `text` is in the set.
+    pub fn contains_text(&self, text: &str) -> bool {
+        text.chars()
+            .filter(|&c| is_ideograph(c))
+            .all(|c| self.contains(c))
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        match self {
+            Self::Bits(words) => {
+                let Some(offset) = (c as u32).checked_sub(IDEOGRAPHS_START) else {
+                    return false;
+                };
+                let offset = offset as usize;
+                words
+                    .get(offset / 64)
+                    .is_some_and(|word| word & (1 << (offset % 64)) != 0)
+            }
+            Self::Chars(chars) => chars.contains(&c),
+        }
+    }
+}
+
+/// Whether `c` is of the CJK Unified or Compatibility Ideographs.
+pub fn is_ideograph(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x323AF
+    )
+}
+
+/// What a build does of an entry with a character out of the charset of its formula.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CharsetMode {
+    /// Keeps it in the artifacts, listed in
+    /// [`OUT_OF_CHARSET`](crate::artifact::OUT_OF_CHARSET).
+    #[default]
+    Tag,
+    /// Leaves it out of the artifacts.
+    Drop,
+}
+
+impl CharsetMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            CharsetMode::Tag => "tag",
+            CharsetMode::Drop => "drop",
+        }
+    }
+}
+
+impl fmt::Display for CharsetMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CharsetMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [CharsetMode::Tag, CharsetMode::Drop]
+            .into_iter()
+            .find(|mode| mode.name() == name)
+            .ok_or_else(|| format!("unknown charset mode {:?}, tag or drop is expected", name))
+    }
+}
+
+impl TryFrom<String> for CharsetMode {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gb2312() {
+        let gb2312 = Charset::builtin("gb2312").unwrap();
+        let count: u32 = gb2312::GB2312.iter().map(|word| word.count_ones()).sum();
+        assert_eq!(count, 6763);
+        assert!(gb2312.contains_text("你好，world 123"));
+        // 㐀 is of extension A, 丟 a traditional character GB2312 lacks
+        assert!(!gb2312.contains_text("丟"));
+        assert!(!gb2312.contains_text("㐀"));
+        assert!(!gb2312.contains('a'));
+        assert!(Charset::builtin("big5").is_none());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chars.txt");
+        fs::write(&path, "你\n好 \n").unwrap();
+        let charset = Charset::from_file(&path).unwrap();
+        assert!(charset.contains_text("你好!"));
+        assert!(!charset.contains_text("你们"));
+        assert!(matches!(
+            Charset::from_file(&dir.path().join("missing.txt")),
+            Err(LiushuError::Missing(_))
+        ));
+        assert_eq!("drop".parse(), Ok(CharsetMode::Drop));
+        assert!("strict".parse::<CharsetMode>().is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! The 6763 hanzi of GB2312, a bit for each code point from [`super::IDEOGRAPHS_START`],
+//! each word the next 64 of them. Generated from the GB2312 codec of Python:
+//!
+//! ```python
+//! hanzi = set()
+//! for hi in range(0xB0, 0xF8):
+//!     for lo in range(0xA1, 0xFF):
+//!         try:
+//!             hanzi.add(ord(bytes([hi, lo]).decode("gb2312")))
+//!         except UnicodeDecodeError:
+//!             pass
+//! ```
+
+pub(super) const GB2312: [u64; 328] = [
+    0xef553db47f7b7f8b,
+    0x400b0243f35dfba8,
+    0x8c2c7bf78d3efb40,
+    0xa8ed1d3ae3fa6eff,
+    0x35558cf5cf83e602,
+    0xd85992b9ffabe048,
+    0x8020d7e92892ab18,
+    0x450ae74af583c438,
+    0x540077629714b000,
+    0xc8c010201420d188,
+    0x0c0413a800002121,
+    0x082870c004408000,
+    0x80000002000408c0,
+    0x3bfb792414722b7b,
+    0x38ef98351ae43327,
+    0xbf69a81328029ad1,
+    0xafc96b112fc665cf,
+    0xa00486a25053340f,
+    0xc00e3f0fe8090106,
+    0xc601001081450a88,
+    0xce00444b26e1a161,
+    0x85bbcadfd4eec7aa,
+    0x8840436ca5203a74,
+    0x3befff798bd23f06,
+    0x5b36fbcbe8eff75a,
+    0x39ee01541bfd0d49,
+    0xa91abfd82e75d855,
+    0xb40c67e0f6bff3d7,
+    0xd08bd49d081382c2,
+    0x59e074f21061065a,
+    0x6aaa0080b3128f9f,
+    0x60ac9d7ab05e3230,
+    0x8a563098c900d303,
+    0x18421f1413907000,
+    0x108080080008c060,
+    0xe6332817ec900400,
+    0x4e09f70890000758,
+    0x18c8af53fc83f485,
+    0x01146adf080c187c,
+    0x2710a011a734c80c,
+    0x00210413422228c5,
+    0x4000182041123010,
+    0x10000300c60c022b,
+    0x0249581000220022,
+    0x1792eeb09670a094,
+    0x2358002505f2cb96,
+    0x4a04cf3842cc25de,
+    0x8a001128359f0c40,
+    0x10560229910a13fa,
+    0x84f0048404200641,
+    0x412c04000c040000,
+    0x00020a4b11541206,
+    0x0094000000c00200,
+    0x242b167cbfbb0001,
+    0xe3790c7f7fa89bbb,
+    0x9f014132e00d10f4,
+    0xff1210b435728652,
+    0x8602c06b4223cf27,
+    0xa1aa3a0c1fd33106,
+    0x0801257202040812,
+    0x601062d0485040cc,
+    0x00109a0029001c80,
+    0x0080000022000004,
+    0x609ecbe668002020,
+    0x398260c03f73916e,
+    0xbd5c000648301034,
+    0x43e820e1d6fb8cd1,
+    0xc4d00500084e0600,
+    0x1602a6e189aa8d1f,
+    0x1a8b365621ed0001,
+    0x30a0650213a51fb7,
+    0xe9226c9323c7b278,
+    0x98208fe33a74e47f,
+    0xbf49bf9c2625280e,
+    0x1916b949ac543218,
+    0x0659fbc1b5220c60,
+    0x800008d98420e343,
+    0x00a1018420225500,
+    0x4080138020104800,
+    0x8020004000160d04,
+    0xe09854368de7fd40,
+    0xd249fec8091e7b8b,
+    0xba2219378dee0611,
+    0xf0daf3ec9fdd77f4,
+    0x26048d3fec424386,
+    0x0cc2628ec021fa6c,
+    0x559977ad0145d785,
+    0xa154260b4045e250,
+    0xa410344358199827,
+    0x07002280411405f2,
+    0x15a17210426600b4,
+    0x0000005441856025,
+    0xcb70c82001040201,
+    0x0095184c6a629320,
+    0x3201aab29a8b1880,
+    0x04c3f3e500c4d87a,
+    0x5072a1a1a238d44d,
+    0x44d1c15284fc980a,
+    0x4210418020c21094,
+    0xd29d02403a000000,
+    0x2432bd40a8b12f01,
+    0xd0ada723d04bd34d,
+    0x01e9adac75a10a92,
+    0xa01b9225771f801a,
+    0x738c060220cadfa1,
+    0x00d00bff003b577f,
+    0x0029a1c40088806a,
+    0x1623400905242a05,
+    0xa211201180056822,
+    0x1382484964900004,
+    0x08922980193023d5,
+    0xa004200188115402,
+    0x6022850281800400,
+    0x120200220b010090,
+    0x00001a0100834011,
+    0x0000000000000000,
+    0x4684009f00000000,
+    0x1a0004fc020012c8,
+    0x80b804020c4c2ede,
+    0x22288c020afca826,
+    0x2135c7d68f7ba0e0,
+    0x62550713f8b106c7,
+    0xfb0e6efa8a19936e,
+    0x7debcd2f48f91630,
+    0x7a2e4ca04e845892,
+    0x1190c649561eedea,
+    0x8124cfdbe83a5324,
+    0x1a8a5853634218f1,
+    0x0514aa3b24d37420,
+    0xc000480089586018,
+    0x2cd684a491018268,
+    0x02100377c4ba8886,
+    0x404aae1100388244,
+    0x15146044510028c0,
+    0x0248008210007310,
+    0x0000c00340060205,
+    0x022000080c020000,
+    0xd161b80040009000,
+    0x3b8af80032744621,
+    0x2280bbd08b00050f,
+    0x0043804007690600,
+    0x250c41d050005420,
+    0x0228110183108410,
+    0x020040a100304008,
+    0xabe3150020000040,
+    0xc624c2c6aa443180,
+    0x03d1b0008004ac13,
+    0x1d9ff3034285611e,
+    0xc3925e2678e8440a,
+    0x4000b00100852000,
+    0x0c8dca0488424a90,
+    0x000422a14203a705,
+    0x107955640c018668,
+    0x40c12000dea00002,
+    0x040003805001488b,
+    0x80d0c05d50040000,
+    0x4dafbb20970aa010,
+    0x831404601e10d921,
+    0x733fd83ba6d68848,
+    0x92130ddc497427bc,
+    0xd1392e758ba1142b,
+    0x6900880850503009,
+    0x80164010024a49d4,
+    0x5316c02089d7e564,
+    0x15e0a34586002b92,
+    0xe200196e0c03008b,
+    0xa82916a580067031,
+    0xe1487aac18802000,
+    0x5f9132e8b5d63207,
+    0x10807c0020e550a1,
+    0x421f00aa9d8a7280,
+    0x0494110002310e22,
+    0x5c10001040080022,
+    0x0580a1a5fcc80343,
+    0x6e08008004008433,
+    0x2901aad881262a4b,
+    0xba8800094490684d,
+    0x87d1000000820040,
+    0x80083161b1e6215b,
+    0xa600a069c2400800,
+    0x550a5d714a328d58,
+    0x4aa640052d579aa0,
+    0x01123fc630b12021,
+    0x50824462260a10c2,
+    0x810004c080409880,
+    0x3818000000002003,
+    0x720e4434f1a60200,
+    0x0900810192e035a2,
+    0x0000888500000400,
+    0x0080400000000000,
+    0x0000404000000000,
+    0x0000000000000000,
+    0x0800000000000000,
+    0x0000000000000082,
+    0xe7efbfff88000004,
+    0xfdffefefffbfffff,
+    0x057fffffbffefbff,
+    0x4216470685b30034,
+    0xb3058092e4105402,
+    0x180b426381305422,
+    0xa9ea07e513f5387b,
+    0x8002060005143c4c,
+    0xf496ee37bd481ad9,
+    0x355fbfb27ec0705f,
+    0x41469000455fe644,
+    0xfe1362a1063b1d40,
+    0x0c08054839028505,
+    0x581834880000144f,
+    0x4bfbbd0ed8153077,
+    0xe61dc10085008a90,
+    0x639bff72b386ed14,
+    0x0a92887bd9befd92,
+    0x177ab9801cb2d3fe,
+    0x3980fffbdc1782c9,
+    0x37df0f01590c4260,
+    0x23070623b15094a3,
+    0x310201f03102f85a,
+    0x056a3a0a1e820040,
+    0xa714800212805b84,
+    0x90011069a04b2612,
+    0x3f801802848a1000,
+    0x4e14011042400708,
+    0x0281c510180080b0,
+    0x8800021010298202,
+    0x1100028000420020,
+    0xfe0258044413e000,
+    0x0473979830283c07,
+    0x431f6210cb13ced1,
+    0xc892422e55ac278d,
+    0x7851403902885380,
+    0x2428b9008088292c,
+    0x42004421080e0c41,
+    0x1204000608680408,
+    0xe0855b3e02903031,
+    0x1082281410442936,
+    0x531b013c83344266,
+    0x00510c220e0d0404,
+    0x88000040c0000012,
+    0x000000000000004a,
+    0x000888685447dff6,
+    0x4000000000000081,
+    0x0200000000000100,
+    0x0000000000080600,
+    0x0000000000000000,
+    0x0000004000000080,
+    0x0000104000000000,
+    0xf7fdefff00000000,
+    0xfffffbfffffeff7f,
+    0x00ffffffbffffdff,
+    0x07080c06042012c2,
+    0x0000000001101624,
+    0x0000000000000000,
+    0xfffffffee0000000,
+    0x00f928df7f79ffff,
+    0xd53a000880120c32,
+    0x2fa89d18ecc2d858,
+    0x2622d60ce0109620,
+    0x9055b24002060f97,
+    0x04049800501180a2,
+    0x0000000000004000,
+    0x0000000000000000,
+    0xfffffbc000000000,
+    0x62430b08dffbeffe,
+    0x23896f74fb3b41b6,
+    0x5960e047ecd7ae7f,
+    0xa030612c098fa096,
+    0x4f7bd44e2aaa090d,
+    0x6110a9c6388bc4b2,
+    0x0202800c42000014,
+    0xe3f7d63e6485fe48,
+    0x0430e40c0c073aa0,
+    0x000000001002f680,
+    0x0000000000000000,
+    0x0010000000000000,
+    0x0000400000004000,
+    0x0000000000000100,
+    0x4000000000000000,
+    0x0000040000000000,
+    0x0000000000008000,
+    0x0000000000400400,
+    0x4000000000000000,
+    0x0000080000000000,
+    0xfffffffffebdffe0,
+    0xf7ffffbffbe77f7f,
+    0xdff7ff7eefffffff,
+    0x804fbffefbdff6f7,
+    0x0000000000000000,
+    0x7fffef0000000000,
+    0xb87e4406b6f7ff7f,
+    0x00f4179688313bf5,
+    0x724900801391a960,
+    0x42c887010024f2f3,
+    0x430524005048e3d3,
+    0x105802274a4c0000,
+    0x0014a80901162820,
+    0x00683ec000000000,
+    0x0000000000000000,
+    0xffe0000000000000,
+    0x000000f7fddbb7ff,
+    0x00000180c72e4000,
+    0x0000400000012000,
+    0xb4f7ffa800300000,
+    0x0000012003ffadf3,
+    0x0000000000000000,
+    0x0000000000000000,
+    0xfffbf00000000000,
+    0x15c301bffdcf9df7,
+    0x0a00a842810a1827,
+    0x1804800880088108,
+    0x000000000012a3be,
+    0x0000000000000000,
+    0x0000000000000000,
+    0x0000000000000000,
+    0x9000000000000000,
+    0x3dff6bffdc3769e6,
+    0x00000004f3f9fcf8,
+    0x0000000000000000,
+    0x0000000000000000,
+    0x0000000000000000,
+    0x0000000000000000,
+    0xe7eebf6f80000000,
+    0xc00b3fd85da2dffe,
+    0x69100040a00c0984,
+    0x5a0086a5b912e210,
+    0x6a80900502896800,
+    0x8000000000030010,
+    0x000000018e001ff9,
+    0x0000000000000000,
+];
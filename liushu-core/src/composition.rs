@@ -0,0 +1,700 @@
+//! What is being typed, segment by segment: the candidates of the longest prefix of the code
+//! left, and the texts selected for the segments before it. Selecting a candidate of the
+//! last segment commits them all, and backspacing over a selected one types its code again.
+//!
+//! Once committed, the candidates are the [associations](InputMethodEngine::associations)
+//! of the text of the candidate selected last until more code is typed, selecting one
+//! committing the rest of its text.
+
+use std::borrow::Cow;
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::engine::{InputMethodEngine, SearchResponse, SearchResultItem};
+use crate::error::LiushuError;
+use crate::keymap::Keymap;
+
+/// Associations of a commit, at most.
+pub const MAX_ASSOCIATIONS: usize = 32;
+
+/// Text committed by a composition, and the codes of its candidates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Commit {
+    pub text: String,
+    pub code: String,
+    /// Index of the candidate selected for each segment among all of its candidates, for the
+    /// [typing log](crate::typing_log).
+    pub indices: Vec<usize>,
+}
+
+/// What selecting a candidate did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Selected {
+    /// There is no such candidate on the page.
+    Missing,
+    /// The segment is done, the next one is active.
+    Continued,
+    Committed(Commit),
+}
+
+/// What a frontend shows of a composition while it is typed, segment by segment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Preedit {
+    pub segments: Vec<Segment>,
+    /// Byte offset of the cursor in the text of the segments, which is always at the end as
+    /// code is only typed and deleted there.
+    pub cursor: usize,
+}
+
+impl Preedit {
+    /// The text of the segments, `"你好zh"` for `你好` converted and `zh` pending.
+    pub fn text(&self) -> String {
+        self.segments.iter().map(|segment| &*segment.text).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Segment {
+    pub text: String,
+    pub kind: SegmentKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    /// The text of a candidate selected for the code typed.
+    Converted,
+    /// Code of the active segment, which the candidates are of.
+    Pending,
+    /// Code after the active segment, that no candidate is of yet.
+    Raw,
+}
+
+/// A segment selected, with what unselecting it takes back.
+#[derive(Debug)]
+struct Selection {
+    /// The code the segment matched of what was typed, shorter than that of the candidate
+    /// when it completes it.
+    typed: String,
+    text_len: usize,
+    code_len: usize,
+}
+
+#[derive(Debug)]
+pub struct Composition {
+    page_size: usize,
+    /// What the composition follows, for searches.
+    context: String,
+    /// The segments selected so far.
+    selected: Commit,
+    /// Each of [`Composition::selected`], in order.
+    selections: Vec<Selection>,
+    /// The code left, the first `response.matched_len` bytes of which are the active segment.
+    code: String,
+    response: SearchResponse,
+    page: usize,
+    /// Index of the highlighted candidate on the page.
+    highlighted: usize,
+    /// What the keys typed are remapped with before they are searched.
+    keymap: Option<Keymap>,
+    /// The text committed last, when the candidates are its associations.
+    associated: Option<String>,
+}
+
+impl Composition {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            page_size: page_size.max(1),
+            context: String::new(),
+            selected: Commit::default(),
+            selections: Vec::new(),
+            code: String::new(),
+            response: SearchResponse::default(),
+            page: 0,
+            highlighted: 0,
+            keymap: None,
+            associated: None,
+        }
+    }
+
+    /// Remaps the keys typed from now on, see [`Keymap`]. The code of the composition is
+    /// that of the dictionary, not the keys typed.
+    pub fn set_keymap(&mut self, keymap: Option<Keymap>) {
+        self.keymap = keymap;
+    }
+
+    fn remap<'a>(&self, keys: &'a str) -> Cow<'a, str> {
+        match &self.keymap {
+            Some(keymap) => keymap.code(keys),
+            None => keys.into(),
+        }
+    }
+
+    /// Starts over with the keys of `code`, typed after `context`.
+    pub fn set_input(
+        &mut self,
+        engine: &dyn InputMethodEngine,
+        code: &str,
+        context: &str,
+    ) -> Result<(), LiushuError> {
+        let code = self.remap(code).into_owned();
+        let response = engine.search_longest(&code, context)?;
+        *self = Self {
+            context: context.to_string(),
+            code,
+            response,
+            keymap: self.keymap.take(),
+            ..Self::new(self.page_size)
+        };
+        Ok(())
+    }
+
+    /// Starts over with nothing typed, the keymap is kept.
+    pub fn clear(&mut self) {
+        *self = Self {
+            keymap: self.keymap.take(),
+            ..Self::new(self.page_size)
+        };
+    }
+
+    /// Whether nothing is being composed. There may be no code left while segments are
+    /// selected, once it is backspaced over, and the candidates may be the associations
+    /// of a commit.
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty() && self.selections.is_empty()
+    }
+
+    /// The text committed last, when the candidates are its associations.
+    pub fn associated(&self) -> Option<&str> {
+        self.associated.as_deref()
+    }
+
+    /// Text of the segments selected so far.
+    pub fn selected_text(&self) -> &str {
+        &self.selected.text
+    }
+
+    /// The code left to select candidates for.
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Bytes of [`Composition::code`] in the active segment, none when nothing matches.
+    pub fn matched_len(&self) -> usize {
+        self.response.matched_len
+    }
+
+    /// The texts selected, each one segment, then the code of the active segment and what
+    /// follows it.
+    pub fn preedit(&self) -> Preedit {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        for selection in &self.selections {
+            let end = start + selection.text_len;
+            segments.push(Segment {
+                text: self.selected.text[start..end].to_string(),
+                kind: SegmentKind::Converted,
+            });
+            start = end;
+        }
+        let (pending, raw) = self.code.split_at(self.response.matched_len);
+        for (text, kind) in [(pending, SegmentKind::Pending), (raw, SegmentKind::Raw)] {
+            if !text.is_empty() {
+                segments.push(Segment {
+                    text: text.to_string(),
+                    kind,
+                });
+            }
+        }
+        Preedit {
+            cursor: self.selected.text.len() + self.code.len(),
+            segments,
+        }
+    }
+
+    /// Types the keys of `code` after the code left, which starts the active segment over.
+    pub fn push_code(
+        &mut self,
+        engine: &dyn InputMethodEngine,
+        code: &str,
+    ) -> Result<(), LiushuError> {
+        let code = format!("{}{}", self.code, self.remap(code));
+        self.response = self.search(engine, &code, &self.selected.text)?;
+        self.code = code;
+        self.associated = None;
+        self.page = 0;
+        self.highlighted = 0;
+        Ok(())
+    }
+
+    /// Deletes the last character of the code left. Once there is none, which leaves the
+    /// cursor right after the segment selected last, that segment is unselected instead and
+    /// its code is left to select a candidate for again. False when there is nothing to
+    /// delete, and nothing changes on an error. The associations of a commit are dismissed.
+    pub fn backspace(&mut self, engine: &dyn InputMethodEngine) -> Result<bool, LiushuError> {
+        if self.associated.is_some() {
+            self.clear();
+            return Ok(false);
+        }
+        if let Some((last, _)) = self.code.char_indices().next_back() {
+            if last == 0 && self.selections.is_empty() {
+                self.clear();
+                return Ok(true);
+            }
+            let code = self.code[..last].to_string();
+            self.response = self.search(engine, &code, &self.selected.text)?;
+            self.code = code;
+        } else {
+            let Some(selection) = self.selections.last() else {
+                return Ok(false);
+            };
+            let text_len = self.selected.text.len() - selection.text_len;
+            let code_len = self.selected.code.len() - selection.code_len;
+            self.response =
+                self.search(engine, &selection.typed, &self.selected.text[..text_len])?;
+            self.code = selection.typed.clone();
+            self.selected.text.truncate(text_len);
+            self.selected.code.truncate(code_len);
+            self.selected.indices.pop();
+            self.selections.pop();
+        }
+        self.page = 0;
+        self.highlighted = 0;
+        Ok(true)
+    }
+
+    /// Makes the associations of `committed` the candidates, if it has any.
+    fn associate(&mut self, engine: &dyn InputMethodEngine, committed: String) {
+        match engine.associations(&committed, MAX_ASSOCIATIONS) {
+            Ok(items) if items.is_empty() => {}
+            Ok(items) => {
+                self.response = SearchResponse {
+                    items,
+                    matched_len: 0,
+                };
+                self.associated = Some(committed);
+            }
+            Err(error) => warn!(error = %error.report(), "cannot find associations"),
+        }
+    }
+
+    /// Candidates of the longest prefix of `code` typed after `selected`.
+    fn search(
+        &self,
+        engine: &dyn InputMethodEngine,
+        code: &str,
+        selected: &str,
+    ) -> Result<SearchResponse, LiushuError> {
+        if code.is_empty() {
+            return Ok(SearchResponse::default());
+        }
+        engine.search_longest(code, &format!("{}{}", self.context, selected))
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The current page, from 0.
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.response.items.len().div_ceil(self.page_size)
+    }
+
+    pub fn page_candidates(&self) -> &[SearchResultItem] {
+        let start = (self.page * self.page_size).min(self.response.items.len());
+        let end = (start + self.page_size).min(self.response.items.len());
+        &self.response.items[start..end]
+    }
+
+    /// Index of the highlighted candidate on the page.
+    pub fn highlighted(&self) -> usize {
+        self.highlighted
+    }
+
+    pub fn highlighted_candidate(&self) -> Option<&SearchResultItem> {
+        self.page_candidates().get(self.highlighted)
+    }
+
+    pub fn next_page(&mut self) -> bool {
+        if self.page + 1 < self.page_count() {
+            self.page += 1;
+            self.highlighted = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn prev_page(&mut self) -> bool {
+        if self.page > 0 {
+            self.page -= 1;
+            self.highlighted = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Moves the highlight down, onto the next page past the last candidate of this one.
+    pub fn highlight_next(&mut self) -> bool {
+        if self.highlighted + 1 < self.page_candidates().len() {
+            self.highlighted += 1;
+            true
+        } else {
+            self.next_page()
+        }
+    }
+
+    /// Moves the highlight up, onto the last candidate of the previous page past the first.
+    pub fn highlight_prev(&mut self) -> bool {
+        if self.highlighted > 0 {
+            self.highlighted -= 1;
+            true
+        } else if self.prev_page() {
+            self.highlighted = self.page_size - 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Selects the `index`th candidate of the page for the active segment, which commits
+    /// the composition when no code is left after it, or the rest of the text of an
+    /// association. The associations of what is committed are the candidates then, none
+    /// when they can't be found, which doesn't fail the commit.
+    pub fn select(
+        &mut self,
+        engine: &dyn InputMethodEngine,
+        index: usize,
+    ) -> Result<Selected, LiushuError> {
+        let Some(item) = self.page_candidates().get(index) else {
+            return Ok(Selected::Missing);
+        };
+        let added = match &self.associated {
+            Some(associated) => item.text.strip_prefix(associated.as_str()),
+            None => None,
+        };
+        let text = format!("{}{}", self.selected.text, added.unwrap_or(&item.text));
+        let code = format!("{}{}", self.selected.code, item.code);
+        let mut indices = self.selected.indices.clone();
+        indices.push(self.page * self.page_size + index);
+        let (typed, rest) = self.code.split_at(self.response.matched_len);
+        if rest.is_empty() {
+            let committed = item.text.clone();
+            self.clear();
+            self.associate(engine, committed);
+            return Ok(Selected::Committed(Commit {
+                text,
+                code,
+                indices,
+            }));
+        }
+
+        let selection = Selection {
+            typed: typed.to_string(),
+            text_len: item.text.len(),
+            code_len: item.code.len(),
+        };
+        let rest = rest.to_string();
+        self.response = self.search(engine, &rest, &text)?;
+        self.selections.push(selection);
+        self.code = rest;
+        self.selected = Commit {
+            text,
+            code,
+            indices,
+        };
+        self.page = 0;
+        self.highlighted = 0;
+        Ok(Selected::Continued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    fn fixture() -> MemoryEngine {
+        [
+            ("你", "ni"),
+            ("尼", "ni"),
+            ("泥", "ni"),
+            ("你好", "nihao"),
+            ("好", "hao"),
+            ("吗", "ma"),
+        ]
+        .into_iter()
+        .map(|(text, code)| SearchResultItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            weight: 1,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        })
+        .collect()
+    }
+
+    fn texts(composition: &Composition) -> Vec<&str> {
+        composition
+            .page_candidates()
+            .iter()
+            .map(|item| item.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_pages() {
+        let engine = fixture();
+        let mut composition = Composition::new(2);
+        composition.set_input(&engine, "ni", "").unwrap();
+        assert_eq!(
+            (texts(&composition), composition.page_count()),
+            (vec!["你", "尼"], 2)
+        );
+
+        assert!(composition.highlight_next());
+        assert!(composition.highlight_next());
+        assert_eq!((composition.page(), composition.highlighted()), (1, 0));
+        assert_eq!(texts(&composition), ["泥", "你好"]);
+        assert!(!composition.next_page());
+
+        assert!(composition.highlight_prev());
+        assert_eq!(composition.highlighted_candidate().unwrap().text, "尼");
+        assert!(composition.highlight_prev());
+        assert!(!composition.highlight_prev());
+        assert_eq!(composition.highlighted_candidate().unwrap().text, "你");
+    }
+
+    #[test]
+    fn test_segments() {
+        let engine = fixture();
+        let mut composition = Composition::new(5);
+        composition.set_input(&engine, "nihaoma", "").unwrap();
+        assert_eq!(composition.matched_len(), 5);
+        assert_eq!(composition.select(&engine, 3).unwrap(), Selected::Missing);
+
+        assert_eq!(composition.select(&engine, 0).unwrap(), Selected::Continued);
+        assert_eq!(
+            (composition.selected_text(), composition.code()),
+            ("你好", "ma")
+        );
+        assert_eq!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit {
+                text: "你好吗".to_string(),
+                code: "nihaoma".to_string(),
+                indices: vec![0, 0],
+            })
+        );
+        assert!(composition.is_empty());
+
+        // nothing matches the rest, there is nothing to select
+        composition.set_input(&engine, "nixx", "").unwrap();
+        assert_eq!(composition.select(&engine, 0).unwrap(), Selected::Continued);
+        assert_eq!((composition.code(), composition.matched_len()), ("xx", 0));
+        assert_eq!(composition.select(&engine, 0).unwrap(), Selected::Missing);
+    }
+
+    fn segments(composition: &Composition) -> Vec<(&'static str, String)> {
+        composition
+            .preedit()
+            .segments
+            .into_iter()
+            .map(|segment| {
+                let kind = match segment.kind {
+                    SegmentKind::Converted => "converted",
+                    SegmentKind::Pending => "pending",
+                    SegmentKind::Raw => "raw",
+                };
+                (kind, segment.text)
+            })
+            .collect()
+    }
+
+    fn assert_preedit(composition: &Composition, expected: &[(&'static str, &str)]) {
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(kind, text)| (kind, text.to_string()))
+            .collect();
+        assert_eq!(segments(composition), expected);
+        let preedit = composition.preedit();
+        assert_eq!(preedit.cursor, preedit.text().len());
+    }
+
+    #[test]
+    fn test_preedit() {
+        let engine = fixture();
+        let mut composition = Composition::new(5);
+        assert_eq!(composition.preedit(), Preedit::default());
+
+        composition.set_input(&engine, "nixx", "").unwrap();
+        assert_preedit(&composition, &[("pending", "ni"), ("raw", "xx")]);
+        composition.set_input(&engine, "nihaomaz", "").unwrap();
+        assert_preedit(&composition, &[("pending", "nihao"), ("raw", "maz")]);
+        composition.select(&engine, 0).unwrap();
+        assert_preedit(
+            &composition,
+            &[("converted", "你好"), ("pending", "ma"), ("raw", "z")],
+        );
+        assert_eq!(composition.preedit().text(), "你好maz");
+        assert_eq!(composition.preedit().cursor, "你好maz".len());
+    }
+
+    #[test]
+    fn test_backspace() {
+        let engine = fixture();
+        let mut composition = Composition::new(5);
+        assert!(!composition.backspace(&engine).unwrap());
+
+        // 你好 completes the segment ni, which it gives back and not its own code
+        composition.set_input(&engine, "nima", "").unwrap();
+        assert_eq!(composition.select(&engine, 3).unwrap(), Selected::Continued);
+        assert_preedit(&composition, &[("converted", "你好"), ("pending", "ma")]);
+        assert!(composition.backspace(&engine).unwrap());
+        assert_preedit(&composition, &[("converted", "你好"), ("pending", "m")]);
+        assert!(composition.backspace(&engine).unwrap());
+        assert_preedit(&composition, &[("converted", "你好")]);
+        assert!(!composition.is_empty());
+        assert_eq!(composition.select(&engine, 0).unwrap(), Selected::Missing);
+
+        assert!(composition.backspace(&engine).unwrap());
+        assert_preedit(&composition, &[("pending", "ni")]);
+        assert_eq!(texts(&composition), ["你", "尼", "泥", "你好"]);
+
+        // typed again, selected otherwise
+        composition.push_code(&engine, "ma").unwrap();
+        assert_preedit(&composition, &[("pending", "ni"), ("raw", "ma")]);
+        assert_eq!(composition.select(&engine, 0).unwrap(), Selected::Continued);
+        assert_eq!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit {
+                text: "你吗".to_string(),
+                code: "nima".to_string(),
+                indices: vec![0, 0],
+            })
+        );
+
+        // segments are unselected the last first
+        composition.set_input(&engine, "nihaomani", "").unwrap();
+        composition.select(&engine, 0).unwrap();
+        composition.select(&engine, 0).unwrap();
+        assert_preedit(
+            &composition,
+            &[
+                ("converted", "你好"),
+                ("converted", "吗"),
+                ("pending", "ni"),
+            ],
+        );
+        for _ in 0..3 {
+            composition.backspace(&engine).unwrap();
+        }
+        assert_preedit(&composition, &[("converted", "你好"), ("pending", "ma")]);
+        assert_eq!(composition.selected_text(), "你好");
+        for _ in 0..3 {
+            composition.backspace(&engine).unwrap();
+        }
+        assert_preedit(&composition, &[("pending", "nihao")]);
+        assert_eq!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit {
+                text: "你好".to_string(),
+                code: "nihao".to_string(),
+                indices: vec![0],
+            })
+        );
+    }
+
+    #[test]
+    fn test_keymap() {
+        let engine = fixture();
+        let mut composition = Composition::new(5);
+        composition.set_keymap(Some(Keymap::preset("dvorak").unwrap()));
+        // the keys of `nihao` on QWERTY, typed on Dvorak
+        composition.set_input(&engine, "bc", "").unwrap();
+        for key in ["d", "a", "r"] {
+            composition.push_code(&engine, key).unwrap();
+        }
+        assert_eq!(composition.code(), "nihao");
+        assert_eq!(texts(&composition), ["你好"]);
+        composition.clear();
+        composition.push_code(&engine, "ma").unwrap();
+        assert_eq!(texts(&composition), ["吗"]);
+        assert_eq!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit {
+                text: "吗".to_string(),
+                code: "ma".to_string(),
+                indices: vec![0],
+            })
+        );
+    }
+
+    /// The fixture, with the associations an index of its texts would find.
+    struct Associating(MemoryEngine);
+
+    impl InputMethodEngine for Associating {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            self.0.search(code)
+        }
+
+        fn associations(
+            &self,
+            committed: &str,
+            limit: usize,
+        ) -> Result<Vec<SearchResultItem>, LiushuError> {
+            let mut items = self.0.search("")?;
+            items.retain(|item| item.text.len() > committed.len());
+            items.retain(|item| item.text.starts_with(committed));
+            items.truncate(limit);
+            Ok(items)
+        }
+    }
+
+    #[test]
+    fn test_associations() {
+        let engine = Associating(fixture());
+        let mut composition = Composition::new(5);
+        composition.set_input(&engine, "ni", "").unwrap();
+        assert!(matches!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit { text, .. }) if text == "你"
+        ));
+        assert!(composition.is_empty());
+        assert_eq!(composition.associated(), Some("你"));
+        assert_eq!(texts(&composition), ["你好"]);
+
+        // the rest of the phrase, which has none
+        assert_eq!(
+            composition.select(&engine, 0).unwrap(),
+            Selected::Committed(Commit {
+                text: "好".to_string(),
+                code: "nihao".to_string(),
+                indices: vec![0],
+            })
+        );
+        assert_eq!(composition.associated(), None);
+        assert!(texts(&composition).is_empty());
+
+        // dismissed by typing on
+        composition.set_input(&engine, "ni", "").unwrap();
+        composition.select(&engine, 0).unwrap();
+        composition.push_code(&engine, "ma").unwrap();
+        assert_eq!(composition.associated(), None);
+        assert_eq!(texts(&composition), ["吗"]);
+
+        composition.clear();
+        composition.set_input(&engine, "ni", "").unwrap();
+        composition.select(&engine, 0).unwrap();
+        assert!(!composition.backspace(&engine).unwrap());
+        assert!(texts(&composition).is_empty());
+    }
+}
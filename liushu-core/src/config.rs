@@ -1,116 +1,600 @@
-use std::{fs::File, path::Path};
+#[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use patricia_tree::PatriciaMap;
-use rusqlite::{params, Connection};
+#[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+use rusqlite::params;
 use serde::{Deserialize, Serialize};
-use serde_dhall::StaticType;
+use tracing::warn;
+#[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+use tracing::{debug, info};
 
+use crate::charset::{Charset, CharsetMode};
+use crate::engine::{
+    ByCodeLength, ByWeight, Calculator, DateFormatter, Pin, Pins, RankStage, Ranker,
+    RankingPipeline, Transformer, Transformers, DEFAULT_STAGES,
+};
+use crate::error::LiushuError;
+use crate::keymap::Keymap;
+#[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+use crate::{
+    artifact::{ArtifactOpenOptions, OpenMode, NORMALIZATION_FORMS},
+    dict::{
+        import, open_dictionary, DictItem, CREATE_DICT_TABLE_SQL, CREATE_NORMALIZATION_TABLE_SQL,
+        CREATE_OUT_OF_CHARSET_TABLE_SQL,
+    },
+    error::IoResultExt,
+    progress::{check_cancelled, estimate_rows, CANCEL_CHECK_ROWS},
+};
+#[cfg(feature = "dict-build")]
 use crate::{
-    dict::{DictItem, DICTIONARY},
-    dirs::PROJECT_DIRS,
-    error::LiushuError,
+    dict::{self, BuildOptions, BuildReport},
+    progress::{NoProgress, ProgressSink},
 };
 
-#[derive(Debug, Serialize, Deserialize, StaticType)]
+mod overlay;
+
+pub use self::overlay::{FormulaOverlay, Overlay, OVERLAY_FILE};
+
+/// The alphabet of a formula that doesn't say.
+const DEFAULT_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz";
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub formulas: Vec<Formula>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default, rename = "typingLog")]
+    pub typing_log: TypingLogConfig,
+    /// The formula to start on before any is switched to, the first one when left out.
+    #[serde(default, rename = "defaultFormula")]
+    pub default_formula: Option<String>,
+    /// The keymap of the formulas without one of their own, checked when the config loads.
+    #[serde(default)]
+    pub keymap: Option<Keymap>,
+    #[serde(default)]
+    pub transformers: TransformersConfig,
+}
+
+/// Which of the [`Transformer`]s candidates are made by, all of them unless turned off.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransformersConfig {
+    /// Dates typed with numbers, see [`DateFormatter`].
+    pub date: bool,
+    /// Arithmetic typed after an `=`, see [`Calculator`].
+    pub calculator: bool,
+}
+
+impl Default for TransformersConfig {
+    fn default() -> Self {
+        Self {
+            date: true,
+            calculator: true,
+        }
+    }
+}
+
+/// Commands run around a deploy, for packagers and frontends to pick up new artifacts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Hooks {
+    /// Run after each deploy with the target dir and the path of a JSON summary of the
+    /// deploy as arguments.
+    pub post_deploy: Option<String>,
+    /// Fail the deploy when a hook fails instead of warning about it.
+    pub fail_on_hook_error: bool,
+}
+
+/// The [typing log](crate::typing_log), off unless `enabled`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TypingLogConfig {
+    pub enabled: bool,
+    /// Also log the committed text, which is left out otherwise.
+    pub log_text: bool,
+    /// Size past which the log is rotated.
+    pub max_bytes: u64,
+}
+
+impl Default for TypingLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            log_text: false,
+            max_bytes: 1 << 20,
+        }
+    }
 }
 
 impl Config {
-    pub fn load() -> Self {
-        Self::load_from_path(PROJECT_DIRS.config_dir.join("main.dhall"))
+    /// The config of [`PROJECT_DIRS`](crate::dirs::PROJECT_DIRS).
+    #[cfg(feature = "desktop-dirs")]
+    pub fn load() -> Result<Self, LiushuError> {
+        Self::load_from_path(crate::dirs::PROJECT_DIRS.config_dir.join("main.dhall"))
     }
 
-    fn load_from_path<P: AsRef<Path>>(path: P) -> Self {
-        serde_dhall::from_file(path)
-            .static_type_annotation()
-            .parse()
-            .unwrap()
+    /// Fields left out of the config take their defaults, so it isn't checked against a
+    /// static type. The [`Overlay`] next to it is applied over it.
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        let mut config: Self =
+            serde_dhall::from_file(path)
+                .parse()
+                .map_err(|e| LiushuError::Config {
+                    path: Some(path.to_path_buf()),
+                    source: Box::new(e),
+                })?;
+        let config_dir = path.parent().unwrap_or(Path::new("."));
+        Overlay::load(config_dir)?.apply(&mut config);
+        config.check_fallbacks().map_err(|e| LiushuError::Config {
+            path: Some(path.to_path_buf()),
+            source: e.into(),
+        })?;
+        Ok(config)
+    }
+
+    /// The formulas searched, in order, when the formula `id` has no candidate: each of its
+    /// `fallbacks` followed by those of its own, once each. Disabled ones are left out,
+    /// though not their own fallbacks.
+    pub fn fallbacks(&self, id: &str) -> Vec<&str> {
+        let mut chain = Vec::new();
+        self.push_fallbacks(id, id, &mut chain);
+        chain.retain(|id| self.formula(id).is_ok_and(Formula::is_enabled));
+        chain
+    }
+
+    fn push_fallbacks<'a>(&'a self, root: &str, id: &str, chain: &mut Vec<&'a str>) {
+        let Ok(formula) = self.formula(id) else {
+            return;
+        };
+        for fallback in &formula.fallbacks {
+            if fallback != root && !chain.contains(&fallback.as_str()) {
+                chain.push(fallback);
+                self.push_fallbacks(root, fallback, chain);
+            }
+        }
+    }
+
+    /// Every fallback is a formula of the config, and none of them falls back to itself.
+    fn check_fallbacks(&self) -> Result<(), String> {
+        for formula in &self.formulas {
+            self.check_chain(&mut vec![formula.id.as_str()])?;
+        }
+        Ok(())
+    }
+
+    fn check_chain<'a>(&'a self, path: &mut Vec<&'a str>) -> Result<(), String> {
+        let id = path[path.len() - 1];
+        let Ok(formula) = self.formula(id) else {
+            return Ok(());
+        };
+        for fallback in &formula.fallbacks {
+            if self.formula(fallback).is_err() {
+                return Err(format!(
+                    "the formula {} falls back to {}, which the config lacks",
+                    id, fallback
+                ));
+            }
+            let cycle = path.contains(&fallback.as_str());
+            path.push(fallback);
+            if cycle {
+                return Err(format!(
+                    "the fallbacks of {} come back to it: {}",
+                    fallback,
+                    path.join(" -> ")
+                ));
+            }
+            self.check_chain(path)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// The keymap the keys typed for the formula `id` go through, its own or that of the
+    /// config.
+    pub fn keymap(&self, id: &str) -> Option<&Keymap> {
+        let formula = self.formulas.iter().find(|formula| formula.id == id);
+        formula
+            .and_then(|formula| formula.keymap.as_ref())
+            .or(self.keymap.as_ref())
+    }
+
+    /// The transformers turned on for the formula `id`, of its alphabet.
+    pub fn transformers(&self, id: &str) -> Transformers {
+        let alphabet = self
+            .formulas
+            .iter()
+            .find(|formula| formula.id == id)
+            .map_or(DEFAULT_ALPHABET, Formula::alphabet);
+        let mut transformers: Vec<Box<dyn Transformer>> = Vec::new();
+        if self.transformers.date {
+            transformers.push(Box::new(DateFormatter));
+        }
+        if self.transformers.calculator {
+            transformers.push(Box::new(Calculator));
+        }
+        Transformers::new(alphabet, transformers)
+    }
+
+    /// The stages the candidates of the formula `id` are ranked by, its `ranking` or
+    /// [`DEFAULT_STAGES`], see [`RankingPipeline`]. The `context` stage ranks with
+    /// `context`, such as the model of [`Hmm::context_ranker`](crate::hmm::Hmm::context_ranker),
+    /// and is left out without one.
+    pub fn ranking(&self, id: &str, context: Option<Arc<dyn Ranker>>) -> RankingPipeline {
+        let formula = self.formulas.iter().find(|formula| formula.id == id);
+        let stages = formula
+            .and_then(|formula| formula.ranking.as_deref())
+            .unwrap_or(&DEFAULT_STAGES);
+        let mut pipeline = RankingPipeline::new();
+        for &stage in stages {
+            pipeline = match stage {
+                RankStage::Weight => pipeline.stage(stage, ByWeight),
+                RankStage::CodeLength => pipeline.stage(stage, ByCodeLength),
+                RankStage::Context => match &context {
+                    Some(context) => pipeline.stage(stage, context.clone()),
+                    None => pipeline,
+                },
+                RankStage::Pins => match formula.filter(|formula| !formula.pins.is_empty()) {
+                    Some(formula) => pipeline.stage(stage, Pins(formula.pins.clone())),
+                    None => pipeline,
+                },
+            };
+        }
+        pipeline
+    }
+
+    pub fn formula(&self, id: &str) -> Result<&Formula, LiushuError> {
+        self.formulas
+            .iter()
+            .find(|formula| formula.id == id)
+            .ok_or_else(|| LiushuError::FormulaUnknown(id.to_string()))
+    }
+
+    /// Like [`Config::formula`], for a formula to be switched to.
+    pub fn enabled_formula(&self, id: &str) -> Result<&Formula, LiushuError> {
+        let formula = self.formula(id)?;
+        if !formula.enabled {
+            return Err(LiushuError::FormulaDisabled(id.to_string()));
+        }
+        Ok(formula)
+    }
+
+    /// Ids of the formulas that are disabled.
+    pub fn disabled_formulas(&self) -> Vec<String> {
+        let disabled = self.formulas.iter().filter(|formula| !formula.enabled);
+        disabled.map(|formula| formula.id.clone()).collect()
+    }
+
+    /// The formula a session starts on: the one `remembered` from the last session, see
+    /// [`SessionState`](crate::state::SessionState), then the `defaultFormula` of the
+    /// config, then its first enabled formula. One of those that is gone from the config
+    /// or disabled is only a warning.
+    pub fn initial_formula(&self, remembered: Option<&str>) -> Result<&Formula, LiushuError> {
+        for (id, source) in [
+            (remembered, "remembered"),
+            (self.default_formula.as_deref(), "default"),
+        ] {
+            let Some(id) = id else { continue };
+            match self.enabled_formula(id) {
+                Ok(formula) => return Ok(formula),
+                Err(LiushuError::FormulaDisabled(_)) => {
+                    warn!(formula = id, "the {} formula is disabled", source)
+                }
+                Err(_) => warn!(formula = id, "the {} formula isn't in the config", source),
+            }
+        }
+        if self.formulas.is_empty() {
+            return Err(LiushuError::InvalidInput(
+                "the config has no formula".to_string(),
+            ));
+        }
+        self.formulas
+            .iter()
+            .find(|formula| formula.enabled)
+            .ok_or_else(|| LiushuError::InvalidInput("every formula is disabled".to_string()))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, StaticType)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Formula {
     pub id: String,
-    name: Option<String>,
+    pub name: Option<String>,
     dictionaries: Vec<String>,
+    /// The annotation table of the formula, see [`BuildOptions::annotation`].
+    #[serde(default)]
+    annotation: Option<String>,
+    #[serde(default)]
+    keymap: Option<Keymap>,
+    #[serde(default)]
+    alphabet: Option<String>,
+    /// Ids of the formulas searched when this one has no candidate, see
+    /// [`Config::fallbacks`].
+    #[serde(default)]
+    fallbacks: Vec<String>,
+    #[serde(default = "normalize_by_default", rename = "normalizeUnicode")]
+    normalize_unicode: bool,
+    /// The stages its candidates are ranked by, in order, see [`Config::ranking`].
+    #[serde(default)]
+    ranking: Option<Vec<RankStage>>,
+    /// Texts put first for their code by the `pins` stage.
+    #[serde(default)]
+    pins: Vec<Pin>,
+    /// Whether it can be switched to, see [`Formula::is_enabled`].
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    /// The characters of its entries, see [`Formula::charset`].
+    #[serde(default)]
+    charset: Option<String>,
+    #[serde(default, rename = "charsetMode")]
+    charset_mode: CharsetMode,
+}
+
+fn normalize_by_default() -> bool {
+    true
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 impl Formula {
+    /// Unless the config or its [`Overlay`] says otherwise. A disabled formula isn't
+    /// deployed, switched to or fallen back to.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Paths of the dictionaries of the formula, which are relative to its config dir.
+    pub fn dictionaries(&self, config_base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+        let self_config_dir = config_base_dir.as_ref().join(&self.id);
+        self.dictionaries
+            .iter()
+            .map(|dict_path| self_config_dir.join(dict_path))
+            .collect()
+    }
+
+    /// The characters of the codes of the formula, input with others is given to the
+    /// transformers. The lowercase ASCII letters unless the config says otherwise.
+    pub fn alphabet(&self) -> &str {
+        self.alphabet.as_deref().unwrap_or(DEFAULT_ALPHABET)
+    }
+
+    /// Whether the dictionaries of the formula are built normalized, see
+    /// [`normalize`](crate::normalize). They are unless the config says otherwise.
+    pub fn normalize_unicode(&self) -> bool {
+        self.normalize_unicode
+    }
+
+    /// Path of the annotation table of the formula, relative to its config dir too.
+    pub fn annotation(&self, config_base_dir: impl AsRef<Path>) -> Option<PathBuf> {
+        let self_config_dir = config_base_dir.as_ref().join(&self.id);
+        self.annotation
+            .as_ref()
+            .map(|path| self_config_dir.join(path))
+    }
+
+    /// The charset of the formula, one of [`BUILTIN`](crate::charset::BUILTIN) or a file
+    /// relative to its config dir, and what its build does of the entries out of it.
+    pub fn charset(
+        &self,
+        config_base_dir: impl AsRef<Path>,
+    ) -> Result<Option<(Charset, CharsetMode)>, LiushuError> {
+        let self_config_dir = config_base_dir.as_ref().join(&self.id);
+        self.charset
+            .as_deref()
+            .map(|name| Ok((Charset::named(name, &self_config_dir)?, self.charset_mode)))
+            .transpose()
+    }
+
+    /// Paths of every file the artifacts of the formula are built from, its dictionaries,
+    /// annotation table and charset file.
+    pub fn sources(&self, config_base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+        let config_base_dir = config_base_dir.as_ref();
+        let mut sources = self.dictionaries(config_base_dir);
+        sources.extend(self.annotation(config_base_dir));
+        let charset = self.charset.as_ref();
+        sources.extend(
+            charset
+                .filter(|name| Charset::builtin(name).is_none())
+                .map(|path| config_base_dir.join(&self.id).join(path)),
+        );
+        sources
+    }
+
+    #[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
     pub fn compile(
         &self,
         config_base_dir: impl AsRef<Path>,
         target_dir: impl AsRef<Path>,
+    ) -> Result<(), LiushuError> {
+        self.compile_with_progress(config_base_dir, target_dir, &NoProgress)
+    }
+
+    /// Like [`Formula::compile`], reporting the rows of each dictionary to `progress` and
+    /// stopping once it is cancelled, without leaving the database behind.
+    #[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+    #[tracing::instrument(name = "compile", skip_all, fields(formula = %self.id))]
+    pub fn compile_with_progress(
+        &self,
+        config_base_dir: impl AsRef<Path>,
+        target_dir: impl AsRef<Path>,
+        progress: &dyn ProgressSink,
     ) -> Result<(), LiushuError> {
         let self_config_dir = config_base_dir.as_ref().join(&self.id);
         let db_path = target_dir.as_ref().join(format!("{}.db3", self.id));
-        let mut conn = Connection::open(db_path)?;
+        // written aside and renamed over the database, like the artifacts of `compile2`
+        let temp = dict::temp_path(&db_path);
+        let _ = fs::remove_file(&temp);
+        let written = self
+            .write_db3(&self_config_dir, &temp, progress)
+            .and_then(|_| {
+                fs::rename(&temp, &db_path).with_path("replace", &db_path)?;
+                Ok(())
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        written
+    }
+
+    #[cfg(all(feature = "sqlite-engine", feature = "dict-build"))]
+    fn write_db3(
+        &self,
+        self_config_dir: &Path,
+        db_path: &Path,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), LiushuError> {
+        let mut conn = ArtifactOpenOptions::new(OpenMode::CreateNew).open_sqlite(db_path)?;
         let tx = conn.transaction()?;
+        tx.execute("DROP TABLE IF EXISTS dict", [])?;
+        tx.execute(CREATE_DICT_TABLE_SQL, [])?;
+        tx.execute("DROP TABLE IF EXISTS normalization", [])?;
+        if self.normalize_unicode {
+            tx.execute(CREATE_NORMALIZATION_TABLE_SQL, [])?;
+            for (of, form) in NORMALIZATION_FORMS {
+                tx.execute(
+                    "INSERT INTO normalization (of, form) VALUES (?1, ?2)",
+                    params![of, form],
+                )?;
+            }
+        }
+        tx.execute("DROP TABLE IF EXISTS out_of_charset", [])?;
+        let charset = match &self.charset {
+            Some(name) => Some(Charset::named(name, self_config_dir)?),
+            None => None,
+        };
+        if charset.is_some() && self.charset_mode == CharsetMode::Tag {
+            tx.execute(CREATE_OUT_OF_CHARSET_TABLE_SQL, [])?;
+        }
         for dict_path in &self.dictionaries {
+            check_cancelled(progress)?;
             let dict_path = self_config_dir.join(dict_path);
-            let mut rdr = csv::ReaderBuilder::new()
-                .delimiter(b'\t')
-                .comment(Some(b'#'))
-                .from_path(dict_path)?;
-            for result in rdr.deserialize() {
-                let dict: DictItem = result?;
+            debug!(dictionary = %dict_path.display(), "compiling dictionary");
+            let estimate = match import::is_scel(&dict_path) {
+                true => None,
+                false => estimate_rows(&dict_path),
+            };
+            progress.on_start(&dict_path.to_string_lossy(), estimate);
+            let items: Box<dyn Iterator<Item = Result<DictItem, LiushuError>>> =
+                if import::is_scel(&dict_path) {
+                    Box::new(import::scel(&dict_path)?.into_iter().map(Ok))
+                } else {
+                    let parse_error = |e| LiushuError::dict_parse(&dict_path, e);
+                    let rdr = open_dictionary(&dict_path)?;
+                    Box::new(
+                        rdr.into_deserialize()
+                            .map(move |row| row.map_err(parse_error)),
+                    )
+                };
+            let mut rows = 0;
+            for dict in items {
+                let mut dict = dict?;
+                if self.normalize_unicode {
+                    dict::normalize_item(&mut dict);
+                }
+                if !charset.as_ref().is_none_or(|c| c.contains_text(&dict.text)) {
+                    match self.charset_mode {
+                        CharsetMode::Drop => continue,
+                        CharsetMode::Tag => tx.execute(
+                            "INSERT INTO out_of_charset (text) VALUES (?1) ON CONFLICT DO NOTHING",
+                            params![dict.text],
+                        )?,
+                    };
+                }
+                // a row given twice keeps its rank, the first id
                 tx.execute(
-                    "INSERT INTO dict (text, code, weight, comment) VALUES (?1, ?2, ?3, ?4)",
+                    "INSERT INTO dict (text, code, weight, comment) VALUES (?1, ?2, ?3, ?4) ON CONFLICT (text, code) DO NOTHING",
                     params![dict.text, dict.code, dict.weight, dict.comment],
                 )?;
+                // a text has the weight and comment of its last row under any code, as in
+                // the redb dictionary
+                tx.execute(
+                    "UPDATE dict SET weight = ?2, comment = ?3 WHERE text = ?1",
+                    params![dict.text, dict.weight, dict.comment],
+                )?;
+                rows += 1;
+                progress.on_advance(rows);
+                if rows % CANCEL_CHECK_ROWS == 0 {
+                    check_cancelled(progress)?;
+                }
             }
+            info!(dictionary = %dict_path.display(), rows, "compiled dictionary");
+            progress.on_finish(&format!("{}: {} rows", dict_path.display(), rows));
         }
+        check_cancelled(progress)?;
+        tx.commit()?;
         Ok(())
     }
 
+    #[cfg(feature = "dict-build")]
     pub fn compile2(
         &self,
         config_base_dir: impl AsRef<Path>,
         target_dir: impl AsRef<Path>,
-    ) -> Result<(), LiushuError> {
-        let self_config_dir = config_base_dir.as_ref().join(&self.id);
-        let db_path = target_dir.as_ref().join(format!("{}.redb", self.id));
-
-        let table = redb::Database::create(db_path)?;
-        let tx = table.begin_write()?;
-        let mut trie = PatriciaMap::new();
-        {
-            let mut dict_table = tx.open_table(DICTIONARY)?;
-            for dict_path in &self.dictionaries {
-                let dict_path = self_config_dir.join(dict_path);
-                let mut rdr = csv::ReaderBuilder::new()
-                    .delimiter(b'\t')
-                    .comment(Some(b'#'))
-                    .from_path(dict_path)?;
-                for result in rdr.deserialize() {
-                    let DictItem {
-                        text,
-                        code,
-                        weight,
-                        comment,
-                    } = result?;
-                    dict_table.insert(text.as_str(), (weight, comment.as_deref()))?;
-
-                    if trie.get(&code).is_none() {
-                        trie.insert_str(code.as_str(), vec![text]);
-                    } else if let Some(entry) = trie.get_mut(code.as_str()) {
-                        entry.push(text);
-                    }
-                }
-            }
-        }
-        tx.commit()?;
+    ) -> Result<BuildReport, LiushuError> {
+        self.compile2_with_progress(config_base_dir, target_dir, &NoProgress)
+    }
 
-        let trie_path = target_dir.as_ref().join(format!("{}.trie", self.id));
-        let trie_writer = File::create(trie_path)?;
-        bincode::serialize_into(trie_writer, &trie)?;
-        Ok(())
+    #[cfg(feature = "dict-build")]
+    #[tracing::instrument(name = "compile2", skip_all, fields(formula = %self.id))]
+    pub fn compile2_with_progress(
+        &self,
+        config_base_dir: impl AsRef<Path>,
+        target_dir: impl AsRef<Path>,
+        progress: &dyn ProgressSink,
+    ) -> Result<BuildReport, LiushuError> {
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.compile2_with_options(config_base_dir, target_dir, options, progress)
+    }
+
+    /// Like [`Formula::compile2`] with the options of a build, such as tracking where each
+    /// entry comes from. The annotation table and charset of the formula are those of the
+    /// options when they have one, and the entries are normalized unless either says
+    /// otherwise.
+    #[cfg(feature = "dict-build")]
+    pub fn compile2_with_options(
+        &self,
+        config_base_dir: impl AsRef<Path>,
+        target_dir: impl AsRef<Path>,
+        options: BuildOptions,
+        progress: &dyn ProgressSink,
+    ) -> Result<BuildReport, LiushuError> {
+        let config_base_dir = config_base_dir.as_ref();
+        let (charset, charset_mode) = match options.charset {
+            Some(charset) => (Some(charset), options.charset_mode),
+            None => match self.charset(config_base_dir)? {
+                Some((charset, mode)) => (Some(Arc::new(charset)), mode),
+                None => (None, options.charset_mode),
+            },
+        };
+        let options = BuildOptions {
+            annotation: options
+                .annotation
+                .or_else(|| self.annotation(config_base_dir)),
+            normalize_unicode: options.normalize_unicode && self.normalize_unicode,
+            charset,
+            charset_mode,
+            ..options
+        };
+        dict::build(
+            &self.dictionaries(config_base_dir),
+            target_dir.as_ref(),
+            &self.id,
+            options,
+            progress,
+        )
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "native"))]
 mod tests {
     use super::*;
+    use crate::engine::{EngineWithRedb, InputMethodEngine};
 
     impl Clone for Formula {
         fn clone(&self) -> Self {
@@ -118,13 +602,23 @@ mod tests {
                 id: self.id.clone(),
                 name: self.name.clone(),
                 dictionaries: self.dictionaries.clone(),
+                annotation: self.annotation.clone(),
+                keymap: self.keymap.clone(),
+                alphabet: self.alphabet.clone(),
+                fallbacks: self.fallbacks.clone(),
+                normalize_unicode: self.normalize_unicode,
+                ranking: self.ranking.clone(),
+                pins: self.pins.clone(),
+                enabled: self.enabled,
+                charset: self.charset.clone(),
+                charset_mode: self.charset_mode,
             }
         }
     }
 
     #[test]
     fn test_prelude() {
-        let config = Config::load_from_path("../prelude/main.dhall");
+        let config = Config::load_from_path("../prelude/main.dhall").unwrap();
 
         assert_eq!(config.formulas.len(), 1);
 
@@ -133,5 +627,478 @@ mod tests {
         assert_eq!(sunman.name, Some(String::from("山人全息")));
 
         assert_eq!(sunman.dictionaries.len(), 3);
+        assert!(config.hooks.post_deploy.is_none());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        std::fs::write(
+            &path,
+            r#"{ formulas = [] : List { id : Text, name : Optional Text, dictionaries : List Text }
+               , hooks = { postDeploy = Some "notify" }
+               }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        assert_eq!(config.hooks.post_deploy.as_deref(), Some("notify"));
+        assert!(!config.hooks.fail_on_hook_error);
+
+        std::fs::write(&path, "{ formulas = [ { id = 1 } ] }").unwrap();
+        assert!(matches!(
+            Config::load_from_path(&path),
+            Err(LiushuError::Config { path: Some(_), .. })
+        ));
+    }
+
+    #[test]
+    fn test_initial_formula() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        let formula = |id: &str| {
+            format!(
+                r#"{{ id = "{}", name = None Text, dictionaries = [] : List Text }}"#,
+                id
+            )
+        };
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{ formulas = [ {}, {}, {} ], defaultFormula = Some "second" }}"#,
+                formula("first"),
+                formula("second"),
+                formula("third")
+            ),
+        )
+        .unwrap();
+        let mut config = Config::load_from_path(&path).unwrap();
+        let initial =
+            |config: &Config, remembered| config.initial_formula(remembered).unwrap().id.clone();
+
+        assert_eq!(initial(&config, Some("third")), "third");
+        assert_eq!(initial(&config, None), "second");
+        // renamed or removed since
+        assert_eq!(initial(&config, Some("gone")), "second");
+        config.default_formula = Some("gone too".to_string());
+        assert_eq!(initial(&config, Some("gone")), "first");
+        config.default_formula = None;
+        assert_eq!(initial(&config, None), "first");
+
+        config.formulas.clear();
+        assert!(matches!(
+            config.initial_formula(Some("first")),
+            Err(LiushuError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        let write = |fallbacks: [&str; 4]| {
+            let formulas: Vec<_> = ["a", "b", "c", "d"]
+                .iter()
+                .zip(fallbacks)
+                .map(|(id, fallbacks)| {
+                    format!(
+                        r#"{{ id = "{}", name = None Text, dictionaries = [] : List Text, fallbacks = {} }}"#,
+                        id, fallbacks
+                    )
+                })
+                .collect();
+            std::fs::write(
+                &path,
+                format!("{{ formulas = [ {} ] }}", formulas.join(", ")),
+            )
+            .unwrap();
+        };
+        let none = "[] : List Text";
+        write([r#"[ "b", "d" ]"#, r#"[ "c", "d" ]"#, none, none]);
+        let config = Config::load_from_path(&path).unwrap();
+        assert_eq!(config.fallbacks("a"), ["b", "c", "d"]);
+        assert_eq!(config.fallbacks("b"), ["c", "d"]);
+        assert!(config.fallbacks("c").is_empty());
+        assert!(config.fallbacks("gone").is_empty());
+        // a disabled formula is skipped, not the formulas it falls back to
+        let mut overlay = Overlay::default();
+        overlay.set_enabled("b", false);
+        overlay.save(dir.path()).unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        assert_eq!(config.fallbacks("a"), ["c", "d"]);
+        overlay.set_enabled("d", false);
+        overlay.save(dir.path()).unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        assert_eq!(config.fallbacks("a"), ["c"]);
+        assert_eq!(config.disabled_formulas(), ["b", "d"]);
+        assert!(matches!(
+            config.enabled_formula("b"),
+            Err(LiushuError::FormulaDisabled(id)) if id == "b"
+        ));
+        assert_eq!(config.initial_formula(Some("d")).unwrap().id, "a");
+        std::fs::remove_file(dir.path().join(OVERLAY_FILE)).unwrap();
+
+        let error = |fallbacks| {
+            write(fallbacks);
+            let error = Config::load_from_path(&path).unwrap_err();
+            assert!(matches!(error, LiushuError::Config { path: Some(_), .. }));
+            error.report()
+        };
+        assert!(error([r#"[ "b" ]"#, r#"[ "c" ]"#, r#"[ "b" ]"#, none])
+            .contains("the fallbacks of b come back to it: a -> b -> c -> b"));
+        assert!(error([r#"[ "a" ]"#, none, none, none])
+            .contains("the fallbacks of a come back to it: a -> a"));
+        assert!(error([r#"[ "e" ]"#, none, none, none])
+            .contains("the formula a falls back to e, which the config lacks"));
+    }
+
+    #[test]
+    fn test_keymap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        let formula = |id: &str, keymap: &str| {
+            format!(
+                r#"{{ id = "{}", name = None Text, dictionaries = [] : List Text{} }}"#,
+                id, keymap
+            )
+        };
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{ formulas = [ {}, {} ], keymap = Some "dvorak" }}"#,
+                // the formulas of a list are of the same type
+                formula("dvorak", ", keymap = None { keys : Text, codes : Text }"),
+                formula(
+                    "swapped",
+                    r#", keymap = Some { keys = "ab", codes = "ba" }"#
+                ),
+            ),
+        )
+        .unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        assert_eq!(config.keymap("dvorak").unwrap().code("bcdar"), "nihao");
+        assert_eq!(config.keymap("swapped").unwrap().code("ab"), "ba");
+        assert_eq!(config.keymap("gone"), config.keymap.as_ref());
+
+        // not a bijection
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{ formulas = [ {} ] }}"#,
+                formula("broken", r#", keymap = Some { keys = "ab", codes = "cc" }"#)
+            ),
+        )
+        .unwrap();
+        let error = Config::load_from_path(&path).unwrap_err();
+        assert!(matches!(error, LiushuError::Config { path: Some(_), .. }));
+        assert!(
+            error
+                .report()
+                .contains("the keymap remaps both a and b to c"),
+            "{}",
+            error.report()
+        );
+        std::fs::write(
+            &path,
+            r#"{ formulas = [] : List { id : Text }, keymap = Some "qwertz" }"#,
+        )
+        .unwrap();
+        assert!(Config::load_from_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_transformers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        std::fs::write(
+            &path,
+            r#"{ formulas = [ { id = "digits", name = None Text, dictionaries = [] : List Text, alphabet = Some "0123456789" } ]
+               , transformers = { calculator = False }
+               }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        assert!(config.transformers.date);
+        let texts = |id: &str, input: &str| -> Vec<String> {
+            let items = config.transformers(id).transform(input);
+            items.into_iter().map(|item| item.text).collect()
+        };
+        assert_eq!(texts("digits", "5/1")[0], "5月1日");
+        assert!(texts("digits", "=3*7").is_empty());
+        // codes of the alphabet of the formula are left alone
+        assert!(texts("digits", "51").is_empty());
+        assert!(texts("gone", "=3*7").is_empty());
+    }
+
+    #[test]
+    fn test_ranking() {
+        use crate::engine::{RankContext, SearchResultItem};
+
+        struct Context;
+
+        impl Ranker for Context {
+            fn rescore(&self, _ctx: &RankContext<'_>, _items: &mut Vec<SearchResultItem>) {}
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.dhall");
+        let write = |ranking: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    r#"{{ formulas =
+                        [ {{ id = "ranked", name = None Text, dictionaries = [] : List Text, ranking = Some {}, pins = [ {{ code = "ni", text = "你" }} ] }}
+                        , {{ id = "plain", name = None Text, dictionaries = [] : List Text, ranking = None (List Text), pins = [] : List {{ code : Text, text : Text }} }}
+                        ] }}"#,
+                    ranking
+                ),
+            )
+            .unwrap();
+        };
+        write(r#"[ "weight", "context", "pins" ]"#);
+        let config = Config::load_from_path(&path).unwrap();
+        let stages = |id: &str, context: Option<Arc<dyn Ranker>>| -> Vec<RankStage> {
+            config.ranking(id, context).stages().collect()
+        };
+        assert_eq!(
+            stages("ranked", Some(Arc::new(Context))),
+            [RankStage::Weight, RankStage::Context, RankStage::Pins]
+        );
+        // without a model
+        assert_eq!(stages("ranked", None), [RankStage::Weight, RankStage::Pins]);
+        // nothing pinned
+        assert_eq!(
+            stages("plain", Some(Arc::new(Context))),
+            [RankStage::Context]
+        );
+
+        write(r#"[ "weight", "boost" ]"#);
+        let error = Config::load_from_path(&path).unwrap_err();
+        assert!(matches!(error, LiushuError::Config { .. }));
+        assert!(error.report().contains("unknown ranking stage \"boost\""));
+    }
+
+    fn fixture_formula(config_dir: &Path) -> Formula {
+        std::fs::create_dir(config_dir.join("fixture")).unwrap();
+        std::fs::write(
+            config_dir.join("fixture/words.tsv"),
+            "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n",
+        )
+        .unwrap();
+
+        Formula {
+            id: "fixture".to_string(),
+            name: None,
+            dictionaries: vec!["words.tsv".to_string()],
+            annotation: None,
+            keymap: None,
+            alphabet: None,
+            fallbacks: Vec::new(),
+            normalize_unicode: true,
+            ranking: None,
+            pins: Vec::new(),
+            enabled: true,
+            charset: None,
+            charset_mode: CharsetMode::Tag,
+        }
+    }
+
+    #[test]
+    fn test_compile2_and_search() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let formula = fixture_formula(config_dir.path());
+
+        formula.compile2(&config_dir, &target_dir).unwrap();
+
+        let engine = EngineWithRedb::with_formula(&target_dir, "fixture").unwrap();
+        let result = engine.search("ni").unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(engine.search("hao").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compile2_provenance() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let formula_dir = config_dir.path().join("fixture");
+        std::fs::create_dir_all(&formula_dir).unwrap();
+        std::fs::write(
+            formula_dir.join("words.tsv"),
+            "text\tcode\tweight\n# a comment\n你\tni\t10\n好\thao\t5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            formula_dir.join("phrases.tsv"),
+            "text\tcode\tweight\n你好\tni hao\t500\n\n好\thao\t7\n好\th\t1",
+        )
+        .unwrap();
+        let formula = Formula {
+            id: "fixture".to_string(),
+            name: None,
+            dictionaries: vec!["words.tsv".to_string(), "phrases.tsv".to_string()],
+            annotation: None,
+            keymap: None,
+            alphabet: None,
+            fallbacks: Vec::new(),
+            normalize_unicode: true,
+            ranking: None,
+            pins: Vec::new(),
+            enabled: true,
+            charset: None,
+            charset_mode: CharsetMode::Tag,
+        };
+
+        formula.compile2(&config_dir, &target_dir).unwrap();
+        let engine = EngineWithRedb::with_formula(&target_dir, "fixture").unwrap();
+        assert_eq!(engine.provenance("你", "ni").unwrap(), None);
+        drop(engine);
+
+        let options = BuildOptions {
+            force: true,
+            track_provenance: true,
+            ..Default::default()
+        };
+        formula
+            .compile2_with_options(&config_dir, &target_dir, options, &NoProgress)
+            .unwrap();
+        let engine = EngineWithRedb::with_formula(&target_dir, "fixture").unwrap();
+        let provenance = |text: &str, code: &str| {
+            engine
+                .provenance(text, code)
+                .unwrap()
+                .map(|provenance| provenance.to_string())
+        };
+        assert_eq!(provenance("你", "ni").as_deref(), Some("words.tsv:3"));
+        assert_eq!(
+            provenance("你好", "ni hao").as_deref(),
+            Some("phrases.tsv:2")
+        );
+        // the last row of a candidate given twice
+        assert_eq!(provenance("好", "hao").as_deref(), Some("phrases.tsv:4"));
+        assert_eq!(provenance("好", "h").as_deref(), Some("phrases.tsv:5"));
+        assert_eq!(provenance("好", "ni"), None);
+        let source = engine.provenance("你", "ni").unwrap().unwrap().source;
+        assert_eq!(Path::new(&source), formula_dir.join("words.tsv"));
+    }
+
+    #[test]
+    fn test_compile2_annotation() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        fixture_formula(config_dir.path());
+        std::fs::write(
+            config_dir.path().join("fixture/keys.tsv"),
+            "code\tannotation\nn\t乙\ni\t丨\n",
+        )
+        .unwrap();
+        let main = config_dir.path().join("main.dhall");
+        std::fs::write(
+            &main,
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], annotation = Some "keys.tsv" } ] }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(&main).unwrap();
+        let formula = config.formula("fixture").unwrap();
+        assert_eq!(
+            formula.sources(&config_dir),
+            [
+                config_dir.path().join("fixture/words.tsv"),
+                config_dir.path().join("fixture/keys.tsv")
+            ]
+        );
+
+        formula.compile2(&config_dir, &target_dir).unwrap();
+        let engine = EngineWithRedb::with_formula(&target_dir, "fixture")
+            .unwrap()
+            .annotate(true);
+        let comment = engine.search("ni").unwrap()[0].comment.clone();
+        assert_eq!(comment.as_deref(), Some("乙 丨"));
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_compile2_emits_events() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let formula = fixture_formula(config_dir.path());
+
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            formula.compile2(&config_dir, &target_dir).unwrap();
+        });
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("compile2{formula=fixture}"));
+        assert!(output.contains("compiled dictionary"));
+        assert!(output.contains("rows=2"));
+        assert!(output.contains("wrote trie codes=2"));
+    }
+
+    #[derive(Default)]
+    struct RecordingProgress(std::sync::Mutex<Vec<(String, Option<u64>)>>);
+
+    impl ProgressSink for RecordingProgress {
+        fn on_start(&self, name: &str, total_hint: Option<u64>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((format!("start {}", name), total_hint));
+        }
+
+        fn on_advance(&self, n: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(("advance".to_string(), Some(n)));
+        }
+
+        fn on_finish(&self, summary: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((format!("finish {}", summary), None));
+        }
+    }
+
+    #[test]
+    fn test_compile2_reports_progress() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let formula = fixture_formula(config_dir.path());
+
+        let progress = RecordingProgress::default();
+        formula
+            .compile2_with_progress(&config_dir, &target_dir, &progress)
+            .unwrap();
+
+        let events = progress.0.into_inner().unwrap();
+        assert!(events[0].0.starts_with("start "));
+        assert_eq!(events[0].1, Some(2));
+        let counts: Vec<u64> = events
+            .iter()
+            .filter(|(name, _)| name == "advance")
+            .map(|(_, n)| n.unwrap())
+            .collect();
+        assert_eq!(counts, [1, 2]);
+        assert!(events.last().unwrap().0.ends_with("2 rows"));
     }
 }
@@ -0,0 +1,124 @@
+//! What the user changed of the config without editing it, read from [`OVERLAY_FILE`] next
+//! to `main.dhall`,
+//!
+//! ```dhall
+//! { formulas = [ { id = "pinyin", enabled = False } ] }
+//! ```
+//!
+//! and applied over the config as it loads. `liushu formula enable|disable` rewrites it,
+//! leaving the config itself as the user or their distribution wrote it.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_dhall::StaticType;
+use tracing::warn;
+
+use super::Config;
+use crate::error::{IoResultExt, LiushuError};
+
+/// The file of the config dir the overlay is read from.
+pub const OVERLAY_FILE: &str = "overlay.dhall";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, StaticType)]
+pub struct Overlay {
+    pub formulas: Vec<FormulaOverlay>,
+}
+
+/// Overrides the fields of the formula `id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, StaticType)]
+pub struct FormulaOverlay {
+    pub id: String,
+    pub enabled: bool,
+}
+
+impl Overlay {
+    /// That of `config_dir`, an empty one while there is no such file.
+    pub fn load(config_dir: &Path) -> Result<Self, LiushuError> {
+        let path = config_dir.join(OVERLAY_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_dhall::from_file(&path)
+            .parse()
+            .map_err(|e| LiushuError::Config {
+                path: Some(path.to_path_buf()),
+                source: Box::new(e),
+            })
+    }
+
+    /// Writes it to `config_dir`, replacing the overlay there at once.
+    pub fn save(&self, config_dir: &Path) -> Result<(), LiushuError> {
+        let path = config_dir.join(OVERLAY_FILE);
+        let temp = config_dir.join(format!("{}.tmp", OVERLAY_FILE));
+        let dhall = serde_dhall::serialize(self)
+            .static_type_annotation()
+            .to_string()
+            .map_err(|e| LiushuError::io_at("encode", &path, e))?;
+        fs::write(&temp, format!("{}\n", dhall)).with_path("write overlay", &temp)?;
+        fs::rename(&temp, &path).with_path("replace overlay", &path)
+    }
+
+    pub fn set_enabled(&mut self, id: &str, enabled: bool) {
+        match self.formulas.iter_mut().find(|formula| formula.id == id) {
+            Some(formula) => formula.enabled = enabled,
+            None => self.formulas.push(FormulaOverlay {
+                id: id.to_string(),
+                enabled,
+            }),
+        }
+    }
+
+    /// Overrides the formulas of `config`, warning about those it lacks.
+    pub(super) fn apply(&self, config: &mut Config) {
+        for overlay in &self.formulas {
+            match config.formulas.iter_mut().find(|f| f.id == overlay.id) {
+                Some(formula) => formula.enabled = overlay.enabled,
+                None => {
+                    warn!(formula = %overlay.id, "the overlay names a formula the config lacks")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("main.dhall"),
+            r#"{ formulas = [
+                { id = "sunman", name = None Text, dictionaries = [ "words.tsv" ], enabled = True },
+                { id = "pinyin", name = None Text, dictionaries = [ "words.tsv" ], enabled = False }
+            ] }"#,
+        )
+        .unwrap();
+        let enabled = |dir: &Path| {
+            let config = Config::load_from_path(dir.join("main.dhall")).unwrap();
+            let formulas = config.formulas.iter();
+            formulas.map(|f| f.is_enabled()).collect::<Vec<_>>()
+        };
+        assert_eq!(Overlay::load(dir.path()).unwrap(), Overlay::default());
+        assert_eq!(enabled(dir.path()), [true, false]);
+
+        let mut overlay = Overlay::default();
+        overlay.set_enabled("sunman", false);
+        overlay.set_enabled("pinyin", true);
+        overlay.set_enabled("sunman", false);
+        overlay.set_enabled("gone", false);
+        overlay.save(dir.path()).unwrap();
+        assert_eq!(Overlay::load(dir.path()).unwrap(), overlay);
+        assert_eq!(overlay.formulas.len(), 3);
+        assert_eq!(enabled(dir.path()), [false, true]);
+
+        // an empty one is typed, as dhall can't tell the type of an empty list
+        Overlay::default().save(dir.path()).unwrap();
+        assert_eq!(Overlay::load(dir.path()).unwrap(), Overlay::default());
+        assert_eq!(enabled(dir.path()), [true, false]);
+    }
+}
@@ -1,12 +1,1478 @@
-use crate::{config::Config, dirs::PROJECT_DIRS, error::LiushuError};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-pub fn deploy() -> Result<(), LiushuError> {
-    let config = Config::load();
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
-    for formula in config.formulas {
-        formula.compile(&PROJECT_DIRS.config_dir, &PROJECT_DIRS.target_dir)?;
-        formula.compile2(&PROJECT_DIRS.config_dir, &PROJECT_DIRS.target_dir)?;
+use crate::{
+    config::{Config, Formula, Hooks},
+    diagnostics::{Diagnostics, Warning},
+    dict,
+    dirs::{
+        lock::{Lock, ARTIFACTS_LOCK},
+        preflight, MyProjectDirs,
+    },
+    engine::{EngineWithRedb, InputMethodEngine, MISSING_DEFINITION},
+    error::{IoResultExt, LiushuError},
+    hmm::MODEL_FILE,
+    progress::{check_cancelled, NoProgress, ProgressSink},
+    userdict::USER_DICT_FILE,
+};
+
+mod job;
+
+pub use self::job::{DeployJob, JobHandle, ProgressSnapshot};
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct DeploySummary {
+    /// In the order of the config.
+    pub formulas: Vec<FormulaSummary>,
+    /// About the deploy rather than a formula, such as a failing hook.
+    pub warnings: Vec<Warning>,
+    /// Artifacts of formulas no longer in the config removed by [`DeployOptions::prune`].
+    pub pruned: Vec<PathBuf>,
+}
+
+impl DeploySummary {
+    pub fn failed(&self) -> impl Iterator<Item = &FormulaSummary> {
+        self.formulas
+            .iter()
+            .filter(|formula| formula.status == FormulaStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormulaStatus {
+    Deployed,
+    /// Skipped as its dictionaries haven't changed since it was last deployed.
+    Unchanged,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct FormulaSummary {
+    pub id: String,
+    pub status: FormulaStatus,
+    pub duration_secs: f64,
+    /// Entries of the dictionaries, as of the last deploy for an unchanged formula.
+    pub entries: u64,
+    pub warnings: Vec<Warning>,
+    pub error: Option<LiushuError>,
+}
+
+/// What a formula was last deployed from, kept next to its artifacts.
+#[derive(Debug, Serialize, Deserialize)]
+struct Stamp {
+    /// Path, size and modification time in nanoseconds of each dictionary.
+    sources: Vec<(PathBuf, u64, u128)>,
+    entries: u64,
+}
+
+impl Stamp {
+    fn path(target_dir: &Path, id: &str) -> PathBuf {
+        target_dir.join(format!("{}.stamp", id))
+    }
+
+    fn read(target_dir: &Path, id: &str) -> Option<Self> {
+        let stamp = fs::read(Self::path(target_dir, id)).ok()?;
+        serde_json::from_slice(&stamp).ok()
+    }
+
+    /// `None` when a dictionary can't be looked at, the build will tell why.
+    fn sources(inputs: &[PathBuf]) -> Option<Vec<(PathBuf, u64, u128)>> {
+        inputs
+            .iter()
+            .map(|input| {
+                let metadata = fs::metadata(input).ok()?;
+                let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                Some((input.clone(), metadata.len(), modified.as_nanos()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeployOptions {
+    /// Search the artifacts of every formula once built, which takes a while to open those
+    /// of huge dictionaries.
+    pub verify: bool,
+    /// Number of backups kept in the target dir, older ones are removed after each deploy.
+    pub keep_backups: usize,
+    /// Remove the artifacts of formulas no longer in the config, they are only warned about
+    /// otherwise.
+    pub prune: bool,
+    /// Wait for another process deploying to the target dir rather than failing, see
+    /// [`Lock::deploying`].
+    pub wait: bool,
+    /// Also build the formulas that are disabled, see [`Formula::is_enabled`]. Their
+    /// artifacts are kept either way.
+    pub include_disabled: bool,
+}
+
+impl Default for DeployOptions {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            keep_backups: 3,
+            prune: false,
+            wait: false,
+            include_disabled: false,
+        }
+    }
+}
+
+impl DeployOptions {
+    /// Those of `config` a deploy builds.
+    pub fn formulas<'a>(&self, config: &'a Config) -> Vec<&'a Formula> {
+        let formulas = config.formulas.iter();
+        formulas
+            .filter(|formula| self.include_disabled || formula.is_enabled())
+            .collect()
+    }
+}
+
+/// Callbacks of an embedder around a deploy.
+pub trait DeployHooks {
+    /// Called once every formula is deployed, whether or not some of them failed.
+    fn post_deploy(&self, target_dir: &Path, summary: &DeploySummary) -> Result<(), LiushuError>;
+
+    /// Whether an error of a hook fails the deploy, it's a warning of the summary otherwise.
+    fn fail_on_error(&self) -> bool {
+        false
+    }
+}
+
+/// Summary of the last deploy passed to the post-deploy command, in the target dir.
+pub const SUMMARY_FILE: &str = "deploy.json";
+
+/// The hooks of the config, run as shell commands.
+struct CommandHooks<'a>(&'a Hooks);
+
+impl DeployHooks for CommandHooks<'_> {
+    fn post_deploy(&self, target_dir: &Path, summary: &DeploySummary) -> Result<(), LiushuError> {
+        let Some(command) = &self.0.post_deploy else {
+            return Ok(());
+        };
+        let summary_path = target_dir.join(SUMMARY_FILE);
+        let json = serde_json::to_vec(summary)
+            .map_err(|e| LiushuError::io_at("write deploy summary", &summary_path, e))?;
+        fs::write(&summary_path, json).with_path("write deploy summary", &summary_path)?;
+        let output = shell(command)
+            .arg(target_dir)
+            .arg(&summary_path)
+            .output()
+            .map_err(|e| {
+                LiushuError::io(format!("cannot run the post-deploy hook {}", command), e)
+            })?;
+        if !output.status.success() {
+            return Err(LiushuError::HookFailed {
+                command: command.clone(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        info!(command, "ran the post-deploy hook");
+        Ok(())
+    }
+
+    fn fail_on_error(&self) -> bool {
+        self.0.fail_on_hook_error
+    }
+}
+
+/// Runs `command` with the shell, the arguments added to it follow the command.
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(format!("{} \"$@\"", command)).arg("sh");
+        shell
+    }
+}
+
+/// Number of codes of the trie searched when verifying a formula.
+const PROBE_CODES: usize = 3;
+
+/// Dir of the target dir holding the artifacts replaced by each deploy.
+pub const BACKUP_DIR: &str = "backups";
+
+/// Dir of the target dir that formulas are built and verified in before their artifacts
+/// are renamed into the target dir.
+pub const STAGING_DIR: &str = "staging";
+
+/// Extensions of the files deployed for a formula.
+const FORMULA_ARTIFACTS: [&str; 5] = ["db3", "redb", "trie", "assoc", "stamp"];
+
+/// The artifacts a deploy replaced, named after the milliseconds since the unix epoch at
+/// which it started.
+#[derive(Debug, Serialize)]
+pub struct Backup {
+    pub timestamp: u64,
+    pub path: PathBuf,
+    /// Formulas with artifacts in the backup, which may no longer be in the config.
+    pub formulas: Vec<String>,
+}
+
+impl Backup {
+    fn open(path: PathBuf) -> Option<Self> {
+        let timestamp = path.file_name()?.to_str()?.parse().ok()?;
+        let mut formulas: Vec<String> = backup_files(&path)
+            .ok()?
+            .iter()
+            .filter_map(|file| Some(file.file_stem()?.to_str()?.to_string()))
+            .collect();
+        formulas.sort();
+        formulas.dedup();
+        Some(Self {
+            timestamp,
+            path,
+            formulas,
+        })
+    }
+}
+
+pub fn deploy(config: &Config, dirs: &MyProjectDirs) -> Result<DeploySummary, LiushuError> {
+    deploy_with_progress(config, dirs, DeployOptions::default(), &NoProgress)
+}
+
+/// Deploys with the callbacks of an embedder in place of the hooks of the config.
+pub fn deploy_with(
+    config: &Config,
+    dirs: &MyProjectDirs,
+    hooks: &dyn DeployHooks,
+) -> Result<DeploySummary, LiushuError> {
+    let rows = AtomicU64::new(0);
+    run(
+        config,
+        dirs,
+        DeployOptions::default(),
+        &NoProgress,
+        hooks,
+        &rows,
+    )
+}
+
+/// Deploys every formula of the config at the same time, a failing formula doesn't stop the
+/// others.
+///
+/// Only a target dir that can't be created or written to, or lacks the space to deploy every
+/// formula, or that another process is deploying to, is an error. Failed formulas are listed
+/// in the summary, as are the formulas whose artifacts fail the verification. `progress`
+/// counts the deployed formulas. The hooks of the config run once it's done, a failing one
+/// is a warning of the summary unless the config says otherwise.
+///
+/// Once `progress` is cancelled, the formulas being built stop and keep their previous
+/// artifacts, and the deploy is [`LiushuError::Cancelled`] without running the hooks. The
+/// formulas deployed by then keep their new ones.
+pub fn deploy_with_progress(
+    config: &Config,
+    dirs: &MyProjectDirs,
+    options: DeployOptions,
+    progress: &dyn ProgressSink,
+) -> Result<DeploySummary, LiushuError> {
+    let rows = AtomicU64::new(0);
+    run(
+        config,
+        dirs,
+        options,
+        progress,
+        &CommandHooks(&config.hooks),
+        &rows,
+    )
+}
+
+/// Deploys, adding the rows each formula compiles to `rows`.
+fn run(
+    config: &Config,
+    dirs: &MyProjectDirs,
+    options: DeployOptions,
+    progress: &dyn ProgressSink,
+    hooks: &dyn DeployHooks,
+    rows: &AtomicU64,
+) -> Result<DeploySummary, LiushuError> {
+    fs::create_dir_all(&dirs.target_dir).with_path("create target dir", &dirs.target_dir)?;
+    let _lock = Lock::deploying(&dirs.target_dir, options.wait)?;
+    let deployed = options.formulas(config);
+    if deployed.len() < config.formulas.len() {
+        info!(disabled = ?config.disabled_formulas(), "skipping disabled formulas");
+    }
+    let inputs: Vec<PathBuf> = deployed
+        .iter()
+        .flat_map(|formula| formula.sources(&dirs.config_dir))
+        .collect();
+    preflight::check(&dirs.target_dir, &inputs, preflight::DEPLOY_RATIO)?;
+    let backup_dir = new_backup_dir(&dirs.target_dir);
+    let staging_dir = dirs.target_dir.join(STAGING_DIR);
+    let total = deployed.len() as u64;
+    progress.on_start(&format!("deploying {} formulas", total), Some(total));
+    let done = AtomicU64::new(0);
+    let formulas: Vec<FormulaSummary> = thread::scope(|scope| {
+        let workers: Vec<_> = deployed
+            .iter()
+            .map(|&formula| {
+                let (done, backup_dir, staging_dir) = (&done, &backup_dir, &staging_dir);
+                scope.spawn(move || {
+                    let compiling = FormulaProgress {
+                        deploy: progress,
+                        rows,
+                        counted: AtomicU64::new(0),
+                    };
+                    let summary =
+                        deploy_formula(formula, dirs, backup_dir, staging_dir, options, &compiling);
+                    progress.on_advance(done.fetch_add(1, Ordering::Relaxed) + 1);
+                    summary
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .zip(&deployed)
+            .map(|(worker, formula)| {
+                worker.join().unwrap_or_else(|_| FormulaSummary {
+                    id: formula.id.clone(),
+                    status: FormulaStatus::Failed,
+                    duration_secs: 0.0,
+                    entries: 0,
+                    warnings: Vec::new(),
+                    error: Some(LiushuError::Other(format!(
+                        "deploying {} panicked",
+                        formula.id
+                    ))),
+                })
+            })
+            .collect()
+    });
+    // only there if something was replaced
+    let _ = fs::remove_dir(&backup_dir);
+    // verifying opened the artifacts built there
+    let _ = fs::remove_file(staging_dir.join(ARTIFACTS_LOCK));
+    let _ = fs::remove_dir(&staging_dir);
+    let cancelled = formulas
+        .iter()
+        .any(|formula| matches!(formula.error, Some(LiushuError::Cancelled)));
+    if cancelled {
+        // left as it was before the deploy
+        if let Some(backups) = backup_dir.parent() {
+            let _ = fs::remove_dir(backups);
+        }
+        progress.on_finish("cancelled the deploy");
+        return Err(LiushuError::Cancelled);
+    }
+    if let Err(error) = prune_backups(dirs, options.keep_backups) {
+        warn!(error = %error.report(), "cannot remove old backups");
+    }
+    let mut diagnostics = Diagnostics::new();
+    let mut pruned = Vec::new();
+    match orphans(config, &dirs.target_dir) {
+        Ok(orphans) => {
+            for orphan in orphans {
+                if !options.prune {
+                    diagnostics.warn(Warning::OrphanArtifact { path: orphan });
+                } else if let Err(error) = fs::remove_file(&orphan) {
+                    diagnostics.warn(Warning::CannotRemove {
+                        path: orphan,
+                        error: error.to_string(),
+                    });
+                } else {
+                    pruned.push(orphan);
+                }
+            }
+        }
+        Err(error) => warn!(error = %error.report(), "cannot look for orphaned artifacts"),
+    }
+    let mut summary = DeploySummary {
+        formulas,
+        warnings: diagnostics.into_warnings(),
+        pruned,
+    };
+    progress.on_finish(&format!(
+        "deployed {} formulas, {} failed",
+        total,
+        summary.failed().count()
+    ));
+    if let Err(error) = hooks.post_deploy(&dirs.target_dir, &summary) {
+        if hooks.fail_on_error() {
+            return Err(error);
+        }
+        summary.warnings.push(Warning::HookFailed {
+            error: error.report(),
+        });
+    }
+    Ok(summary)
+}
+
+/// The progress of compiling a formula during a deploy, cancelled along with it, whose rows
+/// add up with those of the other formulas.
+struct FormulaProgress<'a> {
+    deploy: &'a dyn ProgressSink,
+    rows: &'a AtomicU64,
+    /// Rows of the current dictionary already added.
+    counted: AtomicU64,
+}
+
+impl ProgressSink for FormulaProgress<'_> {
+    fn on_start(&self, _name: &str, _total_hint: Option<u64>) {
+        self.counted.store(0, Ordering::Relaxed);
+    }
+
+    fn on_advance(&self, n: u64) {
+        let counted = self.counted.swap(n, Ordering::Relaxed);
+        self.rows
+            .fetch_add(n.saturating_sub(counted), Ordering::Relaxed);
+    }
+
+    fn on_finish(&self, _summary: &str) {}
+
+    fn is_cancelled(&self) -> bool {
+        self.deploy.is_cancelled()
+    }
+}
+
+/// Whether the sources of `formula` changed since it was last deployed, `None` when it has
+/// no stamp or a source can't be looked at.
+pub(crate) fn is_stale(formula: &Formula, dirs: &MyProjectDirs) -> Option<bool> {
+    let previous = Stamp::read(&dirs.target_dir, &formula.id)?;
+    let sources = Stamp::sources(&formula.sources(&dirs.config_dir))?;
+    Some(sources != previous.sources)
+}
+
+/// Builds a formula unless it is unchanged, linking its previous artifacts into `backup_dir`
+/// first.
+///
+/// The formula is built and verified in `staging_dir`, then each artifact is renamed over
+/// the deployed one, which is restored from the backup if a rename fails. An engine that
+/// has the previous artifacts open keeps reading them until it is reopened, and none opens
+/// them while they are renamed.
+fn deploy_formula(
+    formula: &Formula,
+    dirs: &MyProjectDirs,
+    backup_dir: &Path,
+    staging_dir: &Path,
+    options: DeployOptions,
+    progress: &dyn ProgressSink,
+) -> FormulaSummary {
+    let start = Instant::now();
+    let mut summary = FormulaSummary {
+        id: formula.id.clone(),
+        status: FormulaStatus::Deployed,
+        duration_secs: 0.0,
+        entries: 0,
+        warnings: Vec::new(),
+        error: None,
+    };
+    let stamp_path = Stamp::path(&dirs.target_dir, &formula.id);
+    let sources = Stamp::sources(&formula.sources(&dirs.config_dir));
+    let previous = Stamp::read(&dirs.target_dir, &formula.id);
+    let deployed = ["db3", "redb", "trie", "assoc"].iter().all(|extension| {
+        let artifact = format!("{}.{}", formula.id, extension);
+        dirs.target_dir.join(artifact).exists()
+    });
+    if let (Some(sources), Some(previous)) = (&sources, previous) {
+        if deployed && *sources == previous.sources {
+            info!(formula = %formula.id, "formula is unchanged");
+            summary.status = FormulaStatus::Unchanged;
+            summary.entries = previous.entries;
+            if options.verify {
+                let verified = verify(&dirs.target_dir, &formula.id).or_else(|error| {
+                    // they were verified by the deploy that built them then
+                    if error.is_in_use() {
+                        debug!(formula = %formula.id, "formula is in use, not verifying it");
+                        Ok(())
+                    } else {
+                        Err(error)
+                    }
+                });
+                if let Err(error) = verified {
+                    warn!(formula = %formula.id, error = %error.report(), "formula failed verification");
+                    let _ = fs::remove_file(&stamp_path);
+                    summary.status = FormulaStatus::Failed;
+                    summary.error = Some(error);
+                }
+            }
+            summary.duration_secs = start.elapsed().as_secs_f64();
+            return summary;
+        }
+    }
+
+    info!(formula = %formula.id, "deploying formula");
+    let mut replaced = Vec::new();
+    let result = check_cancelled(progress)
+        .and_then(|_| back_up(&dirs.target_dir, &formula.id, backup_dir, &mut replaced))
+        .and_then(|_| fs::create_dir_all(staging_dir).with_path("create staging dir", staging_dir))
+        .and_then(|_| formula.compile_with_progress(&dirs.config_dir, staging_dir, progress))
+        .and_then(|_| formula.compile2_with_progress(&dirs.config_dir, staging_dir, progress))
+        .and_then(|report| {
+            if options.verify {
+                verify(staging_dir, &formula.id)?;
+            }
+            Ok(report)
+        })
+        .and_then(|report| {
+            let _lock = Lock::replacing(&dirs.target_dir)?;
+            for extension in ["db3", "redb", "trie", "assoc"] {
+                let name = format!("{}.{}", formula.id, extension);
+                let artifact = dirs.target_dir.join(&name);
+                fs::rename(staging_dir.join(name), &artifact).with_path("replace", &artifact)?;
+            }
+            Ok(report)
+        });
+    for extension in ["db3", "redb", "trie", "assoc"] {
+        let _ = fs::remove_file(staging_dir.join(format!("{}.{}", formula.id, extension)));
+    }
+    match result {
+        Ok(report) => {
+            summary.entries = report.entries;
+            summary.warnings = report.warnings;
+            if let Some(sources) = sources {
+                let stamp = Stamp {
+                    sources,
+                    entries: report.entries,
+                };
+                // the backup is a link to the previous stamp, which is left alone
+                let temp = dict::temp_path(&stamp_path);
+                let written = serde_json::to_vec(&stamp)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| fs::write(&temp, json).map_err(|e| e.to_string()))
+                    .and_then(|_| fs::rename(&temp, &stamp_path).map_err(|e| e.to_string()));
+                if let Err(error) = written {
+                    warn!(formula = %formula.id, %error, "cannot write the deploy stamp");
+                }
+            }
+        }
+        // cancelled while compiling, before anything was replaced
+        Err(LiushuError::Cancelled) => {
+            info!(formula = %formula.id, "cancelled deploying formula");
+            for (_, backup) in replaced {
+                let _ = fs::remove_file(backup);
+            }
+            summary.status = FormulaStatus::Failed;
+            summary.error = Some(LiushuError::Cancelled);
+        }
+        Err(error) => {
+            warn!(formula = %formula.id, error = %error.report(), "failed to deploy formula");
+            let _ = fs::remove_file(&stamp_path);
+            for (artifact, backup) in replaced {
+                if let Err(error) = restore(&backup, &artifact) {
+                    warn!(artifact = %artifact.display(), error = %error.report(), "cannot restore the backup");
+                }
+            }
+            summary.status = FormulaStatus::Failed;
+            summary.error = Some(error);
+        }
+    }
+    summary.duration_secs = start.elapsed().as_secs_f64();
+    summary
+}
+
+/// Links the artifacts of a formula into `backup_dir`, or copies them where they can't be
+/// linked, recording each of them and its backup in `replaced`.
+fn back_up(
+    target_dir: &Path,
+    id: &str,
+    backup_dir: &Path,
+    replaced: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), LiushuError> {
+    for extension in FORMULA_ARTIFACTS {
+        let name = format!("{}.{}", id, extension);
+        let artifact = target_dir.join(&name);
+        if !artifact.exists() {
+            continue;
+        }
+        fs::create_dir_all(backup_dir).with_path("create backup dir", backup_dir)?;
+        let backup = backup_dir.join(name);
+        link_or_copy(&artifact, &backup).with_path("back up", &artifact)?;
+        replaced.push((artifact, backup));
+    }
+    Ok(())
+}
+
+/// Puts `backup` back in place of `artifact` with a rename, so an engine opening the
+/// artifact finds either of them.
+fn restore(backup: &Path, artifact: &Path) -> Result<(), LiushuError> {
+    let temp = dict::temp_path(artifact);
+    let _ = fs::remove_file(&temp);
+    link_or_copy(backup, &temp).with_path("copy backup", backup)?;
+    fs::rename(&temp, artifact).with_path("restore", artifact)
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    fs::hard_link(from, to).or_else(|_| fs::copy(from, to).map(drop))
+}
+
+/// A backup dir named after the current time, which no earlier deploy used.
+fn new_backup_dir(target_dir: &Path) -> PathBuf {
+    let mut timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64);
+    loop {
+        let path = target_dir.join(BACKUP_DIR).join(timestamp.to_string());
+        if !path.exists() {
+            return path;
+        }
+        timestamp += 1;
+    }
+}
+
+fn backup_files(path: &Path) -> Result<Vec<PathBuf>, LiushuError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path).with_path("list backup", path)? {
+        let path = entry.with_path("list backup", path)?.path();
+        let extension = path.extension().and_then(OsStr::to_str);
+        if path.is_file() && extension.is_some_and(|ext| FORMULA_ARTIFACTS.contains(&ext)) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The backups of the target dir, the most recent first.
+pub fn backups(dirs: &MyProjectDirs) -> Result<Vec<Backup>, LiushuError> {
+    let dir = dirs.target_dir.join(BACKUP_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&dir).with_path("list backups", &dir)? {
+        let path = entry.with_path("list backups", &dir)?.path();
+        if path.is_dir() {
+            backups.extend(Backup::open(path));
+        }
+    }
+    backups.sort_by_key(|backup| Reverse(backup.timestamp));
+    Ok(backups)
+}
+
+fn prune_backups(dirs: &MyProjectDirs, keep: usize) -> Result<(), LiushuError> {
+    for backup in backups(dirs)?.into_iter().skip(keep) {
+        debug!(timestamp = backup.timestamp, "removing old backup");
+        fs::remove_dir_all(&backup.path).with_path("remove backup", &backup.path)?;
     }
+    Ok(())
+}
+
+/// Restores the artifacts of a backup, the most recent one without a `timestamp`, whether or
+/// not their formulas are still in the config.
+///
+/// Every file is copied next to its artifact before any of them is renamed over it, so a
+/// failing copy leaves the deployed artifacts alone. The backup itself is kept. Fails right
+/// away while another process is deploying.
+pub fn rollback(dirs: &MyProjectDirs, timestamp: Option<u64>) -> Result<Backup, LiushuError> {
+    let _lock = Lock::deploying(&dirs.target_dir, false)?;
+    let mut backups = backups(dirs)?.into_iter();
+    let backup = match timestamp {
+        Some(timestamp) => backups
+            .find(|backup| backup.timestamp == timestamp)
+            .ok_or_else(|| {
+                let path = dirs.target_dir.join(BACKUP_DIR).join(timestamp.to_string());
+                LiushuError::ArtifactMissing(path)
+            })?,
+        None => backups
+            .next()
+            .ok_or_else(|| LiushuError::ArtifactMissing(dirs.target_dir.join(BACKUP_DIR)))?,
+    };
 
+    let mut staged = Vec::new();
+    for file in backup_files(&backup.path)? {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        let mut temp = name.to_owned();
+        temp.push(".rollback");
+        let temp = dirs.target_dir.join(temp);
+        if let Err(error) = fs::copy(&file, &temp) {
+            for (temp, _) in staged {
+                let _ = fs::remove_file(temp);
+            }
+            return Err(LiushuError::io_at("copy backup", &file, error));
+        }
+        staged.push((temp, dirs.target_dir.join(name)));
+    }
+    let replacing = Lock::replacing(&dirs.target_dir)?;
+    for (temp, artifact) in staged {
+        fs::rename(temp, &artifact).with_path("restore", &artifact)?;
+    }
+    drop(replacing);
+    // a stamp of the replaced artifacts would have the next deploy skip the restored ones
+    for id in &backup.formulas {
+        if !backup.path.join(format!("{}.stamp", id)).exists() {
+            let _ = fs::remove_file(Stamp::path(&dirs.target_dir, id));
+        }
+    }
+    info!(timestamp = backup.timestamp, "rolled back");
+    Ok(backup)
+}
+
+/// Opens the artifacts of a formula and searches the first codes of its trie, each of them
+/// must find its candidates in the dictionary.
+pub(crate) fn verify(target_dir: &Path, id: &str) -> Result<(), LiushuError> {
+    let failed = |extension: &str, reason: String| LiushuError::ArtifactCorrupt {
+        path: target_dir.join(format!("{}.{}", id, extension)),
+        source: format!("verification of {} failed: {}", id, reason).into(),
+    };
+    let engine = EngineWithRedb::with_formula(target_dir, id)?;
+    let codes: Vec<String> = engine.codes().take(PROBE_CODES).collect();
+    if codes.is_empty() {
+        return Err(failed("trie", "the code trie is empty".to_string()));
+    }
+    for code in &codes {
+        let missing = engine.inconsistencies();
+        let results = engine.search(code)?;
+        // those the dictionary lacks are candidates still, as a search makes them
+        let found = results
+            .iter()
+            .filter(|item| {
+                item.code == *code && item.comment.as_deref() != Some(MISSING_DEFINITION)
+            })
+            .count();
+        if found == 0 {
+            return Err(failed(
+                "redb",
+                format!("no candidate of {} is in the dictionary", code),
+            ));
+        }
+        if engine.inconsistencies() > missing {
+            return Err(failed(
+                "redb",
+                format!("some candidates of {} are not in the dictionary", code),
+            ));
+        }
+    }
+    debug!(formula = id, codes = codes.len(), "verified formula");
     Ok(())
 }
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CleanOptions {
+    /// Also remove the trained HMM model.
+    pub all: bool,
+    /// Only remove the artifacts of formulas no longer in the config.
+    pub orphans: bool,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CleanReport {
+    pub removed: Vec<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// Removes compiled artifacts from the target dir.
+///
+/// Only regular files directly inside the target dir are considered, symlinks are never
+/// followed, and the config dir and data dir are left alone even if the target dir
+/// resolves to one of their parents.
+pub fn clean(dirs: &MyProjectDirs, options: CleanOptions) -> Result<CleanReport, LiushuError> {
+    let mut report = CleanReport {
+        removed: Vec::new(),
+        dry_run: options.dry_run,
+    };
+    if !dirs.target_dir.exists() {
+        return Ok(report);
+    }
+    let _lock = if options.dry_run {
+        None
+    } else {
+        Some(Lock::deploying(&dirs.target_dir, false)?)
+    };
+
+    let target_dir = dirs.target_dir.canonicalize()?;
+    for protected in [&dirs.config_dir, &dirs.data_dir] {
+        let protected = protected
+            .canonicalize()
+            .unwrap_or_else(|_| protected.clone());
+        if protected.starts_with(&target_dir) {
+            return Err(LiushuError::InvalidInput(format!(
+                "refusing to clean {}, it contains {}",
+                target_dir.display(),
+                protected.display()
+            )));
+        }
+    }
+
+    let orphans = if options.orphans {
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall"))?;
+        Some(orphans(&config, &target_dir)?)
+    } else {
+        None
+    };
+
+    let mut entries = fs::read_dir(&target_dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .with_path("list target dir", &target_dir)?;
+    entries.sort();
+
+    for path in entries {
+        let metadata = fs::symlink_metadata(&path).with_path("read metadata of", &path)?;
+        if !metadata.file_type().is_file() || !is_artifact(&path, options.all) {
+            continue;
+        }
+        if orphans
+            .as_ref()
+            .is_some_and(|orphans| !orphans.contains(&path))
+        {
+            continue;
+        }
+        if !options.dry_run {
+            fs::remove_file(&path).with_path("remove", &path)?;
+        }
+        report.removed.push(path);
+    }
+
+    Ok(report)
+}
+
+/// Artifacts in the target dir of formulas that aren't in the config.
+///
+/// Only the files a formula deploys are considered, never the HMM model nor the user and
+/// patch dictionaries should the data dir share the target dir.
+pub fn orphans(config: &Config, target_dir: &Path) -> Result<Vec<PathBuf>, LiushuError> {
+    if !target_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let ids: HashSet<&str> = config
+        .formulas
+        .iter()
+        .map(|formula| formula.id.as_str())
+        .collect();
+    let mut orphans = Vec::new();
+    for entry in fs::read_dir(target_dir).with_path("list target dir", target_dir)? {
+        let path = entry.with_path("list target dir", target_dir)?.path();
+        let metadata = fs::symlink_metadata(&path).with_path("read metadata of", &path)?;
+        if !metadata.file_type().is_file() {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+        if [MODEL_FILE, USER_DICT_FILE].contains(&name) || name.ends_with(".patch.redb") {
+            continue;
+        }
+        match name.rsplit_once('.') {
+            Some((id, extension))
+                if FORMULA_ARTIFACTS.contains(&extension) && !ids.contains(id) =>
+            {
+                orphans.push(path)
+            }
+            _ => {}
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+fn is_artifact(path: &Path, all: bool) -> bool {
+    if path.file_name() == Some(OsStr::new(MODEL_FILE)) {
+        return all;
+    }
+    matches!(
+        path.extension().and_then(OsStr::to_str),
+        Some("redb" | "trie" | "assoc" | "db3" | "stamp")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use patricia_tree::PatriciaMap;
+
+    use super::*;
+
+    fn scratch_dirs(root: &Path) -> MyProjectDirs {
+        let dirs = MyProjectDirs::from_root(root);
+        dirs.ensure().unwrap();
+        for name in [
+            "sunman.redb",
+            "sunman.trie",
+            "sunman.db3",
+            MODEL_FILE,
+            "notes.txt",
+        ] {
+            fs::write(dirs.target_dir.join(name), "").unwrap();
+        }
+        fs::write(dirs.config_dir.join("main.dhall"), "").unwrap();
+        dirs
+    }
+
+    fn file_names(paths: &[PathBuf]) -> Vec<&str> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    fn config(dirs: &MyProjectDirs) -> Config {
+        Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap()
+    }
+
+    #[test]
+    fn test_deploy_keeps_going() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::create_dir(dirs.config_dir.join("fixture")).unwrap();
+        let words = dirs.config_dir.join("fixture/words.tsv");
+        fs::write(&words, "text\tcode\tweight\n你好\tnihao\t2\n").unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "broken", name = None Text, dictionaries = [ "missing.tsv" ] },
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let config = config(&dirs);
+
+        let summary = deploy(&config, &dirs).unwrap();
+        let statuses: Vec<_> = summary
+            .formulas
+            .iter()
+            .map(|formula| (formula.id.as_str(), formula.status, formula.entries))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("broken", FormulaStatus::Failed, 0),
+                ("fixture", FormulaStatus::Deployed, 1)
+            ]
+        );
+        let failed: Vec<_> = summary.failed().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].error.as_ref().unwrap().exit_code(), 3);
+        assert!(dirs.target_dir.join("fixture.trie").exists());
+        assert!(summary.formulas[1].warnings.is_empty());
+
+        let summary = deploy(&config, &dirs).unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Failed);
+        assert_eq!(summary.formulas[1].status, FormulaStatus::Unchanged);
+        assert_eq!(summary.formulas[1].entries, 1);
+
+        fs::write(
+            &words,
+            "text\tcode\tweight\n你好\tnihao\t2\n你好\tnh\t1\n你\tni\t1\n",
+        )
+        .unwrap();
+        let summary = deploy(&config, &dirs).unwrap();
+        let fixture = &summary.formulas[1];
+        assert_eq!(
+            (fixture.status, fixture.entries),
+            (FormulaStatus::Deployed, 3)
+        );
+        assert!(matches!(
+            fixture.warnings[..],
+            [Warning::ReplacedWeights { entries: 1, .. }]
+        ));
+
+        fs::remove_file(dirs.target_dir.join("fixture.redb")).unwrap();
+        let summary = deploy(&config, &dirs).unwrap();
+        assert_eq!(summary.formulas[1].status, FormulaStatus::Deployed);
+    }
+
+    #[test]
+    fn test_deploy_disabled() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::create_dir(dirs.config_dir.join("fixture")).unwrap();
+        let words = dirs.config_dir.join("fixture/words.tsv");
+        fs::write(&words, "text\tcode\tweight\n你好\tnihao\t2\n").unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "broken", name = None Text, dictionaries = [ "missing.tsv" ], enabled = False },
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], enabled = True }
+            ] }"#,
+        )
+        .unwrap();
+        let config = config(&dirs);
+
+        let summary = deploy(&config, &dirs).unwrap();
+        let ids: Vec<_> = summary.formulas.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, ["fixture"]);
+        assert_eq!(summary.failed().count(), 0);
+
+        let options = DeployOptions {
+            include_disabled: true,
+            ..Default::default()
+        };
+        let summary = deploy_with_progress(&config, &dirs, options, &NoProgress).unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Failed);
+        assert_eq!(summary.formulas[1].status, FormulaStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_deploy_verify() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::create_dir(dirs.config_dir.join("empty")).unwrap();
+        fs::write(
+            dirs.config_dir.join("empty/words.tsv"),
+            "text\tcode\tweight\n",
+        )
+        .unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "empty", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let config = config(&dirs);
+
+        let unverified = DeployOptions {
+            verify: false,
+            ..Default::default()
+        };
+        let summary = deploy_with_progress(&config, &dirs, unverified, &NoProgress).unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Deployed);
+        assert_eq!(summary.formulas[0].warnings[0].code(), "W_EMPTY_DICTIONARY");
+
+        // unchanged formulas are verified too
+        let summary = deploy(&config, &dirs).unwrap();
+        let error = summary.formulas[0].error.as_ref().unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Failed);
+        assert!(error.report().ends_with("the code trie is empty"));
+        assert!(!Stamp::path(&dirs.target_dir, "empty").exists());
+
+        // a trie pointing at texts missing from the dictionary
+        let mut trie: PatriciaMap<Vec<String>> = PatriciaMap::new();
+        trie.insert("nihao", vec!["你好".to_string()]);
+        let file = fs::File::create(dirs.target_dir.join("empty.trie")).unwrap();
+        bincode::serialize_into(file, &trie).unwrap();
+        let error = verify(&dirs.target_dir, "empty").unwrap_err();
+        assert!(error
+            .report()
+            .ends_with("no candidate of nihao is in the dictionary"));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::create_dir(dirs.config_dir.join("fixture")).unwrap();
+        let words = dirs.config_dir.join("fixture/words.tsv");
+        fs::write(&words, "text\tcode\tweight\n你好\tnihao\t2\n").unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let search = |code: &str| -> Vec<String> {
+            let engine = EngineWithRedb::with_formula(&dirs.target_dir, "fixture").unwrap();
+            let results = engine.search(code).unwrap();
+            results.into_iter().map(|item| item.text).collect()
+        };
+
+        // nothing to replace yet
+        deploy(&config(&dirs), &dirs).unwrap();
+        assert!(backups(&dirs).unwrap().is_empty());
+
+        fs::write(&words, "text\tcode\tweight\n你们\tnimen\t2\n").unwrap();
+        deploy(&config(&dirs), &dirs).unwrap();
+        assert_eq!(search("nimen"), ["你们"]);
+        let backup = &backups(&dirs).unwrap()[0];
+        assert_eq!(backup.formulas, ["fixture"]);
+
+        let restored = rollback(&dirs, None).unwrap();
+        assert_eq!(search("nihao"), ["你好"]);
+        assert!(search("nimen").is_empty());
+        // the restored stamp is of the old dictionary, so the next deploy rebuilds
+        let summary = deploy(&config(&dirs), &dirs).unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Deployed);
+        assert_eq!(search("nimen"), ["你们"]);
+
+        // backups of formulas no longer in the config are still restorable
+        fs::write(dirs.config_dir.join("main.dhall"), "{ formulas = [] }").unwrap();
+        for extension in FORMULA_ARTIFACTS {
+            fs::remove_file(dirs.target_dir.join(format!("fixture.{}", extension))).unwrap();
+        }
+        rollback(&dirs, Some(restored.timestamp)).unwrap();
+        assert_eq!(search("nihao"), ["你好"]);
+        assert!(rollback(&dirs, Some(1)).is_err());
+    }
+
+    #[test]
+    fn test_deploy_locked() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            "{ formulas = [] : List { id : Text, name : Optional Text, dictionaries : List Text } }",
+        )
+        .unwrap();
+        let config = config(&dirs);
+        let lock = Lock::deploying(&dirs.target_dir, false).unwrap();
+        let error = deploy(&config, &dirs).unwrap_err();
+        assert_eq!(error.code(), "E_LOCKED");
+        assert_eq!(rollback(&dirs, None).unwrap_err().code(), "E_LOCKED");
+        assert!(clean(&dirs, CleanOptions::default()).is_err());
+        assert!(dirs.target_dir.join("sunman.redb").exists());
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let options = DeployOptions {
+                    wait: true,
+                    ..Default::default()
+                };
+                deploy_with_progress(&config, &dirs, options, &NoProgress)
+            });
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            drop(lock);
+            waiting.join().unwrap().unwrap();
+        });
+        // a lock is not an artifact
+        let report = clean(&dirs, CleanOptions::default()).unwrap();
+        assert!(!file_names(&report.removed).contains(&crate::dirs::lock::DEPLOY_LOCK));
+    }
+
+    #[test]
+    fn test_keep_backups() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::create_dir(dirs.config_dir.join("fixture")).unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let options = DeployOptions {
+            verify: false,
+            keep_backups: 2,
+            ..Default::default()
+        };
+        for weight in 1..=4 {
+            fs::write(
+                dirs.config_dir.join("fixture/words.tsv"),
+                format!("text\tcode\tweight\n你\tni\t{}\n", weight * 10),
+            )
+            .unwrap();
+            deploy_with_progress(&config(&dirs), &dirs, options, &NoProgress).unwrap();
+        }
+        let backups = backups(&dirs).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(backups[0].timestamp > backups[1].timestamp);
+    }
+
+    fn hooked_config(dirs: &MyProjectDirs, hooks: &str) -> Config {
+        // they would be warned about as orphans
+        for name in ["sunman.redb", "sunman.trie", "sunman.db3"] {
+            let _ = fs::remove_file(dirs.target_dir.join(name));
+        }
+        fs::create_dir_all(dirs.config_dir.join("fixture")).unwrap();
+        fs::write(
+            dirs.config_dir.join("fixture/words.tsv"),
+            "text\tcode\tweight\n你好\tnihao\t2\n",
+        )
+        .unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            format!(
+                r#"{{ formulas = [
+                    {{ id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] }}
+                ], hooks = {} }}"#,
+                hooks
+            ),
+        )
+        .unwrap();
+        config(dirs)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_post_deploy_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        let marker = root.path().join("marker");
+        let script = root.path().join("hook.sh");
+        fs::write(
+            &script,
+            format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\n", marker.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let hooks = format!("{{ postDeploy = Some \"{}\" }}", script.display());
+
+        let summary = deploy(&hooked_config(&dirs, &hooks), &dirs).unwrap();
+        assert!(summary.warnings.is_empty());
+        let summary_path = dirs.target_dir.join(SUMMARY_FILE);
+        assert_eq!(
+            fs::read_to_string(&marker).unwrap(),
+            format!(
+                "{}\n{}\n",
+                dirs.target_dir.display(),
+                summary_path.display()
+            )
+        );
+        let written: serde_json::Value =
+            serde_json::from_slice(&fs::read(summary_path).unwrap()).unwrap();
+        assert_eq!(written["formulas"][0]["status"], "deployed");
+
+        let hooks = r#"{ postDeploy = Some "echo broken >&2; exit 3" }"#;
+        let summary = deploy(&hooked_config(&dirs, hooks), &dirs).unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Deployed);
+        assert_eq!(summary.warnings[0].code(), "W_HOOK_FAILED");
+        assert!(summary.warnings[0].to_string().ends_with("broken"));
+
+        let hooks = r#"{ postDeploy = Some "exit 3", failOnHookError = True }"#;
+        let err = deploy(&hooked_config(&dirs, hooks), &dirs).unwrap_err();
+        assert_eq!(err.code(), "E_HOOK_FAILED");
+        assert!(matches!(err, LiushuError::HookFailed { status, .. } if status.code() == Some(3)));
+    }
+
+    #[derive(Default)]
+    struct RecordingHooks(std::sync::Mutex<Vec<String>>);
+
+    impl DeployHooks for RecordingHooks {
+        fn post_deploy(
+            &self,
+            target_dir: &Path,
+            summary: &DeploySummary,
+        ) -> Result<(), LiushuError> {
+            let mut calls = self.0.lock().unwrap();
+            for formula in &summary.formulas {
+                calls.push(format!("{} {}", target_dir.display(), formula.id));
+            }
+            Err(LiushuError::Other("not now".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_deploy_with_hooks() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        // the callbacks replace the command of the config
+        let config = hooked_config(&dirs, r#"{ postDeploy = Some "exit 1" }"#);
+
+        let hooks = RecordingHooks::default();
+        let summary = deploy_with(&config, &dirs, &hooks).unwrap();
+        assert_eq!(
+            summary.warnings,
+            [Warning::HookFailed {
+                error: "not now".to_string()
+            }]
+        );
+        let calls = hooks.0.into_inner().unwrap();
+        assert_eq!(calls, [format!("{} fixture", dirs.target_dir.display())]);
+        assert!(!dirs.target_dir.join(SUMMARY_FILE).exists());
+    }
+
+    #[test]
+    fn test_prune_orphans() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        for id in ["fixture", "removed"] {
+            fs::create_dir(dirs.config_dir.join(id)).unwrap();
+            fs::write(
+                dirs.config_dir.join(id).join("words.tsv"),
+                "text\tcode\tweight\n你好\tnihao\t2\n",
+            )
+            .unwrap();
+        }
+        let formulas = |ids: &[&str]| {
+            let formulas: Vec<_> = ids
+                .iter()
+                .map(|id| {
+                    format!(
+                        r#"{{ id = "{}", name = None Text, dictionaries = [ "words.tsv" ] }}"#,
+                        id
+                    )
+                })
+                .collect();
+            fs::write(
+                dirs.config_dir.join("main.dhall"),
+                format!("{{ formulas = [ {} ] }}", formulas.join(", ")),
+            )
+            .unwrap();
+            config(&dirs)
+        };
+        fs::write(dirs.target_dir.join(USER_DICT_FILE), "").unwrap();
+        fs::write(dirs.target_dir.join("removed.patch.redb"), "").unwrap();
+        // sunman is left over by the scratch dirs
+        for name in ["sunman.redb", "sunman.trie", "sunman.db3"] {
+            fs::remove_file(dirs.target_dir.join(name)).unwrap();
+        }
+
+        let summary = deploy(&formulas(&["fixture", "removed"]), &dirs).unwrap();
+        assert!(summary.warnings.is_empty());
+
+        let config = formulas(&["fixture"]);
+        let summary = deploy(&config, &dirs).unwrap();
+        assert_eq!(summary.warnings.len(), 5);
+        assert_eq!(
+            summary.warnings[0],
+            Warning::OrphanArtifact {
+                path: dirs.target_dir.join("removed.assoc")
+            }
+        );
+        assert!(dirs.target_dir.join("removed.redb").exists());
+
+        let preview = clean(
+            &dirs,
+            CleanOptions {
+                orphans: true,
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            file_names(&preview.removed),
+            [
+                "removed.assoc",
+                "removed.db3",
+                "removed.redb",
+                "removed.stamp",
+                "removed.trie"
+            ]
+        );
+
+        let options = DeployOptions {
+            prune: true,
+            ..Default::default()
+        };
+        let summary = deploy_with_progress(&config, &dirs, options, &NoProgress).unwrap();
+        assert_eq!(file_names(&summary.pruned), file_names(&preview.removed));
+        let mut left: Vec<_> = fs::read_dir(&dirs.target_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                ARTIFACTS_LOCK,
+                crate::dirs::lock::DEPLOY_LOCK,
+                "fixture.assoc",
+                "fixture.db3",
+                "fixture.redb",
+                "fixture.stamp",
+                "fixture.trie",
+                MODEL_FILE,
+                "notes.txt",
+                "removed.patch.redb",
+                USER_DICT_FILE
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clean() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+
+        let report = clean(&dirs, CleanOptions::default()).unwrap();
+        assert_eq!(
+            file_names(&report.removed),
+            ["sunman.db3", "sunman.redb", "sunman.trie"]
+        );
+        assert!(!dirs.target_dir.join("sunman.redb").exists());
+        assert!(dirs.target_dir.join(MODEL_FILE).exists());
+        assert!(dirs.target_dir.join("notes.txt").exists());
+        assert!(dirs.config_dir.join("main.dhall").exists());
+
+        let report = clean(
+            &dirs,
+            CleanOptions {
+                all: true,
+                dry_run: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(file_names(&report.removed), [MODEL_FILE]);
+    }
+
+    #[test]
+    fn test_clean_dry_run() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+
+        let report = clean(
+            &dirs,
+            CleanOptions {
+                all: true,
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.removed.len(), 4);
+        assert!(report.removed.iter().all(|path| path.exists()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clean_does_not_escape() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        let outside = root.path().join("outside.redb");
+        fs::write(&outside, "").unwrap();
+        std::os::unix::fs::symlink(&outside, dirs.target_dir.join("linked.redb")).unwrap();
+
+        clean(&dirs, CleanOptions::default()).unwrap();
+        assert!(outside.exists());
+
+        let linked_dirs = MyProjectDirs {
+            target_dir: root.path().join("linked_target"),
+            ..scratch_dirs(root.path())
+        };
+        std::os::unix::fs::symlink(root.path(), &linked_dirs.target_dir).unwrap();
+        assert!(clean(&linked_dirs, CleanOptions::default()).is_err());
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let summary = DeploySummary {
+            formulas: vec![
+                FormulaSummary {
+                    id: "sunman".to_string(),
+                    status: FormulaStatus::Deployed,
+                    duration_secs: 0.5,
+                    entries: 2,
+                    warnings: vec![Warning::EmptyDictionary {
+                        path: PathBuf::from("words.tsv"),
+                    }],
+                    error: None,
+                },
+                FormulaSummary {
+                    id: "broken".to_string(),
+                    status: FormulaStatus::Failed,
+                    duration_secs: 0.0,
+                    entries: 0,
+                    warnings: Vec::new(),
+                    error: Some(LiushuError::Missing(PathBuf::from("broken/words.tsv"))),
+                },
+            ],
+            warnings: vec![Warning::HookFailed {
+                error: "the post-deploy hook failed".to_string(),
+            }],
+            pruned: vec![PathBuf::from("removed.redb")],
+        };
+        crate::snapshot::assert_snapshot("deploy_summary", &summary);
+    }
+}
@@ -0,0 +1,224 @@
+//! Deploys on a thread of their own, for frontends such as a settings app that show a
+//! progress bar and a cancel button rather than waiting for [`deploy`](super::deploy).
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use serde::Serialize;
+
+use super::{run, CommandHooks, DeployOptions, DeploySummary};
+use crate::{config::Config, dirs::MyProjectDirs, error::LiushuError, progress::ProgressSink};
+
+/// A deploy run in the background, see [`DeployJob::start`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct DeployJob;
+
+impl DeployJob {
+    /// Deploys like [`deploy_with_progress`](super::deploy_with_progress) on a new thread,
+    /// answering a handle to follow, cancel and wait for it. The deploy goes on when the
+    /// handle is dropped.
+    pub fn start(config: Config, dirs: MyProjectDirs, options: DeployOptions) -> JobHandle {
+        let state = Arc::new(JobState::default());
+        let thread = thread::spawn({
+            let state = state.clone();
+            move || {
+                let hooks = CommandHooks(&config.hooks);
+                run(&config, &dirs, options, &*state, &hooks, &state.rows)
+            }
+        });
+        JobHandle { state, thread }
+    }
+}
+
+/// How far a [`DeployJob`] got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct ProgressSnapshot {
+    /// Formulas of the config, none until the target dir is locked for the deploy.
+    pub formulas: u64,
+    /// Formulas done, deployed, unchanged or failed.
+    pub done: u64,
+    /// Rows compiled so far by every formula, each dictionary being read once for the
+    /// database and once for the trie.
+    pub rows: u64,
+    /// Whether [`JobHandle::cancel`] was called, the job may not have stopped yet.
+    pub cancelled: bool,
+    /// Whether the job is over, [`JobHandle::join`] answering without waiting.
+    pub finished: bool,
+}
+
+pub struct JobHandle {
+    state: Arc<JobState>,
+    thread: JoinHandle<Result<DeploySummary, LiushuError>>,
+}
+
+impl JobHandle {
+    pub fn progress(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            formulas: self.state.formulas.load(Ordering::Relaxed),
+            done: self.state.done.load(Ordering::Relaxed),
+            rows: self.state.rows.load(Ordering::Relaxed),
+            cancelled: self.state.cancelled.load(Ordering::Relaxed),
+            finished: self.thread.is_finished(),
+        }
+    }
+
+    /// Stops the job at the next dictionary or within [`CANCEL_CHECK_ROWS`] rows, the
+    /// formulas being built keeping their previous artifacts and the staging dir removed.
+    /// [`JobHandle::join`] then answers [`LiushuError::Cancelled`], unless every formula
+    /// was done already.
+    ///
+    /// [`CANCEL_CHECK_ROWS`]: crate::progress::CANCEL_CHECK_ROWS
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the job to be over, answering what the deploy does.
+    pub fn join(self) -> Result<DeploySummary, LiushuError> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(LiushuError::Other("the deploy panicked".to_string())))
+    }
+}
+
+/// Shared by a job and its handle, the progress of the deploy as a whole.
+#[derive(Debug, Default)]
+struct JobState {
+    formulas: AtomicU64,
+    done: AtomicU64,
+    rows: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl ProgressSink for JobState {
+    fn on_start(&self, _name: &str, total_hint: Option<u64>) {
+        self.formulas
+            .store(total_hint.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn on_advance(&self, n: u64) {
+        self.done.store(n, Ordering::Relaxed);
+    }
+
+    fn on_finish(&self, _summary: &str) {}
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::deploy::{FormulaStatus, STAGING_DIR};
+
+    /// The files under `dir` with their contents, by their path from `root`.
+    fn contents(root: &Path, dir: &Path, files: &mut BTreeMap<PathBuf, Vec<u8>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                contents(root, &path, files);
+            } else {
+                let name = path.strip_prefix(root).unwrap().to_path_buf();
+                files.insert(name, fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    fn target_contents(dirs: &MyProjectDirs) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        contents(&dirs.target_dir, &dirs.target_dir, &mut files);
+        files
+    }
+
+    fn start(dirs: &MyProjectDirs) -> JobHandle {
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        DeployJob::start(config, dirs.clone(), DeployOptions::default())
+    }
+
+    fn fixture(root: &Path) -> MyProjectDirs {
+        let dirs = MyProjectDirs::from_root(root);
+        dirs.ensure().unwrap();
+        fs::create_dir(dirs.config_dir.join("fixture")).unwrap();
+        fs::write(
+            dirs.config_dir.join("fixture/words.tsv"),
+            "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n",
+        )
+        .unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        dirs
+    }
+
+    #[test]
+    fn test_job() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = fixture(root.path());
+        let job = start(&dirs);
+        let summary = job.join().unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Deployed);
+
+        let job = start(&dirs);
+        while !job.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            job.progress(),
+            ProgressSnapshot {
+                formulas: 1,
+                done: 1,
+                rows: 0,
+                cancelled: false,
+                finished: true,
+            }
+        );
+        let summary = job.join().unwrap();
+        assert_eq!(summary.formulas[0].status, FormulaStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = fixture(root.path());
+        start(&dirs).join().unwrap();
+        let deployed = target_contents(&dirs);
+
+        let mut words = String::from("text\tcode\tweight\n");
+        for i in 0..200_000u32 {
+            let text = char::from_u32(0x4e00 + i % 20_000).unwrap();
+            words.push_str(&format!("{}{}\tc{}\t{}\n", text, i, i % 1000, i));
+        }
+        fs::write(dirs.config_dir.join("fixture/words.tsv"), words).unwrap();
+        let job = start(&dirs);
+        // cancelled once it has compiled some of the dictionary
+        while job.progress().rows < 10_000 && !job.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        job.cancel();
+        assert!(job.progress().cancelled);
+        assert!(matches!(job.join(), Err(LiushuError::Cancelled)));
+
+        assert!(!dirs.target_dir.join(STAGING_DIR).exists());
+        assert!(target_contents(&dirs) == deployed);
+    }
+}
@@ -0,0 +1,200 @@
+//! Warnings of a build, deploy or training: problems with what they read that didn't stop
+//! them, collected by a [`Diagnostics`] as they come and kept in their reports.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use tracing::debug;
+
+/// A problem that didn't stop a build, deploy or training, with where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// A dictionary without a single entry.
+    EmptyDictionary { path: PathBuf },
+    /// Entries of a dictionary replacing the weight of an earlier entry with the same text.
+    ReplacedWeights { path: PathBuf, entries: u64 },
+    /// Of those, the entries whose text or code was normalized into that of the earlier
+    /// entry, see [`crate::normalize`].
+    NormalizedDuplicates { path: PathBuf, entries: u64 },
+    /// Entries of a dictionary left out for a character out of the charset of the formula,
+    /// see [`crate::charset`].
+    OutOfCharset { path: PathBuf, entries: u64 },
+    /// A row of a dictionary with a negative weight or one heavier than
+    /// [`MAX_WEIGHT`](crate::artifact::MAX_WEIGHT), as it was given, clamped to the bound.
+    ClampedWeight {
+        path: PathBuf,
+        line: u64,
+        weight: String,
+        clamped: u64,
+    },
+    /// An artifact of the target dir that belongs to no formula of the config.
+    OrphanArtifact { path: PathBuf },
+    /// An orphaned artifact that couldn't be pruned.
+    CannotRemove { path: PathBuf, error: String },
+    /// A hook run after a deploy failed.
+    HookFailed { error: String },
+    /// `.jsonl` lines of a corpus file that are not an object with a string in the text
+    /// field, left out of the training.
+    MalformedLines { path: PathBuf, lines: u64 },
+}
+
+impl Warning {
+    /// Stable code for frontends to match on, like [`LiushuError::code`](crate::error::LiushuError::code).
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::EmptyDictionary { .. } => "W_EMPTY_DICTIONARY",
+            Warning::ReplacedWeights { .. } => "W_REPLACED_WEIGHTS",
+            Warning::NormalizedDuplicates { .. } => "W_NORMALIZED_DUPLICATES",
+            Warning::OutOfCharset { .. } => "W_OUT_OF_CHARSET",
+            Warning::ClampedWeight { .. } => "W_CLAMPED_WEIGHT",
+            Warning::OrphanArtifact { .. } => "W_ORPHAN_ARTIFACT",
+            Warning::CannotRemove { .. } => "W_CANNOT_REMOVE",
+            Warning::HookFailed { .. } => "W_HOOK_FAILED",
+            Warning::MalformedLines { .. } => "W_MALFORMED_LINES",
+        }
+    }
+
+    /// The file the warning is about, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Warning::EmptyDictionary { path }
+            | Warning::ReplacedWeights { path, .. }
+            | Warning::NormalizedDuplicates { path, .. }
+            | Warning::OutOfCharset { path, .. }
+            | Warning::ClampedWeight { path, .. }
+            | Warning::OrphanArtifact { path }
+            | Warning::CannotRemove { path, .. }
+            | Warning::MalformedLines { path, .. } => Some(path),
+            Warning::HookFailed { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::EmptyDictionary { path } => write!(f, "{} has no entries", path.display()),
+            Warning::ReplacedWeights { path, entries } => write!(
+                f,
+                "{} entries of {} replace the weight of an earlier entry with the same text",
+                entries,
+                path.display()
+            ),
+            Warning::NormalizedDuplicates { path, entries } => write!(
+                f,
+                "{} entries of {} are the same as an earlier entry once normalized",
+                entries,
+                path.display()
+            ),
+            Warning::OutOfCharset { path, entries } => write!(
+                f,
+                "{} entries of {} have a character out of the charset, left out",
+                entries,
+                path.display()
+            ),
+            Warning::ClampedWeight {
+                path,
+                line,
+                weight,
+                clamped,
+            } => write!(
+                f,
+                "{}:{} has the weight {}, clamped to {}",
+                path.display(),
+                line,
+                weight,
+                clamped
+            ),
+            Warning::OrphanArtifact { path } => {
+                write!(f, "{} belongs to no formula of the config", path.display())
+            }
+            Warning::CannotRemove { path, error } => {
+                write!(f, "cannot remove {}: {}", path.display(), error)
+            }
+            Warning::HookFailed { error } => f.write_str(error),
+            Warning::MalformedLines { path, lines } => {
+                write!(f, "skipped {} malformed lines of {}", lines, path.display())
+            }
+        }
+    }
+}
+
+/// The shape frontends get in JSON, like that of an error without the hint.
+impl Serialize for Warning {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Warning", 3)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("path", &self.path())?;
+        state.end()
+    }
+}
+
+/// Collects the warnings of a build, deploy or training for its report, leaving it to the
+/// frontend to show them rather than logging each on its own.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    warnings: Vec<Warning>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn warn(&mut self, warning: Warning) {
+        debug!(code = warning.code(), "{}", warning);
+        self.warnings.push(warning);
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn into_warnings(self) -> Vec<Warning> {
+        self.warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.warn(Warning::ReplacedWeights {
+            path: PathBuf::from("words.tsv"),
+            entries: 2,
+        });
+        diagnostics.warn(Warning::HookFailed {
+            error: "not now".to_string(),
+        });
+        diagnostics.warn(Warning::MalformedLines {
+            path: PathBuf::from("corpus.jsonl"),
+            lines: 3,
+        });
+        let warnings = diagnostics.into_warnings();
+        let codes: Vec<_> = warnings.iter().map(Warning::code).collect();
+        assert_eq!(
+            codes,
+            ["W_REPLACED_WEIGHTS", "W_HOOK_FAILED", "W_MALFORMED_LINES"]
+        );
+        assert_eq!(warnings[0].path(), Some(Path::new("words.tsv")));
+        assert_eq!(warnings[1].path(), None);
+        assert_eq!(
+            warnings[2].to_string(),
+            "skipped 3 malformed lines of corpus.jsonl"
+        );
+        assert_eq!(
+            serde_json::to_value(&warnings[0]).unwrap(),
+            serde_json::json!({
+                "code": "W_REPLACED_WEIGHTS",
+                "message": "2 entries of words.tsv replace the weight of an earlier entry with the same text",
+                "path": "words.tsv",
+            })
+        );
+    }
+}
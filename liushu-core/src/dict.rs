@@ -1,7 +1,37 @@
-use redb::TableDefinition;
-use serde::Deserialize;
-pub const DICTIONARY: TableDefinition<&str, (u64, Option<&str>)> =
-    TableDefinition::new("dictionary");
+pub mod fix;
+pub mod import;
+mod trie;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use flate2::read::MultiGzDecoder;
+use redb::Table;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use self::trie::TrieBuilder;
+
+pub use crate::artifact::{parse_weight, DictItem, DICTIONARY, MAX_WEIGHT};
+pub use crate::engine::{ArtifactReader, Entries};
+use crate::{
+    artifact::{
+        ArtifactOpenOptions, OpenMode, ANNOTATIONS, NORMALIZATION, NORMALIZATION_FORMS,
+        OUT_OF_CHARSET, PROVENANCE, SOURCES,
+    },
+    charset::{Charset, CharsetMode},
+    diagnostics::{Diagnostics, Warning},
+    engine::SearchResultItem,
+    error::{IoResultExt, LiushuError},
+    normalize,
+    progress::{check_cancelled, estimate_rows, ProgressSink, CANCEL_CHECK_ROWS},
+};
 
 pub const CREATE_DICT_TABLE_SQL: &str = r#"
     CREATE TABLE dict (
@@ -14,10 +44,1046 @@ pub const CREATE_DICT_TABLE_SQL: &str = r#"
     )
 "#;
 
+/// The normalization of the `.db3` artifact, like [`NORMALIZATION`] in the redb one.
+pub const CREATE_NORMALIZATION_TABLE_SQL: &str = r#"
+    CREATE TABLE normalization (
+        of TEXT PRIMARY KEY,
+        form TEXT NOT NULL
+    )
+"#;
+
+/// The texts of the `.db3` artifact out of the charset, like [`OUT_OF_CHARSET`] in the
+/// redb one.
+pub const CREATE_OUT_OF_CHARSET_TABLE_SQL: &str = r#"
+    CREATE TABLE out_of_charset (
+        text TEXT PRIMARY KEY
+    )
+"#;
+
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// Overwrite artifacts left by an earlier build.
+    pub force: bool,
+    /// Record the dictionary and line of each row in the redb artifact, which grows it.
+    pub track_provenance: bool,
+    /// A TSV of a `code` of one character and its `annotation` on each row, compiled into
+    /// the redb artifact for engines to comment candidates with, see
+    /// [`EngineWithRedb::annotate`](crate::engine::EngineWithRedb::annotate).
+    pub annotation: Option<PathBuf>,
+    /// Normalize the texts and codes of the entries, see [`normalize`], which is the
+    /// default.
+    pub normalize_unicode: bool,
+    /// Bytes of rows gathered for the code trie before they are spilled to sorted runs next
+    /// to it, rather than all gathered in memory, for dictionaries too big for it. The
+    /// codes are still kept in memory, and the trie is the same.
+    pub max_memory: Option<usize>,
+    /// The characters of the entries, those with others dropped or tagged by the
+    /// `charset_mode`, see [`crate::charset`].
+    pub charset: Option<Arc<Charset>>,
+    pub charset_mode: CharsetMode,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            track_provenance: false,
+            annotation: None,
+            normalize_unicode: true,
+            max_memory: None,
+            charset: None,
+            charset_mode: CharsetMode::default(),
+        }
+    }
+}
+
+/// A row of an annotation table.
 #[derive(Debug, Deserialize)]
-pub struct DictItem {
-    pub text: String,
-    pub code: String,
-    pub weight: u64,
-    pub comment: Option<String>,
+struct AnnotationRow {
+    code: String,
+    annotation: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    pub entries: u64,
+    pub codes: usize,
+    /// Every row the build would fail on, where it stops at the first.
+    pub errors: Vec<LiushuError>,
+    /// Those the build would have.
+    pub warnings: Vec<Warning>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    pub entries: u64,
+    pub codes: usize,
+    /// Paths and sizes of the written artifacts.
+    pub artifacts: Vec<(PathBuf, u64)>,
+    /// Problems with the input that didn't stop the build.
+    pub warnings: Vec<Warning>,
+}
+
+/// Opens a dictionary or corpus file, decompressing it when the name ends with `.gz`. `kind`
+/// names the file in errors.
+pub(crate) fn open_input(path: &Path, kind: &str) -> Result<Box<dyn BufRead>, LiushuError> {
+    if !path.exists() {
+        return Err(LiushuError::Missing(path.to_path_buf()));
+    }
+    let file = File::open(path).with_path(&format!("open {}", kind), path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Reads the rows of a TSV dictionary, where lines starting with `#` are comments. Quotes are
+/// nothing special, a text such as `"` is an entry of its own as in the dictionaries of Rime.
+#[cfg(any(feature = "sqlite-engine", feature = "hmm"))]
+pub(crate) fn open_dictionary(path: &Path) -> Result<csv::Reader<Box<dyn BufRead>>, LiushuError> {
+    Ok(dictionary_reader(open_input(path, "dictionary")?))
+}
+
+fn dictionary_reader<R: Read>(reader: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .comment(Some(b'#'))
+        .quoting(false)
+        .from_reader(reader)
+}
+
+/// The offsets of the newlines read by a [`LineCounter`], those before the records parsed
+/// yet.
+type Newlines = Rc<RefCell<VecDeque<u64>>>;
+
+/// Notes the newlines of what is read, for the line of each record, which csv doesn't count
+/// across comments and puts on the blank line before a record.
+struct LineCounter<R> {
+    inner: R,
+    read: u64,
+    newlines: Newlines,
+}
+
+impl<R: Read> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut newlines = self.newlines.borrow_mut();
+        for (i, _) in buf[..n].iter().enumerate().filter(|(_, &b)| b == b'\n') {
+            newlines.push_back(self.read + i as u64);
+        }
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts the lines of the records of a [`LineCounter`] as they are read.
+struct Lines {
+    newlines: Newlines,
+    /// Lines ended before the last record.
+    ended: u64,
+}
+
+impl Lines {
+    /// The line, from 1, of the record read last, which the reader is at the `end` of.
+    fn line_ending_at(&mut self, end: u64) -> u64 {
+        let mut newlines = self.newlines.borrow_mut();
+        // the newline ending the record is its own
+        while newlines.front().is_some_and(|&at| at + 1 < end) {
+            newlines.pop_front();
+            self.ended += 1;
+        }
+        self.ended + 1
+    }
+}
+
+type CountedReader = csv::Reader<LineCounter<Box<dyn BufRead>>>;
+
+/// Like [`open_dictionary`], with the lines of the records read from it.
+fn open_dictionary_lines(path: &Path) -> Result<(CountedReader, Lines), LiushuError> {
+    let newlines = Newlines::default();
+    let counter = LineCounter {
+        inner: open_input(path, "dictionary")?,
+        read: 0,
+        newlines: newlines.clone(),
+    };
+    let lines = Lines { newlines, ended: 0 };
+    Ok((dictionary_reader(counter), lines))
+}
+
+/// Writes `items` as a TSV dictionary, which [`build`] reads back as they are.
+pub fn write_dictionary(items: &[SearchResultItem], writer: impl Write) -> Result<(), LiushuError> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .quote_style(csv::QuoteStyle::Never)
+        .from_writer(writer);
+    for item in items {
+        wtr.serialize(item)
+            .map_err(|e| LiushuError::io("cannot write the dictionary", e))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Builds the redb dictionary, code trie and association index of `id` in `target_dir` from
+/// TSV dictionaries, or phrase libraries [`import::scel`] reads. The association index is a
+/// trie keyed by the texts of the phrases, those of more than one character, valued by
+/// their codes, see [`InputMethodEngine::associations`](crate::engine::InputMethodEngine::associations).
+///
+/// Each is written next to their path first and renamed over it once complete, so that an
+/// engine opening them never sees half of a build, and one that has them open keeps reading
+/// the files it opened. A build cancelled by `progress` leaves none of its files behind.
+pub fn build(
+    inputs: &[PathBuf],
+    target_dir: &Path,
+    id: &str,
+    options: BuildOptions,
+    progress: &dyn ProgressSink,
+) -> Result<BuildReport, LiushuError> {
+    let annotation = options.annotation.iter();
+    if let Some(missing) = inputs
+        .iter()
+        .chain(annotation)
+        .find(|input| !input.exists())
+    {
+        return Err(LiushuError::Missing(missing.clone()));
+    }
+    let db_path = target_dir.join(format!("{}.redb", id));
+    let trie_path = target_dir.join(format!("{}.trie", id));
+    let assoc_path = target_dir.join(format!("{}.assoc", id));
+    let paths = [&db_path, &trie_path, &assoc_path];
+    if !options.force {
+        if let Some(existing) = paths.into_iter().find(|p| p.exists()) {
+            return Err(LiushuError::InvalidInput(format!(
+                "refusing to overwrite {} without forcing it",
+                existing.display()
+            )));
+        }
+    }
+    fs::create_dir_all(target_dir).with_path("create target dir", target_dir)?;
+
+    let temps = paths.map(|path| temp_path(path));
+    let mut diagnostics = Diagnostics::new();
+    let written =
+        write_artifacts(inputs, &temps, &options, progress, &mut diagnostics).and_then(|built| {
+            for (temp, path) in temps.iter().zip(paths) {
+                fs::rename(temp, path).with_path("replace", path)?;
+            }
+            Ok(built)
+        });
+    if written.is_err() {
+        for temp in &temps {
+            let _ = fs::remove_file(temp);
+        }
+    }
+    let (entries, codes) = written?;
+
+    let mut artifacts = Vec::new();
+    for path in [db_path, trie_path, assoc_path] {
+        let size = fs::metadata(&path)
+            .with_path("read metadata of", &path)?
+            .len();
+        artifacts.push((path, size));
+    }
+    Ok(BuildReport {
+        entries,
+        codes,
+        artifacts,
+        warnings: diagnostics.into_warnings(),
+    })
+}
+
+/// Writes the rows of the annotation table at `path`, a later row of a code replacing the
+/// earlier one.
+fn write_annotations(
+    path: &Path,
+    table: &mut Table<'_, '_, &str, &str>,
+) -> Result<(), LiushuError> {
+    let (mut rdr, mut lines) = open_dictionary_lines(path)?;
+    let parse_error = |e| LiushuError::dict_parse(path, e);
+    let headers = rdr.headers().map_err(parse_error)?.clone();
+    let mut record = csv::StringRecord::new();
+    let mut rows = 0;
+    while rdr.read_record(&mut record).map_err(parse_error)? {
+        let AnnotationRow { code, annotation } =
+            record.deserialize(Some(&headers)).map_err(parse_error)?;
+        if code.chars().count() != 1 {
+            return Err(LiushuError::DictParse {
+                file: path.to_path_buf(),
+                line: lines.line_ending_at(rdr.position().byte()),
+                source: format!("the code {:?} isn't one character", code).into(),
+            });
+        }
+        table.insert(code.as_str(), annotation.as_str())?;
+        rows += 1;
+    }
+    debug!(annotation = %path.display(), rows, "compiled annotation table");
+    Ok(())
+}
+
+/// Warns of the row `record` at `line` of `path` when [`DictItem`] clamped its weight into
+/// `item`.
+fn warn_clamped(
+    path: &Path,
+    line: u64,
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+    item: &DictItem,
+    diagnostics: &mut Diagnostics,
+) {
+    // the weights clamped are the bounds
+    if item.weight != 0 && item.weight != MAX_WEIGHT {
+        return;
+    }
+    let given = headers
+        .iter()
+        .position(|header| header == "weight")
+        .and_then(|field| record.get(field));
+    if let Some(given) = given.filter(|given| matches!(parse_weight(given), Ok((_, true)))) {
+        diagnostics.warn(Warning::ClampedWeight {
+            path: path.to_path_buf(),
+            line,
+            weight: given.to_string(),
+            clamped: item.weight,
+        });
+    }
+}
+
+/// Normalizes the text and code of `item`, answering whether either changed.
+pub(crate) fn normalize_item(item: &mut DictItem) -> bool {
+    let owned = |value: Cow<'_, str>| match value {
+        Cow::Owned(value) => Some(value),
+        Cow::Borrowed(_) => None,
+    };
+    let text = owned(normalize::text(&item.text));
+    let code = owned(normalize::code(&item.code));
+    let changed = text.is_some() || code.is_some();
+    if let Some(text) = text {
+        item.text = text;
+    }
+    if let Some(code) = code {
+        item.code = code;
+    }
+    changed
+}
+
+/// Where an artifact is written before it is renamed over `path`.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Writes the dictionary, the trie and the association index to their paths, answering
+/// the entries and codes of the build.
+fn write_artifacts(
+    inputs: &[PathBuf],
+    [db_path, trie_path, assoc_path]: &[PathBuf; 3],
+    options: &BuildOptions,
+    progress: &dyn ProgressSink,
+    diagnostics: &mut Diagnostics,
+) -> Result<(u64, usize), LiushuError> {
+    // left by a build that was killed
+    let _ = fs::remove_file(db_path);
+    let table =
+        ArtifactOpenOptions::new(OpenMode::CreateNew).open_redb(db_path, "create dictionary")?;
+    let tx = table.begin_write()?;
+    let mut trie = TrieBuilder::new(options.max_memory, trie_path);
+    let mut assoc = TrieBuilder::new(options.max_memory, assoc_path);
+    let mut entries = 0;
+    // the texts of earlier entries are in the dictionary table, those normalized here
+    let mut normalized_texts = NormalizedTexts::default();
+    {
+        let mut dict_table = tx.open_table(DICTIONARY)?;
+        let mut provenance = match options.track_provenance {
+            true => Some((tx.open_table(SOURCES)?, tx.open_table(PROVENANCE)?)),
+            false => None,
+        };
+        let mut tagged = match (&options.charset, options.charset_mode) {
+            (Some(_), CharsetMode::Tag) => Some(tx.open_table(OUT_OF_CHARSET)?),
+            _ => None,
+        };
+        for (source, dict_path) in (0u32..).zip(inputs) {
+            check_cancelled(progress)?;
+            debug!(dictionary = %dict_path.display(), "compiling dictionary");
+            let scel = import::is_scel(dict_path);
+            let estimate = match scel {
+                true => None,
+                false => estimate_rows(dict_path),
+            };
+            progress.on_start(&dict_path.to_string_lossy(), estimate);
+            if let Some((sources, _)) = &mut provenance {
+                sources.insert(source, &*dict_path.to_string_lossy())?;
+            }
+            let mut counts = Counts::default();
+            // the line of a row for its provenance, the entry of a phrase library
+            let mut insert = |mut item: DictItem, line: Option<u64>| -> Result<(), LiushuError> {
+                let normalized = options.normalize_unicode && normalize_item(&mut item);
+                if !options
+                    .charset
+                    .as_ref()
+                    .is_none_or(|c| c.contains_text(&item.text))
+                {
+                    match &mut tagged {
+                        Some(tagged) => {
+                            tagged.insert(item.text.as_str(), ())?;
+                        }
+                        None => {
+                            counts.dropped += 1;
+                            return Ok(());
+                        }
+                    }
+                }
+                let DictItem {
+                    text,
+                    code,
+                    weight,
+                    comment,
+                } = item;
+                let earlier = dict_table
+                    .insert(text.as_str(), (weight, comment.as_deref()))?
+                    .is_some();
+                if let (Some((_, provenance)), Some(line)) = (&mut provenance, line) {
+                    provenance.insert((text.as_str(), code.as_str()), (source, line))?;
+                }
+                counts.count(&text, normalized, earlier, &mut normalized_texts);
+                if text.chars().nth(1).is_some() {
+                    assoc.push(text.clone(), code.clone())?;
+                }
+                trie.push(code, text)?;
+                progress.on_advance(counts.rows);
+                if counts.rows % CANCEL_CHECK_ROWS == 0 {
+                    check_cancelled(progress)?;
+                }
+                Ok(())
+            };
+            if scel {
+                for (entry, item) in (1..).zip(import::scel(dict_path)?) {
+                    insert(item, Some(entry))?;
+                }
+            } else {
+                let (mut rdr, mut lines) = open_dictionary_lines(dict_path)?;
+                let parse_error = |e| LiushuError::dict_parse(dict_path, e);
+                let headers = rdr.headers().map_err(parse_error)?.clone();
+                // read as rdr.deserialize() would, keeping the position of each record
+                let mut record = csv::StringRecord::new();
+                while rdr.read_record(&mut record).map_err(parse_error)? {
+                    let item = record.deserialize(Some(&headers)).map_err(parse_error)?;
+                    let line = lines.line_ending_at(rdr.position().byte());
+                    warn_clamped(dict_path, line, &headers, &record, &item, diagnostics);
+                    insert(item, options.track_provenance.then_some(line))?;
+                }
+            }
+            let rows = counts.rows;
+            info!(dictionary = %dict_path.display(), rows, "compiled dictionary");
+            input_warnings(dict_path, counts, diagnostics);
+            progress.on_finish(&format!("{}: {} rows", dict_path.display(), rows));
+            entries += rows;
+        }
+        if let Some(annotation) = &options.annotation {
+            write_annotations(annotation, &mut tx.open_table(ANNOTATIONS)?)?;
+        }
+        if options.normalize_unicode {
+            let mut normalization = tx.open_table(NORMALIZATION)?;
+            for (of, form) in NORMALIZATION_FORMS {
+                normalization.insert(of, form)?;
+            }
+        }
+    }
+    check_cancelled(progress)?;
+    tx.commit()?;
+    let codes = trie.write(trie_path)?;
+    let phrases = assoc.write(assoc_path)?;
+    debug!(phrases, "wrote association index");
+
+    // closed before it is renamed
+    drop(table);
+    Ok((entries, codes))
+}
+
+/// Reads the dictionaries like [`build`] without writing anything, normalizing them as it
+/// does by default.
+pub fn validate(inputs: &[PathBuf]) -> Result<ValidationReport, LiushuError> {
+    let mut report = ValidationReport::default();
+    let mut diagnostics = Diagnostics::new();
+    let mut texts = HashSet::new();
+    let mut normalized_texts = NormalizedTexts::default();
+    let mut codes = HashSet::new();
+    for dict_path in inputs {
+        let mut counts = Counts::default();
+        let mut count = |mut item: DictItem| {
+            let normalized = normalize_item(&mut item);
+            let earlier = texts.contains(&item.text);
+            counts.count(&item.text, normalized, earlier, &mut normalized_texts);
+            if !earlier {
+                texts.insert(item.text);
+            }
+            codes.insert(item.code);
+        };
+        if import::is_scel(dict_path) {
+            match import::scel(dict_path) {
+                Ok(items) => items.into_iter().for_each(count),
+                Err(e) => {
+                    report.errors.push(e);
+                    continue;
+                }
+            }
+        } else {
+            let (mut rdr, mut lines) = open_dictionary_lines(dict_path)?;
+            let parse_error = |e| LiushuError::dict_parse(dict_path, e);
+            let headers = match rdr.headers() {
+                Ok(headers) => headers.clone(),
+                Err(e) => {
+                    report.errors.push(parse_error(e));
+                    continue;
+                }
+            };
+            let mut record = csv::StringRecord::new();
+            loop {
+                match rdr.read_record(&mut record) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    // the reader would fail again on the next row
+                    Err(e) if e.is_io_error() => {
+                        report.errors.push(parse_error(e));
+                        break;
+                    }
+                    Err(e) => {
+                        report.errors.push(parse_error(e));
+                        continue;
+                    }
+                }
+                let line = lines.line_ending_at(rdr.position().byte());
+                match record.deserialize::<DictItem>(Some(&headers)) {
+                    Ok(item) => {
+                        warn_clamped(dict_path, line, &headers, &record, &item, &mut diagnostics);
+                        count(item);
+                    }
+                    Err(e) => report.errors.push(parse_error(e)),
+                }
+            }
+        }
+        report.entries += counts.rows;
+        input_warnings(dict_path, counts, &mut diagnostics);
+    }
+    report.codes = codes.len();
+    report.warnings = diagnostics.into_warnings();
+    Ok(report)
+}
+
+/// The entries of a dictionary the warnings are about.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    rows: u64,
+    /// Those with the text of an earlier one.
+    replaced: u64,
+    /// Of those replaced, the ones normalized into it.
+    normalized: u64,
+    /// Rows left out, with a character out of the charset.
+    dropped: u64,
+}
+
+impl Counts {
+    /// Counts an entry of `text`, which replaces an `earlier` entry of the same text or
+    /// not. Either being `normalized` makes it a normalized duplicate.
+    fn count(&mut self, text: &str, normalized: bool, earlier: bool, texts: &mut NormalizedTexts) {
+        self.rows += 1;
+        if earlier {
+            self.replaced += 1;
+            self.normalized += u64::from(normalized || texts.0.contains(text));
+        }
+        if normalized && !texts.0.contains(text) {
+            texts.0.insert(text.to_string());
+        }
+    }
+}
+
+/// The texts of the entries normalized so far, fewer than those of the build.
+#[derive(Debug, Default)]
+struct NormalizedTexts(HashSet<String>);
+
+/// Warns about a dictionary of the entries `counts` counted.
+fn input_warnings(path: &Path, counts: Counts, diagnostics: &mut Diagnostics) {
+    if counts.rows == 0 {
+        diagnostics.warn(Warning::EmptyDictionary {
+            path: path.to_path_buf(),
+        });
+    }
+    if counts.replaced > 0 {
+        diagnostics.warn(Warning::ReplacedWeights {
+            path: path.to_path_buf(),
+            entries: counts.replaced,
+        });
+    }
+    if counts.normalized > 0 {
+        diagnostics.warn(Warning::NormalizedDuplicates {
+            path: path.to_path_buf(),
+            entries: counts.normalized,
+        });
+    }
+    if counts.dropped > 0 {
+        diagnostics.warn(Warning::OutOfCharset {
+            path: path.to_path_buf(),
+            entries: counts.dropped,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        let more = dir.path().join("more.tsv");
+        let empty = dir.path().join("empty.tsv");
+        fs::write(
+            &words,
+            "text\tcode\tweight\n你好\tnihao\t2\n你\tni\tmany\n你\tni\t1\n泥\n",
+        )
+        .unwrap();
+        fs::write(&more, "text\tcode\tweight\n你好\tnh\t1\n").unwrap();
+        fs::write(&empty, "text\tcode\tweight\n").unwrap();
+
+        let report = validate(&[words.clone(), more.clone(), empty.clone()]).unwrap();
+        assert_eq!((report.entries, report.codes), (3, 3));
+        let lines: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| match error {
+                LiushuError::DictParse { file, line, .. } => (file.clone(), *line),
+                error => panic!("unexpected {:?}", error),
+            })
+            .collect();
+        assert_eq!(lines, [(words.clone(), 3), (words, 5)]);
+        assert_eq!(
+            report.warnings,
+            [
+                Warning::ReplacedWeights {
+                    path: more,
+                    entries: 1
+                },
+                Warning::EmptyDictionary { path: empty },
+            ]
+        );
+
+        let missing = dir.path().join("missing.tsv");
+        assert!(matches!(validate(&[missing]), Err(LiushuError::Missing(_))));
+    }
+
+    #[test]
+    fn test_scel_dictionary() {
+        use crate::config::Config;
+        use crate::engine::{EngineWithRedb, InputMethodEngine, ShapeCodeEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        let formula_dir = dir.path().join("fixture");
+        fs::create_dir_all(&formula_dir).unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/phrases.scel");
+        fs::copy(&fixture, formula_dir.join("phrases.scel")).unwrap();
+        fs::write(
+            formula_dir.join("words.tsv"),
+            "text\tcode\tweight\n你好\tni hao\t1\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv", "phrases.scel" ] } ] }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(dir.path().join("main.dhall")).unwrap();
+        let formula = &config.formulas[0];
+        let target_dir = dir.path().join("target");
+        fs::create_dir_all(&target_dir).unwrap();
+        let report = formula.compile2(dir.path(), &target_dir).unwrap();
+        assert_eq!((report.entries, report.codes), (5, 3));
+        formula.compile(dir.path(), &target_dir).unwrap();
+
+        // the later weight of the library, as with a later TSV row
+        let redb = EngineWithRedb::with_formula(&target_dir, "fixture").unwrap();
+        let sqlite = ShapeCodeEngine::with_formula(&target_dir, "fixture").unwrap();
+        let texts = |engine: &dyn InputMethodEngine| -> Vec<(String, u64)> {
+            let items = engine.search("ni hao").unwrap();
+            items
+                .into_iter()
+                .map(|item| (item.text, item.weight))
+                .collect()
+        };
+        let expected = [("你好".to_string(), 20), ("拟好".to_string(), 3)];
+        assert_eq!(texts(&redb), expected);
+        assert_eq!(texts(&sqlite), expected);
+        let report = validate(&formula.dictionaries(dir.path())).unwrap();
+        assert_eq!((report.entries, report.codes), (5, 3));
+
+        // a library cut short stops the build with the group it ends in
+        let truncated = formula_dir.join("phrases.scel");
+        let bytes = fs::read(&fixture).unwrap();
+        fs::write(&truncated, &bytes[..bytes.len() - 4]).unwrap();
+        let error = formula.compile2(dir.path(), &target_dir).unwrap_err();
+        assert_eq!(error.to_string(), format!("{}:3", truncated.display()));
+        assert_eq!(
+            error.hint(),
+            Some("the phrase library is likely truncated, export it again")
+        );
+        let report = validate(&formula.dictionaries(dir.path())).unwrap();
+        assert_eq!(report.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let report = ValidationReport {
+            entries: 3,
+            codes: 2,
+            errors: vec![LiushuError::DictParse {
+                file: PathBuf::from("words.tsv"),
+                line: 3,
+                source: "invalid weight many".into(),
+            }],
+            warnings: vec![Warning::EmptyDictionary {
+                path: PathBuf::from("empty.tsv"),
+            }],
+        };
+        crate::snapshot::assert_snapshot("validation_report", &report);
+    }
+
+    #[test]
+    fn test_normalize() {
+        use crate::config::Config;
+        use crate::engine::{EngineWithRedb, InputMethodEngine, ShapeCodeEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("fixture")).unwrap();
+        let words = dir.path().join("fixture/words.tsv");
+        // the same café in NFD then NFC, and a full-width code
+        fs::write(
+            &words,
+            "text\tcode\tweight\ncafe\u{301}\tkafei\t1\ncaf\u{e9}\tkafei\t2\n你\tｎｉ\t3\n",
+        )
+        .unwrap();
+        let load = |normalize: &str| {
+            fs::write(
+                dir.path().join("main.dhall"),
+                format!(
+                    r#"{{ formulas = [ {{ id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], normalizeUnicode = {} }} ] }}"#,
+                    normalize
+                ),
+            )
+            .unwrap();
+            Config::load_from_path(dir.path().join("main.dhall")).unwrap()
+        };
+
+        let config = load("True");
+        let formula = &config.formulas[0];
+        let report = formula.compile2(dir.path(), dir.path()).unwrap();
+        assert_eq!((report.entries, report.codes), (3, 2));
+        assert_eq!(
+            report.warnings,
+            [
+                Warning::ReplacedWeights {
+                    path: words.clone(),
+                    entries: 1
+                },
+                Warning::NormalizedDuplicates {
+                    path: words.clone(),
+                    entries: 1
+                },
+            ]
+        );
+        assert_eq!(
+            validate(std::slice::from_ref(&words)).unwrap().warnings,
+            report.warnings
+        );
+        formula.compile(dir.path(), dir.path()).unwrap();
+        let redb = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+        let sqlite = ShapeCodeEngine::with_formula(dir.path(), "fixture").unwrap();
+        for engine in [&redb as &dyn InputMethodEngine, &sqlite] {
+            let items = engine.search("kafei").unwrap();
+            assert_eq!(
+                (items.len(), items[0].text.as_str(), items[0].weight),
+                (1, "caf\u{e9}", 2)
+            );
+            for code in ["ni", "ｎｉ", "NI"] {
+                let items = engine.search(code).unwrap();
+                assert_eq!(
+                    (items[0].text.as_str(), items[0].code.as_str()),
+                    ("你", "ni")
+                );
+            }
+            assert_eq!(engine.reverse_lookup("cafe\u{301}").unwrap(), ["kafei"]);
+        }
+        drop((redb, sqlite));
+
+        // as they are
+        let config = load("False");
+        let report = config.formulas[0].compile2(dir.path(), dir.path()).unwrap();
+        assert_eq!(report.codes, 2);
+        assert!(report.warnings.is_empty());
+        let redb = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+        assert_eq!(redb.search("kafei").unwrap().len(), 2);
+        assert!(redb.search("ni").unwrap().is_empty());
+        assert_eq!(redb.search("ｎｉ").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_charset() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        use crate::config::Config;
+        use crate::engine::{EngineWithRedb, InputMethodEngine, ShapeCodeEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("fixture")).unwrap();
+        let words = dir.path().join("fixture/words.tsv");
+        // 丟 is a traditional character GB2312 lacks
+        fs::write(
+            &words,
+            "text\tcode\tweight\n丢失\tdiushi\t2\n丟失\tdiushi\t1\n",
+        )
+        .unwrap();
+        let load = |mode: &str| {
+            fs::write(
+                dir.path().join("main.dhall"),
+                format!(
+                    r#"{{ formulas = [ {{ id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], charset = Some "gb2312", charsetMode = "{}" }} ] }}"#,
+                    mode
+                ),
+            )
+            .unwrap();
+            Config::load_from_path(dir.path().join("main.dhall")).unwrap()
+        };
+        let texts = |engine: &dyn InputMethodEngine| {
+            let items = engine.search("diushi").unwrap();
+            items.into_iter().map(|item| item.text).collect::<Vec<_>>()
+        };
+
+        let config = load("tag");
+        let formula = &config.formulas[0];
+        let report = formula.compile2(dir.path(), dir.path()).unwrap();
+        assert_eq!(report.entries, 2);
+        assert!(report.warnings.is_empty());
+        formula.compile(dir.path(), dir.path()).unwrap();
+        let strict = Arc::new(AtomicBool::new(true));
+        let redb = EngineWithRedb::with_formula(dir.path(), "fixture")
+            .unwrap()
+            .strict_charset(strict.clone());
+        let sqlite = ShapeCodeEngine::with_formula(dir.path(), "fixture")
+            .unwrap()
+            .strict_charset(strict.clone());
+        for engine in [&redb as &dyn InputMethodEngine, &sqlite] {
+            strict.store(true, Ordering::Relaxed);
+            assert_eq!(texts(engine), ["丢失"]);
+            strict.store(false, Ordering::Relaxed);
+            assert_eq!(texts(engine), ["丢失", "丟失"]);
+        }
+        strict.store(true, Ordering::Relaxed);
+        assert!(redb.associations("丟", 5).unwrap().is_empty());
+        drop((redb, sqlite));
+
+        let config = load("drop");
+        let formula = &config.formulas[0];
+        let report = formula.compile2(dir.path(), dir.path()).unwrap();
+        assert_eq!(report.entries, 1);
+        assert_eq!(
+            report.warnings,
+            [Warning::OutOfCharset {
+                path: words.clone(),
+                entries: 1
+            }]
+        );
+        formula.compile(dir.path(), dir.path()).unwrap();
+        let off = Arc::new(AtomicBool::new(false));
+        let redb = EngineWithRedb::with_formula(dir.path(), "fixture")
+            .unwrap()
+            .strict_charset(off.clone());
+        let sqlite = ShapeCodeEngine::with_formula(dir.path(), "fixture")
+            .unwrap()
+            .strict_charset(off);
+        for engine in [&redb as &dyn InputMethodEngine, &sqlite] {
+            assert_eq!(texts(engine), ["丢失"]);
+        }
+    }
+
+    #[test]
+    fn test_weights() {
+        use crate::config::Config;
+        use crate::engine::{EngineWithRedb, InputMethodEngine, ShapeCodeEngine};
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("fixture")).unwrap();
+        let words = dir.path().join("fixture/words.tsv");
+        fs::write(
+            &words,
+            "text\tcode\tweight\n你\tni\t4294967296\n# a comment\n泥\tni\t-5\n尼\tni\t99999999999999999999\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(dir.path().join("main.dhall")).unwrap();
+        let formula = &config.formulas[0];
+        let report = formula.compile2(dir.path(), dir.path()).unwrap();
+        let clamped = |line, weight: &str, clamped| Warning::ClampedWeight {
+            path: words.clone(),
+            line,
+            weight: weight.to_string(),
+            clamped,
+        };
+        assert_eq!(
+            report.warnings,
+            [
+                clamped(4, "-5", 0),
+                clamped(5, "99999999999999999999", MAX_WEIGHT),
+            ]
+        );
+        assert_eq!(
+            report.warnings[0].to_string(),
+            format!("{}:4 has the weight -5, clamped to 0", words.display())
+        );
+        assert_eq!(
+            validate(std::slice::from_ref(&words)).unwrap().warnings,
+            report.warnings
+        );
+
+        // the engines of either artifact answer the same weights
+        formula.compile(dir.path(), dir.path()).unwrap();
+        let redb = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+        let sqlite = ShapeCodeEngine::with_formula(dir.path(), "fixture").unwrap();
+        for engine in [&redb as &dyn InputMethodEngine, &sqlite] {
+            let mut weights: Vec<_> = engine
+                .search("ni")
+                .unwrap()
+                .into_iter()
+                .map(|item| (item.text, item.weight))
+                .collect();
+            weights.sort();
+            assert_eq!(
+                weights,
+                [
+                    ("你".to_string(), 4294967296),
+                    ("尼".to_string(), MAX_WEIGHT),
+                    ("泥".to_string(), 0),
+                ]
+            );
+        }
+    }
+
+    mod properties {
+        use proptest::prelude::*;
+
+        use super::*;
+        use crate::config::{Config, Formula};
+        use crate::engine::{
+            EngineWithRedb, InputMethodEngine, MemoryEngine, SearchResultItem, ShapeCodeEngine,
+        };
+
+        /// Rows of a dictionary, with the quotes, spaces and symbols of real ones.
+        fn items() -> impl Strategy<Value = Vec<SearchResultItem>> {
+            let item = (
+                "[a-z你好中文\"' ][a-z你好中文\"#' ]{0,3}",
+                "[a-zA-Z_%]{1,4}",
+                0..1_000_000u64,
+                proptest::option::of("[a-z好 ]{1,4}"),
+            )
+                .prop_map(|(text, code, weight, comment)| SearchResultItem {
+                    text,
+                    code,
+                    weight,
+                    comment,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                });
+            // and some of them twice
+            (
+                proptest::collection::vec(item, 0..32),
+                proptest::collection::vec(any::<prop::sample::Index>(), 0..4),
+            )
+                .prop_map(|(mut items, repeated)| {
+                    if !items.is_empty() {
+                        for index in repeated {
+                            items.push(items[index.index(items.len())].clone());
+                        }
+                    }
+                    items
+                })
+        }
+
+        /// The formula `fixture` of `items`, with `dir` as its config dir, normalizing them
+        /// or not.
+        fn formula(dir: &Path, items: &[SearchResultItem], normalize: bool) -> Formula {
+            let mut tsv = String::from("text\tcode\tweight\tcomment\n");
+            for item in items {
+                tsv.push_str(&format!(
+                    "{}\t{}\t{}\t{}\n",
+                    item.text,
+                    item.code,
+                    item.weight,
+                    item.comment.as_deref().unwrap_or_default()
+                ));
+            }
+            fs::create_dir_all(dir.join("fixture")).unwrap();
+            fs::write(dir.join("fixture/words.tsv"), tsv).unwrap();
+            fs::write(
+                dir.join("main.dhall"),
+                format!(
+                    r#"{{ formulas = [ {{ id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], normalizeUnicode = {} }} ] }}"#,
+                    if normalize { "True" } else { "False" }
+                ),
+            )
+            .unwrap();
+            let config = Config::load_from_path(dir.join("main.dhall")).unwrap();
+            config.formulas.into_iter().next().unwrap()
+        }
+
+        /// Every prefix of the codes, and codes of nothing.
+        fn queries(items: &[SearchResultItem]) -> Vec<String> {
+            let mut queries: Vec<String> = items
+                .iter()
+                .flat_map(|item| (0..=item.code.len()).map(|end| item.code[..end].to_string()))
+                .chain(["zzzzz".to_string(), "_".to_string(), "%".to_string()])
+                .collect();
+            queries.sort();
+            queries.dedup();
+            queries
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(64))]
+
+            #[test]
+            fn test_build_matches_memory_engine(items in items()) {
+                let dir = tempfile::tempdir().unwrap();
+                // which searches codes as they are
+                let report = formula(dir.path(), &items, false).compile2(&dir, &dir).unwrap();
+                prop_assert_eq!(report.entries, items.len() as u64);
+                let engine = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+                let reference: MemoryEngine = items.iter().cloned().collect();
+                for query in queries(&items) {
+                    prop_assert_eq!(engine.search(&query).unwrap(), reference.search(&query).unwrap());
+                }
+            }
+
+            #[test]
+            fn test_sqlite_matches_redb(items in items()) {
+                let dir = tempfile::tempdir().unwrap();
+                let formula = formula(dir.path(), &items, true);
+                formula.compile(&dir, &dir).unwrap();
+                formula.compile2(&dir, &dir).unwrap();
+                let redb = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+                let sqlite = ShapeCodeEngine::with_formula(dir.path(), "fixture").unwrap();
+                for query in queries(&items) {
+                    prop_assert_eq!(
+                        sqlite.search(&query).unwrap(),
+                        redb.search(&query).unwrap(),
+                        "searching {}", query
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,565 @@
+//! Repairs of TSV dictionaries that mean no guess about what their author meant, for
+//! `liushu dict fix`. Each [`Fix`] can be left out, and the rows the build would still fail
+//! on are dropped, so that what is written builds without an error.
+//!
+//! Rows are split on tabs and lines starting with `#` are comments as the build reads them,
+//! and the comments and blank lines are written back where they were. A BOM starting a
+//! file, which the build skips, is never written back.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use super::{import, open_input, parse_weight};
+use crate::error::{IoResultExt, LiushuError};
+use crate::normalize;
+
+/// The columns the build reads, in the order they are written.
+const CANONICAL: [&str; 4] = ["text", "code", "weight", "comment"];
+
+const BOM: char = '\u{feff}';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fix {
+    /// Normalizes the texts and codes as the build does, see [`normalize`].
+    Unicode,
+    /// Keeps one row of each text and code, the heaviest.
+    Duplicates,
+    /// Strips the whitespace ending a field, the BOMs starting one and the empty fields
+    /// past the columns of the header.
+    Whitespace,
+    /// Writes 0 for a missing weight, and those the build would clamp clamped.
+    Weights,
+    /// Writes the columns in the order of the build, and fills in the missing ones.
+    Columns,
+}
+
+impl Fix {
+    pub const ALL: [Fix; 5] = [
+        Fix::Unicode,
+        Fix::Duplicates,
+        Fix::Whitespace,
+        Fix::Weights,
+        Fix::Columns,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fix::Unicode => "unicode",
+            Fix::Duplicates => "duplicates",
+            Fix::Whitespace => "whitespace",
+            Fix::Weights => "weights",
+            Fix::Columns => "columns",
+        }
+    }
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Fix {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Fix::ALL
+            .into_iter()
+            .find(|fix| fix.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Fix::ALL.iter().map(|fix| fix.name()).collect();
+                format!(
+                    "unknown fix {:?}, one of {} is expected",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[non_exhaustive]
+pub struct FixReport {
+    /// The rows written.
+    pub entries: u64,
+    /// The rows each fix applied changed, those it removed for [`Fix::Duplicates`].
+    pub fixed: BTreeMap<Fix, u64>,
+    /// The rows left out, as the build would fail on them.
+    pub dropped: Vec<LiushuError>,
+}
+
+/// A line of a dictionary as it is written back.
+enum Line {
+    /// A comment or a blank line.
+    Verbatim(String),
+    /// The fields of a row, by the columns of its dictionary, and whether those it lacked
+    /// were filled in.
+    Row(Vec<String>, bool),
+}
+
+/// A dictionary read for the fixes.
+struct Input {
+    /// The comments and blank lines before the header.
+    preamble: Vec<String>,
+    columns: Vec<String>,
+    lines: Vec<Line>,
+}
+
+/// Writes the rows of the TSV dictionaries `inputs` to `writer` as one, applying `fixes`
+/// and leaving out the rows the build would fail on. A dictionary whose header lacks a
+/// `text` or `code` column fails it, as it does the build.
+pub fn fix(
+    inputs: &[PathBuf],
+    fixes: &[Fix],
+    mut writer: impl Write,
+) -> Result<FixReport, LiushuError> {
+    let mut report = FixReport {
+        fixed: fixes.iter().map(|&fix| (fix, 0)).collect(),
+        ..FixReport::default()
+    };
+    let mut dicts = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        if import::is_scel(path) {
+            return Err(LiushuError::InvalidInput(format!(
+                "{} is a phrase library, write it as a TSV dictionary with import-scel first",
+                path.display()
+            )));
+        }
+        dicts.push(read(path, &mut report)?);
+    }
+    if report.fixed.contains_key(&Fix::Duplicates) {
+        collapse(&mut dicts, &mut report);
+    }
+
+    let mut columns: Vec<String> = Vec::new();
+    for dict in &dicts {
+        for column in &dict.columns {
+            if !columns.contains(column) {
+                columns.push(column.clone());
+            }
+        }
+    }
+    if columns.is_empty() {
+        // of dictionaries with no header, as the build reads empty ones
+        columns = CANONICAL[..3].iter().map(|c| c.to_string()).collect();
+    }
+    if report.fixed.contains_key(&Fix::Columns) {
+        let rank = |column: &String| CANONICAL.iter().position(|c| c == column);
+        columns.sort_by_key(|column| rank(column).unwrap_or(CANONICAL.len()));
+    }
+    let write_error = |e| LiushuError::io("cannot write the dictionary", e);
+    let mut written = 0;
+    for (i, dict) in dicts.into_iter().enumerate() {
+        for line in &dict.preamble {
+            writeln!(writer, "{}", line).map_err(write_error)?;
+        }
+        if i == 0 {
+            writeln!(writer, "{}", columns.join("\t")).map_err(write_error)?;
+        }
+        let at: Vec<_> = columns
+            .iter()
+            .map(|column| dict.columns.iter().position(|c| c == column))
+            .collect();
+        let reordered = at.iter().enumerate().any(|(i, &at)| at != Some(i));
+        for line in dict.lines {
+            let fields = match line {
+                Line::Verbatim(line) => {
+                    writeln!(writer, "{}", line).map_err(write_error)?;
+                    continue;
+                }
+                Line::Row(fields, filled) => {
+                    if reordered || filled {
+                        count(&mut report, Fix::Columns);
+                    }
+                    fields
+                }
+            };
+            let fields: Vec<_> = at
+                .iter()
+                .map(|at| at.map_or("", |at| fields[at].as_str()))
+                .collect();
+            writeln!(writer, "{}", fields.join("\t")).map_err(write_error)?;
+            written += 1;
+        }
+    }
+    writer.flush().map_err(write_error)?;
+    report.entries = written;
+    Ok(report)
+}
+
+/// Reads the dictionary at `path`, fixing each row on its own.
+fn read(path: &Path, report: &mut FixReport) -> Result<Input, LiushuError> {
+    let mut reader = open_input(path, "dictionary")?;
+    let mut dict = Input {
+        preamble: Vec::new(),
+        columns: Vec::new(),
+        lines: Vec::new(),
+    };
+    let mut buf = Vec::new();
+    let mut line_no = 0;
+    // where the text, code and weight are
+    let mut at = None;
+    loop {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .with_path("read dictionary", path)?
+            == 0
+        {
+            break;
+        }
+        line_no += 1;
+        let dropped = |reason: String| LiushuError::DictParse {
+            file: path.to_path_buf(),
+            line: line_no,
+            source: reason.into(),
+        };
+        let Ok(line) = std::str::from_utf8(&buf) else {
+            report
+                .dropped
+                .push(dropped("the row isn't UTF-8".to_string()));
+            continue;
+        };
+        let mut line = line.trim_end_matches('\n');
+        line = line.strip_suffix('\r').unwrap_or(line);
+        if line_no == 1 {
+            if let Some(rest) = line.strip_prefix(BOM) {
+                line = rest;
+                count(report, Fix::Whitespace);
+            }
+        }
+        let mut fields: Vec<String> = line.split('\t').map(str::to_string).collect();
+        let whitespace = report.fixed.contains_key(&Fix::Whitespace) && !line.starts_with('#');
+        // the empty fields of a header are past its columns
+        let stripped = whitespace && strip(&mut fields, dict.columns.len().max(1));
+        // a line of whitespace is a row of it without the fix
+        let blank = fields == [""] || whitespace && fields.iter().all(String::is_empty);
+        if stripped && (blank || at.is_none()) {
+            count(report, Fix::Whitespace);
+        }
+        if line.starts_with('#') || blank {
+            let verbatim = match line.starts_with('#') {
+                true => line.to_string(),
+                false => String::new(),
+            };
+            match at {
+                Some(_) => dict.lines.push(Line::Verbatim(verbatim)),
+                None => dict.preamble.push(verbatim),
+            }
+            continue;
+        }
+        let Some((text, code, weight)) = at else {
+            let position = |name| fields.iter().position(|field| field == name);
+            let (Some(text), Some(code)) = (position("text"), position("code")) else {
+                return Err(dropped(
+                    "the header names no text or code column".to_string(),
+                ));
+            };
+            let mut weight = position("weight");
+            if weight.is_none() && report.fixed.contains_key(&Fix::Weights) {
+                weight = Some(fields.len());
+                fields.push("weight".to_string());
+            }
+            at = Some((text, code, weight));
+            dict.columns = fields;
+            continue;
+        };
+        match fix_row(fields, &dict.columns, (text, code, weight), &report.fixed) {
+            Ok((fields, filled, fixed)) => {
+                let stripped = stripped.then_some(Fix::Whitespace);
+                for &fix in stripped.iter().chain(&fixed) {
+                    count(report, fix);
+                }
+                dict.lines.push(Line::Row(fields, filled));
+            }
+            Err(reason) => report.dropped.push(dropped(reason)),
+        }
+    }
+    Ok(dict)
+}
+
+/// Strips the BOMs starting `fields`, the whitespace ending them and the empty ones past
+/// the first `columns`, answering whether any went.
+fn strip(fields: &mut Vec<String>, columns: usize) -> bool {
+    let mut stripped = false;
+    for field in fields.iter_mut() {
+        let trimmed = field.trim_start_matches(BOM).trim_end();
+        if trimmed.len() != field.len() {
+            *field = trimmed.to_string();
+            stripped = true;
+        }
+    }
+    while fields.len() > columns && fields.last().is_some_and(|field| field.is_empty()) {
+        fields.pop();
+        stripped = true;
+    }
+    stripped
+}
+
+/// The `fields` of a row under `columns` once fixed, whether those it lacked were filled
+/// in and the other fixes that changed it, or why the build would fail on it.
+fn fix_row(
+    mut fields: Vec<String>,
+    columns: &[String],
+    (text, code, weight): (usize, usize, Option<usize>),
+    fixes: &BTreeMap<Fix, u64>,
+) -> Result<(Vec<String>, bool, Vec<Fix>), String> {
+    let applied = |fix| fixes.contains_key(&fix);
+    let [weights, fill, unicode] = [Fix::Weights, Fix::Columns, Fix::Unicode].map(applied);
+    let mut fixed = Vec::new();
+    if fields.len() > columns.len() {
+        return Err(format!(
+            "the row has {} fields, the header {}",
+            fields.len(),
+            columns.len()
+        ));
+    }
+    if fields.len() <= text.max(code) {
+        return Err("the row has no text or code".to_string());
+    }
+    let Some(weight) = weight else {
+        return Err("the row has no weight".to_string());
+    };
+    if fields.len() <= weight || fields[weight].is_empty() {
+        if !weights {
+            return Err("the row has no weight".to_string());
+        }
+        fields.resize(fields.len().max(weight + 1), String::new());
+        fields[weight] = "0".to_string();
+        fixed.push(Fix::Weights);
+    } else {
+        match parse_weight(&fields[weight]) {
+            Ok((clamped, true)) if weights => {
+                fields[weight] = clamped.to_string();
+                fixed.push(Fix::Weights);
+            }
+            Ok(_) => {}
+            Err(e) => return Err(format!("invalid weight {:?}: {}", fields[weight], e)),
+        }
+    }
+    let filled = fields.len() < columns.len();
+    if filled {
+        if !fill {
+            return Err(format!(
+                "the row has {} fields, the header {}",
+                fields.len(),
+                columns.len()
+            ));
+        }
+        fields.resize(columns.len(), String::new());
+    }
+    if unicode {
+        let normalized_text = normalize::text(&fields[text]).into_owned();
+        let normalized_code = normalize::code(&fields[code]).into_owned();
+        if normalized_text != fields[text] || normalized_code != fields[code] {
+            fields[text] = normalized_text;
+            fields[code] = normalized_code;
+            fixed.push(Fix::Unicode);
+        }
+    }
+    Ok((fields, filled, fixed))
+}
+
+/// Keeps the heaviest row of each text and code of `dicts`, the first of those as heavy.
+fn collapse(dicts: &mut [Input], report: &mut FixReport) {
+    // the dictionary and line of the row kept, and its weight
+    let mut kept: HashMap<(String, String), (usize, usize, u64)> = HashMap::new();
+    let mut removed = HashSet::new();
+    for (d, dict) in dicts.iter().enumerate() {
+        let at = |name| dict.columns.iter().position(|c| c == name);
+        let (Some(text), Some(code), Some(weight)) = (at("text"), at("code"), at("weight")) else {
+            continue;
+        };
+        for (l, line) in dict.lines.iter().enumerate() {
+            let Line::Row(fields, _) = line else {
+                continue;
+            };
+            let key = (fields[text].clone(), fields[code].clone());
+            let weight = parse_weight(&fields[weight]).map_or(0, |(weight, _)| weight);
+            match kept.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert((d, l, weight));
+                }
+                // the row itself is kept, with the fields of its own columns
+                Entry::Occupied(mut entry) if weight > entry.get().2 => {
+                    let (first_d, first_l, _) = entry.insert((d, l, weight));
+                    removed.insert((first_d, first_l));
+                }
+                Entry::Occupied(_) => {
+                    removed.insert((d, l));
+                }
+            }
+        }
+    }
+    for (d, dict) in dicts.iter_mut().enumerate() {
+        let mut l = 0;
+        dict.lines.retain(|_| {
+            l += 1;
+            !removed.contains(&(d, l - 1))
+        });
+    }
+    report.fixed.insert(Fix::Duplicates, removed.len() as u64);
+}
+
+fn count(report: &mut FixReport, fix: Fix) {
+    if let Some(count) = report.fixed.get_mut(&fix) {
+        *count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::dict::validate;
+
+    const FIXED: &str = "# words to fix
+text\tcode\tweight\tcomment
+你好\tnihao\t2\t
+你\tni\t3\t常用
+好\thao\t0\t
+我\two\t0\t
+
+们\tmen\t0\t
+的\tde\t4\t
+café\tkafei\t1\t
+可\tke\t1\t
+";
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/unfixed.tsv")
+    }
+
+    fn lines(report: &FixReport) -> Vec<u64> {
+        let lines = report.dropped.iter().map(|error| match error {
+            LiushuError::DictParse { line, .. } => *line,
+            error => panic!("unexpected {:?}", error),
+        });
+        lines.collect()
+    }
+
+    #[test]
+    fn test_fix() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixed = dir.path().join("fixed.tsv");
+        let report = fix(&[fixture()], &Fix::ALL, fs::File::create(&fixed).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&fixed).unwrap(), FIXED);
+        assert_eq!(report.entries, 8);
+        assert_eq!(
+            report
+                .fixed
+                .iter()
+                .map(|(&f, &n)| (f, n))
+                .collect::<Vec<_>>(),
+            [
+                (Fix::Unicode, 2),
+                (Fix::Duplicates, 2),
+                (Fix::Whitespace, 3),
+                (Fix::Weights, 3),
+                (Fix::Columns, 8),
+            ]
+        );
+        // the weight many, and a row of more fields than the header
+        assert_eq!(lines(&report), [9, 13]);
+
+        let check = validate(&[fixed]).unwrap();
+        assert_eq!(check.entries, 8);
+        assert!(check.errors.is_empty(), "{:?}", check.errors);
+        assert!(check.warnings.is_empty(), "{:?}", check.warnings);
+        // the build would fail on the rows before anything is fixed
+        assert!(!validate(&[fixture()]).unwrap().errors.is_empty());
+    }
+
+    #[test]
+    fn test_each_fix() {
+        let fixed = |only: Fix| {
+            let mut output = Vec::new();
+            let report = fix(&[fixture()], &[only], &mut output).unwrap();
+            let fixed: Vec<_> = report.fixed.iter().map(|(&f, &n)| (f, n)).collect();
+            (fixed, lines(&report))
+        };
+        // that of the empty weight, one of a missing one and the one past the header
+        assert_eq!(
+            fixed(Fix::Unicode),
+            (vec![(Fix::Unicode, 1)], vec![6, 7, 9, 11, 13])
+        );
+        // 你 isn't that of 你 and a space
+        assert_eq!(
+            fixed(Fix::Duplicates),
+            (vec![(Fix::Duplicates, 1)], vec![6, 7, 9, 11, 13])
+        );
+        assert_eq!(
+            fixed(Fix::Whitespace),
+            (vec![(Fix::Whitespace, 3)], vec![6, 7, 9, 13])
+        );
+        // the row of no comment is missing a field still
+        assert_eq!(
+            fixed(Fix::Weights),
+            (vec![(Fix::Weights, 2)], vec![7, 9, 11, 13])
+        );
+        assert_eq!(
+            fixed(Fix::Columns),
+            (vec![(Fix::Columns, 7)], vec![6, 7, 9, 11, 13])
+        );
+
+        let mut output = Vec::new();
+        let report = fix(&[fixture()], &[], &mut output).unwrap();
+        assert!(report.fixed.is_empty());
+        // as they were, but the first BOM
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("# words to fix\ncode\ttext\tweight\tcomment\nnihao\t"));
+    }
+
+    #[test]
+    fn test_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        let more = dir.path().join("more.tsv");
+        fs::write(&words, "text\tcode\tweight\n你\tni\t1\n").unwrap();
+        // no weight column, and a lighter row of a text and code of the first
+        fs::write(
+            &more,
+            "# more\ncode\ttext\tcomment\nni\t你\tagain\nhao\t好\t\n",
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        let report = fix(&[words.clone(), more.clone()], &Fix::ALL, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "text\tcode\tweight\tcomment\n你\tni\t1\t\n# more\n好\thao\t0\t\n"
+        );
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.fixed[&Fix::Duplicates], 1);
+
+        // the comment of a heavier row of a dictionary with more columns
+        fs::write(&words, "text\tcode\tweight\n你\tni\t1\n好\thao\t1\n").unwrap();
+        fs::write(&more, "text\tcode\tweight\tcomment\n你\tni\t5\tcommon\n").unwrap();
+        for fixes in [&Fix::ALL[..], &[Fix::Duplicates]] {
+            let mut output = Vec::new();
+            fix(&[words.clone(), more.clone()], fixes, &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "text\tcode\tweight\tcomment\n好\thao\t1\t\n你\tni\t5\tcommon\n"
+            );
+        }
+
+        fs::write(&words, "word\tkeys\n你\tni\n").unwrap();
+        assert!(matches!(
+            fix(&[words], &Fix::ALL, Vec::new()),
+            Err(LiushuError::DictParse { line: 1, .. })
+        ));
+        let scel = dir.path().join("phrases.scel");
+        assert!(matches!(
+            fix(&[scel], &Fix::ALL, Vec::new()),
+            Err(LiushuError::InvalidInput(_))
+        ));
+    }
+}
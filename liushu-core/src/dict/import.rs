@@ -0,0 +1,255 @@
+//! Dictionaries of other input methods, read into the entries of a TSV dictionary.
+//!
+//! The phrase libraries of Sogou (`.scel`) and QQ (`.qcel`) share a layout of
+//! little-endian integers and UTF-16LE text:
+//!
+//! | offset   | what                                                                  |
+//! |----------|-----------------------------------------------------------------------|
+//! | `0x0`    | the magic `40 15 00 00`                                               |
+//! | `0x120`  | the number of groups of words, then of words, both `u32`              |
+//! | `0x130`  | the name, description and examples of the library, not read           |
+//! | `0x1540` | the number of syllables of the pinyin table, then each of them        |
+//! | `0x2628` | the groups of words                                                   |
+//!
+//! A syllable of the pinyin table is its `u16` index, the `u16` bytes of its text and the
+//! text. A group of words is the `u16` number of its words and the `u16` bytes of the
+//! indexes of their syllables, each a `u16`, followed by each word: the `u16` bytes of its
+//! text, the text, and the `u16` bytes of an extension starting with its `u16` frequency.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::artifact::DictItem;
+use crate::error::{IoResultExt, LiushuError};
+
+/// Extensions of the phrase libraries [`scel`] reads, whatever their case.
+pub const SCEL_EXTENSIONS: [&str; 2] = ["scel", "qcel"];
+
+const MAGIC: [u8; 4] = [0x40, 0x15, 0x00, 0x00];
+const COUNTS: usize = 0x120;
+const PINYIN_TABLE: usize = 0x1540;
+const WORDS: usize = 0x2628;
+
+/// Whether `path` is named like a phrase library [`scel`] reads.
+pub fn is_scel(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            SCEL_EXTENSIONS
+                .iter()
+                .any(|scel| ext.eq_ignore_ascii_case(scel))
+        })
+}
+
+/// The words of a phrase library of Sogou or QQ, in the order of the library. The code of
+/// a word is its pinyin, syllables joined by spaces, and its weight the frequency of the
+/// library.
+///
+/// What can't be read is a `DictParse` error at the group of words it is in, counting from
+/// 1, or 0 for the header, as these files are often truncated.
+pub fn scel(path: &Path) -> Result<Vec<DictItem>, LiushuError> {
+    if !path.exists() {
+        return Err(LiushuError::Missing(path.to_path_buf()));
+    }
+    let bytes = fs::read(path).with_path("read phrase library", path)?;
+    let mut reader = Reader {
+        bytes: &bytes,
+        at: 0,
+        group: 0,
+    };
+    reader.words().map_err(|reason| LiushuError::DictParse {
+        file: path.to_path_buf(),
+        line: reader.group,
+        source: reason.into(),
+    })
+}
+
+/// The bytes of a phrase library, read from `at` on.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// Of the words read, from 1, 0 while reading the header.
+    group: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn words(&mut self) -> Result<Vec<DictItem>, String> {
+        if self.bytes.get(..MAGIC.len()) != Some(&MAGIC) {
+            return Err("not a phrase library of Sogou or QQ".to_string());
+        }
+        self.at = COUNTS;
+        let groups = self.u32()?;
+        let words = self.u32()?;
+
+        self.at = PINYIN_TABLE;
+        let syllables = self.u32()?;
+        let mut pinyin = HashMap::new();
+        for _ in 0..syllables {
+            let index = self.u16()?;
+            let len = self.u16()?;
+            pinyin.insert(index, self.text(len)?);
+        }
+
+        self.at = WORDS;
+        let mut items = Vec::new();
+        for group in 1..=u64::from(groups) {
+            self.group = group;
+            let count = self.u16()?;
+            let len = self.u16()?;
+            if len % 2 != 0 {
+                return Err(format!("odd length {} of the pinyin of words", len));
+            }
+            let mut syllables = Vec::with_capacity(usize::from(len / 2));
+            for _ in 0..len / 2 {
+                let index = self.u16()?;
+                let syllable = pinyin
+                    .get(&index)
+                    .ok_or_else(|| format!("no syllable {} in the pinyin table", index))?;
+                syllables.push(syllable.as_str());
+            }
+            let code = syllables.join(" ");
+            for _ in 0..count {
+                let len = self.u16()?;
+                let text = self.text(len)?;
+                let len = self.u16()?;
+                let extension = self.take(usize::from(len))?;
+                let frequency = match extension {
+                    [low, high, ..] => u16::from_le_bytes([*low, *high]),
+                    _ => 0,
+                };
+                items.push(DictItem {
+                    text,
+                    code: code.clone(),
+                    weight: u64::from(frequency),
+                    comment: None,
+                });
+            }
+        }
+        if items.len() as u64 != u64::from(words) {
+            return Err(format!(
+                "{} words in the groups where the header has {}",
+                items.len(),
+                words
+            ));
+        }
+        Ok(items)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.at..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or_else(|| {
+                format!(
+                    "truncated at byte {}, {} more expected at {}",
+                    self.bytes.len(),
+                    len,
+                    self.at
+                )
+            })?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// UTF-16LE text of `len` bytes.
+    fn text(&mut self, len: u16) -> Result<String, String> {
+        let at = self.at;
+        let bytes = self.take(usize::from(len))?;
+        if bytes.len() % 2 != 0 {
+            return Err(format!("odd length {} of the text at {}", len, at));
+        }
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        String::from_utf16(&units).map_err(|_| format!("invalid UTF-16 text at {}", at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/phrases.scel")
+    }
+
+    #[test]
+    fn test_scel() {
+        let items = scel(&fixture()).unwrap();
+        let entries: Vec<_> = items
+            .iter()
+            .map(|item| (item.text.as_str(), item.code.as_str(), item.weight))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("你好", "ni hao", 20),
+                ("拟好", "ni hao", 3),
+                ("世界", "shi jie", 15),
+                ("你", "ni", 9),
+            ]
+        );
+        assert!(items.iter().all(|item| item.comment.is_none()));
+
+        assert!(is_scel(Path::new("dir/phrases.QCEL")));
+        assert!(!is_scel(Path::new("scel.tsv")));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = fs::read(fixture()).unwrap();
+        let path = dir.path().join("truncated.scel");
+        let error = |len: usize| {
+            fs::write(&path, &bytes[..len]).unwrap();
+            match scel(&path).unwrap_err() {
+                LiushuError::DictParse { file, line, source } => {
+                    assert_eq!(file, path);
+                    (line, source.to_string())
+                }
+                error => panic!("{:?}", error),
+            }
+        };
+        // wherever it ends, nothing panics
+        for len in 0..bytes.len() {
+            error(len);
+        }
+        assert_eq!(
+            error(2),
+            (0, "not a phrase library of Sogou or QQ".to_string())
+        );
+        assert_eq!(
+            error(WORDS),
+            (
+                1,
+                format!("truncated at byte {0}, 2 more expected at {0}", WORDS)
+            )
+        );
+        assert_eq!(error(bytes.len() - 1).0, 3);
+
+        fs::write(&path, b"text\tcode\tweight\n").unwrap();
+        assert_eq!(
+            scel(&path).unwrap_err().to_string(),
+            format!("{}:0", path.display())
+        );
+        assert!(matches!(
+            scel(&dir.path().join("missing.scel")),
+            Err(LiushuError::Missing(_))
+        ));
+    }
+}
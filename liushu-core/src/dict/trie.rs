@@ -0,0 +1,315 @@
+//! The code trie of a build, gathered as the rows are read and written once they all are.
+//!
+//! Gathered in memory, or with [`BuildOptions::max_memory`](super::BuildOptions::max_memory)
+//! in runs of rows sorted by code, spilled next to the trie as the buffer fills up and merged
+//! when it is written. Either way the codes are inserted in bytewise order, so that the
+//! trie is the same: merging streams the texts of each code to the file and keeps only the
+//! codes in memory, which the trie is serialized from before the texts are appended, as
+//! bincode writes a [`PatriciaMap`] as its nodes then its values in the order of the codes.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use patricia_tree::PatriciaMap;
+use tracing::debug;
+
+use crate::error::{IoResultExt, LiushuError};
+
+/// A row of a run: its code, the index of the row in the build, for the order of the texts
+/// of a code, and its text.
+type Pair = (String, u64, String);
+
+/// Bytes a buffered row takes besides its code and text.
+const PAIR_OVERHEAD: usize = mem::size_of::<Pair>();
+
+pub(super) enum TrieBuilder {
+    Memory(BTreeMap<String, Vec<String>>),
+    Spill(Spill),
+}
+
+impl TrieBuilder {
+    /// Spilling rows past `max_memory` bytes of them next to `trie_path`, if given.
+    pub(super) fn new(max_memory: Option<usize>, trie_path: &Path) -> Self {
+        match max_memory {
+            None => Self::Memory(BTreeMap::new()),
+            Some(max_memory) => Self::Spill(Spill {
+                trie_path: trie_path.to_path_buf(),
+                max_memory,
+                pairs: Vec::new(),
+                buffered: 0,
+                rows: 0,
+                runs: Vec::new(),
+                temps: Vec::new(),
+            }),
+        }
+    }
+
+    /// Adds `text` to the candidates of `code`, where a row given twice is one.
+    pub(super) fn push(&mut self, code: String, text: String) -> Result<(), LiushuError> {
+        match self {
+            Self::Memory(codes) => {
+                let texts = codes.entry(code).or_default();
+                if !texts.contains(&text) {
+                    texts.push(text);
+                }
+                Ok(())
+            }
+            Self::Spill(spill) => spill.push(code, text),
+        }
+    }
+
+    /// Writes the trie to `path`, answering its codes.
+    pub(super) fn write(self, path: &Path) -> Result<usize, LiushuError> {
+        let file = File::create(path).with_path("create trie", path)?;
+        let mut writer = BufWriter::new(file);
+        let codes = match self {
+            Self::Memory(codes) => {
+                let mut trie = PatriciaMap::new();
+                for (code, texts) in codes {
+                    trie.insert_str(&code, texts);
+                }
+                serialize(&mut writer, &trie, "write trie", path)?;
+                trie.len()
+            }
+            Self::Spill(spill) => spill.write(&mut writer, path)?,
+        };
+        writer.flush().with_path("write trie", path)?;
+        debug!(codes, "wrote trie");
+        Ok(codes)
+    }
+}
+
+pub(super) struct Spill {
+    trie_path: PathBuf,
+    max_memory: usize,
+    pairs: Vec<Pair>,
+    /// Bytes of the pairs.
+    buffered: usize,
+    rows: u64,
+    /// Each with its rows.
+    runs: Vec<(PathBuf, u64)>,
+    /// Files to remove once the trie is written or the build failed, the runs among them.
+    temps: Vec<PathBuf>,
+}
+
+impl Spill {
+    fn push(&mut self, code: String, text: String) -> Result<(), LiushuError> {
+        self.buffered += code.len() + text.len() + PAIR_OVERHEAD;
+        self.pairs.push((code, self.rows, text));
+        self.rows += 1;
+        if self.buffered > self.max_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered rows as a run, sorted.
+    fn spill(&mut self) -> Result<(), LiushuError> {
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+        // each row has an index of its own, the texts are never compared
+        self.pairs.sort_unstable();
+        let path = self.temp(&format!("run{}", self.runs.len()));
+        let file = File::create(&path).with_path("create run", &path)?;
+        let mut writer = BufWriter::new(file);
+        for pair in &self.pairs {
+            serialize(&mut writer, pair, "write run", &path)?;
+        }
+        writer.flush().with_path("write run", &path)?;
+        debug!(run = %path.display(), rows = self.pairs.len(), "spilled rows");
+        self.runs.push((path, self.pairs.len() as u64));
+        self.pairs.clear();
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Where a file named `name` is written, next to the trie.
+    fn temp(&mut self, name: &str) -> PathBuf {
+        let mut path = self.trie_path.as_os_str().to_os_string();
+        path.push(format!(".{}.tmp", name));
+        let path = PathBuf::from(path);
+        self.temps.push(path.clone());
+        path
+    }
+
+    /// Merges the runs, writing the trie to `writer`.
+    fn write(mut self, writer: &mut impl Write, path: &Path) -> Result<usize, LiushuError> {
+        self.spill()?;
+        let mut runs = Vec::new();
+        for (run, rows) in &self.runs {
+            let file = File::open(run).with_path("open run", run)?;
+            runs.push(Run {
+                path: run.clone(),
+                reader: BufReader::new(file),
+                left: *rows,
+            });
+        }
+        let mut heap = BinaryHeap::new();
+        for (i, run) in runs.iter_mut().enumerate() {
+            if let Some(pair) = run.next()? {
+                heap.push(Reverse((pair, i)));
+            }
+        }
+
+        let mut codes = PatriciaMap::new();
+        let values_path = self.temp("values");
+        let values_file = File::create(&values_path).with_path("create trie", &values_path)?;
+        let mut values = BufWriter::new(values_file);
+        let mut current: Option<(String, Vec<String>)> = None;
+        let mut finish = |code: String, texts: Vec<String>| {
+            codes.insert_str(&code, ());
+            serialize(&mut values, &texts, "write trie", &values_path)
+        };
+        while let Some(Reverse(((code, _, text), i))) = heap.pop() {
+            if let Some(pair) = runs[i].next()? {
+                heap.push(Reverse((pair, i)));
+            }
+            match &mut current {
+                Some((current, texts)) if *current == code => {
+                    if !texts.contains(&text) {
+                        texts.push(text);
+                    }
+                }
+                _ => {
+                    if let Some((code, texts)) = current.replace((code, vec![text])) {
+                        finish(code, texts)?;
+                    }
+                }
+            }
+        }
+        if let Some((code, texts)) = current {
+            finish(code, texts)?;
+        }
+        let values = values
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path("write trie", &values_path)?;
+        drop(values);
+
+        // the nodes of codes without values are those of the trie, followed by its values
+        serialize(writer, &codes, "write trie", path)?;
+        let mut values = File::open(&values_path).with_path("open trie", &values_path)?;
+        io::copy(&mut values, writer).with_path("write trie", path)?;
+        Ok(codes.len())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        for temp in &self.temps {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+/// The rows of a run left to read.
+struct Run {
+    path: PathBuf,
+    reader: BufReader<File>,
+    left: u64,
+}
+
+impl Run {
+    fn next(&mut self) -> Result<Option<Pair>, LiushuError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        bincode::deserialize_from(&mut self.reader)
+            .map(Some)
+            .map_err(|e| LiushuError::io_at("read run", &self.path, e))
+    }
+}
+
+/// Writes `value` as the trie artifact does, `operation` of `path` in errors.
+fn serialize<T: serde::Serialize + ?Sized>(
+    writer: &mut impl Write,
+    value: &T,
+    operation: &str,
+    path: &Path,
+) -> Result<(), LiushuError> {
+    bincode::serialize_into(writer, value).map_err(|e| LiushuError::io_at(operation, path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dict::{build, BuildOptions};
+    use crate::progress::NoProgress;
+
+    /// Builds `words` into `dir` with `max_memory`, answering the trie and the dictionary.
+    fn build_with(dir: &Path, words: &Path, max_memory: Option<usize>) -> (Vec<u8>, Vec<u8>) {
+        let target_dir = dir.join(format!("{:?}", max_memory));
+        let options = BuildOptions {
+            max_memory,
+            ..Default::default()
+        };
+        let report = build(
+            &[words.to_path_buf()],
+            &target_dir,
+            "fixture",
+            options,
+            &NoProgress,
+        )
+        .unwrap();
+        let mut names: Vec<_> = fs::read_dir(&target_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        // nothing left of the runs
+        assert_eq!(names, ["fixture.assoc", "fixture.redb", "fixture.trie"]);
+        assert!(report.codes > 0);
+        (
+            fs::read(target_dir.join("fixture.trie")).unwrap(),
+            fs::read(target_dir.join("fixture.redb")).unwrap(),
+        )
+    }
+
+    /// `rows` rows, a fifth of them given twice and texts under several codes, with
+    /// codes sharing leading bytes as `é` and `ê` do.
+    fn words(dir: &Path, rows: u32) -> PathBuf {
+        let mut words = String::from("text\tcode\tweight\n");
+        let codes = ["a", "ab", "abc", "b", "é", "ê", "éa", "ni hao", "z"];
+        for i in 0..rows {
+            let text = char::from_u32(0x4e00 + i % 4000).unwrap();
+            let code = codes[(i as usize * 7) % codes.len()];
+            words.push_str(&format!("{}\t{}{}\t{}\n", text, code, i % 13, i));
+            if i % 5 == 0 {
+                words.push_str(&format!("{}\t{}{}\t{}\n", text, code, i % 13, i));
+            }
+        }
+        let path = dir.join("words.tsv");
+        fs::write(&path, words).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_spill() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = words(dir.path(), 500);
+        let expected = build_with(dir.path(), &words, None);
+        for max_memory in [0, 1000, 1 << 20] {
+            assert!(
+                build_with(dir.path(), &words, Some(max_memory)) == expected,
+                "spilling past {} bytes",
+                max_memory
+            );
+        }
+    }
+
+    /// Run with `cargo test --release -p liushu-core --features big-tests test_spill_big`.
+    #[cfg(feature = "big-tests")]
+    #[test]
+    fn test_spill_big() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = words(dir.path(), 1_000_000);
+        let expected = build_with(dir.path(), &words, None);
+        assert!(build_with(dir.path(), &words, Some(4 << 20)) == expected);
+    }
+}
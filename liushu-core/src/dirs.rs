@@ -1,19 +1,75 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
+#[cfg(feature = "desktop-dirs")]
 use directories::BaseDirs;
+#[cfg(feature = "desktop-dirs")]
 use once_cell::sync::Lazy;
 
-#[derive(Debug)]
+use crate::error::{IoResultExt, LiushuError};
+
+pub mod lock;
+pub mod preflight;
+pub mod profiles;
+
+/// Points [`PROJECT_DIRS`] at a profile root of its own when set, see
+/// [`MyProjectDirs::from_root`].
+#[cfg(feature = "desktop-dirs")]
+pub const PROFILE_ENV: &str = "LIUSHU_PROFILE";
+
+#[derive(Debug, Clone)]
 pub struct MyProjectDirs {
     pub config_dir: PathBuf,
     pub data_dir: PathBuf,
     pub target_dir: PathBuf,
 }
 
-pub static PROJECT_DIRS: Lazy<MyProjectDirs> = Lazy::new(|| {
-    let base_dirs = BaseDirs::new().expect(
-        "there is no valid home directory path could be retrieved from the operating system",
+impl MyProjectDirs {
+    /// A profile with everything under `root`, `config/` and `data/` with its `target/`.
+    pub fn from_root(root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref();
+        let data_dir = root.join("data");
+        Self {
+            config_dir: root.join("config"),
+            target_dir: data_dir.join("target"),
+            data_dir,
+        }
+    }
+
+    /// Creates the dirs of the profile, naming the one that can't be.
+    pub fn ensure(&self) -> Result<(), LiushuError> {
+        for (kind, dir) in [
+            ("config", &self.config_dir),
+            ("data", &self.data_dir),
+            ("target", &self.target_dir),
+        ] {
+            fs::create_dir_all(dir).with_path(&format!("create {} dir", kind), dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// The dirs of the profile in use, those of [`PROFILE_ENV`] when set and otherwise
+/// [`user_dirs`]. Nothing is created until [`MyProjectDirs::ensure`] is called.
+#[cfg(feature = "desktop-dirs")]
+pub static PROJECT_DIRS: Lazy<MyProjectDirs> =
+    Lazy::new(
+        || match std::env::var_os(PROFILE_ENV).filter(|root| !root.is_empty()) {
+            Some(root) => MyProjectDirs::from_root(root),
+            None => user_dirs(),
+        },
     );
+
+/// The dirs of the user, in which the [named profiles](profiles::Profiles) are kept too.
+///
+/// Without a home dir the profile is `.liushu` in the working dir rather than a panic.
+#[cfg(feature = "desktop-dirs")]
+pub fn user_dirs() -> MyProjectDirs {
+    let Some(base_dirs) = BaseDirs::new() else {
+        return MyProjectDirs::from_root(".liushu");
+    };
     let data_dir = base_dirs.data_dir().join("liushu");
 
     MyProjectDirs {
@@ -21,4 +77,49 @@ pub static PROJECT_DIRS: Lazy<MyProjectDirs> = Lazy::new(|| {
         target_dir: data_dir.join("target"),
         data_dir,
     }
-});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = MyProjectDirs::from_root(root.path().join("profile"));
+        dirs.ensure().unwrap();
+        assert!(dirs.target_dir.is_dir());
+        assert!(dirs.config_dir.is_dir());
+
+        // a file in the way fails even for root
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+        let error = MyProjectDirs::from_root(&file).ensure().unwrap_err();
+        assert_eq!(error.exit_code(), 4);
+        assert!(error.to_string().starts_with(&format!(
+            "failed to create config dir ‘{}’",
+            file.join("config").display()
+        )));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ensure_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let parent = root.path().join("read-only");
+        fs::create_dir(&parent).unwrap();
+        fs::set_permissions(&parent, fs::Permissions::from_mode(0o555)).unwrap();
+        // permissions don't stop root
+        if fs::create_dir(parent.join("probe")).is_ok() {
+            return;
+        }
+
+        let dirs = MyProjectDirs::from_root(parent.join("profile"));
+        let error = dirs.ensure().unwrap_err().to_string();
+        assert!(error.contains(&dirs.config_dir.display().to_string()));
+        assert!(error.contains("ermission denied"));
+        fs::set_permissions(&parent, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
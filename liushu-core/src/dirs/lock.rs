@@ -0,0 +1,185 @@
+//! Advisory locks of a target dir, so that liushu processes sharing a profile take turns
+//! writing it:
+//!
+//! - a deploy, rollback, clean or training holds [`DEPLOY_LOCK`] for as long as it writes
+//!   the target dir, and fails with [`LiushuError::Locked`] while another one does, unless
+//!   it waits for it;
+//! - the artifacts of a formula are renamed into the target dir under the exclusive
+//!   [`ARTIFACTS_LOCK`], and engines open artifacts under the shared one, so that none of
+//!   them opens the new dictionary of a formula with its previous trie.
+//!
+//! Once open, artifacts are read without any lock, a deploy only ever renames new files
+//! over them. A lock is released when it is dropped, or when its process dies.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+use crate::error::{IoResultExt, LiushuError};
+
+/// The file of the target dir locked by the process writing it.
+pub const DEPLOY_LOCK: &str = "deploy.lock";
+
+/// The file of the target dir locked while artifacts are replaced or opened.
+pub const ARTIFACTS_LOCK: &str = "artifacts.lock";
+
+/// A lock of a file, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// The lock of the process writing `target_dir`. Without `wait`, fails right away while
+    /// another process holds it.
+    pub fn deploying(target_dir: &Path, wait: bool) -> Result<Self, LiushuError> {
+        let path = target_dir.join(DEPLOY_LOCK);
+        let file = open(&path)?;
+        if wait {
+            file.lock().with_path("lock", &path)?;
+        } else {
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    return Err(LiushuError::Locked(target_dir.to_path_buf()))
+                }
+                Err(TryLockError::Error(e)) => return Err(LiushuError::io_at("lock", &path, e)),
+            }
+        }
+        debug!(path = %path.display(), "locked");
+        Ok(Self { file, path })
+    }
+
+    /// Held while artifacts are renamed into `target_dir`, waiting for the engines opening
+    /// them.
+    pub fn replacing(target_dir: &Path) -> Result<Self, LiushuError> {
+        let path = target_dir.join(ARTIFACTS_LOCK);
+        let file = open(&path)?;
+        file.lock().with_path("lock", &path)?;
+        Ok(Self { file, path })
+    }
+
+    /// Held while artifacts of `target_dir` are opened, waiting for a deploy replacing them.
+    /// `None` in a dir that can't be written, where no deploy replaces them either, or where
+    /// files can't be locked.
+    pub fn opening(target_dir: &Path) -> Result<Option<Self>, LiushuError> {
+        let path = target_dir.join(ARTIFACTS_LOCK);
+        let file = match OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(LiushuError::io_at("open lock", &path, e)),
+        };
+        match file.lock_shared() {
+            Ok(()) => Ok(Some(Self { file, path })),
+            Err(e) if e.kind() == ErrorKind::Unsupported => Ok(None),
+            Err(e) => Err(LiushuError::io_at("lock", &path, e)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // closing the file releases it too
+        let _ = self.file.unlock();
+    }
+}
+
+fn open(path: &Path) -> Result<File, LiushuError> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_path("open lock", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_deploying() {
+        let dir = tempfile::tempdir().unwrap();
+        let target_dir = dir.path();
+        let lock = Lock::deploying(target_dir, false).unwrap();
+        thread::scope(|scope| {
+            let error = scope
+                .spawn(|| Lock::deploying(target_dir, false).unwrap_err())
+                .join()
+                .unwrap();
+            assert_eq!(error.code(), "E_LOCKED");
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "another liushu process is deploying to {}",
+                    target_dir.display()
+                )
+            );
+            assert_eq!(error.path(), Some(target_dir));
+            assert_eq!(error.exit_code(), 6);
+
+            // one waiting gets it once released
+            let (locked, waited) = mpsc::channel();
+            scope.spawn(move || {
+                let lock = Lock::deploying(target_dir, true).unwrap();
+                locked.send(()).unwrap();
+                drop(lock);
+            });
+            assert!(waited.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(lock);
+            waited.recv_timeout(Duration::from_secs(10)).unwrap();
+        });
+        drop(Lock::deploying(target_dir, false).unwrap());
+    }
+
+    #[test]
+    fn test_opening() {
+        let dir = tempfile::tempdir().unwrap();
+        let target_dir = dir.path();
+        // engines open artifacts at the same time
+        let first = Lock::opening(target_dir).unwrap().unwrap();
+        let second = Lock::opening(target_dir).unwrap().unwrap();
+        assert_eq!(first.path(), target_dir.join(ARTIFACTS_LOCK));
+
+        thread::scope(|scope| {
+            let (replacing, replaced) = mpsc::channel();
+            scope.spawn(move || {
+                let lock = Lock::replacing(target_dir).unwrap();
+                replacing.send(()).unwrap();
+                drop(lock);
+            });
+            drop(first);
+            assert!(replaced.recv_timeout(Duration::from_millis(100)).is_err());
+            drop(second);
+            replaced.recv_timeout(Duration::from_secs(10)).unwrap();
+        });
+        // nor do they hold the deploy lock
+        let _opening = Lock::opening(target_dir).unwrap();
+        drop(Lock::deploying(target_dir, false).unwrap());
+    }
+}
@@ -0,0 +1,177 @@
+//! Checks run before writing large artifacts, so that a full disk or a read-only dir fails
+//! right away instead of halfway through.
+
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use tracing::debug;
+
+use crate::error::{IoResultExt, LiushuError};
+
+/// Margin over the estimated size of the outputs.
+pub const SAFETY_FACTOR: f64 = 1.5;
+
+/// Bytes of the sqlite database, redb database and trie of a formula per byte of its
+/// dictionaries.
+pub const DEPLOY_RATIO: f64 = 4.0;
+
+/// Bytes of the counts of a model per byte of its corpora, they are usually much smaller.
+pub const TRAIN_RATIO: f64 = 0.5;
+
+/// What the checks need to know about the filesystem.
+pub trait FsInfo {
+    /// Bytes available to the user on the filesystem of `dir`, `None` when the platform
+    /// can't tell.
+    fn available_space(&self, dir: &Path) -> io::Result<Option<u64>>;
+
+    /// Creates and removes a file in `dir`.
+    fn probe_write(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// The filesystem of the machine.
+pub struct SystemFs;
+
+impl FsInfo for SystemFs {
+    #[cfg(unix)]
+    fn available_space(&self, dir: &Path) -> io::Result<Option<u64>> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: the path is nul-terminated and statvfs only writes into `stat`
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: initialized by the successful call
+        let stat = unsafe { stat.assume_init() };
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    #[cfg(not(unix))]
+    fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn probe_write(&self, dir: &Path) -> io::Result<()> {
+        let probe = dir.join(format!(".liushu-probe-{}", process::id()));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe)?;
+        fs::remove_file(probe)
+    }
+}
+
+/// Space needed to build outputs of `ratio` times the size of inputs of `input_bytes`.
+pub fn required_space(input_bytes: u64, ratio: f64) -> u64 {
+    (input_bytes as f64 * ratio * SAFETY_FACTOR).ceil() as u64
+}
+
+/// Total size of the inputs, those that can't be looked at count for nothing as their build
+/// will fail anyway.
+pub fn input_size<'a>(inputs: impl IntoIterator<Item = &'a PathBuf>) -> u64 {
+    inputs
+        .into_iter()
+        .filter_map(|input| fs::metadata(input).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Fails unless `dir`, which must exist, is writable and has `needed` bytes available.
+pub fn check_with(fs: &dyn FsInfo, dir: &Path, needed: u64) -> Result<(), LiushuError> {
+    fs.probe_write(dir)
+        .map_err(|_| LiushuError::NotWritable(dir.to_path_buf()))?;
+    let available = fs
+        .available_space(dir)
+        .with_path("get the free space of", dir)?;
+    debug!(dir = %dir.display(), needed, ?available, "checked free space");
+    match available {
+        Some(available) if available < needed => {
+            Err(LiushuError::InsufficientSpace { needed, available })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// [`check_with`] the filesystem of the machine, for outputs of `ratio` times the size of
+/// `inputs`.
+pub fn check<'a>(
+    dir: &Path,
+    inputs: impl IntoIterator<Item = &'a PathBuf>,
+    ratio: f64,
+) -> Result<(), LiushuError> {
+    check_with(&SystemFs, dir, required_space(input_size(inputs), ratio))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeFs {
+        available: Option<u64>,
+        writable: bool,
+    }
+
+    impl FsInfo for FakeFs {
+        fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+            Ok(self.available)
+        }
+
+        fn probe_write(&self, _dir: &Path) -> io::Result<()> {
+            if self.writable {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::PermissionDenied.into())
+            }
+        }
+    }
+
+    #[test]
+    fn test_required_space() {
+        assert_eq!(required_space(0, DEPLOY_RATIO), 0);
+        assert_eq!(required_space(1000, DEPLOY_RATIO), 6000);
+        assert_eq!(required_space(1001, TRAIN_RATIO), 751);
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = Path::new("target");
+        let fs = |available, writable| FakeFs {
+            available,
+            writable,
+        };
+
+        assert!(check_with(&fs(Some(100), true), dir, 100).is_ok());
+        assert!(check_with(&fs(None, true), dir, u64::MAX).is_ok());
+        let error = check_with(&fs(Some(99), true), dir, 100).unwrap_err();
+        assert!(matches!(
+            error,
+            LiushuError::InsufficientSpace {
+                needed: 100,
+                available: 99
+            }
+        ));
+        assert_eq!(error.exit_code(), 4);
+        let error = check_with(&fs(Some(100), false), dir, 1).unwrap_err();
+        assert!(matches!(&error, LiushuError::NotWritable(path) if path == dir));
+        assert_eq!(error.to_string(), "target is not writable");
+    }
+
+    #[test]
+    fn test_system_fs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("words.tsv");
+        fs::write(&file, "0123456789").unwrap();
+        assert_eq!(input_size([&file, &dir.path().join("missing.tsv")]), 10);
+        check(dir.path(), [&file], DEPLOY_RATIO).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        let available = SystemFs.available_space(dir.path()).unwrap();
+        assert_eq!(available.is_some(), cfg!(unix));
+    }
+}
@@ -0,0 +1,149 @@
+//! Named profiles of a user, each a dir of [`PROFILES_DIR`] in the config dir laid out by
+//! [`MyProjectDirs::from_root`], with a config, data and artifacts of its own. Nothing of a
+//! profile is ever read from another one, or from the dirs it is kept in.
+
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use crate::error::{IoResultExt, LiushuError};
+
+use super::MyProjectDirs;
+
+/// The dir of the config dir keeping the profiles.
+pub const PROFILES_DIR: &str = "profiles";
+
+/// The profiles kept in the config dir of some dirs.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    root: PathBuf,
+}
+
+impl Profiles {
+    pub fn of(dirs: &MyProjectDirs) -> Self {
+        Self {
+            root: dirs.config_dir.join(PROFILES_DIR),
+        }
+    }
+
+    /// The dir of the profile `name`, which must have been created.
+    pub fn root(&self, name: &str) -> Result<PathBuf, LiushuError> {
+        let root = self.path(name)?;
+        if !root.is_dir() {
+            return Err(LiushuError::InvalidInput(format!(
+                "unknown profile {}, `liushu profile create {}` creates it",
+                name, name
+            )));
+        }
+        Ok(root)
+    }
+
+    /// The dirs of the profile `name`, which must have been created.
+    pub fn dirs(&self, name: &str) -> Result<MyProjectDirs, LiushuError> {
+        self.root(name).map(MyProjectDirs::from_root)
+    }
+
+    /// The names of the profiles, in order.
+    pub fn list(&self) -> Result<Vec<String>, LiushuError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(LiushuError::io_at("list profiles in", &self.root, e)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.with_path("list profiles in", &self.root)?;
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            if entry.path().is_dir() && check_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Creates the dirs of the profile `name`, which mustn't exist yet.
+    pub fn create(&self, name: &str) -> Result<MyProjectDirs, LiushuError> {
+        let root = self.path(name)?;
+        if root.exists() {
+            return Err(LiushuError::InvalidInput(format!(
+                "the profile {} already exists",
+                name
+            )));
+        }
+        let dirs = MyProjectDirs::from_root(root);
+        dirs.ensure()?;
+        Ok(dirs)
+    }
+
+    /// Removes the profile `name` with everything in it.
+    pub fn remove(&self, name: &str) -> Result<(), LiushuError> {
+        let root = self.root(name)?;
+        fs::remove_dir_all(&root).with_path("remove profile", &root)
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, LiushuError> {
+        check_name(name)?;
+        Ok(self.root.join(name))
+    }
+}
+
+/// Whether `profile` names a profile rather than a dir: a name is a single component of
+/// letters, digits, `-`, `_` and `.`, that doesn't start with a `.`.
+pub fn is_name(profile: &str) -> bool {
+    check_name(profile).is_ok()
+}
+
+fn check_name(name: &str) -> Result<(), LiushuError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(LiushuError::InvalidInput(format!(
+            "invalid profile name {:?}, use letters, digits, `-`, `_` and `.`",
+            name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let root = tempfile::tempdir().unwrap();
+        let profiles = Profiles::of(&MyProjectDirs::from_root(root.path()));
+        assert!(profiles.list().unwrap().is_empty());
+        assert_eq!(profiles.dirs("work").unwrap_err().code(), "E_INVALID_INPUT");
+
+        let work = profiles.create("work").unwrap();
+        assert!(work.target_dir.is_dir());
+        assert_eq!(
+            work.config_dir,
+            root.path().join("config/profiles/work/config")
+        );
+        profiles.create("home.2").unwrap();
+        assert!(profiles.create("work").is_err());
+        // neither a dir nor a name is a profile
+        fs::write(root.path().join("config/profiles/notes"), "").unwrap();
+        fs::create_dir(root.path().join("config/profiles/.cache")).unwrap();
+        assert_eq!(profiles.list().unwrap(), ["home.2", "work"]);
+        assert_eq!(profiles.dirs("work").unwrap().data_dir, work.data_dir);
+
+        for name in ["", ".", "..", "../work", "a/b", ".hidden", "with space"] {
+            assert!(!is_name(name), "{:?}", name);
+            assert!(profiles.create(name).is_err(), "{:?}", name);
+        }
+        assert!(is_name("工作"));
+
+        profiles.remove("work").unwrap();
+        assert!(!work.config_dir.exists());
+        assert!(profiles.remove("work").is_err());
+        assert_eq!(profiles.list().unwrap(), ["home.2"]);
+    }
+}
@@ -0,0 +1,396 @@
+//! Checks of a profile for what most often goes wrong with it, run by `liushu doctor` and
+//! the `doctor` method of the server: its dirs, config, artifacts, model and user
+//! dictionary, and a search of its active formula.
+//!
+//! A database some engine has open can't be opened again, as redb allows once per file, so
+//! its checks pass without opening it, the engine having opened it fine.
+
+use std::{
+    fmt,
+    fs::File,
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    artifact::{ArtifactOpenOptions, OpenMode},
+    config::Config,
+    deploy,
+    dirs::{
+        preflight::{FsInfo, SystemFs},
+        MyProjectDirs,
+    },
+    engine::{EngineWithRedb, InputMethodEngine},
+    error::{IoResultExt, LiushuError},
+    hmm::{Model, MODEL_FILE},
+    state::SessionState,
+    userdict::USER_DICT_FILE,
+};
+
+/// A search of the active formula slower than this is warned about.
+pub const PROBE_LATENCY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    /// Something works, though not as it should, such as a stale deploy.
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct CheckResult {
+    /// Such as `config` or `artifacts:pinyin`, those of a formula ending with its id.
+    pub check: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+impl CheckResult {
+    fn new(check: impl Into<String>, status: CheckStatus, message: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn of(check: impl Into<String>, result: Result<String, LiushuError>) -> Self {
+        match result {
+            Ok(message) => Self::new(check, CheckStatus::Pass, message),
+            Err(error) if error.is_in_use() => {
+                Self::new(check, CheckStatus::Pass, "in use by an engine, not opened")
+            }
+            Err(error) => Self::new(check, CheckStatus::Fail, error.report()),
+        }
+    }
+}
+
+/// Runs every check on the profile of `dirs`, those of the formulas only when the config
+/// loads.
+pub fn run(dirs: &MyProjectDirs) -> Vec<CheckResult> {
+    let mut results = vec![
+        check_dir("dirs:config", &dirs.config_dir, true),
+        check_dir("dirs:data", &dirs.data_dir, false),
+        check_dir("dirs:target", &dirs.target_dir, false),
+    ];
+    let config = Config::load_from_path(dirs.config_dir.join("main.dhall"));
+    results.push(match &config {
+        Ok(config) => CheckResult::new(
+            "config",
+            CheckStatus::Pass,
+            format!("{} formulas", config.formulas.len()),
+        ),
+        Err(error) => CheckResult::new("config", CheckStatus::Fail, error.report()),
+    });
+    if let Ok(config) = &config {
+        // disabled ones aren't deployed
+        for formula in config.formulas.iter().filter(|f| f.is_enabled()) {
+            results.push(check_artifacts(&dirs.target_dir, &formula.id));
+            results.push(match deploy::is_stale(formula, dirs) {
+                Some(false) => CheckResult::new(
+                    format!("stale:{}", formula.id),
+                    CheckStatus::Pass,
+                    "deployed from the current sources",
+                ),
+                Some(true) => CheckResult::new(
+                    format!("stale:{}", formula.id),
+                    CheckStatus::Warn,
+                    "the sources changed since the last deploy, run liushu deploy",
+                ),
+                None => CheckResult::new(
+                    format!("stale:{}", formula.id),
+                    CheckStatus::Warn,
+                    "not deployed, or from sources that can't be read",
+                ),
+            });
+        }
+    }
+    results.push(check_model(&dirs.target_dir.join(MODEL_FILE)));
+    results.push(check_user_dict(&dirs.data_dir.join(USER_DICT_FILE)));
+    if let Ok(config) = &config {
+        results.push(check_search(dirs, config));
+    }
+    results
+}
+
+/// A missing dir that is `required` fails, the others are created once needed.
+fn check_dir(check: &str, dir: &Path, required: bool) -> CheckResult {
+    if !dir.is_dir() {
+        let status = if required {
+            CheckStatus::Fail
+        } else {
+            CheckStatus::Warn
+        };
+        return CheckResult::new(check, status, format!("{} is missing", dir.display()));
+    }
+    match SystemFs.probe_write(dir) {
+        Ok(()) => CheckResult::new(check, CheckStatus::Pass, dir.display().to_string()),
+        Err(_) => CheckResult::new(
+            check,
+            CheckStatus::Fail,
+            LiushuError::NotWritable(dir.to_path_buf()).report(),
+        ),
+    }
+}
+
+/// Verified like a deploy does, warning of texts of the trie the dictionary lacks, which
+/// searches find without a weight.
+fn check_artifacts(target_dir: &Path, formula: &str) -> CheckResult {
+    let check = format!("artifacts:{}", formula);
+    let missing = deploy::verify(target_dir, formula)
+        .and_then(|_| EngineWithRedb::with_formula(target_dir, formula)?.missing_definitions());
+    match missing {
+        Ok(0) => CheckResult::new(check, CheckStatus::Pass, "verified"),
+        Ok(missing) => CheckResult::new(
+            check,
+            CheckStatus::Warn,
+            format!(
+                "{} texts of the trie are not in the dictionary, clean and deploy again",
+                missing
+            ),
+        ),
+        Err(error) => CheckResult::of(check, Err(error)),
+    }
+}
+
+/// The model is optional, only one that doesn't open fails. Like the user dictionary it is
+/// only opened once it looks like a database, so as not to overwrite it.
+fn check_model(path: &Path) -> CheckResult {
+    if !path.exists() {
+        return CheckResult::new("hmm_model", CheckStatus::Pass, "none trained");
+    }
+    CheckResult::of(
+        "hmm_model",
+        check_redb_header(path)
+            .and_then(|_| Model::open(path))
+            .and_then(|model| model.info())
+            .map(|info| format!("order {}", info.order)),
+    )
+}
+
+/// Opened read-only, so that neither a missing dictionary is created nor a journal replayed,
+/// and only once it looks like a database.
+fn check_user_dict(path: &Path) -> CheckResult {
+    if !path.exists() {
+        return CheckResult::new(
+            "user_dict",
+            CheckStatus::Pass,
+            "none yet, the first commit creates it",
+        );
+    }
+    CheckResult::of(
+        "user_dict",
+        check_redb_header(path)
+            .and_then(|_| {
+                ArtifactOpenOptions::new(OpenMode::ReadOnly).open_redb(path, "open user dictionary")
+            })
+            .map(|_| "opened".to_string()),
+    )
+}
+
+/// The first bytes of a redb database.
+const REDB_MAGIC: [u8; 9] = [b'r', b'e', b'd', b'b', 0x1A, 0x0A, 0xA9, 0x0D, 0x0A];
+
+/// Fails unless the file at `path` starts like a redb database, without opening it: redb
+/// opens some files that aren't one as an empty database, overwriting them.
+fn check_redb_header(path: &Path) -> Result<(), LiushuError> {
+    let mut header = [0; REDB_MAGIC.len()];
+    let mut file = File::open(path).with_path("open", path)?;
+    match io::Read::read_exact(&mut file, &mut header) {
+        Ok(()) if header == REDB_MAGIC => Ok(()),
+        Ok(()) => Err(LiushuError::ArtifactCorrupt {
+            path: path.to_path_buf(),
+            source: "not a redb database".into(),
+        }),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(LiushuError::ArtifactCorrupt {
+            path: path.to_path_buf(),
+            source: "not a redb database".into(),
+        }),
+        Err(e) => Err(LiushuError::io_at("read", path, e)),
+    }
+}
+
+/// Searches the first code of the trie of the formula a server would start on, warning
+/// past [`PROBE_LATENCY`].
+fn check_search(dirs: &MyProjectDirs, config: &Config) -> CheckResult {
+    let state = SessionState::load(&dirs.data_dir);
+    let probe = || {
+        let formula = &config.initial_formula(state.active_formula.as_deref())?.id;
+        let engine = EngineWithRedb::with_formula(&dirs.target_dir, formula)?;
+        let Some(code) = engine.codes().next() else {
+            return Err(LiushuError::Other(format!("{} has no codes", formula)));
+        };
+        let start = Instant::now();
+        let candidates = engine.search(&code)?.len();
+        let elapsed = start.elapsed();
+        if candidates == 0 {
+            return Err(LiushuError::Other(format!(
+                "no candidate of {} in {}",
+                code, formula
+            )));
+        }
+        let message = format!("{} in {}: {} candidates", code, formula, candidates);
+        Ok((message, elapsed))
+    };
+    match probe() {
+        Ok((message, elapsed)) if elapsed > PROBE_LATENCY => CheckResult::new(
+            "search",
+            CheckStatus::Warn,
+            format!("{}, searched in {} ms", message, elapsed.as_millis()),
+        ),
+        result => CheckResult::of("search", result.map(|(message, _)| message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::userdict::UserDict;
+
+    fn deployed(root: &Path) -> MyProjectDirs {
+        let dirs = MyProjectDirs::from_root(root);
+        dirs.ensure().unwrap();
+        for id in ["fixture", "broken"] {
+            fs::create_dir(dirs.config_dir.join(id)).unwrap();
+            fs::write(
+                dirs.config_dir.join(id).join("words.tsv"),
+                "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n",
+            )
+            .unwrap();
+        }
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] },
+                { id = "broken", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        deploy::deploy(&config, &dirs).unwrap();
+        dirs
+    }
+
+    fn status(results: &[CheckResult], check: &str) -> CheckStatus {
+        results
+            .iter()
+            .find(|result| result.check == check)
+            .unwrap_or_else(|| panic!("no check {}", check))
+            .status
+    }
+
+    #[test]
+    fn test_healthy_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = deployed(root.path());
+        drop(UserDict::open(dirs.data_dir.join(USER_DICT_FILE)).unwrap());
+        let results = run(&dirs);
+        let checks: Vec<_> = results.iter().map(|result| result.check.as_str()).collect();
+        assert_eq!(
+            checks,
+            [
+                "dirs:config",
+                "dirs:data",
+                "dirs:target",
+                "config",
+                "artifacts:fixture",
+                "stale:fixture",
+                "artifacts:broken",
+                "stale:broken",
+                "hmm_model",
+                "user_dict",
+                "search",
+            ]
+        );
+        assert!(
+            results
+                .iter()
+                .all(|result| result.status == CheckStatus::Pass),
+            "{:?}",
+            results
+        );
+        let search = results.last().unwrap();
+        assert_eq!(search.message, "ni in fixture: 2 candidates");
+        assert_eq!(
+            serde_json::to_value(search).unwrap(),
+            serde_json::json!({
+                "check": "search",
+                "status": "pass",
+                "message": "ni in fixture: 2 candidates",
+            })
+        );
+    }
+
+    #[test]
+    fn test_broken_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = deployed(root.path());
+        fs::write(dirs.target_dir.join("broken.trie"), "not a trie").unwrap();
+        fs::write(
+            dirs.config_dir.join("fixture/words.tsv"),
+            "text\tcode\tweight\n你好\tnihao\t3\n",
+        )
+        .unwrap();
+        fs::write(dirs.target_dir.join(MODEL_FILE), "not a model").unwrap();
+        fs::write(dirs.data_dir.join(USER_DICT_FILE), "not a dictionary").unwrap();
+        SessionState::remember_formula(&dirs.data_dir, "broken");
+
+        let results = run(&dirs);
+        assert_eq!(status(&results, "config"), CheckStatus::Pass);
+        assert_eq!(status(&results, "artifacts:fixture"), CheckStatus::Pass);
+        assert_eq!(status(&results, "stale:fixture"), CheckStatus::Warn);
+        assert_eq!(status(&results, "artifacts:broken"), CheckStatus::Fail);
+        assert_eq!(status(&results, "stale:broken"), CheckStatus::Pass);
+        assert_eq!(status(&results, "hmm_model"), CheckStatus::Fail);
+        assert_eq!(status(&results, "user_dict"), CheckStatus::Fail);
+        assert_eq!(status(&results, "search"), CheckStatus::Fail);
+        // left as they were
+        assert_eq!(
+            fs::read_to_string(dirs.data_dir.join(USER_DICT_FILE)).unwrap(),
+            "not a dictionary"
+        );
+
+        // a code past those verified whose text the dictionary lacks
+        let mut trie = patricia_tree::PatriciaMap::new();
+        for (code, text) in [("ni", "你"), ("nihao", "你好"), ("zy", "你"), ("zz", "无")] {
+            trie.insert(code, vec![text.to_string()]);
+        }
+        let file = File::create(dirs.target_dir.join("fixture.trie")).unwrap();
+        bincode::serialize_into(file, &trie).unwrap();
+        let results = run(&dirs);
+        let artifacts = results
+            .iter()
+            .find(|result| result.check == "artifacts:fixture")
+            .unwrap();
+        assert_eq!(artifacts.status, CheckStatus::Warn);
+        assert_eq!(
+            artifacts.message,
+            "1 texts of the trie are not in the dictionary, clean and deploy again"
+        );
+
+        fs::write(dirs.config_dir.join("main.dhall"), "{ formulas = 1 }").unwrap();
+        fs::remove_dir_all(&dirs.target_dir).unwrap();
+        let results = run(&dirs);
+        assert_eq!(status(&results, "dirs:target"), CheckStatus::Warn);
+        assert_eq!(status(&results, "config"), CheckStatus::Fail);
+        assert!(!results.iter().any(|result| result.check == "search"));
+    }
+}
@@ -1,13 +1,250 @@
-use std::{collections::VecDeque, fs::File, path::Path};
+mod cache;
+mod fallback;
+mod filter;
+mod lazy;
+mod memory;
+mod prefix;
+mod rank;
+#[cfg(feature = "runtime")]
+mod reader;
+#[cfg(feature = "runtime")]
+mod sample;
+#[cfg(feature = "runtime")]
+mod store;
+#[cfg(feature = "runtime")]
+mod top;
+mod transform;
+mod warm;
 
+#[cfg(feature = "sqlite-engine")]
+use std::sync::{Mutex, MutexGuard};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::Read,
+    time::Duration,
+};
+#[cfg(feature = "runtime")]
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bincode::Options;
 use patricia_tree::PatriciaMap;
-use redb::{Database, ReadableTable};
+#[cfg(feature = "runtime")]
+use redb::ReadableTable;
+#[cfg(feature = "sqlite-engine")]
 use rusqlite::{params, Connection, Result as SqlResult, Row};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "runtime")]
+use tracing::warn;
+
+pub use self::cache::{CacheStats, SearchCache, DEFAULT_CAPACITY};
+pub use self::fallback::FallbackEngine;
+pub use self::filter::{BlockedPair, FilteredEngine, Filters, FiltersConfig, FILTERS_FILE};
+pub use self::lazy::LazyEngine;
+pub use self::memory::MemoryEngine;
+pub use self::prefix::{PrefixInfo, DESCENDANT_LIMIT};
+pub use self::rank::{
+    break_ties, ByCodeLength, ByWeight, Pin, Pins, RankContext, RankStage, RankedEngine, Ranker,
+    RankingPipeline, DEFAULT_STAGES,
+};
+#[cfg(feature = "runtime")]
+pub use self::reader::{ArtifactReader, Entries};
+#[cfg(feature = "runtime")]
+pub use self::sample::FLOOR_WEIGHT;
+#[cfg(feature = "runtime")]
+pub use self::store::{ArtifactStore, RedbArtifacts};
+pub use self::transform::{
+    Calculator, DateFormatter, TransformedEngine, Transformer, Transformers,
+};
+pub use self::warm::WarmUp;
+#[cfg(feature = "runtime")]
+use crate::artifact::{
+    read_redb, ArtifactOpenOptions, Provenance, DICTIONARY, PROVENANCE, SOURCES,
+};
+use crate::error::LiushuError;
+#[cfg(feature = "runtime")]
+use crate::normalize;
 
-use crate::{dict::DICTIONARY, dirs::PROJECT_DIRS, error::LiushuError};
+/// The comment of a candidate of the trie that has no definition in the dictionary, found
+/// with a weight of 0, see [`InputMethodEngine::inconsistencies`].
+pub const MISSING_DEFINITION: &str = "definition missing";
 
-pub trait InputMethodEngine {
+/// Engines are shared by the connections of a server, so they are `Send + Sync`.
+pub trait InputMethodEngine: Send + Sync {
+    /// Candidates of every code starting with `code`, codes in bytewise order and the texts
+    /// of a code in the order of the dictionary. The engines of a deployed formula find the
+    /// same ones, which `tests/parity.rs` checks.
     fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError>;
+
+    /// Like [`InputMethodEngine::search`], appending the candidates to `items`, so that a
+    /// caller searching on every keystroke can reuse its buffer.
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        items.extend(self.search(code)?);
+        Ok(())
+    }
+
+    /// The candidates of [`InputMethodEngine::search`] grouped by code, shorter codes first
+    /// and those as long in bytewise order, the texts of a code by weight, the heaviest
+    /// first and those as heavy in the order of the dictionary.
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let mut items = self.search(code)?;
+        items.sort_by(|a, b| (a.code.len(), &a.code).cmp(&(b.code.len(), &b.code)));
+        let mut groups = group_runs(items);
+        for (_, items) in &mut groups {
+            items.sort_by_key(|item| Reverse(item.weight));
+        }
+        Ok(groups)
+    }
+
+    /// Like [`InputMethodEngine::search`], with the text committed right before `code` for
+    /// the engines ranking candidates by what they follow.
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let _ = context;
+        self.search(code)
+    }
+
+    /// Codes of `text`, those engines without an index from texts to codes have none.
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        let _ = text;
+        Ok(Vec::new())
+    }
+
+    /// The codes of each of `texts`, as [`InputMethodEngine::reverse_lookup`] answers them,
+    /// for the engines that look up several texts at once faster than one by one.
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        texts.iter().map(|text| self.reverse_lookup(text)).collect()
+    }
+
+    /// Each character of `text` with its shortest code, the first in bytewise order of
+    /// those as short, or none when the engine has no code of it, such as punctuation.
+    /// A character is looked up once however many times it appears.
+    fn annotate_text(&self, text: &str) -> Result<Vec<(char, Option<String>)>, LiushuError> {
+        let mut chars: Vec<char> = text.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        let texts: Vec<String> = chars.iter().map(char::to_string).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let shortest: HashMap<char, String> = chars
+            .into_iter()
+            .zip(self.reverse_lookup_batch(&texts)?)
+            .filter_map(|(c, codes)| {
+                let code = codes
+                    .into_iter()
+                    .min_by(|a, b| (a.len(), a).cmp(&(b.len(), b)))?;
+                Some((c, code))
+            })
+            .collect();
+        Ok(text
+            .chars()
+            .map(|c| (c, shortest.get(&c).cloned()))
+            .collect())
+    }
+
+    /// Phrases longer than `committed` that start with it, for a frontend to offer once it
+    /// is committed without more code typed, the heaviest first and at most `limit` of
+    /// them. Engines without an index of the texts have none.
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let _ = (committed, limit);
+        Ok(Vec::new())
+    }
+
+    /// How the codes of the trie go on from `code`, for a frontend to hint that typing on
+    /// finds nothing more. Engines without a code trie have none.
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        let _ = code;
+        Ok(None)
+    }
+
+    /// What the engine does beyond plain searches, none of it by default.
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities::default()
+    }
+
+    /// Reads what the first searches would, for about `budget` at most, so that they don't
+    /// wait for the pages of cold artifacts. Engines in memory have nothing to warm.
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        let _ = budget;
+        Ok(WarmUp::default())
+    }
+
+    /// Candidates the searches since the engine was opened found in its trie without a
+    /// definition in its dictionary, as after a partial deploy. Only engines of two
+    /// artifacts that can disagree have any.
+    fn inconsistencies(&self) -> u64 {
+        0
+    }
+
+    /// Candidates of the longest prefix of `code` that has any, so that what follows can
+    /// be typed on once one of them is selected. Nothing matches when not even the first
+    /// character does.
+    fn search_longest(&self, code: &str, context: &str) -> Result<SearchResponse, LiushuError> {
+        let mut ends: Vec<_> = code.char_indices().skip(1).map(|(i, _)| i).collect();
+        ends.push(code.len());
+        for end in ends.into_iter().rev().filter(|&end| end > 0) {
+            let items = self.search_in_context(&code[..end], context)?;
+            if !items.is_empty() {
+                return Ok(SearchResponse {
+                    items,
+                    matched_len: end,
+                });
+            }
+        }
+        Ok(SearchResponse::default())
+    }
+}
+
+/// The runs of candidates of the same code, in the order they come in.
+fn group_runs(items: Vec<SearchResultItem>) -> Vec<(String, Vec<SearchResultItem>)> {
+    let mut groups: Vec<(String, Vec<SearchResultItem>)> = Vec::new();
+    for item in items {
+        match groups.last_mut() {
+            Some((code, run)) if *code == item.code => run.push(item),
+            _ => groups.push((item.code.clone(), vec![item])),
+        }
+    }
+    groups
+}
+
+/// What an engine does of what [`InputMethodEngine`] allows, for frontends to tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EngineCapabilities {
+    /// [`InputMethodEngine::search_in_context`] ranks by the context.
+    pub context: bool,
+    /// [`InputMethodEngine::reverse_lookup`] finds codes.
+    pub reverse_lookup: bool,
+    /// [`InputMethodEngine::associations`] finds phrases. Left out by servers older than
+    /// it.
+    #[serde(default)]
+    pub associations: bool,
+    /// [`InputMethodEngine::prefix_info`] tells of the trie. Left out by servers older
+    /// than it.
+    #[serde(default)]
+    pub prefix_info: bool,
 }
 
 pub struct EngineManager {
@@ -15,8 +252,23 @@ pub struct EngineManager {
 }
 
 impl EngineManager {
-    pub fn set_active_engine(&mut self, idx: usize) {
+    pub fn set_active_engine(&mut self, idx: usize) -> Result<(), LiushuError> {
+        if idx >= self.engines.len() {
+            return Err(LiushuError::InvalidInput(format!(
+                "no engine {}, there are {}",
+                idx,
+                self.engines.len()
+            )));
+        }
         self.engines.swap(0, idx);
+        Ok(())
+    }
+
+    fn active(&self) -> Result<&dyn InputMethodEngine, LiushuError> {
+        self.engines
+            .front()
+            .map(|engine| engine.as_ref())
+            .ok_or_else(|| LiushuError::InvalidInput("there is no engine to search".to_string()))
     }
 }
 
@@ -33,29 +285,158 @@ where
 
 impl InputMethodEngine for EngineManager {
     fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
-        self.engines[0].search(code)
+        self.active()?.search(code)
+    }
+
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        self.active()?.search_into(code, items)
+    }
+
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        self.active()?.search_grouped(code)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.active()?.search_in_context(code, context)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.active()?.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.active()?.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.active()?.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.active()?.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.active().map_or_else(
+            |_| EngineCapabilities::default(),
+            |engine| engine.capabilities(),
+        )
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.active()?.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.active().map_or(0, |engine| engine.inconsistencies())
     }
 }
 
+#[cfg(feature = "sqlite-engine")]
 #[derive(Debug)]
 pub struct ShapeCodeEngine {
-    conn: Connection,
+    /// A connection can't be shared by threads, searches take turns.
+    conn: Mutex<Connection>,
+    /// Whether the database has the normalization table of a build that normalized it,
+    /// and searches are normalized alike.
+    normalized: bool,
+    /// Whether the build tagged texts out of the charset, left out while `strict_charset`.
+    tagged: bool,
+    strict_charset: Arc<AtomicBool>,
 }
 
+#[cfg(feature = "sqlite-engine")]
 impl ShapeCodeEngine {
     pub fn new(conn: Connection) -> Self {
-        Self { conn }
+        // a connection failing this fails the searches too
+        let has_table = |name: &str| {
+            conn.query_row(
+                "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .is_ok_and(|tables| tables > 0)
+        };
+        let (normalized, tagged) = (has_table("normalization"), has_table("out_of_charset"));
+        Self {
+            conn: Mutex::new(conn),
+            normalized,
+            tagged,
+            strict_charset: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Like [`EngineWithRedb::strict_charset`].
+    pub fn strict_charset(mut self, strict: Arc<AtomicBool>) -> Self {
+        self.strict_charset = strict;
+        self
+    }
+
+    /// The condition of the searches leaving out the tagged texts, if they are.
+    fn charset_filter(&self) -> &'static str {
+        match self.tagged && self.strict_charset.load(Ordering::Relaxed) {
+            true => " AND text NOT IN (SELECT text FROM out_of_charset)",
+            false => "",
+        }
+    }
+
+    fn normalize_code<'a>(&self, code: &'a str) -> Cow<'a, str> {
+        match self.normalized {
+            true => normalize::code(code),
+            false => Cow::Borrowed(code),
+        }
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // a search that panicked leaves nothing half done in the connection
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Opens the `.db3` artifact of `formula_id` in `path`, read-only.
+    pub fn with_formula(path: impl AsRef<Path>, formula_id: &str) -> Result<Self, LiushuError> {
+        Self::with_options(path, formula_id, ArtifactOpenOptions::default())
+    }
+
+    pub fn with_options(
+        path: impl AsRef<Path>,
+        formula_id: &str,
+        options: ArtifactOpenOptions,
+    ) -> Result<Self, LiushuError> {
+        let db_path = path.as_ref().join(format!("{}.db3", formula_id));
+        Ok(Self::new(options.open_sqlite(&db_path)?))
     }
 }
 
+#[cfg(feature = "sqlite-engine")]
 impl InputMethodEngine for ShapeCodeEngine {
     fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
-        let mut stmt = self.conn.prepare_cached(
-            "SELECT * FROM (SELECT * FROM dict WHERE code LIKE ?1) GROUP BY text ORDER BY weight DESC",
-        )?;
+        let code = self.normalize_code(code);
+        let conn = self.conn();
+        // not LIKE, which would take `_` and `%` in codes for wildcards and ignore case;
+        // codes compared bytewise, in the order of the trie of the redb engine
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT text, code, weight, comment FROM dict WHERE substr(code, 1, length(?1)) = ?1{} ORDER BY code, id",
+            self.charset_filter()
+        ))?;
 
-        let code = code.to_string() + "%";
-        let rows = stmt.query_map(params![code], |row| SearchResultItem::try_from(row))?;
+        let rows = stmt.query_map(params![code], |row| exact_row(row, &code))?;
 
         let mut result = Vec::new();
         for text_result in rows {
@@ -64,70 +445,597 @@ impl InputMethodEngine for ShapeCodeEngine {
 
         Ok(result)
     }
-}
 
-impl Default for ShapeCodeEngine {
-    fn default() -> Self {
-        let db_dir = &PROJECT_DIRS.target_dir;
-        let db_path = db_dir.join("sunman.db3");
-        let conn = Connection::open(db_path).unwrap();
-        Self::new(conn)
+    /// Sorted by sqlite, each group a run of the rows.
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let code = self.normalize_code(code);
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT text, code, weight, comment FROM dict WHERE substr(code, 1, length(?1)) = ?1{} ORDER BY length(code), code, weight DESC, id",
+            self.charset_filter()
+        ))?;
+        let rows = stmt.query_map(params![code], |row| exact_row(row, &code))?;
+        Ok(group_runs(rows.collect::<SqlResult<_>>()?))
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        let text = match self.normalized {
+            true => normalize::text(text),
+            false => Cow::Borrowed(text),
+        };
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare_cached("SELECT DISTINCT code FROM dict WHERE text = ?1 ORDER BY code")?;
+        let rows = stmt.query_map(params![text], |row| row.get(0))?;
+        Ok(rows.collect::<SqlResult<_>>()?)
     }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            reverse_lookup: true,
+            ..EngineCapabilities::default()
+        }
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.warm_up_sqlite(budget)
+    }
+}
+
+/// The candidate of a row found searching `code`, exact when the code column is the code.
+#[cfg(feature = "sqlite-engine")]
+fn exact_row(row: &Row<'_>, code: &str) -> SqlResult<SearchResultItem> {
+    let item = SearchResultItem::try_from(row)?;
+    Ok(SearchResultItem {
+        is_exact: item.code == code,
+        ..item
+    })
 }
 
+/// The redb dictionary and code trie of a formula. The database stays open and the trie is
+/// read into memory, so a deploy renaming new artifacts over them changes nothing for the
+/// engine: it searches the files it opened until it is dropped.
+///
+/// Engines of the same [`RedbArtifacts`] share them, see [`ArtifactStore`].
+#[cfg(feature = "runtime")]
 pub struct EngineWithRedb {
-    db: Database,
-    trie: PatriciaMap<Vec<String>>,
+    artifacts: Arc<RedbArtifacts>,
+    annotate: bool,
+    /// Whether the candidates tagged as out of the charset are left out, shared with
+    /// whoever toggles it.
+    strict_charset: Arc<AtomicBool>,
+    descendant_limit: usize,
+    /// See [`InputMethodEngine::inconsistencies`].
+    missing: AtomicU64,
 }
 
+#[cfg(feature = "runtime")]
 impl EngineWithRedb {
     pub fn with(path: impl AsRef<Path>) -> Result<Self, LiushuError> {
-        let path = path.as_ref();
-        let db = Database::open(path.join("sunman.redb"))?;
-        let trie: PatriciaMap<Vec<String>> =
-            bincode::deserialize_from(File::open(path.join("sunman.trie"))?)?;
+        Self::with_formula(path, "sunman")
+    }
 
-        Ok(Self { db, trie })
+    /// Opens artifacts of its own, which fails while others of the process have the redb
+    /// dictionary open. Those of an [`ArtifactStore`] don't.
+    pub fn with_formula(path: impl AsRef<Path>, formula_id: &str) -> Result<Self, LiushuError> {
+        Self::with_options(path, formula_id, ArtifactOpenOptions::default())
+    }
+
+    /// Like [`EngineWithRedb::with_formula`], the dictionary opened with `options`.
+    pub fn with_options(
+        path: impl AsRef<Path>,
+        formula_id: &str,
+        options: ArtifactOpenOptions,
+    ) -> Result<Self, LiushuError> {
+        let artifacts = RedbArtifacts::open_with(path.as_ref(), formula_id, options)?;
+        Ok(Self::from_artifacts(Arc::new(artifacts)))
+    }
+
+    /// Those of every engine of redb artifacts, for a [`LazyEngine`] to tell before opening
+    /// one.
+    pub const CAPABILITIES: EngineCapabilities = EngineCapabilities {
+        context: false,
+        reverse_lookup: true,
+        associations: true,
+        prefix_info: true,
+    };
+
+    pub fn from_artifacts(artifacts: Arc<RedbArtifacts>) -> Self {
+        Self {
+            artifacts,
+            annotate: false,
+            strict_charset: Arc::new(AtomicBool::new(true)),
+            descendant_limit: DESCENDANT_LIMIT,
+            missing: AtomicU64::new(0),
+        }
+    }
+
+    /// How many longer codes [`InputMethodEngine::prefix_info`] counts at most, so that it
+    /// stays cheap for the short codes most others start with.
+    pub fn descendant_limit(mut self, limit: usize) -> Self {
+        self.descendant_limit = limit;
+        self
+    }
+
+    /// Whether candidates without a comment get one from the annotation table of the build,
+    /// each character of the code mapped to its annotation, as `艹 一` for `ag`. Characters
+    /// the table lacks are kept as typed, and a code of none of them gets no comment.
+    ///
+    /// The comments are made by searches, the artifacts are left as they are.
+    pub fn annotate(mut self, annotate: bool) -> Self {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Leaves out the candidates the build tagged as out of the charset of the formula while
+    /// `strict` is, as by default, see [`charset`](crate::charset).
+    pub fn strict_charset(mut self, strict: Arc<AtomicBool>) -> Self {
+        self.strict_charset = strict;
+        self
+    }
+
+    /// The texts searches leave out, none while not strict.
+    fn hidden(&self) -> Result<Option<&HashSet<String>>, LiushuError> {
+        if !self.strict_charset.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let hidden = self.artifacts.out_of_charset()?;
+        Ok((!hidden.is_empty()).then_some(hidden))
+    }
+
+    /// The comment `annotations` make of `code`.
+    fn annotation(annotations: &HashMap<char, String>, code: &str) -> Option<String> {
+        if !code.chars().any(|c| annotations.contains_key(&c)) {
+            return None;
+        }
+        let parts: Vec<String> = code
+            .chars()
+            .map(|c| {
+                annotations
+                    .get(&c)
+                    .cloned()
+                    .unwrap_or_else(|| c.to_string())
+            })
+            .collect();
+        Some(parts.join(" "))
+    }
+
+    pub fn artifacts(&self) -> &Arc<RedbArtifacts> {
+        &self.artifacts
+    }
+
+    /// Iterates every code of the trie in lexicographic order.
+    pub fn codes(&self) -> impl Iterator<Item = String> + '_ {
+        self.artifacts
+            .trie
+            .keys()
+            .map(|key| String::from_utf8_lossy(&key).into_owned())
+    }
+
+    /// Texts of the trie without a definition in the dictionary, those of several codes
+    /// counted once per code. Unlike [`InputMethodEngine::inconsistencies`], every one of
+    /// them is looked up.
+    pub fn missing_definitions(&self) -> Result<u64, LiushuError> {
+        let RedbArtifacts {
+            db, db_path, trie, ..
+        } = &*self.artifacts;
+        read_redb(db_path, || {
+            let tx = db.begin_read()?;
+            let dictionary = tx.open_table(DICTIONARY)?;
+            let mut missing = 0;
+            for texts in trie.values() {
+                for text in texts {
+                    if dictionary.get(text.as_str())?.is_none() {
+                        missing += 1;
+                    }
+                }
+            }
+            Ok(missing)
+        })
+    }
+
+    /// The dictionary and line `text` of `code` comes from, none when there is no such
+    /// candidate or the artifacts were built without tracking provenance.
+    pub fn provenance(&self, text: &str, code: &str) -> Result<Option<Provenance>, LiushuError> {
+        let RedbArtifacts { db, db_path, .. } = &*self.artifacts;
+        read_redb(db_path, || {
+            let tx = db.begin_read()?;
+            let provenance = match tx.open_table(PROVENANCE) {
+                Err(redb::Error::TableDoesNotExist(_)) => return Ok(None),
+                provenance => provenance?,
+            };
+            let Some(value) = provenance.get((text, code))? else {
+                return Ok(None);
+            };
+            let (source, line) = value.value();
+            let source = tx
+                .open_table(SOURCES)?
+                .get(source)?
+                .map(|source| source.value().to_string())
+                .ok_or_else(|| LiushuError::ArtifactCorrupt {
+                    path: db_path.clone(),
+                    source: format!("no dictionary {} of provenance", source).into(),
+                })?;
+            Ok(Some(Provenance { source, line }))
+        })
     }
 }
 
+#[cfg(feature = "runtime")]
 impl InputMethodEngine for EngineWithRedb {
     fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
-        let tx = self.db.begin_read()?;
-        let dictionary = tx.open_table(DICTIONARY)?;
-        Ok(self
-            .trie
-            .iter_prefix(code.as_bytes())
-            .flat_map(|(key, value)| {
-                let dictionary = &dictionary;
-                value.iter().map(move |text| {
-                    let code = String::from_utf8(key.clone()).unwrap();
-                    dictionary.get(text.as_str()).map(|a| {
-                        a.map(|v| {
-                            let (weight, comment) = v.value();
-                            SearchResultItem {
-                                code: code.clone(),
-                                text: text.clone(),
-                                weight,
-                                comment: comment.map(|c| c.to_owned()),
-                            }
+        let mut items = Vec::new();
+        self.search_into(code, &mut items)?;
+        Ok(items)
+    }
+
+    /// Nothing is appended on an error. Each text is copied once, the code of a key once
+    /// per candidate but the last, which takes the key itself.
+    ///
+    /// A text of the trie the dictionary has no definition of is a candidate still, so that
+    /// it isn't lost without a word: one of [`MISSING_DEFINITION`], warned about the first
+    /// time the engine finds any.
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        let RedbArtifacts {
+            db,
+            db_path,
+            trie,
+            trie_path,
+            ..
+        } = &*self.artifacts;
+        let code = match self.artifacts.normalized()? {
+            true => normalize::code(code),
+            false => Cow::Borrowed(code),
+        };
+        let mut keys = trie.iter_prefix(code.as_bytes()).peekable();
+        // a read transaction allocates more than the candidates of a short code do
+        if keys.peek().is_none() {
+            return Ok(());
+        }
+        let annotations = match self.annotate {
+            true => Some(self.artifacts.annotations()?),
+            false => None,
+        };
+        let hidden = self.hidden()?;
+        let start = items.len();
+        let mut missing = 0;
+        let searched = read_redb(db_path, || {
+            let tx = db.begin_read()?;
+            let dictionary = tx.open_table(DICTIONARY)?;
+            for (key, texts) in keys {
+                let is_exact = key.len() == code.len();
+                let code = String::from_utf8(key).map_err(|e| LiushuError::ArtifactCorrupt {
+                    path: trie_path.clone(),
+                    source: Box::new(e),
+                })?;
+                let first = items.len();
+                for text in texts {
+                    if hidden.is_some_and(|hidden| hidden.contains(text)) {
+                        continue;
+                    }
+                    let (weight, comment) = match dictionary.get(text.as_str())? {
+                        Some(value) => {
+                            let (weight, comment) = value.value();
+                            (weight, comment.map(|c| c.to_owned()))
+                        }
+                        None => {
+                            missing += 1;
+                            (0, Some(MISSING_DEFINITION.to_string()))
+                        }
+                    };
+                    items.push(SearchResultItem {
+                        code: String::new(),
+                        text: text.clone(),
+                        weight,
+                        comment,
+                        formula: None,
+                        user_count: None,
+                        is_exact,
+                    });
+                }
+                if let Some(annotations) = annotations {
+                    let mut annotation = None;
+                    for item in &mut items[first..] {
+                        if item.comment.as_deref().is_none_or(str::is_empty) {
+                            item.comment = annotation
+                                .get_or_insert_with(|| Self::annotation(annotations, &code))
+                                .clone();
+                        }
+                    }
+                }
+                if let Some((last, others)) = items[first..].split_last_mut() {
+                    for item in others {
+                        item.code.clone_from(&code);
+                    }
+                    last.code = code;
+                }
+            }
+            Ok(())
+        });
+        if searched.is_err() {
+            items.truncate(start);
+            return searched;
+        }
+        if missing > 0 && self.missing.fetch_add(missing, Ordering::Relaxed) == 0 {
+            warn!(
+                path = %db_path.display(),
+                code = %code,
+                missing,
+                "texts of the trie have no definition in the dictionary, redeploy the formula"
+            );
+        }
+        Ok(())
+    }
+
+    /// The trie walks the candidates of a code in a run, only the groups are sorted.
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let mut items = Vec::new();
+        self.search_into(code, &mut items)?;
+        let mut groups = group_runs(items);
+        groups.sort_by(|(a, _), (b, _)| (a.len(), a).cmp(&(b.len(), b)));
+        for (_, items) in &mut groups {
+            items.sort_by_key(|item| Reverse(item.weight));
+        }
+        Ok(groups)
+    }
+
+    /// Scans the whole trie once for all of them.
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        let normalized = self.artifacts.normalized()?;
+        let texts: Vec<Cow<'_, str>> = texts
+            .iter()
+            .map(|text| match normalized {
+                true => normalize::text(text),
+                false => Cow::Borrowed(*text),
+            })
+            .collect();
+        reverse_lookup_trie(&self.artifacts.trie, &texts)
+            .into_iter()
+            .map(|keys| {
+                keys.into_iter()
+                    .map(|key| {
+                        String::from_utf8(key).map_err(|e| LiushuError::ArtifactCorrupt {
+                            path: self.artifacts.trie_path.clone(),
+                            source: Box::new(e),
                         })
                     })
-                })
+                    .collect()
             })
-            .filter_map(|v| v.ok().flatten())
-            .collect())
+            .collect()
+    }
+
+    /// Scans the whole trie, which is only indexed by code.
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        let text = match self.artifacts.normalized()? {
+            true => normalize::text(text),
+            false => Cow::Borrowed(text),
+        };
+        let mut codes = Vec::new();
+        for (key, texts) in self.artifacts.trie.iter() {
+            if texts.iter().any(|t| *t == text) {
+                codes.push(
+                    String::from_utf8(key).map_err(|e| LiushuError::ArtifactCorrupt {
+                        path: self.artifacts.trie_path.clone(),
+                        source: Box::new(e),
+                    })?,
+                );
+            }
+        }
+        Ok(codes)
+    }
+
+    /// Walks the association index of the build, none for artifacts deployed without one.
+    /// Each phrase has the first of its codes in the dictionaries.
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let RedbArtifacts {
+            db,
+            db_path,
+            assoc,
+            assoc_path,
+            ..
+        } = &*self.artifacts;
+        let Some(assoc) = assoc else {
+            return Ok(Vec::new());
+        };
+        let committed = match self.artifacts.normalized()? {
+            true => normalize::text(committed),
+            false => Cow::Borrowed(committed),
+        };
+        let mut phrases = assoc
+            .iter_prefix(committed.as_bytes())
+            .filter(|(text, _)| text.len() > committed.len())
+            .peekable();
+        if committed.is_empty() || phrases.peek().is_none() {
+            return Ok(Vec::new());
+        }
+        let hidden = self.hidden()?;
+        let mut items = read_redb(db_path, || {
+            let tx = db.begin_read()?;
+            let dictionary = tx.open_table(DICTIONARY)?;
+            let mut items = Vec::new();
+            for (text, codes) in phrases {
+                let text = String::from_utf8(text).map_err(|e| LiushuError::ArtifactCorrupt {
+                    path: assoc_path.clone(),
+                    source: Box::new(e),
+                })?;
+                if hidden.is_some_and(|hidden| hidden.contains(&text)) {
+                    continue;
+                }
+                let (Some(code), Some(value)) = (codes.first(), dictionary.get(text.as_str())?)
+                else {
+                    continue;
+                };
+                let (weight, comment) = value.value();
+                items.push(SearchResultItem {
+                    text,
+                    code: code.clone(),
+                    weight,
+                    comment: comment.map(|c| c.to_owned()),
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                });
+            }
+            Ok(items)
+        })?;
+        // stable, the phrases as heavy in bytewise order
+        items.sort_by_key(|item| Reverse(item.weight));
+        items.truncate(limit);
+        Ok(items)
+    }
+
+    /// Read off the nodes of the trie, none of the definitions.
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        let code = match self.artifacts.normalized()? {
+            true => normalize::code(code),
+            false => Cow::Borrowed(code),
+        };
+        let trie = &self.artifacts.trie;
+        Ok(Some(prefix::prefix_info(
+            trie,
+            &code,
+            self.descendant_limit,
+        )))
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        Self::CAPABILITIES
     }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.warm_up_redb(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.missing.load(Ordering::Relaxed)
+    }
+}
+
+/// The keys of `trie` with each of `texts` among their texts, in bytewise order, walking it
+/// once.
+fn reverse_lookup_trie(
+    trie: &PatriciaMap<Vec<String>>,
+    texts: &[Cow<'_, str>],
+) -> Vec<Vec<Vec<u8>>> {
+    let mut indices: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, text) in texts.iter().enumerate() {
+        indices.entry(text).or_default().push(i);
+    }
+    let mut keys = vec![Vec::new(); texts.len()];
+    for (key, candidates) in trie.iter() {
+        for candidate in candidates {
+            for &i in indices.get(candidate.as_str()).into_iter().flatten() {
+                keys[i].push(key.clone());
+            }
+        }
+    }
+    keys
 }
 
-#[derive(Debug, PartialEq)]
+/// Reads a trie written by `bincode::serialize_into`, with a limit so that a garbage length
+/// can't allocate more than the `size` bytes there are.
+fn decode_trie(reader: impl Read, size: u64) -> bincode::Result<PatriciaMap<Vec<String>>> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(size)
+        .deserialize_from(reader)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SearchResultItem {
     pub text: String,
     pub code: String,
     pub weight: u64,
     pub comment: Option<String>,
+    /// The fallback formula the candidate comes from, see [`FallbackEngine`], none for
+    /// those of the formula searched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+    /// Times the user committed it, attached by the frontend from the
+    /// user dictionary, none for a candidate never committed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_count: Option<u64>,
+    /// Whether its code is the code searched, once normalized, rather than a longer one it
+    /// starts. Never for the candidates made of the code rather than found under it, such
+    /// as those of the [`Transformers`] or the sentences of the model, and left out when
+    /// it isn't.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_exact: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[cfg(feature = "runtime")]
+impl From<crate::artifact::DictItem> for SearchResultItem {
+    fn from(item: crate::artifact::DictItem) -> Self {
+        let crate::artifact::DictItem {
+            text,
+            code,
+            weight,
+            comment,
+        } = item;
+        Self {
+            text,
+            code,
+            weight,
+            comment,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        }
+    }
 }
 
+/// `你好 [nihao] (1)`, or `你好 [nihao] w=1 u=3` with the times the user committed it,
+/// followed by the comment when there is one, then by the fallback formula it comes from,
+/// `(from pinyin)`.
+impl fmt::Display for SearchResultItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.user_count {
+            Some(count) => write!(
+                f,
+                "{} [{}] w={} u={}",
+                self.text, self.code, self.weight, count
+            )?,
+            None => write!(f, "{} [{}] ({})", self.text, self.code, self.weight)?,
+        }
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+        if let Some(formula) = &self.formula {
+            write!(f, " (from {})", formula)?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`InputMethodEngine::search_longest`] found.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct SearchResponse {
+    pub items: Vec<SearchResultItem>,
+    /// Bytes of the code the items are candidates of.
+    pub matched_len: usize,
+}
+
+#[cfg(feature = "sqlite-engine")]
 impl TryFrom<&Row<'_>> for SearchResultItem {
     type Error = rusqlite::Error;
 
@@ -137,15 +1045,68 @@ impl TryFrom<&Row<'_>> for SearchResultItem {
             code: row.get("code")?,
             weight: row.get("weight")?,
             comment: row.get("comment").ok(),
+            formula: None,
+            user_count: None,
+            is_exact: false,
         })
     }
 }
 
-#[cfg(test)]
+/// Side-by-side view of two result lists for the same query.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Comparison {
+    /// Candidate texts at each rank, `None` where one side has run out of candidates.
+    pub rows: Vec<(Option<String>, Option<String>)>,
+    pub only_left: Vec<String>,
+    pub only_right: Vec<String>,
+}
+
+impl Comparison {
+    pub fn is_identical(&self) -> bool {
+        self.rows.iter().all(|(left, right)| left == right)
+    }
+}
+
+pub fn compare_results(left: &[SearchResultItem], right: &[SearchResultItem]) -> Comparison {
+    let texts = |items: &[SearchResultItem]| -> Vec<String> {
+        items.iter().map(|item| item.text.clone()).collect()
+    };
+    let (left, right) = (texts(left), texts(right));
+
+    let rows = (0..left.len().max(right.len()))
+        .map(|i| (left.get(i).cloned(), right.get(i).cloned()))
+        .collect();
+    let only_left = left
+        .iter()
+        .filter(|t| !right.contains(t))
+        .cloned()
+        .collect();
+    let only_right = right
+        .iter()
+        .filter(|t| !left.contains(t))
+        .cloned()
+        .collect();
+
+    Comparison {
+        rows,
+        only_left,
+        only_right,
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
 mod tests {
+    use std::fs::{self, File};
+
+    use redb::Database;
+
     use rusqlite::{params, Connection};
 
-    use crate::dict::CREATE_DICT_TABLE_SQL;
+    use crate::{
+        dict::{build, BuildOptions, CREATE_DICT_TABLE_SQL},
+        progress::NoProgress,
+        snapshot,
+    };
 
     use super::*;
 
@@ -169,12 +1130,388 @@ mod tests {
                 code: "ni hao".to_string(),
                 weight: 1,
                 comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: true,
             }]
         );
+        assert!(!engine.search("ni").unwrap()[0].is_exact);
 
         let not_found = engine.search("hello");
         assert!(not_found.is_ok());
         assert_eq!(not_found.unwrap(), Vec::new());
+
+        assert_eq!(engine.reverse_lookup("你好").unwrap(), ["ni hao"]);
+        assert!(engine.reverse_lookup("再见").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_grouped() {
+        let rows = [
+            ("你", "ni", 2),
+            ("尼", "ni", 9),
+            ("泥", "ni", 2),
+            ("逆", "nic", 1),
+            ("拟", "nic", 4),
+            ("妮", "niab", 5),
+            ("好", "hao", 8),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let mut words = String::from("text\tcode\tweight\tcomment\n");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(CREATE_DICT_TABLE_SQL, ()).unwrap();
+        for (text, code, weight) in rows {
+            words.push_str(&format!("{}\t{}\t{}\t\n", text, code, weight));
+            conn.execute(
+                "INSERT INTO dict (text, code, weight) VALUES (?1, ?2, ?3)",
+                params![text, code, weight],
+            )
+            .unwrap();
+        }
+        let path = dir.path().join("words.tsv");
+        fs::write(&path, words).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[path], dir.path(), "grouped", options, &NoProgress).unwrap();
+
+        let redb = EngineWithRedb::with_formula(&dir, "grouped").unwrap();
+        let sqlite = ShapeCodeEngine::new(conn);
+        let memory = MemoryEngine::from_redb(&redb).unwrap();
+        let engines: [&dyn InputMethodEngine; 3] = [&redb, &sqlite, &memory];
+        for engine in engines {
+            let groups: Vec<(String, Vec<String>)> = engine
+                .search_grouped("ni")
+                .unwrap()
+                .into_iter()
+                .map(|(code, items)| {
+                    assert!(items.iter().all(|item| item.code == code));
+                    (code, items.into_iter().map(|item| item.text).collect())
+                })
+                .collect();
+            // shorter codes first, heavier texts first and the others as in the dictionary
+            let group = |code: &str, texts: &[&str]| {
+                let texts = texts.iter().map(|text| text.to_string()).collect();
+                (code.to_string(), texts)
+            };
+            assert_eq!(
+                groups,
+                [
+                    group("ni", &["尼", "你", "泥"]),
+                    group("nic", &["拟", "逆"]),
+                    group("niab", &["妮"]),
+                ]
+            );
+            assert!(engine.search_grouped("x").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_is_exact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.tsv");
+        fs::write(&path, "text\tcode\tweight\n你\tni\t1\n你好\tnihao\t2\n").unwrap();
+        build(
+            &[path],
+            dir.path(),
+            "exact",
+            BuildOptions::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(CREATE_DICT_TABLE_SQL, ()).unwrap();
+        for (text, code) in [("你", "ni"), ("你好", "nihao")] {
+            conn.execute(
+                "INSERT INTO dict (text, code, weight) VALUES (?1, ?2, 1)",
+                params![text, code],
+            )
+            .unwrap();
+        }
+
+        let redb = EngineWithRedb::with_formula(&dir, "exact").unwrap();
+        let sqlite = ShapeCodeEngine::new(conn);
+        let memory = MemoryEngine::from_redb(&redb).unwrap();
+        let exact = |engine: &dyn InputMethodEngine, code: &str| -> Vec<(String, bool)> {
+            let items = engine.search(code).unwrap();
+            items
+                .into_iter()
+                .map(|item| (item.text, item.is_exact))
+                .collect()
+        };
+        let engines: [&dyn InputMethodEngine; 3] = [&redb, &sqlite, &memory];
+        for engine in engines {
+            assert_eq!(
+                exact(engine, "ni"),
+                [("你".to_string(), true), ("你好".to_string(), false)]
+            );
+            assert_eq!(exact(engine, "nihao"), [("你好".to_string(), true)]);
+        }
+        // exact once normalized, like the entries of the build
+        for engine in [&redb as &dyn InputMethodEngine, &memory] {
+            assert_eq!(exact(engine, "ＮＩ")[0], ("你".to_string(), true));
+        }
+    }
+
+    #[test]
+    fn test_annotate_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words.tsv");
+        let words = [
+            ("把", "bv"),
+            ("把", "b"),
+            ("手", "sf"),
+            ("手", "ss"),
+            ("把手", "bs"),
+        ];
+        let mut tsv = String::from("text\tcode\tweight\n");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(CREATE_DICT_TABLE_SQL, ()).unwrap();
+        for (text, code) in words {
+            tsv.push_str(&format!("{}\t{}\t1\n", text, code));
+            conn.execute(
+                "INSERT INTO dict (text, code, weight) VALUES (?1, ?2, 1)",
+                params![text, code],
+            )
+            .unwrap();
+        }
+        fs::write(&path, tsv).unwrap();
+        build(
+            &[path],
+            dir.path(),
+            "shapes",
+            BuildOptions::default(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let redb = EngineWithRedb::with_formula(&dir, "shapes").unwrap();
+        let memory = MemoryEngine::from_redb(&redb).unwrap();
+        let sqlite = ShapeCodeEngine::new(conn);
+        let engines: [&dyn InputMethodEngine; 3] = [&redb, &memory, &sqlite];
+        for engine in engines {
+            // punctuation and characters of no entry are kept without a code
+            let annotated = engine.annotate_text("把手，抬把").unwrap();
+            let expected = [
+                ('把', Some("b")),
+                ('手', Some("sf")),
+                ('，', None),
+                ('抬', None),
+                ('把', Some("b")),
+            ];
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|(c, code)| (c, code.map(str::to_string)))
+                .collect();
+            assert_eq!(annotated, expected);
+            let texts = ["手", "抬", "把手", "手"];
+            let one_by_one: Vec<_> = texts
+                .iter()
+                .map(|text| engine.reverse_lookup(text).unwrap())
+                .collect();
+            assert_eq!(engine.reverse_lookup_batch(&texts).unwrap(), one_by_one);
+            assert!(engine.annotate_text("").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_compare_results() {
+        let item = |text: &str| SearchResultItem {
+            text: text.to_string(),
+            code: "a".to_string(),
+            weight: 0,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        };
+
+        let same = compare_results(&[item("一"), item("二")], &[item("一"), item("二")]);
+        assert!(same.is_identical());
+        assert!(same.only_left.is_empty() && same.only_right.is_empty());
+
+        let reordered = compare_results(&[item("一"), item("二")], &[item("二"), item("一")]);
+        assert!(!reordered.is_identical());
+        assert!(reordered.only_left.is_empty() && reordered.only_right.is_empty());
+
+        let different = compare_results(&[item("一"), item("二")], &[item("一")]);
+        assert_eq!(
+            different.rows,
+            vec![
+                (Some("一".to_string()), Some("一".to_string())),
+                (Some("二".to_string()), None),
+            ]
+        );
+        assert_eq!(different.only_left, vec!["二".to_string()]);
+        assert!(different.only_right.is_empty());
+    }
+
+    #[test]
+    fn test_missing_sqlite_artifact() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            ShapeCodeEngine::with_formula(&dir, "nothing"),
+            Err(LiushuError::ArtifactMissing(_))
+        ));
+        assert!(!dir.path().join("nothing.db3").exists());
+        assert!(matches!(
+            EngineWithRedb::with_formula(&dir, "nothing"),
+            Err(LiushuError::ArtifactMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_trie() {
+        let dir = tempfile::tempdir().unwrap();
+        Database::create(dir.path().join("broken.redb")).unwrap();
+        std::fs::write(dir.path().join("broken.trie"), [0xff; 3]).unwrap();
+        let error = EngineWithRedb::with_formula(&dir, "broken").err().unwrap();
+        assert!(
+            matches!(&error, LiushuError::ArtifactCorrupt { path, .. } if path.ends_with("broken.trie"))
+        );
+        assert_eq!(error.exit_code(), 5);
+    }
+
+    #[test]
+    fn test_missing_definition() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        fs::write(&words, "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n").unwrap();
+        build(
+            &[words],
+            dir.path(),
+            "partial",
+            BuildOptions::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        // the trie of one deploy with the dictionary of another
+        let db = Database::open(dir.path().join("partial.redb")).unwrap();
+        let tx = db.begin_write().unwrap();
+        tx.open_table(DICTIONARY).unwrap().remove("你好").unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let engine = EngineWithRedb::with_formula(&dir, "partial").unwrap();
+        assert_eq!(engine.inconsistencies(), 0);
+        let results = engine.search("ni").unwrap();
+        let candidates: Vec<_> = results
+            .iter()
+            .map(|item| (item.text.as_str(), item.weight, item.comment.as_deref()))
+            .collect();
+        assert_eq!(
+            candidates,
+            [("你", 1, None), ("你好", 0, Some(MISSING_DEFINITION))]
+        );
+        assert_eq!(engine.inconsistencies(), 1);
+        // counted by each search
+        engine.search("nihao").unwrap();
+        engine.search("ni").unwrap();
+        assert_eq!(engine.inconsistencies(), 3);
+        assert_eq!(engine.missing_definitions().unwrap(), 1);
+
+        let manager = EngineManager::from([Box::new(engine) as Box<dyn InputMethodEngine>]);
+        assert_eq!(manager.inconsistencies(), 3);
+    }
+
+    /// Bytes of a xorshift generator, so the garbage is the same on every run.
+    fn garbage(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_malformed_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let artifact = |id: &str, extension: &str| dir.path().join(format!("{}.{}", id, extension));
+        let words = dir.path().join("words.tsv");
+        fs::write(&words, "text\tcode\tweight\n你好\tnau\t2\n你\tni\t1\n").unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[words], dir.path(), "ok", options, &NoProgress).unwrap();
+
+        let engine = EngineWithRedb::with_formula(&dir, "ok").unwrap();
+        for seed in 1..=64 {
+            let code = garbage(seed, seed as usize % 12);
+            assert!(engine.search(&String::from_utf8_lossy(&code)).is_ok());
+        }
+        drop(engine);
+
+        fs::copy(artifact("ok", "redb"), artifact("garbage", "redb")).unwrap();
+        for seed in 1..=64 {
+            fs::write(
+                artifact("garbage", "trie"),
+                garbage(seed, seed as usize * 7 % 256),
+            )
+            .unwrap();
+            if let Ok(engine) = EngineWithRedb::with_formula(&dir, "garbage") {
+                let _ = engine.search("n");
+            }
+        }
+
+        let mut trie = PatriciaMap::new();
+        trie.insert(b"n\xff", vec!["你".to_string()]);
+        let file = File::create(artifact("garbage", "trie")).unwrap();
+        bincode::serialize_into(file, &trie).unwrap();
+        let engine = EngineWithRedb::with_formula(&dir, "garbage").unwrap();
+        let error = engine.search("n").unwrap_err();
+        assert!(
+            matches!(&error, LiushuError::ArtifactCorrupt { path, .. } if path.ends_with("garbage.trie"))
+        );
+        let mut items = EngineWithRedb::with_formula(&dir, "ok")
+            .unwrap()
+            .search("n")
+            .unwrap();
+        assert!(engine.search_into("n", &mut items).is_err());
+        assert_eq!(items.len(), 2);
+        drop(engine);
+
+        let db = fs::read(artifact("ok", "redb")).unwrap();
+        fs::copy(artifact("ok", "trie"), artifact("truncated", "trie")).unwrap();
+        for len in [0, 1, 64, db.len() / 2, db.len() - 1] {
+            fs::write(artifact("truncated", "redb"), &db[..len]).unwrap();
+            if let Ok(engine) = EngineWithRedb::with_formula(&dir, "truncated") {
+                let _ = engine.search("n");
+            }
+        }
+
+        for (seed, len) in [(1, 0), (2, 100), (3, 4096)] {
+            fs::write(artifact("garbage", "db3"), garbage(seed, len)).unwrap();
+            let engine = ShapeCodeEngine::with_formula(&dir, "garbage").unwrap();
+            assert!(engine.search("n").is_err());
+        }
+    }
+
+    /// An input the fuzzer found redb 0.13 to panic on while searching, see `fuzz/`.
+    #[test]
+    fn test_redb_panic() {
+        let data = fs::read("../fuzz/corpus/open_artifacts/regression-redb-panic").unwrap();
+        let len = usize::from(u16::from_le_bytes([data[0], data[1]]));
+        let (trie, redb) = data[2..].split_at(len);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("fuzz.trie"), trie).unwrap();
+        fs::write(dir.path().join("fuzz.redb"), redb).unwrap();
+        let engine = EngineWithRedb::with_formula(&dir, "fuzz").unwrap();
+        let errors: Vec<_> = engine
+            .codes()
+            .filter_map(|code| engine.search(&code).err())
+            .collect();
+        assert!(!errors.is_empty());
+        for error in errors {
+            assert!(
+                matches!(&error, LiushuError::ArtifactCorrupt { path, .. } if path.ends_with("fuzz.redb"))
+            );
+        }
     }
 
     #[test]
@@ -199,7 +1536,162 @@ mod tests {
 
         assert!(engine.search("hello").is_ok());
 
-        engine.set_active_engine(1);
+        engine.set_active_engine(1).unwrap();
         assert!(engine.search("hello").is_err());
+        assert!(engine.set_active_engine(2).is_err());
+        let empty = EngineManager::from(Vec::<Box<dyn InputMethodEngine>>::new());
+        assert!(empty.search("hello").is_err());
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let response = SearchResponse {
+            items: vec![
+                SearchResultItem {
+                    text: "你".to_string(),
+                    code: "ni".to_string(),
+                    weight: 2,
+                    comment: Some("〔亻尔〕".to_string()),
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                },
+                SearchResultItem {
+                    text: "你好".to_string(),
+                    code: "ni hao".to_string(),
+                    weight: 1,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                },
+            ],
+            matched_len: 2,
+        };
+        snapshot::assert_snapshot("search_response", &response);
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SearchResponse>(&json).unwrap(),
+            response
+        );
+
+        assert_eq!(response.items[0].to_string(), "你 [ni] (2) 〔亻尔〕");
+        assert_eq!(response.items[1].to_string(), "你好 [ni hao] (1)");
+    }
+
+    #[test]
+    fn test_annotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        fs::write(
+            &words,
+            "text\tcode\tweight\tcomment\n\
+             草\tag\t3\t\n\
+             苦\tag\t2\t〔艹古〕\n\
+             蓝\tagx\t1\t\n\
+             喵\txy\t1\t\n",
+        )
+        .unwrap();
+        let annotation = dir.path().join("radicals.tsv");
+        fs::write(
+            &annotation,
+            "code\tannotation\n# the later row wins\na\t木\na\t艹\ng\t一\n",
+        )
+        .unwrap();
+        let options = BuildOptions {
+            force: true,
+            annotation: Some(annotation.clone()),
+            ..Default::default()
+        };
+        build(
+            std::slice::from_ref(&words),
+            dir.path(),
+            "annotated",
+            options,
+            &NoProgress,
+        )
+        .unwrap();
+        let artifacts = Arc::new(RedbArtifacts::open(dir.path(), "annotated").unwrap());
+        let comments = |engine: &EngineWithRedb, code: &str| -> Vec<Option<String>> {
+            let items = engine.search(code).unwrap();
+            items.into_iter().map(|item| item.comment).collect()
+        };
+        let some = |comment: &str| Some(comment.to_string());
+
+        let annotated = EngineWithRedb::from_artifacts(artifacts.clone()).annotate(true);
+        assert_eq!(
+            comments(&annotated, "ag"),
+            [some("艹 一"), some("〔艹古〕"), some("艹 一 x")]
+        );
+        // none of the code is in the table
+        assert_eq!(comments(&annotated, "xy"), [None]);
+        // nor are the comments stored
+        let plain = EngineWithRedb::from_artifacts(artifacts);
+        assert_eq!(comments(&plain, "ag"), [None, some("〔艹古〕"), None]);
+
+        fs::write(&annotation, "code\tannotation\nab\t艹\n").unwrap();
+        let options = BuildOptions {
+            force: true,
+            annotation: Some(annotation.clone()),
+            ..Default::default()
+        };
+        let error = build(&[words], dir.path(), "bad", options, &NoProgress).unwrap_err();
+        assert_eq!(error.to_string(), format!("{}:2", annotation.display()));
+    }
+
+    #[test]
+    fn test_associations() {
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        fs::write(
+            &words,
+            "text\tcode\tweight\n\
+             你\tni\t9\n\
+             你好\tnihao\t3\n\
+             你好\tnhao\t3\n\
+             你们\tnimen\t5\n\
+             你好吗\tnihaoma\t1\n\
+             好\thao\t2\n\
+             尼姑\tnigu\t4\n",
+        )
+        .unwrap();
+        build(
+            std::slice::from_ref(&words),
+            dir.path(),
+            "fixture",
+            BuildOptions::default(),
+            &NoProgress,
+        )
+        .unwrap();
+        let engine = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+        let associations = |committed: &str, limit: usize| -> Vec<(String, String)> {
+            let items = engine.associations(committed, limit).unwrap();
+            items
+                .into_iter()
+                .map(|item| (item.text, item.code))
+                .collect()
+        };
+        let pair = |text: &str, code: &str| (text.to_string(), code.to_string());
+
+        // the heaviest first, each with its first code
+        assert_eq!(
+            associations("你", 10),
+            [
+                pair("你们", "nimen"),
+                pair("你好", "nihao"),
+                pair("你好吗", "nihaoma")
+            ]
+        );
+        assert_eq!(associations("你", 1), [pair("你们", "nimen")]);
+        assert_eq!(associations("你好", 10), [pair("你好吗", "nihaoma")]);
+        assert!(associations("好", 10).is_empty());
+        assert!(associations("", 10).is_empty());
+        assert!(engine.capabilities().associations);
+
+        // deployed before builds wrote the index
+        fs::remove_file(dir.path().join("fixture.assoc")).unwrap();
+        drop(engine);
+        let engine = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+        assert!(engine.associations("你", 10).unwrap().is_empty());
     }
 }
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+/// Searches a [`SearchCache`] keeps when not told otherwise, enough for the prefixes of
+/// what is being typed.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// How a [`SearchCache`] fared since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Searches cached now.
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    formula: String,
+    code: String,
+    /// `None` for [`InputMethodEngine::search`].
+    context: Option<String>,
+}
+
+/// The searches used last, each with the tick it was used at.
+#[derive(Debug, Default)]
+struct Lru {
+    tick: u64,
+    entries: HashMap<Key, (u64, Vec<SearchResultItem>)>,
+    by_tick: BTreeMap<u64, Key>,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key) -> Option<Vec<SearchResultItem>> {
+        let (tick, items) = self.entries.get_mut(key)?;
+        self.by_tick.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.by_tick.insert(self.tick, key.clone());
+        Some(items.clone())
+    }
+
+    fn insert(&mut self, key: Key, items: Vec<SearchResultItem>, capacity: usize) {
+        self.tick += 1;
+        if let Some((tick, _)) = self.entries.insert(key.clone(), (self.tick, items)) {
+            self.by_tick.remove(&tick);
+        }
+        self.by_tick.insert(self.tick, key);
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.by_tick.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&Key) -> bool) {
+        self.entries.retain(|key, _| keep(key));
+        self.by_tick.retain(|_, key| keep(key));
+    }
+}
+
+/// An engine answering the searches it answered lately from memory, as typing searches the
+/// same prefixes over and over.
+///
+/// Entries are keyed by the formula of the engine, the code and the context searched. They
+/// stay valid until the engine is replaced, see [`SearchCache::set_inner`], or until
+/// something it ranks by changes, which [`SearchCache::invalidate`] is for.
+pub struct SearchCache<E> {
+    inner: E,
+    formula: String,
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<E: InputMethodEngine> SearchCache<E> {
+    /// Caches at most `capacity` searches of `inner`, the engine of `formula`. Nothing is
+    /// cached with a `capacity` of 0.
+    pub fn new(inner: E, formula: &str, capacity: usize) -> Self {
+        Self {
+            inner,
+            formula: formula.to_string(),
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    /// The engine to change, such as by reopening its artifacts. What it answered for its
+    /// formula is forgotten.
+    pub fn inner_mut(&mut self) -> &mut E {
+        let formula = &self.formula;
+        self.lru().retain(|key| key.formula != *formula);
+        &mut self.inner
+    }
+
+    /// Searches `inner`, the engine of `formula`, from now on. The searches of other
+    /// formulas are kept, those of `formula` may have been answered by other artifacts.
+    pub fn set_inner(&mut self, formula: &str, inner: E) {
+        self.inner = inner;
+        self.formula = formula.to_string();
+        self.lru().retain(|key| key.formula != formula);
+    }
+
+    /// Forgets every search.
+    pub fn invalidate(&self) {
+        self.lru().retain(|_| false);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lru().entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    // an entry is either inserted whole or not, a panic can't leave one half done
+    fn lru(&self) -> MutexGuard<'_, Lru> {
+        self.lru
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The items of `key`, searched with `search` on a miss. The lock isn't held while
+    /// searching, so searches of the same code may run together and cache the same items.
+    fn cached(
+        &self,
+        code: &str,
+        context: Option<&str>,
+        search: impl FnOnce() -> Result<Vec<SearchResultItem>, LiushuError>,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        if self.capacity == 0 {
+            return search();
+        }
+        let key = Key {
+            formula: self.formula.clone(),
+            code: code.to_string(),
+            context: context.map(String::from),
+        };
+        if let Some(items) = self.lru().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(items);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let items = search()?;
+        self.lru().insert(key, items.clone(), self.capacity);
+        Ok(items)
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for SearchCache<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.cached(code, None, || self.inner.search(code))
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.cached(code, Some(context), || {
+            self.inner.search_in_context(code, context)
+        })
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// Answers `code` with the number of searches it ran so far.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl InputMethodEngine for Counting {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            if code.is_empty() {
+                return Err(LiushuError::InvalidInput("empty code".to_string()));
+            }
+            Ok(vec![SearchResultItem {
+                text: self.0.fetch_add(1, Ordering::SeqCst).to_string(),
+                code: code.to_string(),
+                weight: 0,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            }])
+        }
+    }
+
+    fn text(engine: &impl InputMethodEngine, code: &str) -> String {
+        engine.search(code).unwrap().remove(0).text
+    }
+
+    #[test]
+    fn test_hits() {
+        let cache = SearchCache::new(Counting::default(), "fixture", 2);
+        assert_eq!(text(&cache, "a"), "0");
+        assert_eq!(text(&cache, "a"), "0");
+        // contexts and plain searches are apart
+        let in_context = cache.search_in_context("a", "你").unwrap();
+        assert_eq!(in_context[0].text, "1");
+        assert_eq!(cache.search_in_context("a", "你").unwrap(), in_context);
+        assert!(cache.search("").is_err());
+        assert!(cache.search("").is_err());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                entries: 2,
+                capacity: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = SearchCache::new(Counting::default(), "fixture", 2);
+        text(&cache, "a");
+        text(&cache, "b");
+        // "a" is used after "b", so "b" goes first
+        text(&cache, "a");
+        text(&cache, "c");
+        assert_eq!(text(&cache, "a"), "0");
+        assert_eq!(text(&cache, "b"), "3");
+        assert_eq!(cache.stats().entries, 2);
+
+        let uncached = SearchCache::new(Counting::default(), "fixture", 0);
+        text(&uncached, "a");
+        assert_eq!(text(&uncached, "a"), "1");
+        assert_eq!(uncached.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut cache = SearchCache::new(Counting::default(), "fixture", 8);
+        text(&cache, "a");
+        cache.invalidate();
+        assert_eq!(text(&cache, "a"), "1");
+
+        // only the searches of the formula replaced are forgotten
+        cache.set_inner("other", Counting::default());
+        assert_eq!(text(&cache, "a"), "0");
+        cache.set_inner("fixture", Counting::default());
+        assert_eq!(text(&cache, "a"), "0");
+        cache.set_inner("other", Counting::default());
+        cache.inner_mut();
+        assert_eq!(text(&cache, "a"), "0");
+        cache.set_inner("fixture", Counting::default());
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
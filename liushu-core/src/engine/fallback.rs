@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+/// An engine whose searches without a candidate are searched in the engines of other
+/// formulas, the `fallbacks` of the config, in order until one of them has some, which are
+/// tagged with the [`formula`](SearchResultItem::formula) they come from. The fallback
+/// engines are best left [lazy](super::LazyEngine), most searches never get to them.
+pub struct FallbackEngine<E> {
+    inner: E,
+    /// Each with the id of its formula.
+    fallbacks: Vec<(String, Box<dyn InputMethodEngine>)>,
+}
+
+impl<E: InputMethodEngine> FallbackEngine<E> {
+    pub fn new(inner: E, fallbacks: Vec<(String, Box<dyn InputMethodEngine>)>) -> Self {
+        Self { inner, fallbacks }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    /// The ids of the fallback formulas, in the order they are searched.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &str> {
+        self.fallbacks.iter().map(|(formula, _)| formula.as_str())
+    }
+
+    /// The items of `search` in the first of the fallbacks that has some, tagged.
+    fn fall_back(
+        &self,
+        search: impl Fn(&dyn InputMethodEngine) -> Result<Vec<SearchResultItem>, LiushuError>,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        for (formula, engine) in &self.fallbacks {
+            let mut items = search(engine.as_ref())?;
+            if !items.is_empty() {
+                for item in &mut items {
+                    item.formula = Some(formula.clone());
+                }
+                return Ok(items);
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for FallbackEngine<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let items = self.inner.search(code)?;
+        if !items.is_empty() {
+            return Ok(items);
+        }
+        self.fall_back(|engine| engine.search(code))
+    }
+
+    /// Falls back only when the inner engine appends nothing.
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        let start = items.len();
+        self.inner.search_into(code, items)?;
+        if items.len() == start {
+            items.extend(self.fall_back(|engine| engine.search(code))?);
+        }
+        Ok(())
+    }
+
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let groups = self.inner.search_grouped(code)?;
+        if !groups.is_empty() {
+            return Ok(groups);
+        }
+        for (formula, engine) in &self.fallbacks {
+            let mut groups = engine.search_grouped(code)?;
+            if !groups.is_empty() {
+                for item in groups.iter_mut().flat_map(|(_, items)| items) {
+                    item.formula = Some(formula.clone());
+                }
+                return Ok(groups);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let items = self.inner.search_in_context(code, context)?;
+        if !items.is_empty() {
+            return Ok(items);
+        }
+        self.fall_back(|engine| engine.search_in_context(code, context))
+    }
+
+    /// Of the inner engine alone, the codes of a fallback can't be typed.
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    /// Of the inner engine alone, like [`InputMethodEngine::reverse_lookup`].
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.associations(committed, limit)
+    }
+
+    /// Of the inner engine alone, like [`InputMethodEngine::reverse_lookup`].
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.inner.capabilities()
+    }
+
+    /// The fallbacks are left to their first search.
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    /// Those of the fallbacks too, as their candidates are found by the engine.
+    fn inconsistencies(&self) -> u64 {
+        let fallbacks = self
+            .fallbacks
+            .iter()
+            .map(|(_, engine)| engine.inconsistencies());
+        self.inner.inconsistencies() + fallbacks.sum::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::engine::{LazyEngine, MemoryEngine};
+
+    fn memory(entries: &[(&str, &str)]) -> MemoryEngine {
+        entries
+            .iter()
+            .map(|(text, code)| SearchResultItem {
+                text: text.to_string(),
+                code: code.to_string(),
+                weight: 1,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fallback() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let lazy = LazyEngine::new(EngineCapabilities::default(), {
+            let opens = opens.clone();
+            move || {
+                opens.fetch_add(1, Ordering::SeqCst);
+                Ok(memory(&[("你", "ni"), ("好", "hao")]))
+            }
+        });
+        let engine = FallbackEngine::new(
+            memory(&[("人", "ni")]),
+            vec![
+                ("empty".to_string(), Box::new(memory(&[])) as _),
+                ("pinyin".to_string(), Box::new(lazy) as _),
+            ],
+        );
+        assert_eq!(engine.fallbacks().collect::<Vec<_>>(), ["empty", "pinyin"]);
+
+        // only when the formula has nothing
+        let items = engine.search("ni").unwrap();
+        assert_eq!(
+            (items[0].text.as_str(), items[0].formula.as_deref()),
+            ("人", None)
+        );
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
+
+        let items = engine.search("hao").unwrap();
+        assert_eq!(items[0].formula.as_deref(), Some("pinyin"));
+        assert_eq!(items[0].to_string(), "好 [hao] (1) (from pinyin)");
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+        let groups = engine.search_grouped("ha").unwrap();
+        assert_eq!(groups[0].1[0].formula.as_deref(), Some("pinyin"));
+        assert_eq!(engine.search_in_context("hao", "你").unwrap().len(), 1);
+        assert!(engine.search("xx").unwrap().is_empty());
+
+        let mut items = engine.inner.search("ni").unwrap();
+        engine.search_into("hao", &mut items).unwrap();
+        assert_eq!(items.len(), 2);
+        engine.search_into("ni", &mut items).unwrap();
+        assert_eq!(items[2].formula, None);
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,359 @@
+//! Candidates blocked whatever the formula and the engine: texts never offered, such as
+//! profanity, and texts never offered for one code, such as a typo committed by accident.
+//! They are read from [`FILTERS_FILE`] in the config dir,
+//!
+//! ```dhall
+//! { texts = [ "笨蛋" ], pairs = [ { code = "ni", text = "尼" } ] }
+//! ```
+//!
+//! either list left out when there is none of it, and a [`FilteredEngine`] leaves them out
+//! of everything it answers. Wrapping the engines of the patch dictionary and the
+//! transformers, it is the last to see the candidates, so that the limit of a search and
+//! the pages of a composition count only those left.
+
+#[cfg(feature = "dhall-config")]
+use std::path::{Path, PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "dhall-config")]
+use tracing::debug;
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+/// The file of the config dir the filters are read from.
+pub const FILTERS_FILE: &str = "filters.dhall";
+
+/// How the filters are written, see the [module](self).
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FiltersConfig {
+    /// Blocked for every code.
+    pub texts: Vec<String>,
+    /// Blocked for their code alone.
+    pub pairs: Vec<BlockedPair>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockedPair {
+    pub code: String,
+    pub text: String,
+}
+
+/// What is blocked, looked up for every candidate.
+#[derive(Debug, Default)]
+struct Blocked {
+    texts: HashSet<String>,
+    /// Texts by code.
+    pairs: HashMap<String, HashSet<String>>,
+}
+
+impl Blocked {
+    fn contains(&self, item: &SearchResultItem) -> bool {
+        self.texts.contains(&item.text)
+            || self
+                .pairs
+                .get(&item.code)
+                .is_some_and(|texts| texts.contains(&item.text))
+    }
+
+    fn len(&self) -> usize {
+        self.texts.len() + self.pairs.values().map(HashSet::len).sum::<usize>()
+    }
+}
+
+impl From<FiltersConfig> for Blocked {
+    fn from(config: FiltersConfig) -> Self {
+        let mut pairs: HashMap<String, HashSet<String>> = HashMap::new();
+        for BlockedPair { code, text } in config.pairs {
+            pairs.entry(code).or_default().insert(text);
+        }
+        Self {
+            texts: config.texts.into_iter().collect(),
+            pairs,
+        }
+    }
+}
+
+/// The filters of a profile, shared by its engines and swapped for those of the file by
+/// [`Filters::reload`] while they search.
+#[derive(Debug, Default)]
+pub struct Filters {
+    blocked: RwLock<Blocked>,
+    /// Of the file they were read from, none for those of a [`FiltersConfig`].
+    #[cfg(feature = "dhall-config")]
+    path: Option<PathBuf>,
+}
+
+impl Filters {
+    pub fn new(config: FiltersConfig) -> Self {
+        let mut filters = Self::default();
+        *filters
+            .blocked
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = config.into();
+        filters
+    }
+
+    /// Those of [`FILTERS_FILE`] in `config_dir`, none while there is no such file.
+    #[cfg(feature = "dhall-config")]
+    pub fn load(config_dir: &Path) -> Result<Self, LiushuError> {
+        let path = config_dir.join(FILTERS_FILE);
+        Ok(Self {
+            blocked: RwLock::new(read(&path)?),
+            path: Some(path),
+        })
+    }
+
+    /// Reads the file they were loaded from again, answering how many texts and pairs it
+    /// blocks. The filters are left as they were when it can't be read, and those of a
+    /// [`FiltersConfig`] always are.
+    #[cfg(feature = "dhall-config")]
+    pub fn reload(&self) -> Result<usize, LiushuError> {
+        if let Some(path) = &self.path {
+            let blocked = read(path)?;
+            debug!(path = %path.display(), blocked = blocked.len(), "reloaded filters");
+            *self
+                .blocked
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = blocked;
+        }
+        Ok(self.len())
+    }
+
+    /// Texts and pairs blocked.
+    pub fn len(&self) -> usize {
+        self.blocked().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_blocked(&self, item: &SearchResultItem) -> bool {
+        self.blocked().contains(item)
+    }
+
+    /// Leaves the blocked candidates out of `items`, the others in the same order.
+    pub fn apply(&self, items: &mut Vec<SearchResultItem>) {
+        let blocked = self.blocked();
+        if blocked.len() > 0 {
+            items.retain(|item| !blocked.contains(item));
+        }
+    }
+
+    fn blocked(&self) -> RwLockReadGuard<'_, Blocked> {
+        self.blocked
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "dhall-config")]
+fn read(path: &Path) -> Result<Blocked, LiushuError> {
+    if !path.exists() {
+        return Ok(Blocked::default());
+    }
+    let config: FiltersConfig =
+        serde_dhall::from_file(path)
+            .parse()
+            .map_err(|e| LiushuError::Config {
+                path: Some(path.to_path_buf()),
+                source: Box::new(e),
+            })?;
+    Ok(config.into())
+}
+
+/// An engine whose candidates the [`Filters`] block are left out, see the [module](self).
+pub struct FilteredEngine<E> {
+    inner: E,
+    filters: Arc<Filters>,
+}
+
+impl<E: InputMethodEngine> FilteredEngine<E> {
+    pub fn new(inner: E, filters: Arc<Filters>) -> Self {
+        Self { inner, filters }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn filters(&self) -> &Arc<Filters> {
+        &self.filters
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for FilteredEngine<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.search(code)?;
+        self.filters.apply(&mut items);
+        Ok(items)
+    }
+
+    /// Only the candidates appended are filtered.
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        let start = items.len();
+        self.inner.search_into(code, items)?;
+        let mut appended = items.split_off(start);
+        self.filters.apply(&mut appended);
+        items.append(&mut appended);
+        Ok(())
+    }
+
+    /// Codes left without a candidate are left out too.
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let mut groups = self.inner.search_grouped(code)?;
+        for (_, items) in &mut groups {
+            self.filters.apply(items);
+        }
+        groups.retain(|(_, items)| !items.is_empty());
+        Ok(groups)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.search_in_context(code, context)?;
+        self.filters.apply(&mut items);
+        Ok(items)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    /// Those blocked are left out after the `limit` is taken, which may leave fewer.
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.associations(committed, limit)?;
+        self.filters.apply(&mut items);
+        Ok(items)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    fn engine(filters: FiltersConfig) -> FilteredEngine<MemoryEngine> {
+        let memory = [("你", "ni", 10), ("尼", "ni", 5), ("你好", "nihao", 20)]
+            .into_iter()
+            .map(|(text, code, weight)| SearchResultItem {
+                text: text.to_string(),
+                code: code.to_string(),
+                weight,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            })
+            .collect();
+        FilteredEngine::new(memory, Arc::new(Filters::new(filters)))
+    }
+
+    fn texts(items: &[SearchResultItem]) -> Vec<&str> {
+        items.iter().map(|item| item.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_filtered_engine() {
+        let engine = engine(FiltersConfig {
+            texts: vec!["你好".to_string()],
+            pairs: vec![BlockedPair {
+                code: "ni".to_string(),
+                text: "尼".to_string(),
+            }],
+        });
+        assert_eq!(engine.filters().len(), 2);
+        assert_eq!(texts(&engine.search("n").unwrap()), ["你"]);
+        assert_eq!(texts(&engine.search_in_context("n", "好").unwrap()), ["你"]);
+        let groups = engine.search_grouped("n").unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].0, "ni");
+
+        // what was there before is left alone
+        let mut items = engine.inner.search("nihao").unwrap();
+        engine.search_into("ni", &mut items).unwrap();
+        assert_eq!(texts(&items), ["你好", "你"]);
+
+        let open = self::engine(FiltersConfig::default());
+        assert!(open.filters().is_empty());
+        assert_eq!(open.search("ni").unwrap().len(), 3);
+    }
+
+    #[cfg(feature = "dhall-config")]
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let filters = Filters::load(dir.path()).unwrap();
+        assert!(filters.is_empty());
+
+        let path = dir.path().join(FILTERS_FILE);
+        std::fs::write(&path, r#"{ texts = [ "尼" ] }"#).unwrap();
+        assert_eq!(filters.reload().unwrap(), 1);
+        std::fs::write(
+            &path,
+            r#"{ pairs = [ { code = "ni", text = "你" }, { code = "ni", text = "尼" } ] }"#,
+        )
+        .unwrap();
+        assert_eq!(filters.reload().unwrap(), 2);
+        let item = |text: &str, code: &str| SearchResultItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            weight: 0,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        };
+        assert!(filters.is_blocked(&item("你", "ni")));
+        assert!(!filters.is_blocked(&item("你", "nil")));
+
+        // a broken file leaves them as they were
+        std::fs::write(&path, "{ texts = [ 1 ] }").unwrap();
+        assert!(matches!(
+            filters.reload(),
+            Err(LiushuError::Config { path: Some(p), .. }) if p == path
+        ));
+        assert_eq!(filters.len(), 2);
+    }
+}
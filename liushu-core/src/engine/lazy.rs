@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+type Open<E> = Box<dyn Fn() -> Result<E, LiushuError> + Send + Sync>;
+
+/// An engine opened by its first search, so that whatever starts it answers before the
+/// artifacts are loaded. An open that fails is tried again by the next search.
+pub struct LazyEngine<E> {
+    open: Open<E>,
+    engine: OnceCell<E>,
+    /// Those of the engine opened, known without opening it.
+    capabilities: EngineCapabilities,
+}
+
+impl<E: InputMethodEngine> LazyEngine<E> {
+    pub fn new(
+        capabilities: EngineCapabilities,
+        open: impl Fn() -> Result<E, LiushuError> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            open: Box::new(open),
+            engine: OnceCell::new(),
+            capabilities,
+        }
+    }
+
+    /// Whether a search has opened the engine yet.
+    pub fn is_open(&self) -> bool {
+        self.engine.get().is_some()
+    }
+
+    fn engine(&self) -> Result<&E, LiushuError> {
+        self.engine.get_or_try_init(|| (self.open)())
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for LazyEngine<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.engine()?.search(code)
+    }
+
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        self.engine()?.search_into(code, items)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.engine()?.search_in_context(code, context)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.engine()?.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.engine()?.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.engine()?.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.engine()?.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.capabilities
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.engine()?.warm_up(budget)
+    }
+
+    /// None until the engine is opened, which this doesn't do.
+    fn inconsistencies(&self) -> u64 {
+        self.engine
+            .get()
+            .map_or(0, |engine| engine.inconsistencies())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    #[test]
+    fn test_lazy() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let engine = LazyEngine::new(EngineCapabilities::default(), {
+            let opens = opens.clone();
+            move || match opens.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(LiushuError::InvalidInput("not yet".to_string())),
+                _ => Ok([SearchResultItem {
+                    text: "你".to_string(),
+                    code: "ni".to_string(),
+                    weight: 1,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                }]
+                .into_iter()
+                .collect::<MemoryEngine>()),
+            }
+        });
+        assert_eq!(engine.capabilities(), EngineCapabilities::default());
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
+
+        assert!(engine.search("ni").is_err());
+        assert!(!engine.is_open());
+        assert_eq!(engine.search("ni").unwrap()[0].text, "你");
+        assert_eq!(engine.search("n").unwrap().len(), 1);
+        assert!(engine.is_open());
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,320 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use bincode::Options;
+use patricia_tree::PatriciaMap;
+use serde::Serialize;
+
+use super::prefix::prefix_info;
+use super::{
+    decode_trie, reverse_lookup_trie, EngineCapabilities, InputMethodEngine, PrefixInfo,
+    SearchResultItem, DESCENDANT_LIMIT,
+};
+use crate::error::LiushuError;
+use crate::normalize;
+
+/// Weight and comment of each text, what the dictionary table of redb holds.
+type Definitions = HashMap<String, (u64, Option<String>)>;
+
+/// A formula held all in memory and loaded from bytes, for where there are no files to
+/// open, such as a browser.
+///
+/// The trie is the `.trie` artifact of a deployed formula, the definitions those of its
+/// redb dictionary. [`MemoryEngine::to_bytes`] writes both.
+#[derive(Debug)]
+pub struct MemoryEngine {
+    trie: PatriciaMap<Vec<String>>,
+    definitions: Definitions,
+    normalized: bool,
+}
+
+impl MemoryEngine {
+    pub fn from_bytes(trie: &[u8], definitions: &[u8]) -> Result<Self, LiushuError> {
+        let corrupt = |name: &str, e| LiushuError::ArtifactCorrupt {
+            path: PathBuf::from(name),
+            source: e,
+        };
+        Ok(Self {
+            trie: decode_trie(trie, trie.len() as u64).map_err(|e| corrupt("trie", e))?,
+            definitions: options(definitions.len() as u64)
+                .deserialize(definitions)
+                .map_err(|e| corrupt("definitions", e))?,
+            normalized: false,
+        })
+    }
+
+    /// Whether what is searched is normalized like the entries of a build that normalized
+    /// them, see [`crate::normalize`]. The bytes don't tell, an engine of them searches as
+    /// typed unless told otherwise, one of [`MemoryEngine::from_redb`] as the artifacts do.
+    pub fn normalize(mut self, normalized: bool) -> Self {
+        self.normalized = normalized;
+        self
+    }
+
+    /// Loads everything the redb engine would search.
+    #[cfg(feature = "runtime")]
+    pub fn from_redb(engine: &super::EngineWithRedb) -> Result<Self, LiushuError> {
+        use redb::ReadableTable;
+
+        let definitions = crate::artifact::read_redb(&engine.artifacts.db_path, || {
+            let tx = engine.artifacts.db.begin_read()?;
+            let dictionary = tx.open_table(crate::artifact::DICTIONARY)?;
+            let mut definitions = Definitions::new();
+            for (text, value) in dictionary.iter()? {
+                let (weight, comment) = value.value();
+                definitions.insert(
+                    text.value().to_string(),
+                    (weight, comment.map(String::from)),
+                );
+            }
+            Ok(definitions)
+        })?;
+        Ok(Self {
+            trie: engine.artifacts.trie.clone(),
+            definitions,
+            normalized: engine.artifacts.normalized()?,
+        })
+    }
+
+    /// The trie and the definitions, as [`MemoryEngine::from_bytes`] reads them.
+    pub fn to_bytes(&self) -> Result<(Vec<u8>, Vec<u8>), LiushuError> {
+        Ok((
+            encode(&self.trie, u64::MAX)?,
+            encode(&self.definitions, u64::MAX)?,
+        ))
+    }
+}
+
+/// An engine of the entries, where a text has the weight and comment of its last entry as
+/// in a deployed dictionary.
+impl FromIterator<SearchResultItem> for MemoryEngine {
+    fn from_iter<I: IntoIterator<Item = SearchResultItem>>(items: I) -> Self {
+        let mut trie = PatriciaMap::<Vec<String>>::new();
+        let mut definitions = Definitions::new();
+        for item in items {
+            match trie.get_mut(&item.code) {
+                Some(texts) if !texts.contains(&item.text) => texts.push(item.text.clone()),
+                Some(_) => {}
+                None => {
+                    trie.insert(&item.code, vec![item.text.clone()]);
+                }
+            }
+            definitions.insert(item.text, (item.weight, item.comment));
+        }
+        Self {
+            trie,
+            definitions,
+            normalized: false,
+        }
+    }
+}
+
+/// Those of the trie artifact, see [`decode_trie`].
+fn options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+fn encode(value: &impl Serialize, limit: u64) -> Result<Vec<u8>, LiushuError> {
+    options(limit)
+        .serialize(value)
+        .map_err(|e| LiushuError::io("encode engine", e))
+}
+
+impl InputMethodEngine for MemoryEngine {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let code = match self.normalized {
+            true => normalize::code(code),
+            false => Cow::Borrowed(code),
+        };
+        let mut result = Vec::new();
+        for (key, texts) in self.trie.iter_prefix(code.as_bytes()) {
+            let is_exact = key.len() == code.len();
+            let code = String::from_utf8_lossy(&key).into_owned();
+            for text in texts {
+                if let Some((weight, comment)) = self.definitions.get(text) {
+                    result.push(SearchResultItem {
+                        text: text.clone(),
+                        code: code.clone(),
+                        weight: *weight,
+                        comment: comment.clone(),
+                        formula: None,
+                        user_count: None,
+                        is_exact,
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        let texts: Vec<Cow<'_, str>> = texts
+            .iter()
+            .map(|text| match self.normalized {
+                true => normalize::text(text),
+                false => Cow::Borrowed(*text),
+            })
+            .collect();
+        Ok(reverse_lookup_trie(&self.trie, &texts)
+            .into_iter()
+            .map(|keys| {
+                keys.iter()
+                    .map(|key| String::from_utf8_lossy(key).into_owned())
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        let text = match self.normalized {
+            true => normalize::text(text),
+            false => Cow::Borrowed(text),
+        };
+        Ok(self
+            .trie
+            .iter()
+            .filter(|(_, texts)| texts.iter().any(|t| *t == text))
+            .map(|(key, _)| String::from_utf8_lossy(&key).into_owned())
+            .collect())
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        let code = match self.normalized {
+            true => normalize::code(code),
+            false => Cow::Borrowed(code),
+        };
+        Ok(Some(prefix_info(&self.trie, &code, DESCENDANT_LIMIT)))
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            reverse_lookup: true,
+            prefix_info: true,
+            ..EngineCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::SearchResponse;
+
+    fn fixture() -> MemoryEngine {
+        [
+            ("你", "ni", 2, Some("〔亻尔〕")),
+            ("你好", "nihao", 3, None),
+            ("拟好", "nihao", 1, None),
+        ]
+        .into_iter()
+        .map(|(text, code, weight, comment)| SearchResultItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            weight,
+            comment: comment.map(String::from),
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let engine = fixture();
+        let texts: Vec<_> = engine
+            .search("ni")
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.text, item.code, item.weight))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                ("你".to_string(), "ni".to_string(), 2),
+                ("你好".to_string(), "nihao".to_string(), 3),
+                ("拟好".to_string(), "nihao".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            engine.search("ni").unwrap()[0].comment.as_deref(),
+            Some("〔亻尔〕")
+        );
+        assert!(engine.search("hao").unwrap().is_empty());
+        assert_eq!(engine.reverse_lookup("你好").unwrap(), ["nihao"]);
+    }
+
+    #[test]
+    fn test_search_longest() {
+        let engine = fixture();
+        let response = engine.search_longest("nihaox", "").unwrap();
+        assert_eq!(response.matched_len, 5);
+        assert_eq!(response.items.len(), 2);
+        assert_eq!(engine.search_longest("nix", "").unwrap().matched_len, 2);
+        for code in ["xni", ""] {
+            assert_eq!(
+                engine.search_longest(code, "").unwrap(),
+                SearchResponse::default()
+            );
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let (trie, definitions) = fixture().to_bytes().unwrap();
+        let engine = MemoryEngine::from_bytes(&trie, &definitions).unwrap();
+        assert_eq!(
+            engine.search("ni").unwrap(),
+            fixture().search("ni").unwrap()
+        );
+
+        let error = MemoryEngine::from_bytes(&trie[..trie.len() / 2], &definitions).unwrap_err();
+        assert_eq!(error.code(), "E_ARTIFACT_CORRUPT");
+        assert!(std::error::Error::source(&error).is_some());
+        assert!(MemoryEngine::from_bytes(&trie, &definitions[..3]).is_err());
+        assert!(MemoryEngine::from_bytes(&[], &[]).is_err());
+
+        let error = encode(&fixture().definitions, 1).unwrap_err();
+        assert_eq!(error.code(), "E_IO");
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_from_redb() {
+        use crate::{
+            dict::{build, BuildOptions},
+            engine::EngineWithRedb,
+            progress::NoProgress,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let words = dir.path().join("words.tsv");
+        std::fs::write(&words, "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n").unwrap();
+        build(
+            &[words],
+            dir.path(),
+            "fixture",
+            BuildOptions {
+                force: true,
+                ..Default::default()
+            },
+            &NoProgress,
+        )
+        .unwrap();
+        let redb = EngineWithRedb::with_formula(&dir, "fixture").unwrap();
+
+        let (trie, definitions) = MemoryEngine::from_redb(&redb).unwrap().to_bytes().unwrap();
+        // the trie is the very artifact of the formula
+        assert_eq!(
+            trie,
+            std::fs::read(dir.path().join("fixture.trie")).unwrap()
+        );
+        let engine = MemoryEngine::from_bytes(&trie, &definitions).unwrap();
+        assert_eq!(engine.search("ni").unwrap(), redb.search("ni").unwrap());
+    }
+}
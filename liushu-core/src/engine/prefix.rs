@@ -0,0 +1,150 @@
+use std::collections::BTreeSet;
+use std::iter;
+
+use patricia_tree::node::Node;
+use patricia_tree::PatriciaMap;
+use serde::{Deserialize, Serialize};
+
+/// How many longer codes [`PrefixInfo::descendant_codes`] counts at most by default.
+pub const DESCENDANT_LIMIT: usize = 1000;
+
+/// How the codes of a trie go on from a code, for a frontend to hint that typing on finds
+/// nothing more, see [`InputMethodEngine::prefix_info`](super::InputMethodEngine::prefix_info).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixInfo {
+    /// Whether the code is one of the trie and none is longer.
+    pub is_leaf: bool,
+    /// The keys typed next of the longer codes, in order.
+    pub child_keys: Vec<char>,
+    /// Codes longer than the code starting with it, counted up to a limit.
+    pub descendant_codes: usize,
+}
+
+/// That of `code` in `trie`, read off its nodes alone, counting up to `limit` longer codes.
+pub(super) fn prefix_info<V>(trie: &PatriciaMap<V>, code: &str, limit: usize) -> PrefixInfo {
+    let Some((pending, node)) = locate(trie.as_ref(), code.as_bytes()) else {
+        return PrefixInfo::default();
+    };
+    let mut keys = BTreeSet::new();
+    let mut descendants = 0;
+    if pending.is_empty() {
+        for child in siblings(node.child()) {
+            next_keys(child.label(), child.child(), &mut keys);
+        }
+    } else {
+        next_keys(pending, node.child(), &mut keys);
+        // the code ends within the label of the node, whose value is of a longer one
+        descendants += usize::from(node.value().is_some());
+    }
+    descendants += count_values(node.child(), limit.saturating_sub(descendants));
+    PrefixInfo {
+        is_leaf: pending.is_empty() && node.value().is_some() && node.child().is_none(),
+        child_keys: keys.into_iter().collect(),
+        descendant_codes: descendants.min(limit),
+    }
+}
+
+/// The node whose label `code` ends in and the rest of that label, none when no code of
+/// the trie starts with it.
+fn locate<'a, V>(root: &'a Node<V>, code: &[u8]) -> Option<(&'a [u8], &'a Node<V>)> {
+    let (mut node, mut rest) = (root, code);
+    loop {
+        let label = node.label();
+        let common = iter::zip(label, rest).take_while(|(a, b)| a == b).count();
+        if common == rest.len() {
+            return Some((&label[common..], node));
+        }
+        if common < label.len() {
+            return None;
+        }
+        rest = &rest[common..];
+        node = siblings(node.child()).find(|child| child.label().first() == rest.first())?;
+    }
+}
+
+/// The first characters of the codes going on with `bytes`, then with the labels of the
+/// nodes `below` while that isn't a whole character.
+fn next_keys<V>(bytes: &[u8], below: Option<&Node<V>>, keys: &mut BTreeSet<char>) {
+    let head = &bytes[..bytes.len().min(4)];
+    match std::str::from_utf8(head) {
+        Ok(head) => keys.extend(head.chars().next()),
+        Err(e) if e.valid_up_to() > 0 => {
+            let valid = std::str::from_utf8(&head[..e.valid_up_to()]);
+            keys.extend(valid.ok().and_then(|head| head.chars().next()));
+        }
+        // a character the label cut short, while the bytes of a malformed code make none
+        // however many follow
+        Err(e) if e.error_len().is_none() => {
+            for child in siblings(below) {
+                next_keys(&[bytes, child.label()].concat(), child.child(), keys);
+            }
+        }
+        Err(_) => {}
+    }
+}
+
+/// The values of the nodes from `first`, its siblings and their children, up to `limit`.
+fn count_values<V>(first: Option<&Node<V>>, limit: usize) -> usize {
+    let mut count = 0;
+    let mut stack: Vec<&Node<V>> = first.into_iter().collect();
+    while let Some(node) = stack.pop() {
+        if count >= limit {
+            break;
+        }
+        count += usize::from(node.value().is_some());
+        stack.extend(node.sibling());
+        stack.extend(node.child());
+    }
+    count.min(limit)
+}
+
+fn siblings<V>(first: Option<&Node<V>>) -> impl Iterator<Item = &Node<V>> {
+    iter::successors(first, |node| node.sibling())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(codes: &[&str]) -> PatriciaMap<()> {
+        codes.iter().map(|code| (code.as_bytes(), ())).collect()
+    }
+
+    fn info(is_leaf: bool, child_keys: &str, descendant_codes: usize) -> PrefixInfo {
+        PrefixInfo {
+            is_leaf,
+            child_keys: child_keys.chars().collect(),
+            descendant_codes,
+        }
+    }
+
+    #[test]
+    fn test_prefix_info() {
+        let trie = trie(&["a", "ab", "abc", "abd", "ax", "b", "bcd"]);
+        let limit = DESCENDANT_LIMIT;
+        assert_eq!(prefix_info(&trie, "", limit), info(false, "ab", 7));
+        assert_eq!(prefix_info(&trie, "a", limit), info(false, "bx", 4));
+        assert_eq!(prefix_info(&trie, "ab", limit), info(false, "cd", 2));
+        assert_eq!(prefix_info(&trie, "abc", limit), info(true, "", 0));
+        assert_eq!(prefix_info(&trie, "ax", limit), info(true, "", 0));
+        // within the label `cd` of the node under `b`
+        assert_eq!(prefix_info(&trie, "b", limit), info(false, "c", 1));
+        assert_eq!(prefix_info(&trie, "bc", limit), info(false, "d", 1));
+        assert_eq!(prefix_info(&trie, "bcd", limit), info(true, "", 0));
+        assert_eq!(prefix_info(&trie, "bx", limit), info(false, "", 0));
+        assert_eq!(prefix_info(&trie, "abcd", limit), info(false, "", 0));
+
+        assert_eq!(prefix_info(&trie, "a", 2), info(false, "bx", 2));
+        assert_eq!(prefix_info(&trie, "a", 0), info(false, "bx", 0));
+    }
+
+    #[test]
+    fn test_multibyte_keys() {
+        // é and ê share their first byte, which the trie splits them at
+        let trie = trie(&["xé", "xê", "xêa", "y"]);
+        let limit = DESCENDANT_LIMIT;
+        assert_eq!(prefix_info(&trie, "x", limit), info(false, "éê", 3));
+        assert_eq!(prefix_info(&trie, "xê", limit), info(false, "a", 1));
+        assert_eq!(prefix_info(&trie, "xé", limit), info(true, "", 0));
+    }
+}
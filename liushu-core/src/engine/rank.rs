@@ -0,0 +1,488 @@
+//! The order candidates are offered in, decided by a pipeline of [`Ranker`]s run one after
+//! the other on the candidates of a search. Each stage keeps the order of the candidates it
+//! doesn't tell apart, so that the later a stage runs, the more it weighs: the last one has
+//! the final say. The stages and their order are those of a formula, `ranking` in the
+//! config,
+//!
+//! ```dhall
+//! { id = "sunman", ranking = Some [ "weight", "codeLength", "context", "pins" ], pins = [ { code = "ni", text = "你" } ] }
+//! ```
+//!
+//! [`DEFAULT_STAGES`] when it is left out, and a stage left out of the list is turned off.
+
+use std::{cmp::Reverse, fmt, str::FromStr, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+/// What a stage knows of the search it ranks the candidates of.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RankContext<'a> {
+    /// The code searched.
+    pub code: &'a str,
+    /// The text committed right before it, empty outside of a context.
+    pub context: &'a str,
+}
+
+/// A stage of a [`RankingPipeline`], reordering the candidates of a search in place.
+pub trait Ranker: Send + Sync {
+    fn rescore(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>);
+
+    /// Whether the stage ranks by [`RankContext::context`], for the engine to tell.
+    fn uses_context(&self) -> bool {
+        false
+    }
+}
+
+impl<R: Ranker + ?Sized> Ranker for Arc<R> {
+    fn rescore(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        (**self).rescore(ctx, items)
+    }
+
+    fn uses_context(&self) -> bool {
+        (**self).uses_context()
+    }
+}
+
+/// The stages a formula can rank by, named as in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", try_from = "String")]
+pub enum RankStage {
+    /// The heaviest candidates first, see [`ByWeight`].
+    Weight,
+    /// Candidates of the code searched before the longer codes it starts, see
+    /// [`ByCodeLength`].
+    CodeLength,
+    /// By how likely the candidates follow the context, with the model of the target dir.
+    Context,
+    /// The [`Pins`] of the formula first.
+    Pins,
+}
+
+/// The stages of a formula that doesn't say: searches keep the order of the dictionary
+/// outside of a context, and the pins win over the model.
+pub const DEFAULT_STAGES: [RankStage; 2] = [RankStage::Context, RankStage::Pins];
+
+impl RankStage {
+    pub const ALL: [RankStage; 4] = [
+        RankStage::Weight,
+        RankStage::CodeLength,
+        RankStage::Context,
+        RankStage::Pins,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RankStage::Weight => "weight",
+            RankStage::CodeLength => "codeLength",
+            RankStage::Context => "context",
+            RankStage::Pins => "pins",
+        }
+    }
+}
+
+impl fmt::Display for RankStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RankStage {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|stage| stage.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|stage| stage.name()).collect();
+                format!(
+                    "unknown ranking stage {:?}, one of {} is expected",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for RankStage {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+/// The heaviest candidates first, those as heavy in the order they come in.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ByWeight;
+
+impl Ranker for ByWeight {
+    fn rescore(&self, _ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        items.sort_by_key(|item| Reverse(item.weight));
+    }
+}
+
+/// The shorter codes first, so that the candidates of the code searched come before those
+/// of the longer codes it starts.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ByCodeLength;
+
+impl Ranker for ByCodeLength {
+    fn rescore(&self, _ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        items.sort_by_key(|item| item.code.len());
+    }
+}
+
+/// A text put first when its code is searched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pin {
+    pub code: String,
+    pub text: String,
+}
+
+/// The pinned candidates of the code searched first, in the order of the pins, whatever
+/// their weight or context.
+#[derive(Debug, Default, Clone)]
+pub struct Pins(pub Vec<Pin>);
+
+impl Ranker for Pins {
+    fn rescore(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        let rank = |item: &SearchResultItem| {
+            self.0
+                .iter()
+                .position(|pin| {
+                    pin.code == ctx.code && pin.code == item.code && pin.text == item.text
+                })
+                .unwrap_or(self.0.len())
+        };
+        if self.0.iter().any(|pin| pin.code == ctx.code) {
+            items.sort_by_key(rank);
+        }
+    }
+}
+
+/// Reorders each run of candidates as heavy as the one before them by `keys`, those of
+/// the candidates in turn, leaving the runs where they are and those of the same key in
+/// their order. A tiebreaker for the order the stages answer, which it never changes
+/// but among candidates of the same weight next to one another.
+pub fn break_ties<K: Ord>(items: &mut Vec<SearchResultItem>, keys: Vec<K>) {
+    let mut keyed: Vec<_> = keys.into_iter().zip(items.drain(..)).collect();
+    let mut start = 0;
+    while start < keyed.len() {
+        let weight = keyed[start].1.weight;
+        let end = keyed[start..]
+            .iter()
+            .position(|(_, item)| item.weight != weight)
+            .map_or(keyed.len(), |len| start + len);
+        keyed[start..end].sort_by(|(a, _), (b, _)| a.cmp(b));
+        start = end;
+    }
+    items.extend(keyed.into_iter().map(|(_, item)| item));
+}
+
+/// The stages of a formula, in the order they run.
+#[derive(Default)]
+pub struct RankingPipeline {
+    stages: Vec<(RankStage, Box<dyn Ranker>)>,
+}
+
+impl RankingPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `ranker` after the stages already there.
+    pub fn stage(mut self, stage: RankStage, ranker: impl Ranker + 'static) -> Self {
+        self.stages.push((stage, Box::new(ranker)));
+        self
+    }
+
+    /// The stages, in the order they run.
+    pub fn stages(&self) -> impl Iterator<Item = RankStage> + '_ {
+        self.stages.iter().map(|(stage, _)| *stage)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn rank(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        for (_, ranker) in &self.stages {
+            ranker.rescore(ctx, items);
+        }
+    }
+
+    fn uses_context(&self) -> bool {
+        self.stages.iter().any(|(_, ranker)| ranker.uses_context())
+    }
+}
+
+/// An engine whose candidates are ordered by a [`RankingPipeline`], see the
+/// [module](self).
+pub struct RankedEngine<E> {
+    inner: E,
+    pipeline: Arc<RankingPipeline>,
+}
+
+impl<E: InputMethodEngine> RankedEngine<E> {
+    pub fn new(inner: E, pipeline: Arc<RankingPipeline>) -> Self {
+        Self { inner, pipeline }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn pipeline(&self) -> &Arc<RankingPipeline> {
+        &self.pipeline
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for RankedEngine<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.search(code)?;
+        let ctx = RankContext { code, context: "" };
+        self.pipeline.rank(&ctx, &mut items);
+        Ok(items)
+    }
+
+    /// Only the candidates appended are ranked.
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        let start = items.len();
+        self.inner.search_into(code, items)?;
+        let mut appended = items.split_off(start);
+        self.pipeline
+            .rank(&RankContext { code, context: "" }, &mut appended);
+        items.append(&mut appended);
+        Ok(())
+    }
+
+    /// The groups keep their order, the candidates of each are ranked.
+    fn search_grouped(
+        &self,
+        code: &str,
+    ) -> Result<Vec<(String, Vec<SearchResultItem>)>, LiushuError> {
+        let mut groups = self.inner.search_grouped(code)?;
+        let ctx = RankContext { code, context: "" };
+        for (_, items) in &mut groups {
+            self.pipeline.rank(&ctx, items);
+        }
+        Ok(groups)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.search_in_context(code, context)?;
+        self.pipeline
+            .rank(&RankContext { code, context }, &mut items);
+        Ok(items)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        let capabilities = self.inner.capabilities();
+        EngineCapabilities {
+            context: capabilities.context || self.pipeline.uses_context(),
+            ..capabilities
+        }
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    /// Ranks the candidates following `你` first, like a model would.
+    struct FollowsNi;
+
+    impl Ranker for FollowsNi {
+        fn rescore(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+            if ctx.context.ends_with('你') {
+                items.sort_by_key(|item| item.text != "好");
+            }
+        }
+
+        fn uses_context(&self) -> bool {
+            true
+        }
+    }
+
+    fn engine(pipeline: RankingPipeline) -> RankedEngine<MemoryEngine> {
+        let memory = [
+            ("号", "hao", 10),
+            ("好", "hao", 5),
+            ("豪", "hao", 1),
+            ("好的", "haod", 20),
+        ]
+        .into_iter()
+        .map(|(text, code, weight)| SearchResultItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            weight,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        })
+        .collect();
+        RankedEngine::new(memory, Arc::new(pipeline))
+    }
+
+    fn texts(items: &[SearchResultItem]) -> Vec<&str> {
+        items.iter().map(|item| item.text.as_str()).collect()
+    }
+
+    fn pins() -> Pins {
+        Pins(vec![Pin {
+            code: "hao".to_string(),
+            text: "豪".to_string(),
+        }])
+    }
+
+    #[test]
+    fn test_stages() {
+        let engine = self::engine(RankingPipeline::new());
+        assert_eq!(
+            texts(&engine.search("hao").unwrap()),
+            ["号", "好", "豪", "好的"]
+        );
+        assert!(!engine.capabilities().context);
+
+        let engine = self::engine(RankingPipeline::new().stage(RankStage::Weight, ByWeight));
+        assert_eq!(
+            texts(&engine.search("hao").unwrap()),
+            ["好的", "号", "好", "豪"]
+        );
+        let engine = self::engine(
+            RankingPipeline::new()
+                .stage(RankStage::Weight, ByWeight)
+                .stage(RankStage::CodeLength, ByCodeLength),
+        );
+        assert_eq!(
+            texts(&engine.search("hao").unwrap()),
+            ["号", "好", "豪", "好的"]
+        );
+        let engine = self::engine(RankingPipeline::new().stage(RankStage::Pins, pins()));
+        assert_eq!(
+            texts(&engine.search("hao").unwrap()),
+            ["豪", "号", "好", "好的"]
+        );
+        // pinned for its code alone
+        assert_eq!(
+            texts(&engine.search("ha").unwrap()),
+            ["号", "好", "豪", "好的"]
+        );
+    }
+
+    #[test]
+    fn test_pins_win_over_context() {
+        let engine = self::engine(
+            RankingPipeline::new()
+                .stage(RankStage::Weight, ByWeight)
+                .stage(RankStage::Context, FollowsNi)
+                .stage(RankStage::Pins, pins()),
+        );
+        assert!(engine.capabilities().context);
+        assert_eq!(
+            texts(&engine.search_in_context("hao", "你").unwrap()),
+            ["豪", "好", "好的", "号"]
+        );
+        assert_eq!(
+            texts(&engine.search_in_context("hao", "我").unwrap()),
+            ["豪", "好的", "号", "好"]
+        );
+
+        // the context wins when it runs last
+        let engine = self::engine(
+            RankingPipeline::new()
+                .stage(RankStage::Pins, pins())
+                .stage(RankStage::Context, FollowsNi),
+        );
+        assert_eq!(
+            texts(&engine.search_in_context("hao", "你").unwrap()),
+            ["好", "豪", "号", "好的"]
+        );
+
+        let mut items = engine.inner.search("haod").unwrap();
+        engine.search_into("hao", &mut items).unwrap();
+        assert_eq!(texts(&items), ["好的", "豪", "号", "好", "好的"]);
+        let groups = engine.search_grouped("hao").unwrap();
+        assert_eq!(texts(&groups[0].1), ["豪", "号", "好"]);
+    }
+
+    #[test]
+    fn test_break_ties() {
+        let weighed = |weights: [u64; 4]| {
+            let mut items = self::engine(RankingPipeline::new()).search("hao").unwrap();
+            for (item, weight) in items.iter_mut().zip(weights) {
+                item.weight = weight;
+            }
+            items
+        };
+        let mut items = weighed([10, 5, 5, 5]);
+        let last_used = [None, None, Some(3), None];
+        break_ties(&mut items, last_used.into_iter().map(Reverse).collect());
+        assert_eq!(texts(&items), ["号", "豪", "好", "好的"]);
+        // as heavy but apart, in runs of their own
+        let mut items = weighed([5, 9, 5, 5]);
+        break_ties(&mut items, vec![3, 0, 2, 1]);
+        assert_eq!(texts(&items), ["号", "好", "好的", "豪"]);
+    }
+
+    #[test]
+    fn test_stage_names() {
+        for stage in RankStage::ALL {
+            assert_eq!(stage.name().parse::<RankStage>(), Ok(stage));
+            assert_eq!(
+                serde_json::to_value(stage).unwrap(),
+                serde_json::json!(stage.name())
+            );
+        }
+        assert_eq!(
+            serde_json::from_str::<RankStage>(r#""codeLength""#).unwrap(),
+            RankStage::CodeLength
+        );
+        assert!("boost"
+            .parse::<RankStage>()
+            .unwrap_err()
+            .starts_with("unknown ranking stage \"boost\""));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+use patricia_tree::map::Iter;
+use redb::ReadableTable;
+
+use super::RedbArtifacts;
+use crate::artifact::{read_redb, DictItem, DICTIONARY};
+use crate::error::LiushuError;
+
+/// Codes joined with the dictionary in a read transaction, so that it is held for a while
+/// rather than opened for each entry or kept for the whole iteration.
+const BATCH_CODES: usize = 256;
+
+/// Every entry of the artifacts of a formula, for the tools going through a whole
+/// dictionary rather than searching it.
+pub struct ArtifactReader {
+    artifacts: Arc<RedbArtifacts>,
+}
+
+impl ArtifactReader {
+    /// Opens artifacts of its own, like
+    /// [`EngineWithRedb::with_formula`](super::EngineWithRedb::with_formula).
+    pub fn open(target_dir: impl AsRef<Path>, formula_id: &str) -> Result<Self, LiushuError> {
+        let artifacts = RedbArtifacts::open(target_dir.as_ref(), formula_id)?;
+        Ok(Self::from_artifacts(Arc::new(artifacts)))
+    }
+
+    pub fn from_artifacts(artifacts: Arc<RedbArtifacts>) -> Self {
+        Self { artifacts }
+    }
+
+    /// The entries in the order of the candidates of a search of every code: codes in
+    /// bytewise order and the texts of a code in the order of the dictionary, a text once
+    /// for each of its codes.
+    ///
+    /// A text of the trie missing from the dictionary is an `ArtifactCorrupt` error in its
+    /// place, and the entries after it follow. An error reading the dictionary is the last
+    /// item.
+    pub fn iter(&self) -> Entries<'_> {
+        Entries {
+            artifacts: &self.artifacts,
+            keys: self.artifacts.trie.iter(),
+            read: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a ArtifactReader {
+    type Item = Result<DictItem, LiushuError>;
+    type IntoIter = Entries<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// See [`ArtifactReader::iter`].
+pub struct Entries<'a> {
+    artifacts: &'a RedbArtifacts,
+    keys: Iter<'a, Vec<String>>,
+    /// The entries of the batch read last, not yet yielded.
+    read: VecDeque<Result<DictItem, LiushuError>>,
+    done: bool,
+}
+
+impl Entries<'_> {
+    fn read_batch(&mut self) {
+        let batch: Vec<_> = self.keys.by_ref().take(BATCH_CODES).collect();
+        if batch.is_empty() {
+            self.done = true;
+            return;
+        }
+        let RedbArtifacts {
+            db,
+            db_path,
+            trie_path,
+            ..
+        } = self.artifacts;
+        let read = read_redb(db_path, || {
+            let tx = db.begin_read()?;
+            let dictionary = tx.open_table(DICTIONARY)?;
+            let mut read = Vec::new();
+            for (key, texts) in batch {
+                let code = match String::from_utf8(key) {
+                    Ok(code) => code,
+                    Err(e) => {
+                        read.push(Err(LiushuError::ArtifactCorrupt {
+                            path: trie_path.clone(),
+                            source: Box::new(e),
+                        }));
+                        continue;
+                    }
+                };
+                for text in texts {
+                    let Some(value) = dictionary.get(text.as_str())? else {
+                        read.push(Err(LiushuError::ArtifactCorrupt {
+                            path: db_path.clone(),
+                            source: format!(
+                                "no entry of {} of the code {} in the trie",
+                                text, code
+                            )
+                            .into(),
+                        }));
+                        continue;
+                    };
+                    let (weight, comment) = value.value();
+                    read.push(Ok(DictItem {
+                        text: text.clone(),
+                        code: code.clone(),
+                        weight,
+                        comment: comment.map(|c| c.to_owned()),
+                    }));
+                }
+            }
+            Ok(read)
+        });
+        match read {
+            Ok(read) => self.read.extend(read),
+            Err(error) => {
+                self.read.push_back(Err(error));
+                self.done = true;
+            }
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<DictItem, LiushuError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.read.is_empty() && !self.done {
+            self.read_batch();
+        }
+        self.read.pop_front()
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::fs;
+
+    use redb::Database;
+
+    use crate::dict::{build, BuildOptions};
+    use crate::engine::{EngineWithRedb, InputMethodEngine, SearchResultItem};
+    use crate::progress::NoProgress;
+
+    use super::*;
+
+    fn build_words(dir: &Path, words: &str) {
+        let path = dir.join("words.tsv");
+        fs::write(&path, format!("text\tcode\tweight\tcomment\n{}", words)).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[path], dir, "reader", options, &NoProgress).unwrap();
+    }
+
+    #[test]
+    fn test_iter() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut words = String::from("你\tni\t10\t\n尼\tni\t5\t〔尸匕〕\n你\tn\t10\t\n");
+        // more codes than a batch
+        for i in 0..600u32 {
+            let text = char::from_u32(0x4e00 + i).unwrap();
+            words.push_str(&format!("{}\tc{:04}\t{}\t\n", text, i, i));
+        }
+        build_words(dir.path(), &words);
+        let reader = ArtifactReader::open(dir.path(), "reader").unwrap();
+        let entries: Vec<DictItem> = reader.iter().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 603);
+
+        // those of searching every code
+        let engine = EngineWithRedb::from_artifacts(reader.artifacts.clone());
+        let searched: Vec<SearchResultItem> = engine
+            .codes()
+            .flat_map(|code| {
+                let mut items = engine.search(&code).unwrap();
+                items.retain(|item| item.is_exact);
+                items
+            })
+            .collect();
+        let entries: Vec<SearchResultItem> = entries
+            .into_iter()
+            .map(|entry| SearchResultItem {
+                is_exact: true,
+                ..entry.into()
+            })
+            .collect();
+        assert_eq!(entries, searched);
+        assert_eq!(
+            (entries[0].code.as_str(), entries[600].text.as_str()),
+            ("c0000", "你")
+        );
+        assert_eq!(entries[602].comment.as_deref(), Some("〔尸匕〕"));
+    }
+
+    #[test]
+    fn test_missing_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        build_words(
+            dir.path(),
+            "你\tni\t10\t\n泥\tni\t7\t\n尼\tni\t5\t\n好\thao\t8\t\n",
+        );
+        // the trie still has what the dictionary lost
+        let db = Database::open(dir.path().join("reader.redb")).unwrap();
+        let tx = db.begin_write().unwrap();
+        tx.open_table(DICTIONARY).unwrap().remove("泥").unwrap();
+        tx.open_table(DICTIONARY).unwrap().remove("好").unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let reader = ArtifactReader::open(dir.path(), "reader").unwrap();
+        let entries: Vec<_> = reader
+            .iter()
+            .map(|entry| entry.map(|entry| entry.text).map_err(|e| e.to_string()))
+            .collect();
+        let corrupt = format!(
+            "corrupt artifact {}",
+            dir.path().join("reader.redb").display()
+        );
+        assert_eq!(
+            entries,
+            [
+                Err(corrupt.clone()),
+                Ok("你".to_string()),
+                Err(corrupt),
+                Ok("尼".to_string()),
+            ]
+        );
+        let error = reader.iter().next().unwrap().unwrap_err();
+        assert_eq!(
+            error.report(),
+            format!(
+                "{}: no entry of 好 of the code hao in the trie",
+                entries[0].as_ref().unwrap_err()
+            )
+        );
+    }
+}
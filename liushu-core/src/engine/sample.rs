@@ -0,0 +1,194 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::hash_map::RandomState;
+use std::collections::BinaryHeap;
+use std::hash::{BuildHasher, Hasher};
+
+use super::{ArtifactReader, EngineWithRedb, SearchResultItem};
+use crate::error::LiushuError;
+
+/// What a candidate of weight 0 weighs in a sample, so that it can still be drawn.
+pub const FLOOR_WEIGHT: f64 = 0.5;
+
+impl EngineWithRedb {
+    /// Draws `n` candidates of the whole dictionary, a text with each of its codes, each
+    /// with a chance in proportion to its weight, for reviewing a dictionary by what is
+    /// typed most. Every candidate draws a key `u^(1/weight)` of a uniform `u`, and those of
+    /// the `n` greatest keys are returned, greatest first. Weight 0 counts as
+    /// [`FLOOR_WEIGHT`].
+    ///
+    /// The same `seed` draws the same sample of the same artifacts, on any platform. Without
+    /// one, every call draws another.
+    pub fn sample(
+        &self,
+        n: usize,
+        seed: Option<u64>,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        let mut random = Xorshift::new(seed);
+        // the least key drawn on top, the first to give up for a greater one
+        let mut drawn: BinaryHeap<Reverse<Drawn>> = BinaryHeap::with_capacity(n + 1);
+        for entry in &ArtifactReader::from_artifacts(self.artifacts.clone()) {
+            let entry = entry?;
+            let floored = if entry.weight == 0 {
+                FLOOR_WEIGHT
+            } else {
+                entry.weight as f64
+            };
+            // ln(u) / weight orders as u^(1/weight) does, without rounding the keys of heavy
+            // candidates to the same 1
+            let key = random.next_unit().ln() / floored;
+            if drawn.len() == n && drawn.peek().is_some_and(|least| least.0.key >= key) {
+                continue;
+            }
+            drawn.push(Reverse(Drawn {
+                key,
+                item: entry.into(),
+            }));
+            if drawn.len() > n {
+                drawn.pop();
+            }
+        }
+        Ok(drawn
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(drawn)| drawn.item)
+            .collect())
+    }
+}
+
+/// A candidate of a sample with the key it drew, ordered by the key alone.
+struct Drawn {
+    key: f64,
+    item: SearchResultItem,
+}
+
+impl PartialEq for Drawn {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Drawn {}
+
+impl PartialOrd for Drawn {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Drawn {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.total_cmp(&other.key)
+    }
+}
+
+/// A xorshift generator, small and the same everywhere, so that a seed always draws the
+/// same sample.
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn new(seed: u64) -> Self {
+        // scrambled, as close seeds start close, and never 0, where xorshift stays
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in (0, 1], of which the logarithm is finite.
+    fn next_unit(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use crate::dict::{build, BuildOptions};
+    use crate::progress::NoProgress;
+
+    use super::*;
+
+    fn engine(dir: &tempfile::TempDir, words: &str) -> EngineWithRedb {
+        let path = dir.path().join("words.tsv");
+        fs::write(&path, words).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[path], dir.path(), "sample", options, &NoProgress).unwrap();
+        EngineWithRedb::with_formula(dir, "sample").unwrap()
+    }
+
+    #[test]
+    fn test_seeded() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(
+            &dir,
+            "text\tcode\tweight\tcomment\n\
+             你\tni\t10\t〔亻尔〕\n\
+             尼\tni\t5\t\n\
+             你好\tnihao\t20\t\n\
+             你\tn\t10\t\n\
+             好\thao\t0\t\n",
+        );
+        let sample = engine.sample(3, Some(42)).unwrap();
+        assert_eq!(sample.len(), 3);
+        assert_eq!(engine.sample(3, Some(42)).unwrap(), sample);
+        assert!(engine.sample(0, Some(42)).unwrap().is_empty());
+
+        // all of them when there are fewer, a text once for each code
+        let mut all: Vec<_> = engine
+            .sample(10, Some(7))
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.code, item.text))
+            .collect();
+        all.sort();
+        let expected = [
+            ("hao", "好"),
+            ("n", "你"),
+            ("ni", "你"),
+            ("ni", "尼"),
+            ("nihao", "你好"),
+        ];
+        assert_eq!(all, expected.map(|(c, t)| (c.to_string(), t.to_string())));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(
+            &dir,
+            "text\tcode\tweight\n\
+             重\tzh\t100\n\
+             中\tzo\t10\n\
+             轻\tqi\t1\n\
+             无\twu\t0\n",
+        );
+        let runs = 2000;
+        let mut firsts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..runs {
+            let sample = engine.sample(1, None).unwrap();
+            *firsts.entry(sample[0].text.clone()).or_default() += 1;
+        }
+        let count = |text: &str| firsts.get(text).copied().unwrap_or_default();
+        // drawn with chances 100 : 10 : 1 : 0.5 out of 111.5, bounds far from them
+        assert!(count("重") > runs * 8 / 10, "{:?}", firsts);
+        assert!(count("中") > count("轻"), "{:?}", firsts);
+        assert!(count("中") < runs / 5, "{:?}", firsts);
+        assert!(count("轻") + count("无") < runs / 20, "{:?}", firsts);
+
+        // first for some seed, as a weight of exactly 0 would never be
+        assert!((0..1000).any(|seed| engine.sample(1, Some(seed)).unwrap()[0].text == "无"));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+use patricia_tree::PatriciaMap;
+use redb::ReadableTable;
+use tracing::debug;
+
+use super::decode_trie;
+use crate::artifact::{
+    read_redb, ArtifactDb, ArtifactOpenOptions, ANNOTATIONS, NORMALIZATION, OUT_OF_CHARSET,
+};
+use crate::dirs::lock::Lock;
+use crate::error::{IoResultExt, LiushuError};
+
+/// The redb dictionary of a formula, open, and its code trie read into memory: what an
+/// [`EngineWithRedb`](super::EngineWithRedb) searches.
+pub struct RedbArtifacts {
+    pub(super) db: ArtifactDb,
+    pub(super) db_path: PathBuf,
+    pub(super) trie: PatriciaMap<Vec<String>>,
+    pub(super) trie_path: PathBuf,
+    /// The association index, none when the target dir was deployed before builds wrote
+    /// one.
+    pub(super) assoc: Option<PatriciaMap<Vec<String>>>,
+    pub(super) assoc_path: PathBuf,
+    /// Whether the build normalized the entries, read by the first search.
+    normalized: OnceCell<bool>,
+    /// Those of the annotation table of the build, read by the first engine annotating.
+    annotations: OnceCell<HashMap<char, String>>,
+    /// The texts the build tagged as out of the charset, read by the first strict search.
+    out_of_charset: OnceCell<HashSet<String>>,
+}
+
+impl RedbArtifacts {
+    /// Opens the artifacts of `formula_id` in `target_dir`, read-only. redb locks the file,
+    /// so this fails while the same one is open elsewhere, in this process too.
+    pub fn open(target_dir: &Path, formula_id: &str) -> Result<Self, LiushuError> {
+        Self::open_with(target_dir, formula_id, ArtifactOpenOptions::default())
+    }
+
+    /// Like [`RedbArtifacts::open`], the dictionary opened with `options`. Both artifacts
+    /// are there whatever the mode, as the trie is read from its file.
+    pub fn open_with(
+        target_dir: &Path,
+        formula_id: &str,
+        options: ArtifactOpenOptions,
+    ) -> Result<Self, LiushuError> {
+        let start = Instant::now();
+        let db_path = target_dir.join(format!("{}.redb", formula_id));
+        let trie_path = target_dir.join(format!("{}.trie", formula_id));
+        for artifact in [&db_path, &trie_path] {
+            if !artifact.exists() {
+                return Err(LiushuError::ArtifactMissing(artifact.clone()));
+            }
+        }
+        // not the new dictionary of a deploy with its previous trie
+        let lock = Lock::opening(target_dir)?;
+        let db = options.open_redb(&db_path, "open dictionary")?;
+        let trie_file = File::open(&trie_path).with_path("open trie", &trie_path)?;
+        let size = trie_file
+            .metadata()
+            .with_path("read metadata of", &trie_path)?
+            .len();
+        let trie = decode_trie(trie_file, size).map_err(|e| LiushuError::ArtifactCorrupt {
+            path: trie_path.clone(),
+            source: e,
+        })?;
+        let assoc_path = target_dir.join(format!("{}.assoc", formula_id));
+        let assoc = match File::open(&assoc_path) {
+            Ok(file) => {
+                let size = file
+                    .metadata()
+                    .with_path("read metadata of", &assoc_path)?
+                    .len();
+                let assoc = decode_trie(file, size).map_err(|e| LiushuError::ArtifactCorrupt {
+                    path: assoc_path.clone(),
+                    source: e,
+                })?;
+                Some(assoc)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(LiushuError::io_at("open association index", &assoc_path, e)),
+        };
+        drop(lock);
+        debug!(formula = formula_id, elapsed = ?start.elapsed(), "opened redb artifacts");
+
+        Ok(Self {
+            db,
+            db_path,
+            trie,
+            trie_path,
+            assoc,
+            assoc_path,
+            normalized: OnceCell::new(),
+            annotations: OnceCell::new(),
+            out_of_charset: OnceCell::new(),
+        })
+    }
+
+    /// What each character of the codes stands for, none when the build had no annotation
+    /// table.
+    pub(super) fn annotations(&self) -> Result<&HashMap<char, String>, LiushuError> {
+        self.annotations
+            .get_or_try_init(|| read_annotations(&self.db, &self.db_path))
+    }
+
+    /// The texts with a character out of the charset of the formula, none when the build
+    /// had none or dropped them.
+    pub(super) fn out_of_charset(&self) -> Result<&HashSet<String>, LiushuError> {
+        self.out_of_charset.get_or_try_init(|| {
+            read_redb(&self.db_path, || {
+                let tx = self.db.begin_read()?;
+                let table = match tx.open_table(OUT_OF_CHARSET) {
+                    Err(redb::Error::TableDoesNotExist(_)) => return Ok(HashSet::new()),
+                    table => table?,
+                };
+                let mut texts = HashSet::new();
+                for (text, _) in table.iter()? {
+                    texts.insert(text.value().to_string());
+                }
+                Ok(texts)
+            })
+        })
+    }
+
+    /// Whether the build normalized the entries, see [`NORMALIZATION`], and the engines
+    /// normalize what they search alike.
+    pub(super) fn normalized(&self) -> Result<bool, LiushuError> {
+        self.normalized
+            .get_or_try_init(|| {
+                read_redb(&self.db_path, || {
+                    match self.db.begin_read()?.open_table(NORMALIZATION) {
+                        Err(redb::Error::TableDoesNotExist(_)) => Ok(false),
+                        table => Ok(table?.len()? > 0),
+                    }
+                })
+            })
+            .copied()
+    }
+}
+
+fn read_annotations(db: &ArtifactDb, db_path: &Path) -> Result<HashMap<char, String>, LiushuError> {
+    read_redb(db_path, || {
+        let tx = db.begin_read()?;
+        let table = match tx.open_table(ANNOTATIONS) {
+            Err(redb::Error::TableDoesNotExist(_)) => return Ok(HashMap::new()),
+            table => table?,
+        };
+        let mut annotations = HashMap::new();
+        for (code, annotation) in table.iter()? {
+            if let Some(code) = code.value().chars().next() {
+                annotations.insert(code, annotation.value().to_string());
+            }
+        }
+        Ok(annotations)
+    })
+}
+
+/// The artifacts of the formulas of a target dir, each loaded once for all the engines of
+/// the process searching it, such as those of the connections to a server.
+///
+/// The store only keeps what engines still use: the artifacts of a formula are closed once
+/// the last engine of them is dropped, and loaded again by the next one asked for.
+pub struct ArtifactStore {
+    target_dir: PathBuf,
+    loaded: Mutex<HashMap<String, Weak<RedbArtifacts>>>,
+}
+
+impl ArtifactStore {
+    pub fn new(target_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            target_dir: target_dir.into(),
+            loaded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    /// The artifacts of `formula_id`, those already loaded if any. The lock is held while
+    /// loading, so that two engines asking at once don't load them twice.
+    pub fn get(&self, formula_id: &str) -> Result<Arc<RedbArtifacts>, LiushuError> {
+        let mut loaded = self.loaded();
+        if let Some(artifacts) = loaded.get(formula_id).and_then(Weak::upgrade) {
+            return Ok(artifacts);
+        }
+        let artifacts = Arc::new(RedbArtifacts::open(&self.target_dir, formula_id)?);
+        loaded.insert(formula_id.to_string(), Arc::downgrade(&artifacts));
+        Ok(artifacts)
+    }
+
+    /// Loads the artifacts of `formula_id` again after a deploy, for the engines asked for
+    /// from now on. Those of the engines already made are theirs until they are dropped.
+    ///
+    /// `None` when the deploy left them unchanged, as the files loaded are still the ones
+    /// in the target dir.
+    pub fn reload(&self, formula_id: &str) -> Result<Option<Arc<RedbArtifacts>>, LiushuError> {
+        // loaded outside of the lock, engines of other formulas are made meanwhile
+        let artifacts = match RedbArtifacts::open(&self.target_dir, formula_id) {
+            Err(error) if error.is_in_use() => return Ok(None),
+            artifacts => Arc::new(artifacts?),
+        };
+        self.loaded()
+            .insert(formula_id.to_string(), Arc::downgrade(&artifacts));
+        Ok(Some(artifacts))
+    }
+
+    // an entry is either replaced or not, a panic while loading leaves the map whole
+    fn loaded(&self) -> MutexGuard<'_, HashMap<String, Weak<RedbArtifacts>>> {
+        let mut loaded = self
+            .loaded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        loaded.retain(|_, artifacts| artifacts.strong_count() > 0);
+        loaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::dict::{build, BuildOptions};
+    use crate::engine::{EngineWithRedb, InputMethodEngine};
+    use crate::progress::NoProgress;
+
+    fn build_words(dir: &Path, id: &str, words: &str) {
+        let input = dir.join(format!("{}.tsv", id));
+        fs::write(&input, format!("text\tcode\tweight\n{}", words)).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[input], dir, id, options, &NoProgress).unwrap();
+    }
+
+    fn texts(engine: &EngineWithRedb, code: &str) -> Vec<String> {
+        let items = engine.search(code).unwrap();
+        items.into_iter().map(|item| item.text).collect()
+    }
+
+    #[test]
+    fn test_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        build_words(dir.path(), "fixture", "你\tni\t1\n");
+        build_words(dir.path(), "other", "尼\tni\t1\n");
+        let store = ArtifactStore::new(dir.path());
+
+        let first = EngineWithRedb::from_artifacts(store.get("fixture").unwrap());
+        let second = EngineWithRedb::from_artifacts(store.get("fixture").unwrap());
+        assert!(Arc::ptr_eq(first.artifacts(), second.artifacts()));
+        // opened on its own, the redb dictionary is locked by the store
+        let error = EngineWithRedb::with_formula(dir.path(), "fixture")
+            .err()
+            .unwrap();
+        assert!(error.is_in_use());
+
+        let other = EngineWithRedb::from_artifacts(store.get("other").unwrap());
+        assert!(!Arc::ptr_eq(first.artifacts(), other.artifacts()));
+        assert_eq!(texts(&other, "ni"), ["尼"]);
+
+        // closed with the last engine
+        drop((first, second));
+        assert!(EngineWithRedb::with_formula(dir.path(), "fixture").is_ok());
+        assert!(matches!(
+            store.get("nothing"),
+            Err(LiushuError::ArtifactMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        build_words(dir.path(), "fixture", "你\tni\t1\n");
+        let store = ArtifactStore::new(dir.path());
+        let engine = EngineWithRedb::from_artifacts(store.get("fixture").unwrap());
+        assert!(store.reload("fixture").unwrap().is_none());
+
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let searcher = scope.spawn(|| {
+                let mut searches = 0;
+                while !stop.load(Ordering::SeqCst) || searches == 0 {
+                    assert_eq!(texts(&engine, "ni"), ["你"]);
+                    searches += 1;
+                }
+            });
+            for i in 0..5 {
+                build_words(dir.path(), "fixture", &format!("尼\tni\t{}\n", i));
+                let reloaded = store.reload("fixture").unwrap().unwrap();
+                assert!(Arc::ptr_eq(&reloaded, &store.get("fixture").unwrap()));
+            }
+            stop.store(true, Ordering::SeqCst);
+            searcher.join().unwrap();
+        });
+
+        let reloaded = EngineWithRedb::from_artifacts(store.get("fixture").unwrap());
+        assert!(!Arc::ptr_eq(engine.artifacts(), reloaded.artifacts()));
+        assert_eq!(texts(&reloaded, "ni"), ["尼"]);
+        assert_eq!(texts(&engine, "ni"), ["你"]);
+    }
+}
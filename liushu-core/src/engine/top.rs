@@ -0,0 +1,176 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+
+use super::{ArtifactReader, EngineWithRedb, SearchResultItem};
+use crate::artifact::DictItem;
+use crate::error::LiushuError;
+
+impl EngineWithRedb {
+    /// The `n` texts of the greatest weights, each with its shortest code, for a frontend to
+    /// answer the first keystrokes with before a server does. The heaviest come first, texts
+    /// of the same weight in bytewise order, and the codes of a text as long as each other
+    /// in bytewise order too.
+    ///
+    /// Only `n` entries of the dictionary are held at a time, while it is read in order.
+    pub fn top_entries(&self, n: usize) -> Result<Vec<SearchResultItem>, LiushuError> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        // the last of those ranked on top, the first to give up for a better one
+        let mut top: BinaryHeap<Ranked> = BinaryHeap::with_capacity(n + 1);
+        // the shortest code of each text on top, the first in bytewise order of those as
+        // short, as codes are read in that order
+        let mut codes: HashMap<String, String> = HashMap::with_capacity(n + 1);
+        for entry in &ArtifactReader::from_artifacts(self.artifacts.clone()) {
+            let DictItem {
+                text,
+                code,
+                weight,
+                comment,
+            } = entry?;
+            if let Some(shortest) = codes.get_mut(&text) {
+                if code.len() < shortest.len() {
+                    *shortest = code;
+                }
+                continue;
+            }
+            // a text given up is never ranked back, its other codes weigh the same
+            if top.len() == n
+                && top
+                    .peek()
+                    .is_some_and(|last| last.rank((weight, &text)) != Ordering::Greater)
+            {
+                continue;
+            }
+            codes.insert(text.clone(), code);
+            top.push(Ranked {
+                weight,
+                text,
+                comment,
+            });
+            if top.len() > n {
+                if let Some(last) = top.pop() {
+                    codes.remove(&last.text);
+                }
+            }
+        }
+        Ok(top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| SearchResultItem {
+                code: codes.remove(&ranked.text).unwrap_or_default(),
+                text: ranked.text,
+                weight: ranked.weight,
+                comment: ranked.comment,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            })
+            .collect())
+    }
+}
+
+/// An entry of the dictionary, less than those ranked after it.
+struct Ranked {
+    weight: u64,
+    text: String,
+    comment: Option<String>,
+}
+
+impl Ranked {
+    /// How this entry ranks against `other`, `Less` when it comes first.
+    fn rank(&self, (weight, text): (u64, &str)) -> Ordering {
+        (Reverse(self.weight), self.text.as_str()).cmp(&(Reverse(weight), text))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank((other.weight, &other.text))
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::fs;
+
+    use crate::dict::{build, BuildOptions};
+    use crate::progress::NoProgress;
+
+    use super::*;
+
+    #[test]
+    fn test_top_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut words = String::from("text\tcode\tweight\tcomment\n");
+        // weights a xorshift draws from few, for many ties
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        for i in 0..200u32 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let text = char::from_u32(0x4e00 + i).unwrap();
+            words.push_str(&format!("{}\tc{}\t{}\t\n", text, i, state % 20));
+        }
+        // the shortest code, and the first in bytewise order of those as short
+        words.push_str("好\thaox\t100\t〔女子〕\nhao\tzzz\t0\t\n好\thb\t100\t\n好\tha\t100\t\n");
+        let path = dir.path().join("words.tsv");
+        fs::write(&path, &words).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[path], dir.path(), "top", options, &NoProgress).unwrap();
+        let engine = EngineWithRedb::with_formula(&dir, "top").unwrap();
+
+        // ranked by sorting them all, of the last row of each text and its codes
+        let mut entries: HashMap<&str, SearchResultItem> = HashMap::new();
+        for row in words.lines().skip(1) {
+            let [text, code, weight, comment] = row.split('\t').collect::<Vec<_>>()[..] else {
+                panic!("{:?}", row);
+            };
+            let entry = entries.entry(text).or_insert_with(|| SearchResultItem {
+                text: text.to_string(),
+                code: code.to_string(),
+                weight: 0,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            });
+            if (code.len(), code) < (entry.code.len(), entry.code.as_str()) {
+                entry.code = code.to_string();
+            }
+            entry.weight = weight.parse().unwrap();
+            entry.comment = Some(comment.to_string()).filter(|c| !c.is_empty());
+        }
+        let mut all: Vec<SearchResultItem> = entries.into_values().collect();
+        all.sort_by(|a, b| (Reverse(a.weight), &a.text).cmp(&(Reverse(b.weight), &b.text)));
+        assert_eq!(all.len(), 202);
+
+        for n in [0, 1, 2, 7, 50, 201, 202, 500] {
+            assert_eq!(
+                engine.top_entries(n).unwrap(),
+                all[..n.min(all.len())],
+                "top {}",
+                n
+            );
+        }
+        let first = &engine.top_entries(1).unwrap()[0];
+        assert_eq!((first.text.as_str(), first.code.as_str()), ("好", "ha"));
+    }
+}
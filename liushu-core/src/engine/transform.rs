@@ -0,0 +1,466 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{EngineCapabilities, InputMethodEngine, PrefixInfo, SearchResultItem, WarmUp};
+use crate::error::LiushuError;
+
+/// Input longer than this is never transformed, so that a transformer stays cheap whatever
+/// is typed.
+const MAX_INPUT_LEN: usize = 64;
+
+/// Candidates made of the input itself rather than found in a dictionary, such as a date
+/// written out or the result of a calculation.
+///
+/// A transformer is run on every search of input outside the alphabet of the formula, so it
+/// is cheap, and input it can't make anything of only gets no candidates.
+pub trait Transformer: Send + Sync {
+    fn transform(&self, input: &str) -> Vec<SearchResultItem>;
+}
+
+/// The transformers of a formula, and the alphabet of its codes, input outside of which is
+/// given to them.
+pub struct Transformers {
+    alphabet: String,
+    transformers: Vec<Box<dyn Transformer>>,
+}
+
+impl Transformers {
+    pub fn new(alphabet: &str, transformers: Vec<Box<dyn Transformer>>) -> Self {
+        Self {
+            alphabet: alphabet.to_string(),
+            transformers,
+        }
+    }
+
+    /// The candidates of every transformer, in order, of input with a character outside of
+    /// the alphabet.
+    pub fn transform(&self, input: &str) -> Vec<SearchResultItem> {
+        if input.len() > MAX_INPUT_LEN || input.chars().all(|c| self.alphabet.contains(c)) {
+            return Vec::new();
+        }
+        self.transformers
+            .iter()
+            .flat_map(|transformer| transformer.transform(input))
+            .collect()
+    }
+}
+
+/// An engine whose candidates are followed by those of [`Transformers`].
+pub struct TransformedEngine<E> {
+    inner: E,
+    transformers: Arc<Transformers>,
+}
+
+impl<E: InputMethodEngine> TransformedEngine<E> {
+    pub fn new(inner: E, transformers: Arc<Transformers>) -> Self {
+        Self {
+            inner,
+            transformers,
+        }
+    }
+}
+
+impl<E: InputMethodEngine> InputMethodEngine for TransformedEngine<E> {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = Vec::new();
+        self.search_into(code, &mut items)?;
+        Ok(items)
+    }
+
+    fn search_into(
+        &self,
+        code: &str,
+        items: &mut Vec<SearchResultItem>,
+    ) -> Result<(), LiushuError> {
+        self.inner.search_into(code, items)?;
+        items.extend(self.transformers.transform(code));
+        Ok(())
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.search_in_context(code, context)?;
+        items.extend(self.transformers.transform(code));
+        Ok(items)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+fn candidate(input: &str, text: String) -> SearchResultItem {
+    SearchResultItem {
+        text,
+        code: input.to_string(),
+        weight: 0,
+        comment: None,
+        formula: None,
+        user_count: None,
+        is_exact: false,
+    }
+}
+
+/// Dates typed with numbers, `2024/5/1`, `2024-05-01`, `2024.5`, or `5/1`, written out
+/// in Chinese: `2024年5月1日`, `２０２４年５月１日`, `二〇二四年五月一日`, and `2024-05-01`
+/// for those with a year, month and day.
+pub struct DateFormatter;
+
+impl DateFormatter {
+    /// The year, month and day of `input`, the year or the day left out of it.
+    fn parse(input: &str) -> Option<(Option<u32>, u32, Option<u32>)> {
+        let separator = input.chars().find(|c| matches!(c, '/' | '-' | '.'))?;
+        let parts: Vec<&str> = input.split(separator).collect();
+        if parts.iter().any(|part| {
+            part.is_empty() || part.len() > 4 || !part.bytes().all(|b| b.is_ascii_digit())
+        }) {
+            return None;
+        }
+        let numbers: Vec<u32> = parts.iter().filter_map(|part| part.parse().ok()).collect();
+        let (year, month, day) = match (&parts[..], &numbers[..]) {
+            ([y, _, _], &[year, month, day]) if y.len() == 4 => (Some(year), month, Some(day)),
+            ([y, m], &[year, month]) if y.len() == 4 && m.len() <= 2 => (Some(year), month, None),
+            ([m, d], &[month, day]) if m.len() <= 2 && d.len() <= 2 => (None, month, Some(day)),
+            _ => return None,
+        };
+        if !(1..=12).contains(&month) {
+            return None;
+        }
+        if let Some(day) = day {
+            // the 29th of February of no year in particular is fine
+            let leap =
+                year.is_none_or(|year| year % 4 == 0 && (year % 100 != 0 || year % 400 == 0));
+            let days = match month {
+                2 if leap => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                _ => 31,
+            };
+            if !(1..=days).contains(&day) {
+                return None;
+            }
+        }
+        Some((year, month, day))
+    }
+
+    fn write(
+        (year, month, day): (Option<u32>, u32, Option<u32>),
+        number: impl Fn(u32, bool) -> String,
+    ) -> String {
+        let mut text = String::new();
+        if let Some(year) = year {
+            let _ = write!(text, "{}年", number(year, true));
+        }
+        let _ = write!(text, "{}月", number(month, false));
+        if let Some(day) = day {
+            let _ = write!(text, "{}日", number(day, false));
+        }
+        text
+    }
+}
+
+impl Transformer for DateFormatter {
+    fn transform(&self, input: &str) -> Vec<SearchResultItem> {
+        let Some(date) = Self::parse(input) else {
+            return Vec::new();
+        };
+        let mut texts = vec![
+            Self::write(date, |n, _| n.to_string()),
+            Self::write(date, |n, _| n.to_string().chars().map(full_width).collect()),
+            Self::write(date, chinese_number),
+        ];
+        if let (Some(year), month, Some(day)) = date {
+            texts.push(format!("{:04}-{:02}-{:02}", year, month, day));
+        }
+        texts
+            .into_iter()
+            .map(|text| candidate(input, text))
+            .collect()
+    }
+}
+
+fn full_width(digit: char) -> char {
+    char::from_u32(digit as u32 - '0' as u32 + '０' as u32).unwrap_or(digit)
+}
+
+/// `n` in Chinese numerals, digit by digit for a year, `二〇二四`, and as a count otherwise,
+/// `三十一`.
+fn chinese_number(n: u32, digits: bool) -> String {
+    const DIGITS: [char; 10] = ['〇', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+    let digit = |d: u32| DIGITS[d as usize % 10];
+    if digits {
+        return n
+            .to_string()
+            .bytes()
+            .map(|b| digit((b - b'0') as u32))
+            .collect();
+    }
+    match (n / 10, n % 10) {
+        (0, ones) => digit(ones).to_string(),
+        (tens, ones) => {
+            let mut text = String::new();
+            if tens > 1 {
+                text.push(digit(tens));
+            }
+            text.push('十');
+            if ones > 0 {
+                text.push(digit(ones));
+            }
+            text
+        }
+    }
+}
+
+/// Arithmetic typed after an `=`: `=3*7` makes `21` and `3*7=21`. Numbers may have
+/// decimals, and `+`, `-`, `*`, `/` and parentheses mean what they do everywhere.
+pub struct Calculator;
+
+/// Parentheses nested deeper than this are given up on.
+const MAX_DEPTH: usize = 16;
+
+impl Calculator {
+    fn evaluate(expression: &str) -> Option<f64> {
+        let tokens: Vec<char> = expression.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut parser = Parser { tokens, at: 0 };
+        let value = parser.expression(0)?;
+        (parser.at == parser.tokens.len() && value.is_finite()).then_some(value)
+    }
+
+    fn format(value: f64) -> String {
+        if value.fract() == 0.0 && value.abs() < 1e15 {
+            return format!("{}", value as i64);
+        }
+        let text = format!("{:.10}", value);
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+impl Transformer for Calculator {
+    fn transform(&self, input: &str) -> Vec<SearchResultItem> {
+        let Some(expression) = input.strip_prefix('=') else {
+            return Vec::new();
+        };
+        let Some(value) = Self::evaluate(expression) else {
+            return Vec::new();
+        };
+        let result = Self::format(value);
+        vec![
+            candidate(input, result.clone()),
+            candidate(input, format!("{}={}", expression.trim(), result)),
+        ]
+    }
+}
+
+/// A recursive descent over the characters of an expression.
+struct Parser {
+    tokens: Vec<char>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.tokens.get(self.at).copied()
+    }
+
+    fn expression(&mut self, depth: usize) -> Option<f64> {
+        let mut value = self.term(depth)?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.at += 1;
+            let rhs = self.term(depth)?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Some(value)
+    }
+
+    fn term(&mut self, depth: usize) -> Option<f64> {
+        let mut value = self.factor(depth)?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.at += 1;
+            let rhs = self.factor(depth)?;
+            value = if op == '*' {
+                value * rhs
+            } else if rhs != 0.0 {
+                value / rhs
+            } else {
+                return None;
+            };
+        }
+        Some(value)
+    }
+
+    fn factor(&mut self, depth: usize) -> Option<f64> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match self.peek()? {
+            '-' => {
+                self.at += 1;
+                self.factor(depth + 1).map(|value| -value)
+            }
+            '(' => {
+                self.at += 1;
+                let value = self.expression(depth + 1)?;
+                (self.peek() == Some(')')).then(|| {
+                    self.at += 1;
+                    value
+                })
+            }
+            _ => {
+                let start = self.at;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.at += 1;
+                }
+                let number: String = self.tokens[start..self.at].iter().collect();
+                number.parse().ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    fn texts(transformer: &dyn Transformer, input: &str) -> Vec<String> {
+        transformer
+            .transform(input)
+            .into_iter()
+            .map(|item| item.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(
+            texts(&DateFormatter, "2024/5/1"),
+            [
+                "2024年5月1日",
+                "２０２４年５月１日",
+                "二〇二四年五月一日",
+                "2024-05-01"
+            ]
+        );
+        assert_eq!(
+            texts(&DateFormatter, "2024-12-31")[2],
+            "二〇二四年十二月三十一日"
+        );
+        assert_eq!(
+            texts(&DateFormatter, "2024.10"),
+            ["2024年10月", "２０２４年１０月", "二〇二四年十月"]
+        );
+        assert_eq!(texts(&DateFormatter, "2/29")[2], "二月二十九日");
+        assert_eq!(texts(&DateFormatter, "2024/2/29").len(), 4);
+        for input in [
+            "2023/2/29",
+            "2024/13/1",
+            "2024/4/31",
+            "2024/0/1",
+            "1/2/3",
+            "2024/5/1/2",
+            "2024-5/1",
+            "5/",
+            "/5",
+            "24/5/1",
+            "123/4",
+            "a/b",
+            "12345/1/1",
+            "2024",
+        ] {
+            assert!(DateFormatter.transform(input).is_empty(), "{}", input);
+        }
+        let item = &DateFormatter.transform("5/1")[0];
+        assert_eq!((item.code.as_str(), item.weight), ("5/1", 0));
+    }
+
+    #[test]
+    fn test_calculator() {
+        assert_eq!(texts(&Calculator, "=3*7"), ["21", "3*7=21"]);
+        assert_eq!(texts(&Calculator, "=1+2*3")[0], "7");
+        assert_eq!(texts(&Calculator, "=(1+2)*3")[0], "9");
+        assert_eq!(texts(&Calculator, "= 10 / 4 ")[..], ["2.5", "10 / 4=2.5"]);
+        assert_eq!(texts(&Calculator, "=1/3")[0], "0.3333333333");
+        assert_eq!(texts(&Calculator, "=-2--3")[0], "1");
+        assert_eq!(texts(&Calculator, "=0.1+0.2")[0], "0.3");
+        let nested = format!("={}1{}", "(".repeat(40), ")".repeat(40));
+        for input in [
+            "3*7", "=", "=1/0", "=1+", "=(1", "=1)", "=2x3", "=1..2", &nested,
+        ] {
+            assert!(Calculator.transform(input).is_empty(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_transformed_engine() {
+        let engine: MemoryEngine = [SearchResultItem {
+            text: "你".to_string(),
+            code: "ni".to_string(),
+            weight: 1,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        }]
+        .into_iter()
+        .collect();
+        let transformers = Transformers::new(
+            "abcdefghijklmnopqrstuvwxyz",
+            vec![Box::new(DateFormatter), Box::new(Calculator)],
+        );
+        let engine = TransformedEngine::new(engine, Arc::new(transformers));
+        let texts = |code: &str| -> Vec<String> {
+            let items = engine.search(code).unwrap();
+            items.into_iter().map(|item| item.text).collect()
+        };
+        assert_eq!(texts("ni"), ["你"]);
+        assert_eq!(texts("=2*3"), ["6", "2*3=6"]);
+        // made of the code rather than found under it
+        assert!(engine.search("ni").unwrap()[0].is_exact);
+        assert!(engine
+            .search("=2*3")
+            .unwrap()
+            .iter()
+            .all(|item| !item.is_exact));
+        assert_eq!(texts("5/1")[0], "5月1日");
+        assert!(texts("=ni").is_empty());
+        // input of the alphabet is never transformed, nor is input too long
+        let transformers = Transformers::new("0123456789=*", vec![Box::new(Calculator)]);
+        assert!(transformers.transform("=2*3").is_empty());
+        let long = format!("={}", "1+".repeat(40) + "1");
+        assert!(Transformers::new("", vec![Box::new(Calculator)])
+            .transform(&long)
+            .is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+#[cfg(any(feature = "runtime", feature = "sqlite-engine"))]
+use std::time::Instant;
+
+#[cfg(feature = "runtime")]
+use redb::ReadableTable;
+#[cfg(feature = "sqlite-engine")]
+use rusqlite::{params, OptionalExtension};
+
+#[cfg(feature = "sqlite-engine")]
+use super::ShapeCodeEngine;
+#[cfg(feature = "runtime")]
+use super::{EngineWithRedb, RedbArtifacts};
+#[cfg(feature = "runtime")]
+use crate::artifact::{read_redb, DICTIONARY};
+#[cfg(any(feature = "runtime", feature = "sqlite-engine"))]
+use crate::error::LiushuError;
+
+/// Codes an engine of redb artifacts looks up to warm up, spread over its trie.
+#[cfg(feature = "runtime")]
+const WARM_CODES: usize = 256;
+
+/// Rows of the sqlite dictionary read at a time to warm it up.
+#[cfg(feature = "sqlite-engine")]
+const WARM_ROWS: i64 = 4096;
+
+/// What [`InputMethodEngine::warm_up`](super::InputMethodEngine::warm_up) managed within
+/// its budget.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WarmUp {
+    /// Lookups done, each of a code or of a chunk of rows.
+    pub warmed: usize,
+    /// Of those the engine would do without a budget.
+    pub total: usize,
+    pub elapsed: Duration,
+}
+
+impl WarmUp {
+    /// Whether the budget was enough for every lookup.
+    pub fn is_complete(&self) -> bool {
+        self.warmed == self.total
+    }
+
+    /// Runs `lookup` for each of `total` steps until `budget` is spent, which is checked
+    /// before each of them, so that only the last one runs past it.
+    #[cfg(any(feature = "runtime", feature = "sqlite-engine"))]
+    fn run(
+        total: usize,
+        budget: Duration,
+        mut lookup: impl FnMut(usize) -> Result<(), LiushuError>,
+    ) -> Result<Self, LiushuError> {
+        let start = Instant::now();
+        let mut warmed = 0;
+        while warmed < total && start.elapsed() < budget {
+            lookup(warmed)?;
+            warmed += 1;
+        }
+        Ok(Self {
+            warmed,
+            total,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl EngineWithRedb {
+    /// Looks up the texts of codes spread evenly over the trie, each in a read transaction
+    /// of its own like a search, rather than searching prefixes, which could read a whole
+    /// huge dictionary at once.
+    pub(super) fn warm_up_redb(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        let RedbArtifacts {
+            db, db_path, trie, ..
+        } = &*self.artifacts;
+        let total = trie.len().min(WARM_CODES);
+        let stride = trie.len().div_ceil(WARM_CODES).max(1);
+        let mut keys = trie.iter().step_by(stride);
+        WarmUp::run(total, budget, |_| {
+            let Some((_, texts)) = keys.next() else {
+                return Ok(());
+            };
+            read_redb(db_path, || {
+                let tx = db.begin_read()?;
+                let dictionary = tx.open_table(DICTIONARY)?;
+                for text in texts {
+                    dictionary.get(text.as_str())?;
+                }
+                Ok(())
+            })
+        })
+    }
+}
+
+#[cfg(feature = "sqlite-engine")]
+impl ShapeCodeEngine {
+    /// Reads the rows in chunks by id, as every search scans the whole table.
+    pub(super) fn warm_up_sqlite(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        let conn = self.conn();
+        let last: Option<i64> = conn
+            .query_row("SELECT max(id) FROM dict", [], |row| row.get(0))
+            .optional()?
+            .flatten();
+        let chunks = (last.unwrap_or(0).max(0) + WARM_ROWS - 1) / WARM_ROWS;
+        let mut stmt = conn.prepare_cached(
+            "SELECT count(text), sum(length(code)), sum(weight) FROM dict WHERE id > ?1 AND id <= ?1 + ?2",
+        )?;
+        WarmUp::run(chunks as usize, budget, |chunk| {
+            stmt.query_row(params![chunk as i64 * WARM_ROWS, WARM_ROWS], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::dict::{build, BuildOptions, CREATE_DICT_TABLE_SQL};
+    use crate::engine::{EngineManager, InputMethodEngine, MemoryEngine, SearchCache};
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn test_warm_up_redb() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut words = String::from("text\tcode\tweight\n");
+        for i in 0..1000u32 {
+            let text = char::from_u32(0x4e00 + i).unwrap();
+            words.push_str(&format!("{}\tc{:04}\t{}\n", text, i, i));
+        }
+        let path = dir.path().join("words.tsv");
+        fs::write(&path, words).unwrap();
+        let options = BuildOptions {
+            force: true,
+            ..Default::default()
+        };
+        build(&[path], dir.path(), "warm", options, &NoProgress).unwrap();
+        let engine = EngineWithRedb::with_formula(&dir, "warm").unwrap();
+
+        let warm_up = engine.warm_up(Duration::from_secs(60)).unwrap();
+        assert_eq!((warm_up.warmed, warm_up.total), (WARM_CODES, WARM_CODES));
+        assert!(warm_up.is_complete());
+        // nothing past the budget
+        let warm_up = engine.warm_up(Duration::ZERO).unwrap();
+        assert_eq!((warm_up.warmed, warm_up.total), (0, WARM_CODES));
+        assert!(!warm_up.is_complete());
+
+        // through the engines wrapping it
+        let engine = EngineManager::from([
+            Box::new(SearchCache::new(engine, "warm", 8)) as Box<dyn InputMethodEngine>
+        ]);
+        assert!(engine
+            .warm_up(Duration::from_secs(60))
+            .unwrap()
+            .is_complete());
+        let memory: MemoryEngine = std::iter::empty().collect();
+        assert_eq!(
+            memory.warm_up(Duration::from_secs(60)).unwrap(),
+            WarmUp::default()
+        );
+    }
+
+    #[test]
+    fn test_warm_up_sqlite() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(CREATE_DICT_TABLE_SQL, ()).unwrap();
+        let engine = ShapeCodeEngine::new(conn);
+        assert!(engine.warm_up(Duration::ZERO).unwrap().is_complete());
+
+        let conn = engine.conn();
+        for i in 0..WARM_ROWS + 1 {
+            conn.execute(
+                "INSERT INTO dict (text, code, weight) VALUES (?1, ?2, 1)",
+                params![format!("t{}", i), format!("c{}", i)],
+            )
+            .unwrap();
+        }
+        drop(conn);
+        let warm_up = engine.warm_up(Duration::from_secs(60)).unwrap();
+        assert_eq!((warm_up.warmed, warm_up.total), (2, 2));
+        assert_eq!(engine.warm_up(Duration::ZERO).unwrap().warmed, 0);
+    }
+}
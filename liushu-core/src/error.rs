@@ -1,37 +1,641 @@
+//! Errors of liushu, each with a stable code for machine-readable output and frontends
+//! that translate the messages:
+//!
+//! | Code                     | Error                                                   |
+//! |--------------------------|---------------------------------------------------------|
+//! | `E_OTHER`                | anything else                                           |
+//! | `E_CONFIG`               | the config can't be loaded                              |
+//! | `E_DICT_PARSE`           | a row of a dictionary or model export is malformed      |
+//! | `E_INPUT_MISSING`        | a dictionary or corpus isn't there                      |
+//! | `E_FORMULA_NOT_DEPLOYED` | an artifact isn't there, as nothing was deployed yet    |
+//! | `E_ARTIFACT_CORRUPT`     | an artifact can't be loaded or fails verification       |
+//! | `E_FORMULA_UNKNOWN`      | the config has no formula with the id                   |
+//! | `E_FORMULA_DISABLED`     | the formula is disabled by the config or its overlay    |
+//! | `E_INVALID_INPUT`        | options or input that make no sense                     |
+//! | `E_IO`                   | reading or writing a file failed                        |
+//! | `E_DB`                   | the database failed                                     |
+//! | `E_INSUFFICIENT_SPACE`   | not enough free space for the outputs                   |
+//! | `E_NOT_WRITABLE`         | the output dir can't be written                         |
+//! | `E_PROTOCOL`             | a request out of turn on the server protocol            |
+//! | `E_LOCKED`               | another process is deploying to the target dir          |
+//! | `E_READ_ONLY`            | a write to a database opened read-only                  |
+//! | `E_CANCELLED`            | a deploy or build was cancelled before it was done      |
+//! | `E_SCHEMA_TOO_NEW`       | a database was written by a newer liushu                |
+//! | `E_HOOK_FAILED`          | the post-deploy hook exited with an error               |
+
+#[cfg(feature = "native")]
+use std::ffi::OsStr;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use thiserror::Error;
 
+#[cfg(feature = "native")]
+use crate::{deploy::BACKUP_DIR, hmm::MODEL_FILE};
+
+/// The error a variant wraps, kept so that [`std::error::Error::source`] reaches it.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The message of a variant never repeats its source, [`LiushuError::report`] adds them.
 #[derive(Error, Debug)]
 pub enum LiushuError {
+    /// What fits none of the other kinds.
     #[error("{0}")]
     Other(String),
+    #[error("config error{}", in_path(.path))]
+    Config {
+        path: Option<PathBuf>,
+        source: BoxError,
+    },
+    /// A row of a dictionary, counting lines from 1 with the header, or a group of words of
+    /// a phrase library, counting them from 1 after the header.
+    #[error("{}:{line}", .file.display())]
+    DictParse {
+        file: PathBuf,
+        line: u64,
+        source: BoxError,
+    },
+    /// An input, such as a dictionary or a corpus, that isn't there.
+    #[error("missing {}", .0.display())]
+    Missing(PathBuf),
+    /// A file written by liushu that isn't there, usually as nothing was deployed yet.
+    #[error("missing artifact {}", .0.display())]
+    ArtifactMissing(PathBuf),
+    #[error("corrupt artifact {}", .path.display())]
+    ArtifactCorrupt { path: PathBuf, source: BoxError },
+    #[error("unknown formula {0}")]
+    FormulaUnknown(String),
+    #[error("the formula {0} is disabled")]
+    FormulaDisabled(String),
+    /// Options or input that make no sense, whatever the files say.
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("{}", io_message(.path, .operation))]
+    Io {
+        /// The file being worked on, see [`IoResultExt`].
+        path: Option<PathBuf>,
+        /// What was being done, such as `open dictionary`.
+        operation: Option<String>,
+        source: BoxError,
+    },
+    #[error("{engine} error")]
+    Db {
+        engine: &'static str,
+        source: BoxError,
+    },
+    #[error("not enough space: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("{} is not writable", .0.display())]
+    NotWritable(PathBuf),
+    /// A request the [server protocol](crate::server) doesn't take at that point, such as
+    /// one before `initialize` or with a version the server doesn't speak.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// A target dir another process is writing, see [`dirs::lock`](crate::dirs::lock).
+    #[error("another liushu process is deploying to {}", .0.display())]
+    Locked(PathBuf),
+    /// A write to a database opened read-only, see
+    /// [`ArtifactOpenOptions`](crate::artifact::ArtifactOpenOptions).
+    #[error("{} is open read-only", .0.display())]
+    ReadOnly(PathBuf),
+    /// A job stopped as its [`ProgressSink`](crate::progress::ProgressSink) was cancelled,
+    /// leaving what it replaces as it was.
+    #[error("cancelled")]
+    Cancelled,
+    /// A database whose schema a newer liushu migrated it to, which this one can't read.
+    #[error("{} has schema version {version}, this liushu reads up to {supported}", .path.display())]
+    SchemaTooNew {
+        path: PathBuf,
+        version: u64,
+        supported: u64,
+    },
+    /// A post-deploy hook that ran but didn't succeed, with what it wrote to stderr.
+    #[error("the post-deploy hook {command} failed with {status}: {stderr}")]
+    HookFailed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
 }
 
-impl From<rusqlite::Error> for LiushuError {
-    fn from(value: rusqlite::Error) -> Self {
-        LiushuError::Other(format!("sqlite error: {}", value))
+fn in_path(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map_or(String::new(), |path| format!(" in {}", path.display()))
+}
+
+fn io_message(path: &Option<PathBuf>, operation: &Option<String>) -> String {
+    match (path, operation) {
+        (Some(path), operation) => format!(
+            "failed to {} ‘{}’",
+            operation.as_deref().unwrap_or("access"),
+            path.display()
+        ),
+        (None, Some(operation)) => format!("io error: {}", operation),
+        (None, None) => "io error".to_string(),
+    }
+}
+
+impl LiushuError {
+    /// An io error with what was being done, which `?` can't tell.
+    pub fn io(operation: impl Into<String>, cause: impl Into<BoxError>) -> Self {
+        LiushuError::Io {
+            path: None,
+            operation: Some(operation.into()),
+            source: cause.into(),
+        }
+    }
+
+    /// An io error about `path`, for failures that aren't an [`io::Error`] such as writing
+    /// a trie.
+    pub fn io_at(operation: &str, path: impl AsRef<Path>, cause: impl Into<BoxError>) -> Self {
+        LiushuError::Io {
+            path: Some(path.as_ref().to_path_buf()),
+            operation: Some(operation.to_string()),
+            source: cause.into(),
+        }
+    }
+
+    /// A dictionary row of `file` that can't be read. The source of a row that doesn't
+    /// deserialize is the field that doesn't, as the line is already in the message.
+    #[cfg(feature = "dict-build")]
+    pub fn dict_parse(file: &Path, error: csv::Error) -> Self {
+        let line = error.position().map_or(0, |position| position.line());
+        let source: BoxError = match error.kind() {
+            csv::ErrorKind::Deserialize { .. } => match error.into_kind() {
+                csv::ErrorKind::Deserialize { err, .. } => Box::new(err),
+                _ => unreachable!(),
+            },
+            _ => Box::new(error),
+        };
+        LiushuError::DictParse {
+            file: file.to_path_buf(),
+            line,
+            source,
+        }
+    }
+
+    /// The message followed by those of its sources, as in
+    /// `failed to open trie ‘sunman.trie’: No such file or directory (os error 2)`.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            report.push_str(": ");
+            report.push_str(&error.to_string());
+            source = error.source();
+        }
+        report
+    }
+
+    /// Stable identifier of the error kind, listed in the [module docs](self).
+    pub fn code(&self) -> &'static str {
+        match self {
+            LiushuError::Other(_) => "E_OTHER",
+            LiushuError::Config { .. } => "E_CONFIG",
+            LiushuError::DictParse { .. } => "E_DICT_PARSE",
+            LiushuError::Missing(_) => "E_INPUT_MISSING",
+            LiushuError::ArtifactMissing(_) => "E_FORMULA_NOT_DEPLOYED",
+            LiushuError::ArtifactCorrupt { .. } => "E_ARTIFACT_CORRUPT",
+            LiushuError::FormulaUnknown(_) => "E_FORMULA_UNKNOWN",
+            LiushuError::FormulaDisabled(_) => "E_FORMULA_DISABLED",
+            LiushuError::InvalidInput(_) => "E_INVALID_INPUT",
+            LiushuError::Io { .. } => "E_IO",
+            LiushuError::Db { .. } => "E_DB",
+            LiushuError::InsufficientSpace { .. } => "E_INSUFFICIENT_SPACE",
+            LiushuError::NotWritable(_) => "E_NOT_WRITABLE",
+            LiushuError::Protocol(_) => "E_PROTOCOL",
+            LiushuError::Locked(_) => "E_LOCKED",
+            LiushuError::ReadOnly(_) => "E_READ_ONLY",
+            LiushuError::Cancelled => "E_CANCELLED",
+            LiushuError::SchemaTooNew { .. } => "E_SCHEMA_TOO_NEW",
+            LiushuError::HookFailed { .. } => "E_HOOK_FAILED",
+        }
+    }
+
+    /// What the user can do about the error, when there is something to suggest.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            LiushuError::Config { .. } => Some(
+                "fix main.dhall in the config dir, `liushu repl --auto` installs a starter one",
+            ),
+            #[cfg(feature = "dict-build")]
+            LiushuError::DictParse { file, .. } if crate::dict::import::is_scel(file) => {
+                Some("the phrase library is likely truncated, export it again")
+            }
+            LiushuError::DictParse { .. } => {
+                Some("each row needs a text, a code and a numeric weight separated by tabs")
+            }
+            LiushuError::Missing(_) => Some("check the dictionaries of the config"),
+            #[cfg(feature = "native")]
+            LiushuError::ArtifactMissing(path)
+                if path.file_name() == Some(OsStr::new(MODEL_FILE)) =>
+            {
+                Some("run `liushu train` to build the model")
+            }
+            #[cfg(feature = "native")]
+            LiushuError::ArtifactMissing(path)
+                if path.components().any(|c| c.as_os_str() == BACKUP_DIR) =>
+            {
+                Some("run `liushu status` to list the backups")
+            }
+            LiushuError::ArtifactMissing(_) => Some("run `liushu deploy` to build this formula"),
+            LiushuError::ArtifactCorrupt { .. } => {
+                Some("run `liushu clean` then `liushu deploy` to rebuild it")
+            }
+            LiushuError::FormulaUnknown(_) => {
+                Some("run `liushu status` to list the formulas of the config")
+            }
+            LiushuError::FormulaDisabled(_) => {
+                Some("it was set `enabled = False`, run `liushu formula enable` with its id")
+            }
+            LiushuError::InsufficientSpace { .. } => {
+                Some("free some space, or use another dir with `--profile`")
+            }
+            LiushuError::NotWritable(_) => {
+                Some("check the permissions of the dir, or use another one with `--profile`")
+            }
+            LiushuError::Protocol(_) => {
+                Some("send `initialize` first, with a protocol version the server supports")
+            }
+            LiushuError::Locked(_) => Some("wait for it to finish, or pass `--wait`"),
+            LiushuError::SchemaTooNew { .. } => Some("upgrade liushu to open it"),
+            LiushuError::HookFailed { .. } => {
+                Some("fix the hook, or set `failOnHookError = False` to only warn about it")
+            }
+            LiushuError::Other(_)
+            | LiushuError::InvalidInput(_)
+            | LiushuError::Io { .. }
+            | LiushuError::Db { .. }
+            | LiushuError::ReadOnly(_)
+            | LiushuError::Cancelled => None,
+        }
+    }
+
+    /// The file the error is about, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            LiushuError::Config { path, .. } => path.as_deref(),
+            LiushuError::DictParse { file: path, .. }
+            | LiushuError::ArtifactCorrupt { path, .. }
+            | LiushuError::Missing(path)
+            | LiushuError::ArtifactMissing(path)
+            | LiushuError::NotWritable(path)
+            | LiushuError::Locked(path)
+            | LiushuError::ReadOnly(path)
+            | LiushuError::SchemaTooNew { path, .. } => Some(path),
+            LiushuError::Io { path, .. } => path.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Process exit code for the error kind:
+    ///
+    /// - 1: any other error
+    /// - 2: the config could not be loaded
+    /// - 3: a dictionary, compiled artifact or formula is missing, or the formula disabled
+    /// - 4: reading or writing a file failed, or would for lack of space or permissions
+    /// - 5: a dictionary or compiled artifact is malformed, or written by a newer liushu
+    /// - 6: another process is deploying to the target dir
+    /// - 130: the job was cancelled, as an interrupted process would exit
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LiushuError::Other(_)
+            | LiushuError::InvalidInput(_)
+            | LiushuError::Db { .. }
+            | LiushuError::Protocol(_)
+            | LiushuError::HookFailed { .. } => 1,
+            LiushuError::Config { .. } => 2,
+            LiushuError::Missing(_)
+            | LiushuError::ArtifactMissing(_)
+            | LiushuError::FormulaUnknown(_)
+            | LiushuError::FormulaDisabled(_) => 3,
+            LiushuError::Io { .. }
+            | LiushuError::InsufficientSpace { .. }
+            | LiushuError::NotWritable(_)
+            | LiushuError::ReadOnly(_) => 4,
+            LiushuError::DictParse { .. }
+            | LiushuError::ArtifactCorrupt { .. }
+            | LiushuError::SchemaTooNew { .. } => 5,
+            LiushuError::Locked(_) => 6,
+            LiushuError::Cancelled => 130,
+        }
+    }
+}
+
+impl Serialize for LiushuError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("LiushuError", 4)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.report())?;
+        state.serialize_field("hint", &self.hint())?;
+        state.serialize_field("path", &self.path())?;
+        state.end()
     }
 }
 
-impl From<csv::Error> for LiushuError {
-    fn from(value: csv::Error) -> Self {
-        LiushuError::Other(format!("csv error: {}", value))
+#[cfg(feature = "sqlite-engine")]
+impl From<rusqlite::Error> for LiushuError {
+    fn from(value: rusqlite::Error) -> Self {
+        LiushuError::Db {
+            engine: "sqlite",
+            source: Box::new(value),
+        }
     }
 }
 
+#[cfg(feature = "runtime")]
 impl From<redb::Error> for LiushuError {
     fn from(value: redb::Error) -> Self {
-        LiushuError::Other(format!("redb error: {}", value))
+        LiushuError::Db {
+            engine: "redb",
+            source: Box::new(value),
+        }
     }
 }
 
-impl From<bincode::Error> for LiushuError {
-    fn from(value: bincode::Error) -> Self {
-        LiushuError::Other(format!("bincode error: {}", value))
+#[cfg(feature = "runtime")]
+impl LiushuError {
+    /// Whether a redb database couldn't be opened because it already is, which redb allows
+    /// once per file. A file replaced by another deploy has been opened by none.
+    pub(crate) fn is_in_use(&self) -> bool {
+        match self {
+            LiushuError::Db {
+                engine: "redb",
+                source,
+            } => matches!(
+                source.downcast_ref::<redb::Error>(),
+                Some(redb::Error::DatabaseAlreadyOpen)
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "dhall-config")]
+impl From<serde_dhall::Error> for LiushuError {
+    fn from(value: serde_dhall::Error) -> Self {
+        LiushuError::Config {
+            path: None,
+            source: Box::new(value),
+        }
     }
 }
 
 impl From<std::io::Error> for LiushuError {
     fn from(value: std::io::Error) -> Self {
-        LiushuError::Other(format!("io error: {}", value))
+        LiushuError::Io {
+            path: None,
+            operation: None,
+            source: Box::new(value),
+        }
+    }
+}
+
+/// Adds the file an io failure is about, which `?` can't tell.
+pub trait IoResultExt<T> {
+    /// Fails with a message like "failed to `operation` ‘`path`’: `cause`", so `operation`
+    /// is a verb phrase such as `open dictionary`.
+    fn with_path(self, operation: &str, path: impl AsRef<Path>) -> Result<T, LiushuError>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_path(self, operation: &str, path: impl AsRef<Path>) -> Result<T, LiushuError> {
+        self.map_err(|e| LiushuError::io_at(operation, path, e))
+    }
+}
+
+#[cfg(feature = "runtime")]
+/// Only the io errors of redb are about the file, a corrupt file is an
+/// [`LiushuError::ArtifactCorrupt`] as the databases are all written by liushu.
+impl<T> IoResultExt<T> for Result<T, redb::Error> {
+    fn with_path(self, operation: &str, path: impl AsRef<Path>) -> Result<T, LiushuError> {
+        self.map_err(|e| match e {
+            redb::Error::Io(e) => LiushuError::io_at(operation, path, e),
+            redb::Error::Corrupted(_) | redb::Error::UpgradeRequired(_) => {
+                LiushuError::ArtifactCorrupt {
+                    path: path.as_ref().to_path_buf(),
+                    source: Box::new(e),
+                }
+            }
+            e => e.into(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::{collections::HashSet, fs};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        dict::{build, BuildOptions},
+        engine::EngineWithRedb,
+        hmm::Smoothing,
+        progress::NoProgress,
+    };
+
+    #[test]
+    fn test_failure_variants() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.dhall");
+        let error = Config::load_from_path(&main).unwrap_err();
+        assert!(matches!(&error, LiushuError::Config { path: Some(path), .. } if *path == main));
+        assert_eq!(error.path(), Some(main.as_path()));
+
+        fs::write(&main, "{ formulas = [] : List { id : Text, name : Optional Text, dictionaries : List Text } }").unwrap();
+        let error = Config::load_from_path(&main)
+            .unwrap()
+            .formula("sunman")
+            .unwrap_err();
+        assert!(matches!(&error, LiushuError::FormulaUnknown(id) if id == "sunman"));
+        assert_eq!(error.exit_code(), 3);
+
+        let words = dir.path().join("words.tsv");
+        let build = |inputs: &[PathBuf]| {
+            let options = BuildOptions {
+                force: true,
+                ..Default::default()
+            };
+            build(inputs, dir.path(), "fixture", options, &NoProgress).unwrap_err()
+        };
+        let error = build(std::slice::from_ref(&words));
+        assert!(matches!(&error, LiushuError::Missing(path) if *path == words));
+        fs::write(&words, "text\tcode\tweight\n你\tni\t1\n好\thao\theavy\n").unwrap();
+        let error = build(std::slice::from_ref(&words));
+        assert!(matches!(&error, LiushuError::DictParse { line: 3, .. }));
+        assert_eq!(error.to_string(), format!("{}:3", words.display()));
+        assert!(error
+            .report()
+            .starts_with(&format!("{}:3: ", words.display())));
+        assert_eq!(error.exit_code(), 5);
+
+        let error = "add-k=heavy".parse::<Smoothing>().unwrap_err();
+        assert!(matches!(error, LiushuError::InvalidInput(_)));
+    }
+
+    #[test]
+    fn test_paths_in_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.dhall");
+        let error = Config::load_from_path(&main).unwrap_err();
+        assert!(error.to_string().contains(&main.display().to_string()));
+
+        fs::write(
+            &main,
+            r#"{ formulas = [{ id = "sunman", name = None Text, dictionaries = ["words.tsv"] }] }"#,
+        )
+        .unwrap();
+        let words = dir.path().join("sunman").join("words.tsv");
+        let error = Config::load_from_path(&main).unwrap().formulas[0]
+            .compile2(dir.path(), dir.path())
+            .unwrap_err();
+        assert_eq!(error.to_string(), format!("missing {}", words.display()));
+
+        fs::create_dir(dir.path().join("sunman")).unwrap();
+        fs::write(&words, "text\tcode\tweight\n你\tni\t1\n").unwrap();
+        build(
+            &[words],
+            dir.path(),
+            "sunman",
+            BuildOptions {
+                force: true,
+                ..Default::default()
+            },
+            &NoProgress,
+        )
+        .unwrap();
+        let trie = dir.path().join("sunman.trie");
+        fs::remove_file(&trie).unwrap();
+        let error = EngineWithRedb::with_formula(dir.path(), "sunman")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains(&trie.display().to_string()));
+
+        let error = fs::read(&trie).with_path("open trie", &trie).unwrap_err();
+        assert!(error
+            .report()
+            .starts_with(&format!("failed to open trie ‘{}’: ", trie.display())));
+        assert_eq!(error.path(), Some(trie.as_path()));
+    }
+
+    #[test]
+    fn test_codes() {
+        let errors = [
+            LiushuError::Other("boom".to_string()),
+            LiushuError::Config {
+                path: None,
+                source: "boom".into(),
+            },
+            LiushuError::DictParse {
+                file: "words.tsv".into(),
+                line: 1,
+                source: "boom".into(),
+            },
+            LiushuError::Missing("words.tsv".into()),
+            LiushuError::ArtifactMissing("sunman.trie".into()),
+            LiushuError::ArtifactCorrupt {
+                path: "sunman.trie".into(),
+                source: "boom".into(),
+            },
+            LiushuError::FormulaUnknown("sunman".to_string()),
+            LiushuError::FormulaDisabled("sunman".to_string()),
+            LiushuError::InvalidInput("boom".to_string()),
+            LiushuError::io("boom", "boom"),
+            LiushuError::Db {
+                engine: "redb",
+                source: "boom".into(),
+            },
+            LiushuError::InsufficientSpace {
+                needed: 2,
+                available: 1,
+            },
+            LiushuError::NotWritable("target".into()),
+            LiushuError::Protocol("boom".to_string()),
+            LiushuError::SchemaTooNew {
+                path: "userdict.redb".into(),
+                version: 4,
+                supported: 3,
+            },
+        ];
+        let codes: HashSet<&str> = errors.iter().map(LiushuError::code).collect();
+        assert_eq!(codes.len(), errors.len());
+        for code in codes {
+            assert!(code.starts_with("E_") && code.len() > 2);
+            assert!(include_str!("error.rs").contains(&format!("//! | `{}`", code)));
+        }
+        for error in &errors {
+            assert!(error.hint().is_none_or(|hint| !hint.is_empty()));
+        }
+
+        let model = Path::new("data").join(MODEL_FILE);
+        assert!(LiushuError::ArtifactMissing(model)
+            .hint()
+            .unwrap()
+            .contains("train"));
+        let backup = Path::new("target").join(BACKUP_DIR).join("1");
+        assert!(LiushuError::ArtifactMissing(backup)
+            .hint()
+            .unwrap()
+            .contains("status"));
+    }
+
+    #[test]
+    fn test_source_chain() {
+        let chain = |error: &LiushuError| {
+            let mut chain = vec![error.to_string()];
+            let mut source = std::error::Error::source(error);
+            while let Some(error) = source {
+                chain.push(error.to_string());
+                source = error.source();
+            }
+            chain
+        };
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let error = LiushuError::from(
+            conn.execute("INSERT INTO nothing VALUES (1)", [])
+                .unwrap_err(),
+        );
+        let source = std::error::Error::source(&error).unwrap();
+        assert!(source.downcast_ref::<rusqlite::Error>().is_some());
+        assert_eq!(chain(&error)[0], "sqlite error");
+        assert!(chain(&error).len() >= 2);
+
+        let trie = Path::new("missing").join("sunman.trie");
+        let error = fs::File::open(&trie)
+            .with_path("open trie", &trie)
+            .unwrap_err();
+        let chain = chain(&error);
+        assert_eq!(chain.len(), 2);
+        assert!(!chain[0].contains(&chain[1]));
+        assert_eq!(error.report(), chain.join(": "));
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(
+            source.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let error = LiushuError::io("cannot open the terminal", "not a tty");
+        assert_eq!(
+            serde_json::to_value(&error).unwrap()["message"],
+            "io error: cannot open the terminal: not a tty"
+        );
+        assert_eq!(
+            serde_json::to_value(LiushuError::ArtifactMissing("x.redb".into())).unwrap(),
+            serde_json::json!({
+                "code": "E_FORMULA_NOT_DEPLOYED",
+                "message": "missing artifact x.redb",
+                "hint": "run `liushu deploy` to build this formula",
+                "path": "x.redb"
+            })
+        );
     }
 }
@@ -1,276 +1,148 @@
+mod context;
+mod corpus;
+mod eval;
+mod model;
 mod pinyin;
+mod portable;
+mod train;
 
 use std::collections::HashMap;
-use std::f64::consts::E;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::path::PathBuf;
 
 use itertools::Itertools;
-use redb::{Database, ReadOnlyTable, ReadableTable, TableDefinition};
-use regex::Regex;
-
-use self::pinyin::{py_split, ToPinyin, POSIBLE_PINYINS};
+use redb::{Database, ReadOnlyTable, ReadTransaction, ReadableTable, TableDefinition};
+
+pub use self::context::RerankedEngine;
+pub use self::corpus::{normalize_width, Preprocess, SkippedLines};
+pub use self::eval::{evaluate, ConversionReport, EvalOptions, EvalReport};
+pub use self::model::{EmissionSource, Granularity, Model, ModelInfo, Smoothing, Transitions};
+use self::pinyin::{py_split, POSIBLE_PINYINS};
+pub use self::portable::{export_model, import_model};
+pub use self::train::{
+    corpus_files, prune, train, train_with_progress, PruneOptions, PruneReport, TrainOptions,
+    TrainReport,
+};
 use crate::{
     engine::{InputMethodEngine, SearchResultItem},
     error::LiushuError,
 };
 
+pub const MODEL_FILE: &str = "hmm_model.redb";
+
 const INIT_TABLE: TableDefinition<&str, f64> = TableDefinition::new("init_prob");
 const TRANS_TABLE: TableDefinition<(&str, &str), f64> = TableDefinition::new("trans_prob");
+/// Keyed by `post`, the total count of the transitions into it and the number of characters
+/// it follows, for smoothing.
+const TRANS_TOTALS: TableDefinition<&str, (u64, u64)> = TableDefinition::new("trans_total");
 const EMISS_TABLE: TableDefinition<(&str, &str), f64> = TableDefinition::new("emiss_prob");
+/// Keyed by `(post, pre, prepre)`, only written by models of order 3.
+const TRIGRAM_TABLE: TableDefinition<(&str, &str, &str), f64> =
+    TableDefinition::new("trigram_prob");
 const PINYIN_STATES: TableDefinition<&str, &str> = TableDefinition::new("pinyin_states");
+/// Words of a word model kept in its vocabulary, with their counts.
+const WORD_VOCAB: TableDefinition<&str, u64> = TableDefinition::new("word_vocab");
+/// Keyed by `(post, pre)` words with the words out of the vocabulary as [`UNK`], normalized
+/// over `pre` unlike the character transitions.
+const WORD_TRANS_TABLE: TableDefinition<(&str, &str), f64> =
+    TableDefinition::new("word_trans_prob");
+/// Stands for every word out of the vocabulary of a word model.
+const UNK: &str = "UNK";
+/// Holds the n-gram `order` and the number of trained `sequences`.
+const META_TABLE: TableDefinition<&str, u64> = TableDefinition::new("meta");
 const MIN_F: f64 = -3.14e100;
+/// Number of best `(pre, current)` states kept at each step of the trigram search.
+const BEAM_WIDTH: usize = 64;
 
-pub fn train(corpus_file: impl AsRef<Path>, save_to: impl AsRef<Path>) {
-    let chinese_re = Regex::new(r#"[\u4e00-\u9fa5]{2,}"#).unwrap();
-    let mut file = File::open(corpus_file).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-
-    let mut seqs = Vec::new();
-    for seq in chinese_re.find_iter(&contents) {
-        seqs.push(seq.as_str().to_string());
-    }
-
-    let db = Database::create(save_to).unwrap();
-    count_init(&db, &seqs);
-    count_trans(&db, &seqs);
-    count_emission(&db, &seqs);
-    count_pinyin_states(&db);
-}
-
-fn count_init(db: &Database, seqs: &Vec<String>) {
-    let mut temp_table: HashMap<String, u64> = HashMap::new();
-    let mut num = 0;
-    let len = seqs.len();
-
-    for seq in seqs {
-        num += 1;
-        if num % 10000 == 0 {
-            println!("{}/{}", num, len);
-        }
-        if seq.is_empty() {
-            continue;
-        }
-
-        let first_char = seq.chars().next().unwrap().to_string();
-        if let Some(value) = temp_table.get_mut(&first_char) {
-            *value = value.to_owned() + 1;
-        } else {
-            temp_table.insert(first_char, 1);
-        }
-    }
-
-    let write_txn = db.begin_write().unwrap();
-    {
-        let mut table = write_txn.open_table(INIT_TABLE).unwrap();
-        for (key, value) in temp_table {
-            let value = (value as f64 / len as f64).log(E);
-            table.insert(&key.as_str(), value).unwrap();
-        }
-    }
-    write_txn.commit().unwrap();
-}
-
-fn count_trans(db: &Database, seqs: &Vec<String>) {
-    let mut temp: HashMap<String, HashMap<String, u64>> = HashMap::new();
-    let mut num = 0;
-    let len = seqs.len();
-
-    for seq in seqs {
-        num += 1;
-        if num % 10000 == 0 {
-            println!("{}/{}", num, len);
-        }
-        if seq.is_empty() {
-            continue;
-        }
-
-        let mut chars: Vec<String> = seq.chars().map(|c| c.to_string()).collect();
-        chars.insert(0, "BOS".to_string());
-        chars.push("EOS".to_string());
-
-        for (index, post) in chars.iter().enumerate() {
-            if index == 0 {
-                continue;
-            }
-
-            let pre = chars[index - 1].clone();
-            if temp.get(post.as_str()).is_none() {
-                temp.insert(post.to_owned(), HashMap::new());
-            }
-            let key = temp.get_mut(post.as_str()).unwrap();
-            let pre_ = pre.clone();
-            (*key).insert(pre, key.get(pre_.as_str()).unwrap_or(&0).to_owned() + 1);
-        }
-    }
-
-    let write_txn = db.begin_write().unwrap();
-    {
-        let mut table = write_txn.open_table(TRANS_TABLE).unwrap();
-        for (post, value) in temp {
-            let total = value.values().sum::<u64>();
-            for (pre, count) in value {
-                let prob = (count as f64 / total as f64).log(E);
-                table.insert((post.as_str(), pre.as_str()), prob).unwrap();
-            }
-        }
-    }
-    write_txn.commit().unwrap();
+#[derive(Debug)]
+pub struct Hmm {
+    model: Model,
+    backoff: f64,
 }
 
-fn count_emission(db: &Database, seqs: &Vec<String>) {
-    let mut temp: HashMap<String, HashMap<String, u64>> = HashMap::new();
-    let mut num = 0;
-    let len = seqs.len();
-
-    for seq in seqs {
-        num += 1;
-        if num % 10000 == 0 {
-            println!("{}/{}", num, len);
-        }
-        if seq.is_empty() {
-            continue;
-        }
-
-        let pinyin = seq.as_str().to_pinyin();
-        let zip_iter = pinyin.zip(seq.chars());
-        for (py, word) in zip_iter {
-            if temp.get(word.to_string().as_str()).is_none() {
-                temp.insert(word.to_string(), HashMap::new());
-            }
-            let key = temp.get_mut(word.to_string().as_str()).unwrap();
-            let py_str = py.unwrap().plain();
-            (*key).insert(
-                py_str.to_string(),
-                key.get(py_str).unwrap_or(&0).to_owned() + 1,
-            );
-        }
+impl Hmm {
+    pub fn new(db: Database, path: impl Into<PathBuf>) -> Self {
+        Self::with_model(Model::new(db, path))
     }
 
-    let write_txn = db.begin_write().unwrap();
-    {
-        let mut table = write_txn.open_table(EMISS_TABLE).unwrap();
-        for (word, pinyins) in temp {
-            let total = pinyins.values().sum::<u64>();
-            for (py, count) in pinyins {
-                let prob = (count as f64 / total as f64).log(E);
-                table.insert((word.as_str(), py.as_str()), prob).unwrap();
-            }
+    pub fn with_model(model: Model) -> Self {
+        Self {
+            model,
+            backoff: 0.4,
         }
     }
-    write_txn.commit().unwrap();
-}
 
-fn count_pinyin_states(db: &Database) {
-    let read_txn = db.begin_read().unwrap();
-    let write_txn = db.begin_write().unwrap();
-    {
-        let emission_table = read_txn.open_table(EMISS_TABLE).unwrap();
-        let mut pinyin_states_table = write_txn.open_table(PINYIN_STATES).unwrap();
-        for (key, _) in emission_table.iter().unwrap() {
-            let (word, py) = key.value().to_owned();
-            let mut words = pinyin_states_table
-                .get(py)
-                .unwrap()
-                .map(|x| x.value().to_string())
-                .unwrap_or("".to_string());
-            words.push_str(word);
-            pinyin_states_table.insert(py, words.as_str()).unwrap();
-        }
+    pub fn model(&self) -> &Model {
+        &self.model
     }
-    write_txn.commit().unwrap();
-}
-
-#[derive(Debug)]
-pub struct Hmm {
-    db: Database,
-}
 
-impl Hmm {
-    pub fn new(db: Database) -> Self {
-        Self { db }
+    /// Weight of the bigram probability when interpolating with trigrams, used alone when
+    /// the trigram is missing.
+    pub fn with_backoff(self, backoff: f64) -> Self {
+        Self { backoff, ..self }
     }
 
+    /// First-order Viterbi, a pinyin the model doesn't know has no states so ends with no
+    /// candidates.
     pub fn viterbi(
-        pinyin_list: &Vec<String>,
+        pinyin_list: &[String],
         pinyin_states: &ReadOnlyTable<&str, &str>,
         init_prob: &ReadOnlyTable<&str, f64>,
-        trans_prob: &ReadOnlyTable<(&str, &str), f64>,
+        transitions: &Transitions,
         emiss_prob: &ReadOnlyTable<(&str, &str), f64>,
-    ) -> Vec<(String, f64)> {
+    ) -> Result<Vec<(String, f64)>, LiushuError> {
         let length = pinyin_list.len();
-        let mut viterbi: HashMap<usize, HashMap<String, (f64, String)>> = HashMap::new();
-        for i in 0..length {
-            viterbi.insert(i, HashMap::new());
+        if length == 0 {
+            return Ok(vec![]);
         }
-
-        let key = pinyin_list[0].as_str();
-        let chars = pinyin_states.get(key).unwrap().unwrap();
-        for s in chars.value().chars() {
-            let p = viterbi.get_mut(&0).unwrap();
-            let init = init_prob
-                .get(s.to_string().as_str())
-                .unwrap()
-                .map(|x| x.value())
-                .unwrap_or(MIN_F);
-            let emiss = emiss_prob
-                .get((s.to_string().as_str(), pinyin_list[0].as_str()))
-                .unwrap()
-                .map(|x| x.value())
-                .unwrap_or(MIN_F);
-            p.insert(s.to_string(), (init + emiss, "".to_string()));
+        let states = |py: &str| -> Result<Vec<String>, LiushuError> {
+            Ok(pinyin_states
+                .get(py)?
+                .map(|v| v.value().chars().map(String::from).collect())
+                .unwrap_or_default())
+        };
+        let emission = |word: &str, py: &str| -> Result<f64, LiushuError> {
+            Ok(emiss_prob.get((word, py))?.map_or(MIN_F, |v| v.value()))
+        };
+        let mut viterbi: Vec<HashMap<String, (f64, String)>> = vec![HashMap::new(); length];
+
+        for s in states(&pinyin_list[0])? {
+            let init = init_prob.get(s.as_str())?.map_or(MIN_F, |x| x.value());
+            let emiss = emission(&s, &pinyin_list[0])?;
+            viterbi[0].insert(s, (init + emiss, "".to_string()));
         }
 
         for i in 0..(length - 1) {
-            let key = pinyin_list[i + 1].as_str();
-            let chars = pinyin_states.get(key).unwrap().unwrap();
-            for s in chars.value().chars() {
-                let value = pinyin_states
-                    .get(pinyin_list[i].as_str())
-                    .unwrap()
-                    .unwrap()
-                    .value()
-                    .chars()
-                    .map(|c| {
-                        let vit = viterbi[&i][c.to_string().as_str()].0;
-                        let emission = emiss_prob
-                            .get((s.to_string().as_str(), pinyin_list[i + 1].as_str()))
-                            .unwrap()
-                            .map(|e| e.value())
-                            .unwrap_or(MIN_F);
-                        let trans = trans_prob
-                            .get((s.to_string().as_str(), c.to_string().as_str()))
-                            .unwrap()
-                            .map(|t| t.value())
-                            .unwrap_or(MIN_F);
-                        (vit + emission + trans, c.to_string())
-                    })
-                    .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
-                    .unwrap();
-                let p = viterbi.get_mut(&(i + 1)).unwrap();
-                p.insert(s.to_string(), value);
+            let previous = states(&pinyin_list[i])?;
+            for s in states(&pinyin_list[i + 1])? {
+                let emiss = emission(&s, &pinyin_list[i + 1])?;
+                // the last of the best, as `max_by` would
+                let mut best: Option<(f64, String)> = None;
+                for c in &previous {
+                    let Some((vit, _)) = viterbi[i].get(c) else {
+                        continue;
+                    };
+                    let trans = transitions.get(c, &s)?.unwrap_or(MIN_F);
+                    let score = vit + emiss + trans;
+                    if best
+                        .as_ref()
+                        .is_none_or(|(best, _)| best.total_cmp(&score).is_le())
+                    {
+                        best = Some((score, c.clone()));
+                    }
+                }
+                if let Some(best) = best {
+                    viterbi[i + 1].insert(s, best);
+                }
             }
         }
 
-        let key = pinyin_list.last().unwrap().as_str();
-        let last = pinyin_states.get(key).unwrap().unwrap();
-        for s in last.value().chars() {
-            let old = &viterbi[&(length - 1)][s.to_string().as_str()];
-            let trans = trans_prob
-                .get(("EOS", s.to_string().as_str()))
-                .unwrap()
-                .map(|x| x.value())
-                .unwrap_or(MIN_F);
-            let new_value = (old.0 + trans, old.1.clone());
-            let p = viterbi.get_mut(&(length - 1)).unwrap();
-            p.insert(s.to_string(), new_value);
+        for (s, (score, _)) in viterbi[length - 1].iter_mut() {
+            *score += transitions.get(s, "EOS")?.unwrap_or(MIN_F);
         }
 
-        viterbi[&(length - 1)]
+        Ok(viterbi[length - 1]
             .iter()
-            .sorted_by(|a, b| a.1 .0.partial_cmp(&b.1 .0).unwrap())
+            .sorted_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
             .rev()
             .map(|data| {
                 let mut words = vec!["".to_string(); length];
@@ -280,7 +152,9 @@ impl Hmm {
                 }
 
                 for n in (0..(length - 1)).rev() {
-                    let current = &viterbi[&(n + 1)][words[n + 1].to_string().as_str()];
+                    let Some(current) = viterbi[n + 1].get(&words[n + 1]) else {
+                        break;
+                    };
                     words[n] = current.1.clone();
                     weight += current.0;
                 }
@@ -288,29 +162,179 @@ impl Hmm {
                 (words.join(""), weight)
             })
             .take(10)
-            .collect_vec()
+            .collect_vec())
+    }
+
+    /// Second-order Viterbi over `(pre, current)` states, interpolating trigram and bigram
+    /// transition probabilities.
+    pub fn viterbi3(
+        pinyin_list: &[String],
+        pinyin_states: &ReadOnlyTable<&str, &str>,
+        init_prob: &ReadOnlyTable<&str, f64>,
+        transitions: &Transitions,
+        trigram_prob: &ReadOnlyTable<(&str, &str, &str), f64>,
+        emiss_prob: &ReadOnlyTable<(&str, &str), f64>,
+        backoff: f64,
+    ) -> Result<Vec<(String, f64)>, LiushuError> {
+        struct Node {
+            pre: String,
+            current: String,
+            score: f64,
+            /// Index of the previous node in the previous layer.
+            back: usize,
+        }
+
+        let transition = |post: &str, pre: &str, prepre: &str| -> Result<f64, LiushuError> {
+            let trigram = trigram_prob.get((post, pre, prepre))?.map(|v| v.value());
+            let bigram = transitions.get(pre, post)?;
+            Ok(interpolate(trigram, bigram, backoff))
+        };
+        let emission = |word: &str, py: &str| -> Result<f64, LiushuError> {
+            Ok(emiss_prob.get((word, py))?.map_or(MIN_F, |v| v.value()))
+        };
+        let states = |py: &str| -> Result<Vec<String>, LiushuError> {
+            Ok(pinyin_states
+                .get(py)?
+                .map(|v| v.value().chars().map(String::from).collect())
+                .unwrap_or_default())
+        };
+
+        let Some(first_pinyin) = pinyin_list.first() else {
+            return Ok(vec![]);
+        };
+        let mut first = Vec::new();
+        for s in states(first_pinyin)? {
+            let init = init_prob.get(s.as_str())?.map_or(MIN_F, |v| v.value());
+            first.push(Node {
+                score: init + emission(&s, first_pinyin)?,
+                pre: "BOS".to_string(),
+                current: s,
+                back: 0,
+            });
+        }
+        let mut layers = vec![first];
+
+        for py in &pinyin_list[1..] {
+            let prev = layers.last().unwrap();
+            let mut best: HashMap<(String, String), Node> = HashMap::new();
+            for s in states(py)? {
+                let emiss = emission(&s, py)?;
+                for (back, node) in prev.iter().enumerate() {
+                    let score = node.score + transition(&s, &node.current, &node.pre)? + emiss;
+                    let key = (node.current.clone(), s.clone());
+                    if best.get(&key).is_none_or(|best| best.score < score) {
+                        let node = Node {
+                            pre: node.current.clone(),
+                            current: s.clone(),
+                            score,
+                            back,
+                        };
+                        best.insert(key, node);
+                    }
+                }
+            }
+            let mut layer: Vec<Node> = best.into_values().collect();
+            layer.sort_by(|a, b| b.score.total_cmp(&a.score));
+            layer.truncate(BEAM_WIDTH);
+            layers.push(layer);
+        }
+
+        let mut ends = Vec::new();
+        for (index, node) in layers.last().unwrap().iter().enumerate() {
+            let score = node.score + transition("EOS", &node.current, &node.pre)?;
+            ends.push((index, score));
+        }
+        ends.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(ends
+            .into_iter()
+            .take(10)
+            .map(|(mut index, score)| {
+                let mut words = Vec::with_capacity(layers.len());
+                for layer in layers.iter().rev() {
+                    words.push(layer[index].current.as_str());
+                    index = layer[index].back;
+                }
+                words.reverse();
+                (words.concat(), score)
+            })
+            .collect())
     }
 }
 
-impl InputMethodEngine for Hmm {
-    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
-        let possible_pinyins = py_split(code, &POSIBLE_PINYINS);
-        let mut result = Vec::new();
+/// Log probability of a transition, mixing the trigram and bigram probabilities with
+/// `backoff` as the weight of the bigram.
+fn interpolate(trigram: Option<f64>, bigram: Option<f64>, backoff: f64) -> f64 {
+    match (trigram, bigram) {
+        (Some(trigram), Some(bigram)) => {
+            ((1.0 - backoff) * trigram.exp() + backoff * bigram.exp()).ln()
+        }
+        (Some(trigram), None) => (1.0 - backoff).ln() + trigram,
+        (None, Some(bigram)) => backoff.ln() + bigram,
+        (None, None) => MIN_F,
+    }
+}
+
+impl Hmm {
+    /// Best sentences for pinyin already split into syllables, none when a syllable is not
+    /// in the model.
+    pub fn decode(&self, pinyins: &[String]) -> Result<Vec<(String, f64)>, LiushuError> {
+        let order = self.model.order()?;
+        let read_txn = self.model.db.begin_read()?;
+        self.decode_in(&read_txn, order, pinyins)
+    }
 
-        let read_txn = self.db.begin_read()?;
+    fn decode_in(
+        &self,
+        read_txn: &ReadTransaction,
+        order: u64,
+        pinyins: &[String],
+    ) -> Result<Vec<(String, f64)>, LiushuError> {
         let init_prob = read_txn.open_table(INIT_TABLE)?;
         let pinyin_states = read_txn.open_table(PINYIN_STATES)?;
-        let trans_prob = read_txn.open_table(TRANS_TABLE)?;
+        let transitions = self.model.transitions(read_txn)?;
         let emiss_prob = read_txn.open_table(EMISS_TABLE)?;
 
-        for pinyins in possible_pinyins {
-            result.push(Self::viterbi(
-                &pinyins,
+        if pinyins.is_empty()
+            || !pinyins
+                .iter()
+                .all(|py| pinyin_states.get(py.as_str()).is_ok_and(|v| v.is_some()))
+        {
+            return Ok(vec![]);
+        }
+        if order == 3 {
+            let trigram_prob = read_txn.open_table(TRIGRAM_TABLE)?;
+            Self::viterbi3(
+                pinyins,
                 &pinyin_states,
                 &init_prob,
-                &trans_prob,
+                &transitions,
+                &trigram_prob,
                 &emiss_prob,
-            ));
+                self.backoff,
+            )
+        } else {
+            Self::viterbi(
+                pinyins,
+                &pinyin_states,
+                &init_prob,
+                &transitions,
+                &emiss_prob,
+            )
+        }
+    }
+}
+
+impl InputMethodEngine for Hmm {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let possible_pinyins = py_split(code, &POSIBLE_PINYINS);
+        let mut result = Vec::new();
+
+        let order = self.model.order()?;
+        let read_txn = self.model.db.begin_read()?;
+        for pinyins in possible_pinyins {
+            // a split with a pinyin the model has never seen has no candidates
+            result.push(self.decode_in(&read_txn, order, &pinyins)?);
         }
 
         Ok(result
@@ -323,7 +347,82 @@ impl InputMethodEngine for Hmm {
                 // workaround
                 code: "".to_string(),
                 comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
             })
             .collect_vec())
     }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.rerank(context, self.search(code)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let half = 0.5_f64.ln();
+        assert!((interpolate(Some(0.0), Some(0.0), 0.4) - 0.0).abs() < 1e-9);
+        assert!((interpolate(None, Some(0.0), 0.5) - half).abs() < 1e-9);
+        assert!((interpolate(Some(0.0), None, 0.5) - half).abs() < 1e-9);
+        assert_eq!(interpolate(None, None, 0.4), MIN_F);
+    }
+
+    #[test]
+    fn test_trigram_context() {
+        // 好 is followed by 市 more often, but never after 你
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(&corpus[0], "你好事\n你好事\n很好市\n很好市\n很好市\n").unwrap();
+
+        let decode = |order| {
+            let model = dir.path().join(format!("order{}.redb", order));
+            let opts = TrainOptions {
+                append: false,
+                order,
+                ..Default::default()
+            };
+            train(&corpus, &model, opts).unwrap();
+            let hmm = Hmm::new(Database::open(&model).unwrap(), model);
+            hmm.search("nihaoshi").unwrap()[0].text.clone()
+        };
+
+        assert_eq!(decode(2), "你好市");
+        assert_eq!(decode(3), "你好事");
+    }
+
+    #[test]
+    fn test_smoothed_decode() {
+        // 世 never follows 好, and 是 follows nothing but BOS
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(&corpus[0], "你好\n世界\n世界\n是的\n").unwrap();
+        let best = |smoothing| {
+            let model = dir.path().join(format!("{}.redb", smoothing));
+            let opts = TrainOptions {
+                smoothing,
+                ..Default::default()
+            };
+            train(&corpus, &model, opts).unwrap();
+            let hmm = Hmm::new(Database::open(&model).unwrap(), model);
+            let pinyins = ["ni", "hao", "shi", "jie"].map(String::from);
+            hmm.decode(&pinyins).unwrap().swap_remove(0)
+        };
+
+        let (_, score) = best(Smoothing::None);
+        assert!(score <= MIN_F / 2.0);
+        let (text, score) = best(Smoothing::AddK(1.0));
+        assert_eq!(text, "你好世界");
+        assert!(score > MIN_F / 2.0);
+    }
 }
@@ -0,0 +1,288 @@
+use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use redb::ReadableTable;
+use tracing::warn;
+
+use super::model::{Granularity, Model};
+use super::{Hmm, MODEL_FILE, UNK, WORD_TRANS_TABLE, WORD_VOCAB};
+use crate::engine::{
+    EngineCapabilities, InputMethodEngine, PrefixInfo, RankContext, Ranker, SearchResultItem,
+    WarmUp,
+};
+use crate::error::LiushuError;
+
+/// Longest suffix of the context looked up in the vocabulary.
+const MAX_WORD_CHARS: usize = 8;
+
+/// How well a candidate follows the context, tiers rank before probabilities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Score {
+    Unknown,
+    Character(f64),
+    Word(f64),
+}
+
+impl Score {
+    fn rank(&self, other: &Self) -> Ordering {
+        let tier = |score: &Self| match score {
+            Self::Unknown => (0, 0.0),
+            Self::Character(prob) => (1, *prob),
+            Self::Word(prob) => (2, *prob),
+        };
+        let (a, b) = (tier(self), tier(other));
+        a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+    }
+}
+
+impl Hmm {
+    /// Orders `candidates` by how likely they follow `context`, the text committed right
+    /// before them.
+    ///
+    /// A word model scores the candidates of its vocabulary by the transition from the last
+    /// word of the context, the longest suffix of it in the vocabulary or `UNK`. The other
+    /// candidates, and all of them with a character model, are scored by the transition from
+    /// the last character of the context to their first one and rank after the words. The
+    /// candidates the model knows nothing about keep their order at the end.
+    pub fn rerank(
+        &self,
+        context: &str,
+        candidates: Vec<SearchResultItem>,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let scores = self.scores(context, &candidates)?;
+        let mut scored: Vec<_> = scores.into_iter().zip(candidates).collect();
+        // stable, so equal scores keep the order of the engine
+        scored.sort_by(|(a, _), (b, _)| b.rank(a));
+        Ok(scored.into_iter().map(|(_, item)| item).collect())
+    }
+
+    /// The score of each of `candidates` following `context`, all unknown without one.
+    fn scores(
+        &self,
+        context: &str,
+        candidates: &[SearchResultItem],
+    ) -> Result<Vec<Score>, LiushuError> {
+        let context: Vec<char> = context.trim_end().chars().collect();
+        let Some(last_char) = context.last().map(|c| c.to_string()) else {
+            return Ok(vec![Score::Unknown; candidates.len()]);
+        };
+        let read_txn = self.model.db.begin_read()?;
+        let transitions = self.model.transitions(&read_txn)?;
+        let words = if self.model.granularity()? == Granularity::Word {
+            let vocab = read_txn.open_table(WORD_VOCAB)?;
+            let word_trans = read_txn.open_table(WORD_TRANS_TABLE)?;
+            let mut last_word = UNK.to_string();
+            for len in (1..=context.len().min(MAX_WORD_CHARS)).rev() {
+                let word: String = context[context.len() - len..].iter().collect();
+                if vocab.get(word.as_str())?.is_some() {
+                    last_word = word;
+                    break;
+                }
+            }
+            Some((vocab, word_trans, last_word))
+        } else {
+            None
+        };
+
+        let mut scores = Vec::with_capacity(candidates.len());
+        for item in candidates {
+            let mut score = Score::Unknown;
+            if let Some((vocab, word_trans, last_word)) = &words {
+                if vocab.get(item.text.as_str())?.is_some() {
+                    if let Some(prob) = word_trans.get((item.text.as_str(), last_word.as_str()))? {
+                        score = Score::Word(prob.value());
+                    }
+                }
+            }
+            if score == Score::Unknown {
+                if let Some(first) = item.text.chars().next() {
+                    if let Some(prob) = transitions.get(&last_char, &first.to_string())? {
+                        score = Score::Character(prob);
+                    }
+                }
+            }
+            scores.push(score);
+        }
+        Ok(scores)
+    }
+}
+
+impl Hmm {
+    /// The model of `target_dir` for the `context` stage of the rankings, opened once and
+    /// shared by the engines of every formula. None while there is no model, or one that
+    /// can't be opened, which is only a warning.
+    pub fn context_ranker(target_dir: &Path) -> Option<Arc<dyn Ranker>> {
+        let path = target_dir.join(MODEL_FILE);
+        if !path.exists() {
+            return None;
+        }
+        match Model::open(&path) {
+            Ok(model) => Some(Arc::new(Self::with_model(model))),
+            Err(error) => {
+                warn!(error = %error.report(), "cannot rank by the context");
+                None
+            }
+        }
+    }
+}
+
+/// The `context` stage of a [`RankingPipeline`](crate::engine::RankingPipeline), which
+/// leaves the order as it was when the model fails.
+impl Ranker for Hmm {
+    fn rescore(&self, ctx: &RankContext<'_>, items: &mut Vec<SearchResultItem>) {
+        if ctx.context.trim_end().is_empty() {
+            return;
+        }
+        match self.scores(ctx.context, items) {
+            Ok(scores) => {
+                let mut scored: Vec<_> = scores.into_iter().zip(items.drain(..)).collect();
+                scored.sort_by(|(a, _), (b, _)| b.rank(a));
+                items.extend(scored.into_iter().map(|(_, item)| item));
+            }
+            Err(error) => warn!(error = %error.report(), "cannot rank by the context"),
+        }
+    }
+
+    fn uses_context(&self) -> bool {
+        true
+    }
+}
+
+/// An engine whose candidates are re-ranked by a model when searched in context.
+pub struct RerankedEngine {
+    inner: Box<dyn InputMethodEngine>,
+    hmm: Hmm,
+}
+
+impl RerankedEngine {
+    pub fn new(inner: Box<dyn InputMethodEngine>, hmm: Hmm) -> Self {
+        Self { inner, hmm }
+    }
+}
+
+impl InputMethodEngine for RerankedEngine {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.search(code)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.hmm
+            .rerank(context, self.inner.search_in_context(code, context)?)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        self.inner.reverse_lookup(text)
+    }
+
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        self.inner.reverse_lookup_batch(texts)
+    }
+
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.inner.associations(committed, limit)
+    }
+
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            context: true,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::hmm::{train, Model, TrainOptions};
+
+    struct Dictionary;
+    impl InputMethodEngine for Dictionary {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            Ok([
+                ("人名", "renming", 10),
+                ("人民", "renmin", 5),
+                ("好", "hao", 1),
+            ]
+            .into_iter()
+            .filter(|(_, c, _)| c.starts_with(code))
+            .map(|(text, code, weight)| SearchResultItem {
+                text: text.to_string(),
+                code: code.to_string(),
+                weight,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            })
+            .collect())
+        }
+    }
+
+    fn engine(dir: &Path, granularity: Granularity) -> RerankedEngine {
+        let corpus = [dir.join("corpus.txt")];
+        fs::write(
+            &corpus[0],
+            "中国 人民 站起来\n中国 人民\n我 的 名字\n人名 很 多\n国好\n",
+        )
+        .unwrap();
+        let path = dir.join(format!("{}.redb", granularity));
+        let opts = TrainOptions {
+            granularity,
+            ..Default::default()
+        };
+        train(&corpus, &path, opts).unwrap();
+        let hmm = Hmm::with_model(Model::open(path).unwrap());
+        RerankedEngine::new(Box::new(Dictionary), hmm)
+    }
+
+    fn texts(engine: &RerankedEngine, code: &str, context: &str) -> Vec<String> {
+        let results = engine.search_in_context(code, context).unwrap();
+        results.into_iter().map(|item| item.text).collect()
+    }
+
+    #[test]
+    fn test_word_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(dir.path(), Granularity::Word);
+        assert_eq!(texts(&engine, "ren", ""), ["人名", "人民"]);
+        // 中国 人民 is in the corpus, 中国 人名 is not
+        assert_eq!(texts(&engine, "ren", "我爱中国"), ["人民", "人名"]);
+        // 好 is out of the vocabulary, its characters rank it after the words
+        assert_eq!(texts(&engine, "", "中国"), ["人民", "好", "人名"]);
+        assert_eq!(engine.search("ren").unwrap()[0].text, "人名");
+    }
+
+    #[test]
+    fn test_character_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = engine(dir.path(), Granularity::Character);
+        // both start with 人, which never follows 国 as the words are separate runs
+        assert_eq!(texts(&engine, "ren", "我爱中国"), ["人名", "人民"]);
+        assert_eq!(texts(&engine, "", "国"), ["好", "人名", "人民"]);
+    }
+}
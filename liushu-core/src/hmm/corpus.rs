@@ -0,0 +1,366 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use serde::Serialize;
+
+/// How the lines of a corpus are cleaned before their chinese text is counted.
+#[derive(Debug, Clone)]
+pub struct Preprocess {
+    /// Map full-width ASCII and the ideographic space to their half-width forms.
+    pub normalize_width: bool,
+    /// Punctuation ending a sentence, any other punctuation is stripped so the text around
+    /// it is counted as one sequence. `None` ends a sentence at every punctuation mark.
+    pub boundaries: Option<String>,
+    /// Skip lines with fewer characters than this.
+    pub min_chars: usize,
+    /// Field holding the text of each line of `.jsonl` inputs.
+    pub json_field: String,
+}
+
+/// A run of chinese characters with the pinyin of each character.
+pub(crate) type AnnotatedSequence = (String, Vec<String>);
+
+impl Default for Preprocess {
+    fn default() -> Self {
+        Self {
+            normalize_width: true,
+            boundaries: None,
+            min_chars: 0,
+            json_field: "text".to_string(),
+        }
+    }
+}
+
+/// Lines left out of the counts, by reason.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedLines {
+    pub blank: u64,
+    pub short: u64,
+    /// `.jsonl` lines that are not an object with a string in the text field.
+    pub malformed: u64,
+    pub no_chinese: u64,
+}
+
+impl SkippedLines {
+    pub fn total(&self) -> u64 {
+        self.blank + self.short + self.malformed + self.no_chinese
+    }
+
+    pub(crate) fn add(&mut self, skip: Skip) {
+        match skip {
+            Skip::Blank => self.blank += 1,
+            Skip::Short => self.short += 1,
+            Skip::Malformed => self.malformed += 1,
+            Skip::NoChinese => self.no_chinese += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Skip {
+    Blank,
+    Short,
+    Malformed,
+    NoChinese,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CorpusFormat {
+    Text,
+    Jsonl,
+    /// `.tsv` lines of text and the space separated pinyin of each of its characters.
+    Annotated,
+}
+
+impl CorpusFormat {
+    /// Guesses the format from the file name, looking through a `.gz` suffix.
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        if name.ends_with(".txt") {
+            Some(Self::Text)
+        } else if name.ends_with(".jsonl") {
+            Some(Self::Jsonl)
+        } else if name.ends_with(".tsv") {
+            Some(Self::Annotated)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn normalize_width(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+fn is_chinese(c: char) -> bool {
+    matches!(c, '\u{4e00}'..='\u{9fa5}')
+}
+
+/// Lowercase without the tone number, with `v` for `ü`.
+fn plain_pinyin(token: &str) -> String {
+    token
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .to_lowercase()
+        .replace('ü', "v")
+}
+
+/// The runs of chinese words of a sentence segmented with whitespace, anything else ends a
+/// run.
+pub(crate) fn word_runs(sentence: &str) -> Vec<Vec<&str>> {
+    let mut runs = vec![vec![]];
+    for token in sentence.split_whitespace() {
+        if token.chars().all(is_chinese) {
+            runs.last_mut().unwrap().push(token);
+        } else if !runs.last().unwrap().is_empty() {
+            runs.push(vec![]);
+        }
+    }
+    runs.retain(|run| !run.is_empty());
+    runs
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || matches!(
+            c,
+            '\u{2010}'..='\u{206f}'
+                | '\u{3001}'..='\u{3003}'
+                | '\u{3008}'..='\u{3011}'
+                | '\u{3014}'..='\u{301f}'
+                | '\u{ff01}'..='\u{ff0f}'
+                | '\u{ff1a}'..='\u{ff20}'
+                | '\u{ff3b}'..='\u{ff40}'
+                | '\u{ff5b}'..='\u{ff65}'
+        )
+}
+
+impl Preprocess {
+    /// The cleaned text of a line, or why it is skipped.
+    pub(crate) fn text(&self, line: &str, format: CorpusFormat) -> Result<String, Skip> {
+        if line.trim().is_empty() {
+            return Err(Skip::Blank);
+        }
+        let text = match format {
+            CorpusFormat::Text | CorpusFormat::Annotated => Cow::Borrowed(line),
+            CorpusFormat::Jsonl => {
+                let value: serde_json::Value =
+                    serde_json::from_str(line).map_err(|_| Skip::Malformed)?;
+                let text = value.get(&self.json_field).and_then(|text| text.as_str());
+                Cow::Owned(text.ok_or(Skip::Malformed)?.to_string())
+            }
+        };
+        let text = if self.normalize_width {
+            normalize_width(&text)
+        } else {
+            text.into_owned()
+        };
+
+        let len = text.trim().chars().count();
+        if len == 0 {
+            Err(Skip::Blank)
+        } else if len < self.min_chars {
+            Err(Skip::Short)
+        } else {
+            Ok(text)
+        }
+    }
+
+    fn boundaries(&self) -> Option<String> {
+        self.boundaries.as_ref().map(|boundaries| {
+            if self.normalize_width {
+                normalize_width(boundaries)
+            } else {
+                boundaries.clone()
+            }
+        })
+    }
+
+    /// Splits `text` into sentences at the boundaries, dropping the other punctuation.
+    pub(crate) fn sentences(&self, text: &str) -> Vec<String> {
+        let boundaries = self.boundaries();
+        let mut sentences = vec![String::new()];
+        for c in text.chars() {
+            if !is_punctuation(c) {
+                sentences.last_mut().unwrap().push(c);
+            } else if boundaries.as_ref().is_none_or(|b| b.contains(c))
+                && !sentences.last().unwrap().is_empty()
+            {
+                sentences.push(String::new());
+            }
+        }
+        sentences.retain(|sentence| !sentence.is_empty());
+        sentences
+    }
+
+    /// The runs of at least two chinese characters of an annotated line, which has a pinyin
+    /// token for every character but whitespace. Anything but chinese ends a run, except the
+    /// punctuation stripped by the boundaries.
+    pub(crate) fn annotated(&self, line: &str) -> Result<Vec<AnnotatedSequence>, Skip> {
+        if line.trim().is_empty() {
+            return Err(Skip::Blank);
+        }
+        let (text, pinyins) = line.split_once('\t').ok_or(Skip::Malformed)?;
+        let text = if self.normalize_width {
+            normalize_width(text)
+        } else {
+            text.to_string()
+        };
+        let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let pinyins: Vec<&str> = pinyins.split_whitespace().collect();
+        if chars.len() != pinyins.len() {
+            return Err(Skip::Malformed);
+        }
+        if chars.is_empty() {
+            return Err(Skip::Blank);
+        }
+        if chars.len() < self.min_chars {
+            return Err(Skip::Short);
+        }
+
+        let boundaries = self.boundaries();
+        let mut runs = vec![(String::new(), vec![])];
+        for (c, py) in chars.into_iter().zip(pinyins) {
+            if is_chinese(c) {
+                let (text, pinyins) = runs.last_mut().unwrap();
+                text.push(c);
+                pinyins.push(plain_pinyin(py));
+            } else if !is_punctuation(c) || boundaries.as_ref().is_none_or(|b| b.contains(c)) {
+                runs.push((String::new(), vec![]));
+            }
+        }
+        runs.retain(|(_, pinyins)| pinyins.len() >= 2);
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_width() {
+        assert_eq!(
+            normalize_width("ＡＢｃ１２！\u{3000}你好，"),
+            "ABc12! 你好,"
+        );
+        assert_eq!(normalize_width("。、"), "。、");
+    }
+
+    #[test]
+    fn test_sentences() {
+        let split = Preprocess::default();
+        assert_eq!(
+            split.sentences("你好，世界。再见"),
+            ["你好", "世界", "再见"]
+        );
+        assert_eq!(split.sentences("“你好”"), ["你好"]);
+
+        let strip = Preprocess {
+            boundaries: Some("。！".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(strip.sentences("你好，世界。再见！"), ["你好世界", "再见"]);
+        // the boundaries are normalized like the text
+        assert_eq!(
+            strip.sentences(&normalize_width("你好！世界")),
+            ["你好", "世界"]
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let preprocess = Preprocess {
+            min_chars: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            preprocess.text("ＯＫ你好", CorpusFormat::Text),
+            Ok("OK你好".to_string())
+        );
+        assert_eq!(preprocess.text("  ", CorpusFormat::Text), Err(Skip::Blank));
+        assert_eq!(
+            preprocess.text("你好", CorpusFormat::Text),
+            Err(Skip::Short)
+        );
+        assert_eq!(
+            preprocess.text(r#"{"text": "你好啊"}"#, CorpusFormat::Jsonl),
+            Ok("你好啊".to_string())
+        );
+        assert_eq!(
+            preprocess.text(r#"{"body": "你好啊"}"#, CorpusFormat::Jsonl),
+            Err(Skip::Malformed)
+        );
+        assert_eq!(
+            preprocess.text("你好啊", CorpusFormat::Jsonl),
+            Err(Skip::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_annotated() {
+        let preprocess = Preprocess::default();
+        let runs = preprocess
+            .annotated("长城，很长 A\tchang2 cheng2 , hen3 chang2 a")
+            .unwrap();
+        let runs: Vec<_> = runs.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(runs, ["长城", "很长"]);
+        let strip = Preprocess {
+            boundaries: Some("。".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            strip.annotated("长城，很长\tchang2 cheng2 , hen3 chang2"),
+            Ok(vec![(
+                "长城很长".to_string(),
+                vec![
+                    "chang".to_string(),
+                    "cheng".to_string(),
+                    "hen".to_string(),
+                    "chang".to_string()
+                ]
+            )])
+        );
+        assert_eq!(
+            preprocess.annotated("绿色\tLÜ4 se4"),
+            Ok(vec![(
+                "绿色".to_string(),
+                vec!["lv".to_string(), "se".to_string()]
+            )])
+        );
+        assert_eq!(preprocess.annotated("长城\tchang"), Err(Skip::Malformed));
+        assert_eq!(preprocess.annotated("长城"), Err(Skip::Malformed));
+    }
+
+    #[test]
+    fn test_word_runs() {
+        assert_eq!(
+            word_runs("我们 喜欢  Rust 和 中文 A1"),
+            [vec!["我们", "喜欢"], vec!["和", "中文"]]
+        );
+        assert!(word_runs("hello world").is_empty());
+    }
+
+    #[test]
+    fn test_format_of() {
+        assert_eq!(
+            CorpusFormat::of(Path::new("a.txt.gz")),
+            Some(CorpusFormat::Text)
+        );
+        assert_eq!(
+            CorpusFormat::of(Path::new("a.jsonl")),
+            Some(CorpusFormat::Jsonl)
+        );
+        assert_eq!(
+            CorpusFormat::of(Path::new("a.tsv")),
+            Some(CorpusFormat::Annotated)
+        );
+        assert_eq!(CorpusFormat::of(Path::new("a.json")), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::BufRead;
+use std::iter::once;
+use std::path::Path;
+
+use redb::ReadableTable;
+use regex::Regex;
+use serde::Serialize;
+
+use super::corpus::{CorpusFormat, Preprocess, Skip, SkippedLines};
+use super::{interpolate, Hmm, TRIGRAM_TABLE};
+use crate::{
+    dict::open_input,
+    error::{IoResultExt, LiushuError},
+};
+
+#[derive(Debug, Clone)]
+pub struct EvalOptions {
+    pub preprocess: Preprocess,
+    /// Probability of a transition the model has never seen, so that a single unseen pair
+    /// of characters doesn't make the perplexity infinite.
+    pub unseen_prob: f64,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self {
+            preprocess: Preprocess::default(),
+            unseen_prob: 1e-6,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EvalReport {
+    pub lines: u64,
+    pub skipped: SkippedLines,
+    pub sequences: u64,
+    /// Predicted tokens, the characters and the end of each sequence.
+    pub tokens: u64,
+    /// Transitions priced with [`EvalOptions::unseen_prob`].
+    pub unseen: u64,
+    pub perplexity: f64,
+    /// Only for corpora annotated with pinyin.
+    pub conversion: Option<ConversionReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ConversionReport {
+    pub sentences: u64,
+    /// Sentences whose best conversion is the reference.
+    pub correct: u64,
+    pub accuracy: f64,
+    /// Characters of the best conversions matching the reference at the same position.
+    pub characters: u64,
+    pub correct_characters: u64,
+    pub character_accuracy: f64,
+}
+
+/// Perplexity of the transitions of `hmm` on a held-out corpus, and how well it converts the
+/// pinyin of an annotated one.
+pub fn evaluate(hmm: &Hmm, corpus: &Path, opts: EvalOptions) -> Result<EvalReport, LiushuError> {
+    let format = CorpusFormat::of(corpus).unwrap_or(CorpusFormat::Text);
+    let chinese_re = Regex::new(r#"[\u4e00-\u9fa5]{2,}"#).unwrap();
+    let order = hmm.model.order()?;
+    let read_txn = hmm.model.db.begin_read()?;
+    let transitions = hmm.model.transitions(&read_txn)?;
+    let trigram_prob = if order == 3 {
+        Some(read_txn.open_table(TRIGRAM_TABLE)?)
+    } else {
+        None
+    };
+    let unseen_log = opts.unseen_prob.ln();
+
+    let mut report = EvalReport {
+        lines: 0,
+        skipped: SkippedLines::default(),
+        sequences: 0,
+        tokens: 0,
+        unseen: 0,
+        perplexity: 0.0,
+        conversion: (format == CorpusFormat::Annotated).then(ConversionReport::default),
+    };
+    let mut log_prob = 0.0;
+    let mut score = |seq: &str, report: &mut EvalReport| -> Result<(), LiushuError> {
+        let (mut prepre, mut pre) = ("BOS".to_string(), "BOS".to_string());
+        for post in seq.chars().map(String::from).chain(once("EOS".to_string())) {
+            let bigram = transitions.get(&pre, &post)?;
+            let prob = match &trigram_prob {
+                Some(trigram_prob) => {
+                    let key = (post.as_str(), pre.as_str(), prepre.as_str());
+                    let trigram = trigram_prob.get(key)?.map(|v| v.value());
+                    (trigram.is_some() || bigram.is_some())
+                        .then(|| interpolate(trigram, bigram, hmm.backoff))
+                }
+                None => bigram,
+            };
+            log_prob += prob.unwrap_or_else(|| {
+                report.unseen += 1;
+                unseen_log
+            });
+            report.tokens += 1;
+            prepre = pre;
+            pre = post;
+        }
+        report.sequences += 1;
+        Ok(())
+    };
+
+    for line in open_input(corpus, "test corpus")?.lines() {
+        let line = line.with_path(&format!("read line {} of", report.lines + 1), corpus)?;
+        report.lines += 1;
+        let before = report.sequences;
+        let scored = if format == CorpusFormat::Annotated {
+            match opts.preprocess.annotated(&line) {
+                Ok(runs) => {
+                    for (seq, pinyins) in runs {
+                        score(&seq, &mut report)?;
+                        let best = hmm.decode(&pinyins)?;
+                        let best = best.first().map_or("", |(text, _)| text.as_str());
+                        let conversion = report.conversion.as_mut().unwrap();
+                        conversion.sentences += 1;
+                        conversion.correct += u64::from(best == seq);
+                        conversion.characters += seq.chars().count() as u64;
+                        conversion.correct_characters +=
+                            best.chars()
+                                .zip(seq.chars())
+                                .filter(|(a, b)| a == b)
+                                .count() as u64;
+                    }
+                    Ok(())
+                }
+                Err(skip) => Err(skip),
+            }
+        } else {
+            match opts.preprocess.text(&line, format) {
+                Ok(text) => {
+                    for sentence in opts.preprocess.sentences(&text) {
+                        for seq in chinese_re.find_iter(&sentence) {
+                            score(seq.as_str(), &mut report)?;
+                        }
+                    }
+                    Ok(())
+                }
+                Err(skip) => Err(skip),
+            }
+        };
+        match scored {
+            Ok(()) if report.sequences == before => report.skipped.add(Skip::NoChinese),
+            Ok(()) => {}
+            Err(skip) => report.skipped.add(skip),
+        }
+    }
+
+    if report.tokens == 0 {
+        return Err(LiushuError::InvalidInput(
+            "the test corpus has no chinese text to evaluate on".to_string(),
+        ));
+    }
+    report.perplexity = (-log_prob / report.tokens as f64).exp();
+    if let Some(conversion) = &mut report.conversion {
+        conversion.accuracy = ratio(conversion.correct, conversion.sentences);
+        conversion.character_accuracy = ratio(conversion.correct_characters, conversion.characters);
+    }
+    Ok(report)
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use redb::Database;
+
+    use super::*;
+    use crate::hmm::{train, TrainOptions};
+
+    fn toy_model(dir: &Path) -> Hmm {
+        // 你 and 好 only follow BOS and 你, EOS follows 好 twice and 们 once
+        let corpus = [dir.join("train.txt")];
+        fs::write(&corpus[0], "你好\n你好\n你们\n").unwrap();
+        let model = dir.join("model.redb");
+        train(&corpus, &model, TrainOptions::default()).unwrap();
+        Hmm::new(Database::open(&model).unwrap(), model)
+    }
+
+    #[test]
+    fn test_perplexity() {
+        let dir = tempfile::tempdir().unwrap();
+        let hmm = toy_model(dir.path());
+        let test = dir.path().join("test.txt");
+
+        fs::write(&test, "你好\n你们\n").unwrap();
+        let report = evaluate(&hmm, &test, EvalOptions::default()).unwrap();
+        assert_eq!((report.sequences, report.tokens, report.unseen), (2, 6, 0));
+        // ln(2/3) + ln(1/3) over 6 tokens
+        let expected = (9.0_f64 / 2.0).powf(1.0 / 6.0);
+        assert!((report.perplexity - expected).abs() < 1e-9);
+        assert!(report.conversion.is_none());
+
+        // BOS 很 and 很 好 are unseen
+        fs::write(&test, "很好\n").unwrap();
+        let opts = EvalOptions {
+            unseen_prob: 0.01,
+            ..Default::default()
+        };
+        let report = evaluate(&hmm, &test, opts).unwrap();
+        assert_eq!(report.unseen, 2);
+        let expected = (-(0.01_f64.ln() * 2.0 + (2.0_f64 / 3.0).ln()) / 3.0).exp();
+        assert!((report.perplexity - expected).abs() < 1e-9);
+
+        fs::write(&test, "hello\n").unwrap();
+        assert!(evaluate(&hmm, &test, EvalOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_conversion_accuracy() {
+        let dir = tempfile::tempdir().unwrap();
+        let hmm = toy_model(dir.path());
+        let test = dir.path().join("test.tsv");
+        // the last reference doesn't match its pinyin, which converts to 你们
+        fs::write(&test, "你好\tni hao\n你们\tni men\n你好\tni men\n").unwrap();
+
+        let report = evaluate(&hmm, &test, EvalOptions::default()).unwrap();
+        let conversion = report.conversion.unwrap();
+        assert_eq!((conversion.sentences, conversion.correct), (3, 2));
+        assert_eq!(
+            (conversion.characters, conversion.correct_characters),
+            (6, 5)
+        );
+        assert!((conversion.accuracy - 2.0 / 3.0).abs() < 1e-9);
+    }
+}
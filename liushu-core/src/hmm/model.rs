@@ -0,0 +1,619 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use redb::{Database, ReadOnlyTable, ReadTransaction, ReadableTable};
+use serde::Serialize;
+
+use super::train::TRANS_COUNTS;
+use super::{
+    EMISS_TABLE, META_TABLE, PINYIN_STATES, TRANS_TABLE, TRANS_TOTALS, TRIGRAM_TABLE, UNK,
+    WORD_TRANS_TABLE, WORD_VOCAB,
+};
+use crate::artifact::open_redb;
+use crate::error::LiushuError;
+
+/// Read access to a trained model, every probability is a natural log.
+#[derive(Debug)]
+pub struct Model {
+    pub(super) db: Database,
+    /// Where `db` is, for the errors about what it holds.
+    path: PathBuf,
+}
+
+/// Where the emission probabilities of a model come from, all of them are stored in the
+/// same table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmissionSource {
+    /// The most common reading of each character.
+    Reading,
+    /// A corpus annotated with the pinyin of every character.
+    Annotated,
+    /// The single characters of pinyin dictionaries, weighted like in the dictionaries.
+    Dictionary,
+}
+
+impl EmissionSource {
+    pub(super) fn to_meta(self) -> u64 {
+        match self {
+            Self::Reading => 0,
+            Self::Annotated => 1,
+            Self::Dictionary => 2,
+        }
+    }
+
+    fn from_meta(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Reading),
+            1 => Some(Self::Annotated),
+            2 => Some(Self::Dictionary),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for EmissionSource {
+    type Err = LiushuError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reading" => Ok(Self::Reading),
+            "annotated" => Ok(Self::Annotated),
+            "dictionary" => Ok(Self::Dictionary),
+            _ => Err(LiushuError::InvalidInput(format!(
+                "unknown emission source {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EmissionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reading => "reading",
+            Self::Annotated => "annotated",
+            Self::Dictionary => "dictionary",
+        })
+    }
+}
+
+/// Tokens the transitions of a model are counted between. Character transitions are always
+/// counted, as the pinyin of a sentence is decoded one character at a time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    #[default]
+    Character,
+    /// Also counts the transitions between the words of a corpus segmented with spaces.
+    Word,
+}
+
+impl Granularity {
+    pub(super) fn to_meta(self) -> u64 {
+        match self {
+            Self::Character => 0,
+            Self::Word => 1,
+        }
+    }
+
+    fn from_meta(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(Self::Character),
+            1 => Some(Self::Word),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = LiushuError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "character" => Ok(Self::Character),
+            "word" => Ok(Self::Word),
+            _ => Err(LiushuError::InvalidInput(format!(
+                "unknown granularity {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Granularity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Character => "character",
+            Self::Word => "word",
+        })
+    }
+}
+
+/// How the probability of a character transition is derived from the raw counts, so that
+/// transitions missing from the corpus can still be decoded.
+///
+/// With `c` the count of a transition into a character, `T` the count of all transitions into
+/// it, `N` the number of characters it follows and `V` the number of characters any character
+/// follows, `BOS` included:
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Smoothing {
+    /// `c / T`, unseen transitions have no probability.
+    #[default]
+    None,
+    /// `(c + k) / (T + k V)`.
+    AddK(f64),
+    /// `(max(c - d, 0) + d N / V) / T`, the discounted mass is spread over every character.
+    AbsoluteDiscount(f64),
+}
+
+impl Smoothing {
+    pub(super) fn to_meta(self) -> (u64, u64) {
+        match self {
+            Self::None => (0, 0),
+            Self::AddK(k) => (1, k.to_bits()),
+            Self::AbsoluteDiscount(d) => (2, d.to_bits()),
+        }
+    }
+
+    fn from_meta(kind: u64, param: u64) -> Option<Self> {
+        let param = f64::from_bits(param);
+        match kind {
+            0 => Some(Self::None),
+            1 => Some(Self::AddK(param)),
+            2 => Some(Self::AbsoluteDiscount(param)),
+            _ => None,
+        }
+    }
+
+    pub(super) fn validate(self) -> Result<(), LiushuError> {
+        match self {
+            Self::AddK(k) if !(k > 0.0 && k.is_finite()) => Err(LiushuError::InvalidInput(
+                format!("add-k smoothing needs a positive k, got {}", k),
+            )),
+            Self::AbsoluteDiscount(d) if !(d > 0.0 && d < 1.0) => {
+                Err(LiushuError::InvalidInput(format!(
+                    "absolute discounting needs a discount between 0 and 1, got {}",
+                    d
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Log probability of a transition, `None` when it has no probability mass.
+    fn apply(self, count: u64, total: u64, successors: u64, states: u64) -> Option<f64> {
+        let (count, total) = (count as f64, total as f64);
+        let prob = match self {
+            Self::None => count / total,
+            Self::AddK(k) => (count + k) / (total + k * states as f64),
+            Self::AbsoluteDiscount(d) => {
+                ((count - d).max(0.0) + d * successors as f64 / states as f64) / total
+            }
+        };
+        (prob > 0.0).then(|| prob.ln())
+    }
+}
+
+impl FromStr for Smoothing {
+    type Err = LiushuError;
+
+    /// `none`, `add-k=K` or `absolute-discount=D`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let param = |param: &str| {
+            param.parse::<f64>().map_err(|_| {
+                LiushuError::InvalidInput(format!("invalid smoothing parameter in {}", s))
+            })
+        };
+        let smoothing = match s.split_once('=') {
+            None if s == "none" => Self::None,
+            Some(("add-k", k)) => Self::AddK(param(k)?),
+            Some(("absolute-discount", d)) => Self::AbsoluteDiscount(param(d)?),
+            _ => {
+                return Err(LiushuError::InvalidInput(format!(
+                    "unknown smoothing {}",
+                    s
+                )))
+            }
+        };
+        smoothing.validate()?;
+        Ok(smoothing)
+    }
+}
+
+impl fmt::Display for Smoothing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::AddK(k) => write!(f, "add-k={}", k),
+            Self::AbsoluteDiscount(d) => write!(f, "absolute-discount={}", d),
+        }
+    }
+}
+
+/// The character transitions of a model within a read transaction, smoothed like the model
+/// was trained to.
+pub struct Transitions<'txn> {
+    probs: ReadOnlyTable<'txn, (&'static str, &'static str), f64>,
+    smoothed: Option<SmoothedCounts<'txn>>,
+}
+
+struct SmoothedCounts<'txn> {
+    smoothing: Smoothing,
+    counts: ReadOnlyTable<'txn, (&'static str, &'static str), u64>,
+    totals: ReadOnlyTable<'txn, &'static str, (u64, u64)>,
+    states: u64,
+}
+
+impl Transitions<'_> {
+    /// Log probability of `post` following `pre`.
+    pub fn get(&self, pre: &str, post: &str) -> Result<Option<f64>, LiushuError> {
+        let Some(smoothed) = &self.smoothed else {
+            let prob = self.probs.get((post, pre))?.map(|v| v.value());
+            return Ok(prob);
+        };
+        let Some(totals) = smoothed.totals.get(post)? else {
+            return Ok(None);
+        };
+        let (total, successors) = totals.value();
+        let count = smoothed.counts.get((post, pre))?.map_or(0, |v| v.value());
+        Ok(smoothed
+            .smoothing
+            .apply(count, total, successors, smoothed.states))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelInfo {
+    pub order: u64,
+    pub emission: EmissionSource,
+    pub granularity: Granularity,
+    pub smoothing: Smoothing,
+    /// Unknown for models trained before the raw counts were kept.
+    pub sequences: Option<u64>,
+    pub characters: usize,
+    pub pinyins: usize,
+    pub transitions: usize,
+    pub trigrams: usize,
+    /// Vocabulary of a word model, without the `UNK` token.
+    pub words: usize,
+}
+
+impl Model {
+    pub fn new(db: Database, path: impl Into<PathBuf>) -> Self {
+        Self {
+            db,
+            path: path.into(),
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(LiushuError::ArtifactMissing(path.to_path_buf()));
+        }
+        Ok(Self::new(
+            open_redb(path, "open model", || Database::open(path))?,
+            path,
+        ))
+    }
+
+    pub fn order(&self) -> Result<u64, LiushuError> {
+        Ok(self.meta("order")?.unwrap_or(2))
+    }
+
+    pub fn sequences(&self) -> Result<Option<u64>, LiushuError> {
+        self.meta("sequences")
+    }
+
+    pub fn emission_source(&self) -> Result<EmissionSource, LiushuError> {
+        match self.meta("emission")? {
+            None => Ok(EmissionSource::Reading),
+            Some(value) => EmissionSource::from_meta(value)
+                .ok_or_else(|| self.corrupt(format!("unknown emission source {}", value))),
+        }
+    }
+
+    pub fn granularity(&self) -> Result<Granularity, LiushuError> {
+        match self.meta("granularity")? {
+            None => Ok(Granularity::Character),
+            Some(value) => Granularity::from_meta(value)
+                .ok_or_else(|| self.corrupt(format!("unknown granularity {}", value))),
+        }
+    }
+
+    pub fn smoothing(&self) -> Result<Smoothing, LiushuError> {
+        let kind = self.meta("smoothing")?.unwrap_or(0);
+        let param = self.meta("smoothing_param")?.unwrap_or(0);
+        Smoothing::from_meta(kind, param)
+            .ok_or_else(|| self.corrupt(format!("unknown smoothing {}", kind)))
+    }
+
+    fn corrupt(&self, reason: String) -> LiushuError {
+        LiushuError::ArtifactCorrupt {
+            path: self.path.clone(),
+            source: reason.into(),
+        }
+    }
+
+    /// The character transitions, to look up many of them in the same transaction.
+    pub fn transitions<'txn>(
+        &self,
+        read_txn: &'txn ReadTransaction,
+    ) -> Result<Transitions<'txn>, LiushuError> {
+        let smoothing = self.smoothing()?;
+        let smoothed = if smoothing == Smoothing::None {
+            None
+        } else {
+            Some(SmoothedCounts {
+                smoothing,
+                counts: read_txn.open_table(TRANS_COUNTS)?,
+                totals: read_txn.open_table(TRANS_TOTALS)?,
+                states: self.meta("trans_states")?.unwrap_or(1),
+            })
+        };
+        Ok(Transitions {
+            probs: read_txn.open_table(TRANS_TABLE)?,
+            smoothed,
+        })
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<u64>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        // models trained before the metadata was recorded are bigram models
+        let Ok(meta) = read_txn.open_table(META_TABLE) else {
+            return Ok(None);
+        };
+        let value = meta.get(key)?.map(|v| v.value());
+        Ok(value)
+    }
+
+    pub fn info(&self) -> Result<ModelInfo, LiushuError> {
+        let order = self.order()?;
+        let read_txn = self.db.begin_read()?;
+        let trigrams = if order == 3 {
+            read_txn.open_table(TRIGRAM_TABLE)?.len()?
+        } else {
+            0
+        };
+        let granularity = self.granularity()?;
+        let words = if granularity == Granularity::Word {
+            read_txn.open_table(WORD_VOCAB)?.len()?
+        } else {
+            0
+        };
+        // sorted by character, so each one starts a run of its readings
+        let mut characters = 0;
+        let mut last = String::new();
+        for (key, _) in read_txn.open_table(EMISS_TABLE)?.iter()? {
+            let (word, _) = key.value();
+            if word != last {
+                characters += 1;
+                last = word.to_string();
+            }
+        }
+        Ok(ModelInfo {
+            order,
+            emission: self.emission_source()?,
+            granularity,
+            smoothing: self.smoothing()?,
+            sequences: self.sequences()?,
+            characters,
+            pinyins: read_txn.open_table(PINYIN_STATES)?.len()?,
+            transitions: read_txn.open_table(TRANS_TABLE)?.len()?,
+            trigrams,
+            words,
+        })
+    }
+
+    /// Probability of `post` following `pre`, `BOS` and `EOS` stand for the start and the
+    /// end of a sentence.
+    pub fn transition(&self, pre: &str, post: &str) -> Result<Option<f64>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        let prob = self.transitions(&read_txn)?.get(pre, post)?;
+        Ok(prob)
+    }
+
+    /// The `k` most likely characters following `pre`, most likely first.
+    pub fn top_successors(&self, pre: &str, k: usize) -> Result<Vec<(String, f64)>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        let transitions = self.transitions(&read_txn)?;
+        let table = read_txn.open_table(TRANS_TABLE)?;
+        // keyed by the successor, so every transition has to be looked at
+        let mut successors = vec![];
+        for (key, _) in table.iter()? {
+            let (post, key_pre) = key.value();
+            if key_pre == pre {
+                if let Some(prob) = transitions.get(pre, post)? {
+                    successors.push((post.to_string(), prob));
+                }
+            }
+        }
+        successors.sort_by(|a, b| b.1.total_cmp(&a.1));
+        successors.truncate(k);
+        Ok(successors)
+    }
+
+    /// Probability of the word `post` following the word `pre` in a word model, words out of
+    /// the vocabulary are looked up as `UNK`. Unlike [`Model::transition`] the probabilities
+    /// of the successors of `pre` add up to 1.
+    pub fn word_transition(&self, pre: &str, post: &str) -> Result<Option<f64>, LiushuError> {
+        if self.granularity()? != Granularity::Word {
+            return Ok(None);
+        }
+        let read_txn = self.db.begin_read()?;
+        let vocab = read_txn.open_table(WORD_VOCAB)?;
+        let known = |word: &str| -> Result<bool, LiushuError> {
+            Ok(matches!(word, "BOS" | "EOS") || vocab.get(word)?.is_some())
+        };
+        let pre = if known(pre)? { pre } else { UNK };
+        let post = if known(post)? { post } else { UNK };
+        let table = read_txn.open_table(WORD_TRANS_TABLE)?;
+        let prob = table.get((post, pre))?.map(|v| v.value());
+        Ok(prob)
+    }
+
+    /// Probability of reading `word` as `pinyin`.
+    pub fn emission(&self, pinyin: &str, word: &str) -> Result<Option<f64>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(EMISS_TABLE)?;
+        let prob = table.get((word, pinyin))?.map(|v| v.value());
+        Ok(prob)
+    }
+
+    /// Characters read as `pinyin`.
+    pub fn states(&self, pinyin: &str) -> Result<Vec<String>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PINYIN_STATES)?;
+        let states = table
+            .get(pinyin)?
+            .map(|v| v.value().chars().map(String::from).collect())
+            .unwrap_or_default();
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::hmm::{train, TrainOptions};
+
+    #[test]
+    fn test_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(&corpus[0], "中国\n中国\n中间\n").unwrap();
+        let path = dir.path().join("model.redb");
+        train(&corpus, &path, TrainOptions::default()).unwrap();
+        let model = Model::open(&path).unwrap();
+
+        // grouped by the successor, 国 and 间 only ever follow 中
+        assert_eq!(model.transition("中", "国").unwrap(), Some(0.0));
+        assert_eq!(model.transition("国", "中").unwrap(), None);
+        let successors = model.top_successors("中", 10).unwrap();
+        assert_eq!(
+            successors
+                .iter()
+                .map(|(post, _)| post.as_str())
+                .collect::<Vec<_>>(),
+            ["国", "间"]
+        );
+        assert_eq!(model.top_successors("中", 1).unwrap().len(), 1);
+        assert_eq!(model.emission("zhong", "中").unwrap(), Some(0.0));
+        assert_eq!(model.emission("guo", "中").unwrap(), None);
+        assert_eq!(model.states("guo").unwrap(), ["国"]);
+
+        let info = model.info().unwrap();
+        assert_eq!((info.order, info.sequences), (2, Some(3)));
+        assert_eq!(info.emission, EmissionSource::Reading);
+        assert_eq!(info.characters, 3);
+        assert_eq!(info.transitions, 5);
+        assert_eq!((info.granularity, info.words), (Granularity::Character, 0));
+        assert_eq!(info.smoothing, Smoothing::None);
+        assert_eq!(model.word_transition("中国", "人民").unwrap(), None);
+
+        assert!(matches!(
+            Model::open(dir.path().join("absent.redb")),
+            Err(LiushuError::ArtifactMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(
+            &corpus[0], "中国
+",
+        )
+        .unwrap();
+        let path = dir.path().join("model.redb");
+        train(&corpus, &path, TrainOptions::default()).unwrap();
+        let model = Model::open(&path).unwrap();
+        let write_txn = model.db.begin_write().unwrap();
+        {
+            let mut meta = write_txn.open_table(META_TABLE).unwrap();
+            for key in ["emission", "granularity", "smoothing"] {
+                meta.insert(key, 99).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+
+        for err in [
+            model.emission_source().unwrap_err(),
+            model.granularity().unwrap_err(),
+            model.smoothing().unwrap_err(),
+        ] {
+            assert_eq!(err.code(), "E_ARTIFACT_CORRUPT");
+            assert_eq!(err.path(), Some(path.as_path()));
+            assert!(err.report().ends_with(" 99"));
+        }
+    }
+
+    #[test]
+    fn test_smoothing() {
+        // into 你: BOS 3, into 好: 你 2, into 们: 你 1, into EOS: 好 2 and 们 1, with BOS, 你,
+        // 好 and 们 as the characters any character follows
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(&corpus[0], "你好\n你好\n你们\n").unwrap();
+        let model = |smoothing| {
+            let path = dir.path().join(format!("{}.redb", smoothing));
+            let opts = TrainOptions {
+                smoothing,
+                ..Default::default()
+            };
+            train(&corpus, &path, opts).unwrap();
+            Model::open(path).unwrap()
+        };
+        let assert_prob = |model: &Model, pre, post, expected: Option<f64>| {
+            let prob = model.transition(pre, post).unwrap();
+            match (prob, expected) {
+                (Some(prob), Some(expected)) => {
+                    assert!((prob - expected.ln()).abs() < 1e-12, "{} {}", pre, post)
+                }
+                _ => assert_eq!(prob, expected, "{} {}", pre, post),
+            }
+        };
+
+        let none = model(Smoothing::None);
+        assert_prob(&none, "你", "好", Some(1.0));
+        assert_prob(&none, "好", "好", None);
+
+        let add_k = model(Smoothing::AddK(1.0));
+        assert_eq!(add_k.smoothing().unwrap(), Smoothing::AddK(1.0));
+        assert_prob(&add_k, "你", "好", Some(3.0 / 6.0));
+        assert_prob(&add_k, "好", "好", Some(1.0 / 6.0));
+        assert_prob(&add_k, "好", "EOS", Some(3.0 / 7.0));
+        assert_prob(&add_k, "好", "中", None);
+
+        let discount = model(Smoothing::AbsoluteDiscount(0.5));
+        assert_prob(&discount, "你", "好", Some((1.5 + 0.5 / 4.0) / 2.0));
+        assert_prob(&discount, "好", "好", Some((0.5 / 4.0) / 2.0));
+        assert_prob(&discount, "好", "EOS", Some((1.5 + 0.5 * 2.0 / 4.0) / 3.0));
+        assert_eq!(discount.top_successors("你", 1).unwrap()[0].0, "好");
+    }
+
+    #[test]
+    fn test_parse_smoothing() {
+        assert_eq!("none".parse::<Smoothing>().unwrap(), Smoothing::None);
+        assert_eq!(
+            "add-k=0.5".parse::<Smoothing>().unwrap(),
+            Smoothing::AddK(0.5)
+        );
+        let discount: Smoothing = "absolute-discount=0.75".parse().unwrap();
+        assert_eq!(discount.to_string(), "absolute-discount=0.75");
+        for invalid in [
+            "add-k",
+            "add-k=0",
+            "absolute-discount=1.5",
+            "kneser-ney=0.5",
+        ] {
+            assert!(invalid.parse::<Smoothing>().is_err(), "{}", invalid);
+        }
+    }
+}
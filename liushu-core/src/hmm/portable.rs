@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+use flate2::{write::GzEncoder, Compression};
+use redb::{Database, ReadableTable};
+
+use super::model::{EmissionSource, Granularity, Model, Smoothing};
+use super::train::{
+    model_order, tmp_path, write_model, EMISS_COUNTS, INIT_COUNTS, TRANS_COUNTS, TRIGRAM_COUNTS,
+    WORD_COUNTS, WORD_TRANS_COUNTS,
+};
+use super::META_TABLE;
+use crate::{
+    dict::open_input,
+    error::{IoResultExt, LiushuError},
+};
+
+/// First line of every dump, followed by `# key<TAB>value` metadata lines.
+const MAGIC: &str = "# liushu hmm model";
+const FORMAT_VERSION: u64 = 1;
+
+/// Row kinds of a dump in the order they are written, with the number of keys of each. The
+/// word sections are only written by word models, and may be missing from the header.
+const SECTIONS: [(&str, usize); 6] = [
+    ("init", 1),
+    ("trans", 2),
+    ("trigram", 3),
+    ("emiss", 2),
+    ("word", 1),
+    ("wtrans", 2),
+];
+
+/// Writes the raw counts of a model as sorted TSV rows, gzipped when `output` ends with
+/// `.gz`, returns the number of rows written.
+///
+/// Transitions are written as `pre post` and trigrams as `prepre pre post`, in reading order
+/// rather than the order of the keys in the model. Word transitions are written like the
+/// character ones.
+pub fn export_model(model: &Path, output: &Path) -> Result<u64, LiushuError> {
+    let model = Model::open(model)?;
+    let order = model_order(&model.db)?;
+    let read_txn = model.db.begin_read()?;
+
+    let mut sections: Vec<Vec<Vec<String>>> = vec![vec![]; SECTIONS.len()];
+    for (key, value) in read_txn.open_table(INIT_COUNTS)?.iter()? {
+        sections[0].push(vec![key.value().to_string(), value.value().to_string()]);
+    }
+    for (key, value) in read_txn.open_table(TRANS_COUNTS)?.iter()? {
+        let (post, pre) = key.value();
+        let row = [pre, post].map(String::from).to_vec();
+        sections[1].push([row, vec![value.value().to_string()]].concat());
+    }
+    if order == 3 {
+        for (key, value) in read_txn.open_table(TRIGRAM_COUNTS)?.iter()? {
+            let (post, pre, prepre) = key.value();
+            let row = [prepre, pre, post].map(String::from).to_vec();
+            sections[2].push([row, vec![value.value().to_string()]].concat());
+        }
+    }
+    for (key, value) in read_txn.open_table(EMISS_COUNTS)?.iter()? {
+        let (word, py) = key.value();
+        let row = [word, py].map(String::from).to_vec();
+        sections[3].push([row, vec![value.value().to_string()]].concat());
+    }
+    let granularity = model.granularity()?;
+    if granularity == Granularity::Word {
+        for (key, value) in read_txn.open_table(WORD_COUNTS)?.iter()? {
+            sections[4].push(vec![key.value().to_string(), value.value().to_string()]);
+        }
+        for (key, value) in read_txn.open_table(WORD_TRANS_COUNTS)?.iter()? {
+            let (post, pre) = key.value();
+            let row = [pre, post].map(String::from).to_vec();
+            sections[5].push([row, vec![value.value().to_string()]].concat());
+        }
+    }
+
+    let mut header = vec![
+        ("format".to_string(), FORMAT_VERSION.to_string()),
+        ("order".to_string(), order.to_string()),
+        ("emission".to_string(), model.emission_source()?.to_string()),
+        (
+            "sequences".to_string(),
+            model.sequences()?.unwrap_or(0).to_string(),
+        ),
+    ];
+    let meta = read_txn.open_table(META_TABLE)?;
+    if let Some(trained) = meta.get("trained")? {
+        header.push(("trained".to_string(), trained.value().to_string()));
+    }
+    let smoothing = model.smoothing()?;
+    if smoothing != Smoothing::None {
+        header.push(("smoothing".to_string(), smoothing.to_string()));
+    }
+    if granularity == Granularity::Word {
+        header.push(("granularity".to_string(), granularity.to_string()));
+        let max_vocab = meta.get("max_vocab")?.map_or(0, |v| v.value());
+        header.push(("max_vocab".to_string(), max_vocab.to_string()));
+    }
+    for ((name, _), rows) in SECTIONS.iter().zip(&mut sections) {
+        rows.sort();
+        header.push((name.to_string(), rows.len().to_string()));
+    }
+
+    let write = |out: &mut dyn Write| -> std::io::Result<()> {
+        writeln!(out, "{}", MAGIC)?;
+        for (key, value) in &header {
+            writeln!(out, "# {}\t{}", key, value)?;
+        }
+        for ((name, _), rows) in SECTIONS.iter().zip(&sections) {
+            for row in rows {
+                writeln!(out, "{}\t{}", name, row.join("\t"))?;
+            }
+        }
+        Ok(())
+    };
+    let file = BufWriter::new(File::create(output).with_path("create model export", output)?);
+    if output.extension().is_some_and(|ext| ext == "gz") {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        write(&mut encoder)?;
+        encoder.finish()?.flush()?;
+    } else {
+        let mut file = file;
+        write(&mut file)?;
+        file.flush()?;
+    }
+    Ok(sections.iter().map(|rows| rows.len() as u64).sum())
+}
+
+/// Rebuilds a model from a dump written by [`export_model`], returns the number of rows
+/// read. The probabilities are computed again from the counts.
+pub fn import_model(input: &Path, save_to: &Path) -> Result<u64, LiushuError> {
+    let invalid =
+        |message: &str| LiushuError::InvalidInput(format!("{}: {}", input.display(), message));
+    let corrupt = |line: usize, message: &str| LiushuError::DictParse {
+        file: input.to_path_buf(),
+        line: line as u64,
+        source: message.into(),
+    };
+    let mut lines = open_input(input, "model export")?
+        .lines()
+        .enumerate()
+        .peekable();
+
+    match lines.next() {
+        Some((_, Ok(line))) if line == MAGIC => {}
+        _ => return Err(invalid("not a liushu model dump")),
+    }
+    let mut header = BTreeMap::new();
+    while let Some((index, Ok(line))) = lines.peek() {
+        let Some(meta) = line.strip_prefix("# ") else {
+            break;
+        };
+        let (key, value) = meta
+            .split_once('\t')
+            .ok_or_else(|| corrupt(index + 1, "malformed header line"))?;
+        header.insert(key.to_string(), value.to_string());
+        lines.next();
+    }
+    let number = |key: &str| -> Result<u64, LiushuError> {
+        header
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid(&format!("missing or invalid {} in the header", key)))
+    };
+    let version = number("format")?;
+    if version != FORMAT_VERSION {
+        return Err(invalid(&format!("unsupported format {}", version)));
+    }
+    let order = number("order")?;
+    if !matches!(order, 2 | 3) {
+        return Err(invalid(&format!("unsupported n-gram order {}", order)));
+    }
+    let emission: EmissionSource = header
+        .get("emission")
+        .ok_or_else(|| invalid("missing emission in the header"))?
+        .parse()
+        .map_err(|e: LiushuError| invalid(&e.report()))?;
+    let sequences = number("sequences")?;
+    let granularity: Granularity = match header.get("granularity") {
+        Some(granularity) => granularity
+            .parse()
+            .map_err(|e: LiushuError| invalid(&e.report()))?,
+        None => Granularity::Character,
+    };
+    let smoothing: Smoothing = match header.get("smoothing") {
+        Some(smoothing) => smoothing
+            .parse()
+            .map_err(|e: LiushuError| invalid(&e.report()))?,
+        None => Smoothing::None,
+    };
+    let max_vocab = if granularity == Granularity::Word {
+        number("max_vocab")?
+    } else {
+        0
+    };
+    let mut expected = vec![];
+    for (name, _) in SECTIONS {
+        let optional = matches!(name, "word" | "wtrans") && !header.contains_key(name);
+        expected.push(if optional { 0 } else { number(name)? });
+    }
+
+    let tmp = tmp_path(save_to, "tmp");
+    if tmp.exists() {
+        fs::remove_file(&tmp).with_path("remove", &tmp)?;
+    }
+    let db = Database::create(&tmp).with_path("create model", &tmp)?;
+    let result = read_rows(&db, lines, order, &corrupt).and_then(|found| {
+        if let Some(((name, _), (expected, found))) = SECTIONS
+            .iter()
+            .zip(expected.iter().zip(&found))
+            .find(|(_, (expected, found))| expected != found)
+        {
+            let message = format!("expected {} {} rows, found {}", expected, name, found);
+            return Err(invalid(&message));
+        }
+
+        let write_txn = db.begin_write()?;
+        {
+            let mut meta = write_txn.open_table(META_TABLE)?;
+            meta.insert("sequences", sequences)?;
+            meta.insert("emission", emission.to_meta())?;
+            meta.insert("granularity", granularity.to_meta())?;
+            meta.insert("max_vocab", max_vocab)?;
+            let (kind, param) = smoothing.to_meta();
+            meta.insert("smoothing", kind)?;
+            meta.insert("smoothing_param", param)?;
+            if let Ok(trained) = number("trained") {
+                meta.insert("trained", trained)?;
+            }
+        }
+        write_txn.commit()?;
+        write_model(&db, order)?;
+        Ok(found.iter().sum())
+    });
+    drop(db);
+    match result {
+        Ok(rows) => {
+            fs::rename(&tmp, save_to).with_path("replace model", save_to)?;
+            Ok(rows)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Adds the rows to the count tables, returns the number of rows of each section.
+fn read_rows(
+    db: &Database,
+    lines: impl Iterator<Item = (usize, std::io::Result<String>)>,
+    order: u64,
+    corrupt: &dyn Fn(usize, &str) -> LiushuError,
+) -> Result<Vec<u64>, LiushuError> {
+    let mut found = vec![0; SECTIONS.len()];
+    let write_txn = db.begin_write()?;
+    {
+        let mut init = write_txn.open_table(INIT_COUNTS)?;
+        let mut trans = write_txn.open_table(TRANS_COUNTS)?;
+        let mut trigrams = write_txn.open_table(TRIGRAM_COUNTS)?;
+        let mut emiss = write_txn.open_table(EMISS_COUNTS)?;
+        let mut words = write_txn.open_table(WORD_COUNTS)?;
+        let mut word_trans = write_txn.open_table(WORD_TRANS_COUNTS)?;
+        for (index, line) in lines {
+            let line = line.map_err(|e| corrupt(index + 1, &e.to_string()))?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            let section = SECTIONS
+                .iter()
+                .position(|(name, keys)| fields[0] == *name && fields.len() == keys + 2)
+                .ok_or_else(|| corrupt(index + 1, "malformed row"))?;
+            let count: u64 = fields[fields.len() - 1]
+                .parse()
+                .map_err(|_| corrupt(index + 1, "invalid count"))?;
+            match (section, &fields[1..fields.len() - 1]) {
+                (0, [word]) => init.insert(*word, count)?,
+                (1, [pre, post]) => trans.insert((*post, *pre), count)?,
+                (2, [prepre, pre, post]) if order == 3 => {
+                    trigrams.insert((*post, *pre, *prepre), count)?
+                }
+                (3, [word, py]) => emiss.insert((*word, *py), count)?,
+                (4, [word]) => words.insert(*word, count)?,
+                (5, [pre, post]) => word_trans.insert((*post, *pre), count)?,
+                _ => return Err(corrupt(index + 1, "trigram row in a bigram model")),
+            };
+            found[section] += 1;
+        }
+    }
+    write_txn.commit()?;
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::hmm::{train, TrainOptions};
+
+    fn dump(path: &Path) -> String {
+        let mut contents = String::new();
+        open_input(path, "model export")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(&corpus[0], "你好世界\n你好\n世界你好\n").unwrap();
+        let model = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            order: 3,
+            smoothing: Smoothing::AddK(0.5),
+            ..Default::default()
+        };
+        train(&corpus, &model, opts).unwrap();
+
+        let exported = dir.path().join("model.tsv.gz");
+        let rows = export_model(&model, &exported).unwrap();
+        let text = dump(&exported);
+        assert!(text.starts_with("# liushu hmm model\n# format\t1\n# order\t3\n"));
+        assert!(text.contains("\ntrans\t你\t好\t3\n"));
+
+        let imported = dir.path().join("imported.redb");
+        assert_eq!(import_model(&exported, &imported).unwrap(), rows);
+        let again = dir.path().join("again.tsv");
+        export_model(&imported, &again).unwrap();
+        assert_eq!(dump(&again), text);
+
+        let (model, imported) = (
+            Model::open(&model).unwrap(),
+            Model::open(&imported).unwrap(),
+        );
+        assert_eq!(imported.order().unwrap(), 3);
+        assert_eq!(imported.smoothing().unwrap(), Smoothing::AddK(0.5));
+        for (pre, post) in [("你", "好"), ("BOS", "世"), ("界", "EOS"), ("好", "好")] {
+            let (a, b) = (
+                model.transition(pre, post).unwrap(),
+                imported.transition(pre, post).unwrap(),
+            );
+            assert!((a.unwrap() - b.unwrap()).abs() < 1e-12);
+        }
+        assert_eq!(
+            model.emission("hao", "好").unwrap(),
+            imported.emission("hao", "好").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trip_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let corpus = [dir.path().join("corpus.txt")];
+        fs::write(
+            &corpus[0],
+            "中国 人民
+中国 人民 站起来
+我 的
+",
+        )
+        .unwrap();
+        let model = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            granularity: Granularity::Word,
+            max_vocab: 3,
+            ..Default::default()
+        };
+        train(&corpus, &model, opts).unwrap();
+
+        let exported = dir.path().join("model.tsv");
+        export_model(&model, &exported).unwrap();
+        let text = dump(&exported);
+        assert!(text.contains("# granularity\tword\n# max_vocab\t3\n"));
+        assert!(text.contains("\nwtrans\t中国\t人民\t2\n"));
+
+        let imported = dir.path().join("imported.redb");
+        import_model(&exported, &imported).unwrap();
+        let imported = Model::open(&imported).unwrap();
+        assert_eq!(imported.info().unwrap().words, 3);
+        assert_eq!(imported.word_transition("中国", "人民").unwrap(), Some(0.0));
+    }
+
+    #[test]
+    fn test_corrupt_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("model.tsv");
+        let save_to = dir.path().join("model.redb");
+        let header = "# liushu hmm model\n# format\t1\n# order\t2\n# emission\treading\n\
+                      # sequences\t1\n# init\t1\n# trans\t0\n# trigram\t0\n# emiss\t0\n";
+
+        for (contents, error) in [
+            ("init\t你\t1\n".to_string(), "not a liushu model dump"),
+            (header.replace("# order\t2", "# order\t4"), "order 4"),
+            (header.replace("# format\t1", "# format\t2"), "format 2"),
+            (header.replace("# sequences\t1\n", ""), "sequences"),
+            (
+                header.replace("reading", "magic"),
+                "unknown emission source",
+            ),
+            (header.to_string(), "expected 1 init rows, found 0"),
+            (format!("{}init\t你\tmany\n", header), "invalid count"),
+            (
+                format!("{}trigram\t你\t好\t吗\t1\n", header),
+                "bigram model",
+            ),
+        ] {
+            fs::write(&input, contents).unwrap();
+            let err = import_model(&input, &save_to).unwrap_err().report();
+            assert!(err.contains(error), "{} does not contain {}", err, error);
+            assert!(!save_to.exists());
+        }
+
+        fs::write(&input, format!("{}init\t你\t1\n", header)).unwrap();
+        assert_eq!(import_model(&input, &save_to).unwrap(), 1);
+    }
+}
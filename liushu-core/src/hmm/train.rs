@@ -0,0 +1,1623 @@
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::E;
+use std::fs;
+use std::hash::Hash;
+use std::io::BufRead;
+use std::iter::once;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{debug, info};
+
+use super::corpus::{word_runs, CorpusFormat, Preprocess, Skip, SkippedLines};
+use super::model::{EmissionSource, Granularity, Model, Smoothing};
+use super::pinyin::{ToPinyin, POSIBLE_PINYINS};
+use super::{
+    EMISS_TABLE, INIT_TABLE, META_TABLE, PINYIN_STATES, TRANS_TABLE, TRANS_TOTALS, TRIGRAM_TABLE,
+    UNK, WORD_TRANS_TABLE, WORD_VOCAB,
+};
+use crate::{
+    artifact::open_redb,
+    diagnostics::{Diagnostics, Warning},
+    dict::{open_dictionary, open_input, DictItem},
+    dirs::preflight,
+    error::{IoResultExt, LiushuError},
+    progress::{NoProgress, ProgressSink},
+};
+
+pub(super) const INIT_COUNTS: TableDefinition<&str, u64> = TableDefinition::new("init_count");
+pub(super) const TRANS_COUNTS: TableDefinition<(&str, &str), u64> =
+    TableDefinition::new("trans_count");
+pub(super) const TRIGRAM_COUNTS: TableDefinition<(&str, &str, &str), u64> =
+    TableDefinition::new("trigram_count");
+pub(super) const EMISS_COUNTS: TableDefinition<(&str, &str), u64> =
+    TableDefinition::new("emiss_count");
+/// Every word of a word model, including the ones left out of the vocabulary.
+pub(super) const WORD_COUNTS: TableDefinition<&str, u64> = TableDefinition::new("word_count");
+pub(super) const WORD_TRANS_COUNTS: TableDefinition<(&str, &str), u64> =
+    TableDefinition::new("word_trans_count");
+
+/// Number of pending counts kept in memory before they are added to the model file.
+const BATCH_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct TrainOptions {
+    /// Merge the new counts into an existing model instead of replacing it.
+    pub append: bool,
+    /// 2 for character bigrams, 3 to also count trigrams.
+    pub order: u64,
+    pub prune: PruneOptions,
+    pub preprocess: Preprocess,
+    /// Pinyin dictionaries to take the emissions from when no input is an annotated corpus.
+    pub emission_dicts: Vec<PathBuf>,
+    /// Number of inputs counted at the same time.
+    pub jobs: usize,
+    /// `Word` reads text inputs as segmented with spaces, the characters are still counted.
+    pub granularity: Granularity,
+    /// Words of a word model kept in its vocabulary, the most frequent ones.
+    pub max_vocab: usize,
+    /// Applied to the character transitions when they are looked up, the stored counts are
+    /// exact.
+    pub smoothing: Smoothing,
+}
+
+impl Default for TrainOptions {
+    fn default() -> Self {
+        Self {
+            append: false,
+            order: 2,
+            prune: PruneOptions::default(),
+            preprocess: Preprocess::default(),
+            emission_dicts: vec![],
+            jobs: 1,
+            granularity: Granularity::Character,
+            max_vocab: 50_000,
+            smoothing: Smoothing::None,
+        }
+    }
+}
+
+/// Limits on the transitions kept in a model, the pruned counts are gone for good so an
+/// appended model starts from the retained counts.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PruneOptions {
+    /// Drop transitions observed fewer times than this.
+    pub min_count: u64,
+    /// Keep only the most frequent successors of each character, or of each pair of
+    /// characters for trigrams.
+    pub max_transitions_per_state: Option<usize>,
+}
+
+impl PruneOptions {
+    fn is_active(&self) -> bool {
+        self.min_count > 1 || self.max_transitions_per_state.is_some()
+    }
+
+    /// Keeps the counts passing the limits, `state` gives the state a transition leaves.
+    fn retain<K, S: Hash + Eq>(
+        &self,
+        counts: Vec<(K, u64)>,
+        state: impl Fn(&K) -> S,
+    ) -> Vec<(K, u64)> {
+        let mut counts: Vec<_> = counts
+            .into_iter()
+            .filter(|(_, count)| *count >= self.min_count)
+            .collect();
+        if let Some(max) = self.max_transitions_per_state {
+            // stable, so ties keep the key order
+            counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+            let mut kept: HashMap<S, usize> = HashMap::new();
+            counts.retain(|(key, _)| {
+                let n = kept.entry(state(key)).or_default();
+                *n += 1;
+                *n <= max
+            });
+        }
+        counts
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PruneReport {
+    pub removed: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrainReport {
+    pub inputs: usize,
+    pub lines: u64,
+    pub skipped: SkippedLines,
+    pub emission: EmissionSource,
+    pub sequences: u64,
+    pub characters: usize,
+    pub transitions: usize,
+    pub trigrams: usize,
+    pub words: usize,
+    pub elapsed_secs: f64,
+    /// About the inputs, such as malformed lines of a corpus file.
+    pub warnings: Vec<Warning>,
+}
+
+/// Counts not yet added to the raw counts stored in the model.
+#[derive(Debug, Default)]
+struct Counts {
+    sequences: u64,
+    init: HashMap<String, u64>,
+    /// Keyed by `(post, pre)`, like the transition table.
+    trans: HashMap<(String, String), u64>,
+    /// Keyed by `(post, pre, prepre)`, like the trigram table.
+    trigrams: HashMap<(String, String, String), u64>,
+    /// Keyed by `(word, pinyin)`, like the emission table.
+    emiss: HashMap<(String, String), u64>,
+    words: HashMap<String, u64>,
+    /// Keyed by `(post, pre)` words.
+    word_trans: HashMap<(String, String), u64>,
+}
+
+impl Counts {
+    fn add_sequence(&mut self, seq: &str, order: u64) {
+        let mut chars = seq.chars().map(String::from).peekable();
+        let Some(first) = chars.peek() else {
+            return;
+        };
+
+        self.sequences += 1;
+        *self.init.entry(first.clone()).or_default() += 1;
+
+        let (mut prepre, mut pre) = ("BOS".to_string(), "BOS".to_string());
+        for post in chars.chain(once("EOS".to_string())) {
+            *self.trans.entry((post.clone(), pre.clone())).or_default() += 1;
+            if order == 3 {
+                *self
+                    .trigrams
+                    .entry((post.clone(), pre.clone(), prepre))
+                    .or_default() += 1;
+            }
+            prepre = pre;
+            pre = post;
+        }
+    }
+
+    /// Counts the most common reading of every character of `seq`.
+    fn add_readings(&mut self, seq: &str) {
+        for (py, word) in seq.to_pinyin().zip(seq.chars()) {
+            if let Some(py) = py {
+                self.add_emission(word, py.plain(), 1);
+            }
+        }
+    }
+
+    /// Counts the words of a run and the transitions between them, from `BOS` to `EOS`.
+    fn add_words(&mut self, words: &[&str]) {
+        let mut pre = "BOS";
+        for &post in words.iter().chain(once(&"EOS")) {
+            if post != "EOS" {
+                *self.words.entry(post.to_string()).or_default() += 1;
+            }
+            *self
+                .word_trans
+                .entry((post.to_string(), pre.to_string()))
+                .or_default() += 1;
+            pre = post;
+        }
+    }
+
+    fn add_emission(&mut self, word: char, py: &str, count: u64) {
+        *self
+            .emiss
+            .entry((word.to_string(), py.to_string()))
+            .or_default() += count;
+    }
+
+    /// Counts the single characters of a pinyin dictionary, by their weight.
+    fn add_dictionary(&mut self, path: &Path) -> Result<(), LiushuError> {
+        info!(dictionary = %path.display(), "counting emissions");
+        for item in open_dictionary(path)?.deserialize::<DictItem>() {
+            let item = item.map_err(|e| LiushuError::dict_parse(path, e))?;
+            let mut chars = item.text.chars();
+            if let (Some(word), None) = (chars.next(), chars.next()) {
+                if POSIBLE_PINYINS.contains(&item.code.as_str()) {
+                    // an entry is a reading even with a weight of 0
+                    self.add_emission(word, &item.code, item.weight.max(1));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sequences == 0 && self.len() == 0
+    }
+
+    fn len(&self) -> usize {
+        self.init.len()
+            + self.trans.len()
+            + self.trigrams.len()
+            + self.emiss.len()
+            + self.words.len()
+            + self.word_trans.len()
+    }
+
+    /// Adds the pending counts to the raw counts of the model.
+    fn flush(&mut self, db: &Database) -> Result<(), LiushuError> {
+        debug!(pending = self.len(), "flushing counts");
+        let write_txn = db.begin_write()?;
+        {
+            let mut meta = write_txn.open_table(META_TABLE)?;
+            let sequences = meta.get("sequences")?.map_or(0, |v| v.value());
+            meta.insert("sequences", sequences + self.sequences)?;
+
+            let mut init = write_txn.open_table(INIT_COUNTS)?;
+            for (key, count) in self.init.drain() {
+                let old = init.get(key.as_str())?.map_or(0, |v| v.value());
+                init.insert(key.as_str(), old + count)?;
+            }
+
+            let mut trans = write_txn.open_table(TRANS_COUNTS)?;
+            for ((post, pre), count) in self.trans.drain() {
+                let key = (post.as_str(), pre.as_str());
+                let old = trans.get(key)?.map_or(0, |v| v.value());
+                trans.insert(key, old + count)?;
+            }
+
+            if !self.trigrams.is_empty() {
+                let mut trigrams = write_txn.open_table(TRIGRAM_COUNTS)?;
+                for ((post, pre, prepre), count) in self.trigrams.drain() {
+                    let key = (post.as_str(), pre.as_str(), prepre.as_str());
+                    let old = trigrams.get(key)?.map_or(0, |v| v.value());
+                    trigrams.insert(key, old + count)?;
+                }
+            }
+
+            let mut emiss = write_txn.open_table(EMISS_COUNTS)?;
+            for ((word, py), count) in self.emiss.drain() {
+                let key = (word.as_str(), py.as_str());
+                let old = emiss.get(key)?.map_or(0, |v| v.value());
+                emiss.insert(key, old + count)?;
+            }
+
+            if !self.word_trans.is_empty() {
+                let mut words = write_txn.open_table(WORD_COUNTS)?;
+                for (word, count) in self.words.drain() {
+                    let old = words.get(word.as_str())?.map_or(0, |v| v.value());
+                    words.insert(word.as_str(), old + count)?;
+                }
+                let mut word_trans = write_txn.open_table(WORD_TRANS_COUNTS)?;
+                for ((post, pre), count) in self.word_trans.drain() {
+                    let key = (post.as_str(), pre.as_str());
+                    let old = word_trans.get(key)?.map_or(0, |v| v.value());
+                    word_trans.insert(key, old + count)?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        self.sequences = 0;
+        Ok(())
+    }
+}
+
+/// Model-wide numbers gathered while writing the probabilities.
+pub(super) struct ModelStats {
+    sequences: u64,
+    characters: usize,
+    transitions: usize,
+    trigrams: usize,
+    words: usize,
+}
+
+/// Computes the probability tables from the raw counts.
+///
+/// Probabilities are `ln(count / total)`, with totals grouped by the first element of the
+/// key. Word transitions are grouped by `pre` instead, after mapping the words out of the
+/// vocabulary to `UNK`.
+pub(super) fn write_model(db: &Database, order: u64) -> Result<ModelStats, LiushuError> {
+    let write_txn = db.begin_write()?;
+    let stats = {
+        let mut meta = write_txn.open_table(META_TABLE)?;
+        meta.insert("order", order)?;
+        let sequences = meta.get("sequences")?.map_or(0, |v| v.value());
+        let granularity = meta.get("granularity")?.map_or(0, |v| v.value());
+        let max_vocab = meta.get("max_vocab")?.map_or(0, |v| v.value());
+        drop(meta);
+
+        let init_counts = write_txn.open_table(INIT_COUNTS)?;
+        let mut init_prob = write_txn.open_table(INIT_TABLE)?;
+        for (key, count) in init_counts.iter()? {
+            let prob = (count.value() as f64 / sequences as f64).log(E);
+            init_prob.insert(key.value(), prob)?;
+        }
+
+        let trans_counts = write_txn.open_table(TRANS_COUNTS)?;
+        let mut trans_prob = write_txn.open_table(TRANS_TABLE)?;
+        let mut totals: HashMap<String, (u64, u64)> = HashMap::new();
+        let mut states: HashSet<String> = HashSet::new();
+        for (key, count) in trans_counts.iter()? {
+            let (post, pre) = key.value();
+            let total = totals.entry(post.to_string()).or_default();
+            total.0 += count.value();
+            total.1 += 1;
+            states.insert(pre.to_string());
+        }
+        let mut transitions = 0;
+        for (key, count) in trans_counts.iter()? {
+            let (post, pre) = key.value();
+            let prob = (count.value() as f64 / totals[post].0 as f64).log(E);
+            trans_prob.insert((post, pre), prob)?;
+            transitions += 1;
+        }
+        let mut trans_totals = write_txn.open_table(TRANS_TOTALS)?;
+        for (post, total) in &totals {
+            trans_totals.insert(post.as_str(), total)?;
+        }
+        let mut meta = write_txn.open_table(META_TABLE)?;
+        meta.insert("trans_states", states.len() as u64)?;
+        drop(meta);
+
+        let mut trigrams = 0;
+        if order == 3 {
+            let trigram_counts = write_txn.open_table(TRIGRAM_COUNTS)?;
+            let mut trigram_prob = write_txn.open_table(TRIGRAM_TABLE)?;
+            let mut totals: HashMap<String, u64> = HashMap::new();
+            for (key, count) in trigram_counts.iter()? {
+                *totals.entry(key.value().0.to_string()).or_default() += count.value();
+            }
+            for (key, count) in trigram_counts.iter()? {
+                let (post, pre, prepre) = key.value();
+                let prob = (count.value() as f64 / totals[post] as f64).log(E);
+                trigram_prob.insert((post, pre, prepre), prob)?;
+                trigrams += 1;
+            }
+        }
+
+        let emiss_counts = write_txn.open_table(EMISS_COUNTS)?;
+        let mut emiss_prob = write_txn.open_table(EMISS_TABLE)?;
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for (key, count) in emiss_counts.iter()? {
+            *totals.entry(key.value().0.to_string()).or_default() += count.value();
+        }
+        // words of each pinyin, in the order of the emission keys
+        let mut states: HashMap<String, String> = HashMap::new();
+        for (key, count) in emiss_counts.iter()? {
+            let (word, py) = key.value();
+            let prob = (count.value() as f64 / totals[word] as f64).log(E);
+            emiss_prob.insert((word, py), prob)?;
+            states.entry(py.to_string()).or_default().push_str(word);
+        }
+        let mut pinyin_states = write_txn.open_table(PINYIN_STATES)?;
+        for (py, words) in &states {
+            pinyin_states.insert(py.as_str(), words.as_str())?;
+        }
+
+        let words = if granularity == Granularity::Word.to_meta() {
+            write_word_model(&write_txn, max_vocab as usize)?
+        } else {
+            0
+        };
+
+        ModelStats {
+            sequences,
+            characters: totals.len(),
+            transitions,
+            trigrams,
+            words,
+        }
+    };
+    write_txn.commit()?;
+    Ok(stats)
+}
+
+/// Writes the vocabulary and the word transitions, returns the size of the vocabulary.
+fn write_word_model(write_txn: &WriteTransaction, max_vocab: usize) -> Result<usize, LiushuError> {
+    // words dropped from the vocabulary of an appended model must not linger
+    write_txn.delete_table(WORD_VOCAB)?;
+    write_txn.delete_table(WORD_TRANS_TABLE)?;
+
+    let word_counts = write_txn.open_table(WORD_COUNTS)?;
+    let mut words: Vec<(String, u64)> = word_counts
+        .iter()?
+        .map(|(word, count)| (word.value().to_string(), count.value()))
+        .collect();
+    // stable, so ties keep the key order
+    words.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    words.truncate(max_vocab);
+    let mut vocab = write_txn.open_table(WORD_VOCAB)?;
+    for (word, count) in &words {
+        vocab.insert(word.as_str(), count)?;
+    }
+    let known =
+        |word: &str| matches!(word, "BOS" | "EOS") || vocab.get(word).is_ok_and(|v| v.is_some());
+
+    let mut trans: HashMap<(String, String), u64> = HashMap::new();
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for (key, count) in write_txn.open_table(WORD_TRANS_COUNTS)?.iter()? {
+        let (post, pre) = key.value();
+        let post = if known(post) { post } else { UNK };
+        let pre = if known(pre) { pre } else { UNK };
+        *trans
+            .entry((post.to_string(), pre.to_string()))
+            .or_default() += count.value();
+        *totals.entry(pre.to_string()).or_default() += count.value();
+    }
+    let mut trans_prob = write_txn.open_table(WORD_TRANS_TABLE)?;
+    for ((post, pre), count) in &trans {
+        let prob = (*count as f64 / totals[pre] as f64).log(E);
+        trans_prob.insert((post.as_str(), pre.as_str()), prob)?;
+    }
+    Ok(words.len())
+}
+
+/// Copies the raw counts of `src` into the empty `dst`, returns how many transitions were
+/// pruned.
+fn copy_counts(
+    src: &Database,
+    dst: &Database,
+    order: u64,
+    prune: PruneOptions,
+) -> Result<usize, LiushuError> {
+    let read_txn = src.begin_read()?;
+    let write_txn = dst.begin_write()?;
+    let mut removed = 0;
+    {
+        let mut meta = write_txn.open_table(META_TABLE)?;
+        for (key, value) in read_txn.open_table(META_TABLE)?.iter()? {
+            meta.insert(key.value(), value.value())?;
+        }
+        let mut init = write_txn.open_table(INIT_COUNTS)?;
+        for (key, value) in read_txn.open_table(INIT_COUNTS)?.iter()? {
+            init.insert(key.value(), value.value())?;
+        }
+        let mut emiss = write_txn.open_table(EMISS_COUNTS)?;
+        for (key, value) in read_txn.open_table(EMISS_COUNTS)?.iter()? {
+            emiss.insert(key.value(), value.value())?;
+        }
+
+        let trans: Vec<_> = read_txn
+            .open_table(TRANS_COUNTS)?
+            .iter()?
+            .map(|(key, value)| {
+                let (post, pre) = key.value();
+                ((post.to_string(), pre.to_string()), value.value())
+            })
+            .collect();
+        let total = trans.len();
+        let trans = prune.retain(trans, |(_, pre)| pre.clone());
+        removed += total - trans.len();
+        let mut trans_counts = write_txn.open_table(TRANS_COUNTS)?;
+        for ((post, pre), count) in &trans {
+            trans_counts.insert((post.as_str(), pre.as_str()), count)?;
+        }
+
+        if order == 3 {
+            let trigrams: Vec<_> = read_txn
+                .open_table(TRIGRAM_COUNTS)?
+                .iter()?
+                .map(|(key, value)| {
+                    let (post, pre, prepre) = key.value();
+                    let key = (post.to_string(), pre.to_string(), prepre.to_string());
+                    (key, value.value())
+                })
+                .collect();
+            let total = trigrams.len();
+            let trigrams = prune.retain(trigrams, |(_, pre, prepre)| (pre.clone(), prepre.clone()));
+            removed += total - trigrams.len();
+            let mut trigram_counts = write_txn.open_table(TRIGRAM_COUNTS)?;
+            for ((post, pre, prepre), count) in &trigrams {
+                trigram_counts.insert((post.as_str(), pre.as_str(), prepre.as_str()), count)?;
+            }
+        }
+
+        if let Ok(word_counts) = read_txn.open_table(WORD_COUNTS) {
+            let mut words = write_txn.open_table(WORD_COUNTS)?;
+            for (key, value) in word_counts.iter()? {
+                words.insert(key.value(), value.value())?;
+            }
+            let word_trans: Vec<_> = read_txn
+                .open_table(WORD_TRANS_COUNTS)?
+                .iter()?
+                .map(|(key, value)| {
+                    let (post, pre) = key.value();
+                    ((post.to_string(), pre.to_string()), value.value())
+                })
+                .collect();
+            let total = word_trans.len();
+            let word_trans = prune.retain(word_trans, |(_, pre)| pre.clone());
+            removed += total - word_trans.len();
+            let mut word_trans_counts = write_txn.open_table(WORD_TRANS_COUNTS)?;
+            for ((post, pre), count) in &word_trans {
+                word_trans_counts.insert((post.as_str(), pre.as_str()), count)?;
+            }
+        }
+    }
+    write_txn.commit()?;
+    Ok(removed)
+}
+
+/// Prunes the transitions of an existing model, the probabilities are normalized again over
+/// the retained counts.
+pub fn prune(model: &Path, options: PruneOptions) -> Result<PruneReport, LiushuError> {
+    if !model.exists() {
+        return Err(LiushuError::ArtifactMissing(model.to_path_buf()));
+    }
+    let bytes_before = fs::metadata(model)
+        .with_path("read metadata of", model)?
+        .len();
+    let src = open_redb(model, "open model", || Database::open(model))?;
+    let order = model_order(&src)?;
+
+    let tmp = tmp_path(model, "tmp");
+    if tmp.exists() {
+        fs::remove_file(&tmp).with_path("remove", &tmp)?;
+    }
+    let dst = Database::create(&tmp).with_path("create model", &tmp)?;
+    let result = copy_counts(&src, &dst, order, options)
+        .and_then(|removed| write_model(&dst, order).map(|_| removed));
+    drop((src, dst));
+    let removed = match result {
+        Ok(removed) => removed,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    fs::rename(&tmp, model).with_path("replace model", model)?;
+
+    Ok(PruneReport {
+        removed,
+        bytes_before,
+        bytes_after: fs::metadata(model)
+            .with_path("read metadata of", model)?
+            .len(),
+    })
+}
+
+/// Lists the `.txt` files of a directory, sorted by name.
+pub fn corpus_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, LiushuError> {
+    let mut files = Vec::new();
+    let dir = dir.as_ref();
+    for entry in fs::read_dir(dir).with_path("list corpus dir", dir)? {
+        let path = entry.with_path("list corpus dir", dir)?.path();
+        if path.is_file() && CorpusFormat::of(&path).is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn train(
+    inputs: &[PathBuf],
+    save_to: &Path,
+    opts: TrainOptions,
+) -> Result<TrainReport, LiushuError> {
+    train_with_progress(inputs, save_to, opts, &NoProgress)
+}
+
+#[tracing::instrument(name = "train", skip_all, fields(order = opts.order))]
+pub fn train_with_progress(
+    inputs: &[PathBuf],
+    save_to: &Path,
+    opts: TrainOptions,
+    progress: &dyn ProgressSink,
+) -> Result<TrainReport, LiushuError> {
+    train_batched(inputs, save_to, opts, progress, BATCH_SIZE)
+}
+
+/// Trains into a temporary file next to `save_to`, which replaces `save_to` on success so a
+/// failed run leaves the existing model alone.
+fn train_batched(
+    inputs: &[PathBuf],
+    save_to: &Path,
+    opts: TrainOptions,
+    progress: &dyn ProgressSink,
+    batch_size: usize,
+) -> Result<TrainReport, LiushuError> {
+    let start = Instant::now();
+    if !matches!(opts.order, 2 | 3) {
+        return Err(LiushuError::InvalidInput(format!(
+            "unsupported n-gram order {}, expected 2 or 3",
+            opts.order
+        )));
+    }
+    opts.smoothing.validate()?;
+    if let Some(missing) = inputs
+        .iter()
+        .chain(&opts.emission_dicts)
+        .find(|input| !input.is_file())
+    {
+        return Err(LiushuError::Missing(missing.clone()));
+    }
+    let source = emission_source(inputs, &opts);
+
+    if let Some(parent) = save_to
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).with_path("create model dir", parent)?;
+    }
+    let model_dir = save_to
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    // an appended model is copied before it grows
+    let appended = (opts.append && save_to.exists()).then(|| save_to.to_path_buf());
+    let needed = preflight::required_space(
+        preflight::input_size(inputs.iter().chain(&opts.emission_dicts)),
+        preflight::TRAIN_RATIO,
+    ) + preflight::input_size(&appended) * 2;
+    preflight::check_with(&preflight::SystemFs, model_dir, needed)?;
+    let tmp = tmp_path(save_to, "tmp");
+    let pruned = tmp_path(save_to, "pruned.tmp");
+    for tmp in [&tmp, &pruned] {
+        if tmp.exists() {
+            fs::remove_file(tmp).with_path("remove", tmp)?;
+        }
+    }
+    if opts.append && save_to.exists() {
+        check_appendable(save_to, &opts, source)?;
+        fs::copy(save_to, &tmp).with_path("copy model", save_to)?;
+    }
+
+    let db = open_redb(&tmp, "create model", || Database::create(&tmp))?;
+    // the databases are dropped by the time it fails, for their files to be removed
+    let trained = (|| {
+        let mut report = count_corpus(&db, inputs, &opts, source, progress, batch_size)?;
+        let (db, written) = if opts.prune.is_active() {
+            let pruned_db = Database::create(&pruned).with_path("create model", &pruned)?;
+            let removed = copy_counts(&db, &pruned_db, opts.order, opts.prune)?;
+            info!(removed, "pruned transitions");
+            drop(db);
+            fs::remove_file(&tmp).with_path("remove", &tmp)?;
+            (pruned_db, &pruned)
+        } else {
+            (db, &tmp)
+        };
+
+        info!("writing model");
+        let stats = write_model(&db, opts.order)?;
+        drop(db);
+        fs::rename(written, save_to).with_path("replace model", save_to)?;
+
+        report.sequences = stats.sequences;
+        report.characters = stats.characters;
+        report.transitions = stats.transitions;
+        report.trigrams = stats.trigrams;
+        report.words = stats.words;
+        report.elapsed_secs = start.elapsed().as_secs_f64();
+        Ok(report)
+    })();
+    if trained.is_err() {
+        let _ = fs::remove_file(&tmp);
+        let _ = fs::remove_file(&pruned);
+    }
+    trained
+}
+
+pub(super) fn tmp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".");
+    tmp.push(suffix);
+    PathBuf::from(tmp)
+}
+
+/// Order of a model that keeps its raw counts.
+pub(super) fn model_order(db: &Database) -> Result<u64, LiushuError> {
+    let read_txn = db.begin_read()?;
+    let meta = read_txn.open_table(META_TABLE).map_err(|_| {
+        LiushuError::InvalidInput("the model has no raw counts, train it again first".to_string())
+    })?;
+    let order = meta.get("order")?.map_or(2, |v| v.value());
+    Ok(order)
+}
+
+fn check_appendable(
+    path: &Path,
+    opts: &TrainOptions,
+    source: EmissionSource,
+) -> Result<(), LiushuError> {
+    let model = Model::open(path)?;
+    let model_order = model_order(&model.db)?;
+    if model_order != opts.order {
+        return Err(LiushuError::InvalidInput(format!(
+            "the model has order {}, cannot append with order {}",
+            model_order, opts.order
+        )));
+    }
+    let granularity = model.granularity()?;
+    if granularity != opts.granularity {
+        return Err(LiushuError::InvalidInput(format!(
+            "the model has {} transitions, cannot append {} transitions",
+            granularity, opts.granularity
+        )));
+    }
+    let model_source = model.emission_source()?;
+    if model_source != source {
+        return Err(LiushuError::InvalidInput(format!(
+            "the model has {} emissions, cannot append {} emissions",
+            model_source, source
+        )));
+    }
+    Ok(())
+}
+
+/// An annotated input wins over the dictionaries, which win over the common readings.
+fn emission_source(inputs: &[PathBuf], opts: &TrainOptions) -> EmissionSource {
+    if inputs
+        .iter()
+        .any(|input| CorpusFormat::of(input) == Some(CorpusFormat::Annotated))
+    {
+        EmissionSource::Annotated
+    } else if !opts.emission_dicts.is_empty() {
+        EmissionSource::Dictionary
+    } else {
+        EmissionSource::Reading
+    }
+}
+
+/// Numbers of one input, added up in input order once every worker is done.
+#[derive(Debug, Default)]
+struct InputCounts {
+    lines: u64,
+    sequences: u64,
+    skipped: SkippedLines,
+}
+
+/// Adds the counts of every input to the model, the returned report only has the numbers of
+/// the inputs filled in.
+///
+/// Inputs are counted by `opts.jobs` workers, each sending its counts to this thread once they
+/// reach its share of `batch_size`. Only this thread writes to the model, and as counts are
+/// added up the model is the same for any number of jobs.
+fn count_corpus(
+    db: &Database,
+    inputs: &[PathBuf],
+    opts: &TrainOptions,
+    source: EmissionSource,
+    progress: &dyn ProgressSink,
+    batch_size: usize,
+) -> Result<TrainReport, LiushuError> {
+    let jobs = opts.jobs.clamp(1, inputs.len().max(1));
+    let batch_size = (batch_size / jobs).max(1);
+    let chinese_re = Regex::new(r#"[\u4e00-\u9fa5]{2,}"#).unwrap();
+    let next = AtomicUsize::new(0);
+    let lines = AtomicU64::new(0);
+    let (sender, receiver) = mpsc::sync_channel::<Counts>(jobs);
+
+    progress.on_start(
+        &format!("counting {} corpus files with {} jobs", inputs.len(), jobs),
+        None,
+    );
+    let mut results: Vec<Option<Result<InputCounts, LiushuError>>> =
+        inputs.iter().map(|_| None).collect();
+    let mut flushed = Ok(());
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                let sender = sender.clone();
+                let (next, lines, chinese_re) = (&next, &lines, &chinese_re);
+                scope.spawn(move || {
+                    let mut counts = Counts::default();
+                    let mut done = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(input) = inputs.get(index) else {
+                            break;
+                        };
+                        let mut worker = Worker {
+                            opts,
+                            source,
+                            chinese_re,
+                            counts: &mut counts,
+                            sender: &sender,
+                            batch_size,
+                            lines,
+                            progress,
+                        };
+                        done.push((index, worker.count(input)));
+                    }
+                    if !counts.is_empty() {
+                        // the receiver lives until every worker is done
+                        let _ = sender.send(counts);
+                    }
+                    done
+                })
+            })
+            .collect();
+        drop(sender);
+
+        for mut counts in receiver {
+            if flushed.is_ok() {
+                flushed = counts.flush(db);
+            }
+        }
+        for worker in workers {
+            match worker.join() {
+                Ok(done) => {
+                    for (index, result) in done {
+                        results[index] = Some(result);
+                    }
+                }
+                Err(_) if flushed.is_ok() => {
+                    flushed = Err(LiushuError::Other("a training worker panicked".to_string()));
+                }
+                Err(_) => {}
+            }
+        }
+    });
+    flushed?;
+
+    let mut report = InputCounts::default();
+    let mut diagnostics = Diagnostics::new();
+    for (input, result) in inputs.iter().zip(results) {
+        let Some(result) = result else {
+            continue;
+        };
+        let counted = result?;
+        if counted.skipped.malformed > 0 {
+            diagnostics.warn(Warning::MalformedLines {
+                path: input.clone(),
+                lines: counted.skipped.malformed,
+            });
+        }
+        report.lines += counted.lines;
+        report.sequences += counted.sequences;
+        let (skipped, counted) = (&mut report.skipped, counted.skipped);
+        skipped.blank += counted.blank;
+        skipped.short += counted.short;
+        skipped.malformed += counted.malformed;
+        skipped.no_chinese += counted.no_chinese;
+    }
+    progress.on_finish(&format!(
+        "{} lines from {} files",
+        report.lines,
+        inputs.len()
+    ));
+    if report.sequences == 0 {
+        return Err(LiushuError::InvalidInput(
+            "the corpus has no chinese text to train on".to_string(),
+        ));
+    }
+
+    let mut counts = Counts::default();
+    if source == EmissionSource::Dictionary {
+        for dict in &opts.emission_dicts {
+            counts.add_dictionary(dict)?;
+        }
+    }
+    counts.flush(db)?;
+
+    let trained = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let write_txn = db.begin_write()?;
+    {
+        let mut meta = write_txn.open_table(META_TABLE)?;
+        meta.insert("emission", source.to_meta())?;
+        meta.insert("granularity", opts.granularity.to_meta())?;
+        meta.insert("max_vocab", opts.max_vocab as u64)?;
+        let (smoothing, param) = opts.smoothing.to_meta();
+        meta.insert("smoothing", smoothing)?;
+        meta.insert("smoothing_param", param)?;
+        // seconds since the unix epoch
+        meta.insert("trained", trained)?;
+    }
+    write_txn.commit()?;
+
+    Ok(TrainReport {
+        inputs: inputs.len(),
+        lines: report.lines,
+        skipped: report.skipped,
+        emission: source,
+        sequences: report.sequences,
+        characters: 0,
+        transitions: 0,
+        trigrams: 0,
+        words: 0,
+        elapsed_secs: 0.0,
+        warnings: diagnostics.into_warnings(),
+    })
+}
+
+/// What a counting thread needs to count one input.
+struct Worker<'a> {
+    opts: &'a TrainOptions,
+    source: EmissionSource,
+    chinese_re: &'a Regex,
+    counts: &'a mut Counts,
+    sender: &'a SyncSender<Counts>,
+    batch_size: usize,
+    /// Lines counted by every worker, for the progress.
+    lines: &'a AtomicU64,
+    progress: &'a dyn ProgressSink,
+}
+
+impl Worker<'_> {
+    fn count(&mut self, input: &Path) -> Result<InputCounts, LiushuError> {
+        info!(corpus = %input.display(), "counting corpus");
+        let (opts, counts) = (self.opts, &mut *self.counts);
+        let format = CorpusFormat::of(input).unwrap_or(CorpusFormat::Text);
+        let mut result = InputCounts::default();
+        for line in open_input(input, "corpus")?.lines() {
+            let line = line.with_path(&format!("read line {} of", result.lines + 1), input)?;
+            result.lines += 1;
+            let before = result.sequences;
+            let counted = if format == CorpusFormat::Annotated {
+                opts.preprocess.annotated(&line).map(|runs| {
+                    for (seq, pinyins) in runs {
+                        counts.add_sequence(&seq, opts.order);
+                        for (word, py) in seq.chars().zip(&pinyins) {
+                            counts.add_emission(word, py, 1);
+                        }
+                        result.sequences += 1;
+                    }
+                })
+            } else {
+                opts.preprocess.text(&line, format).map(|text| {
+                    for sentence in opts.preprocess.sentences(&text) {
+                        let add = |counts: &mut Counts, sequences: &mut u64, seq: &str| {
+                            counts.add_sequence(seq, opts.order);
+                            if self.source == EmissionSource::Reading {
+                                counts.add_readings(seq);
+                            }
+                            *sequences += 1;
+                        };
+                        if opts.granularity == Granularity::Word {
+                            for words in word_runs(&sentence) {
+                                let seq = words.concat();
+                                if seq.chars().count() >= 2 {
+                                    add(counts, &mut result.sequences, &seq);
+                                }
+                                counts.add_words(&words);
+                            }
+                        } else {
+                            for seq in self.chinese_re.find_iter(&sentence) {
+                                add(counts, &mut result.sequences, seq.as_str());
+                            }
+                        }
+                    }
+                })
+            };
+            match counted {
+                Ok(()) => {
+                    if result.sequences == before {
+                        result.skipped.add(Skip::NoChinese);
+                    }
+                }
+                Err(skip) => {
+                    debug!(corpus = %input.display(), line = result.lines, ?skip, "skipping line");
+                    result.skipped.add(skip);
+                }
+            }
+            if counts.len() >= self.batch_size {
+                let _ = self.sender.send(mem::take(counts));
+            }
+            if result.lines % 10000 == 0 {
+                let total = self.lines.fetch_add(10000, Ordering::Relaxed) + 10000;
+                debug!(lines = total, "counting corpus");
+                self.progress.on_advance(total);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::InputMethodEngine;
+    use crate::hmm::Hmm;
+
+    fn write_corpus(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn trans_count(db: &Database, post: &str, pre: &str) -> Option<u64> {
+        let read_txn = db.begin_read().unwrap();
+        let table = read_txn.open_table(TRANS_COUNTS).unwrap();
+        let count = table.get((post, pre)).unwrap().map(|v| v.value());
+        count
+    }
+
+    #[test]
+    fn test_train_multiple_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [
+            write_corpus(dir.path(), "a.txt", "你好世界\nhello\n"),
+            write_corpus(dir.path(), "b.txt", "你好，中国"),
+        ];
+        let model = dir.path().join("model.redb");
+
+        let report = train(&inputs, &model, TrainOptions::default()).unwrap();
+        assert_eq!(report.inputs, 2);
+        assert_eq!(report.lines, 3);
+        assert_eq!(report.sequences, 3);
+        assert_eq!(report.characters, 6);
+
+        let db = Database::open(&model).unwrap();
+        assert_eq!(trans_count(&db, "好", "你"), Some(2));
+        assert_eq!(trans_count(&db, "你", "BOS"), Some(2));
+        assert_eq!(trans_count(&db, "EOS", "国"), Some(1));
+
+        let read_txn = db.begin_read().unwrap();
+        let states = read_txn.open_table(PINYIN_STATES).unwrap();
+        assert_eq!(states.get("ni").unwrap().unwrap().value(), "你");
+        let trans = read_txn.open_table(TRANS_TABLE).unwrap();
+        let prob = trans.get(("好", "你")).unwrap().unwrap().value();
+        assert!((prob - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_train_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = [write_corpus(dir.path(), "a.txt", "你好")];
+        let second = [write_corpus(dir.path(), "b.txt", "你好\n你们")];
+        let model = dir.path().join("model.redb");
+
+        train(&first, &model, TrainOptions::default()).unwrap();
+        let report = train(
+            &second,
+            &model,
+            TrainOptions {
+                append: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.sequences, 3);
+
+        let db = Database::open(&model).unwrap();
+        assert_eq!(trans_count(&db, "好", "你"), Some(2));
+        assert_eq!(trans_count(&db, "们", "你"), Some(1));
+        drop(db);
+
+        let report = train(&second, &model, TrainOptions::default()).unwrap();
+        assert_eq!(report.sequences, 2);
+        let db = Database::open(&model).unwrap();
+        assert_eq!(trans_count(&db, "好", "你"), Some(1));
+    }
+
+    #[test]
+    fn test_train_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("model.redb");
+        train(
+            &[write_corpus(dir.path(), "a.txt", "你好")],
+            &model,
+            TrainOptions::default(),
+        )
+        .unwrap();
+        let trained = fs::read(&model).unwrap();
+
+        let missing = dir.path().join("missing.txt");
+        let error = train(&[missing], &model, TrainOptions::default()).unwrap_err();
+        assert!(matches!(error, LiushuError::Missing(_)));
+
+        let empty = [write_corpus(dir.path(), "empty.txt", "hello\n\n")];
+        assert!(train(&empty, &model, TrainOptions::default()).is_err());
+        assert!(train(
+            &empty,
+            &model,
+            TrainOptions {
+                append: true,
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        let invalid = dir.path().join("invalid.txt");
+        fs::write(&invalid, b"\xe4\xbd\xa0\xe5\xa5\xbd\n\xff\n").unwrap();
+        let error = train(&[invalid], &model, TrainOptions::default()).unwrap_err();
+        assert!(error.to_string().starts_with("failed to read line 2 of ‘"));
+        assert!(error
+            .report()
+            .ends_with("invalid.txt’: stream did not contain valid UTF-8"));
+
+        assert_eq!(fs::read(&model).unwrap(), trained);
+    }
+
+    #[test]
+    fn test_train_creates_target_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = dir.path().join("target/model.redb");
+        let report = train(
+            &[write_corpus(dir.path(), "a.txt", "你好")],
+            &model,
+            TrainOptions {
+                append: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.sequences, 1);
+        assert!(model.exists());
+    }
+
+    #[test]
+    fn test_train_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(
+            dir.path(),
+            "a.txt",
+            "你好世界\n你好\n世界你好",
+        )];
+        let opts = TrainOptions {
+            append: false,
+            order: 3,
+            ..Default::default()
+        };
+        let batched = dir.path().join("batched.redb");
+        let whole = dir.path().join("whole.redb");
+        train_batched(&inputs, &batched, opts.clone(), &NoProgress, 1).unwrap();
+        let report = train(&inputs, &whole, opts).unwrap();
+        assert_eq!(report.sequences, 3);
+        assert!(report.trigrams > 0);
+
+        let (batched, whole) = (
+            Database::open(&batched).unwrap(),
+            Database::open(&whole).unwrap(),
+        );
+        let (batched, whole) = (batched.begin_read().unwrap(), whole.begin_read().unwrap());
+        let rows = |txn: &redb::ReadTransaction| -> Vec<(String, String, String, u64)> {
+            let table = txn.open_table(TRIGRAM_COUNTS).unwrap();
+            let rows = table
+                .iter()
+                .unwrap()
+                .map(|(key, value)| {
+                    let (post, pre, prepre) = key.value();
+                    (
+                        post.to_string(),
+                        pre.to_string(),
+                        prepre.to_string(),
+                        value.value(),
+                    )
+                })
+                .collect();
+            rows
+        };
+        assert_eq!(rows(&batched), rows(&whole));
+        let (post, pre, prepre) = ("好".to_string(), "你".to_string(), "BOS".to_string());
+        assert!(rows(&whole).contains(&(post, pre, prepre, 2)));
+    }
+
+    #[test]
+    fn test_train_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(dir.path(), "a.txt", "你好")];
+        let model = dir.path().join("model.redb");
+        train(&inputs, &model, TrainOptions::default()).unwrap();
+
+        let trigram = TrainOptions {
+            append: true,
+            order: 3,
+            ..Default::default()
+        };
+        assert!(train(&inputs, &model, trigram).is_err());
+        let quadgram = TrainOptions {
+            append: false,
+            order: 4,
+            ..Default::default()
+        };
+        assert!(train(&inputs, &model, quadgram).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(
+            dir.path(),
+            "a.txt",
+            "你好\n你好\n你们\n你我\n好人\n",
+        )];
+        let model = dir.path().join("model.redb");
+        train(&inputs, &model, TrainOptions::default()).unwrap();
+
+        let top = PruneOptions {
+            max_transitions_per_state: Some(1),
+            ..Default::default()
+        };
+        // BOS 好, 你 们, 你 我 and 好 人, which lost against 好 EOS
+        assert_eq!(prune(&model, top).unwrap().removed, 4);
+        {
+            let db = Database::open(&model).unwrap();
+            assert_eq!(trans_count(&db, "好", "你"), Some(2));
+            assert_eq!(trans_count(&db, "们", "你"), None);
+            assert_eq!(trans_count(&db, "人", "好"), None);
+            assert_eq!(trans_count(&db, "EOS", "人"), Some(1));
+        }
+
+        let min = PruneOptions {
+            min_count: 2,
+            ..Default::default()
+        };
+        let report = prune(&model, min).unwrap();
+        assert_eq!(report.removed, 3);
+        assert!(report.bytes_after > 0);
+
+        let db = Database::open(&model).unwrap();
+        let read_txn = db.begin_read().unwrap();
+        let trans: Vec<_> = read_txn
+            .open_table(TRANS_TABLE)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(|(_, value)| value.value())
+            .collect();
+        // each successor is left with a single predecessor holding all the mass
+        assert_eq!(trans, vec![0.0; 3]);
+        drop(read_txn);
+        let hmm = Hmm::new(db, &model);
+        assert_eq!(hmm.search("nihao").unwrap()[0].text, "你好");
+    }
+
+    #[test]
+    fn test_train_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(dir.path(), "a.txt", "你好\n你好\n你们\n")];
+        let model = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            order: 3,
+            prune: PruneOptions {
+                min_count: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let report = train(&inputs, &model, opts).unwrap();
+        assert_eq!(report.transitions, 3);
+        assert!(!dir.path().join("model.redb.pruned.tmp").exists());
+
+        let db = Database::open(&model).unwrap();
+        assert_eq!(trans_count(&db, "们", "你"), None);
+        let hmm = Hmm::new(db, &model);
+        assert_eq!(hmm.search("nihao").unwrap()[0].text, "你好");
+    }
+
+    #[test]
+    fn test_train_pruned_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(dir.path(), "a.txt", "你好\n你好\n你们\n")];
+        // a dir the pruned model can't replace
+        let model = dir.path().join("model.redb");
+        fs::create_dir(&model).unwrap();
+        fs::write(model.join("kept"), "").unwrap();
+        let opts = TrainOptions {
+            prune: PruneOptions {
+                min_count: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let error = train(&inputs, &model, opts).unwrap_err();
+        assert!(
+            error.report().contains("replace model"),
+            "{}",
+            error.report()
+        );
+        assert!(!dir.path().join("model.redb.tmp").exists());
+        assert!(!dir.path().join("model.redb.pruned.tmp").exists());
+    }
+
+    #[test]
+    fn test_train_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let jsonl = write_corpus(
+            dir.path(),
+            "a.jsonl",
+            concat!(
+                "{\"body\": \"你好\"}\n",
+                "{\"body\": \"你好，世界\"}\n",
+                "\n",
+                "not json\n",
+                "{\"text\": \"你好\"}\n",
+                "{\"body\": \"Hello\"}\n",
+            ),
+        );
+        let gz = dir.path().join("b.txt.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&gz).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::Write::write_all(&mut encoder, "世界，你好\n".as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let model = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            preprocess: Preprocess {
+                boundaries: Some("。".to_string()),
+                json_field: "body".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let report = train(&[jsonl.clone(), gz], &model, opts).unwrap();
+        assert_eq!(report.lines, 7);
+        assert_eq!(
+            report.skipped,
+            SkippedLines {
+                blank: 1,
+                short: 0,
+                malformed: 2,
+                no_chinese: 1,
+            }
+        );
+        assert_eq!(report.sequences, 3);
+        // only the malformed lines are a warning, of the file they are in
+        assert_eq!(
+            report.warnings,
+            [Warning::MalformedLines {
+                path: jsonl,
+                lines: 2
+            }]
+        );
+
+        // the comma is stripped rather than ending the sentence
+        let db = Database::open(&model).unwrap();
+        assert_eq!(trans_count(&db, "世", "好"), Some(1));
+        assert_eq!(trans_count(&db, "你", "界"), Some(1));
+    }
+
+    #[test]
+    fn test_train_annotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(
+            dir.path(),
+            "a.tsv",
+            "长城很长\tchang2 cheng2 hen3 chang2\n\
+             校长\txiao4 zhang3\n\
+             长大了\tzhang3 da4 le\n\
+             长大\tzhang3\n",
+        )];
+        let model = dir.path().join("model.redb");
+        let report = train(&inputs, &model, TrainOptions::default()).unwrap();
+        assert_eq!(report.emission, EmissionSource::Annotated);
+        assert_eq!(report.skipped.malformed, 1);
+
+        let model = Model::open(&model).unwrap();
+        assert_eq!(model.emission_source().unwrap(), EmissionSource::Annotated);
+        let half = 0.5_f64.ln();
+        let chang = model.emission("chang", "长").unwrap().unwrap();
+        let zhang = model.emission("zhang", "长").unwrap().unwrap();
+        assert!((chang - half).abs() < 1e-9 && (zhang - half).abs() < 1e-9);
+
+        let hmm = Hmm::with_model(model);
+        assert_eq!(hmm.search("zhangda").unwrap()[0].text, "长大");
+        assert_eq!(hmm.search("changcheng").unwrap()[0].text, "长城");
+        assert_eq!(hmm.search("henchang").unwrap()[0].text, "很长");
+    }
+
+    #[test]
+    fn test_train_dictionary_emissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(dir.path(), "a.txt", "长大\n")];
+        let dict = write_corpus(
+            dir.path(),
+            "pinyin.dict.tsv",
+            "text\tcode\tweight\tcomment\n\
+             长\tchang\t100\t\n\
+             长\tzhang\t300\t\n\
+             长大\tzhangda\t50\t\n\
+             大\tda\t10\t\n",
+        );
+        let path = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            emission_dicts: vec![dict],
+            ..Default::default()
+        };
+        train(&inputs, &path, opts).unwrap();
+
+        let model = Model::open(&path).unwrap();
+        assert_eq!(model.emission_source().unwrap(), EmissionSource::Dictionary);
+        let zhang = model.emission("zhang", "长").unwrap().unwrap();
+        assert!((zhang - 0.75_f64.ln()).abs() < 1e-9);
+        assert_eq!(model.states("da").unwrap(), ["大"]);
+        drop(model);
+
+        // the plain readings would not know 长 as zhang
+        let err = train(
+            &inputs,
+            &path,
+            TrainOptions {
+                append: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("dictionary emissions"));
+    }
+
+    #[test]
+    fn test_train_words() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [write_corpus(
+            dir.path(),
+            "a.txt",
+            "中国 人民\n中国 人民 站起来\n我 的\nhello world\n",
+        )];
+        let path = dir.path().join("model.redb");
+        let opts = TrainOptions {
+            granularity: Granularity::Word,
+            max_vocab: 3,
+            ..Default::default()
+        };
+        let report = train(&inputs, &path, opts.clone()).unwrap();
+        assert_eq!((report.sequences, report.words), (3, 3));
+        assert_eq!(report.skipped.no_chinese, 1);
+
+        // the words of a run are counted as one sequence of characters
+        let model = Model::open(&path).unwrap();
+        assert_eq!(trans_count(&model.db, "人", "国"), Some(2));
+        assert_eq!(model.granularity().unwrap(), Granularity::Word);
+        let prob = |pre, post| model.word_transition(pre, post).unwrap();
+        assert!((prob("BOS", "中国").unwrap() - (2.0_f64 / 3.0).ln()).abs() < 1e-9);
+        assert_eq!(prob("中国", "人民"), Some(0.0));
+        // 站起来 and 的 are out of the vocabulary
+        assert_eq!(prob("人民", "站起来"), Some(0.5_f64.ln()));
+        assert_eq!(prob("人民", "的"), prob("人民", "站起来"));
+        assert_eq!(prob("的", "EOS"), Some(0.0));
+        assert_eq!(prob("我", "人民"), None);
+        drop(model);
+
+        let err = train(
+            &inputs,
+            &path,
+            TrainOptions {
+                append: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("word transitions"));
+        let report = train(
+            &inputs,
+            &path,
+            TrainOptions {
+                append: true,
+                max_vocab: 10,
+                ..opts
+            },
+        )
+        .unwrap();
+        assert_eq!(report.words, 5);
+    }
+
+    /// A corpus of `files` files of `lines` lines of characters cycling through a few words.
+    fn generate_corpus(dir: &Path, files: usize, lines: usize) -> Vec<PathBuf> {
+        let words = [
+            "你好", "世界", "中国", "长城", "很长", "天气", "今天", "明天",
+        ];
+        (0..files)
+            .map(|file| {
+                let contents: String = (0..lines)
+                    .map(|line| {
+                        let len = 2 + (file + line) % 5;
+                        let mut text: String = (0..len)
+                            .map(|i| words[(file * 7 + line * 3 + i * i) % words.len()])
+                            .collect();
+                        text.push_str("。\n");
+                        text
+                    })
+                    .collect();
+                write_corpus(dir, &format!("{}.txt", file), &contents)
+            })
+            .collect()
+    }
+
+    fn dump(path: &Path) -> Vec<String> {
+        let db = Database::open(path).unwrap();
+        let read_txn = db.begin_read().unwrap();
+        let mut rows = vec![];
+        for (key, value) in read_txn.open_table(META_TABLE).unwrap().iter().unwrap() {
+            if key.value() != "trained" {
+                rows.push(format!("meta {} {}", key.value(), value.value()));
+            }
+        }
+        for (key, value) in read_txn.open_table(INIT_COUNTS).unwrap().iter().unwrap() {
+            rows.push(format!("init {} {}", key.value(), value.value()));
+        }
+        for (key, value) in read_txn.open_table(TRANS_COUNTS).unwrap().iter().unwrap() {
+            rows.push(format!("trans {:?} {}", key.value(), value.value()));
+        }
+        for (key, value) in read_txn.open_table(TRIGRAM_COUNTS).unwrap().iter().unwrap() {
+            rows.push(format!("trigram {:?} {}", key.value(), value.value()));
+        }
+        for (key, value) in read_txn.open_table(EMISS_COUNTS).unwrap().iter().unwrap() {
+            rows.push(format!("emiss {:?} {}", key.value(), value.value()));
+        }
+        for (key, value) in read_txn.open_table(TRANS_TABLE).unwrap().iter().unwrap() {
+            rows.push(format!("trans_prob {:?} {}", key.value(), value.value()));
+        }
+        rows
+    }
+
+    #[test]
+    fn test_train_jobs_deterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = generate_corpus(dir.path(), 6, 50);
+        let train_with = |jobs: usize, batch_size: usize| {
+            let model = dir
+                .path()
+                .join(format!("model{}-{}.redb", jobs, batch_size));
+            let opts = TrainOptions {
+                order: 3,
+                jobs,
+                ..Default::default()
+            };
+            let report = train_batched(&inputs, &model, opts, &NoProgress, batch_size).unwrap();
+            (report.lines, report.sequences, dump(&model))
+        };
+
+        let single = train_with(1, BATCH_SIZE);
+        assert_eq!(single.0, 300);
+        assert_eq!(train_with(4, BATCH_SIZE), single);
+        assert_eq!(train_with(4, 16), single);
+        assert_eq!(train_with(16, 1), single);
+    }
+
+    #[test]
+    fn test_train_jobs_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inputs = generate_corpus(dir.path(), 3, 10);
+        inputs.insert(1, write_corpus(dir.path(), "bad.txt", ""));
+        fs::write(&inputs[1], [0xff, 0xfe, b'\n']).unwrap();
+        let opts = TrainOptions {
+            jobs: 4,
+            ..Default::default()
+        };
+        let err = train(&inputs, &dir.path().join("model.redb"), opts).unwrap_err();
+        assert!(err.to_string().contains("line 1 of"));
+        assert_eq!(err.path(), Some(inputs[1].as_path()));
+        assert!(!dir.path().join("model.redb").exists());
+    }
+
+    /// Run with `cargo test --release -p liushu-core bench_train_jobs -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_train_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = generate_corpus(dir.path(), 16, 50_000);
+        let jobs = thread::available_parallelism().map_or(4, |n| n.get().max(2));
+        for jobs in [1, jobs] {
+            let opts = TrainOptions {
+                jobs,
+                ..Default::default()
+            };
+            let start = Instant::now();
+            train(
+                &inputs,
+                &dir.path().join(format!("model{}.redb", jobs)),
+                opts,
+            )
+            .unwrap();
+            println!("{} jobs: {:.2}s", jobs, start.elapsed().as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn test_corpus_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_corpus(dir.path(), "b.txt", "");
+        write_corpus(dir.path(), "a.txt", "");
+        write_corpus(dir.path(), "c.json", "");
+        write_corpus(dir.path(), "d.jsonl.gz", "");
+
+        let files = corpus_files(dir.path()).unwrap();
+        assert_eq!(
+            files,
+            [
+                dir.path().join("a.txt"),
+                dir.path().join("b.txt"),
+                dir.path().join("d.jsonl.gz")
+            ]
+        );
+    }
+}
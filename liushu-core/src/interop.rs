@@ -0,0 +1,4 @@
+//! Adapters to what frontends of other input method frameworks expect, so that they can
+//! switch to liushu without rewriting how they draw candidates.
+
+pub mod rime;
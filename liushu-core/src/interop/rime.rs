@@ -0,0 +1,464 @@
+//! Rime's candidate semantics: [`RimeContext`] is the `RimeContext` of librime's
+//! `rime_api.h`, and [`process_key`] takes the paging and selection keys of its default key
+//! bindings, named like Rime names them.
+//!
+//! | keys                            | action                                  |
+//! |---------------------------------|-----------------------------------------|
+//! | `Page_Up`, `minus`, `comma`     | previous page                           |
+//! | `Page_Down`, `equal`, `period`  | next page                               |
+//! | `Up`, `Down`                    | move the highlight, across pages        |
+//! | `space`                         | select the highlighted candidate        |
+//! | `1` to `9`, `0`                 | select the 1st to 9th, 10th of the page |
+//! | `BackSpace`                     | delete the last character of the code,  |
+//! |                                 | else unselect the last segment          |
+//! | `Escape`                        | clear the composition                   |
+//!
+//! The [associations](Composition::associated) of a commit are a menu without a preedit,
+//! each candidate the rest of its phrase. They take the paging and selection keys but
+//! `space`, which dismisses them like `BackSpace` and every other key, left to the app.
+
+use serde::Serialize;
+
+use crate::composition::{Commit, Composition, Selected};
+use crate::engine::{InputMethodEngine, SearchResultItem};
+use crate::error::LiushuError;
+
+/// The default `menu/page_size` of Rime.
+pub const PAGE_SIZE: usize = 5;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RimeContext {
+    pub composition: RimeComposition,
+    pub menu: RimeMenu,
+    /// What committing the highlighted candidate would commit, with the code after its
+    /// segment as typed.
+    pub commit_text_preview: Option<String>,
+}
+
+/// The preedit, the text selected for the segments before the active one followed by the
+/// code left. Offsets are in bytes, like those of librime.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RimeComposition {
+    pub length: usize,
+    pub cursor_pos: usize,
+    pub sel_start: usize,
+    pub sel_end: usize,
+    pub preedit: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RimeMenu {
+    pub page_size: usize,
+    /// From 0.
+    pub page_no: usize,
+    pub is_last_page: bool,
+    pub highlighted_candidate_index: usize,
+    pub num_candidates: usize,
+    pub candidates: Vec<RimeCandidate>,
+    /// `None` for the digits.
+    pub select_keys: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RimeCandidate {
+    pub text: String,
+    /// The comment of the entry, else the rest of its code prefixed with `~` when it is a
+    /// completion of the segment, as Rime's table translator shows them.
+    pub comment: Option<String>,
+}
+
+impl From<&Composition> for RimeContext {
+    fn from(composition: &Composition) -> Self {
+        if let Some(associated) = composition.associated() {
+            let candidates: Vec<_> = composition
+                .page_candidates()
+                .iter()
+                .map(|item| RimeCandidate {
+                    text: rest(item, associated).to_string(),
+                    comment: item.comment.clone(),
+                })
+                .collect();
+            return Self {
+                composition: RimeComposition {
+                    length: 0,
+                    cursor_pos: 0,
+                    sel_start: 0,
+                    sel_end: 0,
+                    preedit: None,
+                },
+                menu: RimeMenu {
+                    page_size: composition.page_size(),
+                    page_no: composition.page(),
+                    is_last_page: composition.page() + 1 >= composition.page_count(),
+                    highlighted_candidate_index: composition.highlighted(),
+                    num_candidates: candidates.len(),
+                    candidates,
+                    select_keys: None,
+                },
+                commit_text_preview: composition
+                    .highlighted_candidate()
+                    .map(|item| rest(item, associated).to_string()),
+            };
+        }
+        if composition.is_empty() {
+            return Self {
+                composition: RimeComposition {
+                    length: 0,
+                    cursor_pos: 0,
+                    sel_start: 0,
+                    sel_end: 0,
+                    preedit: None,
+                },
+                menu: RimeMenu {
+                    page_size: composition.page_size(),
+                    page_no: 0,
+                    is_last_page: true,
+                    highlighted_candidate_index: 0,
+                    num_candidates: 0,
+                    candidates: Vec::new(),
+                    select_keys: None,
+                },
+                commit_text_preview: None,
+            };
+        }
+
+        let selected = composition.selected_text();
+        let segment = &composition.code()[..composition.matched_len()];
+        let preedit = format!("{}{}", selected, composition.code());
+        let candidates: Vec<_> = composition
+            .page_candidates()
+            .iter()
+            .map(|item| candidate(item, segment))
+            .collect();
+        let commit_text_preview = composition.highlighted_candidate().map(|item| {
+            let rest = &composition.code()[composition.matched_len()..];
+            format!("{}{}{}", selected, item.text, rest)
+        });
+        Self {
+            composition: RimeComposition {
+                length: preedit.len(),
+                cursor_pos: preedit.len(),
+                sel_start: selected.len(),
+                sel_end: selected.len() + segment.len(),
+                preedit: Some(preedit),
+            },
+            menu: RimeMenu {
+                page_size: composition.page_size(),
+                page_no: composition.page(),
+                is_last_page: composition.page() + 1 >= composition.page_count(),
+                highlighted_candidate_index: composition.highlighted(),
+                num_candidates: candidates.len(),
+                candidates,
+                select_keys: None,
+            },
+            commit_text_preview,
+        }
+    }
+}
+
+/// What selecting the association `item` of `associated` commits.
+fn rest<'a>(item: &'a SearchResultItem, associated: &str) -> &'a str {
+    item.text.strip_prefix(associated).unwrap_or(&item.text)
+}
+
+fn candidate(item: &SearchResultItem, segment: &str) -> RimeCandidate {
+    let completion = item
+        .code
+        .strip_prefix(segment)
+        .filter(|rest| !rest.is_empty())
+        .map(|rest| format!("~{}", rest));
+    RimeCandidate {
+        text: item.text.clone(),
+        comment: item.comment.clone().or(completion),
+    }
+}
+
+/// What a key did, like the result of librime's `process_key`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyOutcome {
+    /// The key isn't bound, or selects no candidate.
+    Ignored,
+    Handled,
+    Committed(Commit),
+}
+
+/// Handles `key` like Rime would while composing, see the [module docs](self). Every key
+/// is ignored when nothing is being composed.
+pub fn process_key(
+    composition: &mut Composition,
+    engine: &dyn InputMethodEngine,
+    key: &str,
+) -> Result<KeyOutcome, LiushuError> {
+    let associating = composition.associated().is_some();
+    if composition.is_empty() && !associating {
+        return Ok(KeyOutcome::Ignored);
+    }
+    if associating && matches!(key, "space" | "BackSpace") {
+        composition.clear();
+        return Ok(KeyOutcome::Ignored);
+    }
+    let index = match key {
+        "Page_Up" | "minus" | "comma" => {
+            composition.prev_page();
+            return Ok(KeyOutcome::Handled);
+        }
+        "Page_Down" | "equal" | "period" => {
+            composition.next_page();
+            return Ok(KeyOutcome::Handled);
+        }
+        "Up" => {
+            composition.highlight_prev();
+            return Ok(KeyOutcome::Handled);
+        }
+        "Down" => {
+            composition.highlight_next();
+            return Ok(KeyOutcome::Handled);
+        }
+        "BackSpace" => {
+            composition.backspace(engine)?;
+            return Ok(KeyOutcome::Handled);
+        }
+        "Escape" => {
+            composition.clear();
+            return Ok(KeyOutcome::Handled);
+        }
+        "space" => composition.highlighted(),
+        "0" => 9,
+        _ => match key.parse::<usize>() {
+            Ok(n @ 1..=9) if key.len() == 1 => n - 1,
+            _ => {
+                if associating {
+                    composition.clear();
+                }
+                return Ok(KeyOutcome::Ignored);
+            }
+        },
+    };
+    Ok(match composition.select(engine, index)? {
+        Selected::Missing => KeyOutcome::Ignored,
+        Selected::Continued => KeyOutcome::Handled,
+        Selected::Committed(commit) => KeyOutcome::Committed(commit),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::engine::MemoryEngine;
+
+    fn fixture() -> MemoryEngine {
+        [
+            ("你", "ni", Some("〔亻尔〕")),
+            ("尼", "ni", None),
+            ("泥", "ni", None),
+            ("拟", "ni", None),
+            ("逆", "ni", None),
+            ("腻", "ni", None),
+            ("你好", "nihao", None),
+            ("吗", "ma", None),
+            ("妈", "ma", None),
+        ]
+        .into_iter()
+        .map(|(text, code, comment)| SearchResultItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            weight: 1,
+            comment: comment.map(String::from),
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        })
+        .collect()
+    }
+
+    fn key(composition: &mut Composition, engine: &MemoryEngine, key: &str) -> KeyOutcome {
+        process_key(composition, engine, key).unwrap()
+    }
+
+    #[test]
+    fn test_context() {
+        let engine = fixture();
+        let mut composition = Composition::new(PAGE_SIZE);
+        composition.set_input(&engine, "nima", "").unwrap();
+        assert_eq!(key(&mut composition, &engine, "equal"), KeyOutcome::Handled);
+        assert_eq!(key(&mut composition, &engine, "Down"), KeyOutcome::Handled);
+
+        assert_eq!(
+            json!(RimeContext::from(&composition)),
+            json!({
+                "composition": {
+                    "length": 4,
+                    "cursor_pos": 4,
+                    "sel_start": 0,
+                    "sel_end": 2,
+                    "preedit": "nima",
+                },
+                "menu": {
+                    "page_size": 5,
+                    "page_no": 1,
+                    "is_last_page": true,
+                    "highlighted_candidate_index": 1,
+                    "num_candidates": 2,
+                    "candidates": [
+                        { "text": "腻", "comment": null },
+                        { "text": "你好", "comment": "~hao" },
+                    ],
+                    "select_keys": null,
+                },
+                "commit_text_preview": "你好ma",
+            })
+        );
+
+        // the first segment is selected, the second is active
+        assert_eq!(
+            key(&mut composition, &engine, "Page_Up"),
+            KeyOutcome::Handled
+        );
+        assert_eq!(key(&mut composition, &engine, "1"), KeyOutcome::Handled);
+        assert_eq!(
+            json!(RimeContext::from(&composition)),
+            json!({
+                "composition": {
+                    "length": 5,
+                    "cursor_pos": 5,
+                    "sel_start": 3,
+                    "sel_end": 5,
+                    "preedit": "你ma",
+                },
+                "menu": {
+                    "page_size": 5,
+                    "page_no": 0,
+                    "is_last_page": true,
+                    "highlighted_candidate_index": 0,
+                    "num_candidates": 2,
+                    "candidates": [
+                        { "text": "吗", "comment": null },
+                        { "text": "妈", "comment": null },
+                    ],
+                    "select_keys": null,
+                },
+                "commit_text_preview": "你吗",
+            })
+        );
+    }
+
+    #[test]
+    fn test_process_key() {
+        let engine = fixture();
+        let mut composition = Composition::new(PAGE_SIZE);
+        assert_eq!(key(&mut composition, &engine, "space"), KeyOutcome::Ignored);
+
+        composition.set_input(&engine, "ni", "").unwrap();
+        assert_eq!(
+            RimeContext::from(&composition).menu.candidates[0]
+                .comment
+                .as_deref(),
+            Some("〔亻尔〕")
+        );
+        for ignored in ["7", "12", "a", "Return"] {
+            assert_eq!(key(&mut composition, &engine, ignored), KeyOutcome::Ignored);
+        }
+        assert_eq!(
+            key(&mut composition, &engine, "period"),
+            KeyOutcome::Handled
+        );
+        assert_eq!(
+            key(&mut composition, &engine, "period"),
+            KeyOutcome::Handled
+        );
+        assert_eq!(composition.page(), 1);
+        assert_eq!(key(&mut composition, &engine, "Up"), KeyOutcome::Handled);
+        assert_eq!(
+            key(&mut composition, &engine, "space"),
+            KeyOutcome::Committed(Commit {
+                text: "逆".to_string(),
+                code: "ni".to_string(),
+                indices: vec![4],
+            })
+        );
+        assert_eq!(RimeContext::from(&composition).composition.preedit, None);
+
+        // typing on after a selection is backspaced over, back into its code
+        composition.set_input(&engine, "nima", "").unwrap();
+        assert_eq!(key(&mut composition, &engine, "1"), KeyOutcome::Handled);
+        let preedit = |composition: &Composition| RimeContext::from(composition).composition;
+        for expected in ["你m", "你", "ni", "n"] {
+            assert_eq!(
+                key(&mut composition, &engine, "BackSpace"),
+                KeyOutcome::Handled
+            );
+            assert_eq!(preedit(&composition).preedit.as_deref(), Some(expected));
+        }
+        assert_eq!(preedit(&composition).sel_end, 1);
+        assert_eq!(
+            key(&mut composition, &engine, "BackSpace"),
+            KeyOutcome::Handled
+        );
+        assert!(composition.is_empty());
+        assert_eq!(
+            key(&mut composition, &engine, "BackSpace"),
+            KeyOutcome::Ignored
+        );
+
+        composition.set_input(&engine, "ni", "").unwrap();
+        assert_eq!(
+            key(&mut composition, &engine, "Escape"),
+            KeyOutcome::Handled
+        );
+        assert!(composition.is_empty());
+    }
+
+    /// The fixture, associating `你好` with `你`.
+    struct Associating(MemoryEngine);
+
+    impl InputMethodEngine for Associating {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            self.0.search(code)
+        }
+
+        fn associations(
+            &self,
+            committed: &str,
+            _limit: usize,
+        ) -> Result<Vec<SearchResultItem>, LiushuError> {
+            let items = self.0.search("nihao")?;
+            Ok(items.into_iter().filter(|_| committed == "你").collect())
+        }
+    }
+
+    #[test]
+    fn test_associations() {
+        let engine = Associating(fixture());
+        let mut composition = Composition::new(PAGE_SIZE);
+        composition.set_input(&engine, "ni", "").unwrap();
+        assert!(matches!(
+            process_key(&mut composition, &engine, "1").unwrap(),
+            KeyOutcome::Committed(Commit { text, .. }) if text == "你"
+        ));
+        let context = RimeContext::from(&composition);
+        assert_eq!(context.composition.preedit, None);
+        assert_eq!(
+            context.menu.candidates,
+            [RimeCandidate {
+                text: "好".to_string(),
+                comment: None,
+            }]
+        );
+        assert_eq!(context.commit_text_preview.as_deref(), Some("好"));
+        assert!(matches!(
+            process_key(&mut composition, &engine, "1").unwrap(),
+            KeyOutcome::Committed(Commit { text, .. }) if text == "好"
+        ));
+
+        // left to the app
+        composition.set_input(&engine, "ni", "").unwrap();
+        process_key(&mut composition, &engine, "1").unwrap();
+        assert_eq!(
+            process_key(&mut composition, &engine, "space").unwrap(),
+            KeyOutcome::Ignored
+        );
+        assert_eq!(RimeContext::from(&composition).menu.num_candidates, 0);
+    }
+}
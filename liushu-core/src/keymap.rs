@@ -0,0 +1,191 @@
+//! Keys remapped to the characters of codes, for typing on a layout other than the one the
+//! codes of a formula assume. Shape codes name the keys of a QWERTY keyboard, so on Dvorak
+//! the key QWERTY has `q` on types `'` and is remapped to `q`, and every code is typed on
+//! the same keys as on QWERTY.
+//!
+//! A keymap is a bijection: every character remapped is one that some other key is
+//! remapped from, so no two keys type the same code and every code can be typed. Keys it
+//! leaves out are typed as they are.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::LiushuError;
+
+/// The keys of QWERTY each preset has, in the order of [`QWERTY`].
+const PRESETS: [(&str, &str); 2] = [
+    ("dvorak", "',.pyfgcrlaoeuidhtns;qjkxbmwvz-/=[]"),
+    ("colemak", "qwfpgjluy;arstdhneiozxcvbkm,./'[]-="),
+];
+
+/// The keys of QWERTY, row by row and then the punctuation on the right of the letters.
+const QWERTY: &str = "qwertyuiopasdfghjkl;zxcvbnm,./'[]-=";
+
+/// How a keymap is written in the config: the name of a preset, `"dvorak"` or `"colemak"`,
+/// or the `keys` typed and the `codes` each of them stands for, in the same order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeymapConfig {
+    Preset(String),
+    Custom { keys: String, codes: String },
+}
+
+/// Keys remapped to the characters of codes, see the [module](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "KeymapConfig", into = "KeymapConfig")]
+pub struct Keymap {
+    /// Those it was made of, for the config it is written back as.
+    keys: Arc<str>,
+    codes: Arc<str>,
+    to_code: Arc<HashMap<char, char>>,
+    to_key: Arc<HashMap<char, char>>,
+}
+
+impl Keymap {
+    /// One of the presets, from the layout typed on to QWERTY.
+    pub fn preset(name: &str) -> Result<Self, LiushuError> {
+        let (_, keys) = PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+                LiushuError::InvalidInput(format!(
+                    "unknown keymap {}, the presets are {}",
+                    name,
+                    names.join(", ")
+                ))
+            })?;
+        Self::new(keys, QWERTY)
+    }
+
+    /// Remaps the `n`th character of `keys` to the `n`th of `codes`, which must be a
+    /// bijection. A key remapped to itself is left out.
+    pub fn new(keys: &str, codes: &str) -> Result<Self, LiushuError> {
+        if keys.chars().count() != codes.chars().count() {
+            return Err(LiushuError::InvalidInput(format!(
+                "the keymap has {} keys but {} codes",
+                keys.chars().count(),
+                codes.chars().count()
+            )));
+        }
+        let mut to_code = HashMap::new();
+        let mut to_key = HashMap::new();
+        for (key, code) in keys.chars().zip(codes.chars()) {
+            if let Some(other) = to_code.insert(key, code).filter(|&other| other != code) {
+                return Err(LiushuError::InvalidInput(format!(
+                    "the keymap remaps {} to both {} and {}",
+                    key, other, code
+                )));
+            }
+            if let Some(other) = to_key.insert(code, key).filter(|&other| other != key) {
+                return Err(LiushuError::InvalidInput(format!(
+                    "the keymap remaps both {} and {} to {}",
+                    other, key, code
+                )));
+            }
+        }
+        to_code.retain(|key, code| key != code);
+        to_key.retain(|code, key| key != code);
+        // a character remapped from no key is typed by its own as well
+        if let Some((key, code)) = to_code.iter().find(|(_, code)| !to_code.contains_key(code)) {
+            return Err(LiushuError::InvalidInput(format!(
+                "the keymap remaps {} to {} but doesn't remap {}, which would type {} too",
+                key, code, code, code
+            )));
+        }
+        Ok(Self {
+            keys: keys.into(),
+            codes: codes.into(),
+            to_code: Arc::new(to_code),
+            to_key: Arc::new(to_key),
+        })
+    }
+
+    /// The code `keys` type.
+    pub fn code<'a>(&self, keys: &'a str) -> Cow<'a, str> {
+        Self::remap(&self.to_code, keys)
+    }
+
+    /// The keys typing `code`, the other way around.
+    pub fn keys<'a>(&self, code: &'a str) -> Cow<'a, str> {
+        Self::remap(&self.to_key, code)
+    }
+
+    fn remap<'a>(map: &HashMap<char, char>, from: &'a str) -> Cow<'a, str> {
+        if !from.chars().any(|c| map.contains_key(&c)) {
+            return Cow::Borrowed(from);
+        }
+        from.chars()
+            .map(|c| map.get(&c).copied().unwrap_or(c))
+            .collect()
+    }
+}
+
+impl TryFrom<KeymapConfig> for Keymap {
+    type Error = LiushuError;
+
+    fn try_from(config: KeymapConfig) -> Result<Self, Self::Error> {
+        match config {
+            KeymapConfig::Preset(name) => Self::preset(&name),
+            KeymapConfig::Custom { keys, codes } => Self::new(&keys, &codes),
+        }
+    }
+}
+
+impl From<Keymap> for KeymapConfig {
+    fn from(keymap: Keymap) -> Self {
+        KeymapConfig::Custom {
+            keys: keymap.keys.to_string(),
+            codes: keymap.codes.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let dvorak = Keymap::preset("dvorak").unwrap();
+        // the keys of QWERTY typing `nihao` and `sunman`
+        assert_eq!(dvorak.code("bcdar"), "nihao");
+        assert_eq!(dvorak.keys("sunman"), "ogbmab");
+        assert_eq!(dvorak.code("ogbmab"), "sunman");
+        assert!(matches!(dvorak.code("am"), Cow::Borrowed("am")));
+
+        let colemak = Keymap::preset("colemak").unwrap();
+        assert_eq!(colemak.code("kuhay"), "nihao");
+        assert_eq!(colemak.keys("nihao"), "kuhay");
+        assert_eq!(
+            Keymap::preset("azerty").unwrap_err().to_string(),
+            "invalid input: unknown keymap azerty, the presets are dvorak, colemak"
+        );
+    }
+
+    #[test]
+    fn test_bijection() {
+        let swapped = Keymap::new("abc", "bca").unwrap();
+        assert_eq!(swapped.code("abcd"), "bcad");
+        assert_eq!(swapped.keys("bcad"), "abcd");
+        // keys remapped to themselves are as good as left out
+        let keymap = Keymap::new("abc", "bac").unwrap();
+        assert!(matches!(keymap.code("cc"), Cow::Borrowed("cc")));
+        assert_eq!(keymap.to_code, Keymap::new("ab", "ba").unwrap().to_code);
+
+        let reason = |keys, codes| match Keymap::new(keys, codes) {
+            Err(LiushuError::InvalidInput(reason)) => reason,
+            keymap => panic!("{:?}", keymap),
+        };
+        assert_eq!(reason("ab", "cc"), "the keymap remaps both a and b to c");
+        assert_eq!(reason("aa", "bc"), "the keymap remaps a to both b and c");
+        assert_eq!(
+            reason("ab", "bc"),
+            "the keymap remaps b to c but doesn't remap c, which would type c too"
+        );
+        assert_eq!(reason("ab", "b"), "the keymap has 2 keys but 1 codes");
+    }
+}
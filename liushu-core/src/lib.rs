@@ -1,7 +1,54 @@
-mod config;
+//! The dictionaries, engines and models of liushu, for the CLI, the language server and
+//! other frontends.
+//!
+//! Library code doesn't panic on what it reads: a malformed config, dictionary or artifact,
+//! or a code that means nothing, is an [`error::LiushuError`]. `unwrap` and indexing are
+//! only for what the code itself guarantees, and the panics of dependencies on malformed
+//! files, such as those of redb on a truncated database, are caught where they are opened.
+//!
+//! Without the default `native` feature, only [`engine::MemoryEngine`] is left of the
+//! engines, loaded from bytes rather than files, which builds for `wasm32-unknown-unknown`.
+//! `native` is the sum of smaller features, `runtime` alone being what searching deployed
+//! artifacts with [`engine::EngineWithRedb`] takes, see the manifest.
+
+#[cfg(feature = "runtime")]
+pub mod artifact;
+#[cfg(feature = "native")]
+pub mod assets;
+#[cfg(feature = "native")]
+pub mod bench;
+pub mod charset;
+pub mod composition;
+#[cfg(feature = "dhall-config")]
+pub mod config;
+#[cfg(feature = "native")]
 pub mod deploy;
+pub mod diagnostics;
+#[cfg(feature = "dict-build")]
 pub mod dict;
+#[cfg(feature = "runtime")]
 pub mod dirs;
+#[cfg(feature = "native")]
+pub mod doctor;
 pub mod engine;
-mod error;
+pub mod error;
+#[cfg(feature = "hmm")]
 pub mod hmm;
+pub mod interop;
+pub mod keymap;
+pub mod normalize;
+#[cfg(feature = "native")]
+pub mod patch;
+pub mod progress;
+#[cfg(feature = "native")]
+pub mod server;
+#[cfg(test)]
+mod snapshot;
+#[cfg(feature = "runtime")]
+pub mod state;
+#[cfg(feature = "native")]
+pub mod status;
+#[cfg(feature = "native")]
+pub mod typing_log;
+#[cfg(feature = "native")]
+pub mod userdict;
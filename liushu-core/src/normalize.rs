@@ -0,0 +1,54 @@
+//! Unicode normalization of dictionaries, so that entries looking the same are the same
+//! whatever tool wrote them: texts to NFC, where `é` is one character rather than `e` and
+//! a combining accent, and codes to NFKC lowercase, where the full-width `ｎｉ` is `ni`.
+//!
+//! Builds normalize the dictionaries of a formula unless it sets `normalize_unicode` to
+//! `False`, and mark the artifacts, whose engines normalize what is searched the same way.
+
+use std::borrow::Cow;
+
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// `text` in NFC, borrowed when it is already.
+pub fn text(text: &str) -> Cow<'_, str> {
+    match is_nfc_quick(text.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(text),
+        _ => match text.nfc().collect::<String>() {
+            normalized if normalized == text => Cow::Borrowed(text),
+            normalized => Cow::Owned(normalized),
+        },
+    }
+}
+
+/// `code` in NFKC and lowercase, borrowed when it is already, as the codes typed mostly
+/// are.
+pub fn code(code: &str) -> Cow<'_, str> {
+    if code
+        .bytes()
+        .all(|b| b.is_ascii() && !b.is_ascii_uppercase())
+    {
+        return Cow::Borrowed(code);
+    }
+    let normalized: String = code.nfkc().flat_map(char::to_lowercase).collect();
+    match normalized == code {
+        true => Cow::Borrowed(code),
+        false => Cow::Owned(normalized),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert!(matches!(text("你好"), Cow::Borrowed("你好")));
+        assert_eq!(text("cafe\u{301}"), "caf\u{e9}");
+        assert!(matches!(text("caf\u{e9}"), Cow::Borrowed(_)));
+        assert!(matches!(code("ni hao"), Cow::Borrowed("ni hao")));
+        assert_eq!(code("ｎｉＨＡＯ"), "nihao");
+        assert_eq!(code("NI"), "ni");
+        // compatibility forms of other letters too
+        assert_eq!(code("ﬁ"), "fi");
+    }
+}
@@ -0,0 +1,335 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use redb::{ReadableTable, TableDefinition};
+
+use crate::artifact::{ArtifactDb, ArtifactOpenOptions, OpenMode, MAX_WEIGHT};
+use crate::engine::{
+    EngineCapabilities, InputMethodEngine, MemoryEngine, PrefixInfo, SearchResultItem, WarmUp,
+};
+use crate::error::{IoResultExt, LiushuError};
+
+/// Keyed by `(code, text)`, a `None` weight is a tombstone hiding the entry of the deployed
+/// dictionary.
+const PATCH: TableDefinition<(&str, &str), Option<u64>> = TableDefinition::new("patch");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchEntry {
+    pub text: String,
+    pub code: String,
+    pub weight: Option<u64>,
+}
+
+/// Entries added by the user on top of a formula, kept in the data dir so deploying doesn't
+/// overwrite them.
+pub struct PatchDict {
+    db: ArtifactDb,
+}
+
+impl PatchDict {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_path("create data dir", parent)?;
+        }
+        let db = ArtifactOpenOptions::new(OpenMode::ReadWrite)
+            .open_redb(path, "open patch dictionary")?;
+        let write_txn = db.begin_write()?;
+        write_txn.open_table(PATCH)?;
+        write_txn.commit()?;
+        Ok(Self { db })
+    }
+
+    pub fn with_formula(path: impl AsRef<Path>, formula_id: &str) -> Result<Self, LiushuError> {
+        Self::open(path.as_ref().join(format!("{}.patch.redb", formula_id)))
+    }
+
+    /// Add an entry or change its weight, clamped to [`MAX_WEIGHT`] like those of the
+    /// dictionaries, returns whether it was already there.
+    pub fn add(&self, text: &str, code: &str, weight: u64) -> Result<bool, LiushuError> {
+        self.set(text, code, Some(weight.min(MAX_WEIGHT)))
+    }
+
+    /// Hide an entry, whether it was added here or comes from the deployed dictionary.
+    pub fn remove(&self, text: &str, code: &str) -> Result<(), LiushuError> {
+        self.set(text, code, None).map(|_| ())
+    }
+
+    fn set(&self, text: &str, code: &str, weight: Option<u64>) -> Result<bool, LiushuError> {
+        let write_txn = self.db.begin_write()?;
+        let existed = {
+            let mut table = write_txn.open_table(PATCH)?;
+            let existed = table
+                .get((code, text))?
+                .is_some_and(|v| v.value().is_some());
+            table.insert((code, text), weight)?;
+            existed
+        };
+        write_txn.commit()?;
+        Ok(existed)
+    }
+
+    pub fn entries(&self) -> Result<Vec<PatchEntry>, LiushuError> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PATCH)?;
+        let mut entries = vec![];
+        for (key, value) in table.iter()? {
+            let (code, text) = key.value();
+            entries.push(PatchEntry {
+                text: text.to_string(),
+                code: code.to_string(),
+                weight: value.value(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Merge the entries matching `code` into `results`, keeping the order of the results.
+    pub fn apply(
+        &self,
+        code: &str,
+        mut results: Vec<SearchResultItem>,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut entries: Vec<_> = self
+            .entries()?
+            .into_iter()
+            .filter(|entry| entry.code.starts_with(code))
+            .collect();
+        results.retain(|item| {
+            !entries
+                .iter()
+                .any(|entry| entry.text == item.text && entry.code == item.code)
+        });
+
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.weight));
+        for entry in entries {
+            let Some(weight) = entry.weight else {
+                continue;
+            };
+            let pos = results
+                .iter()
+                .position(|item| item.weight < weight)
+                .unwrap_or(results.len());
+            results.insert(
+                pos,
+                SearchResultItem {
+                    text: entry.text,
+                    is_exact: entry.code == code,
+                    code: entry.code,
+                    weight,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                },
+            );
+        }
+        Ok(results)
+    }
+}
+
+/// An engine whose results are patched by a [`PatchDict`].
+pub struct PatchedEngine {
+    inner: Box<dyn InputMethodEngine>,
+    patch: Arc<PatchDict>,
+}
+
+impl PatchedEngine {
+    pub fn new(inner: Box<dyn InputMethodEngine>, patch: Arc<PatchDict>) -> Self {
+        Self { inner, patch }
+    }
+
+    /// Replaces the engine patched by the one `open` returns, closing it first so that
+    /// the same artifacts can be opened again. Only the patch is left when `open` fails.
+    pub fn reopen(
+        &mut self,
+        open: impl FnOnce() -> Result<Box<dyn InputMethodEngine>, LiushuError>,
+    ) -> Result<(), LiushuError> {
+        self.inner = Box::new(std::iter::empty().collect::<MemoryEngine>());
+        self.inner = open()?;
+        Ok(())
+    }
+
+    /// Changes the `codes` of `text` by those of the patch `entries`, sorted.
+    fn patch_codes(entries: &[PatchEntry], text: &str, codes: &mut Vec<String>) {
+        for entry in entries.iter().filter(|e| e.text == text) {
+            codes.retain(|code| *code != entry.code);
+            if entry.weight.is_some() {
+                codes.push(entry.code.clone());
+            }
+        }
+        codes.sort();
+    }
+}
+
+impl InputMethodEngine for PatchedEngine {
+    fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.patch.apply(code, self.inner.search(code)?)
+    }
+
+    fn search_in_context(
+        &self,
+        code: &str,
+        context: &str,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        self.patch
+            .apply(code, self.inner.search_in_context(code, context)?)
+    }
+
+    fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+        let mut codes = self.inner.reverse_lookup(text)?;
+        Self::patch_codes(&self.patch.entries()?, text, &mut codes);
+        Ok(codes)
+    }
+
+    /// The entries of the patch are read once for all of them.
+    fn reverse_lookup_batch(&self, texts: &[&str]) -> Result<Vec<Vec<String>>, LiushuError> {
+        let mut codes = self.inner.reverse_lookup_batch(texts)?;
+        let entries = self.patch.entries()?;
+        for (text, codes) in texts.iter().zip(&mut codes) {
+            Self::patch_codes(&entries, text, codes);
+        }
+        Ok(codes)
+    }
+
+    /// Those of the engine patched but removed by the patch, whose own phrases aren't
+    /// indexed by their texts.
+    fn associations(
+        &self,
+        committed: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResultItem>, LiushuError> {
+        let mut items = self.inner.associations(committed, limit)?;
+        let removed: Vec<_> = self
+            .patch
+            .entries()?
+            .into_iter()
+            .filter(|entry| entry.weight.is_none())
+            .collect();
+        items.retain(|item| {
+            !removed
+                .iter()
+                .any(|entry| entry.text == item.text && entry.code == item.code)
+        });
+        Ok(items)
+    }
+
+    /// Those of the engine patched, the patch has codes of its own to look up.
+    /// Of the engine patched, the codes the patch adds aside.
+    fn prefix_info(&self, code: &str) -> Result<Option<PrefixInfo>, LiushuError> {
+        self.inner.prefix_info(code)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            reverse_lookup: true,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        self.inner.warm_up(budget)
+    }
+
+    fn inconsistencies(&self) -> u64 {
+        self.inner.inconsistencies()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYED: [(&str, &str, u64); 2] = [("你好", "nihao", 10), ("你", "ni", 5)];
+
+    struct Deployed;
+    impl InputMethodEngine for Deployed {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            Ok(DEPLOYED
+                .into_iter()
+                .filter(|(_, c, _)| c.starts_with(code))
+                .map(|(text, code, weight)| SearchResultItem {
+                    text: text.to_string(),
+                    code: code.to_string(),
+                    weight,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                })
+                .collect())
+        }
+
+        fn reverse_lookup(&self, text: &str) -> Result<Vec<String>, LiushuError> {
+            Ok(DEPLOYED
+                .into_iter()
+                .filter(|(t, _, _)| *t == text)
+                .map(|(_, code, _)| code.to_string())
+                .collect())
+        }
+    }
+
+    fn texts(engine: &impl InputMethodEngine, code: &str) -> Vec<String> {
+        engine
+            .search(code)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_patched_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Arc::new(PatchDict::with_formula(&dir, "fixture").unwrap());
+        let engine = PatchedEngine::new(Box::new(Deployed), patch.clone());
+        assert_eq!(texts(&engine, "ni"), vec!["你好", "你"]);
+
+        assert!(!patch.add("尼", "ni", 7).unwrap());
+        assert_eq!(texts(&engine, "ni"), vec!["你好", "尼", "你"]);
+
+        assert!(patch.add("尼", "ni", 500).unwrap());
+        assert_eq!(texts(&engine, "ni"), vec!["尼", "你好", "你"]);
+        assert_eq!(patch.entries().unwrap().len(), 1);
+
+        patch.remove("尼", "ni").unwrap();
+        patch.remove("你好", "nihao").unwrap();
+        assert_eq!(texts(&engine, "ni"), vec!["你"]);
+        assert!(texts(&engine, "nih").is_empty());
+
+        patch.add("你好", "nihao", 1).unwrap();
+        assert_eq!(texts(&engine, "ni"), vec!["你", "你好"]);
+    }
+
+    #[test]
+    fn test_patched_reverse_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Arc::new(PatchDict::with_formula(&dir, "fixture").unwrap());
+        let engine = PatchedEngine::new(Box::new(Deployed), patch.clone());
+        assert_eq!(engine.reverse_lookup("你").unwrap(), ["ni"]);
+
+        patch.add("你", "n", 1).unwrap();
+        patch.remove("你", "ni").unwrap();
+        assert_eq!(engine.reverse_lookup("你").unwrap(), ["n"]);
+        assert!(engine.reverse_lookup("尼").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Arc::new(PatchDict::with_formula(&dir, "fixture").unwrap());
+        patch.add("尼", "ni", 7).unwrap();
+        let mut engine = PatchedEngine::new(Box::new(Deployed), patch);
+
+        let missing = || {
+            Err(LiushuError::ArtifactMissing(
+                dir.path().join("fixture.redb"),
+            ))
+        };
+        assert!(engine.reopen(missing).is_err());
+        assert_eq!(texts(&engine, "ni"), vec!["尼"]);
+        engine.reopen(|| Ok(Box::new(Deployed))).unwrap();
+        assert_eq!(texts(&engine, "ni"), vec!["你好", "尼", "你"]);
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(feature = "dict-build")]
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+#[cfg(feature = "dict-build")]
+use crate::error::LiushuError;
+
+/// Receives progress of long running jobs such as compiling dictionaries or training.
+pub trait ProgressSink: Send + Sync {
+    /// A new unit of work starts, `total_hint` is the expected number of rows if known.
+    fn on_start(&self, name: &str, total_hint: Option<u64>);
+
+    /// `n` rows of the current unit have been processed so far.
+    fn on_advance(&self, n: u64);
+
+    /// The current unit of work is done.
+    fn on_finish(&self, summary: &str);
+
+    /// Whether the job should stop, checked between dictionaries and every
+    /// [`CANCEL_CHECK_ROWS`] rows, which it does with
+    /// [`LiushuError::Cancelled`](crate::error::LiushuError::Cancelled).
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Rows compiled between two checks of [`ProgressSink::is_cancelled`].
+pub const CANCEL_CHECK_ROWS: u64 = 4096;
+
+/// Fails once `progress` is cancelled.
+#[cfg(feature = "dict-build")]
+pub(crate) fn check_cancelled(progress: &dyn ProgressSink) -> Result<(), LiushuError> {
+    match progress.is_cancelled() {
+        true => Err(LiushuError::Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Discards all progress, used when the caller does not ask for it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn on_start(&self, _name: &str, _total_hint: Option<u64>) {}
+
+    fn on_advance(&self, _n: u64) {}
+
+    fn on_finish(&self, _summary: &str) {}
+}
+
+/// Cheap upfront estimate of the rows in a dictionary file, by counting its lines.
+#[cfg(feature = "dict-build")]
+pub(crate) fn estimate_rows(path: impl AsRef<Path>) -> Option<u64> {
+    let file = File::open(path).ok()?;
+    let mut reader = BufReader::new(file);
+    let mut lines = 0;
+    loop {
+        let buf = reader.fill_buf().ok()?;
+        if buf.is_empty() {
+            break;
+        }
+        lines += buf.iter().filter(|b| **b == b'\n').count() as u64;
+        let len = buf.len();
+        reader.consume(len);
+    }
+    // the header line is not a row
+    Some(lines.saturating_sub(1))
+}
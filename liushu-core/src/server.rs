@@ -0,0 +1,1762 @@
+//! A newline-delimited JSON protocol for frontends keeping one engine alive across queries.
+//!
+//! Each line is a request `{"id": 1, "method": "search", "params": {"code": "nihao"}}`,
+//! answered by one line `{"id": 1, "result": ...}` or `{"id": 1, "error": {...}}` with the
+//! error serialized like everywhere else. The methods are
+//!
+//! | method           | params                                        | result                                     |
+//! |------------------|-----------------------------------------------|--------------------------------------------|
+//! | `initialize`     | `protocol_version`, `capabilities`, `profile` | an [`InitializeResult`]                    |
+//! | `search`         | `code`, `limit`, `rime_compat`                | candidates, at most `limit` when given     |
+//! | `process_key`    | `key`                                         | `{"handled", "commit", "rime_context"}`    |
+//! | `set_formula`    | `formula`                                     | `{"formula": ...}`                         |
+//! | `commit`         | `text`, `code`                                | `null`, the user dictionary records it     |
+//! | `reverse_lookup` | `text`                                        | codes of the text                          |
+//! | `associations`   | `text`, `limit`                               | phrases starting with the text             |
+//! | `prefix_info`    | `code`                                        | a [`PrefixInfo`], `null` without a trie    |
+//! | `info`           |                                               | an [`EngineInfo`]                          |
+//! | `reload`         |                                               | `{"formula": ...}`, see [`Server::reload`] |
+//! | `doctor`         |                                               | the checks of [`doctor::run`]              |
+//! | `shutdown`       |                                               | `null`, the server stops afterwards        |
+//!
+//! `initialize` comes first on each connection, with the [`PROTOCOL_VERSION`] the client
+//! speaks and the [`PROTOCOL_CAPABILITIES`] it wants, all of them when left out. Until then
+//! every request but `shutdown` gets an `E_PROTOCOL` error, as does a version the server
+//! doesn't speak, and the connection can still be initialized. Codes are searched up to
+//! [`MAX_CODE_LEN`] bytes, and a search answers at most [`MAX_CANDIDATES`].
+//!
+//! With `"rime_compat": true`, a connection that has the `rime_compat` capability starts a
+//! composition of the code instead and answers its [`RimeContext`]. `process_key` then takes the keys of
+//! [`rime::process_key`] and answers whether it handled the key, the text it committed if
+//! any, which the user dictionary records, and the `RimeContext` after it.
+//!
+//! A [`Server`] is shared by the [`Protocol`] of each connection. The formula, engine and
+//! user dictionary are those of the server, while the text committed last, which searches
+//! see as their context, and the composition belong to the connection. The formula
+//! `set_formula` switches to is remembered, the next server started without one opens it.
+//!
+//! With a `profile`, `initialize` connects to the [named profile](Profiles) of that name
+//! kept in the config dir of the server instead, so that one server hosts the profiles of
+//! a user. Each profile is served by a [`Server`] of its own, opened by the first
+//! connection to it with the config, artifacts, user dictionary and state of the profile
+//! alone, and kept for those to come. `shutdown` stops the server hosting them all.
+//!
+//! A deploy can run while the server is up. It only ever replaces artifacts by renaming
+//! complete files over them, and the engine keeps the files it opened, so searches go on
+//! against the previous artifacts until [`Server::reload`] swaps in the new ones.
+//!
+//! The codes of `search` and the keys of `process_key` are remapped with the
+//! [keymap](Config::keymap) of the formula first, while `reverse_lookup` answers the codes
+//! of the dictionary. Codes outside the alphabet of the formula also get the candidates of
+//! the [transformers](Config::transformers) of the config, such as `21` for `=3*7`.
+//! A search without a candidate gets those of the [fallbacks](Config::fallbacks) of the
+//! formula instead, opened by the first search that needs them and tagged with the formula
+//! they come from.
+//!
+//! Candidates the [filters](Filters) of the config dir block are left out of every search
+//! before its limit, whatever the formula, and [`Server::reload_filters`] reads them again.
+//!
+//! The latest searches are answered from a [`SearchCache`], which forgets them on a reload
+//! and on every commit. `info` tells how many searches it answered.
+//!
+//! The candidates of `search` tell how many times the user committed them, those never
+//! committed without a `user_count`. The user dictionary is opened by the first commit, or
+//! the first search when there is one, and with [`ServerOptions::lazy`] the
+//! artifacts of a formula by its first search, so that `initialize` is answered before
+//! anything is loaded. `info` tells how long each of them took, and how long the engine
+//! took to [warm up](Server::warm_up) when the server was told to. With
+//! [`ServerOptions::recency_tiebreak`] the candidates as heavy as one another are offered
+//! by when they were last committed instead.
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(unix)]
+pub mod socket;
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{
+    composition::Composition,
+    config::Config,
+    dirs::{profiles::Profiles, MyProjectDirs},
+    doctor,
+    engine::{
+        break_ties, ArtifactStore, CacheStats, EngineCapabilities, EngineWithRedb, FallbackEngine,
+        FilteredEngine, Filters, InputMethodEngine, LazyEngine, PrefixInfo, RankedEngine, Ranker,
+        SearchCache, SearchResultItem, TransformedEngine, WarmUp, DEFAULT_CAPACITY,
+    },
+    error::LiushuError,
+    hmm::Hmm,
+    interop::rime::{self, KeyOutcome, RimeContext},
+    patch::{PatchDict, PatchedEngine},
+    state::SessionState,
+    typing_log::TypingLog,
+    userdict::{UserDict, USER_DICT_FILE},
+};
+
+/// The version of the protocol, which `initialize` must ask for.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a connection can ask for in `initialize` beyond the plain methods: `rime_compat`
+/// for the searches with `rime_compat` and `process_key`.
+pub const PROTOCOL_CAPABILITIES: &[&str] = &["rime_compat"];
+
+/// Bytes of the longest code searched.
+pub const MAX_CODE_LEN: usize = 64;
+
+/// Candidates of a search at most, whatever its `limit`.
+pub const MAX_CANDIDATES: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    /// Echoed in the response, `null` when missing.
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub id: Value,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Result(Value),
+    Error(LiushuError),
+}
+
+/// The answer to `info`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EngineInfo {
+    /// The text committed last on the connection.
+    pub context: String,
+    pub formula: String,
+    /// Those of the config that are enabled.
+    pub formulas: Vec<String>,
+    /// Those of the config that are disabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disabled: Vec<String>,
+    /// Of liushu.
+    pub version: String,
+    /// Of the searches of the server.
+    pub cache: CacheStats,
+    /// Seconds each component of the server took to open, those opened so far.
+    #[serde(default)]
+    pub timings: BTreeMap<String, f64>,
+    /// Of the engine of the formula, see [`InputMethodEngine::inconsistencies`].
+    #[serde(default)]
+    pub inconsistencies: u64,
+}
+
+/// The answer to `initialize`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct InitializeResult {
+    pub protocol_version: u32,
+    /// Those asked for that the server has, the connection may only use these.
+    pub capabilities: Vec<String>,
+    /// Those of the engine of the current formula.
+    pub engine: EngineCapabilities,
+    pub formulas: Vec<String>,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Limits {
+    pub max_code_len: usize,
+    pub max_candidates: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_code_len: MAX_CODE_LEN,
+            max_candidates: MAX_CANDIDATES,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct InitializeParams {
+    protocol_version: u32,
+    capabilities: Option<Vec<String>>,
+    profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    code: String,
+    limit: Option<usize>,
+    #[serde(default)]
+    rime_compat: bool,
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct FormulaParams {
+    formula: String,
+}
+
+#[derive(Deserialize)]
+struct CommitParams {
+    text: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct CodeParams {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct TextParams {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct AssociationParams {
+    text: String,
+    limit: Option<usize>,
+}
+
+/// What the connections change for each other, behind one lock: searches share it while
+/// commits and formula switches take turns.
+struct State {
+    formula: String,
+    engine: SearchCache<FilteredEngine<FallbackEngine<RankedEngine<PatchedEngine>>>>,
+}
+
+impl State {
+    /// Records a commit in the user dictionary. The cached searches are forgotten, so that
+    /// none of them is from before it.
+    fn record(&self, user_dict: &UserDict, text: &str, code: &str) -> Result<(), LiushuError> {
+        user_dict.record(text, code)?;
+        self.engine.invalidate();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ServerOptions {
+    /// Load the artifacts of a formula on its first search rather than when switching to
+    /// it, so that the server answers `initialize` sooner.
+    pub lazy: bool,
+    /// Comment the candidates without one from the annotation table of the formula, see
+    /// [`EngineWithRedb::annotate`].
+    pub annotate: bool,
+    /// Offer the candidates of `search` as heavy as one another by when the user last
+    /// committed them, the latest first, see [`break_ties`].
+    pub recency_tiebreak: bool,
+}
+
+/// How long the components of a server took to open, the last time each did.
+#[derive(Default)]
+struct Timings(Mutex<BTreeMap<String, f64>>);
+
+impl Timings {
+    fn time<T>(
+        &self,
+        component: &str,
+        open: impl FnOnce() -> Result<T, LiushuError>,
+    ) -> Result<T, LiushuError> {
+        let start = Instant::now();
+        let opened = open()?;
+        let elapsed = start.elapsed();
+        debug!(component, ?elapsed, "opened");
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(component.to_string(), elapsed.as_secs_f64());
+        Ok(opened)
+    }
+
+    fn get(&self) -> BTreeMap<String, f64> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+/// The engine of the current formula of a profile, shared by the connections to it.
+pub struct Server {
+    config: Config,
+    dirs: MyProjectDirs,
+    /// Where the engines of the server get their artifacts, loaded once however many
+    /// engines search them.
+    store: Arc<ArtifactStore>,
+    options: ServerOptions,
+    /// Shared by the engines of every formula.
+    filters: Arc<Filters>,
+    /// The model of the `context` ranking stage, shared by the engines of every formula.
+    context: Option<Arc<dyn Ranker>>,
+    state: RwLock<State>,
+    /// Opened by the first commit, or the first search once there is one.
+    user_dict: OnceCell<UserDict>,
+    timings: Arc<Timings>,
+    typing_log: Option<TypingLog>,
+    /// The servers of the named profiles connected to so far, by name.
+    profiles: Mutex<HashMap<String, Arc<Server>>>,
+    shut_down: AtomicBool,
+}
+
+impl Server {
+    /// Opens `formula`, or without it the [initial formula](Config::initial_formula) of the
+    /// config, preferring the one the last `set_formula`, of any server, switched to.
+    pub fn new(
+        config: Config,
+        dirs: &MyProjectDirs,
+        formula: Option<&str>,
+    ) -> Result<Arc<Self>, LiushuError> {
+        Self::with_options(config, dirs, formula, ServerOptions::default())
+    }
+
+    /// Like [`Server::new`]. The user dictionary is opened by the first commit or search
+    /// whatever the options, and the formulas but the current one are opened by switching to them.
+    pub fn with_options(
+        config: Config,
+        dirs: &MyProjectDirs,
+        formula: Option<&str>,
+        options: ServerOptions,
+    ) -> Result<Arc<Self>, LiushuError> {
+        let formula = match formula {
+            Some(formula) => config.enabled_formula(formula)?.id.clone(),
+            None => {
+                let state = SessionState::load(&dirs.data_dir);
+                config
+                    .initial_formula(state.active_formula.as_deref())?
+                    .id
+                    .clone()
+            }
+        };
+        let store = Arc::new(ArtifactStore::new(&dirs.target_dir));
+        let timings = Arc::<Timings>::default();
+        let filters = Arc::new(Filters::load(&dirs.config_dir)?);
+        let context = Hmm::context_ranker(&dirs.target_dir);
+        let engine = FilteredEngine::new(
+            open_engine(&store, &timings, dirs, &config, &formula, options, &context)?,
+            filters.clone(),
+        );
+        Ok(Arc::new(Self {
+            typing_log: TypingLog::open(&dirs.data_dir, &config.typing_log),
+            config,
+            dirs: dirs.clone(),
+            store,
+            options,
+            filters,
+            context,
+            state: RwLock::new(State {
+                engine: SearchCache::new(engine, &formula, DEFAULT_CAPACITY),
+                formula,
+            }),
+            user_dict: OnceCell::new(),
+            timings,
+            profiles: Mutex::default(),
+            shut_down: AtomicBool::new(false),
+        }))
+    }
+
+    /// The user dictionary, opened by the first call.
+    pub fn user_dict(&self) -> Result<&UserDict, LiushuError> {
+        self.user_dict.get_or_try_init(|| {
+            self.timings.time("user_dict", || {
+                UserDict::open(self.dirs.data_dir.join(USER_DICT_FILE))
+            })
+        })
+    }
+
+    /// Attaches the commits of the user dictionary to `items`, which a search of a profile
+    /// without one doesn't create.
+    fn annotate(&self, items: &mut [SearchResultItem]) -> Result<(), LiushuError> {
+        if self.user_dict.get().is_none() && !self.dirs.data_dir.join(USER_DICT_FILE).exists() {
+            return Ok(());
+        }
+        self.user_dict()?.annotate(items)
+    }
+
+    /// Puts the candidates last committed first among those as heavy, with
+    /// [`ServerOptions::recency_tiebreak`].
+    fn break_ties(&self, items: &mut Vec<SearchResultItem>) -> Result<(), LiushuError> {
+        if !self.options.recency_tiebreak
+            || self.user_dict.get().is_none() && !self.dirs.data_dir.join(USER_DICT_FILE).exists()
+        {
+            return Ok(());
+        }
+        let last_used = self.user_dict()?.last_used(items)?;
+        break_ties(items, last_used.into_iter().map(Reverse).collect());
+        Ok(())
+    }
+
+    /// The server of the named profile `name`, opened on the initial formula of its config
+    /// by the first call and with the options of this one.
+    pub fn profile(&self, name: &str) -> Result<Arc<Server>, LiushuError> {
+        let mut profiles = self
+            .profiles
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(server) = profiles.get(name) {
+            return Ok(server.clone());
+        }
+        let dirs = Profiles::of(&self.dirs).dirs(name)?;
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall"))?;
+        let server = Self::with_options(config, &dirs, None, self.options)?;
+        debug!(profile = name, "opened profile");
+        profiles.insert(name.to_string(), server.clone());
+        Ok(server)
+    }
+
+    /// This server and those of the named profiles it opened.
+    fn hosted(self: &Arc<Self>) -> Vec<Arc<Server>> {
+        let profiles = self
+            .profiles
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::iter::once(self.clone())
+            .chain(profiles.values().cloned())
+            .collect()
+    }
+
+    /// Warms up the engine of the current formula for about `budget`, see
+    /// [`InputMethodEngine::warm_up`], which is one of the [timings](Self::timings).
+    pub fn warm_up(&self, budget: Duration) -> Result<WarmUp, LiushuError> {
+        let warm_up = self
+            .timings
+            .time("warm_up", || self.read().engine.warm_up(budget))?;
+        debug!(?warm_up, "warmed up");
+        Ok(warm_up)
+    }
+
+    /// Seconds each component took to open, see [`EngineInfo::timings`].
+    pub fn timings(&self) -> BTreeMap<String, f64> {
+        self.timings.get()
+    }
+
+    fn open_engine(
+        &self,
+        formula: &str,
+    ) -> Result<FilteredEngine<FallbackEngine<RankedEngine<PatchedEngine>>>, LiushuError> {
+        let engine = open_engine(
+            &self.store,
+            &self.timings,
+            &self.dirs,
+            &self.config,
+            formula,
+            self.options,
+            &self.context,
+        )?;
+        Ok(FilteredEngine::new(engine, self.filters.clone()))
+    }
+
+    /// Reads the filters of the config dir again, answering how many texts and pairs they
+    /// block. The cached searches are forgotten, those from before blocked other ones.
+    pub fn reload_filters(&self) -> Result<usize, LiushuError> {
+        let blocked = self.filters.reload()?;
+        self.read().engine.invalidate();
+        Ok(blocked)
+    }
+
+    /// A new connection, with a context of its own.
+    pub fn connect(self: &Arc<Self>) -> Protocol {
+        Protocol {
+            host: self.clone(),
+            server: self.clone(),
+            capabilities: None,
+            context: String::new(),
+            composition: Composition::new(rime::PAGE_SIZE),
+        }
+    }
+
+    /// Those that can be switched to.
+    fn formulas(&self) -> Vec<String> {
+        self.config
+            .formulas
+            .iter()
+            .filter(|formula| formula.is_enabled())
+            .map(|formula| formula.id.clone())
+            .collect()
+    }
+
+    /// Reopens the artifacts of the current formula after a deploy, answering the formula.
+    ///
+    /// The new artifacts are loaded into the store before the lock is taken, so searches
+    /// wait only for the swap, and those already running finish with the previous engine.
+    /// Artifacts the deploy left unchanged are still open in the current engine, which is
+    /// kept.
+    pub fn reload(&self) -> Result<String, LiushuError> {
+        let formula = self.read().formula.clone();
+        let Some(artifacts) = self.store.reload(&formula)? else {
+            debug!(formula, "artifacts are unchanged, not reloading");
+            return Ok(formula);
+        };
+        let engine = TransformedEngine::new(
+            EngineWithRedb::from_artifacts(artifacts).annotate(self.options.annotate),
+            Arc::new(self.config.transformers(&formula)),
+        );
+        let mut state = self.write();
+        // a connection switched formulas meanwhile, to the artifacts now in the store
+        if state.formula == formula {
+            state
+                .engine
+                .inner_mut()
+                .inner_mut()
+                .inner_mut()
+                .inner_mut()
+                .reopen(|| Ok(Box::new(engine)))?;
+        }
+        debug!(formula, "reloaded");
+        Ok(formula)
+    }
+
+    /// Whether a connection has asked the server to shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    // a request that panicked has either replaced the engine or not, both are usable
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Answers the requests of one connection in order.
+pub struct Protocol {
+    /// The server connected to, which stops them all.
+    host: Arc<Server>,
+    /// That of the profile `initialize` asked for, the host itself without one.
+    server: Arc<Server>,
+    /// Those agreed on by `initialize`, `None` before it.
+    capabilities: Option<Vec<String>>,
+    /// The text committed last on this connection.
+    context: String,
+    composition: Composition,
+}
+
+impl Protocol {
+    /// A server with a single connection.
+    pub fn new(
+        config: Config,
+        dirs: &MyProjectDirs,
+        formula: Option<&str>,
+    ) -> Result<Self, LiushuError> {
+        Ok(Server::new(config, dirs, formula)?.connect())
+    }
+
+    /// Whether a `shutdown` request has been answered on any connection.
+    pub fn is_shut_down(&self) -> bool {
+        self.host.is_shut_down()
+    }
+
+    pub fn handle(&mut self, request: Request) -> Response {
+        let outcome = match self.dispatch(&request.method, request.params) {
+            Ok(result) => Outcome::Result(result),
+            Err(error) => Outcome::Error(error),
+        };
+        Response {
+            id: request.id,
+            outcome,
+        }
+    }
+
+    /// Answers a line of the protocol, a line that isn't a request gets an error with a
+    /// `null` id.
+    pub fn handle_line(&mut self, line: &str) -> Response {
+        match serde_json::from_str(line) {
+            Ok(request) => self.handle(request),
+            Err(e) => Response {
+                id: Value::Null,
+                outcome: Outcome::Error(LiushuError::InvalidInput(format!(
+                    "malformed request: {}",
+                    e
+                ))),
+            },
+        }
+    }
+
+    /// Answers the lines of `input` on `output` until the end of input or a `shutdown`.
+    /// Blank lines are skipped.
+    pub fn serve(
+        &mut self,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> Result<(), LiushuError> {
+        for line in input.lines() {
+            let line = line.map_err(|e| LiushuError::io("read request", e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = self.handle_line(&line);
+            let response = serde_json::to_string(&response)
+                .map_err(|e| LiushuError::io("write response", e))?;
+            writeln!(output, "{}", response)
+                .and_then(|_| output.flush())
+                .map_err(|e| LiushuError::io("write response", e))?;
+            if self.is_shut_down() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn initialize(&mut self, params: Value) -> Result<Value, LiushuError> {
+        let params: InitializeParams = parse_params("initialize", params)?;
+        if params.protocol_version != PROTOCOL_VERSION {
+            return Err(LiushuError::Protocol(format!(
+                "unsupported protocol version {}, the server speaks {}",
+                params.protocol_version, PROTOCOL_VERSION
+            )));
+        }
+        let capabilities: Vec<String> = match params.capabilities {
+            Some(wanted) => PROTOCOL_CAPABILITIES
+                .iter()
+                .filter(|capability| wanted.iter().any(|wanted| wanted == *capability))
+                .map(|capability| capability.to_string())
+                .collect(),
+            None => PROTOCOL_CAPABILITIES
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        };
+        if let Some(profile) = &params.profile {
+            self.server = self.host.profile(profile)?;
+        }
+        self.capabilities = Some(capabilities.clone());
+        Ok(json!(InitializeResult {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities,
+            engine: self.server.read().engine.capabilities(),
+            formulas: self.server.formulas(),
+            limits: Limits::default(),
+        }))
+    }
+
+    fn require(&self, capability: &str) -> Result<(), LiushuError> {
+        let agreed = self.capabilities.as_deref().unwrap_or_default();
+        if agreed.iter().any(|agreed| agreed == capability) {
+            Ok(())
+        } else {
+            Err(LiushuError::Protocol(format!(
+                "the connection wasn't initialized with {}",
+                capability
+            )))
+        }
+    }
+
+    fn dispatch(&mut self, method: &str, params: Value) -> Result<Value, LiushuError> {
+        match (method, &self.capabilities) {
+            ("initialize", None) => return self.initialize(params),
+            ("initialize", Some(_)) => {
+                return Err(LiushuError::Protocol(
+                    "the connection is already initialized".to_string(),
+                ))
+            }
+            ("shutdown", _) | (_, Some(_)) => {}
+            (_, None) => {
+                return Err(LiushuError::Protocol(format!(
+                    "{} before initialize",
+                    method
+                )))
+            }
+        }
+        let server = &*self.server;
+        match method {
+            "search" => {
+                let params: SearchParams = parse_params(method, params)?;
+                if params.code.len() > MAX_CODE_LEN {
+                    return Err(LiushuError::InvalidInput(format!(
+                        "the code is longer than {} bytes",
+                        MAX_CODE_LEN
+                    )));
+                }
+                let state = server.read();
+                let keymap = server.config.keymap(&state.formula);
+                if params.rime_compat {
+                    self.require("rime_compat")?;
+                    self.composition.set_keymap(keymap.cloned());
+                    self.composition
+                        .set_input(&state.engine, &params.code, &self.context)?;
+                    return Ok(json!(RimeContext::from(&self.composition)));
+                }
+                let code = keymap.map_or(Cow::Borrowed(params.code.as_str()), |keymap| {
+                    keymap.code(&params.code)
+                });
+                let mut results = state.engine.search_in_context(&code, &self.context)?;
+                server.break_ties(&mut results)?;
+                results.truncate(
+                    params
+                        .limit
+                        .map_or(MAX_CANDIDATES, |limit| limit.min(MAX_CANDIDATES)),
+                );
+                server.annotate(&mut results)?;
+                Ok(json!(results))
+            }
+            "process_key" => {
+                self.require("rime_compat")?;
+                let params: KeyParams = parse_params(method, params)?;
+                let state = server.read();
+                let keymap = server.config.keymap(&state.formula);
+                self.composition.set_keymap(keymap.cloned());
+                let outcome = rime::process_key(&mut self.composition, &state.engine, &params.key)?;
+                drop(state);
+                let handled = outcome != KeyOutcome::Ignored;
+                let commit = match outcome {
+                    KeyOutcome::Committed(commit) => {
+                        let state = server.write();
+                        state.record(server.user_dict()?, &commit.text, &commit.code)?;
+                        if let Some(log) = &server.typing_log {
+                            log.record(&state.formula, &commit);
+                        }
+                        drop(state);
+                        self.context = commit.text.clone();
+                        Some(commit.text)
+                    }
+                    _ => None,
+                };
+                Ok(json!({
+                    "handled": handled,
+                    "commit": commit,
+                    "rime_context": RimeContext::from(&self.composition),
+                }))
+            }
+            "set_formula" => {
+                let params: FormulaParams = parse_params(method, params)?;
+                let formula = server.config.enabled_formula(&params.formula)?.id.clone();
+                let mut state = server.write();
+                let engine = server.open_engine(&formula)?;
+                state.engine.set_inner(&formula, engine);
+                SessionState::remember_formula(&server.dirs.data_dir, &formula);
+                state.formula = formula;
+                self.composition.clear();
+                Ok(json!({ "formula": state.formula }))
+            }
+            "commit" => {
+                let params: CommitParams = parse_params(method, params)?;
+                let user_dict = server.user_dict()?;
+                server
+                    .write()
+                    .record(user_dict, &params.text, &params.code)?;
+                self.context = params.text;
+                Ok(Value::Null)
+            }
+            "reverse_lookup" => {
+                let params: TextParams = parse_params(method, params)?;
+                Ok(json!(server.read().engine.reverse_lookup(&params.text)?))
+            }
+            "associations" => {
+                let params: AssociationParams = parse_params(method, params)?;
+                let limit = params
+                    .limit
+                    .map_or(MAX_CANDIDATES, |limit| limit.min(MAX_CANDIDATES));
+                let mut results = server.read().engine.associations(&params.text, limit)?;
+                server.annotate(&mut results)?;
+                Ok(json!(results))
+            }
+            "prefix_info" => {
+                let params: CodeParams = parse_params(method, params)?;
+                let state = server.read();
+                let keymap = server.config.keymap(&state.formula);
+                let code = keymap.map_or(Cow::Borrowed(params.code.as_str()), |keymap| {
+                    keymap.code(&params.code)
+                });
+                let info: Option<PrefixInfo> = state.engine.prefix_info(&code)?;
+                Ok(json!(info))
+            }
+            "info" => Ok(json!(EngineInfo {
+                context: self.context.clone(),
+                formula: server.read().formula.clone(),
+                formulas: server.formulas(),
+                disabled: server.config.disabled_formulas(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                cache: server.read().engine.stats(),
+                timings: server.timings(),
+                inconsistencies: server.read().engine.inconsistencies(),
+            })),
+            "reload" => {
+                let formula = server.reload()?;
+                self.composition.clear();
+                Ok(json!({ "formula": formula }))
+            }
+            "doctor" => Ok(json!(doctor::run(&server.dirs))),
+            "shutdown" => {
+                self.host.shut_down.store(true, Ordering::SeqCst);
+                Ok(Value::Null)
+            }
+            _ => Err(LiushuError::InvalidInput(format!(
+                "unknown method {}",
+                method
+            ))),
+        }
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(method: &str, params: Value) -> Result<T, LiushuError> {
+    serde_json::from_value(params)
+        .map_err(|e| LiushuError::InvalidInput(format!("invalid params of {}: {}", method, e)))
+}
+
+fn open_engine(
+    store: &Arc<ArtifactStore>,
+    timings: &Arc<Timings>,
+    dirs: &MyProjectDirs,
+    config: &Config,
+    formula: &str,
+    options: ServerOptions,
+    context: &Option<Arc<dyn Ranker>>,
+) -> Result<FallbackEngine<RankedEngine<PatchedEngine>>, LiushuError> {
+    let patch = timings.time("patch", || PatchDict::with_formula(&dirs.data_dir, formula))?;
+    let transformers = Arc::new(config.transformers(formula));
+    let open = {
+        let (store, timings, formula) = (store.clone(), timings.clone(), formula.to_string());
+        move || {
+            let artifacts = timings.time("artifacts", || store.get(&formula))?;
+            let engine = EngineWithRedb::from_artifacts(artifacts).annotate(options.annotate);
+            Ok(TransformedEngine::new(engine, transformers.clone()))
+        }
+    };
+    let engine: Box<dyn InputMethodEngine> = if options.lazy {
+        Box::new(LazyEngine::new(EngineWithRedb::CAPABILITIES, open))
+    } else {
+        Box::new(open()?)
+    };
+    // opened by the first search they answer, whatever the options
+    let fallbacks = config
+        .fallbacks(formula)
+        .into_iter()
+        .map(|fallback| {
+            let (store, id) = (store.clone(), fallback.to_string());
+            let engine = LazyEngine::new(EngineWithRedb::CAPABILITIES, move || {
+                Ok(EngineWithRedb::from_artifacts(store.get(&id)?).annotate(options.annotate))
+            });
+            (
+                fallback.to_string(),
+                Box::new(engine) as Box<dyn InputMethodEngine>,
+            )
+        })
+        .collect();
+    let ranking = Arc::new(config.ranking(formula, context.clone()));
+    Ok(FallbackEngine::new(
+        RankedEngine::new(PatchedEngine::new(engine, Arc::new(patch)), ranking),
+        fallbacks,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::Overlay;
+    use crate::deploy::deploy;
+    use crate::keymap::Keymap;
+
+    pub(super) fn profile(root: &std::path::Path) -> (Config, MyProjectDirs) {
+        let dirs = MyProjectDirs::from_root(root);
+        dirs.ensure().unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] },
+                { id = "other", name = None Text, dictionaries = [ "words.tsv" ] }
+            ] }"#,
+        )
+        .unwrap();
+        for (id, words) in [
+            ("fixture", "你好\tnihao\t2\n你\tni\t1\n"),
+            ("other", "尼\tni\t1\n"),
+        ] {
+            fs::create_dir_all(dirs.config_dir.join(id)).unwrap();
+            fs::write(
+                dirs.config_dir.join(id).join("words.tsv"),
+                format!("text\tcode\tweight\n{}", words),
+            )
+            .unwrap();
+        }
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        deploy(&config, &dirs).unwrap();
+        (config, dirs)
+    }
+
+    /// A connection past `initialize`.
+    fn connect(config: Config, dirs: &MyProjectDirs, formula: Option<&str>) -> Protocol {
+        let mut protocol = Protocol::new(config, dirs, formula).unwrap();
+        initialize(&mut protocol);
+        protocol
+    }
+
+    fn initialize(protocol: &mut Protocol) {
+        let initialize = r#"{"method":"initialize","params":{"protocol_version":1}}"#;
+        assert!(matches!(
+            protocol.handle_line(initialize).outcome,
+            Outcome::Result(_)
+        ));
+    }
+
+    fn exchange(protocol: &mut Protocol, input: &str) -> String {
+        let mut output = Vec::new();
+        protocol.serve(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_keymap() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut config, dirs) = profile(root.path());
+        config.keymap = Some(Keymap::preset("dvorak").unwrap());
+        let mut protocol = connect(config, &dirs, None);
+        // the keys of `nihao` on QWERTY
+        let output = exchange(
+            &mut protocol,
+            r#"{"id":1,"method":"search","params":{"code":"bcdar"}}"#,
+        );
+        assert_eq!(
+            output.trim_end(),
+            r#"{"id":1,"result":[{"code":"nihao","comment":null,"is_exact":true,"text":"你好","weight":2}]}"#
+        );
+    }
+
+    #[test]
+    fn test_transformers() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut config, dirs) = profile(root.path());
+        config.transformers.date = false;
+        let mut protocol = connect(config, &dirs, None);
+        let output = exchange(
+            &mut protocol,
+            concat!(
+                r#"{"id":1,"method":"search","params":{"code":"=3*7","limit":1}}"#,
+                "\n",
+                r#"{"id":2,"method":"search","params":{"code":"5/1"}}"#,
+                "\n",
+            ),
+        );
+        assert_eq!(
+            output.trim_end(),
+            concat!(
+                r#"{"id":1,"result":[{"code":"=3*7","comment":null,"text":"21","weight":0}]}"#,
+                "\n",
+                r#"{"id":2,"result":[]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        // weighed over every entry of the formulas
+        for formula in ["fixture", "other"] {
+            let patch = PatchDict::with_formula(&dirs.data_dir, formula).unwrap();
+            patch.add("妮", "ni", 500).unwrap();
+        }
+        let server = Server::new(config, &dirs, Some("fixture")).unwrap();
+        let mut protocol = server.connect();
+        initialize(&mut protocol);
+        let mut search = |code: &str, limit: usize| {
+            let request = json!({
+                "method": "search",
+                "params": { "code": code, "limit": limit },
+            });
+            let response = json!(protocol.handle(serde_json::from_value(request).unwrap()));
+            let texts: Vec<String> = response["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["text"].as_str().unwrap().to_string())
+                .collect();
+            texts
+        };
+        assert_eq!(search("ni", 1), ["妮"]);
+        assert_eq!(search("=3*7", 1), ["21"]);
+
+        fs::write(
+            dirs.config_dir.join(crate::engine::FILTERS_FILE),
+            r#"{ texts = [ "21" ], pairs = [ { code = "ni", text = "妮" } ] }"#,
+        )
+        .unwrap();
+        assert_eq!(server.reload_filters().unwrap(), 2);
+        // the next candidate takes the place of the blocked one, cached searches or not
+        assert_eq!(search("ni", 1), ["你"]);
+        assert_eq!(search("ni", 5), ["你", "你好"]);
+        assert_eq!(search("=3*7", 1), ["3*7=21"]);
+        // for the formulas switched to as well
+        let output = exchange(
+            &mut protocol,
+            r#"{"id":1,"method":"set_formula","params":{"formula":"other"}}"#,
+        );
+        assert!(output.contains(r#""formula":"other""#));
+        let output = exchange(
+            &mut protocol,
+            r#"{"id":2,"method":"search","params":{"code":"ni"}}"#,
+        );
+        assert!(output.contains("尼") && !output.contains("妮"));
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let root = tempfile::tempdir().unwrap();
+        let (_, dirs) = profile(root.path());
+        let path = dirs.config_dir.join("main.dhall");
+        fs::write(
+            &path,
+            r#"{ formulas = [
+                { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ], fallbacks = [] : List Text },
+                { id = "other", name = None Text, dictionaries = [ "words.tsv" ], fallbacks = [ "fixture" ] }
+            ] }"#,
+        )
+        .unwrap();
+        let config = Config::load_from_path(&path).unwrap();
+        let mut protocol = connect(config, &dirs, Some("other"));
+        let output = exchange(
+            &mut protocol,
+            concat!(
+                r#"{"id":1,"method":"search","params":{"code":"ni"}}"#,
+                "\n",
+                r#"{"id":2,"method":"search","params":{"code":"nihao"}}"#,
+                "\n",
+                r#"{"id":3,"method":"search","params":{"code":"xx"}}"#,
+            ),
+        );
+        // only when the formula has no candidate
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                r#"{"id":1,"result":[{"code":"ni","comment":null,"is_exact":true,"text":"尼","weight":1}]}"#,
+                r#"{"id":2,"result":[{"code":"nihao","comment":null,"formula":"fixture","is_exact":true,"text":"你好","weight":2}]}"#,
+                r#"{"id":3,"result":[]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, None);
+
+        let input = concat!(
+            r#"{"id":1,"method":"search","params":{"code":"ni","limit":1}}"#,
+            "\n\n",
+            r#"{"id":2,"method":"reverse_lookup","params":{"text":"你好"}}"#,
+            "\n",
+            r#"{"id":3,"method":"commit","params":{"text":"你","code":"ni"}}"#,
+            "\n",
+            r#"{"id":4,"method":"set_formula","params":{"formula":"other"}}"#,
+            "\n",
+            r#"{"id":5,"method":"search","params":{"code":"ni"}}"#,
+            "\n",
+            r#"{"id":6,"method":"info"}"#,
+            "\n",
+        );
+        let expected = [
+            r#"{"id":1,"result":[{"code":"ni","comment":null,"is_exact":true,"text":"你","weight":1}]}"#,
+            r#"{"id":2,"result":["nihao"]}"#,
+            r#"{"id":3,"result":null}"#,
+            r#"{"id":4,"result":{"formula":"other"}}"#,
+            r#"{"id":5,"result":[{"code":"ni","comment":null,"is_exact":true,"text":"尼","weight":1}]}"#,
+            concat!(
+                r#"{"id":6,"result":{"cache":{"capacity":256,"entries":1,"hits":0,"misses":2},"#,
+                r#""context":"你","formula":"other","formulas":["fixture","other"],"inconsistencies":0,"#,
+                r#""version":"0.1.0"}}"#,
+            ),
+        ];
+        let output = exchange(&mut protocol, input);
+        let (output, info) = output.trim_end().rsplit_once('\n').unwrap();
+        assert_eq!(output, expected[..5].join("\n"));
+        // the timings vary from one run to the next
+        let mut info: Value = serde_json::from_str(info).unwrap();
+        let timings = info["result"].as_object_mut().unwrap().remove("timings");
+        let components: Vec<_> = timings
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(components, ["artifacts", "patch", "user_dict"]);
+        assert_eq!(info.to_string(), expected[5]);
+        assert!(!protocol.is_shut_down());
+        drop(protocol);
+
+        let entries = UserDict::open(dirs.data_dir.join(USER_DICT_FILE))
+            .unwrap()
+            .entries()
+            .unwrap();
+        assert_eq!((entries[0].text.as_str(), entries[0].count), ("你", 1));
+    }
+
+    #[test]
+    fn test_doctor() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, None);
+        let mut call = |request: &str| json!(protocol.handle_line(request));
+        call(r#"{"method":"commit","params":{"text":"你","code":"ni"}}"#);
+
+        // what the server has open passes without being opened again
+        let results = call(r#"{"method":"doctor"}"#)["result"].clone();
+        let results = results.as_array().unwrap();
+        assert!(results.iter().all(|result| result["status"] == "pass"));
+        let message = |check: &str| {
+            results
+                .iter()
+                .find(|result| result["check"] == check)
+                .unwrap()["message"]
+                .clone()
+        };
+        assert_eq!(
+            message("artifacts:fixture"),
+            "in use by an engine, not opened"
+        );
+        assert_eq!(message("user_dict"), "in use by an engine, not opened");
+        assert_eq!(message("artifacts:other"), "verified");
+    }
+
+    #[test]
+    fn test_initialize() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = Protocol::new(config, &dirs, None).unwrap();
+        let mut call = |request: &str| json!(protocol.handle_line(request));
+
+        // nothing before initialize, which can still come afterwards
+        let early = call(r#"{"id":1,"method":"search","params":{"code":"ni"}}"#);
+        assert_eq!(early["error"]["code"], "E_PROTOCOL");
+        assert_eq!(
+            early["error"]["message"],
+            "protocol error: search before initialize"
+        );
+        let mismatch = call(r#"{"id":2,"method":"initialize","params":{"protocol_version":2}}"#);
+        assert_eq!(
+            mismatch["error"]["message"],
+            "protocol error: unsupported protocol version 2, the server speaks 1"
+        );
+        assert_eq!(
+            call(r#"{"id":3,"method":"initialize","params":{}}"#)["error"]["code"],
+            "E_INVALID_INPUT"
+        );
+
+        let initialize = concat!(
+            r#"{"id":4,"method":"initialize","#,
+            r#""params":{"protocol_version":1,"capabilities":["telepathy"]}}"#
+        );
+        assert_eq!(
+            call(initialize),
+            json!({
+                "id": 4,
+                "result": {
+                    "protocol_version": 1,
+                    "capabilities": [],
+                    "engine": {
+                        "context": false,
+                        "reverse_lookup": true,
+                        "associations": true,
+                        "prefix_info": true,
+                    },
+                    "formulas": ["fixture", "other"],
+                    "limits": { "max_code_len": 64, "max_candidates": 500 },
+                },
+            })
+        );
+        assert_eq!(call(initialize)["error"]["code"], "E_PROTOCOL");
+        assert_eq!(
+            call(r#"{"id":5,"method":"search","params":{"code":"ni"}}"#)["result"][0]["text"],
+            "你"
+        );
+        assert_eq!(
+            call(r#"{"id":5,"method":"prefix_info","params":{"code":"ni"}}"#)["result"],
+            json!({ "is_leaf": false, "child_keys": ["h"], "descendant_codes": 1 })
+        );
+        assert_eq!(
+            call(r#"{"id":5,"method":"prefix_info","params":{"code":"nihao"}}"#)["result"],
+            json!({ "is_leaf": true, "child_keys": [], "descendant_codes": 0 })
+        );
+        // the capabilities not agreed on can't be used
+        let rime = call(r#"{"id":6,"method":"process_key","params":{"key":"space"}}"#);
+        assert_eq!(
+            rime["error"]["message"],
+            "protocol error: the connection wasn't initialized with rime_compat"
+        );
+        let long = format!(
+            r#"{{"id":7,"method":"search","params":{{"code":"{}"}}}}"#,
+            "n".repeat(MAX_CODE_LEN + 1)
+        );
+        assert_eq!(call(&long)["error"]["code"], "E_INVALID_INPUT");
+
+        // each connection negotiates its own
+        let mut other = protocol.server.connect();
+        assert_eq!(
+            json!(other.handle_line(r#"{"method":"info"}"#))["error"]["code"],
+            "E_PROTOCOL"
+        );
+        assert_eq!(
+            json!(other.handle_line(r#"{"method":"shutdown"}"#))["result"],
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let info = EngineInfo {
+            context: "你".to_string(),
+            formula: "sunman".to_string(),
+            formulas: vec!["sunman".to_string(), "pinyin".to_string()],
+            disabled: vec!["wubi".to_string()],
+            version: "0.1.0".to_string(),
+            cache: CacheStats {
+                hits: 3,
+                misses: 2,
+                entries: 2,
+                capacity: DEFAULT_CAPACITY,
+            },
+            timings: BTreeMap::from([
+                ("artifacts".to_string(), 0.25),
+                ("patch".to_string(), 0.0625),
+            ]),
+            inconsistencies: 1,
+        };
+        crate::snapshot::assert_snapshot("engine_info", &info);
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<EngineInfo>(&json).unwrap(), info);
+
+        let initialize = InitializeResult {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec!["rime_compat".to_string()],
+            engine: EngineCapabilities {
+                context: true,
+                reverse_lookup: true,
+                associations: true,
+                prefix_info: true,
+            },
+            formulas: vec!["sunman".to_string()],
+            limits: Limits::default(),
+        };
+        crate::snapshot::assert_snapshot("initialize_result", &initialize);
+        let json = serde_json::to_string(&initialize).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InitializeResult>(&json).unwrap(),
+            initialize
+        );
+    }
+
+    #[test]
+    fn test_rime_compat() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, None);
+
+        let mut call = |request: Value| protocol.handle(serde_json::from_value(request).unwrap());
+        let search = call(json!({
+            "id": 1,
+            "method": "search",
+            "params": { "code": "nix", "rime_compat": true },
+        }));
+        let context = json!(search)["result"].clone();
+        assert_eq!(context["composition"]["preedit"], "nix");
+        assert_eq!(context["composition"]["sel_end"], 2);
+        assert_eq!(
+            context["menu"]["candidates"][1],
+            json!({ "text": "你好", "comment": "~hao" })
+        );
+
+        let key = |key: &str| json!({ "id": 2, "method": "process_key", "params": { "key": key } });
+        assert_eq!(json!(call(key("Down")))["result"]["handled"], true);
+        let result = json!(call(key("space")))["result"].clone();
+        assert_eq!(
+            (&result["handled"], &result["commit"]),
+            (&json!(true), &Value::Null)
+        );
+        assert_eq!(result["rime_context"]["composition"]["preedit"], "你好x");
+        assert_eq!(result["rime_context"]["menu"]["num_candidates"], 0);
+        assert_eq!(json!(call(key("1")))["result"]["handled"], false);
+
+        call(
+            json!({ "id": 3, "method": "search", "params": { "code": "ni", "rime_compat": true } }),
+        );
+        let result = json!(call(key("1")))["result"].clone();
+        assert_eq!(result["commit"], "你");
+        assert_eq!(
+            result["rime_context"]["composition"]["preedit"],
+            Value::Null
+        );
+        // then the rest of the phrases starting with it
+        assert_eq!(
+            result["rime_context"]["menu"]["candidates"],
+            json!([{ "text": "好", "comment": null }])
+        );
+        let info = json!(call(json!({ "id": 4, "method": "info" })));
+        assert_eq!(info["result"]["context"], "你");
+        let result = json!(call(key("1")))["result"].clone();
+        assert_eq!(result["commit"], "好");
+        assert_eq!(result["rime_context"]["menu"]["num_candidates"], 0);
+        let associations = call(json!({
+            "id": 5,
+            "method": "associations",
+            "params": { "text": "你", "limit": 1 },
+        }));
+        assert_eq!(json!(associations)["result"][0]["text"], "你好");
+        drop(protocol);
+
+        let entries = UserDict::open(dirs.data_dir.join(USER_DICT_FILE))
+            .unwrap()
+            .entries()
+            .unwrap();
+        assert_eq!(
+            (entries[0].text.as_str(), entries[0].code.as_str()),
+            ("你", "ni")
+        );
+    }
+
+    #[test]
+    fn test_typing_log() {
+        use crate::typing_log;
+
+        let root = tempfile::tempdir().unwrap();
+        let (mut config, dirs) = profile(root.path());
+        config.typing_log.enabled = true;
+        let mut protocol = connect(config, &dirs, None);
+        let mut call = |request: Value| protocol.handle(serde_json::from_value(request).unwrap());
+        call(json!({ "method": "search", "params": { "code": "ni", "rime_compat": true } }));
+        call(json!({ "method": "process_key", "params": { "key": "Down" } }));
+        call(json!({ "method": "process_key", "params": { "key": "space" } }));
+        // commits that aren't selections aren't logged
+        call(json!({ "method": "commit", "params": { "text": "你", "code": "ni" } }));
+
+        let (records, _) = typing_log::read(&dirs.data_dir, None).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            (records[0].formula.as_str(), records[0].code_len),
+            ("fixture", 5)
+        );
+        assert_eq!((&records[0].indices, &records[0].text), (&vec![1], &None));
+    }
+
+    #[test]
+    fn test_reload_while_searching() {
+        use std::sync::atomic::AtomicUsize;
+        use std::thread;
+
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let words = dirs.config_dir.join("fixture").join("words.tsv");
+        let server = Server::new(
+            Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap(),
+            &dirs,
+            None,
+        )
+        .unwrap();
+        let search = |protocol: &mut Protocol| {
+            let request = r#"{"method":"search","params":{"code":"ni"}}"#;
+            match protocol.handle_line(request).outcome {
+                Outcome::Result(result) => result,
+                Outcome::Error(error) => panic!("search failed: {}", error.report()),
+            }
+        };
+        let mut protocol = server.connect();
+        initialize(&mut protocol);
+
+        let stop = AtomicBool::new(false);
+        let searches = AtomicUsize::new(0);
+        let last = thread::scope(|scope| {
+            let searcher = scope.spawn(|| {
+                let mut protocol = server.connect();
+                initialize(&mut protocol);
+                while !stop.load(Ordering::SeqCst) {
+                    assert!(!search(&mut protocol).as_array().unwrap().is_empty());
+                    searches.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            let mut last = String::new();
+            for i in 1..=10 {
+                // longer each time, so the deploy never takes it for unchanged
+                last = format!("新{}", "词".repeat(i));
+                fs::write(&words, format!("text\tcode\tweight\n{}\tni\t100\n", last)).unwrap();
+                let summary = deploy(&config, &dirs).unwrap();
+                assert_eq!(summary.failed().count(), 0);
+                assert_eq!(server.reload().unwrap(), "fixture");
+            }
+            stop.store(true, Ordering::SeqCst);
+            searcher.join().unwrap();
+            last
+        });
+        assert!(searches.load(Ordering::SeqCst) > 0);
+        assert_eq!(search(&mut protocol)[0]["text"], last);
+        // nothing changed since, the open artifacts aren't verified and the engine is kept
+        assert_eq!(deploy(&config, &dirs).unwrap().failed().count(), 0);
+        assert!(!dirs.target_dir.join(crate::deploy::STAGING_DIR).exists());
+        assert_eq!(server.reload().unwrap(), "fixture");
+        assert_eq!(search(&mut protocol)[0]["text"], last);
+    }
+
+    #[test]
+    fn test_search_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, None);
+        let mut call = |request: Value| {
+            json!(protocol.handle(serde_json::from_value(request).unwrap()))["result"].clone()
+        };
+        let search = json!({ "method": "search", "params": { "code": "ni" } });
+        let info = json!({ "method": "info" });
+        let found = call(search.clone());
+        assert_eq!(call(search.clone()), found);
+        assert_eq!(call(info.clone())["cache"]["hits"], 1);
+
+        // the context is the commit now, and nothing from before it is answered
+        call(json!({ "method": "commit", "params": { "text": "你", "code": "ni" } }));
+        assert_eq!(call(info.clone())["cache"]["entries"], 0);
+        let searched = call(search.clone());
+        assert_eq!(searched[0]["user_count"], 1);
+        assert_eq!(searched[1], found[1]);
+        let cache = serde_json::from_value::<CacheStats>(call(info)["cache"].clone()).unwrap();
+        assert_eq!((cache.hits, cache.misses, cache.entries), (1, 2, 1));
+
+        // counted by each commit, those never committed without a count
+        call(json!({ "method": "commit", "params": { "text": "你", "code": "ni" } }));
+        let searched = call(search);
+        assert_eq!(searched[0]["user_count"], 2);
+        assert!(searched[1].get("user_count").is_none());
+    }
+
+    #[test]
+    fn test_remembered_formula() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, None);
+        let switch = r#"{"method":"set_formula","params":{"formula":"other"}}"#;
+        assert!(matches!(
+            protocol.handle_line(switch).outcome,
+            Outcome::Result(_)
+        ));
+        drop(protocol);
+
+        let reload = || Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        let server = Server::new(reload(), &dirs, None).unwrap();
+        assert_eq!(server.read().formula, "other");
+        // unless asked for another
+        drop(server);
+        let server = Server::new(reload(), &dirs, Some("fixture")).unwrap();
+        assert_eq!(server.read().formula, "fixture");
+        drop(server);
+
+        // gone from the config since
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        let server = Server::new(reload(), &dirs, None).unwrap();
+        assert_eq!(server.read().formula, "fixture");
+    }
+
+    #[test]
+    fn test_shared_artifacts() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let server = Server::new(config, &dirs, None).unwrap();
+        let mut protocol = server.connect();
+        initialize(&mut protocol);
+
+        // the artifacts of the engine of the server, locked for anything but the store
+        let artifacts = server.store.get("fixture").unwrap();
+        let error = EngineWithRedb::with_formula(&dirs.target_dir, "fixture")
+            .err()
+            .unwrap();
+        assert!(error.is_in_use());
+        let search = r#"{"method":"search","params":{"code":"ni"}}"#;
+        let found = json!(protocol.handle_line(search))["result"].clone();
+        let engine = EngineWithRedb::from_artifacts(artifacts.clone());
+        assert_eq!(json!(engine.search("ni").unwrap()), found);
+
+        let mut switch = |formula: &str| {
+            let request = json!({ "method": "set_formula", "params": { "formula": formula } });
+            json!(protocol.handle(serde_json::from_value(request).unwrap()))
+        };
+        assert_eq!(switch("other")["result"]["formula"], "other");
+        assert_eq!(switch("fixture")["result"]["formula"], "fixture");
+        assert!(Arc::ptr_eq(
+            &artifacts,
+            &server.store.get("fixture").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let server = Server::new(config, &dirs, None).unwrap();
+        let warm_up = server.warm_up(Duration::from_secs(60)).unwrap();
+        assert!(warm_up.warmed > 0 && warm_up.is_complete());
+        assert!(server.timings().contains_key("warm_up"));
+        // nor are the searches of the warm-up cached
+        assert_eq!(server.read().engine.stats().misses, 0);
+    }
+
+    #[test]
+    fn test_lazy() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        for extension in ["redb", "trie"] {
+            fs::remove_file(dirs.target_dir.join(format!("other.{}", extension))).unwrap();
+        }
+        let options = ServerOptions {
+            lazy: true,
+            ..Default::default()
+        };
+        let server = Server::with_options(config, &dirs, None, options).unwrap();
+        let mut protocol = server.connect();
+        initialize(&mut protocol);
+        let mut call = |request: &str| json!(protocol.handle_line(request));
+
+        // nothing is open but the patch, the artifacts aren't locked
+        assert!(!dirs.data_dir.join(USER_DICT_FILE).exists());
+        assert_eq!(server.timings().into_keys().collect::<Vec<_>>(), ["patch"]);
+        drop(EngineWithRedb::with_formula(&dirs.target_dir, "fixture").unwrap());
+        let search = r#"{"method":"search","params":{"code":"ni"}}"#;
+        assert_eq!(call(search)["result"][0]["text"], "你");
+        assert!(server.timings().contains_key("artifacts"));
+        assert!(!dirs.data_dir.join(USER_DICT_FILE).exists());
+        call(r#"{"method":"commit","params":{"text":"你","code":"ni"}}"#);
+        assert!(dirs.data_dir.join(USER_DICT_FILE).exists());
+
+        // a formula whose artifacts are missing fails its searches, not the switch
+        let switch = r#"{"method":"set_formula","params":{"formula":"other"}}"#;
+        assert_eq!(call(switch)["result"]["formula"], "other");
+        assert_eq!(call(search)["error"]["code"], "E_FORMULA_NOT_DEPLOYED");
+    }
+
+    #[test]
+    fn test_recency_tiebreak() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        // the last three offered after the candidates of the dictionary, as heavy as `你`
+        let patch = PatchDict::with_formula(&dirs.data_dir, "fixture").unwrap();
+        for (text, weight) in [("妮", 500), ("尼", 1), ("泥", 1), ("倪", 1)] {
+            patch.add(text, "ni", weight).unwrap();
+        }
+        drop(patch);
+        let search = |server: &Arc<Server>| {
+            let mut protocol = server.connect();
+            initialize(&mut protocol);
+            let response =
+                json!(protocol.handle_line(r#"{"method":"search","params":{"code":"ni"}}"#));
+            let texts: Vec<String> = response["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["text"].as_str().unwrap().to_string())
+                .collect();
+            texts
+        };
+        let options = ServerOptions {
+            recency_tiebreak: true,
+            ..Default::default()
+        };
+        let server = Server::with_options(config, &dirs, None, options).unwrap();
+        let before = search(&server);
+        assert_eq!(before, ["妮", "你", "你好", "倪", "尼", "泥"]);
+
+        let middle = before[4].clone();
+        let commit = json!({ "method": "commit", "params": { "text": middle, "code": "ni" } });
+        let mut protocol = server.connect();
+        initialize(&mut protocol);
+        protocol.handle(serde_json::from_value(commit).unwrap());
+        // only the tie is reordered, the heavier candidates above it and `你` apart from it
+        // left where they are
+        assert_eq!(search(&server), ["妮", "你", "你好", "尼", "倪", "泥"]);
+        drop((protocol, server));
+
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        let server = Server::new(config, &dirs, None).unwrap();
+        assert_eq!(search(&server), before);
+    }
+
+    #[test]
+    fn test_disabled_formula() {
+        let root = tempfile::tempdir().unwrap();
+        let (_, dirs) = profile(root.path());
+        let mut overlay = Overlay::default();
+        overlay.set_enabled("other", false);
+        overlay.save(&dirs.config_dir).unwrap();
+        let load = || Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        assert!(matches!(
+            Server::new(load(), &dirs, Some("other")),
+            Err(LiushuError::FormulaDisabled(id)) if id == "other"
+        ));
+
+        let mut protocol = connect(load(), &dirs, None);
+        let switch = r#"{"method":"set_formula","params":{"formula":"other"}}"#;
+        let response = json!(protocol.handle_line(switch));
+        assert_eq!(response["error"]["code"], "E_FORMULA_DISABLED");
+        let info = json!(protocol.handle_line(r#"{"method":"info"}"#))["result"].clone();
+        assert_eq!(info["formula"], "fixture");
+        assert_eq!(info["formulas"], json!(["fixture"]));
+        assert_eq!(info["disabled"], json!(["other"]));
+    }
+
+    #[test]
+    fn test_errors_and_shutdown() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let mut protocol = connect(config, &dirs, Some("other"));
+
+        let input = concat!(
+            "not json\n",
+            r#"{"id":"a","method":"fly"}"#,
+            "\n",
+            r#"{"id":"b","method":"search","params":{}}"#,
+            "\n",
+            r#"{"id":"c","method":"set_formula","params":{"formula":"missing"}}"#,
+            "\n",
+            r#"{"id":"d","method":"shutdown"}"#,
+            "\n",
+            r#"{"id":"e","method":"info"}"#,
+            "\n",
+        );
+        let error = |id: Value, error: LiushuError| json!({ "id": id, "error": error });
+        let invalid = |id, message: &str| error(id, LiushuError::InvalidInput(message.to_string()));
+        let responses: Vec<Value> = exchange(&mut protocol, input)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            responses,
+            [
+                invalid(
+                    Value::Null,
+                    "malformed request: expected ident at line 1 column 2"
+                ),
+                invalid(json!("a"), "unknown method fly"),
+                invalid(json!("b"), "invalid params of search: missing field `code`"),
+                error(
+                    json!("c"),
+                    LiushuError::FormulaUnknown("missing".to_string())
+                ),
+                json!({ "id": "d", "result": null }),
+            ]
+        );
+        assert_eq!(responses[1]["error"]["code"], "E_INVALID_INPUT");
+        assert!(protocol.is_shut_down());
+    }
+    #[test]
+    fn test_profiles() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let profiles = Profiles::of(&dirs);
+        for (name, words) in [("work", "工作\tgz\t5\n"), ("home", "家\tgz\t1\n")] {
+            let dirs = profiles.create(name).unwrap();
+            fs::write(
+                dirs.config_dir.join("main.dhall"),
+                format!(
+                    r#"{{ formulas = [ {{ id = "{}", name = None Text, dictionaries = [ "words.tsv" ] }} ] }}"#,
+                    name
+                ),
+            )
+            .unwrap();
+            fs::create_dir_all(dirs.config_dir.join(name)).unwrap();
+            fs::write(
+                dirs.config_dir.join(name).join("words.tsv"),
+                format!("text\tcode\tweight\n{}", words),
+            )
+            .unwrap();
+            let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+            deploy(&config, &dirs).unwrap();
+        }
+        let server = Server::new(config, &dirs, None).unwrap();
+        let initialize_on = |protocol: &mut Protocol, profile: &str| {
+            let request = json!({
+                "method": "initialize",
+                "params": { "protocol_version": 1, "profile": profile },
+            });
+            json!(protocol.handle_line(&request.to_string()))
+        };
+        let search = |protocol: &mut Protocol, code: &str| {
+            let request = json!({ "method": "search", "params": { "code": code } });
+            let response = json!(protocol.handle_line(&request.to_string()));
+            let texts: Vec<String> = response["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["text"].as_str().unwrap().to_string())
+                .collect();
+            texts
+        };
+
+        let mut work = server.connect();
+        let mut home = server.connect();
+        let mut host = server.connect();
+        assert_eq!(
+            initialize_on(&mut work, "work")["result"]["formulas"],
+            json!(["work"])
+        );
+        assert_eq!(
+            initialize_on(&mut home, "home")["result"]["formulas"],
+            json!(["home"])
+        );
+        initialize(&mut host);
+        assert_eq!(search(&mut work, "gz"), ["工作"]);
+        assert_eq!(search(&mut home, "gz"), ["家"]);
+        assert!(search(&mut host, "gz").is_empty());
+        assert_eq!(search(&mut host, "ni"), ["你", "你好"]);
+        assert!(Arc::ptr_eq(
+            &server.profile("work").unwrap(),
+            &server.profile("work").unwrap()
+        ));
+
+        // a profile that doesn't exist leaves the connection to be initialized
+        let mut missing = server.connect();
+        let error = initialize_on(&mut missing, "missing");
+        assert_eq!(error["error"]["code"], "E_INVALID_INPUT");
+        assert_eq!(
+            initialize_on(&mut missing, "../work")["error"]["code"],
+            "E_INVALID_INPUT"
+        );
+        assert!(initialize_on(&mut missing, "home")["result"].is_object());
+
+        let commit = r#"{"method":"commit","params":{"text":"工作","code":"gz"}}"#;
+        assert!(matches!(
+            work.handle_line(commit).outcome,
+            Outcome::Result(_)
+        ));
+        // the shutdown of any connection stops them all
+        work.handle_line(r#"{"method":"shutdown"}"#);
+        assert!(server.is_shut_down() && home.is_shut_down());
+        drop((work, home, host, missing, server));
+
+        let user_dict = profiles.dirs("work").unwrap().data_dir.join(USER_DICT_FILE);
+        let entries = UserDict::open(user_dict).unwrap().entries().unwrap();
+        assert_eq!(entries[0].text, "工作");
+        assert!(!dirs.data_dir.join(USER_DICT_FILE).exists());
+        let user_dict = profiles.dirs("home").unwrap().data_dir.join(USER_DICT_FILE);
+        assert!(!user_dict.exists());
+    }
+}
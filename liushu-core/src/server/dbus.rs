@@ -0,0 +1,419 @@
+//! The engine on the session bus, for desktop frontends such as an fcitx5 addon. The
+//! service `org.liushu.Engine1` has the object `/org/liushu/Engine1`, implementing
+//!
+//! | member                     | kind   |                                                   |
+//! |----------------------------|--------|---------------------------------------------------|
+//! | `Search(s) → a(ssus)`      | method | text, code, weight and comment of the candidates  |
+//! | `SetFormula(s)`            | method | switches the formula of the server                |
+//! | `Commit(u)`                | method | commits the candidate at the index of last search |
+//! | `Reload()`                 | method | reopens the artifacts of the formula              |
+//! | `ArtifactsChanged(s)`      | signal | the formula reloaded                              |
+//!
+//! An error of a method is named after its code, `E_FORMULA_UNKNOWN` being
+//! `org.liushu.Error.FormulaUnknown`, with the report as the message.
+//!
+//! [`ReloadHook`] asks the service to reload after a deploy, which is what emits the
+//! signal.
+
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use tracing::debug;
+use zbus::blocking::{connection, Connection};
+use zbus::message::{Header, Message};
+use zbus::names::ErrorName;
+use zbus::object_server::SignalEmitter;
+use zbus::DBusError;
+
+use super::Server;
+use crate::composition::Commit;
+use crate::deploy::{DeployHooks, DeploySummary};
+use crate::engine::{InputMethodEngine, SearchResultItem};
+use crate::error::LiushuError;
+
+pub const BUS_NAME: &str = "org.liushu.Engine1";
+pub const OBJECT_PATH: &str = "/org/liushu/Engine1";
+pub const INTERFACE: &str = "org.liushu.Engine1";
+
+/// A [`LiushuError`] as the reply of a method.
+#[derive(Debug)]
+struct Error {
+    name: ErrorName<'static>,
+    message: String,
+}
+
+impl From<LiushuError> for Error {
+    fn from(error: LiushuError) -> Self {
+        let name: String = error
+            .code()
+            .trim_start_matches("E_")
+            .split('_')
+            .map(|word| word[..1].to_string() + &word[1..].to_lowercase())
+            .collect();
+        Self {
+            name: ErrorName::from_string_unchecked(format!("org.liushu.Error.{}", name)),
+            message: error.report(),
+        }
+    }
+}
+
+impl DBusError for Error {
+    fn create_reply(&self, call: &Header<'_>) -> zbus::Result<Message> {
+        Message::error(call, self.name())?.build(&(self.message.as_str(),))
+    }
+
+    fn name(&self) -> ErrorName<'_> {
+        self.name.as_ref()
+    }
+
+    fn description(&self) -> Option<&str> {
+        Some(&self.message)
+    }
+}
+
+fn bus_error(context: &str, error: zbus::Error) -> LiushuError {
+    LiushuError::io(context, io::Error::other(error))
+}
+
+/// The interface, with the context and last candidates of the callers, who share them.
+struct Engine1 {
+    server: Arc<Server>,
+    context: Mutex<String>,
+    candidates: Mutex<Vec<SearchResultItem>>,
+}
+
+impl Engine1 {
+    fn candidates(&self) -> MutexGuard<'_, Vec<SearchResultItem>> {
+        self.candidates.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn context(&self) -> MutexGuard<'_, String> {
+        self.context.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[zbus::interface(name = "org.liushu.Engine1")]
+impl Engine1 {
+    fn search(&self, code: &str) -> Result<Vec<(String, String, u32, String)>, Error> {
+        let context = self.context().clone();
+        let results = self
+            .server
+            .read()
+            .engine
+            .search_in_context(code, &context)?;
+        let reply = results
+            .iter()
+            .map(|item| {
+                (
+                    item.text.clone(),
+                    item.code.clone(),
+                    u32::try_from(item.weight).unwrap_or(u32::MAX),
+                    item.comment.clone().unwrap_or_default(),
+                )
+            })
+            .collect();
+        *self.candidates() = results;
+        Ok(reply)
+    }
+
+    fn set_formula(&self, formula: &str) -> Result<(), Error> {
+        let formula = self.server.config.enabled_formula(formula)?.id.clone();
+        let mut state = self.server.write();
+        let engine = self.server.open_engine(&formula)?;
+        state.engine.set_inner(&formula, engine);
+        state.formula = formula;
+        self.candidates().clear();
+        Ok(())
+    }
+
+    fn commit(&self, index: u32) -> Result<(), Error> {
+        let mut candidates = self.candidates();
+        let Some(item) = candidates.get(index as usize) else {
+            return Err(LiushuError::InvalidInput(format!(
+                "no candidate {}, the last search has {}",
+                index,
+                candidates.len()
+            ))
+            .into());
+        };
+        let state = self.server.write();
+        state.record(self.server.user_dict()?, &item.text, &item.code)?;
+        if let Some(log) = &self.server.typing_log {
+            let commit = Commit {
+                text: item.text.clone(),
+                code: item.code.clone(),
+                indices: vec![index as usize],
+            };
+            log.record(&state.formula, &commit);
+        }
+        drop(state);
+        *self.context() = item.text.clone();
+        candidates.clear();
+        Ok(())
+    }
+
+    async fn reload(
+        &self,
+        #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+    ) -> Result<(), Error> {
+        let formula = self.server.reload()?;
+        self.candidates().clear();
+        Self::artifacts_changed(&emitter, &formula)
+            .await
+            .map_err(|e| bus_error("emit ArtifactsChanged", e))?;
+        Ok(())
+    }
+
+    #[zbus(signal)]
+    async fn artifacts_changed(emitter: &SignalEmitter<'_>, formula: &str) -> zbus::Result<()>;
+}
+
+/// The service, answering on a thread of the connection for as long as it is kept.
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    /// Serves on the session bus.
+    pub fn session(server: Arc<Server>) -> Result<Self, LiushuError> {
+        let builder = connection::Builder::session()
+            .map_err(|e| bus_error("connect to the session bus", e))?;
+        Self::serve(builder, server)
+    }
+
+    /// Serves on the bus at `address`, such as a private one.
+    pub fn at(address: &str, server: Arc<Server>) -> Result<Self, LiushuError> {
+        let builder = connection::Builder::address(address)
+            .map_err(|e| bus_error(&format!("connect to the bus at {}", address), e))?;
+        Self::serve(builder, server)
+    }
+
+    fn serve(builder: connection::Builder<'_>, server: Arc<Server>) -> Result<Self, LiushuError> {
+        let engine = Engine1 {
+            server,
+            context: Mutex::default(),
+            candidates: Mutex::default(),
+        };
+        let connection = builder
+            .name(BUS_NAME)
+            .and_then(|builder| builder.serve_at(OBJECT_PATH, engine))
+            .and_then(|builder| builder.build())
+            .map_err(|e| bus_error(&format!("register {}", BUS_NAME), e))?;
+        Ok(Self { connection })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Serves until the process is stopped.
+    pub fn run(self) -> ! {
+        loop {
+            thread::park();
+        }
+    }
+}
+
+/// Asks the service to reload once a deploy is done, nothing happens when none is running.
+pub struct ReloadHook {
+    connection: Option<Connection>,
+}
+
+impl ReloadHook {
+    /// Calls the service on the session bus, when there is one.
+    pub fn session() -> Self {
+        let connection = Connection::session()
+            .map_err(|error| debug!(%error, "no session bus to notify of the deploy"))
+            .ok();
+        Self { connection }
+    }
+
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection: Some(connection),
+        }
+    }
+}
+
+impl DeployHooks for ReloadHook {
+    fn post_deploy(&self, _: &std::path::Path, _: &DeploySummary) -> Result<(), LiushuError> {
+        let Some(connection) = &self.connection else {
+            return Ok(());
+        };
+        match connection.call_method(Some(BUS_NAME), OBJECT_PATH, Some(INTERFACE), "Reload", &()) {
+            Ok(_) => Ok(()),
+            Err(zbus::Error::MethodError(name, _, _))
+                if name == "org.freedesktop.DBus.Error.ServiceUnknown" =>
+            {
+                debug!("no service to notify of the deploy");
+                Ok(())
+            }
+            Err(e) => Err(bus_error(&format!("reload {}", BUS_NAME), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    use zbus::blocking::MessageIterator;
+    use zbus::MatchRule;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::deploy::deploy_with;
+    use crate::server::tests::profile;
+    use crate::userdict::{UserDict, USER_DICT_FILE};
+
+    /// A bus of the test alone, stopped when dropped.
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon is installed");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Self {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn connect(&self) -> Connection {
+            connection::Builder::address(self.address.as_str())
+                .unwrap()
+                .build()
+                .unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn call<R>(client: &Connection, method: &str, body: &R) -> zbus::Result<Message>
+    where
+        R: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        client.call_method(Some(BUS_NAME), OBJECT_PATH, Some(INTERFACE), method, body)
+    }
+
+    #[test]
+    fn test_methods() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let bus = PrivateBus::start();
+        let server = Server::new(config, &dirs, None).unwrap();
+        let service = DbusService::at(&bus.address, server.clone()).unwrap();
+        let client = bus.connect();
+
+        let reply = call(&client, "Search", &("ni",)).unwrap();
+        let candidates: Vec<(String, String, u32, String)> = reply.body().deserialize().unwrap();
+        assert_eq!(
+            candidates,
+            [
+                ("你".to_string(), "ni".to_string(), 1, String::new()),
+                ("你好".to_string(), "nihao".to_string(), 2, String::new()),
+            ]
+        );
+        call(&client, "Commit", &(1u32,)).unwrap();
+        let Err(zbus::Error::MethodError(name, message, _)) = call(&client, "Commit", &(0u32,))
+        else {
+            panic!("a commit without candidates succeeded");
+        };
+        assert_eq!(name.as_str(), "org.liushu.Error.InvalidInput");
+        assert_eq!(
+            message.as_deref(),
+            Some("invalid input: no candidate 0, the last search has 0")
+        );
+
+        let Err(zbus::Error::MethodError(name, _, _)) = call(&client, "SetFormula", &("missing",))
+        else {
+            panic!("switched to a missing formula");
+        };
+        assert_eq!(name.as_str(), "org.liushu.Error.FormulaUnknown");
+        call(&client, "SetFormula", &("other",)).unwrap();
+        let reply = call(&client, "Search", &("ni",)).unwrap();
+        let candidates: Vec<(String, String, u32, String)> = reply.body().deserialize().unwrap();
+        assert_eq!(candidates[0].0, "尼");
+        drop(service);
+        // the connection lets go of the server on a thread of its own
+        for _ in 0..100 {
+            if Arc::strong_count(&server) == 1 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        drop(server);
+
+        let entries = UserDict::open(dirs.data_dir.join(USER_DICT_FILE))
+            .unwrap()
+            .entries()
+            .unwrap();
+        assert_eq!(
+            (entries[0].text.as_str(), entries[0].code.as_str()),
+            ("你好", "nihao")
+        );
+    }
+
+    #[test]
+    fn test_reload_on_deploy() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let bus = PrivateBus::start();
+        let service = DbusService::at(
+            &bus.address,
+            Server::new(
+                Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap(),
+                &dirs,
+                None,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let client = bus.connect();
+        let rule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface(INTERFACE)
+            .unwrap()
+            .member("ArtifactsChanged")
+            .unwrap()
+            .build();
+        let mut signals = MessageIterator::for_match_rule(rule, &client, None).unwrap();
+
+        fs::write(
+            dirs.config_dir.join("fixture").join("words.tsv"),
+            "text\tcode\tweight\n拟\tni\t1\n",
+        )
+        .unwrap();
+        let summary = deploy_with(&config, &dirs, &ReloadHook::new(bus.connect())).unwrap();
+        assert!(summary.warnings.is_empty());
+
+        let signal = signals.next().unwrap().unwrap();
+        let formula: String = signal.body().deserialize().unwrap();
+        assert_eq!(formula, "fixture");
+        let reply = call(&client, "Search", &("ni",)).unwrap();
+        let candidates: Vec<(String, String, u32, String)> = reply.body().deserialize().unwrap();
+        assert_eq!(candidates[0].0, "拟");
+
+        // nothing to notify once the service is gone
+        drop(service);
+        deploy_with(&config, &dirs, &ReloadHook::new(bus.connect())).unwrap();
+    }
+}
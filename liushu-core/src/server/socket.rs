@@ -0,0 +1,271 @@
+//! The protocol on a unix domain socket, for a daemon shared by every frontend of a desktop.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use super::Server;
+use crate::error::{IoResultExt, LiushuError};
+
+/// How often the listener looks for new connections, a shutdown and the idle timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Connections being served, to close them when the server stops, and since when there
+/// have been none.
+#[derive(Default)]
+struct Connections {
+    streams: HashMap<u64, UnixStream>,
+    idle_since: Option<Instant>,
+}
+
+pub struct SocketServer {
+    server: Arc<Server>,
+    listener: UnixListener,
+    path: PathBuf,
+    /// Stop once no connection has been open for this long.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl SocketServer {
+    /// Listens on `path`, replacing the socket left by a server that didn't shut down
+    /// cleanly but not one still listening, nor a file that isn't a socket.
+    pub fn bind(server: Arc<Server>, path: impl AsRef<Path>) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(LiushuError::io_at(
+                    "listen on",
+                    path,
+                    io::Error::new(ErrorKind::AlreadyExists, "the file is not a socket"),
+                ));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(LiushuError::io_at(
+                    "listen on",
+                    path,
+                    io::Error::new(ErrorKind::AddrInUse, "another server is listening"),
+                ));
+            }
+            debug!(path = %path.display(), "removing stale socket");
+            fs::remove_file(path).with_path("remove stale socket", path)?;
+        }
+        let listener = UnixListener::bind(path).with_path("listen on", path)?;
+        listener
+            .set_nonblocking(true)
+            .with_path("listen on", path)?;
+        Ok(Self {
+            server,
+            listener,
+            path: path.to_path_buf(),
+            idle_timeout: None,
+        })
+    }
+
+    /// Serves every connection on a thread of its own until a `shutdown` request or the
+    /// idle timeout, then closes the connections left and removes the socket.
+    pub fn run(&self) -> Result<(), LiushuError> {
+        let connections = Mutex::new(Connections {
+            idle_since: Some(Instant::now()),
+            ..Default::default()
+        });
+        let result = thread::scope(|scope| {
+            let result = self.accept(scope, &connections);
+            let connections = connections.lock().unwrap_or_else(|e| e.into_inner());
+            for stream in connections.streams.values() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            result
+        });
+        fs::remove_file(&self.path).with_path("remove socket", &self.path)?;
+        result
+    }
+
+    fn accept<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        connections: &'scope Mutex<Connections>,
+    ) -> Result<(), LiushuError> {
+        let lock = move || connections.lock().unwrap_or_else(|e| e.into_inner());
+        for id in 0.. {
+            let stream = loop {
+                if self.server.is_shut_down() {
+                    return Ok(());
+                }
+                if let (Some(timeout), Some(idle_since)) = (self.idle_timeout, lock().idle_since) {
+                    if idle_since.elapsed() >= timeout {
+                        debug!(?timeout, "no connection, shutting down");
+                        return Ok(());
+                    }
+                }
+                match self.listener.accept() {
+                    Ok((stream, _)) => break stream,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.flush_user_dict();
+                        thread::sleep(POLL_INTERVAL)
+                    }
+                    Err(e) => return Err(LiushuError::io_at("accept on", &self.path, e)),
+                }
+            };
+            let reader = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.try_clone())
+                .with_path("accept on", &self.path)?;
+            {
+                let mut connections = lock();
+                connections
+                    .streams
+                    .insert(id, reader.try_clone().with_path("accept on", &self.path)?);
+                connections.idle_since = None;
+            }
+            scope.spawn(move || {
+                let mut protocol = self.server.connect();
+                if let Err(error) = protocol.serve(BufReader::new(reader), &stream) {
+                    warn!(error = %error.report(), "connection failed");
+                }
+                let mut connections = lock();
+                connections.streams.remove(&id);
+                if connections.streams.is_empty() {
+                    connections.idle_since = Some(Instant::now());
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the commits of a batch due while no connection is coming in.
+    fn flush_user_dict(&self) {
+        for server in self.server.hosted() {
+            if let Some(user_dict) = server.user_dict.get() {
+                if let Err(error) = user_dict.flush_if_due() {
+                    warn!(error = %error.report(), "cannot write the user dictionary");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Write};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::server::tests::profile;
+
+    struct Client {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+
+    impl Client {
+        fn connect(path: &Path) -> Self {
+            let writer = UnixStream::connect(path).unwrap();
+            let reader = BufReader::new(writer.try_clone().unwrap());
+            let mut client = Self { reader, writer };
+            let initialize = json!({ "method": "initialize", "params": { "protocol_version": 1 } });
+            assert_eq!(client.call(initialize)["result"]["protocol_version"], 1);
+            client
+        }
+
+        fn call(&mut self, request: Value) -> Value {
+            writeln!(self.writer, "{}", request).unwrap();
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_concurrent_clients() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let path = root.path().join("liushu.sock");
+        // a stale socket of a server that is gone
+        drop(UnixListener::bind(&path).unwrap());
+        let server = SocketServer::bind(Server::new(config, &dirs, None).unwrap(), &path).unwrap();
+        assert!(SocketServer::bind(server.server.clone(), &path).is_err());
+
+        thread::scope(|scope| {
+            let running = scope.spawn(|| server.run());
+            let mut a = Client::connect(&path);
+            let mut b = Client::connect(&path);
+
+            let search = json!({ "id": 1, "method": "search", "params": { "code": "nihao" } });
+            let expected = json!({
+                "id": 1,
+                "result": [{
+                    "code": "nihao",
+                    "comment": null,
+                    "text": "你好",
+                    "weight": 2,
+                    "is_exact": true,
+                }],
+            });
+            assert_eq!(a.call(search.clone()), expected);
+            assert_eq!(b.call(search), expected);
+
+            let commit =
+                json!({ "id": 2, "method": "commit", "params": { "text": "你", "code": "ni" } });
+            assert_eq!(a.call(commit), json!({ "id": 2, "result": null }));
+            let switch =
+                json!({ "id": 3, "method": "set_formula", "params": { "formula": "other" } });
+            assert_eq!(b.call(switch)["result"], json!({ "formula": "other" }));
+
+            // the formula is shared, what was committed is the context of its connection only
+            let info = json!({ "id": 4, "method": "info" });
+            let (info_a, info_b) = (a.call(info.clone()), b.call(info));
+            assert_eq!(
+                (&info_a["result"]["context"], &info_a["result"]["formula"]),
+                (&json!("你"), &json!("other"))
+            );
+            assert_eq!(
+                (&info_b["result"]["context"], &info_b["result"]["formula"]),
+                (&json!(""), &json!("other"))
+            );
+
+            assert_eq!(
+                a.call(json!({ "id": 5, "method": "shutdown" }))["result"],
+                Value::Null
+            );
+            running.join().unwrap().unwrap();
+            // b is closed by the server
+            let mut line = String::new();
+            assert_eq!(b.reader.read_line(&mut line).unwrap(), 0);
+        });
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let root = tempfile::tempdir().unwrap();
+        let (config, dirs) = profile(root.path());
+        let path = root.path().join("liushu.sock");
+        fs::write(&path, "").unwrap();
+        let server = Server::new(config, &dirs, None).unwrap();
+        assert!(SocketServer::bind(server.clone(), &path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let mut socket = SocketServer::bind(server, &path).unwrap();
+        socket.idle_timeout = Some(Duration::from_millis(100));
+        thread::scope(|scope| {
+            let running = scope.spawn(|| socket.run());
+            // a connection keeps the server up past the timeout
+            let mut client = Client::connect(&path);
+            thread::sleep(Duration::from_millis(300));
+            assert_eq!(client.call(json!({ "id": 1, "method": "info" }))["id"], 1);
+            drop(client);
+            running.join().unwrap().unwrap();
+        });
+        assert!(!path.exists());
+    }
+}
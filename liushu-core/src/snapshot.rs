@@ -0,0 +1,27 @@
+//! Golden files in `snapshots/` pinning the JSON of the types that cross process boundaries,
+//! so that a change of their shape is deliberate. `LIUSHU_UPDATE_SNAPSHOTS=1` rewrites the
+//! files instead of comparing with them.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+
+pub(crate) fn assert_snapshot(name: &str, value: &impl Serialize) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("snapshots")
+        .join(format!("{}.json", name));
+    let json = serde_json::to_string_pretty(value).unwrap() + "\n";
+    if std::env::var_os("LIUSHU_UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, json).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+    assert_eq!(
+        json, expected,
+        "the JSON of {} changed, rerun with LIUSHU_UPDATE_SNAPSHOTS=1 if that is intended",
+        name
+    );
+}
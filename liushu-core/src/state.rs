@@ -0,0 +1,100 @@
+//! What liushu remembers of one session for the next, in [`STATE_FILE`] of the data dir: the
+//! formula last switched to, which [`Config::initial_formula`] starts on.
+//!
+//! The state is only ever a preference. A file that is missing or can't be read is the
+//! default state, and one that can't be written is a warning.
+//!
+//! [`Config::initial_formula`]: crate::config::Config::initial_formula
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::{IoResultExt, LiushuError};
+
+pub const STATE_FILE: &str = "state.json";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct SessionState {
+    /// The id of the formula switched to last, which may be gone from the config since.
+    pub active_formula: Option<String>,
+}
+
+impl SessionState {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join(STATE_FILE);
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(_) => return Self::default(),
+        };
+        serde_json::from_str(&json).unwrap_or_else(|error| {
+            warn!(path = %path.display(), %error, "ignoring unreadable state");
+            Self::default()
+        })
+    }
+
+    /// Written aside and renamed over the state, so that a crash can't leave half of it.
+    pub fn save(&self, data_dir: &Path) -> Result<(), LiushuError> {
+        let path = data_dir.join(STATE_FILE);
+        let temp = data_dir.join(format!("{}.tmp", STATE_FILE));
+        fs::create_dir_all(data_dir).with_path("create data dir", data_dir)?;
+        let json =
+            serde_json::to_string(self).map_err(|e| LiushuError::io_at("encode", &path, e))?;
+        fs::write(&temp, json).with_path("write state", &temp)?;
+        fs::rename(&temp, &path).with_path("replace state", &path)
+    }
+
+    /// Remembers `formula` as the one switched to last, warning when that fails as the
+    /// switch itself succeeded.
+    pub fn remember_formula(data_dir: &Path, formula: &str) {
+        let mut state = Self::load(data_dir);
+        if state.active_formula.as_deref() == Some(formula) {
+            return;
+        }
+        state.active_formula = Some(formula.to_string());
+        if let Err(error) = state.save(data_dir) {
+            warn!(error = %error.report(), "cannot remember the formula");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        assert_eq!(SessionState::load(&data_dir), SessionState::default());
+
+        SessionState::remember_formula(&data_dir, "pinyin");
+        assert_eq!(
+            SessionState::load(&data_dir).active_formula.as_deref(),
+            Some("pinyin")
+        );
+        SessionState::remember_formula(&data_dir, "sunman");
+        assert_eq!(
+            SessionState::load(&data_dir).active_formula.as_deref(),
+            Some("sunman")
+        );
+        assert!(!data_dir.join("state.json.tmp").exists());
+
+        fs::write(data_dir.join(STATE_FILE), "{ not json").unwrap();
+        assert_eq!(SessionState::load(&data_dir), SessionState::default());
+        // fields it doesn't know are ignored
+        fs::write(
+            data_dir.join(STATE_FILE),
+            r#"{"active_formula":"pinyin","later":1}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            SessionState::load(&data_dir).active_formula.as_deref(),
+            Some("pinyin")
+        );
+    }
+}
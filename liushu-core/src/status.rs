@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Instant, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, Formula},
+    deploy::{self, Backup},
+    dirs::MyProjectDirs,
+    error::LiushuError,
+    hmm::MODEL_FILE,
+    server::Server,
+};
+
+const FORMULA_ARTIFACTS: [&str; 4] = ["db3", "redb", "trie", "assoc"];
+
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub version: &'static str,
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub config_error: Option<String>,
+    pub formulas: Vec<FormulaStatus>,
+    pub hmm_model: Option<ArtifactStatus>,
+    /// Artifacts of formulas no longer in the config.
+    pub orphans: Vec<PathBuf>,
+    /// The most recent first.
+    pub backups: Vec<Backup>,
+    /// Those of [`startup_timings`], when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<BTreeMap<String, f64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FormulaStatus {
+    pub id: String,
+    pub name: Option<String>,
+    /// Whether the artifacts used by `EngineWithRedb` are all present.
+    pub deployed: bool,
+    /// See [`Formula::is_enabled`].
+    pub enabled: bool,
+    pub artifacts: Vec<ArtifactStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtifactStatus {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Last modification time in seconds since the unix epoch.
+    pub modified: Option<u64>,
+}
+
+impl ArtifactStatus {
+    fn stat(path: PathBuf) -> Option<Self> {
+        let metadata = fs::metadata(&path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+
+        Some(Self {
+            path,
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+pub fn collect(dirs: &MyProjectDirs) -> StatusReport {
+    let (formulas, orphans, config_error) =
+        match Config::load_from_path(dirs.config_dir.join("main.dhall")) {
+            Ok(config) => (
+                config
+                    .formulas
+                    .iter()
+                    .map(|formula| formula_status(&dirs.target_dir, formula))
+                    .collect(),
+                deploy::orphans(&config, &dirs.target_dir).unwrap_or_default(),
+                None,
+            ),
+            Err(e) => (Vec::new(), Vec::new(), Some(e.report())),
+        };
+
+    StatusReport {
+        version: env!("CARGO_PKG_VERSION"),
+        config_dir: dirs.config_dir.clone(),
+        data_dir: dirs.data_dir.clone(),
+        target_dir: dirs.target_dir.clone(),
+        config_error,
+        formulas,
+        hmm_model: ArtifactStatus::stat(dirs.target_dir.join(MODEL_FILE)),
+        orphans,
+        backups: deploy::backups(dirs).unwrap_or_default(),
+        timings: None,
+    }
+}
+
+/// Seconds each component takes to open when a frontend starts a server: the config, then
+/// those of [`Server::timings`] for the first formula, the user dictionary included, which
+/// is created if missing.
+pub fn startup_timings(dirs: &MyProjectDirs) -> Result<BTreeMap<String, f64>, LiushuError> {
+    let start = Instant::now();
+    let config = Config::load_from_path(dirs.config_dir.join("main.dhall"))?;
+    let config_secs = start.elapsed().as_secs_f64();
+    let server = Server::new(config, dirs, None)?;
+    server.user_dict()?;
+    let mut timings = server.timings();
+    timings.insert("config".to_string(), config_secs);
+    Ok(timings)
+}
+
+fn formula_status(target_dir: &Path, formula: &Formula) -> FormulaStatus {
+    let id = &formula.id;
+    let artifacts: Vec<ArtifactStatus> = FORMULA_ARTIFACTS
+        .iter()
+        .filter_map(|ext| ArtifactStatus::stat(target_dir.join(format!("{}.{}", id, ext))))
+        .collect();
+    let deployed = ["redb", "trie"]
+        .iter()
+        .all(|ext| target_dir.join(format!("{}.{}", id, ext)).exists());
+
+    FormulaStatus {
+        id: id.to_string(),
+        name: formula.name.clone(),
+        deployed,
+        enabled: formula.is_enabled(),
+        artifacts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dirs(root: &Path) -> MyProjectDirs {
+        let dirs = MyProjectDirs::from_root(root);
+        fs::create_dir_all(dirs.config_dir.join("fixture")).unwrap();
+        fs::create_dir_all(&dirs.target_dir).unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [ { id = "fixture", name = Some "Fixture", dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        fs::write(
+            dirs.config_dir.join("fixture/words.tsv"),
+            "text\tcode\tweight\n你\tni\t1\n",
+        )
+        .unwrap();
+        dirs
+    }
+
+    #[test]
+    fn test_fresh_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+
+        let report = collect(&dirs);
+        assert!(report.config_error.is_none());
+        assert_eq!(report.formulas.len(), 1);
+        assert!(!report.formulas[0].deployed);
+        assert!(report.formulas[0].artifacts.is_empty());
+        assert!(report.hmm_model.is_none());
+        assert!(report.backups.is_empty());
+    }
+
+    #[test]
+    fn test_missing_config() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        fs::remove_file(dirs.config_dir.join("main.dhall")).unwrap();
+
+        let report = collect(&dirs);
+        assert!(report.config_error.is_some());
+        assert!(report.formulas.is_empty());
+    }
+
+    #[test]
+    fn test_deployed_profile() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = scratch_dirs(root.path());
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        config.formulas[0]
+            .compile2(&dirs.config_dir, &dirs.target_dir)
+            .unwrap();
+        fs::write(dirs.target_dir.join(MODEL_FILE), "model").unwrap();
+
+        let report = collect(&dirs);
+        let formula = &report.formulas[0];
+        assert_eq!(formula.name.as_deref(), Some("Fixture"));
+        assert!(formula.deployed);
+        assert_eq!(formula.artifacts.len(), 3);
+        assert!(formula.artifacts.iter().all(|a| a.size > 0));
+        assert_eq!(report.hmm_model.map(|m| m.size), Some(5));
+        assert!(report.orphans.is_empty());
+
+        fs::write(dirs.target_dir.join("removed.trie"), "").unwrap();
+        assert_eq!(
+            collect(&dirs).orphans,
+            [dirs.target_dir.join("removed.trie")]
+        );
+
+        let timings = startup_timings(&dirs).unwrap();
+        let components: Vec<_> = timings.keys().map(String::as_str).collect();
+        assert_eq!(components, ["artifacts", "config", "patch", "user_dict"]);
+    }
+}
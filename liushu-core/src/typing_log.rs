@@ -0,0 +1,249 @@
+//! A log of the commits of compositions, opt-in with `typingLog = { enabled = True }` in
+//! the config, for analyzing one's own typing. It is a JSON line per commit in the data dir,
+//! with the code length, the indices of the candidates selected and the formula, but not
+//! the committed text unless `logText` is set.
+//!
+//! The log never fails what is being logged: an error writing it is only a warning. Past
+//! `maxBytes` it is moved to [`TYPING_LOG_FILE`]`.1`, replacing the one rotated before.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::composition::Commit;
+use crate::config::TypingLogConfig;
+use crate::error::{IoResultExt, LiushuError};
+
+pub const TYPING_LOG_FILE: &str = "typing.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypingRecord {
+    /// Seconds since the unix epoch.
+    pub ts: u64,
+    pub formula: String,
+    /// Bytes of the code of the whole commit.
+    pub code_len: usize,
+    /// Index of the candidate selected for each segment.
+    pub indices: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+pub struct TypingLog {
+    path: PathBuf,
+    config: TypingLogConfig,
+    /// Connections take turns, so that a line is never written into a file being rotated.
+    lock: Mutex<()>,
+}
+
+impl TypingLog {
+    /// The log in `data_dir`, `None` unless enabled.
+    pub fn open(data_dir: &Path, config: &TypingLogConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            path: data_dir.join(TYPING_LOG_FILE),
+            config: config.clone(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the record of `commit`, warning when it can't be.
+    pub fn record(&self, formula: &str, commit: &Commit) {
+        let record = TypingRecord {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            formula: formula.to_string(),
+            code_len: commit.code.len(),
+            indices: commit.indices.clone(),
+            text: self.config.log_text.then(|| commit.text.clone()),
+        };
+        if let Err(e) = self.append(&record) {
+            warn!(path = %self.path.display(), "cannot write the typing log: {}", e);
+        }
+    }
+
+    fn append(&self, record: &TypingRecord) -> io::Result<()> {
+        let _lock = self
+            .lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size > 0 && size + line.len() as u64 > self.config.max_bytes {
+            fs::rename(&self.path, rotated(&self.path))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+/// What the log in a data dir tells, see `liushu log stats`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[non_exhaustive]
+pub struct TypingStats {
+    pub commits: usize,
+    /// Bytes of code per commit.
+    pub average_code_len: f64,
+    /// Number of candidates selected at each index.
+    pub selections: BTreeMap<usize, usize>,
+    /// Lines that aren't records, such as one cut short by a crash.
+    pub malformed: usize,
+}
+
+/// The records of the log in `data_dir`, the rotated ones first, of `formula` when given.
+/// Malformed lines are counted.
+pub fn read(
+    data_dir: &Path,
+    formula: Option<&str>,
+) -> Result<(Vec<TypingRecord>, usize), LiushuError> {
+    let path = data_dir.join(TYPING_LOG_FILE);
+    let mut records = Vec::new();
+    let mut malformed = 0;
+    for path in [rotated(&path), path] {
+        let content = match fs::read_to_string(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            content => content.with_path("read typing log", &path)?,
+        };
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<TypingRecord>(line) {
+                Ok(record) if formula.is_some_and(|formula| record.formula != formula) => {}
+                Ok(record) => records.push(record),
+                Err(_) => malformed += 1,
+            }
+        }
+    }
+    Ok((records, malformed))
+}
+
+pub fn stats(data_dir: &Path, formula: Option<&str>) -> Result<TypingStats, LiushuError> {
+    let (records, malformed) = read(data_dir, formula)?;
+    let mut selections = BTreeMap::new();
+    for index in records.iter().flat_map(|record| &record.indices) {
+        *selections.entry(*index).or_default() += 1;
+    }
+    let code_len: usize = records.iter().map(|record| record.code_len).sum();
+    Ok(TypingStats {
+        commits: records.len(),
+        average_code_len: if records.is_empty() {
+            0.0
+        } else {
+            code_len as f64 / records.len() as f64
+        },
+        selections,
+        malformed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn commit(text: &str, code: &str, indices: &[usize]) -> Commit {
+        Commit {
+            text: text.to_string(),
+            code: code.to_string(),
+            indices: indices.to_vec(),
+        }
+    }
+
+    fn config(log_text: bool, max_bytes: u64) -> TypingLogConfig {
+        TypingLogConfig {
+            enabled: true,
+            log_text,
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(TypingLog::open(dir.path(), &TypingLogConfig::default()).is_none());
+
+        let log = TypingLog::open(dir.path(), &config(false, 1 << 20)).unwrap();
+        log.record("sunman", &commit("你好吗", "nihaoma", &[0, 2]));
+        let content = fs::read_to_string(dir.path().join(TYPING_LOG_FILE)).unwrap();
+        let mut record: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert!(record["ts"].as_u64().unwrap() > 0);
+        record["ts"] = json!(0);
+        assert_eq!(
+            record,
+            json!({ "ts": 0, "formula": "sunman", "code_len": 7, "indices": [0, 2] })
+        );
+
+        let log = TypingLog::open(dir.path(), &config(true, 1 << 20)).unwrap();
+        log.record("sunman", &commit("你", "ni", &[1]));
+        let (records, malformed) = read(dir.path(), None).unwrap();
+        assert_eq!((records.len(), malformed), (2, 0));
+        assert_eq!(records[0].text, None);
+        assert_eq!(records[1].text.as_deref(), Some("你"));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = TypingLog::open(dir.path(), &config(false, 150)).unwrap();
+        for i in 0..5 {
+            log.record("sunman", &commit("你", "ni", &[i]));
+        }
+        let size = |name: &str| fs::metadata(dir.path().join(name)).unwrap().len();
+        assert!(size(TYPING_LOG_FILE) <= 150);
+        assert!(size("typing.jsonl.1") <= 150);
+        // what was rotated before is gone, the rest is read back in order
+        let (records, _) = read(dir.path(), None).unwrap();
+        assert!(records.len() < 5);
+        let indices: Vec<_> = records.iter().map(|record| record.indices[0]).collect();
+        assert_eq!(indices, (5 - records.len()..5).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fail_soft() {
+        let dir = tempfile::tempdir().unwrap();
+        // the data dir is a file
+        let data_dir = dir.path().join("data");
+        fs::write(&data_dir, "").unwrap();
+        let log = TypingLog::open(&data_dir, &config(false, 1 << 20)).unwrap();
+        log.record("sunman", &commit("你", "ni", &[0]));
+        assert!(read(&data_dir, None).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(stats(dir.path(), None).unwrap(), TypingStats::default());
+
+        let log = TypingLog::open(dir.path(), &config(false, 1 << 20)).unwrap();
+        log.record("sunman", &commit("你好吗", "nihaoma", &[0, 2]));
+        log.record("sunman", &commit("你", "ni", &[0]));
+        log.record("other", &commit("你", "nix", &[1]));
+        let mut file = OpenOptions::new().append(true).open(log.path()).unwrap();
+        file.write_all(b"{\"ts\": 1, \"formu").unwrap();
+
+        let stats = stats(dir.path(), Some("sunman")).unwrap();
+        assert_eq!(stats.commits, 2);
+        assert_eq!(stats.average_code_len, 4.5);
+        assert_eq!(stats.selections, BTreeMap::from([(0, 2), (2, 1)]));
+        assert_eq!(stats.malformed, 1);
+    }
+}
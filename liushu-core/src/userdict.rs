@@ -0,0 +1,985 @@
+//! Phrases committed by the user, counted in a redb database next to the other data files.
+//!
+//! A commit is appended to a journal, [`JOURNAL_EXTENSION`] next to the database, and
+//! counted in memory until a batch of them is written to the database, as a write
+//! transaction on every commit would add to its latency and wear the flash of phones. The
+//! journal of a process that died before writing its batch is replayed when the dictionary
+//! is opened again, so a crash loses at most what the OS hadn't written of the journal.
+//! Dropping it takes a daily backup, see [`backup`].
+//!
+//! redb opens a database once, so a dictionary opened while another process holds it, as
+//! the REPL or a second server does next to the daemon, journals its commits in a file of
+//! its own in the [`JOURNALS_EXTENSION`] dir next to the database instead, locked for as
+//! long as it is open. The process holding the database folds the journals no process
+//! holds any more into it as it opens and with each batch it writes, then removes them.
+//! Until then the commits of each are counted by it alone.
+
+mod backup;
+pub mod migrations;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redb::{ReadableTable, Table, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::artifact::{ArtifactDb, ArtifactOpenOptions, OpenMode};
+use crate::engine::SearchResultItem;
+use crate::error::{IoResultExt, LiushuError};
+
+pub use self::backup::{BackupPolicy, RestoreReport, BACKUP_DIR};
+
+pub const USER_DICT_FILE: &str = "userdict.redb";
+
+/// The extension of the journal of a user dictionary, `userdict.journal` for
+/// [`USER_DICT_FILE`].
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+/// The extension of the dir of the journals of the processes that couldn't open the
+/// database, `userdict.journals` for [`USER_DICT_FILE`], see the [module docs](self).
+pub const JOURNALS_EXTENSION: &str = "journals";
+
+/// Keyed by `(code, text)`, valued by `(count, last_used, source)`, of the schema version
+/// [`migrations::CURRENT_VERSION`].
+const USER_DICT: TableDefinition<(&str, &str), (u64, u64, Option<&str>)> =
+    TableDefinition::new("user_dict");
+
+/// The sequence number of the last commit of the journal written to the database, under
+/// [`JOURNAL_APPLIED`], so that a journal left by a crash right after a batch was written
+/// isn't counted twice, and the version of the schema, see [`migrations`].
+const META: TableDefinition<&str, u64> = TableDefinition::new("meta");
+const JOURNAL_APPLIED: &str = "journal_applied";
+/// Followed by the name of a journal of the [`JOURNALS_EXTENSION`] dir, the sequence
+/// number of the last of its commits folded, for one left by a crash right after.
+const JOURNAL_FOLDED: &str = "journal_folded:";
+
+/// When commits counted in memory are written to the database: once there are `commits` of
+/// them, or the first of them is `interval` old as another is recorded or
+/// [`UserDict::flush_if_due`] is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    pub commits: usize,
+    pub interval: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            commits: 32,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Commits recorded since the last batch was written, each one a line of the journal.
+struct Pending {
+    journal: File,
+    journal_path: PathBuf,
+    /// Sequence number of the last commit of the journal.
+    seq: u64,
+    /// Commits of each `(code, text)` and when the last one was.
+    counts: BTreeMap<(String, String), (u64, u64)>,
+    commits: usize,
+    since: Option<Instant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserDictItem {
+    pub text: String,
+    pub code: String,
+    pub count: u64,
+    /// Seconds since the unix epoch.
+    pub last_used: u64,
+    /// Where the entry came from when it wasn't committed, such as another dictionary it
+    /// was imported from, kept by the commits that follow.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Add the imported counts to the existing entries.
+    Merge,
+    /// Drop every existing entry before importing.
+    Replace,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub warnings: Vec<String>,
+}
+
+/// The commits of a user dictionary as of its last batch, read once by a frontend that
+/// doesn't commit, such as the REPL, so as not to keep the dictionary open.
+#[derive(Debug, Default, Clone)]
+pub struct UserCounts(HashMap<(String, String), u64>);
+
+impl UserCounts {
+    /// Those of the dictionary at `path`, opened read-only, none when there is none.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let db =
+            ArtifactOpenOptions::new(OpenMode::ReadOnly).open_redb(path, "open user dictionary")?;
+        let read_txn = db.begin_read()?;
+        Ok(Self(migrations::read_counts(&read_txn, path)?))
+    }
+
+    /// Like [`UserDict::annotate`].
+    pub fn annotate(&self, items: &mut [SearchResultItem]) {
+        for item in items {
+            if let Some(&count) = self.0.get(&(item.code.clone(), item.text.clone())) {
+                item.user_count = Some(count);
+            }
+        }
+    }
+}
+
+/// Phrases committed by the user, see the [module docs](self). Dropping it writes the
+/// commits it still counts in memory, which [`UserDict::close`] does with an error to tell,
+/// then backs it up when due.
+pub struct UserDict {
+    /// None while another process holds it, the commits journaled for that one to fold.
+    db: Option<ArtifactDb>,
+    path: PathBuf,
+    policy: FlushPolicy,
+    /// None when it is never backed up.
+    backups: Option<BackupPolicy>,
+    pending: Mutex<Pending>,
+}
+
+impl UserDict {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LiushuError> {
+        Self::open_with_policy(path, FlushPolicy::default())
+    }
+
+    /// Opens the dictionary at `path`, migrating it to the current schema, then counting the
+    /// commits of a journal left by a process that didn't write them and those of the
+    /// processes that couldn't open it. While another process holds it, it journals its
+    /// commits for that one instead, see the [module docs](self).
+    pub fn open_with_policy(
+        path: impl AsRef<Path>,
+        policy: FlushPolicy,
+    ) -> Result<Self, LiushuError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_path("create data dir", parent)?;
+        }
+        let db = match ArtifactOpenOptions::new(OpenMode::ReadWrite)
+            .open_redb(path, "open user dictionary")
+        {
+            Ok(db) => db,
+            Err(e) if e.is_in_use() => return Self::open_journaled(path, policy),
+            Err(e) => return Err(e),
+        };
+        migrations::run(&db)?;
+        let journal_path = path.with_extension(JOURNAL_EXTENSION);
+        let seq = replay(&db, &journal_path)?;
+        fold(&db, &path.with_extension(JOURNALS_EXTENSION))?;
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .with_path("open journal", &journal_path)?;
+        journal
+            .set_len(0)
+            .with_path("truncate journal", &journal_path)?;
+        Ok(Self {
+            db: Some(db),
+            path: path.to_path_buf(),
+            policy,
+            backups: Some(BackupPolicy::default()),
+            pending: Mutex::new(Pending {
+                journal,
+                journal_path,
+                seq,
+                counts: BTreeMap::new(),
+                commits: 0,
+                since: None,
+            }),
+        })
+    }
+
+    /// Journals the commits in a new file of the journals dir, locked until it is dropped.
+    fn open_journaled(path: &Path, policy: FlushPolicy) -> Result<Self, LiushuError> {
+        let dir = path.with_extension(JOURNALS_EXTENSION);
+        fs::create_dir_all(&dir).with_path("create journal dir", &dir)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let name = format!("{}-{}.{}", std::process::id(), nanos, JOURNAL_EXTENSION);
+        let (journal, journal_path) = create_journal(&dir, &name, |_| {})?;
+        debug!(journal = %journal_path.display(), "the user dictionary is in use, journaling commits for it");
+        Ok(Self {
+            db: None,
+            path: path.to_path_buf(),
+            policy,
+            backups: None,
+            pending: Mutex::new(Pending {
+                journal,
+                journal_path,
+                seq: 0,
+                counts: BTreeMap::new(),
+                commits: 0,
+                since: None,
+            }),
+        })
+    }
+
+    /// Whether it holds the database, rather than journaling commits for the process that
+    /// does.
+    pub fn holds_database(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// Backed up by `policy` as it is dropped, or never with none.
+    pub fn with_backups(mut self, policy: Option<BackupPolicy>) -> Self {
+        self.backups = policy;
+        self
+    }
+
+    /// Count one more commit of `text` typed with `code`, in the journal and in memory
+    /// until the batch is due.
+    pub fn record(&self, text: &str, code: &str) -> Result<(), LiushuError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut pending = self.lock();
+        let line = serde_json::to_string(&(pending.seq + 1, code, text, now))
+            .map_err(|e| LiushuError::io_at("encode", &pending.journal_path, e))?;
+        writeln!(pending.journal, "{}", line).with_path("append to", &pending.journal_path)?;
+        pending.seq += 1;
+        let count = pending
+            .counts
+            .entry((code.to_string(), text.to_string()))
+            .or_default();
+        *count = (count.0 + 1, now);
+        pending.commits += 1;
+        pending.since.get_or_insert_with(Instant::now);
+        if pending.commits >= self.policy.commits {
+            self.write(&mut pending)?;
+        } else {
+            self.write_if_due(&mut pending)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the commits counted in memory to the database, answering whether there were
+    /// any.
+    pub fn flush(&self) -> Result<bool, LiushuError> {
+        self.write(&mut self.lock())
+    }
+
+    /// Like [`UserDict::flush`] once the first commit counted in memory is as old as the
+    /// interval of the policy, for a host to call when idle.
+    pub fn flush_if_due(&self) -> Result<bool, LiushuError> {
+        self.write_if_due(&mut self.lock())
+    }
+
+    /// Writes what it still counts in memory, then backs it up when due like a drop, which
+    /// only logs why it couldn't.
+    pub fn close(self) -> Result<(), LiushuError> {
+        self.flush().map(drop)
+    }
+
+    /// The database, failing like a write to one opened read-only while another process
+    /// holds it.
+    fn db(&self) -> Result<&ArtifactDb, LiushuError> {
+        self.db
+            .as_ref()
+            .ok_or_else(|| LiushuError::ReadOnly(self.path.clone()))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_if_due(&self, pending: &mut Pending) -> Result<bool, LiushuError> {
+        match pending.since {
+            Some(since) if since.elapsed() >= self.policy.interval => self.write(pending),
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes the batch of `pending` in one transaction, then empties the journal and folds
+    /// those of the other processes. Nothing is lost when it fails, the commits stay in
+    /// memory and in the journal. Those journaled for another process stay in memory.
+    fn write(&self, pending: &mut Pending) -> Result<bool, LiushuError> {
+        let Some(db) = &self.db else {
+            return Ok(false);
+        };
+        if pending.counts.is_empty() {
+            return Ok(false);
+        }
+        let write_txn = db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(USER_DICT)?;
+            for ((code, text), &(commits, last_used)) in &pending.counts {
+                let key = (code.as_str(), text.as_str());
+                let (count, source) = table.get(key)?.map_or((0, None), |v| {
+                    (v.value().0, v.value().2.map(str::to_string))
+                });
+                table.insert(key, (count + commits, last_used, source.as_deref()))?;
+            }
+            write_txn
+                .open_table(META)?
+                .insert(JOURNAL_APPLIED, pending.seq)?;
+        }
+        write_txn.commit()?;
+        debug!(commits = pending.commits, "wrote user dictionary batch");
+        pending.counts.clear();
+        pending.commits = 0;
+        pending.since = None;
+        pending
+            .journal
+            .set_len(0)
+            .with_path("truncate journal", &pending.journal_path)?;
+        let journals = self.path.with_extension(JOURNALS_EXTENSION);
+        if let Err(error) = fold(db, &journals) {
+            warn!(error = %error.report(), dir = %journals.display(), "cannot fold the journals of other processes");
+        }
+        Ok(true)
+    }
+
+    /// Those of the database and those counted in memory, by code then text, only the
+    /// latter while another process holds the database.
+    pub fn entries(&self) -> Result<Vec<UserDictItem>, LiushuError> {
+        let pending = self.lock();
+        let mut entries = BTreeMap::new();
+        if let Some(db) = &self.db {
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(USER_DICT)?;
+            for (key, value) in table.iter()? {
+                let (code, text) = key.value();
+                let (count, last_used, source) = value.value();
+                let value = (count, last_used, source.map(str::to_string));
+                entries.insert((code.to_string(), text.to_string()), value);
+            }
+        }
+        for (key, &(commits, last_used)) in &pending.counts {
+            let entry = entries.entry(key.clone()).or_default();
+            (entry.0, entry.1) = (entry.0 + commits, last_used);
+        }
+        Ok(entries
+            .into_iter()
+            .map(|((code, text), (count, last_used, source))| UserDictItem {
+                text,
+                code,
+                count,
+                last_used,
+                source,
+            })
+            .collect())
+    }
+
+    /// Attaches to each item the times it was committed, those of the database and those
+    /// counted in memory, leaving those never committed without a count.
+    pub fn annotate(&self, items: &mut [SearchResultItem]) -> Result<(), LiushuError> {
+        let pending = self.lock();
+        let read_txn = self.db.as_ref().map(|db| db.begin_read()).transpose()?;
+        let table = read_txn
+            .as_ref()
+            .map(|tx| tx.open_table(USER_DICT))
+            .transpose()?;
+        for item in items {
+            let written = match &table {
+                Some(table) => table.get((item.code.as_str(), item.text.as_str()))?,
+                None => None,
+            };
+            let written = written.map(|value| value.value().0);
+            let counted = pending
+                .counts
+                .get(&(item.code.clone(), item.text.clone()))
+                .map(|&(commits, _)| commits);
+            if written.is_some() || counted.is_some() {
+                item.user_count = Some(written.unwrap_or(0) + counted.unwrap_or(0));
+            }
+        }
+        Ok(())
+    }
+
+    /// When each item was last committed, those of the database and those counted in
+    /// memory, in one transaction however many there are. None for those never committed.
+    pub fn last_used(&self, items: &[SearchResultItem]) -> Result<Vec<Option<u64>>, LiushuError> {
+        let pending = self.lock();
+        let read_txn = self.db.as_ref().map(|db| db.begin_read()).transpose()?;
+        let table = read_txn
+            .as_ref()
+            .map(|tx| tx.open_table(USER_DICT))
+            .transpose()?;
+        let mut last_used = Vec::with_capacity(items.len());
+        for item in items {
+            let key = (item.code.clone(), item.text.clone());
+            let used = match (pending.counts.get(&key), &table) {
+                (Some(&(_, last_used)), _) => Some(last_used),
+                (None, Some(table)) => table
+                    .get((item.code.as_str(), item.text.as_str()))?
+                    .map(|value| value.value().1),
+                (None, None) => None,
+            };
+            last_used.push(used);
+        }
+        Ok(last_used)
+    }
+
+    /// Write every entry as TSV with a header, returns the number of entries written. Fails
+    /// while another process holds the database, which has most of them.
+    pub fn export(&self, writer: impl Write) -> Result<usize, LiushuError> {
+        self.db()?;
+        let entries = self.entries()?;
+        write_entries(&entries, writer)?;
+        Ok(entries.len())
+    }
+
+    /// Read entries written by [`UserDict::export`], malformed rows are skipped with a warning.
+    pub fn import(&self, reader: impl Read, mode: ImportMode) -> Result<ImportReport, LiushuError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .comment(Some(b'#'))
+            .flexible(true)
+            .from_reader(reader);
+        let mut report = ImportReport::default();
+
+        // what is counted in memory is under what is imported, replaced by it too
+        let db = self.db()?;
+        self.flush()?;
+        let write_txn = db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(USER_DICT)?;
+            if mode == ImportMode::Replace {
+                let keys: Vec<(String, String)> = table
+                    .iter()?
+                    .map(|(key, _)| {
+                        let (code, text) = key.value();
+                        (code.to_string(), text.to_string())
+                    })
+                    .collect();
+                for (code, text) in &keys {
+                    table.remove((code.as_str(), text.as_str()))?;
+                }
+            }
+
+            for result in rdr.deserialize::<UserDictItem>() {
+                let item = match result {
+                    Ok(item) if item.text.is_empty() || item.code.is_empty() => {
+                        Err(format!("empty text or code in {:?}", item))
+                    }
+                    Ok(item) => Ok(item),
+                    Err(e) => Err(e.to_string()),
+                };
+                let item = match item {
+                    Ok(item) => item,
+                    Err(warning) => {
+                        warn!("skipping user dictionary row: {}", warning);
+                        report.warnings.push(warning);
+                        report.skipped += 1;
+                        continue;
+                    }
+                };
+
+                let key = (item.code.as_str(), item.text.as_str());
+                let existing = table.get(key)?.map(|v| {
+                    let (count, last_used, source) = v.value();
+                    (count, last_used, source.map(str::to_string))
+                });
+                let (count, last_used, source) = match existing {
+                    Some((count, last_used, source)) => {
+                        report.updated += 1;
+                        let source = source.or(item.source);
+                        (count + item.count, last_used.max(item.last_used), source)
+                    }
+                    None => {
+                        report.added += 1;
+                        (item.count, item.last_used, item.source)
+                    }
+                };
+                table.insert(key, (count, last_used, source.as_deref()))?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(report)
+    }
+}
+
+impl Drop for UserDict {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            warn!(error = %error.report(), "cannot write the user dictionary, its journal is left");
+        }
+        if let Err(error) = self.back_up_if_due(SystemTime::now()) {
+            warn!(error = %error.report(), dir = %self.backup_dir().display(), "cannot back up the user dictionary");
+        }
+    }
+}
+
+fn write_entries(entries: &[UserDictItem], writer: impl Write) -> Result<(), LiushuError> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    for entry in entries {
+        wtr.serialize(entry)
+            .map_err(|e| LiushuError::io("cannot export the user dictionary", e))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Counts the commits of the journal at `path` the database hasn't, answering the sequence
+/// number of the last one. A line that can't be read, such as the last one of a process
+/// killed while writing it, is skipped.
+fn replay(db: &ArtifactDb, path: &Path) -> Result<u64, LiushuError> {
+    let write_txn = db.begin_write()?;
+    let mut seq;
+    {
+        let mut table = write_txn.open_table(USER_DICT)?;
+        let mut meta = write_txn.open_table(META)?;
+        seq = meta.get(JOURNAL_APPLIED)?.map_or(0, |v| v.value());
+        // none once emptied, or before the first commit
+        if let Ok(journal) = File::open(path) {
+            let replayed;
+            (seq, replayed) = apply(&mut table, &journal, path, seq)?;
+            if replayed > 0 {
+                debug!(path = %path.display(), replayed, "replayed user dictionary journal");
+            }
+        }
+        meta.insert(JOURNAL_APPLIED, seq)?;
+    }
+    write_txn.commit()?;
+    Ok(seq)
+}
+
+/// Counts the commits of the journals of `dir` no process holds any more in one
+/// transaction, then removes them. Those it counted of one it failed to remove are
+/// skipped the next time.
+fn fold(db: &ArtifactDb, dir: &Path) -> Result<(), LiushuError> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(LiushuError::io_at("read journal dir", dir, e)),
+    };
+    let mut journals = Vec::new();
+    for entry in read_dir {
+        let path = entry.with_path("read journal dir", dir)?.path();
+        // one created aside by a process that died before renaming it, empty as it is
+        // locked before anything is journaled
+        let stray = path.extension() == Some(OsStr::new("tmp"))
+            && Path::new(path.file_stem().unwrap_or_default()).extension()
+                == Some(OsStr::new(JOURNAL_EXTENSION));
+        if !stray && path.extension() != Some(OsStr::new(JOURNAL_EXTENSION)) {
+            continue;
+        }
+        let journal = File::open(&path).with_path("open journal", &path)?;
+        match journal.try_lock() {
+            Ok(()) if stray => fs::remove_file(&path).with_path("remove journal", &path)?,
+            Ok(()) => journals.push((path, journal)),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(LiushuError::io_at("lock", &path, e)),
+        }
+    }
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(USER_DICT)?;
+        let mut meta = write_txn.open_table(META)?;
+        let mut folded = 0;
+        for (path, journal) in &journals {
+            let key = folded_key(path);
+            let seq = meta.get(key.as_str())?.map_or(0, |v| v.value());
+            let (seq, count) = apply(&mut table, journal, path, seq)?;
+            meta.insert(key.as_str(), seq)?;
+            folded += count;
+        }
+        // those of journals removed since they were folded
+        let mut stale = Vec::new();
+        for entry in meta.range(JOURNAL_FOLDED..)? {
+            let key = entry.0.value().to_string();
+            let Some(name) = key.strip_prefix(JOURNAL_FOLDED) else {
+                break;
+            };
+            if !dir.join(name).exists() {
+                stale.push(key);
+            }
+        }
+        for key in stale {
+            meta.remove(key.as_str())?;
+        }
+        if folded > 0 {
+            debug!(dir = %dir.display(), journals = journals.len(), folded, "folded the journals of other processes");
+        }
+    }
+    write_txn.commit()?;
+    for (path, _) in journals {
+        fs::remove_file(&path).with_path("remove journal", &path)?;
+    }
+    Ok(())
+}
+
+/// Creates the journal `name` aside in `dir`, locks it and renames it in place, calling
+/// `before_rename` in between. A fold may remove it as stray before it is locked, in which
+/// case the rename finds nothing and it is created again.
+fn create_journal(
+    dir: &Path,
+    name: &str,
+    before_rename: impl Fn(&Path),
+) -> Result<(File, PathBuf), LiushuError> {
+    const ATTEMPTS: usize = 3;
+
+    let temp = dir.join(format!("{}.tmp", name));
+    let journal_path = dir.join(name);
+    for attempt in 1.. {
+        let journal = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&temp)
+            .with_path("create journal", &temp)?;
+        journal.lock().with_path("lock", &temp)?;
+        before_rename(&temp);
+        match fs::rename(&temp, &journal_path) {
+            Ok(()) => return Ok((journal, journal_path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && attempt < ATTEMPTS => {
+                debug!(journal = %temp.display(), "the journal was removed before it was renamed, creating it again");
+            }
+            Err(e) => return Err(LiushuError::io_at("rename journal", &journal_path, e)),
+        }
+    }
+    unreachable!()
+}
+
+fn folded_key(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("{}{}", JOURNAL_FOLDED, name)
+}
+
+/// Counts the commits of `journal` after `seq` into `table`, returns the sequence number
+/// of the last and how many there were.
+fn apply(
+    table: &mut Table<(&str, &str), (u64, u64, Option<&str>)>,
+    journal: &File,
+    path: &Path,
+    mut seq: u64,
+) -> Result<(u64, usize), LiushuError> {
+    let mut applied = 0;
+    for line in BufReader::new(journal).lines() {
+        let line = line.with_path("read journal", path)?;
+        let (line_seq, code, text, last_used) =
+            match serde_json::from_str::<(u64, &str, &str, u64)>(&line) {
+                Ok(commit) => commit,
+                Err(error) => {
+                    warn!(path = %path.display(), %error, "skipping journal line");
+                    continue;
+                }
+            };
+        if line_seq <= seq {
+            continue;
+        }
+        let (count, source) = table.get((code, text))?.map_or((0, None), |v| {
+            (v.value().0, v.value().2.map(str::to_string))
+        });
+        table.insert((code, text), (count + 1, last_used, source.as_deref()))?;
+        seq = line_seq;
+        applied += 1;
+    }
+    Ok((seq, applied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(text: &str, code: &str, count: u64, last_used: u64) -> UserDictItem {
+        UserDictItem {
+            text: text.to_string(),
+            code: code.to_string(),
+            count,
+            last_used,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        dict.record("你好", "nihao").unwrap();
+        dict.record("你好", "nihao").unwrap();
+        dict.record("世界", "shijie").unwrap();
+
+        let mut tsv = vec![];
+        assert_eq!(dict.export(&mut tsv).unwrap(), 2);
+        assert!(String::from_utf8_lossy(&tsv).starts_with("text\tcode\tcount\tlast_used\tsource\n"));
+
+        let other = UserDict::open(dir.path().join("other.redb")).unwrap();
+        let report = other.import(tsv.as_slice(), ImportMode::Merge).unwrap();
+        assert_eq!((report.added, report.updated, report.skipped), (2, 0, 0));
+        assert_eq!(other.entries().unwrap(), dict.entries().unwrap());
+    }
+
+    #[test]
+    fn test_annotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        let candidate = |text: &str| SearchResultItem {
+            text: text.to_string(),
+            code: "ni".to_string(),
+            weight: 1,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        };
+        let mut items = vec![candidate("你"), candidate("尼")];
+        assert!(UserCounts::read(&path).unwrap().0.is_empty());
+
+        let dict = UserDict::open(&path).unwrap();
+        dict.record("你", "ni").unwrap();
+        dict.record("你", "ni").unwrap();
+        dict.annotate(&mut items).unwrap();
+        assert_eq!((items[0].user_count, items[1].user_count), (Some(2), None));
+        let last_used = dict.last_used(&items).unwrap();
+        assert!(last_used[0].is_some() && last_used[1].is_none());
+        // written, then counted in memory
+        dict.flush().unwrap();
+        assert_eq!(dict.last_used(&items).unwrap(), last_used);
+        dict.record("你", "ni").unwrap();
+        dict.annotate(&mut items).unwrap();
+        assert_eq!(items[0].user_count, Some(3));
+        drop(dict);
+
+        let mut items = vec![candidate("你"), candidate("尼")];
+        UserCounts::read(&path).unwrap().annotate(&mut items);
+        assert_eq!(items[0].user_count, Some(3));
+        // left out rather than zero
+        let json = serde_json::to_value(&items).unwrap();
+        assert_eq!(json[0]["user_count"], 3);
+        assert!(json[1].as_object().unwrap().get("user_count").is_none());
+    }
+
+    #[test]
+    fn test_import_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        let tsv = "text\tcode\tcount\tlast_used\n你好\tnihao\t2\t10\n世界\tshijie\t1\t20\n";
+        dict.import(tsv.as_bytes(), ImportMode::Merge).unwrap();
+
+        let update = "text\tcode\tcount\tlast_used\n你好\tnihao\t3\t5\n";
+        let report = dict.import(update.as_bytes(), ImportMode::Merge).unwrap();
+        assert_eq!((report.added, report.updated), (0, 1));
+        assert_eq!(
+            dict.entries().unwrap(),
+            vec![item("你好", "nihao", 5, 10), item("世界", "shijie", 1, 20)]
+        );
+
+        let report = dict.import(update.as_bytes(), ImportMode::Replace).unwrap();
+        assert_eq!((report.added, report.updated), (1, 0));
+        assert_eq!(dict.entries().unwrap(), vec![item("你好", "nihao", 3, 5)]);
+    }
+
+    #[test]
+    fn test_import_malformed_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        let tsv = "text\tcode\tcount\tlast_used\n\
+                   你好\tnihao\t2\t10\n\
+                   坏\thuai\tmany\t10\n\
+                   缺\tque\n\
+                   \tkong\t1\t1\n\
+                   世界\tshijie\t1\t20\n";
+        let report = dict.import(tsv.as_bytes(), ImportMode::Merge).unwrap();
+
+        assert_eq!((report.added, report.updated, report.skipped), (2, 0, 3));
+        assert_eq!(report.warnings.len(), 3);
+        assert_eq!(dict.entries().unwrap().len(), 2);
+    }
+
+    fn counts(dict: &UserDict) -> Vec<(String, u64)> {
+        dict.entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.text, entry.count))
+            .collect()
+    }
+
+    /// The files of `dir` as they would be left by a process killed right now.
+    fn crash(dir: &Path, into: &Path) -> PathBuf {
+        fs::create_dir_all(into).unwrap();
+        for name in [USER_DICT_FILE, "userdict.journal"] {
+            fs::copy(dir.join(name), into.join(name)).unwrap();
+        }
+        into.join(USER_DICT_FILE)
+    }
+
+    #[test]
+    fn test_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        let policy = FlushPolicy {
+            commits: 3,
+            interval: Duration::from_secs(3600),
+        };
+        let dict = UserDict::open_with_policy(&path, policy).unwrap();
+        dict.record("你好", "nihao").unwrap();
+        dict.record("你好", "nihao").unwrap();
+        // counted before they are written
+        assert_eq!(counts(&dict), [("你好".to_string(), 2)]);
+        assert!(!dict.flush_if_due().unwrap());
+        let killed = crash(dir.path(), &dir.path().join("killed"));
+
+        dict.record("世界", "shijie").unwrap();
+        assert_eq!(
+            fs::metadata(dir.path().join("userdict.journal"))
+                .unwrap()
+                .len(),
+            0
+        );
+        dict.record("世界", "shijie").unwrap();
+        let killed_later = crash(dir.path(), &dir.path().join("killed later"));
+        dict.close().unwrap();
+
+        let expected = [("你好".to_string(), 2), ("世界".to_string(), 2)];
+        assert_eq!(counts(&UserDict::open(&path).unwrap()), expected);
+        // the journals of the killed ones are replayed, what was written isn't counted again
+        assert_eq!(
+            counts(&UserDict::open(&killed).unwrap()),
+            [("你好".to_string(), 2)]
+        );
+        let reopened = UserDict::open(&killed_later).unwrap();
+        assert_eq!(counts(&reopened), expected);
+        drop(reopened);
+        assert_eq!(counts(&UserDict::open(&killed_later).unwrap()), expected);
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        let dict = UserDict::open(&path).unwrap();
+        dict.record("你", "ni").unwrap();
+        dict.flush().unwrap();
+        drop(dict);
+
+        // killed after writing a batch before emptying the journal, then while appending
+        fs::write(
+            dir.path().join("userdict.journal"),
+            "[1,\"ni\",\"你\",10]\n[2,\"ni\",\"你\",20]\n[3,\"hao\",\"好\",30]\n[4,\"hao\",\"好",
+        )
+        .unwrap();
+        let dict = UserDict::open(&path).unwrap();
+        assert_eq!(
+            dict.entries().unwrap(),
+            [item("好", "hao", 1, 30), item("你", "ni", 2, 20)]
+        );
+        dict.record("好", "hao").unwrap();
+        drop(dict);
+        assert_eq!(
+            counts(&UserDict::open(&path).unwrap())[0],
+            ("好".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn test_two_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        // the daemon holds the database, the REPL journals its commits for it
+        let daemon = UserDict::open(&path).unwrap();
+        let repl = UserDict::open(&path).unwrap();
+        assert!(daemon.holds_database());
+        assert!(!repl.holds_database());
+        daemon.record("你", "ni").unwrap();
+        repl.record("你", "ni").unwrap();
+        repl.record("好", "hao").unwrap();
+        assert_eq!(
+            counts(&repl),
+            [("好".to_string(), 1), ("你".to_string(), 1)]
+        );
+        assert!(matches!(
+            repl.export(Vec::new()),
+            Err(LiushuError::ReadOnly(_))
+        ));
+
+        // not while the REPL still holds its journal
+        daemon.flush().unwrap();
+        assert_eq!(counts(&daemon), [("你".to_string(), 1)]);
+        drop(repl);
+        daemon.record("好", "hao").unwrap();
+        daemon.flush().unwrap();
+        assert_eq!(
+            counts(&daemon),
+            [("好".to_string(), 2), ("你".to_string(), 2)]
+        );
+        let journals = path.with_extension(JOURNALS_EXTENSION);
+        assert_eq!(fs::read_dir(&journals).unwrap().count(), 0);
+
+        // those left once the daemon is gone are folded as it opens again, once
+        let repl = UserDict::open(&path).unwrap();
+        repl.record("你", "ni").unwrap();
+        drop(repl);
+        drop(daemon);
+        let daemon = UserDict::open(&path).unwrap();
+        assert_eq!(
+            counts(&daemon),
+            [("好".to_string(), 2), ("你".to_string(), 3)]
+        );
+        drop(daemon);
+        let daemon = UserDict::open(&path).unwrap();
+        assert_eq!(
+            counts(&daemon),
+            [("好".to_string(), 2), ("你".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_stray_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        let journals = path.with_extension(JOURNALS_EXTENSION);
+        fs::create_dir_all(&journals).unwrap();
+        // left by a process killed before renaming its journal, unlike one still held
+        let stray = journals.join("1-1.journal.tmp");
+        fs::write(&stray, "").unwrap();
+        let held = journals.join("2-2.journal.tmp");
+        let held_file = File::create(&held).unwrap();
+        held_file.lock().unwrap();
+        let dict = UserDict::open(&path).unwrap();
+        assert!(dict.holds_database());
+        assert!(!stray.exists());
+        assert!(held.exists());
+    }
+
+    #[test]
+    fn test_journal_removed_before_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let removals = std::cell::Cell::new(0);
+        // as a fold would, between its creation and its lock
+        let (mut journal, journal_path) = create_journal(dir.path(), "1-1.journal", |temp| {
+            if removals.get() == 0 {
+                fs::remove_file(temp).unwrap();
+            }
+            removals.set(removals.get() + 1);
+        })
+        .unwrap();
+        assert_eq!(removals.get(), 2);
+        assert_eq!(journal_path, dir.path().join("1-1.journal"));
+        assert!(!dir.path().join("1-1.journal.tmp").exists());
+        writeln!(journal, "{}", serde_json::json!([1, "ni", "你", 1])).unwrap();
+        assert!(fs::read_to_string(&journal_path)
+            .unwrap()
+            .starts_with("[1,"));
+
+        let err = create_journal(dir.path(), "2-2.journal", |temp| {
+            fs::remove_file(temp).unwrap()
+        })
+        .unwrap_err();
+        assert_eq!(err.code(), "E_IO");
+    }
+}
@@ -0,0 +1,301 @@
+//! Rolling backups of a user dictionary, taken as it is dropped and at most once a day: its
+//! entries as gzipped TSV, `userdict-YYYYMMDD.tsv.gz` in [`BACKUP_DIR`] next to it, and the
+//! session state of the same dir as `state-YYYYMMDD.json`, dated in UTC. Only the newest
+//! [`BackupPolicy::keep`] are kept, and [`UserDict::restore`] reads one back.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use tracing::debug;
+
+use super::{ImportMode, ImportReport, UserDict};
+use crate::error::{IoResultExt, LiushuError};
+use crate::state::STATE_FILE;
+
+/// The dir of the backups, next to the user dictionary.
+pub const BACKUP_DIR: &str = "backups";
+
+const PREFIX: &str = "userdict-";
+const EXTENSION: &str = ".tsv.gz";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupPolicy {
+    /// Backups kept, at least one, those of the oldest days removed past them.
+    pub keep: usize,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self { keep: 7 }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    #[serde(flatten)]
+    pub entries: ImportReport,
+    /// Whether the session state backed up along with the entries was restored too.
+    pub state: bool,
+}
+
+impl UserDict {
+    /// Backs up the dictionary unless there is a backup dated like `now` already, or it
+    /// has no entry, which would push out a backup worth restoring. Answers the backup
+    /// written.
+    pub fn back_up_if_due(&self, now: SystemTime) -> Result<Option<PathBuf>, LiushuError> {
+        // one journaling its commits for another process lacks most of the entries
+        let (Some(policy), Some(_)) = (self.backups, &self.db) else {
+            return Ok(None);
+        };
+        let dir = self.backup_dir();
+        let date = date(now);
+        let path = dir.join(format!("{}{}{}", PREFIX, date, EXTENSION));
+        if path.exists() {
+            return Ok(None);
+        }
+        let entries = self.entries()?;
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&dir).with_path("create backup dir", &dir)?;
+        // written aside and renamed, so that a crash can't leave half of a backup to restore
+        let temp = dir.join(format!("{}{}.tmp", PREFIX, date));
+        let file = File::create(&temp).with_path("create backup", &temp)?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        super::write_entries(&entries, &mut encoder)?;
+        encoder
+            .finish()
+            .and_then(|writer| writer.into_inner().map_err(|e| e.into_error()))
+            .with_path("write backup", &temp)?;
+        fs::rename(&temp, &path).with_path("rename backup", &path)?;
+
+        if let Some(data_dir) = self.path.parent() {
+            let state = data_dir.join(STATE_FILE);
+            if state.exists() {
+                let backup = state_backup(&dir, &date);
+                fs::copy(&state, &backup).with_path("back up state", &backup)?;
+            }
+        }
+        prune(&dir, policy.keep.max(1))?;
+        debug!(path = %path.display(), entries = entries.len(), "backed up user dictionary");
+        Ok(Some(path))
+    }
+
+    /// Replaces the entries with those of `backup`, a backup or any TSV written by
+    /// [`UserDict::export`], gzipped when named `.gz`, and the session state with the one
+    /// backed up along with them if there is one.
+    pub fn restore(&self, backup: &Path) -> Result<RestoreReport, LiushuError> {
+        let reader = crate::dict::open_input(backup, "backup")?;
+        let entries = self.import(reader, ImportMode::Replace)?;
+        let backed_up = backup
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(PREFIX)?.strip_suffix(EXTENSION))
+            .zip(backup.parent())
+            .map(|(date, dir)| state_backup(dir, date));
+        let state = match (backed_up, self.path.parent()) {
+            (Some(backed_up), Some(data_dir)) if backed_up.exists() => {
+                let state = data_dir.join(STATE_FILE);
+                fs::copy(&backed_up, &state).with_path("restore state", &state)?;
+                true
+            }
+            _ => false,
+        };
+        Ok(RestoreReport { entries, state })
+    }
+
+    pub(super) fn backup_dir(&self) -> PathBuf {
+        self.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(BACKUP_DIR)
+    }
+}
+
+fn state_backup(dir: &Path, date: &str) -> PathBuf {
+    dir.join(format!("state-{}.json", date))
+}
+
+/// Removes the backups of `dir` past the newest `keep`, with their state.
+fn prune(dir: &Path, keep: usize) -> Result<(), LiushuError> {
+    let mut dates: Vec<String> = fs::read_dir(dir)
+        .with_path("read backup dir", dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            Some(
+                name.strip_prefix(PREFIX)?
+                    .strip_suffix(EXTENSION)?
+                    .to_string(),
+            )
+        })
+        .collect();
+    // the dates sort like the days
+    dates.sort_unstable();
+    for date in &dates[..dates.len().saturating_sub(keep)] {
+        let path = dir.join(format!("{}{}{}", PREFIX, date, EXTENSION));
+        fs::remove_file(&path).with_path("remove backup", &path)?;
+        let state = state_backup(dir, date);
+        if state.exists() {
+            fs::remove_file(&state).with_path("remove backup", &state)?;
+        }
+        debug!(path = %path.display(), "removed old backup");
+    }
+    Ok(())
+}
+
+/// `YYYYMMDD` of the UTC day of `time`.
+fn date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // the civil date of a count of days since the epoch, by Howard Hinnant
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::userdict::USER_DICT_FILE;
+
+    const DAY: u64 = 86_400;
+
+    /// 2024-02-28, a day before a leap day.
+    fn day(n: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_709_078_400 + n * DAY)
+    }
+
+    fn backups(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.join(BACKUP_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(UNIX_EPOCH), "19700101");
+        assert_eq!(date(day(0)), "20240228");
+        assert_eq!(date(day(1)), "20240229");
+        assert_eq!(date(day(2) - Duration::from_secs(1)), "20240229");
+        assert_eq!(date(day(2)), "20240301");
+        assert_eq!(date(day(307)), "20241231");
+        assert_eq!(date(day(308)), "20250101");
+    }
+
+    #[test]
+    fn test_back_up_daily() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE))
+            .unwrap()
+            .with_backups(Some(BackupPolicy { keep: 2 }));
+        // nothing worth backing up
+        assert_eq!(dict.back_up_if_due(day(0)).unwrap(), None);
+        assert!(!dir.path().join(BACKUP_DIR).exists());
+
+        dict.record("你好", "nihao").unwrap();
+        fs::write(
+            dir.path().join(STATE_FILE),
+            r#"{"active_formula":"sunman"}"#,
+        )
+        .unwrap();
+        let backup = dict.back_up_if_due(day(0)).unwrap().unwrap();
+        assert_eq!(backup, dir.path().join("backups/userdict-20240228.tsv.gz"));
+        // once a day
+        dict.record("世界", "shijie").unwrap();
+        assert_eq!(
+            dict.back_up_if_due(day(1) - Duration::from_secs(1))
+                .unwrap(),
+            None
+        );
+
+        for n in 1..4 {
+            assert!(dict.back_up_if_due(day(n)).unwrap().is_some());
+        }
+        assert_eq!(
+            backups(dir.path()),
+            [
+                "state-20240301.json",
+                "state-20240302.json",
+                "userdict-20240301.tsv.gz",
+                "userdict-20240302.tsv.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        dict.record("你好", "nihao").unwrap();
+        fs::write(
+            dir.path().join(STATE_FILE),
+            r#"{"active_formula":"sunman"}"#,
+        )
+        .unwrap();
+        let backup = dict.back_up_if_due(day(0)).unwrap().unwrap();
+        let entries = dict.entries().unwrap();
+
+        dict.record("世界", "shijie").unwrap();
+        fs::write(dir.path().join(STATE_FILE), "{}").unwrap();
+        let report = dict.restore(&backup).unwrap();
+        assert_eq!((report.entries.added, report.state), (1, true));
+        assert_eq!(dict.entries().unwrap(), entries);
+        assert_eq!(
+            fs::read_to_string(dir.path().join(STATE_FILE)).unwrap(),
+            r#"{"active_formula":"sunman"}"#
+        );
+
+        // an export, without a state
+        let tsv = dir.path().join("export.tsv");
+        dict.export(File::create(&tsv).unwrap()).unwrap();
+        assert!(!dict.restore(&tsv).unwrap().state);
+        assert!(matches!(
+            dict.restore(&dir.path().join("missing.tsv.gz")),
+            Err(LiushuError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn test_back_up_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        dict.record("你好", "nihao").unwrap();
+        dict.close().unwrap();
+        let names = backups(dir.path());
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with(PREFIX));
+
+        // a failed backup is only logged
+        let dict = UserDict::open(dir.path().join(USER_DICT_FILE)).unwrap();
+        fs::remove_dir_all(dir.path().join(BACKUP_DIR)).unwrap();
+        fs::write(dir.path().join(BACKUP_DIR), "not a dir").unwrap();
+        dict.record("世界", "shijie").unwrap();
+        drop(dict);
+        assert_eq!(
+            UserDict::open(dir.path().join(USER_DICT_FILE))
+                .unwrap()
+                .with_backups(None)
+                .entries()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! The schema of a user dictionary, its version under [`SCHEMA_VERSION`] in the meta table,
+//! each version migrated from the one before it as the dictionary is opened.
+//!
+//! 1. `(code, text)` valued by the count of commits.
+//! 2. Valued by `(count, last_used)`. Dictionaries without a version, written before it
+//!    was kept, are of this one.
+//! 3. Valued by `(count, last_used, source)`, see [`UserDictItem::source`].
+//!
+//! [`UserDictItem::source`]: super::UserDictItem::source
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redb::{ReadTransaction, ReadableTable, TableDefinition, WriteTransaction};
+use tracing::debug;
+
+use super::{META, USER_DICT};
+use crate::artifact::ArtifactDb;
+use crate::error::LiushuError;
+
+/// The version of the schema this liushu writes.
+pub const CURRENT_VERSION: u64 = 3;
+
+const SCHEMA_VERSION: &str = "schema_version";
+
+const USER_DICT_V1: TableDefinition<(&str, &str), u64> = TableDefinition::new("user_dict");
+const USER_DICT_V2: TableDefinition<(&str, &str), (u64, u64)> = TableDefinition::new("user_dict");
+
+/// Migrates the tables of a transaction from one version to the next, given the unix time
+/// the migration runs at.
+type Migration = fn(&WriteTransaction<'_>, u64) -> Result<(), LiushuError>;
+
+/// The migration from each version to the next, in order.
+const MIGRATIONS: [(u64, Migration); 2] = [(1, v1_to_v2), (2, v2_to_v3)];
+
+/// Migrates the dictionary of `db` to [`CURRENT_VERSION`] in one write transaction, so that
+/// it is left as it was when one of them fails, answering the version it was of. A new
+/// dictionary is of the current version, one of a newer liushu an error.
+pub fn run(db: &ArtifactDb) -> Result<u64, LiushuError> {
+    let write_txn = db.begin_write()?;
+    let tables: Vec<String> = write_txn.list_tables()?.collect();
+    let version = write_txn
+        .open_table(META)?
+        .get(SCHEMA_VERSION)?
+        .map(|v| v.value());
+    let stamped = version.is_some();
+    let version = match version {
+        Some(version) => version,
+        None if tables.iter().any(|name| name == USER_DICT.name()) => 2,
+        None => CURRENT_VERSION,
+    };
+    if version == CURRENT_VERSION && stamped {
+        write_txn.abort()?;
+        return Ok(version);
+    }
+    if version > CURRENT_VERSION {
+        return Err(LiushuError::SchemaTooNew {
+            path: db.path().to_path_buf(),
+            version,
+            supported: CURRENT_VERSION,
+        });
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    for (from, migration) in MIGRATIONS {
+        if from >= version {
+            migration(&write_txn, now)?;
+            debug!(path = %db.path().display(), from, "migrated user dictionary");
+        }
+    }
+    write_txn
+        .open_table(META)?
+        .insert(SCHEMA_VERSION, CURRENT_VERSION)?;
+    write_txn.commit()?;
+    Ok(version)
+}
+
+/// The count of commits of each `(code, text)` of a dictionary opened read-only, which
+/// can't be migrated, of any version this liushu knows.
+pub(super) fn read_counts(
+    read_txn: &ReadTransaction<'_>,
+    path: &Path,
+) -> Result<HashMap<(String, String), u64>, LiushuError> {
+    let version = match read_txn.open_table(META) {
+        Ok(meta) => meta.get(SCHEMA_VERSION)?.map(|v| v.value()),
+        Err(redb::Error::TableDoesNotExist(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut counts = HashMap::new();
+    let mut insert = |code: &str, text: &str, count| {
+        counts.insert((code.to_string(), text.to_string()), count);
+    };
+    match version.unwrap_or(2) {
+        1 => {
+            for (key, value) in read_txn.open_table(USER_DICT_V1)?.iter()? {
+                let (code, text) = key.value();
+                insert(code, text, value.value());
+            }
+        }
+        2 => {
+            for (key, value) in read_txn.open_table(USER_DICT_V2)?.iter()? {
+                let (code, text) = key.value();
+                insert(code, text, value.value().0);
+            }
+        }
+        CURRENT_VERSION => {
+            for (key, value) in read_txn.open_table(USER_DICT)?.iter()? {
+                let (code, text) = key.value();
+                insert(code, text, value.value().0);
+            }
+        }
+        version => {
+            return Err(LiushuError::SchemaTooNew {
+                path: path.to_path_buf(),
+                version,
+                supported: CURRENT_VERSION,
+            })
+        }
+    }
+    Ok(counts)
+}
+
+/// Last used as the migration runs.
+fn v1_to_v2(write_txn: &WriteTransaction<'_>, now: u64) -> Result<(), LiushuError> {
+    let rows: Vec<(String, String, u64)> = write_txn
+        .open_table(USER_DICT_V1)?
+        .iter()?
+        .map(|(key, value)| {
+            let (code, text) = key.value();
+            (code.to_string(), text.to_string(), value.value())
+        })
+        .collect();
+    write_txn.delete_table(USER_DICT_V1)?;
+    let mut table = write_txn.open_table(USER_DICT_V2)?;
+    for (code, text, count) in &rows {
+        table.insert((code.as_str(), text.as_str()), (*count, now))?;
+    }
+    Ok(())
+}
+
+/// Without a source, the entries being those of commits and imports that didn't keep one.
+fn v2_to_v3(write_txn: &WriteTransaction<'_>, _now: u64) -> Result<(), LiushuError> {
+    let rows: Vec<(String, String, (u64, u64))> = write_txn
+        .open_table(USER_DICT_V2)?
+        .iter()?
+        .map(|(key, value)| {
+            let (code, text) = key.value();
+            (code.to_string(), text.to_string(), value.value())
+        })
+        .collect();
+    write_txn.delete_table(USER_DICT_V2)?;
+    let mut table = write_txn.open_table(USER_DICT)?;
+    for (code, text, (count, last_used)) in &rows {
+        table.insert((code.as_str(), text.as_str()), (*count, *last_used, None))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::Database;
+
+    use super::*;
+    use crate::userdict::{UserCounts, UserDict, UserDictItem, USER_DICT_FILE};
+
+    /// A dictionary as written by a liushu of `version`, or before versions were kept.
+    fn legacy(path: &Path, version: Option<u64>, rows: &[(&str, &str, u64)]) {
+        let db = Database::create(path).unwrap();
+        let write_txn = db.begin_write().unwrap();
+        {
+            let mut meta = write_txn.open_table(META).unwrap();
+            if let Some(version) = version {
+                meta.insert(SCHEMA_VERSION, version).unwrap();
+            }
+            if version == Some(1) {
+                let mut table = write_txn.open_table(USER_DICT_V1).unwrap();
+                for &(code, text, count) in rows {
+                    table.insert((code, text), count).unwrap();
+                }
+            } else {
+                let mut table = write_txn.open_table(USER_DICT_V2).unwrap();
+                for &(code, text, count) in rows {
+                    table.insert((code, text), (count, 10)).unwrap();
+                }
+            }
+        }
+        write_txn.commit().unwrap();
+    }
+
+    fn version(path: &Path) -> Option<u64> {
+        let db = Database::open(path).unwrap();
+        let read_txn = db.begin_read().unwrap();
+        let meta = read_txn.open_table(META).unwrap();
+        let version = meta.get(SCHEMA_VERSION).unwrap().map(|v| v.value());
+        version
+    }
+
+    #[test]
+    fn test_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        legacy(&path, Some(1), &[("nihao", "你好", 3), ("ni", "你", 1)]);
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let dict = UserDict::open(&path).unwrap();
+        let entries = dict.entries().unwrap();
+        let texts: Vec<_> = entries.iter().map(|e| (e.text.as_str(), e.count)).collect();
+        assert_eq!(texts, [("你", 1), ("你好", 3)]);
+        assert!(entries
+            .iter()
+            .all(|e| e.last_used >= start && e.source.is_none()));
+        dict.record("你", "ni").unwrap();
+        drop(dict);
+        assert_eq!(version(&path), Some(CURRENT_VERSION));
+        let counts = UserCounts::read(&path).unwrap();
+        assert_eq!(counts.0[&("ni".to_string(), "你".to_string())], 2);
+    }
+
+    #[test]
+    fn test_unversioned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        legacy(&path, None, &[("ni", "你", 2)]);
+        // read as it is, without migrating it
+        let counts = UserCounts::read(&path).unwrap();
+        assert_eq!(counts.0[&("ni".to_string(), "你".to_string())], 2);
+        assert_eq!(version(&path), None);
+
+        let dict = UserDict::open(&path).unwrap();
+        assert_eq!(
+            dict.entries().unwrap(),
+            [UserDictItem {
+                text: "你".to_string(),
+                code: "ni".to_string(),
+                count: 2,
+                last_used: 10,
+                source: None,
+            }]
+        );
+        drop(dict);
+        assert_eq!(version(&path), Some(CURRENT_VERSION));
+        // a new one is of the current version, migrated again by none
+        let fresh = dir.path().join("fresh.redb");
+        drop(UserDict::open(&fresh).unwrap());
+        assert_eq!(version(&fresh), Some(CURRENT_VERSION));
+        assert_eq!(UserDict::open(&path).unwrap().entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_too_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_DICT_FILE);
+        legacy(&path, Some(CURRENT_VERSION + 1), &[("ni", "你", 2)]);
+        let error = UserDict::open(&path).err().unwrap();
+        assert!(matches!(
+            error,
+            LiushuError::SchemaTooNew {
+                version: 4,
+                supported: 3,
+                ..
+            }
+        ));
+        assert_eq!(error.exit_code(), 5);
+        assert!(matches!(
+            UserCounts::read(&path),
+            Err(LiushuError::SchemaTooNew { .. })
+        ));
+        assert_eq!(version(&path), Some(CURRENT_VERSION + 1));
+    }
+}
@@ -0,0 +1,55 @@
+//! Allocations of the searches of `EngineWithRedb`, counted by dhat. It replaces the global
+//! allocator, so it is a test binary of its own, with a single test as dhat profiles one
+//! thing at a time.
+
+use std::fs;
+
+use liushu_core::dict::{self, BuildOptions};
+use liushu_core::engine::{EngineWithRedb, InputMethodEngine};
+use liushu_core::progress::NoProgress;
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+/// What a search of 6 candidates under 3 codes into a buffer big enough may allocate: a
+/// copy of each text, a code per key, and what redb allocates to read them, 19 blocks
+/// with redb 0.13.
+const BUDGET: u64 = 6 + 3 + 19;
+
+fn blocks(f: impl FnOnce()) -> u64 {
+    let before = dhat::HeapStats::get().total_blocks;
+    f();
+    dhat::HeapStats::get().total_blocks - before
+}
+
+#[test]
+fn test_search_allocations() {
+    let dir = tempfile::tempdir().unwrap();
+    let words = dir.path().join("words.tsv");
+    let mut tsv = String::from("text\tcode\tweight\n");
+    for (i, text) in ["一", "二", "三", "四", "五", "六"].iter().enumerate() {
+        tsv.push_str(&format!("{}\tab{}\t{}\n", text, i % 3, i));
+    }
+    fs::write(&words, tsv).unwrap();
+    let options = BuildOptions::default();
+    dict::build(&[words], dir.path(), "fixture", options, &NoProgress).unwrap();
+    let engine = EngineWithRedb::with_formula(dir.path(), "fixture").unwrap();
+    // what redb reads once and caches
+    engine.search("ab").unwrap();
+
+    let _profiler = dhat::Profiler::builder().testing().build();
+    let mut items = Vec::with_capacity(8);
+    let allocated = blocks(|| engine.search_into("ab", &mut items).unwrap());
+    assert_eq!(items.len(), 6);
+    dhat::assert!(allocated <= BUDGET, "{} blocks allocated", allocated);
+
+    // appended, the buffer grows but nothing else changes
+    let allocated = blocks(|| engine.search_into("ab", &mut items).unwrap());
+    assert_eq!(items.len(), 12);
+    dhat::assert!(allocated <= BUDGET + 1, "{} blocks allocated", allocated);
+
+    // no read transaction without a code to read
+    items.clear();
+    let allocated = blocks(|| engine.search_into("zz", &mut items).unwrap());
+    dhat::assert_eq!(allocated, 0);
+}
@@ -0,0 +1,243 @@
+//! The same dictionaries deployed for every backend and searched by each of them, which
+//! must all find the same candidates in the same order: those of every code starting with
+//! what's typed, codes in bytewise order, the texts of a code in the order of the rows,
+//! each text with the weight and comment of its last row.
+//!
+//! What engines learn to do comes with queries here, in `battery`, and a new backend with
+//! an entry of `BACKENDS`, once `deploy` writes its artifacts.
+
+use std::fs;
+use std::path::Path;
+
+use liushu_core::config::Config;
+use liushu_core::engine::{
+    ArtifactStore, EngineWithRedb, InputMethodEngine, MemoryEngine, SearchResultItem,
+    ShapeCodeEngine,
+};
+use liushu_core::error::LiushuError;
+use tempfile::TempDir;
+
+/// A formula `fixture` deployed by everything that deploys formulas.
+struct Deployed {
+    _dir: TempDir,
+    store: ArtifactStore,
+}
+
+type Open = fn(&Deployed) -> Result<Box<dyn InputMethodEngine>, LiushuError>;
+
+/// The first is the one the others are compared to.
+const BACKENDS: &[(&str, Open)] = &[
+    ("sqlite", |deployed| {
+        let engine = ShapeCodeEngine::with_formula(deployed.store.target_dir(), "fixture")?;
+        Ok(Box::new(engine))
+    }),
+    ("redb", |deployed| {
+        let artifacts = deployed.store.get("fixture")?;
+        Ok(Box::new(EngineWithRedb::from_artifacts(artifacts)))
+    }),
+    ("memory", |deployed| {
+        let redb = EngineWithRedb::from_artifacts(deployed.store.get("fixture")?);
+        Ok(Box::new(MemoryEngine::from_redb(&redb)?))
+    }),
+];
+
+/// Deploys the dictionaries, TSV with their header, as the formula `fixture`.
+fn deploy(dictionaries: &[&str]) -> Deployed {
+    let dir = tempfile::tempdir().unwrap();
+    let formula_dir = dir.path().join("fixture");
+    fs::create_dir_all(&formula_dir).unwrap();
+    let mut names = Vec::new();
+    for (i, tsv) in dictionaries.iter().enumerate() {
+        let name = format!("{}.dict.tsv", i);
+        fs::write(formula_dir.join(&name), tsv).unwrap();
+        names.push(format!("{:?}", name));
+    }
+    fs::write(
+        dir.path().join("main.dhall"),
+        format!(
+            r#"{{ formulas = [ {{ id = "fixture", name = None Text, dictionaries = [ {} ] }} ] }}"#,
+            names.join(", ")
+        ),
+    )
+    .unwrap();
+    let config = Config::load_from_path(dir.path().join("main.dhall")).unwrap();
+    let formula = config.formula("fixture").unwrap();
+    formula.compile(dir.path(), dir.path()).unwrap();
+    formula.compile2(dir.path(), dir.path()).unwrap();
+
+    let store = ArtifactStore::new(dir.path());
+    Deployed { _dir: dir, store }
+}
+
+/// What is searched: every prefix of the codes, exact codes among them, and codes of
+/// nothing, with `_` and `%` that a LIKE would take for wildcards.
+fn battery(deployed: &Deployed) -> Vec<String> {
+    let redb = EngineWithRedb::from_artifacts(deployed.store.get("fixture").unwrap());
+    let mut queries: Vec<String> = redb
+        .codes()
+        .flat_map(|code| {
+            let ends: Vec<usize> = code
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([code.len()])
+                .collect();
+            ends.into_iter()
+                .map(|end| code[..end].to_string())
+                .chain([format!("{}z", code), code.to_uppercase()])
+                .collect::<Vec<_>>()
+        })
+        .chain(
+            [
+                "zzzzz", "_", "%", "a_", "a%", "_%", "ni hao", "ǐ", "你", "ｎｉ",
+            ]
+            .map(String::from),
+        )
+        .collect();
+    queries.sort();
+    queries.dedup();
+    queries
+}
+
+/// Asserts that every backend answers `queries` of `deployed` as the first one does. The
+/// `limit` of a server search keeps the first candidates, the same ones then.
+fn assert_parity(deployed: &Deployed, queries: &[String]) {
+    let engines: Vec<(&str, Box<dyn InputMethodEngine>)> = BACKENDS
+        .iter()
+        .map(|(name, open)| (*name, open(deployed).unwrap()))
+        .collect();
+    let ((reference_name, reference), others) = engines.split_first().unwrap();
+    let mut texts = Vec::new();
+    for query in queries {
+        let expected = reference.search(query).unwrap();
+        texts.extend(expected.iter().map(|item| item.text.clone()));
+        for (name, engine) in others {
+            let found = engine.search(query).unwrap();
+            assert_eq!(
+                found, expected,
+                "{} and {} differ searching {:?}",
+                name, reference_name, query
+            );
+
+            let mut items = vec![SearchResultItem {
+                text: "已有".to_string(),
+                code: "yy".to_string(),
+                weight: 0,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            }];
+            engine.search_into(query, &mut items).unwrap();
+            assert_eq!(items[1..], expected[..], "{} appending {:?}", name, query);
+
+            let longest = format!("{}qqq", query);
+            assert_eq!(
+                engine.search_longest(&longest, "").unwrap(),
+                reference.search_longest(&longest, "").unwrap(),
+                "{} and {} differ searching the longest of {:?}",
+                name,
+                reference_name,
+                longest
+            );
+        }
+    }
+
+    texts.sort();
+    texts.dedup();
+    texts.push("没有".to_string());
+    for text in &texts {
+        let expected = reference.reverse_lookup(text).unwrap();
+        for (name, engine) in others {
+            assert_eq!(
+                engine.reverse_lookup(text).unwrap(),
+                expected,
+                "{} and {} differ looking {:?} up",
+                name,
+                reference_name,
+                text
+            );
+        }
+    }
+}
+
+fn assert_fixture(dictionaries: &[&str]) {
+    let deployed = deploy(dictionaries);
+    let queries = battery(&deployed);
+    assert_parity(&deployed, &queries);
+}
+
+#[test]
+fn test_prefixes() {
+    assert_fixture(&["text\tcode\tweight\n\
+         你\tni\t10\n\
+         尼\tni\t50\n\
+         你好\tnihao\t20\n\
+         泥\tnia\t3\n\
+         拟\tnii\t7\n\
+         好\that\t1\n\
+         号\th\t9\n"]);
+}
+
+#[test]
+fn test_repeated_rows() {
+    assert_fixture(&[
+        "text\tcode\tweight\tcomment\n\
+         你\tni\t10\t一\n\
+         尼\tni\t1\t\n\
+         你\tni\t30\t\n\
+         你\tn\t20\t二\n\
+         尼\tnu\t5\t\n\
+         呢\tne\t2\t三\n",
+        // a later dictionary redefines the texts of the earlier ones
+        "text\tcode\tweight\tcomment\n\
+         呢\tni\t4\t\n\
+         你\tnii\t40\t四\n",
+    ]);
+}
+
+#[test]
+fn test_unicode_codes() {
+    assert_fixture(&["text\tcode\tweight\n\
+         你\tnǐ\t1\n\
+         泥\tní\t2\n\
+         尼\tni\t3\n\
+         呢\tㄋㄧ\t4\n\
+         拟\tㄋ\t5\n\
+         好\tNi\t6\n\
+         号\tz\t7\n\
+         嗯\té\t8\n\
+         笑\t😀\t9\n"]);
+}
+
+#[test]
+fn test_wildcard_codes() {
+    assert_fixture(&["text\tcode\tweight\n\
+         一\ta_\t1\n\
+         二\ta%\t2\n\
+         三\tab\t3\n\
+         四\tA_\t4\n\
+         五\t_\t5\n\
+         六\t%a\t6\n\
+         七\tni hao\t7\n"]);
+}
+
+/// The start of the dictionaries of the sunman prelude.
+#[test]
+fn test_prelude() {
+    let head = |path: &str, rows: usize| {
+        let tsv = fs::read_to_string(Path::new("../prelude/sunman").join(path)).unwrap();
+        tsv.lines()
+            .take(rows + 1)
+            .map(|line| format!("{}\n", line))
+            .collect::<String>()
+    };
+    let words = head("words.dict.tsv", 1000);
+    let phrases = head("phrases.brief.dict.tsv", 300);
+    let deployed = deploy(&[&words, &phrases]);
+    // every prefix of thousands of codes is many queries, those of two letters at most
+    let queries: Vec<String> = battery(&deployed)
+        .into_iter()
+        .filter(|query| query.chars().count() <= 2)
+        .collect();
+    assert_parity(&deployed, &queries);
+}
@@ -0,0 +1,101 @@
+#ifndef LIUSHU_H
+#define LIUSHU_H
+
+/* Generated by cbindgen from liushu-ffi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ The engine of a formula of the config, and the user dictionary its commits go to, which
+ is kept in the target dir.
+ */
+typedef struct LiushuEngine LiushuEngine;
+
+typedef struct LiushuCandidate {
+  char *text;
+  char *code;
+  uint64_t weight;
+  /*
+   Null when the entry has no comment.
+   */
+  char *comment;
+} LiushuCandidate;
+
+typedef struct LiushuCandidateList {
+  struct LiushuCandidate *candidates;
+  uintptr_t len;
+} LiushuCandidateList;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Opens the engine of the first formula of the config in `config_dir`, deployed in
+ `target_dir`. Returns null on failure.
+
+ # Safety
+
+ Both paths must be null or nul-terminated UTF-8 strings.
+ */
+struct LiushuEngine *liushu_engine_new(const char *config_dir, const char *target_dir);
+
+/*
+ Candidates of `code` with the current formula, to be freed with
+ [`liushu_candidates_free`]. Returns null on failure.
+
+ # Safety
+
+ `engine` must come from [`liushu_engine_new`] and `code` be a nul-terminated string.
+ */
+struct LiushuCandidateList *liushu_engine_search(const struct LiushuEngine *engine,
+                                                 const char *code);
+
+/*
+ # Safety
+
+ `list` must be null or come from [`liushu_engine_search`], and not be used afterwards.
+ */
+void liushu_candidates_free(struct LiushuCandidateList *list);
+
+/*
+ Switches to another formula of the config. Returns 0 on success, the engine is left
+ alone on failure.
+
+ # Safety
+
+ `engine` must come from [`liushu_engine_new`] and `formula` be a nul-terminated string.
+ */
+int liushu_engine_set_formula(struct LiushuEngine *engine, const char *formula);
+
+/*
+ Records that the user committed `text` typed with `code`. Returns 0 on success.
+
+ # Safety
+
+ `engine` must come from [`liushu_engine_new`], `text` and `code` be nul-terminated
+ strings.
+ */
+int liushu_engine_commit(const struct LiushuEngine *engine, const char *text, const char *code);
+
+/*
+ # Safety
+
+ `engine` must be null or come from [`liushu_engine_new`], and not be used afterwards.
+ */
+void liushu_engine_free(struct LiushuEngine *engine);
+
+/*
+ Why the last failing call of this thread failed, null if none did. The string is owned
+ by liushu and valid until the next failing call of the thread.
+ */
+const char *liushu_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* LIUSHU_H */
@@ -0,0 +1,381 @@
+//! C ABI of liushu for input method frontends such as fcitx5 and ibus addons, declared in
+//! `include/liushu.h`.
+//!
+//! No function panics across the boundary. A failing call returns a null pointer or a
+//! non-zero status, which is the exit code of the CLI for the same error, and
+//! [`liushu_last_error_message`] tells why.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use liushu_core::{
+    config::Config,
+    engine::{EngineWithRedb, InputMethodEngine, SearchResultItem},
+    error::LiushuError,
+    userdict::{UserDict, USER_DICT_FILE},
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The engine of a formula of the config, and the user dictionary its commits go to, which
+/// is kept in the target dir.
+pub struct LiushuEngine {
+    config: Config,
+    target_dir: PathBuf,
+    formula: String,
+    engine: EngineWithRedb,
+    user_dict: UserDict,
+}
+
+#[repr(C)]
+pub struct LiushuCandidate {
+    pub text: *mut c_char,
+    pub code: *mut c_char,
+    pub weight: u64,
+    /// Null when the entry has no comment.
+    pub comment: *mut c_char,
+}
+
+#[repr(C)]
+pub struct LiushuCandidateList {
+    pub candidates: *mut LiushuCandidate,
+    pub len: usize,
+}
+
+fn set_last_error(message: String) {
+    // a message with a nul would be cut there by C anyway
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, its error or panic is the last error and the result is `failed` instead.
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, LiushuError>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(error)) => {
+            set_last_error(error.report());
+            failed
+        }
+        Err(_) => {
+            set_last_error("liushu panicked".to_string());
+            failed
+        }
+    }
+}
+
+/// Like [`guard`] for the calls returning a status, 0 on success.
+fn guard_status(f: impl FnOnce() -> Result<(), LiushuError>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(error)) => {
+            set_last_error(error.report());
+            error.exit_code()
+        }
+        Err(_) => {
+            set_last_error("liushu panicked".to_string());
+            1
+        }
+    }
+}
+
+/// # Safety
+///
+/// `s` must be null or a nul-terminated string.
+unsafe fn to_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, LiushuError> {
+    if s.is_null() {
+        return Err(LiushuError::InvalidInput(format!("{} is null", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| LiushuError::InvalidInput(format!("{} is not UTF-8", name)))
+}
+
+fn to_c(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', " "))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// # Safety
+///
+/// `s` must be null or come from [`to_c`].
+unsafe fn free_c(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+impl LiushuEngine {
+    fn open(config_dir: &Path, target_dir: &Path) -> Result<Self, LiushuError> {
+        let config = Config::load_from_path(config_dir.join("main.dhall"))?;
+        let formula = config
+            .formulas
+            .first()
+            .map(|formula| formula.id.clone())
+            .ok_or_else(|| LiushuError::InvalidInput("the config has no formula".to_string()))?;
+        let engine = EngineWithRedb::with_formula(target_dir, &formula)?;
+        let user_dict = UserDict::open(target_dir.join(USER_DICT_FILE))?;
+        Ok(Self {
+            config,
+            target_dir: target_dir.to_path_buf(),
+            formula,
+            engine,
+            user_dict,
+        })
+    }
+
+    fn set_formula(&mut self, formula: &str) -> Result<(), LiushuError> {
+        self.config.formula(formula)?;
+        self.engine = EngineWithRedb::with_formula(&self.target_dir, formula)?;
+        self.formula = formula.to_string();
+        Ok(())
+    }
+}
+
+fn candidate_list(items: Vec<SearchResultItem>) -> *mut LiushuCandidateList {
+    let candidates: Box<[LiushuCandidate]> = items
+        .iter()
+        .map(|item| LiushuCandidate {
+            text: to_c(&item.text),
+            code: to_c(&item.code),
+            weight: item.weight,
+            comment: item.comment.as_deref().map_or(ptr::null_mut(), to_c),
+        })
+        .collect();
+    let len = candidates.len();
+    Box::into_raw(Box::new(LiushuCandidateList {
+        candidates: Box::into_raw(candidates) as *mut LiushuCandidate,
+        len,
+    }))
+}
+
+/// Opens the engine of the first formula of the config in `config_dir`, deployed in
+/// `target_dir`. Returns null on failure.
+///
+/// # Safety
+///
+/// Both paths must be null or nul-terminated UTF-8 strings.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_engine_new(
+    config_dir: *const c_char,
+    target_dir: *const c_char,
+) -> *mut LiushuEngine {
+    guard(ptr::null_mut(), || {
+        let config_dir = to_str(config_dir, "config_dir")?;
+        let target_dir = to_str(target_dir, "target_dir")?;
+        let engine = LiushuEngine::open(Path::new(config_dir), Path::new(target_dir))?;
+        Ok(Box::into_raw(Box::new(engine)))
+    })
+}
+
+/// Candidates of `code` with the current formula, to be freed with
+/// [`liushu_candidates_free`]. Returns null on failure.
+///
+/// # Safety
+///
+/// `engine` must come from [`liushu_engine_new`] and `code` be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_engine_search(
+    engine: *const LiushuEngine,
+    code: *const c_char,
+) -> *mut LiushuCandidateList {
+    guard(ptr::null_mut(), || {
+        let engine = engine
+            .as_ref()
+            .ok_or_else(|| LiushuError::InvalidInput("engine is null".to_string()))?;
+        let code = to_str(code, "code")?;
+        Ok(candidate_list(engine.engine.search(code)?))
+    })
+}
+
+/// # Safety
+///
+/// `list` must be null or come from [`liushu_engine_search`], and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_candidates_free(list: *mut LiushuCandidateList) {
+    if list.is_null() {
+        return;
+    }
+    let list = Box::from_raw(list);
+    let candidates = Box::from_raw(ptr::slice_from_raw_parts_mut(list.candidates, list.len));
+    for candidate in candidates.iter() {
+        free_c(candidate.text);
+        free_c(candidate.code);
+        free_c(candidate.comment);
+    }
+}
+
+/// Switches to another formula of the config. Returns 0 on success, the engine is left
+/// alone on failure.
+///
+/// # Safety
+///
+/// `engine` must come from [`liushu_engine_new`] and `formula` be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_engine_set_formula(
+    engine: *mut LiushuEngine,
+    formula: *const c_char,
+) -> c_int {
+    guard_status(|| {
+        let engine = engine
+            .as_mut()
+            .ok_or_else(|| LiushuError::InvalidInput("engine is null".to_string()))?;
+        engine.set_formula(to_str(formula, "formula")?)
+    })
+}
+
+/// Records that the user committed `text` typed with `code`. Returns 0 on success.
+///
+/// # Safety
+///
+/// `engine` must come from [`liushu_engine_new`], `text` and `code` be nul-terminated
+/// strings.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_engine_commit(
+    engine: *const LiushuEngine,
+    text: *const c_char,
+    code: *const c_char,
+) -> c_int {
+    guard_status(|| {
+        let engine = engine
+            .as_ref()
+            .ok_or_else(|| LiushuError::InvalidInput("engine is null".to_string()))?;
+        engine
+            .user_dict
+            .record(to_str(text, "text")?, to_str(code, "code")?)
+    })
+}
+
+/// # Safety
+///
+/// `engine` must be null or come from [`liushu_engine_new`], and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn liushu_engine_free(engine: *mut LiushuEngine) {
+    if !engine.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(engine))));
+    }
+}
+
+/// Why the last failing call of this thread failed, null if none did. The string is owned
+/// by liushu and valid until the next failing call of the thread.
+#[no_mangle]
+pub extern "C" fn liushu_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use liushu_core::{deploy::deploy, dirs::MyProjectDirs};
+
+    use super::*;
+
+    fn last_error() -> String {
+        let message = liushu_last_error_message();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = MyProjectDirs::from_root(root.path());
+        dirs.ensure().unwrap();
+        fs::write(
+            dirs.config_dir.join("main.dhall"),
+            r#"{ formulas = [
+                { id = "shapes", name = None Text, dictionaries = ["words.tsv"] },
+                { id = "other", name = None Text, dictionaries = ["words.tsv"] }
+            ] }"#,
+        )
+        .unwrap();
+        for id in ["shapes", "other"] {
+            fs::create_dir_all(dirs.config_dir.join(id)).unwrap();
+            fs::write(
+                dirs.config_dir.join(id).join("words.tsv"),
+                "text\tcode\tweight\tcomment\n你\tni\t2\t〔亻尔〕\n你好\tnihao\t1\t\n",
+            )
+            .unwrap();
+        }
+        let config = Config::load_from_path(dirs.config_dir.join("main.dhall")).unwrap();
+        deploy(&config, &dirs).unwrap();
+
+        let c = |s: &str| CString::new(s).unwrap();
+        let (config_dir, target_dir) = (
+            c(dirs.config_dir.to_str().unwrap()),
+            c(dirs.target_dir.to_str().unwrap()),
+        );
+        unsafe {
+            let engine = liushu_engine_new(config_dir.as_ptr(), target_dir.as_ptr());
+            assert!(!engine.is_null());
+
+            let list = liushu_engine_search(engine, c("ni").as_ptr());
+            assert!(!list.is_null());
+            let candidates = std::slice::from_raw_parts((*list).candidates, (*list).len);
+            let mut found: Vec<(String, String, u64, Option<String>)> = candidates
+                .iter()
+                .map(|candidate| {
+                    let s = |p: *mut c_char| CStr::from_ptr(p).to_string_lossy().into_owned();
+                    let comment = (!candidate.comment.is_null()).then(|| s(candidate.comment));
+                    (
+                        s(candidate.text),
+                        s(candidate.code),
+                        candidate.weight,
+                        comment,
+                    )
+                })
+                .collect();
+            found.sort();
+            assert_eq!(
+                found,
+                vec![
+                    (
+                        "你".to_string(),
+                        "ni".to_string(),
+                        2,
+                        Some("〔亻尔〕".to_string())
+                    ),
+                    ("你好".to_string(), "nihao".to_string(), 1, None),
+                ]
+            );
+            liushu_candidates_free(list);
+
+            assert_eq!(liushu_engine_set_formula(engine, c("other").as_ptr()), 0);
+            assert_eq!(liushu_engine_set_formula(engine, c("missing").as_ptr()), 3);
+            assert!(last_error().contains("unknown formula missing"));
+            assert_eq!(
+                liushu_engine_commit(engine, c("你").as_ptr(), c("ni").as_ptr()),
+                0
+            );
+            assert_ne!(
+                liushu_engine_commit(engine, ptr::null(), c("ni").as_ptr()),
+                0
+            );
+            assert_eq!(last_error(), "invalid input: text is null");
+            liushu_engine_free(engine);
+        }
+
+        let entries = UserDict::open(dirs.target_dir.join(USER_DICT_FILE))
+            .unwrap()
+            .entries()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].text.as_str(), entries[0].count), ("你", 1));
+
+        let missing = c(root.path().join("nowhere").to_str().unwrap());
+        let engine = unsafe { liushu_engine_new(missing.as_ptr(), target_dir.as_ptr()) };
+        assert!(engine.is_null());
+        assert!(last_error().starts_with("config error in "));
+        unsafe { liushu_candidates_free(ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,59 @@
+package org.liushu;
+
+/**
+ * The engine of a liushu profile, backed by {@code libliushu_jni}.
+ *
+ * <p>The app passes the dirs of the profile: {@code configDir} holds {@code main.dhall} and
+ * the dictionaries, {@code dataDir} the user dictionary and the deployed {@code target/}.
+ * Candidates are a JSON array of objects with a {@code text}, {@code code}, {@code weight}
+ * and {@code comment}.
+ */
+public final class Engine implements AutoCloseable {
+    static {
+        System.loadLibrary("liushu_jni");
+    }
+
+    private long handle;
+
+    /** Opens the first formula of the config, which must have been deployed. */
+    public Engine(String configDir, String dataDir) {
+        handle = open(configDir, dataDir);
+    }
+
+    /** Deploys the formulas of the config, and returns the summary as JSON. */
+    public static String deployProfile(String configDir, String dataDir) {
+        return deploy(configDir, dataDir);
+    }
+
+    /** The candidates of {@code code} as JSON, at most {@code limit} when it is positive. */
+    public synchronized String search(String code, int limit) {
+        return search(handle, code, limit);
+    }
+
+    public synchronized void setFormula(String formula) {
+        setFormula(handle, formula);
+    }
+
+    /** Records the commit in the user dictionary, the context of the next searches. */
+    public synchronized void commit(String text, String code) {
+        commit(handle, text, code);
+    }
+
+    @Override
+    public synchronized void close() {
+        free(handle);
+        handle = 0;
+    }
+
+    private static native String deploy(String configDir, String dataDir);
+
+    private static native long open(String configDir, String dataDir);
+
+    private static native String search(long handle, String code, int limit);
+
+    private static native void setFormula(long handle, String formula);
+
+    private static native void commit(long handle, String text, String code);
+
+    private static native void free(long handle);
+}
@@ -0,0 +1,16 @@
+package org.liushu;
+
+/** An error of liushu, {@link #getCode()} tells which kind. */
+public class LiushuException extends RuntimeException {
+    private final String code;
+
+    public LiushuException(String code, String message) {
+        super(message);
+        this.code = code;
+    }
+
+    /** Such as {@code E_FORMULA_UNKNOWN}, the code of the error in the CLI and the protocol. */
+    public String getCode() {
+        return code;
+    }
+}
@@ -0,0 +1,241 @@
+//! JNI bindings of liushu for Android input methods, the natives of `org.liushu.Engine` in
+//! `java/`.
+//!
+//! Nothing is looked up in the dirs of a desktop user, the app passes those of its profile:
+//! the config dir, and the data dir that the user dictionary and the `target/` of the
+//! artifacts are kept in. Candidates cross as a JSON string, the result of `search` in the
+//! [server protocol](liushu_core::server).
+//!
+//! No call panics across the boundary. A failing call throws an
+//! `org.liushu.LiushuException` with the code and the report of the error, and returns 0
+//! or null.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use jni::{
+    objects::{JClass, JObject, JString, JThrowable, JValue},
+    sys::{jint, jlong, jstring},
+    JNIEnv,
+};
+use liushu_core::{
+    config::Config,
+    deploy::deploy,
+    dirs::MyProjectDirs,
+    error::LiushuError,
+    server::{Outcome, Protocol, Request, PROTOCOL_VERSION},
+};
+use serde_json::{json, Value};
+
+const EXCEPTION: &str = "org/liushu/LiushuException";
+
+/// Throws a `LiushuException`, unless an exception is already pending.
+fn throw(env: &mut JNIEnv, code: &str, message: &str) {
+    if env.exception_check().unwrap_or(true) {
+        return;
+    }
+    let exception = (|| {
+        let code = JObject::from(env.new_string(code)?);
+        let message = JObject::from(env.new_string(message)?);
+        let exception = env.new_object(
+            EXCEPTION,
+            "(Ljava/lang/String;Ljava/lang/String;)V",
+            &[JValue::Object(&code), JValue::Object(&message)],
+        )?;
+        env.throw(JThrowable::from(exception))
+    })();
+    // without the class, the NoClassDefFoundError is pending instead
+    if exception.is_err() && !env.exception_check().unwrap_or(true) {
+        let _ = env.throw_new("java/lang/RuntimeException", message);
+    }
+}
+
+/// Runs `f`, its error or panic is thrown and the result is `failed` instead.
+fn guard<'local, T>(
+    env: &mut JNIEnv<'local>,
+    failed: T,
+    f: impl FnOnce(&mut JNIEnv<'local>) -> Result<T, LiushuError>,
+) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(env))) {
+        Ok(Ok(value)) => value,
+        Ok(Err(error)) => {
+            throw(env, error.code(), &error.report());
+            failed
+        }
+        Err(_) => {
+            throw(env, "E_PANIC", "liushu panicked");
+            failed
+        }
+    }
+}
+
+fn to_string(env: &mut JNIEnv, s: &JString, name: &str) -> Result<String, LiushuError> {
+    if s.is_null() {
+        return Err(LiushuError::InvalidInput(format!("{} is null", name)));
+    }
+    env.get_string(s)
+        .map(String::from)
+        .map_err(|e| LiushuError::InvalidInput(format!("{}: {}", name, e)))
+}
+
+fn to_java(env: &mut JNIEnv, value: &Value) -> Result<jstring, LiushuError> {
+    env.new_string(value.to_string())
+        .map(JString::into_raw)
+        .map_err(|e| LiushuError::Other(format!("cannot create a Java string: {}", e)))
+}
+
+/// The profile the app keeps in `config_dir` and `data_dir`.
+fn dirs(
+    env: &mut JNIEnv,
+    config_dir: &JString,
+    data_dir: &JString,
+) -> Result<(Config, MyProjectDirs), LiushuError> {
+    let config_dir = PathBuf::from(to_string(env, config_dir, "configDir")?);
+    let data_dir = PathBuf::from(to_string(env, data_dir, "dataDir")?);
+    let config = Config::load_from_path(config_dir.join("main.dhall"))?;
+    let dirs = MyProjectDirs {
+        config_dir,
+        target_dir: data_dir.join("target"),
+        data_dir,
+    };
+    Ok((config, dirs))
+}
+
+/// The connection behind a handle from [`Java_org_liushu_Engine_open`].
+///
+/// # Safety
+///
+/// `handle` must be 0 or come from [`Java_org_liushu_Engine_open`], and not be freed yet.
+unsafe fn protocol<'a>(handle: jlong) -> Result<&'a Mutex<Protocol>, LiushuError> {
+    (handle as *const Mutex<Protocol>)
+        .as_ref()
+        .ok_or_else(|| LiushuError::InvalidInput("the engine is closed".to_string()))
+}
+
+/// Answers a request of the server protocol on the connection.
+fn call(protocol: &Mutex<Protocol>, method: &str, params: Value) -> Result<Value, LiushuError> {
+    let mut protocol = protocol
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let request = Request {
+        id: Value::Null,
+        method: method.to_string(),
+        params,
+    };
+    match protocol.handle(request).outcome {
+        Outcome::Result(result) => Ok(result),
+        Outcome::Error(error) => Err(error),
+    }
+}
+
+/// `static String deploy(String configDir, String dataDir)`: deploys the formulas of the
+/// config, and returns the summary as JSON.
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_deploy<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    config_dir: JString<'local>,
+    data_dir: JString<'local>,
+) -> jstring {
+    guard(&mut env, std::ptr::null_mut(), |env| {
+        let (config, dirs) = dirs(env, &config_dir, &data_dir)?;
+        dirs.ensure()?;
+        let summary = deploy(&config, &dirs)?;
+        let summary = serde_json::to_value(&summary)
+            .map_err(|e| LiushuError::Other(format!("cannot encode the summary: {}", e)))?;
+        to_java(env, &summary)
+    })
+}
+
+/// `static long open(String configDir, String dataDir)`: opens the first formula of the
+/// config, deployed in the data dir, and returns the handle of the engine.
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_open<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    config_dir: JString<'local>,
+    data_dir: JString<'local>,
+) -> jlong {
+    guard(&mut env, 0, |env| {
+        let (config, dirs) = dirs(env, &config_dir, &data_dir)?;
+        let protocol = Mutex::new(Protocol::new(config, &dirs, None)?);
+        call(
+            &protocol,
+            "initialize",
+            json!({ "protocol_version": PROTOCOL_VERSION }),
+        )?;
+        Ok(Box::into_raw(Box::new(protocol)) as jlong)
+    })
+}
+
+/// `static String search(long handle, String code, int limit)`: the candidates of `code`
+/// as a JSON array, at most `limit` of them when it is positive.
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_search<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    code: JString<'local>,
+    limit: jint,
+) -> jstring {
+    guard(&mut env, std::ptr::null_mut(), |env| {
+        // SAFETY: `org.liushu.Engine` only passes handles it hasn't freed
+        let protocol = unsafe { protocol(handle)? };
+        let code = to_string(env, &code, "code")?;
+        let limit = usize::try_from(limit).ok().filter(|&limit| limit > 0);
+        let candidates = call(protocol, "search", json!({ "code": code, "limit": limit }))?;
+        to_java(env, &candidates)
+    })
+}
+
+/// `static void setFormula(long handle, String formula)`
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_setFormula<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    formula: JString<'local>,
+) {
+    guard(&mut env, (), |env| {
+        // SAFETY: as for search
+        let protocol = unsafe { protocol(handle)? };
+        let formula = to_string(env, &formula, "formula")?;
+        call(protocol, "set_formula", json!({ "formula": formula })).map(drop)
+    })
+}
+
+/// `static void commit(long handle, String text, String code)`: records the commit in the
+/// user dictionary, and makes it the context of the next searches.
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_commit<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    text: JString<'local>,
+    code: JString<'local>,
+) {
+    guard(&mut env, (), |env| {
+        // SAFETY: as for search
+        let protocol = unsafe { protocol(handle)? };
+        let text = to_string(env, &text, "text")?;
+        let code = to_string(env, &code, "code")?;
+        call(protocol, "commit", json!({ "text": text, "code": code })).map(drop)
+    })
+}
+
+/// `static void free(long handle)`, 0 is ignored.
+#[no_mangle]
+pub extern "system" fn Java_org_liushu_Engine_free<'local>(
+    _env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) {
+    if handle != 0 {
+        // SAFETY: `org.liushu.Engine` frees a handle once
+        let protocol = unsafe { Box::from_raw(handle as *mut Mutex<Protocol>) };
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(protocol)));
+    }
+}
@@ -0,0 +1,40 @@
+import org.liushu.Engine;
+import org.liushu.LiushuException;
+
+/** Drives the natives like an app would, the test profile dirs are the arguments. */
+public class EngineTest {
+    static void check(boolean ok, String what) {
+        if (!ok) {
+            throw new AssertionError(what);
+        }
+    }
+
+    static String code(Runnable call) {
+        try {
+            call.run();
+        } catch (LiushuException e) {
+            return e.getCode();
+        }
+        throw new AssertionError("no LiushuException");
+    }
+
+    public static void main(String[] args) {
+        String configDir = args[0], dataDir = args[1];
+        String summary = Engine.deployProfile(configDir, dataDir);
+        check(summary.contains("\"id\":\"shapes\""), summary);
+
+        try (Engine engine = new Engine(configDir, dataDir)) {
+            String candidates = engine.search("ni", 0);
+            check(candidates.startsWith("[{\"code\":\"ni\""), candidates);
+            check(candidates.contains("\"text\":\"你好\""), candidates);
+            check(candidates.contains("\"comment\":\"〔亻尔〕\""), candidates);
+            check(!engine.search("ni", 1).contains("你好"), "limit");
+
+            engine.commit("你好", "nihao");
+            engine.setFormula("other");
+            check(code(() -> engine.setFormula("missing")).equals("E_FORMULA_UNKNOWN"), "formula");
+            check(code(() -> engine.commit(null, "ni")).equals("E_INVALID_INPUT"), "null");
+        }
+        check(code(() -> new Engine(dataDir, dataDir)).equals("E_CONFIG"), "config");
+    }
+}
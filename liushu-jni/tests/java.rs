@@ -0,0 +1,79 @@
+//! Compiles `org.liushu.Engine` and `EngineTest.java`, and runs the test against the
+//! library built for the tests.
+
+use std::{fs, io::ErrorKind, path::Path, process::Command};
+
+use liushu_core::userdict::{UserDict, USER_DICT_FILE};
+
+#[test]
+fn test_java() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let root = tempfile::tempdir().unwrap();
+    let (config_dir, data_dir, classes) = (
+        root.path().join("config"),
+        root.path().join("data"),
+        root.path().join("classes"),
+    );
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::write(
+        config_dir.join("main.dhall"),
+        r#"{ formulas = [
+            { id = "shapes", name = None Text, dictionaries = ["words.tsv"] },
+            { id = "other", name = None Text, dictionaries = ["words.tsv"] }
+        ] }"#,
+    )
+    .unwrap();
+    for id in ["shapes", "other"] {
+        fs::create_dir_all(config_dir.join(id)).unwrap();
+        fs::write(
+            config_dir.join(id).join("words.tsv"),
+            "text\tcode\tweight\tcomment\n你\tni\t2\t〔亻尔〕\n你好\tnihao\t1\t\n",
+        )
+        .unwrap();
+    }
+
+    let javac = Command::new("javac")
+        .arg("-encoding")
+        .arg("UTF-8")
+        .arg("-d")
+        .arg(&classes)
+        .arg(manifest_dir.join("java/org/liushu/Engine.java"))
+        .arg(manifest_dir.join("java/org/liushu/LiushuException.java"))
+        .arg(manifest_dir.join("tests/EngineTest.java"))
+        .status();
+    match javac {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("skipped, there is no javac");
+            return;
+        }
+        status => assert!(status.unwrap().success()),
+    }
+
+    // cargo builds no cdylib for the tests, it goes in target/<profile> next to their deps
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "-p", "liushu-jni", "--lib"])
+        .args((!cfg!(debug_assertions)).then_some("--release"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let exe = std::env::current_exe().unwrap();
+    let library_dir = exe.parent().unwrap().parent().unwrap();
+    let status = Command::new("java")
+        .arg("-Dfile.encoding=UTF-8")
+        .arg(format!("-Djava.library.path={}", library_dir.display()))
+        .arg("-cp")
+        .arg(&classes)
+        .arg("EngineTest")
+        .arg(&config_dir)
+        .arg(&data_dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let entries = UserDict::open(data_dir.join(USER_DICT_FILE))
+        .unwrap()
+        .entries()
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].text.as_str(), entries[0].count), ("你好", 1));
+}
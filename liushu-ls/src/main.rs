@@ -1,8 +1,11 @@
+use clap::{ArgAction, Parser};
+use liushu_core::dirs::PROJECT_DIRS;
 use liushu_core::engine::{InputMethodEngine, ShapeCodeEngine};
 use tokio::sync::{Mutex, RwLock};
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
+use tracing_subscriber::filter::LevelFilter;
 
 macro_rules! regex {
     ($re:literal $(,)?) => {{
@@ -11,6 +14,18 @@ macro_rules! regex {
     }};
 }
 
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Log more details to stderr, repeat for even more
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
 #[derive(Debug)]
 struct Backend {
     client: Client,
@@ -19,11 +34,11 @@ struct Backend {
 }
 
 impl Backend {
-    pub fn new(client: Client) -> Self {
+    pub fn new(client: Client, engine: ShapeCodeEngine) -> Self {
         Self {
             client,
             input: RwLock::new(String::new()),
-            engine: Mutex::new(ShapeCodeEngine::default()),
+            engine: Mutex::new(engine),
         }
     }
 }
@@ -97,7 +112,7 @@ impl LanguageServer for Backend {
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(new_input) = params.content_changes.get(0) {
+        if let Some(new_input) = params.content_changes.first() {
             let re = regex!(r"[a-z]+");
             let mut input_writer = self.input.write().await;
 
@@ -118,10 +133,29 @@ impl LanguageServer for Backend {
 
 #[tokio::main]
 async fn main() {
+    let args = Cli::parse();
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    // stdout carries the LSP messages, so logs must go to stderr.
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .try_init();
+
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(Backend::new);
+    let engine =
+        ShapeCodeEngine::with_formula(&PROJECT_DIRS.target_dir, "sunman").unwrap_or_else(|e| {
+            eprintln!("error: {}", e.report());
+            std::process::exit(e.exit_code());
+        });
+    let (service, socket) = LspService::new(|client| Backend::new(client, engine));
 
     Server::new(stdin, stdout, socket).serve(service).await;
 }
@@ -0,0 +1,138 @@
+//! Python bindings of liushu for dictionary and corpus tooling, built with maturin as the
+//! `liushu` module.
+//!
+//! Everything returned is made of plain Python types. A failure raises `liushu.LiushuError`,
+//! with the `code`, `hint` and `path` of the [`LiushuError`] as attributes.
+
+use std::path::PathBuf;
+
+use liushu_core::{
+    config::Config,
+    dict::{self, BuildOptions},
+    engine::{EngineWithRedb, InputMethodEngine},
+    error::LiushuError,
+    progress::NoProgress,
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use serde::Serialize;
+
+mod exceptions {
+    pyo3::create_exception!(
+        liushu,
+        LiushuError,
+        pyo3::exceptions::PyException,
+        "An error of liushu, `code` tells which kind."
+    );
+}
+
+fn py_err(py: Python<'_>, error: LiushuError) -> PyErr {
+    let err = exceptions::LiushuError::new_err(error.report());
+    let value = err.value(py);
+    let attrs = [
+        ("code", Some(error.code().to_string())),
+        ("hint", error.hint().map(String::from)),
+        ("path", error.path().map(|path| path.display().to_string())),
+    ];
+    for (name, attr) in attrs {
+        if let Err(e) = value.setattr(name, attr) {
+            return e;
+        }
+    }
+    err
+}
+
+/// Converts through JSON, so that reports become dicts and lists.
+fn to_python<'py>(py: Python<'py>, value: &impl Serialize) -> PyResult<Bound<'py, PyAny>> {
+    let json = serde_json::to_string(value).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// The redb engine of a formula deployed in `target_dir`, the first of the config in
+/// `config_dir` unless `formula` is given.
+#[pyclass(frozen)]
+struct Engine {
+    engine: EngineWithRedb,
+}
+
+#[pymethods]
+impl Engine {
+    #[new]
+    #[pyo3(signature = (config_dir, target_dir, formula = None))]
+    fn new(
+        py: Python<'_>,
+        config_dir: PathBuf,
+        target_dir: PathBuf,
+        formula: Option<&str>,
+    ) -> PyResult<Self> {
+        let open = || {
+            let config = Config::load_from_path(config_dir.join("main.dhall"))?;
+            let formula = match formula {
+                Some(formula) => config.formula(formula)?,
+                None => config.formulas.first().ok_or_else(|| {
+                    LiushuError::InvalidInput("the config has no formula".to_string())
+                })?,
+            };
+            EngineWithRedb::with_formula(&target_dir, &formula.id)
+        };
+        let engine = open().map_err(|e| py_err(py, e))?;
+        Ok(Self { engine })
+    }
+
+    /// Candidates of `code`, dicts with a `text`, `code`, `weight` and `comment`.
+    #[pyo3(signature = (code, limit = 9))]
+    fn search<'py>(
+        &self,
+        py: Python<'py>,
+        code: &str,
+        limit: usize,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let mut results = self.engine.search(code).map_err(|e| py_err(py, e))?;
+        results.truncate(limit);
+        to_python(py, &results)
+    }
+
+    fn reverse_lookup(&self, py: Python<'_>, text: &str) -> PyResult<Vec<String>> {
+        self.engine.reverse_lookup(text).map_err(|e| py_err(py, e))
+    }
+}
+
+/// Builds the redb dictionary and trie of `formula` in `output_dir` like `liushu dict
+/// build`, and returns the report.
+#[pyfunction]
+#[pyo3(signature = (inputs, output_dir, formula = "sunman", force = false))]
+fn build_dict<'py>(
+    py: Python<'py>,
+    inputs: Vec<PathBuf>,
+    output_dir: PathBuf,
+    formula: &str,
+    force: bool,
+) -> PyResult<Bound<'py, PyAny>> {
+    let options = BuildOptions {
+        force,
+        ..Default::default()
+    };
+    let report = py
+        .detach(|| dict::build(&inputs, &output_dir, formula, options, &NoProgress))
+        .map_err(|e| py_err(py, e))?;
+    to_python(py, &report)
+}
+
+/// Reads TSV dictionaries without building them, and returns every malformed row in
+/// `errors` along with the warnings of a build.
+#[pyfunction]
+fn validate_dict(py: Python<'_>, inputs: Vec<PathBuf>) -> PyResult<Bound<'_, PyAny>> {
+    let report = py
+        .detach(|| dict::validate(&inputs))
+        .map_err(|e| py_err(py, e))?;
+    to_python(py, &report)
+}
+
+#[pymodule]
+#[pyo3(name = "liushu")]
+pub fn liushu_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Engine>()?;
+    m.add_function(wrap_pyfunction!(build_dict, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_dict, m)?)?;
+    m.add("LiushuError", m.py().get_type::<exceptions::LiushuError>())?;
+    Ok(())
+}
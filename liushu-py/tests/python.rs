@@ -0,0 +1,25 @@
+//! Runs the Python tests against the module linked in, so they need no wheel to be built.
+
+use pyo3::prelude::*;
+
+#[test]
+fn test_python() {
+    use liushu_py::liushu_module;
+
+    pyo3::append_to_inittab!(liushu_module);
+    Python::initialize();
+    let tests = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+    let successful: bool = Python::attach(|py| {
+        let unittest = py.import("unittest")?;
+        let suite = unittest
+            .getattr("defaultTestLoader")?
+            .call_method1("discover", (tests,))?;
+        unittest
+            .call_method1("TextTestRunner", ())?
+            .call_method1("run", (suite,))?
+            .call_method0("wasSuccessful")?
+            .extract()
+    })
+    .unwrap();
+    assert!(successful);
+}
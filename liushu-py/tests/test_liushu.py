@@ -0,0 +1,61 @@
+import tempfile
+import unittest
+from pathlib import Path
+
+import liushu
+
+CONFIG = """{ formulas = [
+    { id = "fixture", name = None Text, dictionaries = [] : List Text }
+] }"""
+
+
+class LiushuTest(unittest.TestCase):
+    def setUp(self):
+        tmp = tempfile.TemporaryDirectory()
+        self.addCleanup(tmp.cleanup)
+        self.root = Path(tmp.name)
+        self.words = self.root / "words.tsv"
+        self.words.write_text(
+            "text\tcode\tweight\tcomment\n你\tni\t2\t〔亻尔〕\n你好\tnihao\t1\t\n",
+            encoding="utf-8",
+        )
+        (self.root / "main.dhall").write_text(CONFIG, encoding="utf-8")
+
+    def test_build_dict(self):
+        report = liushu.build_dict([self.words], self.root / "target", formula="fixture")
+        self.assertEqual((report["entries"], report["codes"], report["warnings"]), (2, 2, []))
+        self.assertEqual(
+            sorted(Path(path).name for path, _ in report["artifacts"]),
+            ["fixture.assoc", "fixture.redb", "fixture.trie"],
+        )
+
+    def test_search(self):
+        liushu.build_dict([self.words], self.root / "target", formula="fixture")
+        engine = liushu.Engine(self.root, self.root / "target")
+        results = engine.search("ni")
+        self.assertEqual({result["text"] for result in results}, {"你", "你好"})
+        self.assertEqual(next(r for r in results if r["text"] == "你")["comment"], "〔亻尔〕")
+        self.assertEqual(len(engine.search("ni", limit=1)), 1)
+        self.assertEqual(engine.search("hao"), [])
+        self.assertEqual(engine.reverse_lookup("你好"), ["nihao"])
+
+    def test_errors(self):
+        with self.assertRaises(liushu.LiushuError) as error:
+            liushu.build_dict([self.root / "missing.tsv"], self.root / "target")
+        self.assertEqual(error.exception.code, "E_INPUT_MISSING")
+        self.assertTrue(error.exception.path.endswith("missing.tsv"))
+
+        with self.assertRaises(liushu.LiushuError) as error:
+            liushu.Engine(self.root, self.root / "target", formula="other")
+        self.assertEqual(error.exception.code, "E_FORMULA_UNKNOWN")
+
+    def test_validate_dict(self):
+        broken = self.root / "broken.tsv"
+        broken.write_text("text\tcode\tweight\n好\thao\theavy\n号\n", encoding="utf-8")
+        report = liushu.validate_dict([self.words, broken])
+        self.assertEqual(report["entries"], 2)
+        self.assertEqual([error["code"] for error in report["errors"]], ["E_DICT_PARSE"] * 2)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,36 @@
+//! Bindings of [`MemoryEngine`] for javascript, to try a formula in a browser.
+//!
+//! An engine is loaded from the `.trie` artifact of a deployed formula and its definitions,
+//! both written by [`MemoryEngine::to_bytes`].
+
+use liushu_core::engine::{InputMethodEngine, MemoryEngine};
+use liushu_core::error::LiushuError;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct Engine {
+    inner: MemoryEngine,
+}
+
+#[wasm_bindgen]
+impl Engine {
+    #[wasm_bindgen(constructor)]
+    pub fn new(trie: &[u8], definitions: &[u8]) -> Result<Engine, JsError> {
+        let inner = MemoryEngine::from_bytes(trie, definitions).map_err(js_error)?;
+        Ok(Self { inner })
+    }
+
+    /// Candidates of `code`, objects with a `text`, `code`, `weight` and `comment`, which is
+    /// `null` when there is none.
+    pub fn search(&self, code: &str) -> Result<JsValue, JsError> {
+        let results = self.inner.search(code).map_err(js_error)?;
+        results
+            .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+fn js_error(error: LiushuError) -> JsError {
+    JsError::new(&error.report())
+}
@@ -0,0 +1,52 @@
+//! Run with `wasm-pack test --node liushu-wasm`.
+#![cfg(target_arch = "wasm32")]
+
+use liushu_core::engine::{MemoryEngine, SearchResultItem};
+use liushu_wasm::Engine;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+fn bytes() -> (Vec<u8>, Vec<u8>) {
+    [
+        ("你", "ni", 2, Some("〔亻尔〕")),
+        ("你好", "nihao", 1, None),
+    ]
+    .into_iter()
+    .map(|(text, code, weight, comment)| SearchResultItem {
+        text: text.to_string(),
+        code: code.to_string(),
+        weight,
+        comment: comment.map(String::from),
+        formula: None,
+    })
+    .collect::<MemoryEngine>()
+    .to_bytes()
+    .unwrap()
+}
+
+#[wasm_bindgen_test]
+fn test_search() {
+    let (trie, definitions) = bytes();
+    let engine = Engine::new(&trie, &definitions).unwrap();
+
+    let results = engine.search("ni").unwrap();
+    let json = js_sys::JSON::stringify(&results).unwrap();
+    assert_eq!(
+        json,
+        r#"[{"text":"你","code":"ni","weight":2,"comment":"〔亻尔〕","is_exact":true},{"text":"你好","code":"nihao","weight":1,"comment":null}]"#
+    );
+    assert_eq!(
+        js_sys::JSON::stringify(&engine.search("x").unwrap()).unwrap(),
+        "[]"
+    );
+}
+
+#[wasm_bindgen_test]
+fn test_corrupt_bytes() {
+    let (trie, definitions) = bytes();
+    let error = Engine::new(&trie[..trie.len() / 2], &definitions)
+        .err()
+        .unwrap();
+    let message = String::from(js_sys::Error::from(JsValue::from(error)).message());
+    assert!(message.starts_with("corrupt artifact trie: "));
+}
@@ -1,91 +1,1953 @@
-use std::io::{stdin, stdout, Write};
+mod progress;
+mod repl;
 
-use clap::{Parser, Subcommand};
-use liushu_core::deploy::deploy;
-use liushu_core::dirs::PROJECT_DIRS;
-use liushu_core::engine::{EngineManager, EngineWithRedb, InputMethodEngine, ShapeCodeEngine};
-use liushu_core::hmm::train;
+#[cfg(feature = "dhat")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+use std::fs::File;
+use std::io::{stderr, stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use liushu_core::artifact::Provenance;
+use liushu_core::bench;
+use liushu_core::charset::{Charset, CharsetMode};
+use liushu_core::config::{Config, Overlay};
+use liushu_core::deploy::{
+    clean, rollback, CleanOptions, DeployJob, DeployOptions, DeploySummary, FormulaStatus,
+};
+use liushu_core::diagnostics::Warning;
+use liushu_core::dict::fix::Fix;
+use liushu_core::dict::{self, BuildOptions};
+use liushu_core::dirs::lock::Lock;
+use liushu_core::dirs::profiles::{self, Profiles};
+use liushu_core::dirs::{user_dirs, PROFILE_ENV, PROJECT_DIRS};
+use liushu_core::doctor::{self, CheckResult, CheckStatus};
+use liushu_core::engine::{
+    EngineWithRedb, InputMethodEngine, RankedEngine, RankingPipeline, SearchCache,
+    SearchResultItem, TransformedEngine, Transformers,
+};
+use liushu_core::error::LiushuError;
+use liushu_core::hmm::{
+    self, train_with_progress, EvalOptions, Granularity, Hmm, Model, Preprocess, PruneOptions,
+    Smoothing, TrainOptions, MODEL_FILE,
+};
+use liushu_core::keymap::Keymap;
+use liushu_core::patch::{PatchDict, PatchedEngine};
+use liushu_core::progress::{NoProgress, ProgressSink};
+#[cfg(unix)]
+use liushu_core::server::socket::SocketServer;
+use liushu_core::server::{Server, ServerOptions};
+use liushu_core::status::{self, ArtifactStatus, StatusReport};
+use liushu_core::typing_log::{self, TypingStats};
+use liushu_core::userdict::{ImportMode, UserDict, USER_DICT_FILE};
+use serde_json::json;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::progress::BarProgress;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Plain)]
+    format: OutputFormat,
+
+    /// Log more details to stderr, repeat for even more
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Keep the config and data in the named profile, see `liushu profile`, or under this
+    /// dir when it is a path, instead of those of the user
+    #[arg(long, global = true, value_name = "NAME|DIR")]
+    profile: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    Deploy,
+    Deploy {
+        /// Skip searching the artifacts of each formula once built
+        #[arg(long)]
+        no_verify: bool,
+        /// Number of backups of replaced artifacts to keep
+        #[arg(long, default_value_t = 3)]
+        keep_backups: usize,
+        /// Remove the artifacts of formulas no longer in the config
+        #[arg(long)]
+        prune: bool,
+        /// Restore the artifacts of a backup instead, the most recent one without a timestamp
+        #[arg(long, value_name = "TIMESTAMP", num_args = 0..=1)]
+        rollback: Option<Option<u64>>,
+        /// Wait for another liushu process deploying to the target dir instead of failing
+        #[arg(long, conflicts_with = "rollback")]
+        wait: bool,
+        /// Also build the formulas that are disabled
+        #[arg(long, conflicts_with = "rollback")]
+        include_disabled: bool,
+    },
 
     #[command(arg_required_else_help = true)]
     Train {
-        corpus_file: String,
+        corpus_files: Vec<PathBuf>,
+
+        /// Also train on every .txt, .jsonl and .tsv file in this directory, optionally gzipped
+        #[arg(long)]
+        corpus_dir: Option<PathBuf>,
+
+        /// Where to save the model, defaults to the target dir
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Merge into the existing model instead of replacing it
+        #[arg(long)]
+        append: bool,
+
+        /// Wait for another liushu process deploying to the target dir instead of failing
+        #[arg(long)]
+        wait: bool,
+
+        /// Also learn character trigrams with 3
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(2..=3))]
+        order: u64,
+
+        /// Drop transitions observed fewer times than this
+        #[arg(long, default_value_t = 1)]
+        min_count: u64,
+
+        /// Keep only this many of the most frequent successors of each character
+        #[arg(long)]
+        max_transitions_per_state: Option<usize>,
+
+        /// Skip lines with fewer characters than this
+        #[arg(long, default_value_t = 0)]
+        min_chars: usize,
+
+        /// Punctuation ending a sentence, any other punctuation is stripped
+        #[arg(long)]
+        boundaries: Option<String>,
+
+        /// Field holding the text of .jsonl corpus files
+        #[arg(long, default_value = "text")]
+        json_field: String,
+
+        /// Pinyin dictionary to learn the readings of characters from, unless a .tsv corpus
+        /// annotated with pinyin is given
+        #[arg(long)]
+        emission_dict: Vec<PathBuf>,
+
+        /// Number of corpus files counted at the same time, defaults to the number of CPUs
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// Also learn the transitions between the words of corpus files segmented with spaces
+        #[arg(long)]
+        words: bool,
+
+        /// Keep only this many of the most frequent words, the others are counted as UNK
+        #[arg(long, default_value_t = 50_000)]
+        max_vocab: usize,
+
+        /// Smoothing of the character transitions: none, add-k=K or absolute-discount=D
+        #[arg(long, default_value = "none")]
+        smoothing: Smoothing,
     },
 
-    Repl,
+    Model {
+        #[command(subcommand)]
+        command: ModelCommands,
+    },
+
+    Repl {
+        /// Run each line of this file instead of reading from the terminal
+        #[arg(long)]
+        script: Option<PathBuf>,
+        /// Install and deploy the starter config without asking when there is no config
+        #[arg(long)]
+        auto: bool,
+    },
+
+    #[command(arg_required_else_help = true)]
+    Search {
+        code: String,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        #[arg(long, default_value_t = 8)]
+        limit: usize,
+
+        /// Comment the candidates without one from the annotation table of the formula
+        #[arg(long)]
+        annotate: bool,
+    },
+
+    /// Print each character of a text over its shortest code, `·` for those without one
+    #[command(arg_required_else_help = true)]
+    Annotate {
+        text: String,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+    },
+
+    Status {
+        /// Also open the first formula like a server does, and tell how long each part took
+        #[arg(long)]
+        timings: bool,
+    },
+
+    /// Check the dirs, config, artifacts, model and user dictionary of the profile, and
+    /// search its formula, exiting with 1 when a check fails
+    Doctor,
+
+    /// Answer newline-delimited JSON requests with one engine kept open
+    Serve {
+        /// Read requests from stdin and answer on stdout
+        #[arg(
+            long,
+            required_unless_present_any = ["socket", "dbus"],
+            conflicts_with_all = ["socket", "dbus"]
+        )]
+        stdio: bool,
+
+        /// Listen on this unix domain socket for any number of clients
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Stop listening once no client has been connected for this long
+        #[arg(long, value_name = "SECONDS", requires = "socket")]
+        idle_timeout: Option<u64>,
+
+        /// Register org.liushu.Engine1 on the session bus instead, with the dbus feature
+        #[arg(long, conflicts_with = "socket")]
+        dbus: bool,
+
+        /// Formula to start with instead of the first of the config
+        #[arg(long)]
+        formula: Option<String>,
+
+        /// Load the artifacts of a formula on its first search, to answer clients sooner
+        #[arg(long)]
+        lazy: bool,
+
+        /// Comment the candidates without one from the annotation table of the formula
+        #[arg(long)]
+        annotate: bool,
+
+        /// Offer the candidates of the same weight last committed first
+        #[arg(long)]
+        recency_tiebreak: bool,
+
+        /// Milliseconds to spend reading the artifacts before the first search, 0 or --lazy
+        /// to skip it
+        #[arg(long, value_name = "MS", default_value_t = 200)]
+        warm_up: u64,
+    },
+
+    Bench {
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        /// File with one code per line, sampled from the trie when omitted
+        #[arg(long)]
+        queries: Option<PathBuf>,
+
+        #[arg(long, default_value_t = 100)]
+        iterations: usize,
+
+        /// Number of codes to sample when no query file is given
+        #[arg(long, default_value_t = 100)]
+        samples: usize,
+
+        /// Search through a cache of this many searches, like the server
+        #[arg(long)]
+        cache: Option<usize>,
+    },
+
+    Clean {
+        /// Also remove the trained HMM model
+        #[arg(long)]
+        all: bool,
+
+        /// Only remove the artifacts of formulas no longer in the config
+        #[arg(long, conflicts_with = "all")]
+        orphans: bool,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Do not ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+
+    Userdict {
+        #[command(subcommand)]
+        command: UserdictCommands,
+    },
+
+    /// Edit the patch dictionary searched on top of a formula
+    Dict {
+        #[command(subcommand)]
+        command: DictCommands,
+    },
+
+    /// Look into the typing log, kept when `typingLog.enabled` is set in the config
+    Log {
+        #[command(subcommand)]
+        command: LogCommands,
+    },
+
+    /// Manage the named profiles of the user, each with a config and data of its own
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommands,
+    },
+
+    /// Enable or disable a formula in the overlay of the config, leaving main.dhall as it is
+    Formula {
+        #[command(subcommand)]
+        command: FormulaCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum FormulaCommands {
+    /// Let a formula be deployed and switched to again
+    Enable { id: String },
+
+    /// Stop deploying a formula and switching or falling back to it, keeping its artifacts
+    Disable { id: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum ProfileCommands {
+    /// Print the names of the profiles
+    List,
+
+    /// Create the dirs of a profile, its config goes in the config dir printed
+    Create { name: String },
+
+    /// Remove a profile with its config, data and artifacts
+    Remove { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum LogCommands {
+    /// Summarize the candidates selected and the length of the codes committed
+    Stats {
+        /// Only the commits made with this formula
+        #[arg(long)]
+        formula: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ModelCommands {
+    /// Prune the transitions of a trained model
+    Prune {
+        /// Defaults to the model in the target dir
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        /// Drop transitions observed fewer times than this
+        #[arg(long, default_value_t = 1)]
+        min_count: u64,
+
+        /// Keep only this many of the most frequent successors of each character
+        #[arg(long)]
+        max_transitions_per_state: Option<usize>,
+    },
+
+    /// Measure the perplexity of a model on held-out text, and its conversion accuracy when
+    /// the text is a .tsv annotated with pinyin
+    Eval {
+        /// Defaults to the model in the target dir
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        #[arg(long)]
+        test_corpus: PathBuf,
+
+        /// Probability of the transitions missing from the model
+        #[arg(long, default_value_t = 1e-6)]
+        unseen_prob: f64,
+    },
+
+    /// Write the counts of a model as sorted TSV, gzipped when the output ends with .gz
+    Export {
+        /// Defaults to the model in the target dir
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        #[arg(long, short)]
+        output: PathBuf,
+    },
+
+    /// Rebuild a model from the output of model export
+    Import {
+        input: PathBuf,
+
+        /// Where to save the model, defaults to the target dir
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Show the metadata of a trained model, or the transitions of some characters
+    Inspect {
+        /// Defaults to the model in the target dir
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        /// List the most likely characters following this one
+        #[arg(long, conflicts_with = "pair")]
+        after: Option<String>,
+
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Show the probability of the second character following the first
+        #[arg(long, num_args = 2, value_names = ["PRE", "POST"])]
+        pair: Option<Vec<String>>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DictCommands {
+    /// Build the redb dictionary and trie of a formula from TSV dictionaries
+    Build {
+        #[arg(long, short, required = true, num_args(1..))]
+        inputs: Vec<PathBuf>,
+
+        /// Directory to write the artifacts to, created if needed
+        #[arg(long, short)]
+        output: PathBuf,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        /// Overwrite existing artifacts
+        #[arg(long)]
+        force: bool,
+
+        /// Record the dictionary and line of each entry, for inspect --provenance
+        #[arg(long)]
+        provenance: bool,
+
+        /// TSV of what each character of the codes stands for, for search --annotate
+        #[arg(long)]
+        annotation: Option<PathBuf>,
+
+        /// Keep the texts and codes as they are rather than normalizing their Unicode
+        #[arg(long)]
+        no_normalize: bool,
+
+        /// Bytes of rows held in memory for the trie, such as 512M, past which they are
+        /// sorted to temporary files next to it and merged
+        #[arg(long, value_parser = parse_size)]
+        max_memory: Option<usize>,
+
+        /// Characters of the entries, gb2312 or a file of them, others tagged or dropped
+        #[arg(long, value_name = "NAME|FILE")]
+        charset: Option<String>,
+
+        /// What to do of the entries with a character out of the charset: tag or drop
+        #[arg(long, default_value = "tag")]
+        charset_mode: CharsetMode,
+    },
+
+    /// Print the entries of a text with each of its codes
+    #[command(arg_required_else_help = true)]
+    Inspect {
+        text: String,
+
+        /// Directory of the artifacts, defaults to those deployed
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        /// Also print the dictionary and line of each, of artifacts built with --provenance
+        #[arg(long)]
+        provenance: bool,
+    },
+
+    /// Add an entry, or change its weight if it is already there
+    Add {
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        #[arg(long)]
+        text: String,
+
+        #[arg(long)]
+        code: String,
+
+        #[arg(long)]
+        weight: u64,
+    },
+
+    /// Hide an entry, including entries of the deployed dictionaries
+    Remove {
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        #[arg(long)]
+        text: String,
+
+        #[arg(long)]
+        code: String,
+    },
+
+    /// Print candidates drawn at random, more often the heavier, to review a dictionary
+    Sample {
+        /// Directory of the artifacts, defaults to those deployed
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        /// How many candidates to draw
+        #[arg(long, default_value_t = 50)]
+        n: usize,
+
+        /// Draw the same candidates on every run
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// Write the heaviest entries with their shortest codes as a TSV dictionary, for a
+    /// frontend to preload
+    Top {
+        /// Directory of the artifacts, defaults to those deployed
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        #[arg(long, default_value = "sunman")]
+        formula: String,
+
+        /// How many entries to write
+        #[arg(long, default_value_t = 2000)]
+        n: usize,
+
+        /// Defaults to stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Write TSV dictionaries as one with what can be repaired safely repaired, leaving out
+    /// the rows a build would fail on
+    Fix {
+        #[arg(long, short, required = true, num_args(1..))]
+        inputs: Vec<PathBuf>,
+
+        /// A new file, never one of the inputs
+        #[arg(long, short)]
+        output: PathBuf,
+
+        /// Apply only these of unicode, duplicates, whitespace, weights and columns
+        #[arg(long, value_delimiter = ',', conflicts_with = "skip")]
+        only: Vec<Fix>,
+
+        /// Apply all the fixes but these
+        #[arg(long, value_delimiter = ',')]
+        skip: Vec<Fix>,
+    },
+
+    /// Write a phrase library of Sogou or QQ (.scel or .qcel) as a TSV dictionary, with
+    /// pinyin codes
+    #[command(arg_required_else_help = true)]
+    ImportScel {
+        file: PathBuf,
+
+        /// Defaults to stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserdictCommands {
+    /// Write the user dictionary as TSV
+    Export {
+        /// Defaults to stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Read a TSV written by export
+    #[command(arg_required_else_help = true)]
+    Import {
+        file: PathBuf,
+
+        /// Add the counts to the existing entries
+        #[arg(long, conflicts_with = "replace", required_unless_present = "replace")]
+        merge: bool,
+
+        /// Drop the existing entries first
+        #[arg(long)]
+        replace: bool,
+    },
+
+    /// Replace the user dictionary with a backup, taken daily in the backups dir of the data
+    /// dir, and the session state with the one backed up along with it
+    #[command(arg_required_else_help = true)]
+    Restore {
+        /// A backup, or a TSV written by export, gzipped or not
+        #[arg(long)]
+        from: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Plain,
+    Tsv,
+    Json,
+}
+
+/// The candidates of `text` of each of its codes, with where each comes from when asked.
+fn inspect(
+    engine: &EngineWithRedb,
+    text: &str,
+    provenance: bool,
+) -> Result<Vec<(SearchResultItem, Option<Provenance>)>, LiushuError> {
+    let mut entries = Vec::new();
+    for code in engine.reverse_lookup(text)? {
+        let Some(item) = engine
+            .search(&code)?
+            .into_iter()
+            .find(|item| item.text == text && item.code == code)
+        else {
+            continue;
+        };
+        let provenance = match provenance {
+            true => engine.provenance(text, &code)?,
+            false => None,
+        };
+        entries.push((item, provenance));
+    }
+    Ok(entries)
+}
+
+/// The entries of [`inspect`], with the keys typing each code on a `keymap`.
+fn format_entries(
+    entries: &[(SearchResultItem, Option<Provenance>)],
+    keymap: Option<&Keymap>,
+    format: OutputFormat,
+) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(
+            &entries
+                .iter()
+                .map(|(item, provenance)| {
+                    let mut entry = json!({ "entry": item, "provenance": provenance });
+                    if let Some(keymap) = keymap {
+                        entry["keys"] = json!(keymap.keys(&item.code));
+                    }
+                    entry
+                })
+                .collect::<Vec<_>>(),
+        )
+        .unwrap(),
+        _ => {
+            let separator = if format == OutputFormat::Tsv {
+                "\t"
+            } else {
+                "  "
+            };
+            entries
+                .iter()
+                .map(|(item, provenance)| {
+                    let mut fields = vec![item.text.clone(), item.code.clone()];
+                    if let Some(keymap) = keymap {
+                        fields.push(format!("[{}]", keymap.keys(&item.code)));
+                    }
+                    fields.push(item.weight.to_string());
+                    if let Some(provenance) = provenance {
+                        fields.push(format!("({})", provenance));
+                    }
+                    fields.join(separator)
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+fn format_results(results: &[SearchResultItem], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Plain => results
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                format!(
+                    "{}. {} {}",
+                    i + 1,
+                    item.text,
+                    item.comment.as_deref().unwrap_or(&item.code)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Tsv => results
+            .iter()
+            .map(|item| {
+                format!(
+                    "{}\t{}\t{}\t{}",
+                    item.text,
+                    item.code,
+                    item.weight,
+                    item.comment.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Json => serde_json::to_string(results).unwrap(),
+    }
+}
+
+/// What a character without a code is printed as.
+const NO_CODE: &str = "·";
+
+/// The characters of [`InputMethodEngine::annotate_text`] over their codes, each pair in a
+/// column as wide as the wider of them, two rows for each line of the text.
+fn format_annotation(annotated: &[(char, Option<String>)], format: OutputFormat) -> String {
+    // near enough for a terminal: CJK and full-width forms take two columns
+    let width = |c: char| if c < '\u{1100}' { 1 } else { 2 };
+    match format {
+        OutputFormat::Plain => annotated
+            .split(|(c, _)| *c == '\n')
+            .map(|line| {
+                let (mut chars, mut codes) = (Vec::new(), Vec::new());
+                for (c, code) in line.iter().filter(|(c, _)| !c.is_control()) {
+                    let code = code.as_deref().unwrap_or(NO_CODE);
+                    let column = width(*c).max(code.chars().count());
+                    chars.push(format!("{}{}", c, " ".repeat(column - width(*c))));
+                    codes.push(format!("{:<1$}", code, column));
+                }
+                format!(
+                    "{}\n{}",
+                    chars.join(" ").trim_end(),
+                    codes.join(" ").trim_end()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Tsv => annotated
+            .iter()
+            .filter(|(c, _)| !c.is_control())
+            .map(|(c, code)| format!("{}\t{}", c, code.as_deref().unwrap_or_default()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::Json => serde_json::to_string(
+            &annotated
+                .iter()
+                .map(|(c, code)| json!({ "char": c, "code": code }))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap(),
+    }
+}
+
+fn format_typing_stats(stats: &TypingStats) -> String {
+    let mut lines = vec![
+        format!("commits: {}", stats.commits),
+        format!("average code length: {:.2}", stats.average_code_len),
+    ];
+    let selections: usize = stats.selections.values().sum();
+    for (index, count) in &stats.selections {
+        lines.push(format!(
+            "candidate {}: {} ({:.1}%)",
+            index + 1,
+            count,
+            *count as f64 * 100.0 / selections as f64
+        ));
+    }
+    if stats.malformed > 0 {
+        lines.push(format!("skipped {} malformed records", stats.malformed));
+    }
+    lines.join("\n")
+}
+
+fn format_doctor(results: &[CheckResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.check.len())
+        .max()
+        .unwrap_or(0);
+    results
+        .iter()
+        .map(|result| {
+            format!(
+                "{}  {:width$}  {}",
+                result.status,
+                result.check,
+                result.message,
+                width = width
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_status(report: &StatusReport) -> String {
+    fn artifact_line(artifact: &ArtifactStatus) -> String {
+        format!(
+            "{} ({} bytes, modified {})",
+            artifact.path.display(),
+            artifact.size,
+            artifact
+                .modified
+                .map_or("unknown".to_string(), |secs| secs.to_string())
+        )
+    }
+
+    let mut lines = vec![
+        format!("version: {}", report.version),
+        format!("config dir: {}", report.config_dir.display()),
+        format!("data dir: {}", report.data_dir.display()),
+        format!("target dir: {}", report.target_dir.display()),
+    ];
+    if let Some(error) = &report.config_error {
+        lines.push(format!("config: {}", error));
+    }
+    for formula in &report.formulas {
+        lines.push(format!(
+            "formula {}{}: {}{}",
+            formula.id,
+            formula
+                .name
+                .as_ref()
+                .map_or(String::new(), |name| format!(" ({})", name)),
+            if formula.deployed {
+                "deployed"
+            } else {
+                "not deployed"
+            },
+            if formula.enabled { "" } else { ", disabled" }
+        ));
+        for artifact in &formula.artifacts {
+            lines.push(format!("  {}", artifact_line(artifact)));
+        }
+    }
+    lines.push(format!(
+        "hmm model: {}",
+        report
+            .hmm_model
+            .as_ref()
+            .map_or("not found".to_string(), artifact_line)
+    ));
+    for orphan in &report.orphans {
+        lines.push(format!("orphan: {}", orphan.display()));
+    }
+    for backup in &report.backups {
+        lines.push(format!(
+            "backup {}: {}",
+            backup.timestamp,
+            backup.formulas.join(", ")
+        ));
+    }
+    for (component, secs) in report.timings.iter().flatten() {
+        lines.push(format!("opened {} in {:.3}s", component, secs));
+    }
+    lines.join("\n")
+}
+
+/// Warnings printed of a formula, or of a command, without `--verbose`.
+const MAX_WARNINGS: usize = 5;
+
+/// A line of each warning, after the `scope` they are about if any, only the first
+/// [`MAX_WARNINGS`] of them and how many are left out unless `verbose`.
+fn format_warnings(scope: Option<&str>, warnings: &[Warning], verbose: bool) -> Vec<String> {
+    let prefix = match scope {
+        Some(scope) => format!("warning: {}: ", scope),
+        None => "warning: ".to_string(),
+    };
+    let shown = match verbose {
+        true => warnings.len(),
+        false => warnings.len().min(MAX_WARNINGS),
+    };
+    let mut lines: Vec<_> = warnings[..shown]
+        .iter()
+        .map(|warning| format!("{}{}", prefix, warning))
+        .collect();
+    if shown < warnings.len() {
+        lines.push(format!(
+            "{}… and {} more, see them with --verbose",
+            prefix,
+            warnings.len() - shown
+        ));
+    }
+    lines
+}
+
+fn format_deploy(summary: &DeploySummary, verbose: bool) -> String {
+    let width = summary
+        .formulas
+        .iter()
+        .map(|formula| formula.id.len())
+        .chain(["formula".len()])
+        .max()
+        .unwrap_or_default();
+    let mut lines = vec![format!(
+        "{:<width$}  {:<9}  {:>8}  {:>7}",
+        "formula", "status", "entries", "seconds"
+    )];
+    for formula in &summary.formulas {
+        let status = match formula.status {
+            FormulaStatus::Deployed => "deployed",
+            FormulaStatus::Unchanged => "unchanged",
+            FormulaStatus::Failed => "failed",
+        };
+        lines.push(format!(
+            "{:<width$}  {:<9}  {:>8}  {:>7.2}",
+            formula.id, status, formula.entries, formula.duration_secs
+        ));
+    }
+    for formula in &summary.formulas {
+        lines.extend(format_warnings(
+            Some(&formula.id),
+            &formula.warnings,
+            verbose,
+        ));
+        if let Some(error) = &formula.error {
+            lines.push(format!("error: {}: {}", formula.id, error.report()));
+        }
+    }
+    for path in &summary.pruned {
+        lines.push(format!("pruned {}", path.display()));
+    }
+    lines.extend(format_warnings(None, &summary.warnings, verbose));
+    lines.join("\n")
+}
+
+fn confirm(paths: &[PathBuf]) -> bool {
+    for path in paths {
+        eprintln!("{}", path.display());
+    }
+    eprint!("remove {} files? [y/N] ", paths.len());
+    stderr().flush().unwrap();
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// A size in bytes, or in KiB, MiB or GiB with a `K`, `M` or `G` after it.
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("{:?} isn't a size such as 512M", size))
+}
+
+fn format_error(error: &LiushuError) -> String {
+    json!({ "error": error }).to_string()
+}
+
+fn fail(error: LiushuError, format: OutputFormat) -> ! {
+    match format {
+        OutputFormat::Json => println!("{}", format_error(&error)),
+        _ => {
+            eprintln!("error[{}]: {}", error.code(), error.report());
+            if let Some(hint) = error.hint() {
+                eprintln!("hint: {}", hint);
+            }
+        }
+    }
+    exit(error.exit_code());
+}
+
+#[cfg(unix)]
+fn serve_socket(
+    server: Arc<Server>,
+    path: &Path,
+    idle_timeout: Option<Duration>,
+) -> Result<(), LiushuError> {
+    let mut socket = SocketServer::bind(server, path)?;
+    socket.idle_timeout = idle_timeout;
+    socket.run()
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_: Arc<Server>, _: &Path, _: Option<Duration>) -> Result<(), LiushuError> {
+    Err(LiushuError::InvalidInput(
+        "unix domain sockets are only served on unix".to_string(),
+    ))
+}
+
+/// Has the service on the session bus reload what was deployed, a failure is a warning of
+/// the deploy.
+#[cfg(feature = "dbus")]
+fn notify_deployed(summary: &mut DeploySummary) {
+    use liushu_core::deploy::DeployHooks;
+
+    let hook = liushu_core::server::dbus::ReloadHook::session();
+    if let Err(error) = hook.post_deploy(&PROJECT_DIRS.target_dir, summary) {
+        summary.warnings.push(Warning::HookFailed {
+            error: error.report(),
+        });
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+fn notify_deployed(_: &mut DeploySummary) {}
+
+#[cfg(feature = "dbus")]
+fn serve_dbus(server: Arc<Server>) -> Result<(), LiushuError> {
+    liushu_core::server::dbus::DbusService::session(server)?.run()
+}
+
+#[cfg(not(feature = "dbus"))]
+fn serve_dbus(_: Arc<Server>) -> Result<(), LiushuError> {
+    Err(LiushuError::InvalidInput(
+        "liushu was built without the dbus feature".to_string(),
+    ))
+}
+
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::WARN,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .try_init();
 }
 
 fn main() {
     let args = Cli::parse();
+    init_logging(args.verbose, args.quiet);
+    let progress: Box<dyn ProgressSink> = if args.quiet {
+        Box::new(NoProgress)
+    } else {
+        Box::<BarProgress>::default()
+    };
+    let format = args.format;
+    let verbose = args.verbose > 0;
+    if let Some(profile) = &args.profile {
+        let root = match profile
+            .to_str()
+            .filter(|profile| profiles::is_name(profile))
+        {
+            Some(name) => Profiles::of(&user_dirs())
+                .root(name)
+                .unwrap_or_else(|e| fail(e, format)),
+            None => profile.clone(),
+        };
+        std::env::set_var(PROFILE_ENV, root);
+    }
+    PROJECT_DIRS.ensure().unwrap_or_else(|e| fail(e, format));
 
     match args.command {
-        Commands::Deploy => {
-            deploy().unwrap();
-        }
-        Commands::Train { corpus_file } => {
-            let save_to = &PROJECT_DIRS.target_dir.join("hmm_model.redb");
-            train(corpus_file, save_to);
-        }
-        Commands::Repl => {
-            let sunman = ShapeCodeEngine::default();
-            let sunman2 = EngineWithRedb::with(&PROJECT_DIRS.target_dir).unwrap();
-            let mut engine_manager = EngineManager::from(
-                [Box::new(sunman), Box::new(sunman2)] as [Box<dyn InputMethodEngine>; 2]
-            );
-
-            loop {
-                print!("liushu> ");
-                stdout().flush().unwrap();
-                let mut input = String::new();
-                match stdin().read_line(&mut input) {
-                    Ok(_) => {
-                        let input = input.trim();
-
-                        if input == "*shift" {
-                            engine_manager.set_active_engine(1);
-                        }
+        Commands::Deploy {
+            rollback: Some(timestamp),
+            ..
+        } => {
+            let backup = rollback(&PROJECT_DIRS, timestamp).unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&backup).unwrap()),
+                _ => println!(
+                    "rolled back to backup {}: {}",
+                    backup.timestamp,
+                    backup.formulas.join(", ")
+                ),
+            }
+        }
+        Commands::Deploy {
+            no_verify,
+            keep_backups,
+            prune,
+            rollback: None,
+            wait,
+            include_disabled,
+        } => {
+            let config = Config::load().unwrap_or_else(|e| fail(e, format));
+            let options = DeployOptions {
+                verify: !no_verify,
+                keep_backups,
+                prune,
+                wait,
+                include_disabled,
+            };
+            let total = options.formulas(&config).len() as u64;
+            let job = DeployJob::start(config, PROJECT_DIRS.clone(), options);
+            progress.on_start(&format!("deploying {} formulas", total), Some(total));
+            while !job.is_finished() {
+                progress.on_advance(job.progress().done);
+                thread::sleep(Duration::from_millis(100));
+            }
+            let mut summary = job.join().unwrap_or_else(|e| fail(e, format));
+            progress.on_finish(&format!(
+                "deployed {} formulas, {} failed",
+                total,
+                summary.failed().count()
+            ));
+            notify_deployed(&mut summary);
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&summary).unwrap()),
+                _ => println!("{}", format_deploy(&summary, verbose)),
+            }
+            let failure = summary.failed().find_map(|failure| failure.error.as_ref());
+            if let Some(code) = failure.map(LiushuError::exit_code) {
+                exit(code);
+            }
+        }
+        Commands::Train {
+            mut corpus_files,
+            corpus_dir,
+            output,
+            append,
+            wait,
+            order,
+            min_count,
+            max_transitions_per_state,
+            min_chars,
+            boundaries,
+            json_field,
+            emission_dict,
+            jobs,
+            words,
+            max_vocab,
+            smoothing,
+        } => {
+            if let Some(dir) = corpus_dir {
+                corpus_files.extend(hmm::corpus_files(dir).unwrap_or_else(|e| fail(e, format)));
+            }
+            if corpus_files.is_empty() {
+                let error = LiushuError::InvalidInput("no corpus files given".to_string());
+                fail(error, format);
+            }
+            // the model of the target dir is replaced under the lock of a deploy
+            let _lock = output.is_none().then(|| {
+                Lock::deploying(&PROJECT_DIRS.target_dir, wait).unwrap_or_else(|e| fail(e, format))
+            });
+            let save_to = output.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let report = train_with_progress(
+                &corpus_files,
+                &save_to,
+                TrainOptions {
+                    append,
+                    order,
+                    prune: PruneOptions {
+                        min_count,
+                        max_transitions_per_state,
+                    },
+                    preprocess: Preprocess {
+                        boundaries,
+                        min_chars,
+                        json_field,
+                        ..Default::default()
+                    },
+                    emission_dicts: emission_dict,
+                    jobs: jobs.unwrap_or_else(|| {
+                        std::thread::available_parallelism().map_or(1, |n| n.get())
+                    }),
+                    granularity: if words {
+                        Granularity::Word
+                    } else {
+                        Granularity::Character
+                    },
+                    max_vocab,
+                    smoothing,
+                },
+                progress.as_ref(),
+            )
+            .unwrap_or_else(|e| fail(e, format));
 
-                        if input == "*quit" {
-                            break;
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => println!(
+                    "trained {} on {} lines from {} files: {} sequences, {} characters with {} emissions, {} transitions, {} trigrams in {:.2}s",
+                    save_to.display(),
+                    report.lines,
+                    report.inputs,
+                    report.sequences,
+                    report.characters,
+                    report.emission,
+                    report.transitions,
+                    report.trigrams,
+                    report.elapsed_secs
+                ),
+            }
+            if format != OutputFormat::Json && words {
+                println!("kept {} words in the vocabulary", report.words);
+            }
+            let skipped = &report.skipped;
+            if format != OutputFormat::Json && skipped.total() > 0 {
+                println!(
+                    "skipped {} lines: {} blank, {} short, {} malformed, {} without chinese",
+                    skipped.total(),
+                    skipped.blank,
+                    skipped.short,
+                    skipped.malformed,
+                    skipped.no_chinese
+                );
+            }
+            if format != OutputFormat::Json {
+                for line in format_warnings(None, &report.warnings, verbose) {
+                    println!("{}", line);
+                }
+            }
+        }
+        Commands::Model {
+            command: ModelCommands::Export { model, output },
+        } => {
+            let model = model.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let rows = hmm::export_model(&model, &output).unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", json!({ "rows": rows })),
+                _ => println!("exported {} rows to {}", rows, output.display()),
+            }
+        }
+        Commands::Model {
+            command: ModelCommands::Import { input, output },
+        } => {
+            let save_to = output.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let rows = hmm::import_model(&input, &save_to).unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", json!({ "rows": rows })),
+                _ => println!("imported {} rows into {}", rows, save_to.display()),
+            }
+        }
+        Commands::Model {
+            command:
+                ModelCommands::Eval {
+                    model,
+                    test_corpus,
+                    unseen_prob,
+                },
+        } => {
+            let path = model.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let opts = EvalOptions {
+                unseen_prob,
+                ..Default::default()
+            };
+            let report = Model::open(&path)
+                .and_then(|model| hmm::evaluate(&Hmm::with_model(model), &test_corpus, opts))
+                .unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => {
+                    println!(
+                        "perplexity {:.4} over {} characters of {} sequences, {} unseen transitions",
+                        report.perplexity, report.tokens, report.sequences, report.unseen
+                    );
+                    if let Some(conversion) = &report.conversion {
+                        println!(
+                            "top-1 accuracy {}/{} ({:.2}%), character accuracy {:.2}%",
+                            conversion.correct,
+                            conversion.sentences,
+                            conversion.accuracy * 100.0,
+                            conversion.character_accuracy * 100.0
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Model {
+            command:
+                ModelCommands::Inspect {
+                    model,
+                    after,
+                    top,
+                    pair,
+                },
+        } => {
+            let path = model.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let model = Model::open(&path).unwrap_or_else(|e| fail(e, format));
+            if let Some(pre) = after {
+                let successors = model
+                    .top_successors(&pre, top)
+                    .unwrap_or_else(|e| fail(e, format));
+                match format {
+                    OutputFormat::Json => println!("{}", json!({ "successors": successors })),
+                    _ => {
+                        for (post, prob) in successors {
+                            println!("{}{}\t{:.4}\t{:.4}", pre, post, prob, prob.exp());
                         }
+                    }
+                }
+            } else if let Some([pre, post]) = pair.as_deref() {
+                let prob = model
+                    .transition(pre, post)
+                    .unwrap_or_else(|e| fail(e, format));
+                match (format, prob) {
+                    (OutputFormat::Json, _) => println!("{}", json!({ "transition": prob })),
+                    (_, Some(prob)) => {
+                        println!("{}{}\t{:.4}\t{:.4}", pre, post, prob, prob.exp())
+                    }
+                    (_, None) => println!("{}{}\tnot in the model", pre, post),
+                }
+            } else {
+                let info = model.info().unwrap_or_else(|e| fail(e, format));
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&info).unwrap()),
+                    _ => println!(
+                        "{}: order {}, {} emissions, {} sequences, {} characters, {} pinyins, {} transitions, {} trigrams",
+                        path.display(),
+                        info.order,
+                        info.emission,
+                        info.sequences
+                            .map_or("unknown".to_string(), |sequences| sequences.to_string()),
+                        info.characters,
+                        info.pinyins,
+                        info.transitions,
+                        info.trigrams
+                    ),
+                }
+                if format != OutputFormat::Json && info.smoothing != Smoothing::None {
+                    println!("transitions smoothed with {}", info.smoothing);
+                }
+                if format != OutputFormat::Json && info.granularity == Granularity::Word {
+                    println!("word transitions over {} words", info.words);
+                }
+            }
+        }
+        Commands::Model {
+            command:
+                ModelCommands::Prune {
+                    model,
+                    min_count,
+                    max_transitions_per_state,
+                },
+        } => {
+            let model = model.unwrap_or_else(|| PROJECT_DIRS.target_dir.join(MODEL_FILE));
+            let options = PruneOptions {
+                min_count,
+                max_transitions_per_state,
+            };
+            let report = hmm::prune(&model, options).unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => println!(
+                    "pruned {} transitions from {}, {} bytes saved",
+                    report.removed,
+                    model.display(),
+                    report.bytes_before.saturating_sub(report.bytes_after)
+                ),
+            }
+        }
+        Commands::Repl { script, auto } => {
+            repl::run(script.as_deref(), format, auto).unwrap_or_else(|e| fail(e, format))
+        }
+        Commands::Search {
+            code,
+            formula,
+            limit,
+            annotate,
+        } => {
+            // artifacts are searched without a config, but not those of a formula it lacks
+            let mut code = code;
+            let mut transformers = Transformers::new("", Vec::new());
+            let mut ranking = RankingPipeline::new();
+            if let Ok(config) = Config::load() {
+                config.formula(&formula).unwrap_or_else(|e| fail(e, format));
+                if let Some(keymap) = config.keymap(&formula) {
+                    code = keymap.code(&code).into_owned();
+                }
+                transformers = config.transformers(&formula);
+                // searched outside of a context, the model would rank nothing
+                ranking = config.ranking(&formula, None);
+            }
+            let results = PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula)
+                .and_then(|patch| {
+                    let engine = EngineWithRedb::with_formula(&PROJECT_DIRS.target_dir, &formula)?
+                        .annotate(annotate);
+                    let engine = TransformedEngine::new(engine, Arc::new(transformers));
+                    let engine = PatchedEngine::new(Box::new(engine), Arc::new(patch));
+                    Ok(RankedEngine::new(engine, Arc::new(ranking)))
+                })
+                .and_then(|engine| engine.search(&code))
+                .unwrap_or_else(|e| fail(e, format));
+            let results = &results[..results.len().min(limit)];
 
-                        engine_manager
-                            .search(input)
-                            .unwrap_or_else(|e| {
-                                println!("error: {}", e);
-                                vec![]
-                            })
+            println!("{}", format_results(results, format));
+            if results.is_empty() {
+                exit(1);
+            }
+        }
+        Commands::Annotate { text, formula } => {
+            let annotated = PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula)
+                .and_then(|patch| {
+                    let engine = EngineWithRedb::with_formula(&PROJECT_DIRS.target_dir, &formula)?;
+                    Ok(PatchedEngine::new(Box::new(engine), Arc::new(patch)))
+                })
+                .and_then(|engine| engine.annotate_text(&text))
+                .unwrap_or_else(|e| fail(e, format));
+            println!("{}", format_annotation(&annotated, format));
+            if annotated.iter().all(|(_, code)| code.is_none()) {
+                exit(1);
+            }
+        }
+        Commands::Serve {
+            stdio: _,
+            socket,
+            idle_timeout,
+            dbus,
+            formula,
+            lazy,
+            annotate,
+            recency_tiebreak,
+            warm_up,
+        } => Config::load()
+            .and_then(|config| {
+                let options = ServerOptions {
+                    lazy,
+                    annotate,
+                    recency_tiebreak,
+                };
+                Server::with_options(config, &PROJECT_DIRS, formula.as_deref(), options)
+            })
+            .and_then(|server| {
+                if !lazy && warm_up > 0 {
+                    server.warm_up(Duration::from_millis(warm_up))?;
+                }
+                Ok(server)
+            })
+            .and_then(|server| match (dbus, socket) {
+                (true, _) => serve_dbus(server),
+                (false, Some(path)) => {
+                    serve_socket(server, &path, idle_timeout.map(Duration::from_secs))
+                }
+                (false, None) => server.connect().serve(stdin().lock(), stdout().lock()),
+            })
+            .unwrap_or_else(|e| fail(e, format)),
+        Commands::Clean {
+            all,
+            orphans,
+            dry_run,
+            yes,
+        } => {
+            let options = CleanOptions {
+                all,
+                orphans,
+                dry_run,
+            };
+            let preview = clean(
+                &PROJECT_DIRS,
+                CleanOptions {
+                    dry_run: true,
+                    ..options
+                },
+            )
+            .unwrap_or_else(|e| fail(e, format));
+            let report = if dry_run || preview.removed.is_empty() {
+                preview
+            } else {
+                if !yes && !confirm(&preview.removed) {
+                    exit(1);
+                }
+                clean(&PROJECT_DIRS, options).unwrap_or_else(|e| fail(e, format))
+            };
+
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => report.removed.iter().for_each(|path| {
+                    let action = if report.dry_run {
+                        "would remove"
+                    } else {
+                        "removed"
+                    };
+                    println!("{} {}", action, path.display());
+                }),
+            }
+        }
+        Commands::Bench {
+            formula,
+            queries,
+            iterations,
+            samples,
+            cache,
+        } => {
+            let engine = EngineWithRedb::with_formula(&PROJECT_DIRS.target_dir, &formula)
+                .unwrap_or_else(|e| fail(e, format));
+            let queries = match queries {
+                Some(path) => std::fs::read_to_string(path)
+                    .map_err(LiushuError::from)
+                    .unwrap_or_else(|e| fail(e, format))
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect(),
+                None => bench::sample_queries(&engine, samples),
+            };
+
+            #[cfg(feature = "dhat")]
+            let _profiler = dhat::Profiler::builder().testing().build();
+            #[cfg(feature = "dhat")]
+            let blocks_before = dhat::HeapStats::get().total_blocks;
+
+            let report = match cache {
+                Some(capacity) => {
+                    let engine = SearchCache::new(engine, &formula, capacity);
+                    bench::run(&engine, &queries, iterations).map(|report| bench::BenchReport {
+                        cache: Some(engine.stats()),
+                        ..report
+                    })
+                }
+                None => bench::run(&engine, &queries, iterations),
+            }
+            .unwrap_or_else(|e| fail(e, format));
+            #[cfg(feature = "dhat")]
+            let report = bench::BenchReport {
+                allocations_per_search: Some(
+                    (dhat::HeapStats::get().total_blocks - blocks_before) as f64
+                        / report.searches.max(1) as f64,
+                ),
+                ..report
+            };
+
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => {
+                    println!(
+                        "{} queries x {} iterations, {} candidates",
+                        report.queries, report.iterations, report.candidates
+                    );
+                    println!(
+                        "p50 {:.1}us, p95 {:.1}us, p99 {:.1}us",
+                        report.p50_us, report.p95_us, report.p99_us
+                    );
+                    if let Some(allocations) = report.allocations_per_search {
+                        println!("{:.1} allocations per search", allocations);
+                    }
+                    if let Some(cache) = report.cache {
+                        println!("cache: {} hits, {} misses", cache.hits, cache.misses);
+                    }
+                }
+            }
+        }
+        Commands::Userdict { command } => {
+            let dict = UserDict::open(PROJECT_DIRS.data_dir.join(USER_DICT_FILE))
+                .unwrap_or_else(|e| fail(e, format));
+            match command {
+                UserdictCommands::Export { output } => {
+                    let exported = match &output {
+                        Some(path) => File::create(path)
+                            .map_err(LiushuError::from)
+                            .and_then(|file| dict.export(file)),
+                        None => dict.export(stdout()),
+                    }
+                    .unwrap_or_else(|e| fail(e, format));
+                    if let Some(path) = output {
+                        match format {
+                            OutputFormat::Json => println!("{}", json!({ "exported": exported })),
+                            _ => println!("exported {} entries to {}", exported, path.display()),
+                        }
+                    }
+                }
+                UserdictCommands::Import { file, replace, .. } => {
+                    let mode = if replace {
+                        ImportMode::Replace
+                    } else {
+                        ImportMode::Merge
+                    };
+                    let report = File::open(file)
+                        .map_err(LiushuError::from)
+                        .and_then(|file| dict.import(file, mode))
+                        .unwrap_or_else(|e| fail(e, format));
+                    match format {
+                        OutputFormat::Json => {
+                            println!("{}", serde_json::to_string(&report).unwrap())
+                        }
+                        _ => println!(
+                            "{} added, {} updated, {} skipped",
+                            report.added, report.updated, report.skipped
+                        ),
+                    }
+                }
+                UserdictCommands::Restore { from } => {
+                    let report = dict.restore(&from).unwrap_or_else(|e| fail(e, format));
+                    match format {
+                        OutputFormat::Json => {
+                            println!("{}", serde_json::to_string(&report).unwrap())
+                        }
+                        _ => println!(
+                            "restored {} entries{}, {} skipped",
+                            report.entries.added,
+                            if report.state {
+                                " and the session state"
+                            } else {
+                                ""
+                            },
+                            report.entries.skipped
+                        ),
+                    }
+                }
+            }
+        }
+        Commands::Dict { command } => match command {
+            DictCommands::Build {
+                inputs,
+                output,
+                formula,
+                force,
+                provenance,
+                annotation,
+                no_normalize,
+                max_memory,
+                charset,
+                charset_mode,
+            } => {
+                let charset = charset.map(|name| {
+                    Charset::named(&name, Path::new(".")).unwrap_or_else(|e| fail(e, format))
+                });
+                let options = BuildOptions {
+                    force,
+                    track_provenance: provenance,
+                    annotation,
+                    normalize_unicode: !no_normalize,
+                    max_memory,
+                    charset: charset.map(Arc::new),
+                    charset_mode,
+                };
+                let report = dict::build(&inputs, &output, &formula, options, progress.as_ref())
+                    .unwrap_or_else(|e| fail(e, format));
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                    _ => println!(
+                        "built {} entries with {} unique codes: {}",
+                        report.entries,
+                        report.codes,
+                        report
+                            .artifacts
                             .iter()
-                            .take(8)
-                            .enumerate()
-                            .for_each(|(i, result)| {
-                                println!("result{}: {:?}", i, result);
-                            });
+                            .map(|(path, size)| format!("{} ({} bytes)", path.display(), size))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+                if format != OutputFormat::Json {
+                    for line in format_warnings(Some(&formula), &report.warnings, verbose) {
+                        println!("{}", line);
+                    }
+                }
+            }
+            DictCommands::Add {
+                formula,
+                text,
+                code,
+                weight,
+            } => {
+                let updated = PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula)
+                    .and_then(|patch| patch.add(&text, &code, weight))
+                    .unwrap_or_else(|e| fail(e, format));
+                match format {
+                    OutputFormat::Json => println!("{}", json!({ "updated": updated })),
+                    _ if updated => println!("updated {} {} to {}", text, code, weight),
+                    _ => println!("added {} {} {}", text, code, weight),
+                }
+            }
+            DictCommands::Remove {
+                formula,
+                text,
+                code,
+            } => {
+                PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula)
+                    .and_then(|patch| patch.remove(&text, &code))
+                    .unwrap_or_else(|e| fail(e, format));
+                if format != OutputFormat::Json {
+                    println!("removed {} {}", text, code);
+                }
+            }
+            DictCommands::Inspect {
+                text,
+                dir,
+                formula,
+                provenance,
+            } => {
+                let dir = dir.unwrap_or_else(|| PROJECT_DIRS.target_dir.clone());
+                let entries = EngineWithRedb::with_formula(dir, &formula)
+                    .and_then(|engine| inspect(&engine, &text, provenance))
+                    .unwrap_or_else(|e| fail(e, format));
+                let config = Config::load().ok();
+                let keymap = config.as_ref().and_then(|config| config.keymap(&formula));
+                println!("{}", format_entries(&entries, keymap, format));
+                if entries.is_empty() {
+                    exit(1);
+                }
+            }
+            DictCommands::Sample {
+                dir,
+                formula,
+                n,
+                seed,
+            } => {
+                let dir = dir.unwrap_or_else(|| PROJECT_DIRS.target_dir.clone());
+                let results = EngineWithRedb::with_formula(dir, &formula)
+                    .and_then(|engine| engine.sample(n, seed))
+                    .unwrap_or_else(|e| fail(e, format));
+                println!("{}", format_results(&results, format));
+            }
+            DictCommands::Top {
+                dir,
+                formula,
+                n,
+                output,
+            } => {
+                let dir = dir.unwrap_or_else(|| PROJECT_DIRS.target_dir.clone());
+                let entries = EngineWithRedb::with_formula(dir, &formula)
+                    .and_then(|engine| engine.top_entries(n))
+                    .unwrap_or_else(|e| fail(e, format));
+                match &output {
+                    Some(path) => File::create(path)
+                        .map_err(LiushuError::from)
+                        .and_then(|file| dict::write_dictionary(&entries, file)),
+                    None => dict::write_dictionary(&entries, stdout()),
+                }
+                .unwrap_or_else(|e| fail(e, format));
+                if let Some(path) = output {
+                    match format {
+                        OutputFormat::Json => println!("{}", json!({ "written": entries.len() })),
+                        _ => println!("wrote {} entries to {}", entries.len(), path.display()),
                     }
-                    Err(error) => println!("error: {}", error),
                 }
             }
+            DictCommands::Fix {
+                inputs,
+                output,
+                only,
+                skip,
+            } => {
+                let fixes: Vec<_> = Fix::ALL
+                    .into_iter()
+                    .filter(|fix| (only.is_empty() || only.contains(fix)) && !skip.contains(fix))
+                    .collect();
+                let same = |input: &PathBuf| {
+                    let canonical = |path: &Path| std::fs::canonicalize(path).ok();
+                    output.exists() && canonical(input) == canonical(&output)
+                };
+                if let Some(input) = inputs.iter().find(|input| same(input)) {
+                    let message = format!("refusing to write the fixes over {}", input.display());
+                    fail(LiushuError::InvalidInput(message), format);
+                }
+                // written once every input is read, none of it when one fails
+                let mut fixed = Vec::new();
+                let report = dict::fix::fix(&inputs, &fixes, &mut fixed)
+                    .and_then(|report| {
+                        std::fs::write(&output, &fixed)
+                            .map_err(|e| LiushuError::io_at("write dictionary", &output, e))?;
+                        Ok(report)
+                    })
+                    .unwrap_or_else(|e| fail(e, format));
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                    _ => {
+                        for error in &report.dropped {
+                            eprintln!("dropped {}", error.report());
+                        }
+                        println!(
+                            "wrote {} entries to {}, dropping {} rows",
+                            report.entries,
+                            output.display(),
+                            report.dropped.len()
+                        );
+                        for (fix, rows) in &report.fixed {
+                            println!("{}: {} rows", fix, rows);
+                        }
+                    }
+                }
+            }
+            DictCommands::ImportScel { file, output } => {
+                let entries: Vec<SearchResultItem> = dict::import::scel(&file)
+                    .unwrap_or_else(|e| fail(e, format))
+                    .into_iter()
+                    .map(Into::into)
+                    .collect();
+                match &output {
+                    Some(path) => File::create(path)
+                        .map_err(LiushuError::from)
+                        .and_then(|file| dict::write_dictionary(&entries, file)),
+                    None => dict::write_dictionary(&entries, stdout()),
+                }
+                .unwrap_or_else(|e| fail(e, format));
+                if let Some(path) = output {
+                    match format {
+                        OutputFormat::Json => println!("{}", json!({ "written": entries.len() })),
+                        _ => println!("wrote {} entries to {}", entries.len(), path.display()),
+                    }
+                }
+            }
+        },
+        Commands::Log {
+            command: LogCommands::Stats { formula },
+        } => {
+            let stats = typing_log::stats(&PROJECT_DIRS.data_dir, formula.as_deref())
+                .unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&stats).unwrap()),
+                _ => println!("{}", format_typing_stats(&stats)),
+            }
+        }
+        Commands::Profile { command } => {
+            let profiles = Profiles::of(&user_dirs());
+            match command {
+                ProfileCommands::List => {
+                    let names = profiles.list().unwrap_or_else(|e| fail(e, format));
+                    match format {
+                        OutputFormat::Json => println!("{}", json!({ "profiles": names })),
+                        _ => names.iter().for_each(|name| println!("{}", name)),
+                    }
+                }
+                ProfileCommands::Create { name } => {
+                    let dirs = profiles.create(&name).unwrap_or_else(|e| fail(e, format));
+                    match format {
+                        OutputFormat::Json => println!(
+                            "{}",
+                            json!({ "created": name, "config_dir": dirs.config_dir })
+                        ),
+                        _ => println!(
+                            "created profile {}, its config goes in {}",
+                            name,
+                            dirs.config_dir.display()
+                        ),
+                    }
+                }
+                ProfileCommands::Remove { name } => {
+                    profiles.remove(&name).unwrap_or_else(|e| fail(e, format));
+                    match format {
+                        OutputFormat::Json => println!("{}", json!({ "removed": name })),
+                        _ => println!("removed profile {}", name),
+                    }
+                }
+            }
+        }
+        Commands::Formula { command } => {
+            let (id, enabled) = match command {
+                FormulaCommands::Enable { id } => (id, true),
+                FormulaCommands::Disable { id } => (id, false),
+            };
+            let config = Config::load().unwrap_or_else(|e| fail(e, format));
+            config.formula(&id).unwrap_or_else(|e| fail(e, format));
+            let config_dir = &PROJECT_DIRS.config_dir;
+            let mut overlay = Overlay::load(config_dir).unwrap_or_else(|e| fail(e, format));
+            overlay.set_enabled(&id, enabled);
+            overlay.save(config_dir).unwrap_or_else(|e| fail(e, format));
+            match format {
+                OutputFormat::Json => println!("{}", json!({ "formula": id, "enabled": enabled })),
+                _ if enabled => println!("enabled formula {}", id),
+                _ => println!("disabled formula {}", id),
+            }
+        }
+        Commands::Status { timings } => {
+            let mut report = status::collect(&PROJECT_DIRS);
+            if timings {
+                report.timings = Some(
+                    status::startup_timings(&PROJECT_DIRS).unwrap_or_else(|e| fail(e, format)),
+                );
+            }
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                _ => println!("{}", format_status(&report)),
+            }
+        }
+        Commands::Doctor => {
+            let results = doctor::run(&PROJECT_DIRS);
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&results).unwrap()),
+                _ => println!("{}", format_doctor(&results)),
+            }
+            if results
+                .iter()
+                .any(|result| result.status == CheckStatus::Fail)
+            {
+                exit(1);
+            }
         }
     };
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Cli;
+    use crate::{format_error, format_results, parse_size, Cli, OutputFormat};
     use clap::CommandFactory;
+    use liushu_core::engine::SearchResultItem;
+    use liushu_core::error::LiushuError;
 
     #[test]
     fn verify_cli() {
         Cli::command().debug_assert()
     }
+
+    #[test]
+    fn test_format_results() {
+        let results = vec![
+            SearchResultItem {
+                text: "你好".to_string(),
+                code: "nihao".to_string(),
+                weight: 2,
+                comment: None,
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            },
+            SearchResultItem {
+                text: "你".to_string(),
+                code: "ni".to_string(),
+                weight: 1,
+                comment: Some("〔亻尔〕".to_string()),
+                formula: None,
+                user_count: None,
+                is_exact: false,
+            },
+        ];
+
+        assert_eq!(
+            format_results(&results, OutputFormat::Plain),
+            "1. 你好 nihao\n2. 你 〔亻尔〕"
+        );
+        assert_eq!(
+            format_results(&results, OutputFormat::Tsv),
+            "你好\tnihao\t2\t\n你\tni\t1\t〔亻尔〕"
+        );
+        assert_eq!(
+            format_results(&results, OutputFormat::Json),
+            r#"[{"text":"你好","code":"nihao","weight":2,"comment":null},{"text":"你","code":"ni","weight":1,"comment":"〔亻尔〕"}]"#
+        );
+    }
+
+    #[test]
+    fn test_format_error() {
+        let error = LiushuError::Other("boom".to_string());
+        assert_eq!(
+            format_error(&error),
+            r#"{"error":{"code":"E_OTHER","hint":null,"message":"boom","path":null}}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("512m"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+    }
 }
@@ -0,0 +1,39 @@
+use std::sync::Mutex;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use liushu_core::progress::ProgressSink;
+
+/// Draws one progress bar per unit of work on stderr.
+#[derive(Debug, Default)]
+pub struct BarProgress {
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl ProgressSink for BarProgress {
+    fn on_start(&self, name: &str, total_hint: Option<u64>) {
+        let bar = match total_hint {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} rows ({per_sec})")
+                    .unwrap()
+                    .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {msg} {pos} rows ({per_sec})").unwrap(),
+            ),
+        };
+        bar.set_message(name.to_string());
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    fn on_advance(&self, n: u64) {
+        if let Some(bar) = &*self.bar.lock().unwrap() {
+            bar.set_position(n);
+        }
+    }
+
+    fn on_finish(&self, summary: &str) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish_with_message(summary.to_string());
+        }
+    }
+}
@@ -0,0 +1,1283 @@
+mod command;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use liushu_core::assets;
+use liushu_core::composition::MAX_ASSOCIATIONS;
+use liushu_core::config::Config;
+use liushu_core::dirs::PROJECT_DIRS;
+use liushu_core::engine::{
+    compare_results, ArtifactStore, EngineManager, EngineWithRedb, FallbackEngine, FilteredEngine,
+    Filters, InputMethodEngine, LazyEngine, RankedEngine, RankingPipeline, SearchResultItem,
+    ShapeCodeEngine, DESCENDANT_LIMIT,
+};
+use liushu_core::error::LiushuError;
+use liushu_core::hmm::Hmm;
+use liushu_core::patch::{PatchDict, PatchedEngine};
+use liushu_core::state::SessionState;
+use liushu_core::userdict::{UserCounts, USER_DICT_FILE};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use serde_json::json;
+
+use self::command::{Backend, ReplCommand, COMMANDS};
+use crate::{format_annotation, OutputFormat};
+
+const PAGE_SIZE: usize = 8;
+
+/// How long `*warmup` reads the artifacts at most.
+const WARM_UP_BUDGET: Duration = Duration::from_secs(1);
+
+/// Candidates of the last search, waiting for the user to pick one.
+#[derive(Debug)]
+struct Selection {
+    candidates: Vec<SearchResultItem>,
+    page: usize,
+    /// The text committed last, when the candidates are its associations.
+    associated: Option<String>,
+}
+
+impl Selection {
+    fn page_count(&self) -> usize {
+        self.candidates.len().div_ceil(PAGE_SIZE)
+    }
+
+    fn current_page(&self) -> &[SearchResultItem] {
+        let start = self.page * PAGE_SIZE;
+        let end = (start + PAGE_SIZE).min(self.candidates.len());
+        &self.candidates[start..end]
+    }
+
+    fn next_page(&mut self) -> bool {
+        if self.page + 1 < self.page_count() {
+            self.page += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn prev_page(&mut self) -> bool {
+        if self.page > 0 {
+            self.page -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Picks the `n`th (1-based) candidate of the current page.
+    fn select(&self, n: usize) -> Option<&SearchResultItem> {
+        n.checked_sub(1)
+            .and_then(|index| self.current_page().get(index))
+    }
+}
+
+struct Repl {
+    engine_manager: EngineManager,
+    patch: Arc<PatchDict>,
+    /// Those of the config dir, shared by the engines of every formula.
+    filters: Arc<Filters>,
+    /// The formulas searched when each formula has no candidate, see
+    /// [`Config::fallbacks`].
+    fallbacks: HashMap<String, Vec<String>>,
+    /// The ranking stages of each formula, see [`Config::ranking`].
+    rankings: HashMap<String, Arc<RankingPipeline>>,
+    /// Whether the engines it opens leave out the candidates out of the charset, toggled
+    /// by `*charset`.
+    strict_charset: Arc<AtomicBool>,
+    /// The commits of the user dictionary when the REPL started, told with the candidates.
+    counts: UserCounts,
+    /// The redb artifacts of the engines, opened once for the one searched, its fallbacks
+    /// and those `*compare` opens.
+    store: Arc<ArtifactStore>,
+    formula: String,
+    formulas: Vec<String>,
+    /// Those of the config that can't be switched to, listed after the others.
+    disabled: Vec<String>,
+    backend: Backend,
+    selection: Option<Selection>,
+    format: OutputFormat,
+    /// Number of inputs that failed, a script run fails if any did.
+    errors: usize,
+}
+
+impl Repl {
+    fn new(
+        engine_manager: EngineManager,
+        patch: Arc<PatchDict>,
+        store: Arc<ArtifactStore>,
+        formula: String,
+        formulas: Vec<String>,
+        backend: Backend,
+        format: OutputFormat,
+    ) -> Self {
+        Self {
+            engine_manager,
+            patch,
+            filters: Arc::default(),
+            fallbacks: HashMap::new(),
+            rankings: HashMap::new(),
+            strict_charset: Arc::new(AtomicBool::new(true)),
+            counts: UserCounts::default(),
+            store,
+            formula,
+            formulas,
+            disabled: Vec::new(),
+            backend,
+            selection: None,
+            format,
+            errors: 0,
+        }
+    }
+
+    /// The filters the engines it opens share, those of the engine it is given too.
+    fn with_filters(mut self, filters: Arc<Filters>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// The fallbacks of each formula for the engines it opens, those of the engine it is
+    /// given too.
+    fn with_fallbacks(mut self, fallbacks: HashMap<String, Vec<String>>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    /// The ranking stages of each formula for the engines it opens, those of the engine it
+    /// is given too.
+    fn with_rankings(mut self, rankings: HashMap<String, Arc<RankingPipeline>>) -> Self {
+        self.rankings = rankings;
+        self
+    }
+
+    /// The toggle the engines it opens share, that of the engine it is given too.
+    fn with_strict_charset(mut self, strict_charset: Arc<AtomicBool>) -> Self {
+        self.strict_charset = strict_charset;
+        self
+    }
+
+    fn with_disabled(mut self, disabled: Vec<String>) -> Self {
+        self.disabled = disabled;
+        self
+    }
+
+    fn with_counts(mut self, counts: UserCounts) -> Self {
+        self.counts = counts;
+        self
+    }
+
+    fn ranking(&self, formula_id: &str) -> Arc<RankingPipeline> {
+        self.rankings.get(formula_id).cloned().unwrap_or_default()
+    }
+
+    fn prompt(&self) -> String {
+        match &self.selection {
+            Some(selection) => format!(
+                "liushu [{}/{}]> ",
+                selection.page + 1,
+                selection.page_count()
+            ),
+            None => "liushu> ".to_string(),
+        }
+    }
+
+    /// Handles one line of input, returns `false` when the REPL should exit.
+    fn handle(&mut self, input: &str, out: &mut impl Write) -> io::Result<bool> {
+        if self.selection.is_some() {
+            if let Ok(n) = input.parse::<usize>() {
+                return self.commit(n, out).map(|_| true);
+            }
+        }
+
+        let command = match ReplCommand::parse(input) {
+            Some(Ok(command)) => command,
+            Some(Err(e)) => return self.fail(e, out).map(|_| true),
+            None => return self.search(input, out).map(|_| true),
+        };
+
+        match command {
+            ReplCommand::Help => writeln!(out, "{}", ReplCommand::help())?,
+            ReplCommand::List => {
+                for formula in &self.formulas {
+                    let marker = if *formula == self.formula { "*" } else { " " };
+                    writeln!(out, "{} {}", marker, formula)?;
+                }
+                for formula in &self.disabled {
+                    writeln!(out, "  {} (disabled)", formula)?;
+                }
+            }
+            ReplCommand::Info => {
+                writeln!(out, "formula: {}", self.formula)?;
+                writeln!(out, "backend: {}", self.backend)?;
+                if let Some(selection) = &self.selection {
+                    writeln!(out, "pending candidates: {}", selection.candidates.len())?;
+                }
+            }
+            ReplCommand::Use(formula_id) if !self.formulas.contains(&formula_id) => {
+                let error = if self.disabled.contains(&formula_id) {
+                    LiushuError::FormulaDisabled(formula_id)
+                } else {
+                    LiushuError::FormulaUnknown(formula_id)
+                };
+                self.fail(format!("error: {}", error.report()), out)?
+            }
+            ReplCommand::Use(formula_id) => {
+                self.open(formula_id.clone(), self.backend, out)?;
+                // the next session starts on it
+                if self.formula == formula_id {
+                    SessionState::remember_formula(&PROJECT_DIRS.data_dir, &formula_id);
+                }
+            }
+            ReplCommand::Backend(backend) => self.open(self.formula.clone(), backend, out)?,
+            ReplCommand::Shift => self.open(self.formula.clone(), self.backend.other(), out)?,
+            ReplCommand::Reload => {
+                // kept while the engine is opened of them, not loaded twice
+                let reloaded = match self.backend {
+                    Backend::Sqlite => Ok(None),
+                    Backend::Redb => self.store.reload(&self.formula),
+                };
+                match reloaded {
+                    Ok(_reloaded) => self.open(self.formula.clone(), self.backend, out)?,
+                    Err(e) => self.fail(
+                        format!(
+                            "error: cannot open {} backend: {}",
+                            self.backend,
+                            e.report()
+                        ),
+                        out,
+                    )?,
+                }
+            }
+            ReplCommand::ReloadFilters => match self.filters.reload() {
+                Ok(blocked) => writeln!(out, "blocking {} candidates", blocked)?,
+                Err(e) => self.fail(format!("error: {}", e.report()), out)?,
+            },
+            ReplCommand::WarmUp => match self.engine_manager.warm_up(WARM_UP_BUDGET) {
+                Ok(warm_up) if self.format == OutputFormat::Json => writeln!(
+                    out,
+                    "{}",
+                    json!({
+                        "warmed": warm_up.warmed,
+                        "total": warm_up.total,
+                        "seconds": warm_up.elapsed.as_secs_f64(),
+                    })
+                )?,
+                Ok(warm_up) => writeln!(
+                    out,
+                    "warmed {} of {} lookups in {:.1?}",
+                    warm_up.warmed, warm_up.total, warm_up.elapsed
+                )?,
+                Err(e) => self.fail(format!("error: {}", e.report()), out)?,
+            },
+            ReplCommand::Charset(strict) => {
+                self.strict_charset.store(strict, Ordering::Relaxed);
+                self.selection = None;
+                match strict {
+                    true => writeln!(out, "leaving out candidates out of the charset")?,
+                    false => writeln!(out, "showing candidates out of the charset")?,
+                }
+            }
+            ReplCommand::Lookup(code) => self.search(&code, out)?,
+            ReplCommand::Grouped(code) => self.search_grouped(&code, out)?,
+            ReplCommand::Compare(code) => self.compare(&code, out)?,
+            ReplCommand::Peek(code) => self.peek(&code, out)?,
+            ReplCommand::Annotate(text) => match self.engine_manager.annotate_text(&text) {
+                Ok(annotated) => writeln!(out, "{}", format_annotation(&annotated, self.format))?,
+                Err(e) => self.fail(format!("error: {}", e.report()), out)?,
+            },
+            ReplCommand::Add { text, code, weight } => match self.patch.add(&text, &code, weight) {
+                Ok(true) => writeln!(out, "updated {} {} to {}", text, code, weight)?,
+                Ok(false) => writeln!(out, "added {} {} {}", text, code, weight)?,
+                Err(e) => self.fail(format!("error: {}", e.report()), out)?,
+            },
+            ReplCommand::Remove { text, code } => match self.patch.remove(&text, &code) {
+                Ok(()) => writeln!(out, "removed {} {}", text, code)?,
+                Err(e) => self.fail(format!("error: {}", e.report()), out)?,
+            },
+            ReplCommand::Commit(n) => self.commit(n, out)?,
+            ReplCommand::Run(path) => return self.run_script(Path::new(&path), out),
+            ReplCommand::Next => {
+                if let Some(selection) = &mut self.selection {
+                    if selection.next_page() {
+                        print_page(selection, out)?;
+                    }
+                }
+            }
+            ReplCommand::Prev => {
+                if let Some(selection) = &mut self.selection {
+                    if selection.prev_page() {
+                        print_page(selection, out)?;
+                    }
+                }
+            }
+            ReplCommand::Quit => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn open(
+        &mut self,
+        formula_id: String,
+        backend: Backend,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let patch = if formula_id == self.formula {
+            Ok(self.patch.clone())
+        } else {
+            PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula_id).map(Arc::new)
+        };
+        match patch.and_then(|patch| {
+            let engine = open_engine(
+                &self.store,
+                &formula_id,
+                backend,
+                patch.clone(),
+                self.filters.clone(),
+                self.fallbacks.get(&formula_id).map_or(&[], Vec::as_slice),
+                self.ranking(&formula_id),
+                self.strict_charset.clone(),
+            )?;
+            Ok((engine, patch))
+        }) {
+            Ok((engine, patch)) => {
+                self.engine_manager = EngineManager::from([engine]);
+                self.patch = patch;
+                self.formula = formula_id;
+                self.backend = backend;
+                self.selection = None;
+            }
+            Err(e) => self.fail(
+                format!("error: cannot open {} backend: {}", backend, e.report()),
+                out,
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Tells of the trie of the redb artifacts whatever the backend, as sqlite has none.
+    fn peek(&mut self, code: &str, out: &mut impl Write) -> io::Result<()> {
+        let info = self.store.get(&self.formula).and_then(|artifacts| {
+            let engine = EngineWithRedb::from_artifacts(artifacts);
+            Ok(engine.prefix_info(code)?.unwrap_or_default())
+        });
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => return self.fail(format!("error: {}", e.report()), out),
+        };
+        if self.format == OutputFormat::Json {
+            return writeln!(out, "{}", json!(info));
+        }
+        if info.is_leaf {
+            return writeln!(out, "{} is a leaf, no code goes on from it", code);
+        }
+        if info.child_keys.is_empty() {
+            return writeln!(out, "no code starts with {}", code);
+        }
+        let keys: Vec<String> = info.child_keys.iter().map(char::to_string).collect();
+        let plus = match info.descendant_codes >= DESCENDANT_LIMIT {
+            true => "+",
+            false => "",
+        };
+        writeln!(
+            out,
+            "longer codes starting with {}: {}{}, next keys: {}",
+            code,
+            info.descendant_codes,
+            plus,
+            keys.join(" ")
+        )
+    }
+
+    fn compare(&mut self, code: &str, out: &mut impl Write) -> io::Result<()> {
+        // the backends of the formula alone, the fallbacks are of redb artifacts either way
+        let search = |backend| {
+            open_engine(
+                &self.store,
+                &self.formula,
+                backend,
+                self.patch.clone(),
+                self.filters.clone(),
+                &[],
+                self.ranking(&self.formula),
+                self.strict_charset.clone(),
+            )
+            .and_then(|engine| engine.search(code))
+            .map_err(|e| format!("error: cannot search {} backend: {}", backend, e.report()))
+        };
+        let (sqlite, redb) = match (search(Backend::Sqlite), search(Backend::Redb)) {
+            (Ok(sqlite), Ok(redb)) => (sqlite, redb),
+            (Err(e), _) | (_, Err(e)) => return self.fail(e, out),
+        };
+
+        let comparison = compare_results(&sqlite, &redb);
+        writeln!(out, "    {:<16}{}", Backend::Sqlite, Backend::Redb)?;
+        for (i, (left, right)) in comparison.rows.iter().enumerate() {
+            let marker = if left == right { ' ' } else { '*' };
+            writeln!(
+                out,
+                "{}{:>2} {:<16}{}",
+                marker,
+                i + 1,
+                left.as_deref().unwrap_or("-"),
+                right.as_deref().unwrap_or("-")
+            )?;
+        }
+        if comparison.is_identical() {
+            writeln!(out, "identical")?;
+        } else {
+            writeln!(
+                out,
+                "only in sqlite: {}, only in redb: {}",
+                comparison.only_left.join(" "),
+                comparison.only_right.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+
+    fn search(&mut self, code: &str, out: &mut impl Write) -> io::Result<()> {
+        let mut candidates = match self.engine_manager.search(code) {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                self.selection = None;
+                return self.fail(format!("error: {}", e.report()), out);
+            }
+        };
+        self.counts.annotate(&mut candidates);
+        if self.format == OutputFormat::Json {
+            writeln!(out, "{}", json!({ "query": code, "results": candidates }))?;
+        }
+        if candidates.is_empty() {
+            self.selection = None;
+        } else {
+            let selection = Selection {
+                candidates,
+                page: 0,
+                associated: None,
+            };
+            if self.format != OutputFormat::Json {
+                print_page(&selection, out)?;
+            }
+            self.selection = Some(selection);
+        }
+        Ok(())
+    }
+
+    /// Each code under its candidates, indented. Not numbered, so the candidates pending
+    /// are kept.
+    fn search_grouped(&mut self, code: &str, out: &mut impl Write) -> io::Result<()> {
+        let mut groups = match self.engine_manager.search_grouped(code) {
+            Ok(groups) => groups,
+            Err(e) => return self.fail(format!("error: {}", e.report()), out),
+        };
+        for (_, items) in &mut groups {
+            self.counts.annotate(items);
+        }
+        if self.format == OutputFormat::Json {
+            let groups: Vec<_> = groups
+                .iter()
+                .map(|(code, items)| json!({ "code": code, "results": items }))
+                .collect();
+            return writeln!(out, "{}", json!({ "query": code, "groups": groups }));
+        }
+        for (code, items) in &groups {
+            writeln!(out, "{}", code)?;
+            for item in items {
+                match item.user_count {
+                    Some(count) => write!(out, "    {} w={} u={}", item.text, item.weight, count)?,
+                    None => write!(out, "    {} ({})", item.text, item.weight)?,
+                }
+                if let Some(comment) = &item.comment {
+                    write!(out, " {}", comment)?;
+                }
+                match &item.formula {
+                    Some(formula) => writeln!(out, " (from {})", formula)?,
+                    None => writeln!(out)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs every line of a script, skipping blank lines and `#` comments.
+    fn run_script(&mut self, path: &Path, out: &mut impl Write) -> io::Result<bool> {
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                self.fail(format!("error: cannot read {}: {}", path.display(), e), out)?;
+                return Ok(true);
+            }
+        };
+        for line in script.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if self.format != OutputFormat::Json || ReplCommand::parse(line).is_some() {
+                writeln!(out, "> {}", line)?;
+            }
+            if !self.handle(line, out)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn fail(&mut self, message: impl Display, out: &mut impl Write) -> io::Result<()> {
+        self.errors += 1;
+        writeln!(out, "{}", message)
+    }
+
+    fn commit(&mut self, n: usize, out: &mut impl Write) -> io::Result<()> {
+        let Some(selection) = &self.selection else {
+            return self.fail("no pending candidates", out);
+        };
+
+        if n == 0 {
+            self.selection = None;
+        } else if let Some(candidate) = selection.select(n) {
+            // the rest of an association
+            let text = match &selection.associated {
+                Some(associated) => candidate.text.strip_prefix(associated.as_str()),
+                None => None,
+            };
+            writeln!(out, "committed: {}", text.unwrap_or(&candidate.text))?;
+            let committed = candidate.text.clone();
+            self.selection = None;
+            self.associate(committed, out)?;
+        } else {
+            self.fail(format!("no candidate {}", n), out)?;
+        }
+        Ok(())
+    }
+
+    /// Offers the phrases starting with what was just committed, as the candidates to pick
+    /// from next.
+    fn associate(&mut self, committed: String, out: &mut impl Write) -> io::Result<()> {
+        let mut candidates = match self
+            .engine_manager
+            .associations(&committed, MAX_ASSOCIATIONS)
+        {
+            Ok(candidates) => candidates,
+            Err(e) => return self.fail(format!("error: {}", e.report()), out),
+        };
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        self.counts.annotate(&mut candidates);
+        if self.format == OutputFormat::Json {
+            writeln!(
+                out,
+                "{}",
+                json!({ "associated": committed, "results": candidates })
+            )?;
+        }
+        let selection = Selection {
+            candidates,
+            page: 0,
+            associated: Some(committed),
+        };
+        if self.format != OutputFormat::Json {
+            writeln!(out, "associations:")?;
+            print_page(&selection, out)?;
+        }
+        self.selection = Some(selection);
+        Ok(())
+    }
+}
+
+/// The candidates of the code typed itself marked with `*`, not those of longer codes.
+fn print_page(selection: &Selection, out: &mut impl Write) -> io::Result<()> {
+    for (i, candidate) in selection.current_page().iter().enumerate() {
+        let marker = if candidate.is_exact { "*" } else { "" };
+        writeln!(out, "{}. {}{}", i + 1, marker, candidate)?;
+    }
+    Ok(())
+}
+
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ReplHelper {
+    formulas: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, candidates) = completions(&line[..pos], &self.formulas);
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .map(|candidate| Pair {
+                    display: candidate.clone(),
+                    replacement: candidate,
+                })
+                .collect(),
+        ))
+    }
+}
+
+fn completions(line: &str, formulas: &[String]) -> (usize, Vec<String>) {
+    if let Some(prefix) = line.strip_prefix("*use ") {
+        let prefix = prefix.trim_start();
+        let candidates = formulas
+            .iter()
+            .filter(|id| id.starts_with(prefix))
+            .cloned()
+            .collect();
+        (line.len() - prefix.len(), candidates)
+    } else if let Some(prefix) = line.strip_prefix('*').filter(|p| !p.contains(' ')) {
+        let candidates = COMMANDS
+            .iter()
+            .filter(|(name, _, _)| name.starts_with(prefix))
+            .map(|(name, _, _)| format!("*{}", name))
+            .collect();
+        (0, candidates)
+    } else {
+        (line.len(), Vec::new())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn open_engine(
+    store: &Arc<ArtifactStore>,
+    formula_id: &str,
+    backend: Backend,
+    patch: Arc<PatchDict>,
+    filters: Arc<Filters>,
+    fallbacks: &[String],
+    ranking: Arc<RankingPipeline>,
+    strict_charset: Arc<AtomicBool>,
+) -> Result<Box<dyn InputMethodEngine>, LiushuError> {
+    let engine: Box<dyn InputMethodEngine> = match backend {
+        Backend::Sqlite => Box::new(
+            ShapeCodeEngine::with_formula(store.target_dir(), formula_id)?
+                .strict_charset(strict_charset.clone()),
+        ),
+        Backend::Redb => Box::new(
+            EngineWithRedb::from_artifacts(store.get(formula_id)?)
+                .strict_charset(strict_charset.clone()),
+        ),
+    };
+    let fallbacks = fallbacks
+        .iter()
+        .map(|fallback| {
+            let (store, id) = (store.clone(), fallback.clone());
+            let strict_charset = strict_charset.clone();
+            let engine = LazyEngine::new(EngineWithRedb::CAPABILITIES, move || {
+                Ok(EngineWithRedb::from_artifacts(store.get(&id)?)
+                    .strict_charset(strict_charset.clone()))
+            });
+            (
+                fallback.clone(),
+                Box::new(engine) as Box<dyn InputMethodEngine>,
+            )
+        })
+        .collect();
+    Ok(Box::new(FilteredEngine::new(
+        FallbackEngine::new(
+            RankedEngine::new(PatchedEngine::new(engine, patch), ranking),
+            fallbacks,
+        ),
+        filters,
+    )))
+}
+
+/// Installs and deploys the starter config when there is no config, right away with `auto`
+/// and otherwise if the user agrees in a terminal.
+fn bootstrap(script: Option<&Path>, auto: bool) -> Result<(), LiushuError> {
+    let dirs = &*PROJECT_DIRS;
+    if !assets::needs_bootstrap(dirs) {
+        return Ok(());
+    }
+    let config_path = dirs.config_dir.join("main.dhall");
+    if !auto {
+        let interactive = script.is_none() && io::stdin().is_terminal();
+        let mut answer = String::new();
+        if interactive {
+            eprint!(
+                "no config found at {}, install the starter config and deploy it? [Y/n] ",
+                config_path.display()
+            );
+            io::stderr().flush()?;
+            io::stdin().read_line(&mut answer)?;
+        }
+        let answer = answer.trim();
+        if !interactive || !(answer.is_empty() || answer.eq_ignore_ascii_case("y")) {
+            return Err(LiushuError::Config {
+                path: Some(config_path),
+                source: "there is none, run `liushu repl --auto` to install the starter config"
+                    .into(),
+            });
+        }
+    }
+
+    let report = assets::bootstrap(dirs)?;
+    if let Some(error) = report.deploy.formulas.into_iter().find_map(|f| f.error) {
+        return Err(error);
+    }
+    eprintln!(
+        "installed the starter config in {}",
+        dirs.config_dir.display()
+    );
+    Ok(())
+}
+
+/// Starts the interactive REPL, or runs `script` and exits when given.
+pub fn run(script: Option<&Path>, format: OutputFormat, auto: bool) -> Result<(), LiushuError> {
+    bootstrap(script, auto)?;
+    let config = Config::load()?;
+    let state = SessionState::load(&PROJECT_DIRS.data_dir);
+    let formula = config
+        .initial_formula(state.active_formula.as_deref())?
+        .id
+        .clone();
+    let fallbacks: HashMap<String, Vec<String>> = config
+        .formulas
+        .iter()
+        .map(|formula| {
+            let fallbacks = config.fallbacks(&formula.id);
+            let fallbacks = fallbacks.into_iter().map(str::to_string).collect();
+            (formula.id.clone(), fallbacks)
+        })
+        .collect();
+    let context = Hmm::context_ranker(&PROJECT_DIRS.target_dir);
+    let rankings: HashMap<String, Arc<RankingPipeline>> = config
+        .formulas
+        .iter()
+        .map(|formula| {
+            let ranking = config.ranking(&formula.id, context.clone());
+            (formula.id.clone(), Arc::new(ranking))
+        })
+        .collect();
+    let disabled = config.disabled_formulas();
+    let formulas: Vec<String> = config
+        .formulas
+        .into_iter()
+        .filter(|formula| formula.is_enabled())
+        .map(|formula| formula.id)
+        .collect();
+    let backend = Backend::Sqlite;
+    let patch = Arc::new(PatchDict::with_formula(&PROJECT_DIRS.data_dir, &formula)?);
+    let filters = Arc::new(Filters::load(&PROJECT_DIRS.config_dir)?);
+    let store = Arc::new(ArtifactStore::new(&PROJECT_DIRS.target_dir));
+    let strict_charset = Arc::new(AtomicBool::new(true));
+    let engine = EngineManager::from([open_engine(
+        &store,
+        &formula,
+        backend,
+        patch.clone(),
+        filters.clone(),
+        &fallbacks[&formula],
+        rankings[&formula].clone(),
+        strict_charset.clone(),
+    )?]);
+    let mut repl = Repl::new(
+        engine,
+        patch,
+        store,
+        formula,
+        formulas.clone(),
+        backend,
+        format,
+    )
+    .with_filters(filters)
+    .with_fallbacks(fallbacks)
+    .with_rankings(rankings)
+    .with_strict_charset(strict_charset)
+    .with_disabled(disabled)
+    .with_counts(
+        // a server may have it open
+        UserCounts::read(PROJECT_DIRS.data_dir.join(USER_DICT_FILE)).unwrap_or_else(|e| {
+            eprintln!("warning: cannot read the user dictionary: {}", e.report());
+            UserCounts::default()
+        }),
+    );
+
+    if let Some(script) = script {
+        repl.run_script(script, &mut io::stdout())?;
+        return match repl.errors {
+            0 => Ok(()),
+            errors => Err(LiushuError::Other(format!(
+                "{} failed in {}",
+                if errors == 1 {
+                    "1 line".to_string()
+                } else {
+                    format!("{} lines", errors)
+                },
+                script.display()
+            ))),
+        };
+    }
+
+    let mut editor: Editor<ReplHelper, DefaultHistory> =
+        Editor::new().map_err(|e| LiushuError::io("cannot open the terminal", e))?;
+    editor.set_helper(Some(ReplHelper { formulas }));
+    let history_path = PROJECT_DIRS.data_dir.join("repl_history");
+    let _ = editor.load_history(&history_path);
+
+    loop {
+        let input = match editor.readline(&repl.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                println!("error: {}", error);
+                break;
+            }
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+
+        match repl.handle(input, &mut io::stdout()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(error) => println!("error: {}", error),
+        }
+    }
+
+    if fs::create_dir_all(&PROJECT_DIRS.data_dir).is_ok() {
+        let _ = editor.save_history(&history_path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NumberEngine;
+
+    impl InputMethodEngine for NumberEngine {
+        fn search(&self, code: &str) -> Result<Vec<SearchResultItem>, LiushuError> {
+            let count = if code == "many" { 10 } else { 0 };
+            Ok((0..count)
+                .map(|i| SearchResultItem {
+                    text: format!("c{}", i),
+                    code: code.to_string(),
+                    weight: 0,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                })
+                .collect())
+        }
+
+        /// `c1a` and `c1b` for `c1`.
+        fn associations(
+            &self,
+            committed: &str,
+            _limit: usize,
+        ) -> Result<Vec<SearchResultItem>, LiushuError> {
+            let texts: &[&str] = match committed {
+                "c1" => &["c1a", "c1b"],
+                _ => &[],
+            };
+            Ok(texts
+                .iter()
+                .map(|text| SearchResultItem {
+                    text: text.to_string(),
+                    code: "many".to_string(),
+                    weight: 0,
+                    comment: None,
+                    formula: None,
+                    user_count: None,
+                    is_exact: false,
+                })
+                .collect())
+        }
+    }
+
+    fn test_repl() -> (Repl, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = Arc::new(PatchDict::with_formula(&dir, "sunman").unwrap());
+        let engine = PatchedEngine::new(Box::new(NumberEngine), patch.clone());
+        let repl = Repl::new(
+            EngineManager::from([Box::new(engine)] as [Box<dyn InputMethodEngine>; 1]),
+            patch,
+            Arc::new(ArtifactStore::new(dir.path())),
+            "sunman".to_string(),
+            vec!["sunman".to_string(), "pinyin".to_string()],
+            Backend::Sqlite,
+            OutputFormat::Plain,
+        );
+        (repl, dir)
+    }
+
+    fn run_lines(repl: &mut Repl, lines: &[&str]) -> String {
+        let mut out = Vec::new();
+        for line in lines {
+            repl.handle(line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_completions() {
+        let formulas = vec!["sunman".to_string(), "pinyin".to_string()];
+
+        assert_eq!(completions("*q", &formulas), (0, vec!["*quit".to_string()]));
+        assert_eq!(completions("*", &formulas).1.len(), COMMANDS.len());
+        assert_eq!(
+            completions("*use s", &formulas),
+            (5, vec!["sunman".to_string()])
+        );
+        assert_eq!(completions("*use ", &formulas), (5, formulas.clone()));
+        assert_eq!(completions("nihao", &formulas), (5, vec![]));
+    }
+
+    #[test]
+    fn test_user_counts() {
+        let (repl, dir) = test_repl();
+        let path = dir.path().join(USER_DICT_FILE);
+        let user_dict = liushu_core::userdict::UserDict::open(&path).unwrap();
+        user_dict.record("c1", "many").unwrap();
+        user_dict.record("c1", "many").unwrap();
+        drop(user_dict);
+        let mut repl = repl.with_counts(UserCounts::read(&path).unwrap());
+
+        let out = run_lines(&mut repl, &["many"]);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[..2], ["1. c0 [many] (0)", "2. c1 [many] w=0 u=2"]);
+
+        repl.format = OutputFormat::Json;
+        let out = run_lines(&mut repl, &["many"]);
+        let results: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert!(results["results"][0].get("user_count").is_none());
+        assert_eq!(results["results"][1]["user_count"], 2);
+    }
+
+    #[test]
+    fn test_selection() {
+        let (mut repl, _dir) = test_repl();
+
+        run_lines(&mut repl, &["many"]);
+        assert_eq!(repl.prompt(), "liushu [1/2]> ");
+
+        assert_eq!(run_lines(&mut repl, &["="]).lines().count(), 2);
+        assert_eq!(repl.prompt(), "liushu [2/2]> ");
+        assert_eq!(run_lines(&mut repl, &["="]), "");
+
+        assert_eq!(run_lines(&mut repl, &["3"]), "no candidate 3\n");
+        assert_eq!(run_lines(&mut repl, &["2"]), "committed: c9\n");
+        assert_eq!(repl.prompt(), "liushu> ");
+
+        run_lines(&mut repl, &["many", "-", "*next", "*prev", "0"]);
+        assert!(repl.selection.is_none());
+
+        run_lines(&mut repl, &["none"]);
+        assert!(repl.selection.is_none());
+    }
+
+    #[test]
+    fn test_commands() {
+        let (mut repl, _dir) = test_repl();
+
+        assert_eq!(
+            run_lines(&mut repl, &["*many"]),
+            "unknown command `*many`, try *help\n"
+        );
+        assert!(repl.selection.is_none());
+
+        assert_eq!(run_lines(&mut repl, &["*list"]), "* sunman\n  pinyin\n");
+        assert_eq!(
+            run_lines(&mut repl, &["*lookup many", "*commit 1"])
+                .lines()
+                .last(),
+            Some("committed: c0")
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["*commit 1"]),
+            "no pending candidates\n"
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["many", "*commit 2", "2"])
+                .lines()
+                .skip(PAGE_SIZE)
+                .collect::<Vec<_>>(),
+            [
+                "committed: c1",
+                "associations:",
+                "1. c1a [many] (0)",
+                "2. c1b [many] (0)",
+                "committed: b"
+            ]
+        );
+        assert!(repl.selection.is_none());
+        let grouped = run_lines(&mut repl, &["many", "*grouped many"]);
+        let lines: Vec<_> = grouped.lines().skip(PAGE_SIZE).collect();
+        assert_eq!(lines[..3], ["many", "    c0 (0)", "    c1 (0)"]);
+        assert_eq!(lines.len(), 11);
+        assert!(repl.selection.is_some());
+        assert_eq!(run_lines(&mut repl, &["*grouped none", "0"]), "");
+        assert_eq!(
+            run_lines(&mut repl, &["*info"]),
+            "formula: sunman\nbackend: sqlite\n"
+        );
+        // nothing to warm of an engine in memory
+        assert!(run_lines(&mut repl, &["*warmup"]).starts_with("warmed 0 of 0 lookups in "));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let (repl, _dir) = test_repl();
+        let mut repl = repl.with_disabled(vec!["wubi".to_string()]);
+
+        assert_eq!(
+            run_lines(&mut repl, &["*list"]),
+            "* sunman\n  pinyin\n  wubi (disabled)\n"
+        );
+        assert!(
+            run_lines(&mut repl, &["*use wubi"]).starts_with("error: the formula wubi is disabled")
+        );
+        assert_eq!(repl.formula, "sunman");
+        assert_eq!(repl.errors, 1);
+    }
+
+    #[test]
+    fn test_charset() {
+        use liushu_core::charset::Charset;
+        use liushu_core::dict::{build, BuildOptions};
+        use liushu_core::progress::NoProgress;
+
+        let (repl, dir) = test_repl();
+        let words = dir.path().join("words.tsv");
+        fs::write(
+            &words,
+            "text\tcode\tweight\n丢失\tdiushi\t2\n丟失\tdiushi\t1\n",
+        )
+        .unwrap();
+        let options = BuildOptions {
+            charset: Charset::builtin("gb2312").map(Arc::new),
+            ..Default::default()
+        };
+        build(&[words], dir.path(), "fixture", options, &NoProgress).unwrap();
+        let engine = open_engine(
+            &repl.store,
+            "fixture",
+            Backend::Redb,
+            repl.patch.clone(),
+            repl.filters.clone(),
+            &[],
+            Arc::default(),
+            repl.strict_charset.clone(),
+        )
+        .unwrap();
+        let mut repl = Repl {
+            engine_manager: EngineManager::from([engine]),
+            ..repl
+        };
+
+        assert_eq!(run_lines(&mut repl, &["diushi"]), "1. *丢失 [diushi] (2)\n");
+        assert_eq!(
+            run_lines(&mut repl, &["*charset off", "diushi"]),
+            "showing candidates out of the charset\n1. *丢失 [diushi] (2)\n2. *丟失 [diushi] (1)\n"
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["*charset strict", "diushi"]),
+            "leaving out candidates out of the charset\n1. *丢失 [diushi] (2)\n"
+        );
+        assert_eq!(repl.errors, 0);
+    }
+
+    #[test]
+    fn test_peek() {
+        use liushu_core::dict::{build, BuildOptions};
+        use liushu_core::progress::NoProgress;
+
+        let (mut repl, dir) = test_repl();
+        let words = dir.path().join("words.tsv");
+        let rows = "text\tcode\tweight\n你\tni\t1\n你好\tnihao\t2\n呢\tne\t1\n";
+        fs::write(&words, rows).unwrap();
+        let options = BuildOptions::default();
+        build(&[words], dir.path(), "sunman", options, &NoProgress).unwrap();
+
+        assert_eq!(
+            run_lines(
+                &mut repl,
+                &["*peek n", "*peek ni", "*peek nihao", "*peek x"]
+            ),
+            concat!(
+                "longer codes starting with n: 3, next keys: e i\n",
+                "longer codes starting with ni: 1, next keys: h\n",
+                "nihao is a leaf, no code goes on from it\n",
+                "no code starts with x\n",
+            )
+        );
+        repl.format = OutputFormat::Json;
+        assert_eq!(
+            run_lines(&mut repl, &["*peek nih"]),
+            "{\"child_keys\":[\"a\"],\"descendant_codes\":1,\"is_leaf\":false}\n"
+        );
+        assert_eq!(repl.errors, 0);
+    }
+
+    #[test]
+    fn test_filters() {
+        let (repl, dir) = test_repl();
+        let filters = Arc::new(Filters::load(dir.path()).unwrap());
+        let engine = FilteredEngine::new(
+            PatchedEngine::new(Box::new(NumberEngine), repl.patch.clone()),
+            filters.clone(),
+        );
+        let mut repl = Repl {
+            engine_manager: EngineManager::from([Box::new(engine) as Box<dyn InputMethodEngine>]),
+            ..repl
+        }
+        .with_filters(filters);
+        // boosted by the patch dictionary over the 10 candidates of `many`
+        repl.patch.add("妮", "many", 10).unwrap();
+        run_lines(&mut repl, &["many"]);
+        assert_eq!(repl.prompt(), "liushu [1/2]> ");
+
+        fs::write(
+            dir.path().join(liushu_core::engine::FILTERS_FILE),
+            r#"{ texts = [ "妮" ], pairs = [ { code = "many", text = "c1" } ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            run_lines(&mut repl, &["*filters reload"]),
+            "blocking 2 candidates\n"
+        );
+        // the pages are of those left
+        let page = run_lines(&mut repl, &["many"]);
+        assert!(page.starts_with("1. c0"));
+        assert!(!page.contains("c1"));
+        assert_eq!(
+            run_lines(&mut repl, &["=", "1"]).lines().last(),
+            Some("committed: c9")
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["*filters again"]),
+            "invalid argument `again` for *filters\n"
+        );
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let (repl, _dir) = test_repl();
+        let pinyin: liushu_core::engine::MemoryEngine = [SearchResultItem {
+            text: "你".to_string(),
+            code: "ni".to_string(),
+            weight: 1,
+            comment: None,
+            formula: None,
+            user_count: None,
+            is_exact: false,
+        }]
+        .into_iter()
+        .collect();
+        let engine = FallbackEngine::new(
+            PatchedEngine::new(Box::new(NumberEngine), repl.patch.clone()),
+            vec![("pinyin".to_string(), Box::new(pinyin) as _)],
+        );
+        let mut repl = Repl {
+            engine_manager: EngineManager::from([Box::new(engine) as Box<dyn InputMethodEngine>]),
+            ..repl
+        };
+        assert_eq!(
+            run_lines(&mut repl, &["ni"]),
+            "1. *你 [ni] (1) (from pinyin)\n"
+        );
+        assert!(!run_lines(&mut repl, &["many"]).contains("(from"));
+        assert_eq!(
+            run_lines(&mut repl, &["*grouped n"]),
+            "ni\n    你 (1) (from pinyin)\n"
+        );
+    }
+
+    #[test]
+    fn test_run_script() {
+        let (mut repl, dir) = test_repl();
+        let script = dir.path().join("queries.txt");
+        fs::write(&script, "# regressions\nmany\n\n*commit 9\n  1\n*bogus\n").unwrap();
+
+        let output = run_lines(&mut repl, &[&format!("*run {}", script.display())]);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], "> many");
+        assert_eq!(
+            lines[9..],
+            [
+                "> *commit 9",
+                "no candidate 9",
+                "> 1",
+                "committed: c0",
+                "> *bogus",
+                "unknown command `*bogus`, try *help"
+            ]
+        );
+        assert_eq!(repl.errors, 2);
+
+        repl.format = OutputFormat::Json;
+        repl.errors = 0;
+        fs::write(&script, "many\nnone\n*quit\nmany\n").unwrap();
+        let output = run_lines(&mut repl, &[&format!("*run {}", script.display())]);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(r#"{"query":"many","results":[{"#));
+        assert_eq!(lines[1], r#"{"query":"none","results":[]}"#);
+        assert_eq!(lines[2], "> *quit");
+        assert_eq!(repl.errors, 0);
+
+        run_lines(&mut repl, &["*run missing.txt"]);
+        assert_eq!(repl.errors, 1);
+    }
+
+    #[test]
+    fn test_patch_commands() {
+        let (mut repl, _dir) = test_repl();
+
+        assert_eq!(
+            run_lines(&mut repl, &["*add 你好 nihao 500", "*add 你好 nihao 600"]),
+            "added 你好 nihao 500\nupdated 你好 nihao to 600\n"
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["nihao", "1"]).lines().last(),
+            Some("committed: 你好")
+        );
+        assert_eq!(
+            run_lines(&mut repl, &["*remove 你好 nihao", "nihao"]),
+            "removed 你好 nihao\n"
+        );
+        assert!(repl.selection.is_none());
+    }
+
+    #[test]
+    fn test_annotate() {
+        let (mut repl, _dir) = test_repl();
+        run_lines(&mut repl, &["*add 把 bv 1", "*add 把 b 1", "*add 手 sfk 1"]);
+        assert_eq!(
+            run_lines(&mut repl, &["*annotate 把手，把x"]),
+            "把 手  ， 把 x\nb  sfk ·  b  ·\n"
+        );
+        assert_eq!(
+            run_lines(&mut repl, &[r#"*annotate "把 手""#]),
+            "把   手\nb  · sfk\n"
+        );
+        repl.format = OutputFormat::Json;
+        assert_eq!(
+            run_lines(&mut repl, &["*annotate 把，"]),
+            "[{\"char\":\"把\",\"code\":\"b\"},{\"char\":\"，\",\"code\":null}]\n"
+        );
+    }
+}
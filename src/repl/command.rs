@@ -0,0 +1,410 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sqlite,
+    Redb,
+}
+
+impl Backend {
+    pub fn other(self) -> Self {
+        match self {
+            Backend::Sqlite => Backend::Redb,
+            Backend::Redb => Backend::Sqlite,
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(Backend::Sqlite),
+            "redb" => Ok(Backend::Redb),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // padded, the backends head the columns of `*compare`
+        f.pad(match self {
+            Backend::Sqlite => "sqlite",
+            Backend::Redb => "redb",
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplCommand {
+    Help,
+    List,
+    Info,
+    Use(String),
+    Backend(Backend),
+    Shift,
+    Reload,
+    ReloadFilters,
+    WarmUp,
+    /// Whether the candidates out of the charset of the formula are left out.
+    Charset(bool),
+    Lookup(String),
+    Grouped(String),
+    Compare(String),
+    Peek(String),
+    Annotate(String),
+    Add {
+        text: String,
+        code: String,
+        weight: u64,
+    },
+    Remove {
+        text: String,
+        code: String,
+    },
+    Commit(usize),
+    Run(String),
+    Next,
+    Prev,
+    Quit,
+}
+
+/// Name, arguments and one-line description of every command, in `*help` order.
+pub const COMMANDS: [(&str, &[&str], &str); 22] = [
+    ("help", &[], "list all commands"),
+    ("list", &[], "list configured formulas"),
+    ("info", &[], "show the active formula and backend"),
+    ("use", &["formula"], "switch to another formula"),
+    ("backend", &["sqlite|redb"], "search with another backend"),
+    ("shift", &[], "toggle between the sqlite and redb backends"),
+    ("reload", &[], "reopen the artifacts of the active formula"),
+    (
+        "filters",
+        &["reload"],
+        "read the blocked candidates of the config dir again",
+    ),
+    (
+        "warmup",
+        &[],
+        "read the artifacts for the first searches, for up to a second",
+    ),
+    (
+        "charset",
+        &["strict|off"],
+        "hide or show the candidates out of the charset of the formula",
+    ),
+    (
+        "lookup",
+        &["code"],
+        "search a code verbatim, quotes allowed",
+    ),
+    (
+        "grouped",
+        &["code"],
+        "search a code, the candidates grouped by code",
+    ),
+    ("compare", &["code"], "compare the results of both backends"),
+    (
+        "peek",
+        &["code"],
+        "tell the keys that go on from a code and how many codes do",
+    ),
+    (
+        "annotate",
+        &["text"],
+        "print each character of a text over its shortest code",
+    ),
+    (
+        "add",
+        &["text", "code", "weight"],
+        "add or reweight an entry",
+    ),
+    ("remove", &["text", "code"], "hide an entry"),
+    (
+        "commit",
+        &["n"],
+        "commit the nth candidate of the current page, then offer its associations",
+    ),
+    ("run", &["file"], "run every line of a file as input"),
+    ("next", &[], "show the next page of candidates, same as `=`"),
+    (
+        "prev",
+        &[],
+        "show the previous page of candidates, same as `-`",
+    ),
+    ("quit", &[], "exit the REPL"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Unknown(String),
+    MissingArgument(&'static str, &'static str),
+    UnexpectedArgument(&'static str),
+    InvalidArgument(&'static str, String),
+    UnterminatedQuote,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Unknown(name) => write!(f, "unknown command `*{}`, try *help", name),
+            ParseError::MissingArgument(name, arg) => write!(f, "*{} expects <{}>", name, arg),
+            ParseError::UnexpectedArgument(name) => write!(f, "too many arguments for *{}", name),
+            ParseError::InvalidArgument(name, arg) => {
+                write!(f, "invalid argument `{}` for *{}", arg, name)
+            }
+            ParseError::UnterminatedQuote => write!(f, "unterminated quote"),
+        }
+    }
+}
+
+impl ReplCommand {
+    /// Returns `None` when the input is not a command and should be searched.
+    pub fn parse(input: &str) -> Option<Result<Self, ParseError>> {
+        match input {
+            "=" => Some(Ok(Self::Next)),
+            "-" => Some(Ok(Self::Prev)),
+            _ => input.strip_prefix('*').map(Self::parse_command),
+        }
+    }
+
+    fn parse_command(line: &str) -> Result<Self, ParseError> {
+        let mut args = split_args(line)?.into_iter();
+        let name = args.next().unwrap_or_default();
+        let &(name, arg_names, _) = COMMANDS
+            .iter()
+            .find(|(command, _, _)| *command == name)
+            .ok_or(ParseError::Unknown(name))?;
+
+        if let Some(missing) = arg_names.get(args.len()) {
+            return Err(ParseError::MissingArgument(name, missing));
+        }
+        if args.len() > arg_names.len() {
+            return Err(ParseError::UnexpectedArgument(name));
+        }
+        let mut arg = || args.next().unwrap_or_default();
+
+        Ok(match name {
+            "help" => Self::Help,
+            "list" => Self::List,
+            "info" => Self::Info,
+            "use" => Self::Use(arg()),
+            "backend" => {
+                let arg = arg();
+                Self::Backend(
+                    arg.parse()
+                        .map_err(|_| ParseError::InvalidArgument(name, arg))?,
+                )
+            }
+            "shift" => Self::Shift,
+            "reload" => Self::Reload,
+            "filters" => {
+                let arg = arg();
+                if arg != "reload" {
+                    return Err(ParseError::InvalidArgument(name, arg));
+                }
+                Self::ReloadFilters
+            }
+            "warmup" => Self::WarmUp,
+            "charset" => match arg().as_str() {
+                "strict" => Self::Charset(true),
+                "off" => Self::Charset(false),
+                arg => return Err(ParseError::InvalidArgument(name, arg.to_string())),
+            },
+            "lookup" => Self::Lookup(arg()),
+            "grouped" => Self::Grouped(arg()),
+            "compare" => Self::Compare(arg()),
+            "peek" => Self::Peek(arg()),
+            "annotate" => Self::Annotate(arg()),
+            "add" => {
+                let (text, code, weight) = (arg(), arg(), arg());
+                Self::Add {
+                    text,
+                    code,
+                    weight: weight
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument(name, weight))?,
+                }
+            }
+            "remove" => Self::Remove {
+                text: arg(),
+                code: arg(),
+            },
+            "commit" => {
+                let arg = arg();
+                Self::Commit(
+                    arg.parse()
+                        .map_err(|_| ParseError::InvalidArgument(name, arg))?,
+                )
+            }
+            "run" => Self::Run(arg()),
+            "next" => Self::Next,
+            "prev" => Self::Prev,
+            "quit" => Self::Quit,
+            _ => unreachable!("every entry of COMMANDS is handled"),
+        })
+    }
+
+    pub fn help() -> String {
+        COMMANDS
+            .iter()
+            .map(|(name, args, description)| {
+                let usage = std::iter::once(format!("*{}", name))
+                    .chain(args.iter().map(|arg| format!("<{}>", arg)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("{:<30}{}", usage, description)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Splits on whitespace, keeping double-quoted parts together.
+fn split_args(line: &str) -> Result<Vec<String>, ParseError> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quoted {
+        return Err(ParseError::UnterminatedQuote);
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ReplCommand::parse("nihao"), None);
+        assert_eq!(ReplCommand::parse("="), Some(Ok(ReplCommand::Next)));
+        assert_eq!(ReplCommand::parse("*prev"), Some(Ok(ReplCommand::Prev)));
+        assert_eq!(
+            ReplCommand::parse("*use  sunman"),
+            Some(Ok(ReplCommand::Use("sunman".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse("*commit 3"),
+            Some(Ok(ReplCommand::Commit(3)))
+        );
+        assert_eq!(
+            ReplCommand::parse("*backend redb"),
+            Some(Ok(ReplCommand::Backend(Backend::Redb)))
+        );
+        assert_eq!(
+            ReplCommand::parse("*filters reload"),
+            Some(Ok(ReplCommand::ReloadFilters))
+        );
+        assert_eq!(
+            ReplCommand::parse("*peek ni"),
+            Some(Ok(ReplCommand::Peek("ni".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse("*charset off"),
+            Some(Ok(ReplCommand::Charset(false)))
+        );
+        assert_eq!(
+            ReplCommand::parse("*charset loose"),
+            Some(Err(ParseError::InvalidArgument(
+                "charset",
+                "loose".to_string()
+            )))
+        );
+        assert_eq!(
+            ReplCommand::parse("*backend mysql"),
+            Some(Err(ParseError::InvalidArgument(
+                "backend",
+                "mysql".to_string()
+            )))
+        );
+        assert_eq!(
+            ReplCommand::parse("*add 你好 nihao 500"),
+            Some(Ok(ReplCommand::Add {
+                text: "你好".to_string(),
+                code: "nihao".to_string(),
+                weight: 500
+            }))
+        );
+        assert_eq!(
+            ReplCommand::parse("*annotate 把手"),
+            Some(Ok(ReplCommand::Annotate("把手".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse("*remove 你好"),
+            Some(Err(ParseError::MissingArgument("remove", "code")))
+        );
+    }
+
+    #[test]
+    fn test_parse_quoting() {
+        assert_eq!(
+            ReplCommand::parse(r#"*lookup "ni hao""#),
+            Some(Ok(ReplCommand::Lookup("ni hao".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse(r#"*lookup """#),
+            Some(Ok(ReplCommand::Lookup("".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse(r#"*lookup "ni"#),
+            Some(Err(ParseError::UnterminatedQuote))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            ReplCommand::parse("*use"),
+            Some(Err(ParseError::MissingArgument("use", "formula")))
+        );
+        assert_eq!(
+            ReplCommand::parse("*quit now"),
+            Some(Err(ParseError::UnexpectedArgument("quit")))
+        );
+        assert_eq!(
+            ReplCommand::parse("*commit one"),
+            Some(Err(ParseError::InvalidArgument(
+                "commit",
+                "one".to_string()
+            )))
+        );
+        assert_eq!(
+            ReplCommand::parse("*shfit"),
+            Some(Err(ParseError::Unknown("shfit".to_string())))
+        );
+        assert_eq!(
+            ReplCommand::parse("*"),
+            Some(Err(ParseError::Unknown("".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let help = ReplCommand::help();
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        for (name, _, _) in COMMANDS {
+            assert!(help.contains(&format!("*{}", name)));
+        }
+    }
+}
@@ -0,0 +1,624 @@
+mod common;
+
+use std::fs;
+
+use self::common::{liushu, text, write_config};
+
+#[test]
+fn test_deploy_broken_config() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(home.path(), "{ formulas = [ { id = 1 } ] }");
+
+    let output = liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .code(2)
+        .get_output()
+        .clone();
+    let stderr = text(&output.stderr);
+    assert!(stderr.starts_with("error[E_CONFIG]: config error"));
+    assert!(stderr
+        .lines()
+        .any(|line| line.starts_with("hint: fix main.dhall")));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn test_deploy_missing_dictionary() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [
+            { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] },
+            { id = "broken", name = None Text, dictionaries = [ "missing.tsv" ] }
+        ] }"#,
+    );
+
+    let output = liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .code(3)
+        .get_output()
+        .clone();
+    let out = text(&output.stdout);
+    let lines: Vec<_> = out.lines().collect();
+    assert!(lines[0].starts_with("formula  status      entries  seconds"));
+    assert!(lines[1].starts_with("fixture  deployed          1"));
+    assert!(lines[2].starts_with("broken   failed            0"));
+    assert!(lines[3].starts_with("error: broken: missing"));
+
+    let output = liushu(home.path())
+        .args(["deploy", "--quiet", "--format", "json"])
+        .assert()
+        .code(3)
+        .get_output()
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["formulas"][0]["status"], "unchanged");
+    assert_eq!(summary["formulas"][1]["error"]["code"], "E_INPUT_MISSING");
+
+    liushu(home.path())
+        .args(["search", "nihao", "--formula", "fixture"])
+        .assert()
+        .success();
+    liushu(home.path())
+        .args(["search", "nihao", "--formula", "broken"])
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn test_formula_enable_disable() {
+    let home = tempfile::tempdir().unwrap();
+    let main = r#"{ formulas = [
+        { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] },
+        { id = "broken", name = None Text, dictionaries = [ "missing.tsv" ] }
+    ] }"#;
+    write_config(home.path(), main);
+    let config_dir = home.path().join(".config/liushu");
+
+    liushu(home.path())
+        .args(["formula", "disable", "broken"])
+        .assert()
+        .success()
+        .stdout("disabled formula broken\n");
+    assert_eq!(
+        fs::read_to_string(config_dir.join("main.dhall")).unwrap(),
+        main
+    );
+    assert!(config_dir.join("overlay.dhall").exists());
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+    liushu(home.path())
+        .args(["deploy", "--quiet", "--include-disabled"])
+        .assert()
+        .code(3);
+
+    liushu(home.path())
+        .args(["formula", "enable", "broken", "--format", "json"])
+        .assert()
+        .success()
+        .stdout("{\"enabled\":true,\"formula\":\"broken\"}\n");
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .code(3);
+    let output = liushu(home.path())
+        .args(["formula", "disable", "gone"])
+        .assert()
+        .code(3)
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).starts_with("error[E_FORMULA_UNKNOWN]"));
+}
+
+#[test]
+fn test_deploy_rollback() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet", "--rollback"])
+        .assert()
+        .code(3);
+
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+    fs::write(
+        home.path().join(".config/liushu/fixture/words.tsv"),
+        "text\tcode\tweight\n你们\tnimen\t2\n",
+    )
+    .unwrap();
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+
+    let output = liushu(home.path())
+        .arg("status")
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let status = text(&output.stdout);
+    let backup = status
+        .lines()
+        .find_map(|line| line.strip_prefix("backup "))
+        .unwrap();
+    let (timestamp, formulas) = backup.split_once(": ").unwrap();
+    assert_eq!(formulas, "fixture");
+
+    let output = liushu(home.path())
+        .args(["deploy", "--quiet", "--rollback", timestamp])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(text(&output.stdout).starts_with("rolled back to backup "));
+    let output = liushu(home.path())
+        .args(["search", "nihao", "--formula", "fixture"])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(text(&output.stdout).contains("你好"));
+}
+
+#[test]
+fn test_repl_bootstrap() {
+    let home = tempfile::tempdir().unwrap();
+    let script = home.path().join("script.txt");
+    fs::write(&script, "b\n").unwrap();
+
+    let output = liushu(home.path())
+        .args(["--quiet", "repl", "--script"])
+        .arg(&script)
+        .assert()
+        .code(2)
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).contains("liushu repl --auto"));
+
+    let output = liushu(home.path())
+        .args(["--quiet", "repl", "--auto", "--script"])
+        .arg(&script)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).starts_with("installed the starter config"));
+    assert!(text(&output.stdout).contains("是"));
+    assert!(home.path().join(".config/liushu/main.dhall").exists());
+
+    // the config is there from now on
+    let output = liushu(home.path())
+        .args(["--quiet", "repl", "--script"])
+        .arg(&script)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).is_empty());
+}
+
+#[test]
+fn test_profile() {
+    let home = tempfile::tempdir().unwrap();
+    let profile = home.path().join("profile");
+    liushu(home.path())
+        .args(["--quiet", "deploy", "--profile"])
+        .arg(&profile)
+        .assert()
+        .code(2);
+    assert!(profile.join("data/target").is_dir());
+    assert!(!home.path().join(".local/share/liushu").exists());
+
+    let file = home.path().join("file");
+    fs::write(&file, "").unwrap();
+    let output = liushu(home.path())
+        .arg("status")
+        .arg("--profile")
+        .arg(&file)
+        .assert()
+        .code(4)
+        .get_output()
+        .clone();
+    let stderr = text(&output.stderr);
+    assert!(stderr.starts_with("error[E_IO]: failed to create config dir ‘"));
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn test_named_profiles() {
+    let home = tempfile::tempdir().unwrap();
+    for (name, words) in [("work", "工作\tgz\t5\n"), ("home", "家\tgz\t1\n")] {
+        liushu(home.path())
+            .args(["--quiet", "profile", "create", name])
+            .assert()
+            .success();
+        let config_dir = home
+            .path()
+            .join(".config/liushu/profiles")
+            .join(name)
+            .join("config");
+        fs::create_dir_all(config_dir.join("words")).unwrap();
+        fs::write(
+            config_dir.join("words/words.tsv"),
+            format!("text\tcode\tweight\n{}", words),
+        )
+        .unwrap();
+        fs::write(
+            config_dir.join("main.dhall"),
+            r#"{ formulas = [ { id = "words", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+        )
+        .unwrap();
+        liushu(home.path())
+            .args(["--quiet", "--profile", name, "deploy"])
+            .assert()
+            .success();
+    }
+    let search = |profile: &str| {
+        let output = liushu(home.path())
+            .args(["--quiet", "--format", "tsv", "--profile", profile])
+            .args(["search", "gz", "--formula", "words"])
+            .output()
+            .unwrap();
+        (output.status.code(), text(&output.stdout))
+    };
+    assert_eq!(search("work"), (Some(0), "工作\tgz\t5\t\n".to_string()));
+    assert_eq!(search("home"), (Some(0), "家\tgz\t1\t\n".to_string()));
+    // neither falls back to the dirs of the user, which have no config
+    assert!(!home
+        .path()
+        .join(".local/share/liushu/target/words")
+        .exists());
+    assert_eq!(search("office").0, Some(1));
+}
+
+#[test]
+fn test_keymap() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ], keymap = Some "dvorak" }"#,
+    );
+    liushu(home.path())
+        .args(["--quiet", "deploy"])
+        .assert()
+        .success();
+    let run = |args: &[&str]| {
+        let output = liushu(home.path())
+            .args(["--quiet", "--format", "tsv"])
+            .args(args)
+            .args(["--formula", "fixture"])
+            .output()
+            .unwrap();
+        (output.status.code(), text(&output.stdout))
+    };
+    // the keys of `nihao` on QWERTY, typed on Dvorak
+    assert_eq!(
+        run(&["search", "bcdar"]),
+        (Some(0), "你好\tnihao\t2\t\n".to_string())
+    );
+    assert_eq!(run(&["search", "nihao"]).0, Some(1));
+    assert_eq!(
+        run(&["dict", "inspect", "你好"]),
+        (Some(0), "你好\tnihao\t[bcdar]\t2\n".to_string())
+    );
+
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ], keymap = Some { keys = "ab", codes = "cc" } }"#,
+    );
+    let output = liushu(home.path()).arg("deploy").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(text(&output.stderr).contains("the keymap remaps both a and b to c"));
+}
+
+#[test]
+fn test_dict_build() {
+    let home = tempfile::tempdir().unwrap();
+    let input = home.path().join("dict.tsv");
+    fs::write(&input, "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n").unwrap();
+    let out_dir = home.path().join("out/nested");
+    let build = |extra: &[&str]| {
+        let mut command = liushu(home.path());
+        command
+            .args(["--quiet", "dict", "build", "-i"])
+            .arg(&input)
+            .arg("-o")
+            .arg(&out_dir)
+            .args(["--formula", "fixture"])
+            .args(extra);
+        command
+    };
+
+    let output = build(&[]).assert().success().get_output().clone();
+    assert!(text(&output.stdout).starts_with("built 2 entries with 2 unique codes: "));
+    assert!(out_dir.join("fixture.redb").exists());
+    assert!(out_dir.join("fixture.trie").exists());
+
+    let output = build(&[]).assert().code(1).get_output().clone();
+    assert!(text(&output.stderr).contains("refusing to overwrite"));
+    build(&["--force"]).assert().success();
+
+    // the same trie when the rows are spilled
+    let trie = fs::read(out_dir.join("fixture.trie")).unwrap();
+    build(&["--force", "--max-memory", "1K"]).assert().success();
+    assert_eq!(fs::read(out_dir.join("fixture.trie")).unwrap(), trie);
+
+    // 丟 is out of GB2312
+    fs::write(
+        &input,
+        "text\tcode\tweight\n你好\tnihao\t2\n你\tni\t1\n丟\tdiu\t1\n",
+    )
+    .unwrap();
+    let drop = ["--force", "--charset", "gb2312", "--charset-mode", "drop"];
+    let output = build(&drop).assert().success().get_output().clone();
+    assert!(text(&output.stdout).starts_with("built 2 entries with 2 unique codes: "));
+    build(&["--force", "--charset-mode", "strict"])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_dict_build_arguments() {
+    let home = tempfile::tempdir().unwrap();
+    let out_dir = home.path().join("out");
+
+    // clap rejects a missing --inputs as a usage error
+    liushu(home.path())
+        .args(["dict", "build", "-o"])
+        .arg(&out_dir)
+        .assert()
+        .code(2);
+    liushu(home.path())
+        .args(["dict", "build", "-i", "-o"])
+        .arg(&out_dir)
+        .assert()
+        .code(2);
+
+    let output = liushu(home.path())
+        .args([
+            "dict",
+            "build",
+            "-i",
+            "words.tsv",
+            "--max-memory",
+            "lots",
+            "-o",
+        ])
+        .arg(&out_dir)
+        .assert()
+        .code(2)
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).contains("isn't a size such as 512M"));
+
+    let missing = home.path().join("missing.tsv");
+    let output = liushu(home.path())
+        .args(["dict", "build", "-i"])
+        .arg(&missing)
+        .arg("-o")
+        .arg(&out_dir)
+        .assert()
+        .code(3)
+        .get_output()
+        .clone();
+    assert!(text(&output.stderr).contains(&missing.display().to_string()));
+    assert!(!out_dir.exists());
+}
+
+#[test]
+fn test_serve_stdio() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+
+    let output = liushu(home.path())
+        .args(["serve", "--stdio"])
+        .write_stdin(concat!(
+            r#"{"id":0,"method":"initialize","params":{"protocol_version":1}}"#,
+            "\n",
+            r#"{"id":1,"method":"search","params":{"code":"ni"}}"#,
+            "\n",
+            r#"{"id":2,"method":"shutdown"}"#,
+            "\n",
+            r#"{"id":3,"method":"info"}"#,
+            "\n",
+        ))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stdout = text(&output.stdout);
+    let (initialized, stdout) = stdout.split_once('\n').unwrap();
+    assert!(initialized.starts_with(r#"{"id":0,"result":{"#));
+    assert_eq!(
+        stdout,
+        concat!(
+            r#"{"id":1,"result":[{"code":"nihao","comment":null,"text":"你好","weight":2}]}"#,
+            "\n",
+            r#"{"id":2,"result":null}"#,
+            "\n",
+        )
+    );
+
+    // the same answers when the artifacts are loaded by the search
+    let output = liushu(home.path())
+        .args(["serve", "--stdio", "--lazy"])
+        .write_stdin(concat!(
+            r#"{"id":0,"method":"initialize","params":{"protocol_version":1}}"#,
+            "\n",
+            r#"{"id":1,"method":"search","params":{"code":"ni"}}"#,
+            "\n",
+        ))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(text(&output.stdout).ends_with(concat!(
+        r#"{"id":1,"result":[{"code":"nihao","comment":null,"text":"你好","weight":2}]}"#,
+        "\n",
+    )));
+
+    let output = liushu(home.path())
+        .args(["status", "--timings"])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let opened: Vec<_> = text(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_prefix("opened "))
+        .map(|line| line.split_once(' ').unwrap().0.to_string())
+        .collect();
+    assert_eq!(opened, ["artifacts", "config", "patch", "user_dict"]);
+
+    // the end of input ends the session just as well
+    liushu(home.path())
+        .args(["serve", "--stdio"])
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout("");
+    liushu(home.path()).arg("serve").assert().code(2);
+}
+
+#[test]
+fn test_log_stats() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ]
+           , typingLog = { enabled = True }
+           }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+    liushu(home.path())
+        .args(["log", "stats"])
+        .assert()
+        .success()
+        .stdout("commits: 0\naverage code length: 0.00\n");
+
+    let initialize = r#"{"method":"initialize","params":{"protocol_version":1}}"#;
+    let search = r#"{"method":"search","params":{"code":"ni","rime_compat":true}}"#;
+    let select = r#"{"method":"process_key","params":{"key":"1"}}"#;
+    liushu(home.path())
+        .args(["serve", "--stdio"])
+        .write_stdin([initialize, search, select, search, select].join("\n"))
+        .assert()
+        .success();
+    liushu(home.path())
+        .args(["log", "stats"])
+        .assert()
+        .success()
+        .stdout("commits: 2\naverage code length: 5.00\ncandidate 1: 2 (100.0%)\n");
+    let log = fs::read_to_string(home.path().join(".local/share/liushu/typing.jsonl")).unwrap();
+    assert!(!log.contains("你好"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bench_cache() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+    let queries = home.path().join("queries.txt");
+    fs::write(&queries, "n\nni\nn\n").unwrap();
+    let output = liushu(home.path())
+        .args([
+            "bench",
+            "--formula",
+            "fixture",
+            "--iterations",
+            "2",
+            "--cache",
+            "8",
+        ])
+        .arg("--queries")
+        .arg(&queries)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stdout = text(&output.stdout);
+    assert!(stdout.starts_with("3 queries x 2 iterations, 6 candidates\n"));
+    assert!(stdout.ends_with("cache: 4 hits, 2 misses\n"));
+}
+
+#[test]
+fn test_serve_socket_idle_timeout() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+
+    let socket = home.path().join("liushu.sock");
+    liushu(home.path())
+        .args(["serve", "--stdio", "--socket"])
+        .arg(&socket)
+        .assert()
+        .code(2);
+    liushu(home.path())
+        .args(["serve", "--idle-timeout", "1", "--socket"])
+        .arg(&socket)
+        .assert()
+        .success();
+    assert!(!socket.exists());
+}
+
+#[cfg(not(feature = "dbus"))]
+#[test]
+fn test_serve_dbus_without_feature() {
+    let home = tempfile::tempdir().unwrap();
+    write_config(
+        home.path(),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    );
+    liushu(home.path())
+        .args(["deploy", "--quiet"])
+        .assert()
+        .success();
+
+    liushu(home.path())
+        .args(["serve", "--stdio", "--dbus"])
+        .assert()
+        .code(2);
+    let output = liushu(home.path())
+        .args(["serve", "--dbus"])
+        .assert()
+        .failure()
+        .get_output()
+        .clone();
+    assert_eq!(
+        text(&output.stderr),
+        "error[E_INVALID_INPUT]: invalid input: liushu was built without the dbus feature\n"
+    );
+}
@@ -0,0 +1,174 @@
+//! What the tests of the binary share: scratch profiles with fixture formulas, and the
+//! golden files in `tests/snapshots/` pinning what subcommands print.
+
+// each test binary uses some of it
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use assert_cmd::Command;
+use regex::Regex;
+use tempfile::TempDir;
+
+/// Runs the binary with a scratch home so the real profile is never touched.
+pub fn liushu(home: &Path) -> Command {
+    let mut command = Command::cargo_bin("liushu").unwrap();
+    command
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_DATA_HOME", home.join(".local/share"))
+        .env_remove("RUST_LOG");
+    command
+}
+
+pub fn write_config(home: &Path, main: &str) {
+    let config_dir = home.join(".config/liushu");
+    fs::create_dir_all(config_dir.join("fixture")).unwrap();
+    fs::write(
+        config_dir.join("fixture/words.tsv"),
+        "text\tcode\tweight\n你好\tnihao\t2\n",
+    )
+    .unwrap();
+    fs::write(config_dir.join("main.dhall"), main).unwrap();
+}
+
+pub fn text(output: &[u8]) -> String {
+    String::from_utf8(output.to_vec()).unwrap()
+}
+
+/// Rows of the formulas of [`Profile::fixture`], with a comment, texts sharing a code and
+/// codes prefixing others.
+pub const WORDS: &str = "text\tcode\tweight\tcomment\n\
+    你\tni\t10\t〔亻尔〕\n\
+    尼\tni\t5\t\n\
+    你好\tnihao\t20\t\n\
+    好\thao\t8\t\n\
+    号\thao\t3\t\n";
+
+/// A profile in a home of its own, with formulas of one dictionary each.
+pub struct Profile {
+    home: TempDir,
+    formulas: Vec<String>,
+}
+
+impl Profile {
+    /// Without a config until a formula is added.
+    pub fn new() -> Self {
+        Self {
+            home: tempfile::tempdir().unwrap(),
+            formulas: Vec::new(),
+        }
+    }
+
+    /// `sunman`, which the subcommands search by default, and `fixture`, both of
+    /// [`WORDS`] and deployed.
+    pub fn fixture() -> Self {
+        Self::new()
+            .formula("sunman", WORDS)
+            .formula("fixture", WORDS)
+            .deploy()
+    }
+
+    pub fn home(&self) -> &Path {
+        self.home.path()
+    }
+
+    pub fn config_dir(&self) -> PathBuf {
+        self.home().join(".config/liushu")
+    }
+
+    /// Adds the formula `id` of the TSV dictionary `words`, header included.
+    pub fn formula(mut self, id: &str, words: &str) -> Self {
+        let dir = self.config_dir().join(id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("words.tsv"), words).unwrap();
+        self.formulas.push(id.to_string());
+        self.write_main();
+        self
+    }
+
+    fn write_main(&self) {
+        let formulas: Vec<String> = self
+            .formulas
+            .iter()
+            .map(|id| {
+                format!(
+                    r#"{{ id = "{}", name = None Text, dictionaries = [ "words.tsv" ] }}"#,
+                    id
+                )
+            })
+            .collect();
+        let main = format!("{{ formulas = [ {} ] }}", formulas.join(", "));
+        fs::write(self.config_dir().join("main.dhall"), main).unwrap();
+    }
+
+    pub fn deploy(self) -> Self {
+        self.liushu().args(["--quiet", "deploy"]).assert().success();
+        self
+    }
+
+    pub fn liushu(&self) -> Command {
+        liushu(self.home())
+    }
+
+    /// The command line, exit code, stdout and stderr of running the binary with `args`,
+    /// with what changes from run to run redacted: the home dir, times, durations, sizes
+    /// and the version.
+    pub fn run(&self, args: &[&str]) -> String {
+        let output = self.liushu().args(args).output().unwrap();
+        let transcript = format!(
+            "$ liushu {}\nexit code: {}\n--- stdout\n{}--- stderr\n{}\n",
+            args.join(" "),
+            output.status.code().unwrap_or(-1),
+            text(&output.stdout),
+            text(&output.stderr)
+        );
+        self.normalize(&transcript)
+    }
+
+    pub fn normalize(&self, output: &str) -> String {
+        let home = self.home().to_string_lossy();
+        let mut output = output
+            .replace(&*home, "[HOME]")
+            .replace(env!("CARGO_PKG_VERSION"), "[VERSION]");
+        for (pattern, replacement) in [
+            (r"modified \d+", "modified [TIME]"),
+            (r#""modified":\d+"#, r#""modified":"[TIME]""#),
+            (r"\d+ bytes", "[SIZE] bytes"),
+            (r#""size":\d+"#, r#""size":"[SIZE]""#),
+            (
+                r#""duration_secs":[0-9.e-]+"#,
+                r#""duration_secs":"[SECS]""#,
+            ),
+            // the seconds column of the deploy summary
+            (r"(?m)^(\S+ +\S+ +\d+ +)\d+\.\d+$", "${1}[SECS]"),
+        ] {
+            output = Regex::new(pattern)
+                .unwrap()
+                .replace_all(&output, replacement)
+                .into_owned();
+        }
+        output
+    }
+}
+
+/// Compares `actual` with `tests/snapshots/{name}.txt`, which `LIUSHU_UPDATE_SNAPSHOTS=1`
+/// rewrites instead, as in liushu-core.
+pub fn assert_snapshot(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.txt", name));
+    if std::env::var_os("LIUSHU_UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+    assert_eq!(
+        actual, expected,
+        "the output of {} changed, rerun with LIUSHU_UPDATE_SNAPSHOTS=1 if that is intended",
+        name
+    );
+}
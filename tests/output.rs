@@ -0,0 +1,421 @@
+//! What the subcommands print for a fixture profile, pinned by the snapshots in
+//! `tests/snapshots/` as scripts parse it. A new subcommand gets a snapshot here.
+
+mod common;
+
+use std::fs;
+
+use self::common::{assert_snapshot, Profile, WORDS};
+
+#[test]
+fn test_repl_script() {
+    let profile = Profile::fixture();
+    let script = profile.home().join("script.txt");
+    fs::write(
+        &script,
+        "# a comment, skipped\n\
+         *help\n\
+         *list\n\
+         *info\n\
+         ni\n\
+         1\n\
+         *use fixture\n\
+         hao\n\
+         *backend redb\n\
+         *info\n\
+         *lookup hao\n\
+         *grouped n\n\
+         *compare ni\n\
+         *add 妮 ni 7\n\
+         *reload\n\
+         ni\n\
+         *remove 妮 ni\n\
+         *reload\n\
+         ni\n\
+         xx\n\
+         *nothing\n\
+         *use missing\n",
+    )
+    .unwrap();
+    let script = script.to_str().unwrap();
+
+    assert_snapshot(
+        "repl_script",
+        &profile.run(&["--quiet", "repl", "--script", script]),
+    );
+    // on the formula the first session switched to
+    assert_snapshot(
+        "repl_script_json",
+        &profile.run(&["--quiet", "--format", "json", "repl", "--script", script]),
+    );
+}
+
+#[test]
+fn test_search() {
+    let profile = Profile::fixture();
+    let transcripts = [
+        profile.run(&["--quiet", "search", "ni"]),
+        profile.run(&["--quiet", "search", "ni", "--limit", "1"]),
+        profile.run(&["--quiet", "--format", "tsv", "search", "hao"]),
+        profile.run(&["--quiet", "--format", "json", "search", "ni"]),
+        profile.run(&["--quiet", "--format", "json", "search", "xx"]),
+        profile.run(&["--quiet", "search", "ni", "--formula", "missing"]),
+    ];
+    assert_snapshot("search", &transcripts.concat());
+}
+
+#[test]
+fn test_annotate() {
+    let profile = Profile::fixture();
+    let transcripts = [
+        profile.run(&["--quiet", "annotate", "你好，你号"]),
+        profile.run(&["--quiet", "--format", "tsv", "annotate", "你好号"]),
+        profile.run(&["--quiet", "--format", "json", "annotate", "你？"]),
+        profile.run(&["--quiet", "annotate", "，"]),
+    ];
+    assert_snapshot("annotate", &transcripts.concat());
+}
+
+#[test]
+fn test_status() {
+    let profile = Profile::fixture().formula("undeployed", WORDS);
+    let transcripts = [
+        profile.run(&["--quiet", "status"]),
+        profile.run(&["--quiet", "--format", "json", "status"]),
+    ];
+    assert_snapshot("status", &transcripts.concat());
+}
+
+#[test]
+fn test_deploy_summary() {
+    let profile = Profile::new()
+        .formula("sunman", WORDS)
+        .formula("broken", "text\tcode\tweight\n你\tni\tmany\n");
+    let transcripts = [
+        profile.run(&["--quiet", "deploy"]),
+        profile.run(&["--quiet", "deploy"]),
+        profile.run(&["--quiet", "--format", "json", "deploy"]),
+    ];
+    assert_snapshot("deploy_summary", &transcripts.concat());
+}
+
+#[test]
+fn test_deploy_warnings() {
+    let profile = Profile::new().formula("sunman", WORDS);
+    let target_dir = profile.home().join(".local/share/liushu/target");
+    fs::create_dir_all(&target_dir).unwrap();
+    for id in ["a", "b", "c", "d", "e", "f", "g"] {
+        fs::write(target_dir.join(format!("{}.redb", id)), "").unwrap();
+    }
+    let transcripts = [
+        profile.run(&["--quiet", "deploy"]),
+        profile.run(&["--quiet", "--format", "json", "deploy"]),
+    ];
+    assert_snapshot("deploy_warnings", &transcripts.concat());
+
+    // every one of them
+    let output = profile.liushu().args(["-v", "deploy"]).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches("belongs to no formula").count(), 7);
+    assert!(!stdout.contains("more, see them"));
+}
+
+#[test]
+fn test_dict_build() {
+    let profile = Profile::new();
+    let input = profile.home().join("words.tsv");
+    fs::write(&input, WORDS).unwrap();
+    let input = input.to_str().unwrap();
+    let out_dir = profile.home().join("out");
+    let out_dir = out_dir.to_str().unwrap();
+    let build = ["--quiet", "dict", "build", "-i", input, "-o", out_dir];
+    let transcripts = [
+        profile.run(&build),
+        profile.run(&build),
+        profile.run(&[&build[..], &["--force", "--formula", "sunman"]].concat()),
+    ];
+    assert_snapshot("dict_build", &transcripts.concat());
+}
+
+#[test]
+fn test_dict_sample() {
+    let profile = Profile::fixture();
+    let out_dir = profile.home().join("out");
+    let input = profile.config_dir().join("fixture/words.tsv");
+    let (input, out_dir) = (input.to_str().unwrap(), out_dir.to_str().unwrap());
+    profile.run(&["--quiet", "dict", "build", "-i", input, "-o", out_dir]);
+    let transcripts = [
+        profile.run(&["--quiet", "dict", "sample", "--n", "3", "--seed", "42"]),
+        profile.run(&["--quiet", "dict", "sample", "--n", "3", "--seed", "42"]),
+        profile.run(&[
+            "--quiet", "--format", "json", "dict", "sample", "--seed", "7",
+        ]),
+        profile.run(&["--quiet", "dict", "sample", "--dir", out_dir, "--seed", "1"]),
+        profile.run(&["--quiet", "dict", "sample", "--formula", "missing"]),
+    ];
+    assert_snapshot("dict_sample", &transcripts.concat());
+}
+
+#[test]
+fn test_dict_top() {
+    let profile = Profile::fixture();
+    let output = profile.home().join("top.tsv");
+    let transcripts = [
+        profile.run(&["--quiet", "dict", "top", "--n", "3"]),
+        profile.run(&[
+            "--quiet",
+            "dict",
+            "top",
+            "--output",
+            output.to_str().unwrap(),
+        ]),
+        profile.run(&["--quiet", "dict", "top", "--formula", "missing"]),
+    ];
+    let written = fs::read_to_string(&output).unwrap();
+    assert_snapshot("dict_top", &format!("{}{}", transcripts.concat(), written));
+}
+
+#[test]
+fn test_dict_import_scel() {
+    let profile = Profile::fixture();
+    let bytes = fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/liushu-core/tests/fixtures/phrases.scel"
+    ))
+    .unwrap();
+    let (scel, truncated) = (
+        profile.home().join("phrases.scel"),
+        profile.home().join("truncated.scel"),
+    );
+    fs::write(&scel, &bytes).unwrap();
+    fs::write(&truncated, &bytes[..9800]).unwrap();
+    let fixture = scel.to_str().unwrap();
+    let output = profile.home().join("phrases.tsv");
+    let transcripts = [
+        profile.run(&["--quiet", "dict", "import-scel", fixture]),
+        profile.run(&[
+            "--quiet",
+            "dict",
+            "import-scel",
+            fixture,
+            "--output",
+            output.to_str().unwrap(),
+        ]),
+        profile.run(&[
+            "--quiet",
+            "dict",
+            "import-scel",
+            truncated.to_str().unwrap(),
+        ]),
+    ];
+    let written = fs::read_to_string(&output).unwrap();
+    assert_snapshot(
+        "dict_import_scel",
+        &format!("{}{}", transcripts.concat(), written),
+    );
+}
+
+#[test]
+fn test_dict_fix() {
+    let profile = Profile::new();
+    let unfixed = profile.home().join("unfixed.tsv");
+    fs::copy(
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/liushu-core/tests/fixtures/unfixed.tsv"
+        ),
+        &unfixed,
+    )
+    .unwrap();
+    let (input, output) = (unfixed.to_str().unwrap(), profile.home().join("fixed.tsv"));
+    let output = output.to_str().unwrap();
+    let fix = |extra: &[&str]| {
+        let args = ["--quiet", "dict", "fix", "-i", input, "-o", output];
+        profile.run(&[&args, extra].concat())
+    };
+    let transcripts = [
+        fix(&["--only", "weights,columns"]),
+        fix(&["--skip", "duplicates"]),
+        fix(&[]),
+        profile.run(&["--quiet", "dict", "fix", "-i", output, "-o", output]),
+    ];
+    let written = fs::read_to_string(output).unwrap();
+    assert_snapshot("dict_fix", &format!("{}{}", transcripts.concat(), written));
+}
+
+#[test]
+fn test_userdict_restore() {
+    let profile = Profile::new();
+    let data_dir = profile.home().join(".local/share/liushu");
+    fs::create_dir_all(&data_dir).unwrap();
+    fs::write(
+        data_dir.join("state.json"),
+        r#"{"active_formula":"sunman"}"#,
+    )
+    .unwrap();
+    let tsv = profile.home().join("userdict.tsv");
+    fs::write(
+        &tsv,
+        "text\tcode\tcount\tlast_used\n你好\tnihao\t2\t10\n世界\tshijie\t1\t20\n",
+    )
+    .unwrap();
+    // backed up as it is closed
+    let import = profile.run(&[
+        "--quiet",
+        "userdict",
+        "import",
+        tsv.to_str().unwrap(),
+        "--merge",
+    ]);
+    let backups: Vec<_> = fs::read_dir(data_dir.join("backups"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".tsv.gz"))
+        .collect();
+    assert_eq!(backups.len(), 1);
+    // dated like the snapshot
+    let backup = data_dir.join("backups/userdict-20240228.tsv.gz");
+    fs::rename(&backups[0], &backup).unwrap();
+    fs::rename(
+        backups[0]
+            .to_string_lossy()
+            .replace("userdict-", "state-")
+            .replace(".tsv.gz", ".json"),
+        data_dir.join("backups/state-20240228.json"),
+    )
+    .unwrap();
+    let backup = backup.to_str().unwrap();
+
+    fs::write(&tsv, "text\tcode\tcount\tlast_used\n好\thao\t1\t30\n").unwrap();
+    profile.run(&[
+        "--quiet",
+        "userdict",
+        "import",
+        tsv.to_str().unwrap(),
+        "--replace",
+    ]);
+    fs::write(data_dir.join("state.json"), "{}").unwrap();
+    let missing = profile.home().join("missing.tsv.gz");
+    let transcripts = [
+        import,
+        profile.run(&["--quiet", "userdict", "restore", "--from", backup]),
+        profile.run(&[
+            "--quiet", "--format", "json", "userdict", "restore", "--from", backup,
+        ]),
+        profile.run(&[
+            "--quiet",
+            "userdict",
+            "restore",
+            "--from",
+            tsv.to_str().unwrap(),
+        ]),
+        profile.run(&[
+            "--quiet",
+            "userdict",
+            "restore",
+            "--from",
+            missing.to_str().unwrap(),
+        ]),
+    ];
+    assert_snapshot("userdict_restore", &transcripts.concat());
+    assert_eq!(
+        fs::read_to_string(data_dir.join("state.json")).unwrap(),
+        r#"{"active_formula":"sunman"}"#
+    );
+}
+
+#[test]
+fn test_dict_inspect() {
+    let profile = Profile::fixture();
+    let words = profile.home().join("words.tsv");
+    let phrases = profile.home().join("phrases.tsv");
+    fs::write(&words, WORDS).unwrap();
+    fs::write(
+        &phrases,
+        "text\tcode\tweight\n# phrases\n你好\tni hao\t500\n你\tn\t1\n",
+    )
+    .unwrap();
+    let out_dir = profile.home().join("out");
+    let (words, phrases) = (words.to_str().unwrap(), phrases.to_str().unwrap());
+    let out_dir = out_dir.to_str().unwrap();
+    profile.run(&[
+        "dict",
+        "build",
+        "-i",
+        words,
+        phrases,
+        "-o",
+        out_dir,
+        "--provenance",
+    ]);
+    let transcripts = [
+        profile.run(&[
+            "--quiet",
+            "dict",
+            "inspect",
+            "你",
+            "--dir",
+            out_dir,
+            "--provenance",
+        ]),
+        profile.run(&[
+            "--quiet",
+            "dict",
+            "inspect",
+            "你好",
+            "--dir",
+            out_dir,
+            "--provenance",
+        ]),
+        profile.run(&[
+            "--quiet",
+            "--format",
+            "json",
+            "dict",
+            "inspect",
+            "你",
+            "--dir",
+            out_dir,
+            "--provenance",
+        ]),
+        // deployed without provenance
+        profile.run(&["--quiet", "dict", "inspect", "你", "--provenance"]),
+        profile.run(&["--quiet", "dict", "inspect", "没有"]),
+    ];
+    assert_snapshot("dict_inspect", &transcripts.concat());
+}
+
+#[test]
+fn test_profile() {
+    let profile = Profile::new();
+    let transcripts = [
+        profile.run(&["--quiet", "profile", "list"]),
+        profile.run(&["--quiet", "profile", "create", "work"]),
+        profile.run(&["--quiet", "--format", "json", "profile", "create", "home"]),
+        profile.run(&["--quiet", "profile", "create", "work"]),
+        profile.run(&["--quiet", "profile", "create", "../work"]),
+        profile.run(&["--quiet", "profile", "list"]),
+        profile.run(&["--quiet", "--format", "json", "profile", "list"]),
+        profile.run(&["--quiet", "profile", "remove", "home"]),
+        profile.run(&["--quiet", "profile", "remove", "home"]),
+        profile.run(&["--quiet", "--profile", "home", "status"]),
+        profile.run(&["--quiet", "profile", "list"]),
+    ];
+    assert_snapshot("profile", &transcripts.concat());
+}
+
+#[test]
+fn test_doctor() {
+    let profile = Profile::fixture();
+    let healthy = profile.run(&["--quiet", "doctor"]);
+    let profile = profile.formula("undeployed", WORDS);
+    let target_dir = profile.home().join(".local/share/liushu/target");
+    fs::write(target_dir.join("fixture.trie"), "not a trie").unwrap();
+    fs::write(target_dir.join("hmm_model.redb"), "not a model").unwrap();
+    let transcripts = [
+        healthy,
+        profile.run(&["--quiet", "doctor"]),
+        profile.run(&["--quiet", "--format", "json", "doctor"]),
+    ];
+    assert_snapshot("doctor", &transcripts.concat());
+}
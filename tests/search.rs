@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::Path;
+
+use assert_cmd::Command;
+
+/// The binary with a scratch home, so the real profile is never touched.
+fn liushu(home: &Path) -> Command {
+    let mut command = Command::cargo_bin("liushu").unwrap();
+    command
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_DATA_HOME", home.join(".local/share"));
+    command
+}
+
+/// A scratch home with the fixture formula of `words` deployed.
+fn deployed(words: &str) -> tempfile::TempDir {
+    let home = tempfile::tempdir().unwrap();
+    let config_dir = home.path().join(".config/liushu");
+    fs::create_dir_all(config_dir.join("fixture")).unwrap();
+    fs::write(
+        config_dir.join("fixture/words.tsv"),
+        format!("text\tcode\tweight\n{}", words),
+    )
+    .unwrap();
+    fs::write(
+        config_dir.join("main.dhall"),
+        r#"{ formulas = [ { id = "fixture", name = None Text, dictionaries = [ "words.tsv" ] } ] }"#,
+    )
+    .unwrap();
+    liushu(home.path()).arg("deploy").assert().success();
+    home
+}
+
+fn search(home: &Path, args: &[&str]) -> (Option<i32>, String) {
+    let output = liushu(home)
+        .arg("search")
+        .args(args)
+        .args(["--formula", "fixture"])
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn test_search() {
+    let home = deployed("你好\tnihao\t2\n");
+
+    let (code, stdout) = search(home.path(), &["nihao"]);
+    assert_eq!(code, Some(0));
+    assert!(stdout.contains("你好"));
+    assert_eq!(
+        search(home.path(), &["nihao", "--format", "tsv"]),
+        (Some(0), "你好\tnihao\t2\t\n".to_string())
+    );
+    let (code, stdout) = search(home.path(), &["nihao", "--format", "json"]);
+    assert_eq!(code, Some(0));
+    let results: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(results[0]["text"], "你好");
+}
+
+#[test]
+fn test_search_no_candidates() {
+    let home = deployed("你好\tnihao\t2\n");
+
+    assert_eq!(search(home.path(), &["hao"]).0, Some(1));
+}
+
+#[test]
+fn test_search_reads_the_home() {
+    let home = deployed("你好\tnihao\t2\n");
+    let other = deployed("你号\tnihao\t2\n");
+    let empty = tempfile::tempdir().unwrap();
+
+    let tsv = |home: &Path| search(home, &["nihao", "--format", "tsv"]);
+    assert_eq!(
+        tsv(home.path()),
+        (Some(0), "你好\tnihao\t2\t\n".to_string())
+    );
+    assert_eq!(
+        tsv(other.path()),
+        (Some(0), "你号\tnihao\t2\t\n".to_string())
+    );
+    // nothing deployed there
+    let (code, stdout) = tsv(empty.path());
+    assert!(!matches!(code, Some(0) | Some(1)));
+    assert!(stdout.is_empty());
+}